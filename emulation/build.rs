@@ -0,0 +1,93 @@
+// Generates the guest syscall number tables (see syscalls/syscall.tbl and syscalls/emulated.txt)
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+/// Table name, the syscall.tbl abis that frontend's kernel is built with, and the file listing the
+/// only emulated.txt syscalls it gets, if it's limited to some
+const FRONTENDS: &[(&str, &[&str], Option<&str>)] = &[
+    ("RISCV64", &["common", "64", "riscv", "newstat", "rlimit", "memfd_secret"], None),
+    // a riscv32 kernel has neither time32 nor stat64, but the syscalls were always emulated under
+    // those numbers and older rv32 toolchains still make them
+    ("RISCV32", &["common", "32", "riscv", "time32", "stat64", "memfd_secret"], None),
+    // the aarch64 frontend hasn't been run with most syscalls yet, see arm64.txt
+    ("ARM64", &["common", "64", "renameat", "newstat", "rlimit", "memfd_secret"], Some("arm64.txt")),
+];
+
+fn entries(path: &Path) -> Vec<Vec<String>> {
+    let text = fs::read_to_string(path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+    text.lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| l.split_whitespace().map(|s| s.to_string()).collect())
+        .collect()
+}
+
+fn main() {
+    let dir = Path::new("syscalls");
+    println!("cargo:rerun-if-changed=syscalls/syscall.tbl");
+    println!("cargo:rerun-if-changed=syscalls/emulated.txt");
+    for (_, _, only) in FRONTENDS {
+        if let Some(f) = only {
+            println!("cargo:rerun-if-changed=syscalls/{}", f);
+        }
+    }
+    let tbl = entries(&dir.join("syscall.tbl"));
+    let mut emulated: HashMap<String, String> = HashMap::new();
+    for e in entries(&dir.join("emulated.txt")) {
+        if e.len() != 2 {
+            panic!("emulated.txt: expected <kernel name> <SyscallType>, got {:?}", e);
+        }
+        if !tbl.iter().any(|t| t.get(2) == Some(&e[0])) {
+            panic!("emulated.txt: {} isn't in syscall.tbl", e[0]);
+        }
+        if emulated.insert(e[0].clone(), e[1].clone()).is_some() {
+            panic!("emulated.txt: {} is listed twice", e[0]);
+        }
+    }
+    let mut out = String::from("// generated by build.rs, don't edit\n");
+    for (table, abis, only) in FRONTENDS {
+        let only: Option<HashSet<String>> = only.map(|f| {
+            entries(&dir.join(f)).into_iter().map(|e| {
+                if e.len() != 1 || !emulated.contains_key(&e[0]) {
+                    panic!("{}: expected an emulated.txt syscall per line, got {:?}", f, e);
+                }
+                e[0].clone()
+            }).collect()
+        });
+        let mut nrs: BTreeMap<u32, &str> = BTreeMap::new();
+        for e in &tbl {
+            if e.len() < 3 {
+                panic!("syscall.tbl: expected <number> <abi> <name>, got {:?}", e);
+            }
+            if !abis.contains(&e[1].as_str()) {
+                continue;
+            }
+            let nr: u32 = e[0].parse().unwrap_or_else(|_| panic!("syscall.tbl: bad number {}", e[0]));
+            if let Some(prev) = nrs.insert(nr, &e[2]) {
+                panic!("syscall.tbl: {} is both {} and {} for {}", nr, prev, e[2], table);
+            }
+        }
+        let len = nrs.keys().next_back().map(|n| n + 1).unwrap_or(0);
+        let holes = (0..len).filter(|n| !nrs.contains_key(n)).count();
+        writeln!(out, "/// {} syscalls, {} holes", nrs.len(), holes).unwrap();
+        writeln!(out, "pub static {}_SYSCALLS: SyscallTable = SyscallTable {{", table).unwrap();
+        out.push_str("    calls: &[\n");
+        for n in 0..len {
+            let allowed = |name: &&&str| only.as_ref().map_or(true, |o| o.contains(**name));
+            match nrs.get(&n).filter(allowed).and_then(|name| emulated.get(*name)) {
+                Some(v) => writeln!(out, "        Some(SyscallType::{}),", v).unwrap(),
+                None => out.push_str("        None,\n"),
+            }
+        }
+        out.push_str("    ],\n    names: &[\n");
+        for n in 0..len {
+            writeln!(out, "        {:?},", nrs.get(&n).copied().unwrap_or("")).unwrap();
+        }
+        out.push_str("    ],\n};\n");
+    }
+    let dest = Path::new(&env::var("OUT_DIR").unwrap()).join("syscall_tables.rs");
+    fs::write(dest, out).unwrap();
+}
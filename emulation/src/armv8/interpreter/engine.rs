@@ -0,0 +1,33 @@
+// The arm64 interpreter behind the common ExecutionEngine seam. It decodes every instruction as it
+// runs it, so there's nothing to translate ahead or invalidate; lockstep() still needs it to
+// stand in for any arm64 backend that does cache.
+use crate::armv8::interpreter::main::Arm64Cpu;
+use crate::common::engine::{ExecutionEngine, MemoryBackend};
+use crate::common::spin::state_digest;
+
+impl ExecutionEngine for Arm64Cpu {
+    fn translate(&mut self, _pc: u64) {}
+    fn run(&mut self, max_insns: u64) {
+        self.run_for(max_insns);
+    }
+    fn invalidate(&mut self, _addr: u64, _len: u64) {}
+    fn pc(&self) -> u64 {
+        self.pc
+    }
+    fn icount(&self) -> u64 {
+        self.icount
+    }
+    fn state_digest(&self) -> u64 {
+        let mut regs = [0; 34];
+        regs[..31].copy_from_slice(&self.regs()[..31]);
+        let f = self.flag_status;
+        regs[31] = self.stack_reg;
+        regs[32] = (f.n as u64) << 3 | (f.z as u64) << 2 | (f.c as u64) << 1 | f.v as u64;
+        regs[33] = (self.fpcr as u64) << 32 | self.fpsr as u64;
+        let vregs: Vec<u64> = self.vreg.iter().flat_map(|v| [v.vect as u64, (v.vect >> 64) as u64]).collect();
+        state_digest(&regs, &vregs) ^ self.pc
+    }
+    fn memory(&mut self) -> &mut dyn MemoryBackend {
+        &mut self.memory_access
+    }
+}
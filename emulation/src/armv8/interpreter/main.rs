@@ -1,3 +1,5 @@
+use std::sync::Arc;
+use sync::Mutex;
 use base::debug;
 use libc::sysinfo;
 use simple_soft_float::RoundingMode;
@@ -7,13 +9,16 @@ use crate::armv8::interpreter::mem::{MemAccessStr, MemData};
 use crate::common::arm_fp_defs::{FPSR, Flags};
 use crate::armv8::interpreter::vect_helper::VectorReg;
 use crate::common::memory::{flat_mem, MemEndian};
+use crate::common::hart_stats::HartCounters;
+use crate::common::poison::PoisonMap;
 cfg_if::cfg_if! {
     if #[cfg(feature = "linux-usermode")] {
         use crate::elf::UserModeRuntime;
         use crate::linux_usermode::defs::{GenericStat, write_sysinfo_generic64};
-        use crate::linux_usermode::main::{dispatch, SyscallIn, SyscallOut, UsermodeCpu};
-        use crate::linux_usermode::signals::{GenericSigactionArg, GenericStackt, SigEntry, SigInfo, Sigmask};
+        use crate::linux_usermode::main::{dispatch, errno_out, group_exit_unwind, SyscallIn, SyscallOut, UsermodeCpu};
+        use crate::linux_usermode::signals::{bind_thread_signals, GenericSigactionArg, GenericStackt, SigEntry, SigInfo, Sigmask};
         use crate::armv8::ume::defs::{arm64_translate_syscall, write_arm64_stat};
+        use crate::linux_usermode::syscall_table::ARM64_SYSCALLS;
 
     }
 }
@@ -34,6 +39,9 @@ pub struct Arm64Cpu {
     pub fpcr: u32,
     pub fpsr: u32,
     pub mdata: MemData,
+    pub stats: Option<Arc<HartCounters>>, // load/store counters, when stats are on
+    pub icount: u64, // instructions retired
+    pub poison: Option<Arc<Mutex<PoisonMap>>>, // shared by every thread of the guest
 
 
 }
@@ -53,7 +61,8 @@ impl Arm64Cpu {
             debug!("Going to execute syscall {:?} (number {:})", s, syscallnum);
             s
         } else {
-            debug!("Failed to execute syscall number {:}", syscallnum);
+            debug!("Failed to execute syscall number {:} ({})", syscallnum,
+                ARM64_SYSCALLS.name(syscallnum as u64).unwrap_or("no such syscall"));
             panic!();
         };
         let arg1 = self.get_reg(0, false) as u64;
@@ -66,7 +75,11 @@ impl Arm64Cpu {
             syscall: systype,
             args: [arg1, arg2, arg3, arg4, arg5, arg6, 0]
         };
-        let out = dispatch(self, sysin);
+        let mut out = dispatch(self, sysin);
+        if out.is_restart() {
+            // no guest signal handlers here yet, so nothing to come back from
+            out = errno_out(libc::EINTR);
+        }
         self.set_reg(0, out.ret1, false);
         if let Some(xx) = out.ret2 {
             self.set_reg(1, xx, false);
@@ -145,7 +158,10 @@ impl Arm64Cpu {
 
     }
     #[cfg(feature = "linux-usermode")]
-    pub fn init_usermode(ume: UserModeRuntime) -> Arm64Cpu {
+    pub fn init_usermode(mut ume: UserModeRuntime) -> Arm64Cpu {
+        ume.hart_stats = ume.stats.as_ref().map(|r| r.register());
+        let stats = ume.hart_stats.clone();
+        let poison = ume.opts.poison.clone();
         Arm64Cpu {
             reg: [0; 32],
             tpidr: [0; 4],
@@ -161,9 +177,16 @@ impl Arm64Cpu {
             want_syscall: false,
             fpcr: 0,
             fpsr: 0,
-            mdata: Default::default()
+            mdata: Default::default(),
+            stats,
+            icount: 0,
+            poison,
         }
     }
+    /// x0 to x30, and an unused 31st
+    pub fn regs(&self) -> &[u64; 32] {
+        &self.reg
+    }
     pub fn get_reg(&mut self, rd: usize, is_stack: bool) -> u64 {
         if rd == 31 {
             return if is_stack {
@@ -179,31 +202,53 @@ impl Arm64Cpu {
         self.stack_reg
     }
     pub fn run(&mut self) {
+        #[cfg(feature = "linux-usermode")]
+        let _bound = self.is_usermode.then(|| bind_thread_signals(&self.user_struct.signals));
         loop {
+            #[cfg(feature = "linux-usermode")]
+            if self.is_usermode && self.user_struct.group_exit.status().is_some() {
+                group_exit_unwind();
+            }
             self.exec_one_by_one();
             if self.stop_exec {
-                if let Some(f) = self.want_pc {
-                    self.pc = f;
-                    self.want_pc = None;
-                }
-                #[cfg(feature = "linux-usermode")]
-                if self.want_syscall {
-                    self.handle_syscall();
-                    self.want_syscall = false;
-                }
-                self.stop_exec = false;
+                self.finish_stop();
+            }
+        }
+    }
+    /// `n` instructions, dealing with jumps and syscalls after each one like run() does
+    pub fn run_for(&mut self, n: u64) {
+        for _ in 0..n {
+            self.exec_one();
+            if self.stop_exec {
+                self.finish_stop();
             }
         }
     }
+    // what the instruction that stopped execution asked for
+    fn finish_stop(&mut self) {
+        if let Some(f) = self.want_pc {
+            self.pc = f;
+            self.want_pc = None;
+        }
+        #[cfg(feature = "linux-usermode")]
+        if self.want_syscall {
+            self.handle_syscall();
+            self.want_syscall = false;
+        }
+        self.stop_exec = false;
+    }
+    fn exec_one(&mut self) {
+        // todo: special mrmaccessstire for instr
+        let instr = self.read32(self.pc, MemAccessStr::fetch()).unwrap();
+        if !crate::armv8::decode::decodestep1::root_decode(self, instr) {
+            self.a64_illegal_instruction();
+        }
+        self.pc += 4;
+        self.icount += 1;
+    }
     pub fn exec_one_by_one(&mut self) {
         loop {
-            // todo: special mrmaccessstire for instr
-            let instr = self.read32(self.pc, MemAccessStr::std_loadstore()).unwrap();
-            if !crate::armv8::decode::decodestep1::root_decode(self, instr) {
-                self.a64_illegal_instruction();
-            }
-            self.pc += 4;
-
+            self.exec_one();
             if self.stop_exec {
                 return;
                 // could be a trap for instr, request to jump, etc...
@@ -218,16 +263,14 @@ fn nofunc() {
 }
 #[cfg(feature = "linux-usermode")]
 impl UsermodeCpu for Arm64Cpu {
-    fn push_stack_natural(&mut self, val: u64) {
-        todo!()
+    fn get_stack_reg(&mut self) -> u64 {
+        self.stack_reg
     }
-
-    fn pop_stack_natural(&mut self) -> u64 {
-        todo!()
+    fn set_stack_reg(&mut self, val: u64) {
+        self.stack_reg = val;
     }
-
-    fn get_stack_reg(&mut self) -> u64 {
-        todo!()
+    fn retired_insns(&mut self) -> Option<u64> {
+        Some(self.icount)
     }
 
     fn get_ume(&mut self) -> &mut UserModeRuntime {
@@ -266,6 +309,12 @@ impl UsermodeCpu for Arm64Cpu {
         todo!()
     }
 
+    fn rt_frame_restore(&mut self) -> SyscallOut {
+        // rt_frame_setup() doesn't build arm64 frames yet, so there's never one to go back from
+        crate::warn_ratelimited!("rt_sigreturn on arm64, which doesn't run guest signal handlers yet");
+        errno_out(libc::ENOSYS)
+    }
+
     fn clone_thread(&mut self, sysin: SyscallIn) -> SyscallOut {
         todo!()
     }
@@ -1,6 +1,14 @@
 use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use sync::Mutex;
 use crate::armv8::interpreter::main::{Arm64Cpu};
 use crate::common::memory::MemEndian;
+use crate::common::hart_stats::Counter;
+use crate::common::poison::{PoisonAccess, PoisonMap, PoisonReport};
+#[cfg(feature = "linux-usermode")]
+use crate::linux_usermode::main::{end_guest, finish_reports, publish_cpu_time};
+#[cfg(feature = "linux-usermode")]
+use crate::linux_usermode::summary::RunExit;
 //pub ThirtyFir
 #[derive(Copy, Clone)]
 pub enum MemAccessType {
@@ -31,6 +39,12 @@ impl MemAccessStr {
             is_atomic: false
         }
     }
+    pub fn fetch() -> MemAccessStr {
+        MemAccessStr {
+            mtype: MemAccessType::Fetch,
+            is_atomic: false
+        }
+    }
     pub fn atomic_load() -> MemAccessStr {
         MemAccessStr {
             mtype: MemAccessType::LoadStore,
@@ -40,6 +54,8 @@ impl MemAccessStr {
 }
 impl Arm64Cpu {
     pub fn read8(&mut self, addr: u64, mem_type: MemAccessStr) -> Option<u8> {
+        self.count_access(Counter::Loads, Counter::LoadBytes, 1);
+        self.poison_check(addr, 1, mem_type, PoisonAccess::Read);
         let val = if mem_type.is_atomic {
             self.memory_access.read_phys_8_atomic(addr as u64,
                                                    Ordering::SeqCst)
@@ -50,6 +66,8 @@ impl Arm64Cpu {
 
     }
     pub fn read16(&mut self, addr: u64, mem_type: MemAccessStr) -> Option<u16> {
+        self.count_access(Counter::Loads, Counter::LoadBytes, 2);
+        self.poison_check(addr, 2, mem_type, PoisonAccess::Read);
         let val = if mem_type.is_atomic {
             self.memory_access.read_phys_16_atomic(addr as u64,
                                                    MemEndian::Little,
@@ -60,6 +78,8 @@ impl Arm64Cpu {
         Some(val)
     }
     pub fn read32(&mut self, addr: u64, mem_type: MemAccessStr) -> Option<u32> {
+        self.count_access(Counter::Loads, Counter::LoadBytes, 4);
+        self.poison_check(addr, 4, mem_type, PoisonAccess::Read);
         let val = if mem_type.is_atomic {
             self.memory_access.read_phys_32_atomic(addr as u64,
                                                    MemEndian::Little,
@@ -70,6 +90,8 @@ impl Arm64Cpu {
         Some(val)
     }
     pub fn read64(&mut self, addr: u64, mem_type: MemAccessStr) -> Option<u64> {
+        self.count_access(Counter::Loads, Counter::LoadBytes, 8);
+        self.poison_check(addr, 8, mem_type, PoisonAccess::Read);
         let val = if mem_type.is_atomic {
             self.memory_access.read_phys_64_atomic(addr as u64,
                                                    MemEndian::Little,
@@ -81,6 +103,8 @@ impl Arm64Cpu {
     }
 
     pub fn write8(&mut self, addr: u64, val: u8, mem_type: MemAccessStr) -> bool {
+        self.count_access(Counter::Stores, Counter::StoreBytes, 1);
+        self.poison_check(addr, 1, mem_type, PoisonAccess::Write);
         if mem_type.is_atomic {
             self.memory_access.write_phys_8_atomic(addr as u64,
                                                     val,
@@ -91,6 +115,8 @@ impl Arm64Cpu {
         false
     }
     pub fn write16(&mut self, addr: u64, val: u16, mem_type: MemAccessStr) -> bool {
+        self.count_access(Counter::Stores, Counter::StoreBytes, 2);
+        self.poison_check(addr, 2, mem_type, PoisonAccess::Write);
         if mem_type.is_atomic {
             self.memory_access.write_phys_16_atomic(addr as u64,
                                                     val, MemEndian::Little,
@@ -101,6 +127,8 @@ impl Arm64Cpu {
         false
     }
     pub fn write32(&mut self, addr: u64, val: u32, mem_type: MemAccessStr) -> bool {
+        self.count_access(Counter::Stores, Counter::StoreBytes, 4);
+        self.poison_check(addr, 4, mem_type, PoisonAccess::Write);
         if mem_type.is_atomic {
             self.memory_access.write_phys_32_atomic(addr as u64,
                                                     val, MemEndian::Little,
@@ -111,6 +139,8 @@ impl Arm64Cpu {
         false
     }
     pub fn write64(&mut self, addr: u64, val: u64, mem_type: MemAccessStr) -> bool {
+        self.count_access(Counter::Stores, Counter::StoreBytes, 8);
+        self.poison_check(addr, 8, mem_type, PoisonAccess::Write);
 
         if mem_type.is_atomic {
             self.memory_access.write_phys_64_atomic(addr as u64, val,
@@ -121,6 +151,57 @@ impl Arm64Cpu {
         }
         false
     }
+    #[inline(always)]
+    fn count_access(&self, what: Counter, bytes_counter: Counter, bytes: u64) {
+        if let Some(s) = &self.stats {
+            s.bump(what);
+            s.add(bytes_counter, bytes);
+        }
+    }
+    /// Poisoned ranges are shared, so set the same map on every thread of a guest
+    pub fn set_poison_map(&mut self, pm: Option<Arc<Mutex<PoisonMap>>>) {
+        self.poison = pm;
+    }
+    #[inline(always)]
+    fn poison_check(&mut self, addr: u64, len: u64, mem_type: MemAccessStr, access: PoisonAccess) {
+        if let MemAccessType::Fetch = mem_type.mtype {
+            return;
+        }
+        if let Some(pm) = &self.poison {
+            let pm = pm.clone();
+            self.poison_check_slow(pm, addr, len, access);
+        }
+    }
+    fn poison_check_slow(&mut self, pm: Arc<Mutex<PoisonMap>>, addr: u64, len: u64, access: PoisonAccess) {
+        let hit = pm.lock().lookup(addr, len).map(|(s, l)| (s, l.to_string()));
+        if let Some((range_start, label)) = hit {
+            let rep = PoisonReport {
+                pc: self.pc,
+                addr,
+                len,
+                access,
+                range_start,
+                label,
+                // no frame walking here, the link register is the one return address we know
+                backtrace: vec![self.get_reg(30, false)],
+            };
+            if pm.lock().report(rep) {
+                self.poison_abort();
+            }
+        }
+    }
+    // the abort policy, like riscv's
+    fn poison_abort(&mut self) {
+        #[cfg(feature = "linux-usermode")]
+        if self.is_usermode {
+            publish_cpu_time(&mut self.user_struct, self.icount);
+            finish_reports(&self.user_struct, RunExit::Exit(1));
+            end_guest(&mut self.user_struct, 1);
+            self.stop_exec = true;
+            return;
+        }
+        std::process::exit(1);
+    }
     pub fn set_exclusive_monitors(&mut self, addr: u64, size: u64) {
         self.mdata.exc_addr = addr;
         self.mdata.exc_size = size;
@@ -16,4 +16,5 @@ pub mod decodeimpl;
 pub mod defs;
 pub mod mem;
 pub mod system;
+pub mod engine;
 // mod armv8;
\ No newline at end of file
@@ -2,359 +2,10 @@ use libc::sysinfo;
 use crate::common::memory::MemEndian;
 use crate::linux_usermode::defs::{GenericStat, write16_advance_ptr, write32_advance_ptr, write64_advance_ptr};
 use crate::linux_usermode::main::SyscallType;
-
-pub const ARM64_SYS_IO_SETUP: u32 = 0;
-pub const ARM64_SYS_IO_DESTROY: u32 = 1;
-pub const ARM64_SYS_IO_SUBMIT: u32 = 2;
-pub const ARM64_SYS_IO_CANCEL: u32 = 3;
-pub const ARM64_SYS_IO_GETEVENTS: u32 = 4;
-pub const ARM64_SYS_SETXATTR: u32 = 5;
-pub const ARM64_SYS_LSETXATTR: u32 = 6;
-pub const ARM64_SYS_FSETXATTR: u32 = 7;
-pub const ARM64_SYS_GETXATTR: u32 = 8;
-pub const ARM64_SYS_LGETXATTR: u32 = 9;
-pub const ARM64_SYS_FGETXATTR: u32 = 10;
-pub const ARM64_SYS_LISTXATTR: u32 = 11;
-pub const ARM64_SYS_LLISTXATTR: u32 = 12;
-pub const ARM64_SYS_FLISTXATTR: u32 = 13;
-pub const ARM64_SYS_REMOVEXATTR: u32 = 14;
-pub const ARM64_SYS_LREMOVEXATTR: u32 = 15;
-pub const ARM64_SYS_FREMOVEXATTR: u32 = 16;
-pub const ARM64_SYS_GETCWD: u32 = 17;
-pub const ARM64_SYS_LOOKUP_DCOOKIE: u32 = 18;
-pub const ARM64_SYS_EVENTFD2: u32 = 19;
-pub const ARM64_SYS_EPOLL_CREATE1: u32 = 20;
-pub const ARM64_SYS_EPOLL_CTL: u32 = 21;
-pub const ARM64_SYS_EPOLL_PWAIT: u32 = 22;
-pub const ARM64_SYS_DUP: u32 = 23;
-pub const ARM64_SYS_DUP3: u32 = 24;
-pub const ARM64_SYS_FCNTL: u32 = 25;
-pub const ARM64_SYS_INOTIFY_INIT1: u32 = 26;
-pub const ARM64_SYS_INOTIFY_ADD_WATCH: u32 = 27;
-pub const ARM64_SYS_INOTIFY_RM_WATCH: u32 = 28;
-pub const ARM64_SYS_IOCTL: u32 = 29;
-pub const ARM64_SYS_IOPRIO_SET: u32 = 30;
-pub const ARM64_SYS_IOPRIO_GET: u32 = 31;
-pub const ARM64_SYS_FLOCK: u32 = 32;
-pub const ARM64_SYS_MKNODAT: u32 = 33;
-pub const ARM64_SYS_MKDIRAT: u32 = 34;
-pub const ARM64_SYS_UNLINKAT: u32 = 35;
-pub const ARM64_SYS_SYMLINKAT: u32 = 36;
-pub const ARM64_SYS_LINKAT: u32 = 37;
-pub const ARM64_SYS_RENAMEAT: u32 = 38;
-pub const ARM64_SYS_UMOUNT2: u32 = 39;
-pub const ARM64_SYS_MOUNT: u32 = 40;
-pub const ARM64_SYS_PIVOT_ROOT: u32 = 41;
-pub const ARM64_SYS_NFSSERVCTL: u32 = 42;
-pub const ARM64_SYS_STATFS: u32 = 43;
-pub const ARM64_SYS_FSTATFS: u32 = 44;
-pub const ARM64_SYS_TRUNCATE: u32 = 45;
-pub const ARM64_SYS_FTRUNCATE: u32 = 46;
-pub const ARM64_SYS_FALLOCATE: u32 = 47;
-pub const ARM64_SYS_FACCESSAT: u32 = 48;
-pub const ARM64_SYS_CHDIR: u32 = 49;
-pub const ARM64_SYS_FCHDIR: u32 = 50;
-pub const ARM64_SYS_CHROOT: u32 = 51;
-pub const ARM64_SYS_FCHMOD: u32 = 52;
-pub const ARM64_SYS_FCHMODAT: u32 = 53;
-pub const ARM64_SYS_FCHOWNAT: u32 = 54;
-pub const ARM64_SYS_FCHOWN: u32 = 55;
-pub const ARM64_SYS_OPENAT: u32 = 56;
-pub const ARM64_SYS_CLOSE: u32 = 57;
-pub const ARM64_SYS_VHANGUP: u32 = 58;
-pub const ARM64_SYS_PIPE2: u32 = 59;
-pub const ARM64_SYS_QUOTACTL: u32 = 60;
-pub const ARM64_SYS_GETDENTS64: u32 = 61;
-pub const ARM64_SYS_LSEEK: u32 = 62;
-pub const ARM64_SYS_READ: u32 = 63;
-pub const ARM64_SYS_WRITE: u32 = 64;
-pub const ARM64_SYS_READV: u32 = 65;
-pub const ARM64_SYS_WRITEV: u32 = 66;
-pub const ARM64_SYS_PREAD64: u32 = 67;
-pub const ARM64_SYS_PWRITE64: u32 = 68;
-pub const ARM64_SYS_PREADV: u32 = 69;
-pub const ARM64_SYS_PWRITEV: u32 = 70;
-pub const ARM64_SYS_SENDFILE: u32 = 71;
-pub const ARM64_SYS_PSELECT6: u32 = 72;
-pub const ARM64_SYS_PPOLL: u32 = 73;
-pub const ARM64_SYS_SIGNALFD4: u32 = 74;
-pub const ARM64_SYS_VMSPLICE: u32 = 75;
-pub const ARM64_SYS_SPLICE: u32 = 76;
-pub const ARM64_SYS_TEE: u32 = 77;
-pub const ARM64_SYS_READLINKAT: u32 = 78;
-pub const ARM64_SYS_FSTATAT: u32 = 79;
-pub const ARM64_SYS_FSTAT: u32 = 80;
-pub const ARM64_SYS_SYNC: u32 = 81;
-pub const ARM64_SYS_FSYNC: u32 = 82;
-pub const ARM64_SYS_FDATASYNC: u32 = 83;
-pub const ARM64_SYS_SYNC_FILE_RANGE: u32 = 84;
-pub const ARM64_SYS_TIMERFD_CREATE: u32 = 85;
-pub const ARM64_SYS_TIMERFD_SETTIME: u32 = 86;
-pub const ARM64_SYS_TIMERFD_GETTIME: u32 = 87;
-pub const ARM64_SYS_UTIMENSAT: u32 = 88;
-pub const ARM64_SYS_ACCT: u32 = 89;
-pub const ARM64_SYS_CAPGET: u32 = 90;
-pub const ARM64_SYS_CAPSET: u32 = 91;
-pub const ARM64_SYS_PERSONALITY: u32 = 92;
-pub const ARM64_SYS_EXIT: u32 = 93;
-pub const ARM64_SYS_EXIT_GROUP: u32 = 94;
-pub const ARM64_SYS_WAITID: u32 = 95;
-pub const ARM64_SYS_SET_TID_ADDRESS: u32 = 96;
-pub const ARM64_SYS_UNSHARE: u32 = 97;
-pub const ARM64_SYS_FUTEX: u32 = 98;
-pub const ARM64_SYS_SET_ROBUST_LIST: u32 = 99;
-pub const ARM64_SYS_GET_ROBUST_LIST: u32 = 100;
-pub const ARM64_SYS_NANOSLEEP: u32 = 101;
-pub const ARM64_SYS_GETITIMER: u32 = 102;
-pub const ARM64_SYS_SETITIMER: u32 = 103;
-pub const ARM64_SYS_KEXEC_LOAD: u32 = 104;
-pub const ARM64_SYS_INIT_MODULE: u32 = 105;
-pub const ARM64_SYS_DELETE_MODULE: u32 = 106;
-pub const ARM64_SYS_TIMER_CREATE: u32 = 107;
-pub const ARM64_SYS_TIMER_GETTIME: u32 = 108;
-pub const ARM64_SYS_TIMER_GETOVERRUN: u32 = 109;
-pub const ARM64_SYS_TIMER_SETTIME: u32 = 110;
-pub const ARM64_SYS_TIMER_DELETE: u32 = 111;
-pub const ARM64_SYS_CLOCK_SETTIME: u32 = 112;
-pub const ARM64_SYS_CLOCK_GETTIME: u32 = 113;
-pub const ARM64_SYS_CLOCK_GETRES: u32 = 114;
-pub const ARM64_SYS_CLOCK_NANOSLEEP: u32 = 115;
-pub const ARM64_SYS_SYSLOG: u32 = 116;
-pub const ARM64_SYS_PTRACE: u32 = 117;
-pub const ARM64_SYS_SCHED_SETPARAM: u32 = 118;
-pub const ARM64_SYS_SCHED_SETSCHEDULER: u32 = 119;
-pub const ARM64_SYS_SCHED_GETSCHEDULER: u32 = 120;
-pub const ARM64_SYS_SCHED_GETPARAM: u32 = 121;
-pub const ARM64_SYS_SCHED_SETAFFINITY: u32 = 122;
-pub const ARM64_SYS_SCHED_GETAFFINITY: u32 = 123;
-pub const ARM64_SYS_SCHED_YIELD: u32 = 124;
-pub const ARM64_SYS_SCHED_GET_PRIORITY_MAX: u32 = 125;
-pub const ARM64_SYS_SCHED_GET_PRIORITY_MIN: u32 = 126;
-pub const ARM64_SYS_SCHED_RR_GET_INTERVAL: u32 = 127;
-pub const ARM64_SYS_RESTART_SYSCALL: u32 = 128;
-pub const ARM64_SYS_KILL: u32 = 129;
-pub const ARM64_SYS_TKILL: u32 = 130;
-pub const ARM64_SYS_TGKILL: u32 = 131;
-pub const ARM64_SYS_SIGALTSTACK: u32 = 132;
-pub const ARM64_SYS_RT_SIGSUSPEND: u32 = 133;
-pub const ARM64_SYS_RT_SIGACTION: u32 = 134;
-pub const ARM64_SYS_RT_SIGPROCMASK: u32 = 135;
-pub const ARM64_SYS_RT_SIGPENDING: u32 = 136;
-pub const ARM64_SYS_RT_SIGTIMEDWAIT: u32 = 137;
-pub const ARM64_SYS_RT_SIGQUEUEINFO: u32 = 138;
-pub const ARM64_SYS_RT_SIGRETURN: u32 = 139;
-pub const ARM64_SYS_SETPRIORITY: u32 = 140;
-pub const ARM64_SYS_GETPRIORITY: u32 = 141;
-pub const ARM64_SYS_REBOOT: u32 = 142;
-pub const ARM64_SYS_SETREGID: u32 = 143;
-pub const ARM64_SYS_SETGID: u32 = 144;
-pub const ARM64_SYS_SETREUID: u32 = 145;
-pub const ARM64_SYS_SETUID: u32 = 146;
-pub const ARM64_SYS_SETRESUID: u32 = 147;
-pub const ARM64_SYS_GETRESUID: u32 = 148;
-pub const ARM64_SYS_SETRESGID: u32 = 149;
-pub const ARM64_SYS_GETRESGID: u32 = 150;
-pub const ARM64_SYS_SETFSUID: u32 = 151;
-pub const ARM64_SYS_SETFSGID: u32 = 152;
-pub const ARM64_SYS_TIMES: u32 = 153;
-pub const ARM64_SYS_SETPGID: u32 = 154;
-pub const ARM64_SYS_GETPGID: u32 = 155;
-pub const ARM64_SYS_GETSID: u32 = 156;
-pub const ARM64_SYS_SETSID: u32 = 157;
-pub const ARM64_SYS_GETGROUPS: u32 = 158;
-pub const ARM64_SYS_SETGROUPS: u32 = 159;
-pub const ARM64_SYS_UNAME: u32 = 160;
-pub const ARM64_SYS_SETHOSTNAME: u32 = 161;
-pub const ARM64_SYS_SETDOMAINNAME: u32 = 162;
-pub const ARM64_SYS_GETRLIMIT: u32 = 163;
-pub const ARM64_SYS_SETRLIMIT: u32 = 164;
-pub const ARM64_SYS_GETRUSAGE: u32 = 165;
-pub const ARM64_SYS_UMASK: u32 = 166;
-pub const ARM64_SYS_PRCTL: u32 = 167;
-pub const ARM64_SYS_GETCPU: u32 = 168;
-pub const ARM64_SYS_GETTIMEOFDAY: u32 = 169;
-pub const ARM64_SYS_SETTIMEOFDAY: u32 = 170;
-pub const ARM64_SYS_ADJTIMEX: u32 = 171;
-pub const ARM64_SYS_GETPID: u32 = 172;
-pub const ARM64_SYS_GETPPID: u32 = 173;
-pub const ARM64_SYS_GETUID: u32 = 174;
-pub const ARM64_SYS_GETEUID: u32 = 175;
-pub const ARM64_SYS_GETGID: u32 = 176;
-pub const ARM64_SYS_GETEGID: u32 = 177;
-pub const ARM64_SYS_GETTID: u32 = 178;
-pub const ARM64_SYS_SYSINFO: u32 = 179;
-pub const ARM64_SYS_MQ_OPEN: u32 = 180;
-pub const ARM64_SYS_MQ_UNLINK: u32 = 181;
-pub const ARM64_SYS_MQ_TIMEDSEND: u32 = 182;
-pub const ARM64_SYS_MQ_TIMEDRECEIVE: u32 = 183;
-pub const ARM64_SYS_MQ_NOTIFY: u32 = 184;
-pub const ARM64_SYS_MQ_GETSETATTR: u32 = 185;
-pub const ARM64_SYS_MSGGET: u32 = 186;
-pub const ARM64_SYS_MSGCTL: u32 = 187;
-pub const ARM64_SYS_MSGRCV: u32 = 188;
-pub const ARM64_SYS_MSGSND: u32 = 189;
-pub const ARM64_SYS_SEMGET: u32 = 190;
-pub const ARM64_SYS_SEMCTL: u32 = 191;
-pub const ARM64_SYS_SEMTIMEDOP: u32 = 192;
-pub const ARM64_SYS_SEMOP: u32 = 193;
-pub const ARM64_SYS_SHMGET: u32 = 194;
-pub const ARM64_SYS_SHMCTL: u32 = 195;
-pub const ARM64_SYS_SHMAT: u32 = 196;
-pub const ARM64_SYS_SHMDT: u32 = 197;
-pub const ARM64_SYS_SOCKET: u32 = 198;
-pub const ARM64_SYS_SOCKETPAIR: u32 = 199;
-pub const ARM64_SYS_BIND: u32 = 200;
-pub const ARM64_SYS_LISTEN: u32 = 201;
-pub const ARM64_SYS_ACCEPT: u32 = 202;
-pub const ARM64_SYS_CONNECT: u32 = 203;
-pub const ARM64_SYS_GETSOCKNAME: u32 = 204;
-pub const ARM64_SYS_GETPEERNAME: u32 = 205;
-pub const ARM64_SYS_SENDTO: u32 = 206;
-pub const ARM64_SYS_RECVFROM: u32 = 207;
-pub const ARM64_SYS_SETSOCKOPT: u32 = 208;
-pub const ARM64_SYS_GETSOCKOPT: u32 = 209;
-pub const ARM64_SYS_SHUTDOWN: u32 = 210;
-pub const ARM64_SYS_SENDMSG: u32 = 211;
-pub const ARM64_SYS_RECVMSG: u32 = 212;
-pub const ARM64_SYS_READAHEAD: u32 = 213;
-pub const ARM64_SYS_BRK: u32 = 214;
-pub const ARM64_SYS_MUNMAP: u32 = 215;
-pub const ARM64_SYS_MREMAP: u32 = 216;
-pub const ARM64_SYS_ADD_KEY: u32 = 217;
-pub const ARM64_SYS_REQUEST_KEY: u32 = 218;
-pub const ARM64_SYS_KEYCTL: u32 = 219;
-pub const ARM64_SYS_CLONE: u32 = 220;
-pub const ARM64_SYS_EXECVE: u32 = 221;
-pub const ARM64_SYS_MMAP: u32 = 222;
-pub const ARM64_SYS_FADVISE64: u32 = 223;
-pub const ARM64_SYS_SWAPON: u32 = 224;
-pub const ARM64_SYS_SWAPOFF: u32 = 225;
-pub const ARM64_SYS_MPROTECT: u32 = 226;
-pub const ARM64_SYS_MSYNC: u32 = 227;
-pub const ARM64_SYS_MLOCK: u32 = 228;
-pub const ARM64_SYS_MUNLOCK: u32 = 229;
-pub const ARM64_SYS_MLOCKALL: u32 = 230;
-pub const ARM64_SYS_MUNLOCKALL: u32 = 231;
-pub const ARM64_SYS_MINCORE: u32 = 232;
-pub const ARM64_SYS_MADVISE: u32 = 233;
-pub const ARM64_SYS_REMAP_FILE_PAGES: u32 = 234;
-pub const ARM64_SYS_MBIND: u32 = 235;
-pub const ARM64_SYS_GET_MEMPOLICY: u32 = 236;
-pub const ARM64_SYS_SET_MEMPOLICY: u32 = 237;
-pub const ARM64_SYS_MIGRATE_PAGES: u32 = 238;
-pub const ARM64_SYS_MOVE_PAGES: u32 = 239;
-pub const ARM64_SYS_RT_TGSIGQUEUEINFO: u32 = 240;
-pub const ARM64_SYS_PERF_EVENT_OPEN: u32 = 241;
-pub const ARM64_SYS_ACCEPT4: u32 = 242;
-pub const ARM64_SYS_RECVMMSG: u32 = 243;
-pub const ARM64_SYS_ARCH_SPECIFIC_SYSCALL: u32 = 244;
-pub const ARM64_SYS_WAIT4: u32 = 260;
-pub const ARM64_SYS_PRLIMIT64: u32 = 261;
-pub const ARM64_SYS_FANOTIFY_INIT: u32 = 262;
-pub const ARM64_SYS_FANOTIFY_MARK: u32 = 263;
-pub const ARM64_SYS_NAME_TO_HANDLE_AT: u32 = 264;
-pub const ARM64_SYS_OPEN_BY_HANDLE_AT: u32 = 265;
-pub const ARM64_SYS_CLOCK_ADJTIME: u32 = 266;
-pub const ARM64_SYS_SYNCFS: u32 = 267;
-pub const ARM64_SYS_SETNS: u32 = 268;
-pub const ARM64_SYS_SENDMMSG: u32 = 269;
-pub const ARM64_SYS_PROCESS_VM_READV: u32 = 270;
-pub const ARM64_SYS_PROCESS_VM_WRITEV: u32 = 271;
-pub const ARM64_SYS_KCMP: u32 = 272;
-pub const ARM64_SYS_FINIT_MODULE: u32 = 273;
-pub const ARM64_SYS_SCHED_SETATTR: u32 = 274;
-pub const ARM64_SYS_SCHED_GETATTR: u32 = 275;
-pub const ARM64_SYS_RENAMEAT2: u32 = 276;
-pub const ARM64_SYS_SECCOMP: u32 = 277;
-pub const ARM64_SYS_GETRANDOM: u32 = 278;
-pub const ARM64_SYS_MEMFD_CREATE: u32 = 279;
-pub const ARM64_SYS_BPF: u32 = 280;
-pub const ARM64_SYS_EXECVEAT: u32 = 281;
-pub const ARM64_SYS_USERFAULTFD: u32 = 282;
-pub const ARM64_SYS_MEMBARRIER: u32 = 283;
-pub const ARM64_SYS_MLOCK2: u32 = 284;
-pub const ARM64_SYS_COPY_FILE_RANGE: u32 = 285;
-pub const ARM64_SYS_PREADV2: u32 = 286;
-pub const ARM64_SYS_PWRITEV2: u32 = 287;
-pub const ARM64_SYS_PKEY_MPROTECT: u32 = 288;
-pub const ARM64_SYS_PKEY_ALLOC: u32 = 289;
-pub const ARM64_SYS_PKEY_FREE: u32 = 290;
-pub const ARM64_SYS_STATX: u32 = 291;
-pub const ARM64_SYS_IO_PGETEVENTS: u32 = 292;
-pub const ARM64_SYS_RSEQ: u32 = 293;
-pub const ARM64_SYS_KEXEC_FILE_LOAD: u32 = 294;
-pub const ARM64_SYS_PIDFD_SEND_SIGNAL: u32 = 424;
-pub const ARM64_SYS_IO_URING_SETUP: u32 = 425;
-pub const ARM64_SYS_IO_URING_ENTER: u32 = 426;
-pub const ARM64_SYS_IO_URING_REGISTER: u32 = 427;
-pub const ARM64_SYS_OPEN_TREE: u32 = 428;
-pub const ARM64_SYS_MOVE_MOUNT: u32 = 429;
-pub const ARM64_SYS_FSOPEN: u32 = 430;
-pub const ARM64_SYS_FSCONFIG: u32 = 431;
-pub const ARM64_SYS_FSMOUNT: u32 = 432;
-pub const ARM64_SYS_FSPICK: u32 = 433;
-pub const ARM64_SYS_PIDFD_OPEN: u32 = 434;
-pub const ARM64_SYS_CLONE3: u32 = 435;
-pub const ARM64_SYS_CLOSE_RANGE: u32 = 436;
-pub const ARM64_SYS_OPENAT2: u32 = 437;
-pub const ARM64_SYS_PIDFD_GETFD: u32 = 438;
-pub const ARM64_SYS_FACCESSAT2: u32 = 439;
-pub const ARM64_SYS_PROCESS_MADVISE: u32 = 440;
-pub const ARM64_SYS_EPOLL_PWAIT2: u32 = 441;
-pub const ARM64_SYS_MOUNT_SETATTR: u32 = 442;
-pub const ARM64_SYS_QUOTACTL_FD: u32 = 443;
-pub const ARM64_SYS_LANDLOCK_CREATE_RULESET: u32 = 444;
-pub const ARM64_SYS_LANDLOCK_ADD_RULE: u32 = 445;
-pub const ARM64_SYS_LANDLOCK_RESTRICT_SELF: u32 = 446;
-pub const ARM64_SYS_MEMFD_SECRET: u32 = 447;
-pub const ARM64_SYS_PROCESS_MRELEASE: u32 = 448;
-pub const ARM64_SYS_FUTEX_WAITV: u32 = 449;
-pub const ARM64_SYS_SET_MEMPOLICY_HOME_NODE: u32 = 450;
+use crate::linux_usermode::syscall_table::ARM64_SYSCALLS;
 
 pub fn arm64_translate_syscall(val: u32) -> Option<SyscallType> {
-    match val {
-        ARM64_SYS_PPOLL => Some(SyscallType::Ppoll),
-        ARM64_SYS_SET_TID_ADDRESS => Some(SyscallType::SetTidAddr),
-        ARM64_SYS_IOCTL => Some(SyscallType::Ioctl),
-        ARM64_SYS_WRITE => Some(SyscallType::Write),
-        ARM64_SYS_WRITEV => Some(SyscallType::Writev),
-        ARM64_SYS_EXIT_GROUP => Some(SyscallType::ExitGroup),
-        ARM64_SYS_RT_SIGPROCMASK => Some(SyscallType::Sigprocmask),
-        ARM64_SYS_FCNTL => Some(SyscallType::Fcntl),
-        ARM64_SYS_GETEUID => Some(SyscallType::Geteuid),
-        ARM64_SYS_PIPE2 => Some(SyscallType::Pipe2),
-        ARM64_SYS_BRK => Some(SyscallType::Brk),
-        ARM64_SYS_FADVISE64 => Some(SyscallType::Fadvise64),
-        ARM64_SYS_RT_SIGACTION => Some(SyscallType::Sigaction),
-        ARM64_SYS_SYSINFO => Some(SyscallType::Sysinfo),
-        ARM64_SYS_MMAP => Some(SyscallType::Mmap),
-        ARM64_SYS_CLOSE => Some(SyscallType::Close),
-        ARM64_SYS_FSTAT => Some(SyscallType::Fstat),
-        ARM64_SYS_CLOCK_GETTIME => Some(SyscallType::ClockGetTime),
-        ARM64_SYS_READ => Some(SyscallType::Read),
-        ARM64_SYS_OPENAT => Some(SyscallType::Openat),
-        ARM64_SYS_FCHOWN => Some(SyscallType::Fchown),
-        ARM64_SYS_FCHMOD => Some(SyscallType::Fchmod),
-        ARM64_SYS_UTIMENSAT => Some(SyscallType::Utimensat),
-        ARM64_SYS_UNAME => Some(SyscallType::Uname),
-        ARM64_SYS_FTRUNCATE => Some(SyscallType::Ftruncate),
-        ARM64_SYS_FACCESSAT => Some(SyscallType::Faccessat),
-        ARM64_SYS_FSTATAT => Some(SyscallType::Fstatat),
-        ARM64_SYS_MUNMAP => Some(SyscallType::Munmap),
-        ARM64_SYS_MPROTECT => Some(SyscallType::Mprotect),
-        ARM64_SYS_SET_ROBUST_LIST => Some(SyscallType::SetRobustList),
-        ARM64_SYS_RSEQ => Some(SyscallType::Rseq),
-        ARM64_SYS_PRLIMIT64 => Some(SyscallType::Prlimit64),
-        ARM64_SYS_READLINKAT => Some(SyscallType::Readlinkat),
-        ARM64_SYS_GETRANDOM => Some(SyscallType::Getrandom),
-        ARM64_SYS_FUTEX => Some(SyscallType::Futex),
-        ARM64_SYS_GETTID => Some(SyscallType::Gettid),
-        ARM64_SYS_GETPID => Some(SyscallType::Getpid),
-        ARM64_SYS_GETRLIMIT => Some(SyscallType::Getrlimit),
-        ARM64_SYS_SIGALTSTACK => Some(SyscallType::Sigaltstack),
-        ARM64_SYS_SCHED_GETAFFINITY => Some(SyscallType::Getaffinity),
-        _ => None
-    }
+    ARM64_SYSCALLS.translate(val as u64)
 }
 pub fn write_arm64_stat(addr: u64, end: MemEndian, stat: GenericStat) {
     let mut realaddr = addr;
@@ -1,14 +1,18 @@
-use std::ffi::CString;
 use std::sync::Arc;
 use base::platform::MemoryMapping;
-use base::{debug, gettid, info, MappedRegion, Protection};
+use base::{gettid, info, MappedRegion, Protection};
 use goblin::elf::Elf;
 use sync::Mutex;
 use crate::armv8::common::ARM64_PAGE_SIZE;
 use crate::armv8::interpreter::main::Arm64Cpu;
-use crate::common::memory::{flat_mem, MemEndian};
-use crate::elf::{AuxType, Auxv, MachineType, MemState, UserModeInit, UserModeRuntime};
+use crate::common::memory::flat_mem;
+use crate::elf::{initResult, AuxType, Auxv, Error, MachineType, MemState, UserModeInit, UserModeRuntime};
 use crate::linux_usermode::defs::SigConstants;
+use crate::linux_usermode::signals::GuestSignals;
+use crate::common::hotpatch::PatchGate;
+use crate::linux_usermode::memusage::MemUsage;
+use crate::linux_usermode::stack::{build_initial_stack, StackError};
+use crate::linux_usermode::main::catch_group_exit;
 
 pub fn init_arm64_runtime(ef: &Elf) -> UserModeRuntime {
     let is64 = ef.is_64;
@@ -33,6 +37,8 @@ pub fn init_arm64_runtime(ef: &Elf) -> UserModeRuntime {
         intrp_idx: None,
         args: vec![],
         envp: vec![],
+        exe_link: String::new(),
+        dl: None,
     };
     UserModeRuntime {
         initvars: Arc::new(Mutex::new(ival)),
@@ -52,33 +58,28 @@ pub fn init_arm64_runtime(ef: &Elf) -> UserModeRuntime {
         str_path: "".to_string(),
         tid_val: gettid() as u64,
         flags: 0,
+        opts: Default::default(),
+        sched: None,
+        sched_event: None,
+        local_locks: Arc::new(Default::default()),
+        ids: None,
+        fakeroot: None,
+        cputime: Arc::new(Default::default()),
+        cpu_slot: None,
+        icount_base: 0,
+        summary: None,
+        isa: None,
+        signals: GuestSignals::new(),
+        memusage: Arc::new(MemUsage::new()),
+        stats: None,
+        hart_stats: None,
+        symbols: Arc::new(Mutex::new(None)),
+        group_exit: Arc::new(Default::default()),
+        console: Arc::new(Default::default()),
+        patch_gate: Arc::new(PatchGate::new()),
         ctid_val: 0
     }
 }
-fn push_stack_val(ai: &mut Arm64Cpu, val: u64) {
-    let ms = ai.user_struct.memstate.lock();
-    if (ms.stack_base - ms.stack_size) > ai.stack_reg {
-        panic!("ran out stack")
-    }
-    ai.stack_reg -= 8;
-    ai.memory_access.write_phys_64(ai.stack_reg, val, MemEndian::Little);
-
-}
-fn push_stack(ri: &mut Arm64Cpu, val: &[u8]) {
-    let ms = ri.user_struct.memstate.lock();
-    ri.stack_reg -= val.len() as u64;
-    if (ms.stack_base - ms.stack_size) > ri.stack_reg {
-        panic!("ran out stack")
-    }
-    let mut stack_ptr_up = ri.stack_reg as *mut u8;
-    for i in val {
-        unsafe {
-            *stack_ptr_up = *i;
-            stack_ptr_up = stack_ptr_up.add(1);
-        }
-
-    }
-}
 fn map_stack(ri: &mut Arm64Cpu) {
     let mut ms = ri.user_struct.memstate.lock();
     let mapreg = MemoryMapping::new_protection_fixed(
@@ -87,10 +88,11 @@ fn map_stack(ri: &mut Arm64Cpu) {
         , Protection::read_write_execute(),
         false).unwrap();
     ri.stack_reg = ms.stack_base;
+    ri.user_struct.memusage.map_loader(ms.stack_base - ms.stack_size, ms.stack_size, true);
     ms.mem_maps.push(mapreg);
 
 }
-pub fn init_stack(ri: &mut Arm64Cpu, ef: &Elf) {
+pub fn init_stack(ri: &mut Arm64Cpu, ef: &Elf) -> Result<(), StackError> {
     ri.stack_reg -= 16;
     // let ms = &mut ume.memstate;
     let random_ptr = ri.get_stack_reg();
@@ -117,47 +119,13 @@ pub fn init_stack(ri: &mut Arm64Cpu, ef: &Elf) {
     auxv.push(Auxv { typ: AuxType::Null, value: 0 as u64 });
     auxv.push(Auxv { typ: AuxType::ExecFn, value: 0 as u64 });
 
-    let subval = 8;
-    let envpclone = iv.envp.clone();
-    let argclone = iv.args.clone();
+    let envp = iv.envp.clone();
+    let args = iv.args.clone();
     drop(iv);
-    let mut envPtrs: Vec<u64> = Vec::new();
-    for i in &envpclone {
-        let pval = CString::new(i.clone().as_bytes()).unwrap().into_bytes_with_nul();
-        debug!("going to write env val value {} to addr 0x{:x}", i, ri.get_stack_reg() - (pval.len() as u64));
-        push_stack(ri, &pval);
-        envPtrs.push(ri.get_stack_reg())
-    }
-    envPtrs.push(0);
-    let mut argPtrs: Vec<u64> = Vec::new();
-    for i in &argclone {
-        let pval = CString::new(i.clone().as_bytes()).unwrap().into_bytes_with_nul();
-        debug!("going to write arg val value {} to addr 0x{:x}", i, ri.get_stack_reg() - (pval.len() as u64));
-        push_stack(ri,  &pval);
-        argPtrs.push(ri.get_stack_reg())
-    }
-    argPtrs.push(0);
-    ri.stack_reg &= !15;
-    for i in auxv.into_iter().rev() {
-        debug!("going to write aux value {} to addr 0x{:x}", i.value, ri.get_stack_reg() - subval);
-        push_stack_val(ri, i.value as u64);
-        debug!("going to write aux key {:?} to addr 0x{:x}", i.typ, ri.get_stack_reg() - subval);
-        push_stack_val(ri,  i.typ as u64);
-    }
-    for i in envPtrs.into_iter().rev() {
-        debug!("going to write envp ptr 0x{:x} to addr 0x{:x}", i, ri.get_stack_reg() - subval);
-        push_stack_val(ri,  i as u64);
-    }
-    for i in argPtrs.into_iter().rev() {
-        // the last valuee should be higher on stack
-        debug!("going to write arg ptr 0x{:x} to addr 0x{:x}", i, ri.get_stack_reg() - subval);
-        push_stack_val(ri,  i as u64);
-    }
-    let argc = ri.user_struct.initvars.lock().args.len() as u64;
-    push_stack_val(ri, argc);
-
+    build_initial_stack(ri, &args, &envp, &auxv)
 }
-pub fn init_arm64_ume(ume: UserModeRuntime, ef: &Elf) {
+/// Runs the guest, returns its exit status
+pub fn init_arm64_ume(ume: UserModeRuntime, ef: &Elf) -> initResult<i32> {
     let iv = ume.initvars.lock();
 
     let mut maxaddr = iv.objects[iv.obj_idx.unwrap()].mem_range.end;
@@ -170,12 +138,17 @@ pub fn init_arm64_ume(ume: UserModeRuntime, ef: &Elf) {
     drop(iv);
     let mut arm64cpu = Arm64Cpu::init_usermode(ume);
     map_stack(&mut arm64cpu);
-    init_stack(&mut arm64cpu, ef);
+    init_stack(&mut arm64cpu, ef).map_err(Error::Stack)?;
+    let dl = arm64cpu.user_struct.initvars.lock().dl.take();
+    // the built-in loader refuses arm64 guests with IFUNCs or initializers, so this is all there is
+    if let Some(tp) = dl.and_then(|dl| dl.tp) {
+        arm64cpu.tpidr[0] = tp;
+    }
     arm64cpu.pc = arm64cpu.user_struct.initvars.lock().real_entry_point;
     //rm64cpu.set_reg(1, 1, false);
     //arm64cpu.set_reg(2, 2, false);
 
-    arm64cpu.run();
-    // anything below run() should not happen.
-    unreachable!("arm64 processor error")
+    let group_exit = arm64cpu.user_struct.group_exit.clone();
+    // it only comes back from run() by exit_group()
+    Ok(catch_group_exit(&group_exit, || arm64cpu.run()).expect("arm64 processor error"))
 }
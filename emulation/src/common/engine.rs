@@ -0,0 +1,107 @@
+// What a CPU front end needs from whatever runs the guest code and holds guest memory, so another
+// backend can be dropped in and, more to the point, checked against the one we have.
+// lockstep() is the check: two engines that start from the same state run the same number of
+// instructions at a time, and the registers (and any memory ranges asked for) have to match after
+// every step. Pairing the block-caching interpreter with an engine that decodes every instruction
+// as it goes (riscv's OracleEngine) catches cache bugs like stale blocks or wrong block ends.
+use vm_memory::{GuestAddress, GuestMemory};
+use crate::common::memory::{flat_mem, MemError};
+
+/// Guest physical memory, whatever it's backed by
+pub trait MemoryBackend {
+    fn read_bytes(&mut self, addr: u64, len: usize) -> Result<Vec<u8>, MemError>;
+    fn write_bytes(&mut self, addr: u64, data: &[u8]) -> Result<(), MemError>;
+}
+// what a guest's own loads and stores go through. In usermode that's host memory, unchecked
+impl MemoryBackend for flat_mem {
+    fn read_bytes(&mut self, addr: u64, len: usize) -> Result<Vec<u8>, MemError> {
+        self.read_phys_n(addr, len)
+    }
+    fn write_bytes(&mut self, addr: u64, data: &[u8]) -> Result<(), MemError> {
+        self.write_phys_n(addr, data.to_vec())
+    }
+}
+// system mode RAM on its own: a range that isn't all RAM is an error, not a short read
+impl MemoryBackend for GuestMemory {
+    fn read_bytes(&mut self, addr: u64, len: usize) -> Result<Vec<u8>, MemError> {
+        let mut buf = vec![0; len];
+        self.read_exact_at_addr(&mut buf, GuestAddress(addr)).map_err(MemError::FlatErr)?;
+        Ok(buf)
+    }
+    fn write_bytes(&mut self, addr: u64, data: &[u8]) -> Result<(), MemError> {
+        self.write_all_at_addr(data, GuestAddress(addr)).map_err(MemError::FlatErr)
+    }
+}
+pub trait ExecutionEngine {
+    /// Get the code at `pc` ready to run. Engines that don't cache anything have nothing to do
+    fn translate(&mut self, pc: u64);
+    /// Run `max_insns` instructions, dealing with traps and syscalls on the way
+    fn run(&mut self, max_insns: u64);
+    /// Guest code in [addr, addr + len) changed, forget anything made from it
+    fn invalidate(&mut self, addr: u64, len: u64);
+    fn pc(&self) -> u64;
+    /// instructions retired so far
+    fn icount(&self) -> u64;
+    /// hash of the architectural registers
+    fn state_digest(&self) -> u64;
+    fn memory(&mut self) -> &mut dyn MemoryBackend;
+}
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mismatch {
+    Pc(u64, u64),
+    Icount(u64, u64),
+    Registers,
+    /// first differing byte
+    Memory(u64),
+}
+/// Where two engines stopped agreeing: somewhere in the `step` instructions before `icount`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub icount: u64,
+    pub step: u64,
+    /// where `a` was when the difference showed
+    pub pc: u64,
+    pub mismatch: Mismatch,
+}
+fn compare(a: &mut dyn ExecutionEngine, b: &mut dyn ExecutionEngine, watch: &[(u64, u64)]) -> Option<Mismatch> {
+    if a.icount() != b.icount() {
+        return Some(Mismatch::Icount(a.icount(), b.icount()));
+    }
+    if a.pc() != b.pc() {
+        return Some(Mismatch::Pc(a.pc(), b.pc()));
+    }
+    if a.state_digest() != b.state_digest() {
+        return Some(Mismatch::Registers);
+    }
+    for &(start, len) in watch {
+        let ma = a.memory().read_bytes(start, len as usize);
+        let mb = b.memory().read_bytes(start, len as usize);
+        match (ma, mb) {
+            (Ok(x), Ok(y)) => {
+                if let Some(i) = x.iter().zip(&y).position(|(p, q)| p != q) {
+                    return Some(Mismatch::Memory(start + i as u64));
+                }
+            }
+            (Err(_), Err(_)) => {}
+            _ => return Some(Mismatch::Memory(start)),
+        }
+    }
+    None
+}
+/// Runs `a` and `b` for `total` instructions, `step` at a time, comparing them after each step
+/// (and the `watch` ranges of their memory, as (start, len))
+pub fn lockstep(a: &mut dyn ExecutionEngine, b: &mut dyn ExecutionEngine, total: u64, step: u64,
+                watch: &[(u64, u64)]) -> Result<(), Divergence> {
+    let step = step.max(1);
+    let mut done = 0;
+    while done < total {
+        let n = step.min(total - done);
+        a.run(n);
+        b.run(n);
+        done += n;
+        if let Some(mismatch) = compare(a, b, watch) {
+            return Err(Divergence { icount: a.icount(), step: n, pc: a.pc(), mismatch });
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,119 @@
+// Error injection, for testing how the guest (and the emulator) copes when things go wrong.
+// Guest ranges can be set to fault on access, as if the memory behind them had gone bad. Data
+// the emulator copies into guest memory on the guest's behalf (the usermode equivalent of DMA,
+// like read() buffers) can get a bit flipped, and I/O requests can fail outright.
+// The random parts come from a seeded generator, so a failing run can be repeated with the same seed.
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use sync::Mutex;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FaultRange {
+    pub end: u64, // exclusive
+    pub on_read: bool,
+    pub on_write: bool,
+}
+#[derive(Debug, Default)]
+pub struct FaultStats {
+    pub mem_faults: AtomicU64,
+    pub bit_flips: AtomicU64,
+    pub failed_requests: AtomicU64,
+}
+#[derive(Debug)]
+struct InjectState {
+    ranges: BTreeMap<u64, FaultRange>,
+    /// chances, in parts per million
+    bitflip_ppm: u32,
+    fail_ppm: u32,
+    rng: u64,
+}
+#[derive(Debug)]
+pub struct FaultInjector {
+    state: Mutex<InjectState>,
+    pub stats: FaultStats,
+}
+impl FaultInjector {
+    pub fn new(seed: u64) -> FaultInjector {
+        FaultInjector {
+            state: Mutex::new(InjectState {
+                ranges: BTreeMap::new(),
+                bitflip_ppm: 0,
+                fail_ppm: 0,
+                rng: seed | 1,
+            }),
+            stats: FaultStats::default(),
+        }
+    }
+    /// Accesses to [start, start + len) fault from now on. Replaces ranges starting at the same address
+    pub fn add_range(&self, start: u64, len: u64, on_read: bool, on_write: bool) {
+        if len == 0 {
+            return;
+        }
+        self.state.lock().ranges.insert(start, FaultRange {
+            end: start.saturating_add(len),
+            on_read,
+            on_write,
+        });
+    }
+    pub fn remove_range(&self, start: u64) {
+        self.state.lock().ranges.remove(&start);
+    }
+    /// Chance that a buffer filled in for the guest gets a bit flipped
+    pub fn set_bitflip_ppm(&self, ppm: u32) {
+        self.state.lock().bitflip_ppm = ppm;
+    }
+    /// Chance that an I/O request fails with EIO
+    pub fn set_fail_ppm(&self, ppm: u32) {
+        self.state.lock().fail_ppm = ppm;
+    }
+    /// Whether a guest access to [addr, addr + len) should fault
+    pub fn mem_fault(&self, addr: u64, len: u64, write: bool) -> bool {
+        let st = self.state.lock();
+        let end = addr.saturating_add(len.max(1));
+        // ranges can overlap, so the closest start isn't necessarily the one that covers addr
+        let hit = st.ranges.range(..end).rev()
+            .any(|(_, r)| r.end > addr && if write { r.on_write } else { r.on_read });
+        if hit {
+            self.stats.mem_faults.fetch_add(1, Ordering::Relaxed);
+        }
+        hit
+    }
+    pub fn fail_request(&self) -> bool {
+        let mut st = self.state.lock();
+        let ppm = st.fail_ppm;
+        if ppm == 0 || st.roll() >= ppm {
+            return false;
+        }
+        self.stats.failed_requests.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+    /// Maybe flips one bit in `buf`, which the emulator just filled in for the guest
+    pub fn maybe_flip(&self, buf: &mut [u8]) {
+        if buf.is_empty() {
+            return;
+        }
+        let mut st = self.state.lock();
+        let ppm = st.bitflip_ppm;
+        if ppm == 0 || st.roll() >= ppm {
+            return;
+        }
+        let bit = st.next_rand() % (buf.len() as u64 * 8);
+        buf[(bit / 8) as usize] ^= 1 << (bit % 8);
+        self.stats.bit_flips.fetch_add(1, Ordering::Relaxed);
+        crate::warn_ratelimited!("fault injection: flipped bit {} of a {} byte buffer", bit, buf.len());
+    }
+}
+impl InjectState {
+    fn next_rand(&mut self) -> u64 {
+        // xorshift64
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng = x;
+        x
+    }
+    fn roll(&mut self) -> u32 {
+        (self.next_rand() % 1_000_000) as u32
+    }
+}
@@ -0,0 +1,197 @@
+// Memory access, TLB and block cache counters, one block per hart.
+// Only the hart that owns a block writes to it, so bumping a counter is a plain load and store
+// (no locked instruction), and every block sits on cache lines of its own so harts don't bounce
+// lines between each other. Nothing is summed until somebody asks: the reader walks the
+// registered blocks and adds them up, which can miss the last few accesses of a running hart,
+// fine for statistics.
+// Faults are also counted per guest PC, so the sites behind a flood of them can be found. Those
+// are rare next to loads and stores, so the hart's own (uncontended) lock is fine there.
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use sync::Mutex;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Counter {
+    Loads,
+    Stores,
+    LoadBytes,
+    StoreBytes,
+    /// translations that had to walk the page table
+    PageWalks,
+    TlbFlushes,
+    BlockHits,
+    /// blocks that had to be translated
+    BlockMisses,
+    /// blocks thrown away because their code was written to
+    BlockInvalidations,
+}
+pub const COUNTERS: [Counter; 9] = [
+    Counter::Loads,
+    Counter::Stores,
+    Counter::LoadBytes,
+    Counter::StoreBytes,
+    Counter::PageWalks,
+    Counter::TlbFlushes,
+    Counter::BlockHits,
+    Counter::BlockMisses,
+    Counter::BlockInvalidations,
+];
+impl Counter {
+    pub fn name(self) -> &'static str {
+        match self {
+            Counter::Loads => "loads",
+            Counter::Stores => "stores",
+            Counter::LoadBytes => "load_bytes",
+            Counter::StoreBytes => "store_bytes",
+            Counter::PageWalks => "page_walks",
+            Counter::TlbFlushes => "tlb_flushes",
+            Counter::BlockHits => "block_hits",
+            Counter::BlockMisses => "block_misses",
+            Counter::BlockInvalidations => "block_invalidations",
+        }
+    }
+}
+/// Kinds of faults counted per site
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum TrapCause {
+    PageFault,
+    AccessFault,
+    IllegalInstruction,
+    Misaligned,
+}
+impl TrapCause {
+    pub fn name(self) -> &'static str {
+        match self {
+            TrapCause::PageFault => "page_fault",
+            TrapCause::AccessFault => "access_fault",
+            TrapCause::IllegalInstruction => "illegal_instruction",
+            TrapCause::Misaligned => "misaligned",
+        }
+    }
+}
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TrapSite {
+    pub pc: u64,
+    pub cause: TrapCause,
+    pub count: u64,
+}
+type SiteCounts = HashMap<(u64, TrapCause), u64>;
+fn merge_sites(into: &mut SiteCounts, from: &SiteCounts) {
+    for (k, n) in from {
+        *into.entry(*k).or_insert(0) += n;
+    }
+}
+/// One hart's counters
+#[repr(align(128))]
+#[derive(Default)]
+pub struct HartCounters {
+    vals: [AtomicU64; COUNTERS.len()],
+    /// faults by (pc, cause)
+    sites: Mutex<SiteCounts>,
+}
+impl HartCounters {
+    /// Only to be called by the hart owning the block
+    #[inline(always)]
+    pub fn add(&self, c: Counter, n: u64) {
+        let v = &self.vals[c as usize];
+        v.store(v.load(Ordering::Relaxed).wrapping_add(n), Ordering::Relaxed);
+    }
+    #[inline(always)]
+    pub fn bump(&self, c: Counter) {
+        self.add(c, 1);
+    }
+    pub fn get(&self, c: Counter) -> u64 {
+        self.vals[c as usize].load(Ordering::Relaxed)
+    }
+    /// The instruction at `pc` faulted
+    pub fn count_trap(&self, pc: u64, cause: TrapCause) {
+        *self.sites.lock().entry((pc, cause)).or_insert(0) += 1;
+    }
+}
+/// Every hart's block, shared by the harts and whoever reads the stats
+#[derive(Default)]
+pub struct StatsRegistry {
+    harts: Mutex<Vec<Arc<HartCounters>>>,
+    /// what harts that are gone counted
+    retired: HartCounters,
+}
+/// How many trap sites the reports list
+pub const TOP_TRAP_SITES: usize = 20;
+impl StatsRegistry {
+    pub fn new() -> StatsRegistry {
+        Default::default()
+    }
+    /// Block for a new hart
+    pub fn register(&self) -> Arc<HartCounters> {
+        let hc = Arc::new(HartCounters::default());
+        self.harts.lock().push(hc.clone());
+        hc
+    }
+    /// The hart won't count anymore, fold its numbers into the totals
+    pub fn retire(&self, hc: &Arc<HartCounters>) {
+        let mut harts = self.harts.lock();
+        for c in COUNTERS {
+            self.retired.vals[c as usize].fetch_add(hc.get(c), Ordering::Relaxed);
+        }
+        merge_sites(&mut self.retired.sites.lock(), &hc.sites.lock());
+        harts.retain(|h| !Arc::ptr_eq(h, hc));
+    }
+    /// A forked child starts over, with just the hart that forked
+    pub fn forked_child(&self, hc: &Arc<HartCounters>) {
+        let mut harts = self.harts.lock();
+        for c in COUNTERS {
+            self.retired.vals[c as usize].store(0, Ordering::Relaxed);
+            hc.vals[c as usize].store(0, Ordering::Relaxed);
+        }
+        self.retired.sites.lock().clear();
+        hc.sites.lock().clear();
+        harts.retain(|h| Arc::ptr_eq(h, hc));
+    }
+    /// Totals over all harts, in COUNTERS order
+    pub fn totals(&self) -> [u64; COUNTERS.len()] {
+        let mut out = [0; COUNTERS.len()];
+        let harts = self.harts.lock();
+        for c in COUNTERS {
+            out[c as usize] = self.retired.get(c) + harts.iter().map(|h| h.get(c)).sum::<u64>();
+        }
+        out
+    }
+    /// The `n` sites that faulted most, over all harts
+    pub fn top_trap_sites(&self, n: usize) -> Vec<TrapSite> {
+        let mut all = self.retired.sites.lock().clone();
+        for h in self.harts.lock().iter() {
+            merge_sites(&mut all, &h.sites.lock());
+        }
+        let mut sites: Vec<TrapSite> = all.into_iter()
+            .map(|((pc, cause), count)| TrapSite { pc, cause, count }).collect();
+        sites.sort_by(|a, b| b.count.cmp(&a.count).then(a.pc.cmp(&b.pc)).then(a.cause.cmp(&b.cause)));
+        sites.truncate(n);
+        sites
+    }
+    /// Top faulting sites, with `describe` putting a name on the pc
+    pub fn trap_sites_report(&self, describe: &dyn Fn(u64) -> String) -> String {
+        let mut s = String::new();
+        let sites = self.top_trap_sites(TOP_TRAP_SITES);
+        if sites.is_empty() {
+            return "no faults\n".to_string();
+        }
+        let _ = writeln!(s, "top faulting sites:");
+        for t in sites {
+            let _ = writeln!(s, "{:>10} {:<20} {}", t.count, t.cause.name(), describe(t.pc));
+        }
+        s
+    }
+    /// Per hart numbers, for the debugger
+    pub fn report(&self) -> String {
+        let mut s = String::new();
+        let totals = self.totals();
+        for c in COUNTERS {
+            let _ = write!(s, "{}: {}", c.name(), totals[c as usize]);
+            let per: Vec<String> = self.harts.lock().iter().map(|h| h.get(c).to_string()).collect();
+            let _ = writeln!(s, " (live harts: {})", per.join(" "));
+        }
+        s
+    }
+}
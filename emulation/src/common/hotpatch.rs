@@ -0,0 +1,227 @@
+// Patching guest code while it runs: stop the harts, write the new bytes, have every hart forget
+// what its block cache made from them, let everything go again.
+// Harts check in between blocks, which costs one relaxed load while nothing is going on. A pause
+// waits until every hart that's running guest code has parked there. Harts that are in a syscall
+// (or haven't started) don't count, they go through a check-in before running guest code again.
+// Every patch bumps the epoch, and each hart drops the patched pages from its own cache the next
+// time it checks in, whether it was parked or not. A patch stays in the log until every live hart
+// has checked in past it.
+// All of that is a PatchGate, one per guest (the usermode runtime has it, a system mode machine
+// makes its own), which every hart's PatchSeen points at.
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use sync::{Condvar, Mutex};
+
+/// how long a pause waits for the harts to stop
+pub const PAUSE_TIMEOUT: Duration = Duration::from_secs(2);
+const PAUSED: u64 = 1;
+
+pub(crate) struct Gate {
+    parked: usize,
+    /// pause()s not resumed yet
+    pauses: usize,
+    /// (epoch, addr, len) of the patches some live hart hasn't checked in for yet
+    patches: Vec<(u64, u64, u64)>,
+    /// epoch each live hart last checked in with, by slot
+    seen: HashMap<u64, u64>,
+    next_slot: u64,
+}
+impl Gate {
+    pub(crate) fn new() -> Gate {
+        Gate { parked: 0, pauses: 0, patches: vec![], seen: HashMap::new(), next_slot: 0 }
+    }
+    /// A new hart, which has nothing made from code patched up to `epoch`. Returns its slot
+    pub(crate) fn register(&mut self, epoch: u64) -> u64 {
+        let slot = self.next_slot;
+        self.next_slot += 1;
+        self.seen.insert(slot, epoch);
+        slot
+    }
+    pub(crate) fn unregister(&mut self, slot: u64, now: u64) {
+        self.seen.remove(&slot);
+        self.trim(now);
+    }
+    /// The hart in `slot` checks in at epoch `now`: the (addr, len) ranges patched after `seen`
+    pub(crate) fn catch_up(&mut self, slot: u64, seen: u64, now: u64) -> Vec<(u64, u64)> {
+        let ranges = self.patches.iter().filter(|(e, _, _)| *e > seen).map(|(_, a, l)| (*a, *l)).collect();
+        self.seen.insert(slot, now);
+        self.trim(now);
+        ranges
+    }
+    pub(crate) fn log(&mut self, epoch: u64, addr: u64, len: u64) {
+        self.patches.push((epoch, addr, len));
+        self.trim(epoch);
+    }
+    /// patches still waiting for a hart
+    pub(crate) fn logged(&self) -> usize {
+        self.patches.len()
+    }
+    // every hart that's still around has seen what's up to the oldest epoch among them
+    fn trim(&mut self, now: u64) {
+        let oldest = self.seen.values().copied().min().unwrap_or(now);
+        self.patches.retain(|(e, _, _)| *e > oldest);
+    }
+}
+thread_local! {
+    // a host thread runs one hart at a time, so this is per thread and not per gate
+    static IN_GUEST: Cell<bool> = Cell::new(false);
+}
+
+/// Pausing and patch bookkeeping shared by the harts of one guest
+pub struct PatchGate {
+    /// epoch << 1 | PAUSED
+    state: AtomicU64,
+    /// threads between enter() and leave()
+    running: AtomicUsize,
+    gate: Mutex<Gate>,
+    /// parked harts wait here for resume(), pause() for harts parking or leaving
+    wake: Condvar,
+    /// /proc/self/mem, opened on the first patch, and the pid that opened it (a forked child's
+    /// would still be its parent's memory)
+    #[cfg(feature = "linux-usermode")]
+    mem: Mutex<Option<(u32, std::fs::File)>>,
+}
+impl Default for PatchGate {
+    fn default() -> PatchGate {
+        PatchGate::new()
+    }
+}
+impl PatchGate {
+    pub fn new() -> PatchGate {
+        PatchGate {
+            state: AtomicU64::new(0),
+            running: AtomicUsize::new(0),
+            gate: Mutex::new(Gate::new()),
+            wake: Condvar::new(),
+            #[cfg(feature = "linux-usermode")]
+            mem: Mutex::new(None),
+        }
+    }
+    /// Harts keep the last state they checked in with and call `checkin` when it changes
+    #[inline]
+    pub fn state(&self) -> u64 {
+        self.state.load(Ordering::Relaxed)
+    }
+    /// This thread is about to run guest code
+    pub fn enter(&self) {
+        if !IN_GUEST.with(|g| g.replace(true)) {
+            self.running.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+    /// This thread stops running guest code for a while (syscall, end of a slice) or for good.
+    /// Returns whether it was running guest code
+    pub fn leave(&self) -> bool {
+        let was = IN_GUEST.with(|g| g.replace(false));
+        if was {
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            if self.paused() {
+                // one less to wait for. Taking the lock means a pause() between looking at
+                // `running` and waiting can't miss it
+                let _g = self.gate.lock();
+                self.wake.notify_all();
+            }
+        }
+        was
+    }
+    /// Parks while paused. Brings `seen` up to date and returns the (addr, len) ranges patched
+    /// since it was last
+    pub fn checkin(&self, seen: &mut PatchSeen) -> Vec<(u64, u64)> {
+        let mut g = self.gate.lock();
+        if self.state.load(Ordering::SeqCst) & PAUSED != 0 {
+            g.parked += 1;
+            self.wake.notify_all();
+            while self.state.load(Ordering::SeqCst) & PAUSED != 0 {
+                g = self.wake.wait(g);
+            }
+            g.parked -= 1;
+        }
+        let now = self.state.load(Ordering::SeqCst);
+        let ranges = g.catch_up(seen.slot, seen.state >> 1, now >> 1);
+        seen.state = now;
+        ranges
+    }
+    /// Stops every other hart at its next check-in, for up to `timeout`. Pauses nest, harts go
+    /// again when the last one is resumed
+    pub fn pause(&self, timeout: Duration) -> Result<(), String> {
+        let deadline = Instant::now() + timeout;
+        let mut g = self.gate.lock();
+        g.pauses += 1;
+        self.state.fetch_or(PAUSED, Ordering::SeqCst);
+        // the thread pausing can't park, so it doesn't count if it runs guest code itself
+        let me = IN_GUEST.with(|g| g.get()) as usize;
+        loop {
+            let others = self.running.load(Ordering::SeqCst).saturating_sub(me);
+            if g.parked >= others {
+                return Ok(());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                let stuck = others - g.parked;
+                drop(g);
+                self.resume();
+                return Err(format!("{} of {} harts didn't stop within {:?}", stuck, others, timeout));
+            }
+            // harts parking and harts leaving guest code both wake us
+            g = self.wake.wait_timeout(g, deadline - now).0;
+        }
+    }
+    pub fn resume(&self) {
+        let mut g = self.gate.lock();
+        if g.pauses == 0 {
+            return;
+        }
+        g.pauses -= 1;
+        if g.pauses == 0 {
+            self.state.fetch_and(!PAUSED, Ordering::SeqCst);
+            self.wake.notify_all();
+        }
+    }
+    /// The forked child has only the thread that forked, which was in a syscall, and `survivor`
+    /// is its hart
+    pub fn forked_child(&self, survivor: &PatchSeen) {
+        self.running.store(0, Ordering::SeqCst);
+        self.state.fetch_and(!PAUSED, Ordering::SeqCst);
+        if let Ok(mut g) = self.gate.try_lock() {
+            g.parked = 0;
+            g.pauses = 0;
+            // the other threads' harts are gone, without being dropped
+            g.seen.retain(|slot, _| *slot == survivor.slot);
+        }
+    }
+    pub fn paused(&self) -> bool {
+        self.state.load(Ordering::SeqCst) & PAUSED != 0
+    }
+    /// Tells the harts that guest code in [addr, addr + len) changed (physical addresses, like
+    /// their caches)
+    pub fn record_patch(&self, addr: u64, len: u64) {
+        let mut g = self.gate.lock();
+        let epoch = (self.state.fetch_add(2, Ordering::SeqCst) >> 1) + 1;
+        g.log(epoch, addr, len);
+    }
+    // runs `f` on /proc/self/mem, opening it if this process hasn't yet
+    #[cfg(feature = "linux-usermode")]
+    fn with_mem<R>(&self, f: impl FnOnce(&std::fs::File) -> std::io::Result<R>) -> std::io::Result<R> {
+        let mut mem = self.mem.lock();
+        let pid = std::process::id();
+        if mem.as_ref().map(|(p, _)| *p) != Some(pid) {
+            let file = std::fs::OpenOptions::new().read(true).write(true).open("/proc/self/mem")?;
+            *mem = Some((pid, file));
+        }
+        f(&mem.as_ref().unwrap().1)
+    }
+    
+/// "13 00 00 00", "13000000" or "0x13,0,0,0": bytes in memory order
+pub fn parse_bytes(s: &str) -> Option<Vec<u8>> {
+    let parts: Vec<&str> = s.split(|c: char| c == ',' || c.is_whitespace()).filter(|p| !p.is_empty()).collect();
+    if parts.len() == 1 && !parts[0].starts_with("0x") && parts[0].len() > 2 {
+        let h = parts[0];
+        if h.len() % 2 != 0 {
+            return None;
+        }
+        return (0..h.len()).step_by(2).map(|i| u8::from_str_radix(h.get(i..i + 2)?, 16).ok()).collect();
+    }
+    parts.iter().map(|p| u8::from_str_radix(p.strip_prefix("0x").unwrap_or(p), 16).ok()).collect()
+}
@@ -0,0 +1,98 @@
+// Log levels by subsystem, and rate limits for warnings that can fire over and over.
+// Levels are the logger's own filter (env_logger syntax, longest module path wins). The subsystem
+// names below are shorthand for the modules behind them, so "info,syscalls=debug" works on the
+// command line (--log-level) and from the debugger's monitor ("loglevel syscalls=debug") while
+// the guest runs.
+// warn_ratelimited! is warn! for things a guest can trigger on every instruction or syscall: a few
+// get through, then at most one per interval, with a count of the ones that were dropped.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// subsystem -> modules
+pub const SUBSYSTEMS: &[(&str, &[&str])] = &[
+    ("interp", &["emulation::riscv::interpreter", "emulation::armv8::interpreter"]),
+    ("mmu", &["emulation::riscv::mem", "emulation::common::memory", "emulation::linux_usermode::memusage"]),
+    ("syscalls", &["emulation::linux_usermode", "emulation::riscv::ume", "emulation::armv8::ume"]),
+    // usermode has no devices, the I/O layers in front of the host are the closest thing
+    ("devices", &["emulation::linux_usermode::throttle", "emulation::linux_usermode::console",
+                  "emulation::linux_usermode::conrelay", "emulation::common::fault_inject"]),
+];
+/// "syscalls=debug" -> a directive per module, anything else passes through
+pub fn expand_filter(spec: &str) -> String {
+    let mut out = vec![];
+    for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (name, level) = part.split_once('=').unwrap_or((part, ""));
+        match SUBSYSTEMS.iter().find(|(s, _)| *s == name) {
+            Some((_, mods)) if !level.is_empty() => out.extend(mods.iter().map(|m| format!("{}={}", m, level))),
+            _ => out.push(part.to_string()),
+        }
+    }
+    out.join(",")
+}
+/// "loglevel [FILTER]" from the monitor
+pub fn monitor_cmd(cmd: &str) -> Option<String> {
+    let (word, rest) = cmd.trim().split_once(' ').unwrap_or((cmd.trim(), ""));
+    if word != "loglevel" {
+        return None;
+    }
+    let names: Vec<&str> = SUBSYSTEMS.iter().map(|(s, _)| *s).collect();
+    if rest.trim().is_empty() {
+        return Some(format!("filter: {}\nsubsystems: {}",
+                            base::syslog::filter().unwrap_or_else(|| "(logging not set up)".to_string()), names.join(" ")));
+    }
+    let spec = expand_filter(rest);
+    Some(match base::syslog::set_filter(&spec) {
+        Ok(()) => format!("filter: {}", spec),
+        Err(e) => format!("can't change the filter: {}", e),
+    })
+}
+/// let this many through before limiting
+const BURST: u64 = 10;
+/// then one per this many seconds
+const INTERVAL_SECS: u64 = 10;
+
+/// Limit for one warn_ratelimited! call site
+pub struct RateLimit {
+    /// seconds since the epoch when the current interval started
+    since: AtomicU64,
+    passed: AtomicU64,
+    dropped: AtomicU64,
+}
+impl RateLimit {
+    pub const fn new() -> RateLimit {
+        RateLimit { since: AtomicU64::new(0), passed: AtomicU64::new(0), dropped: AtomicU64::new(0) }
+    }
+    /// Some(number dropped since the last one) if this one gets logged. Racy, a few more or less
+    /// get through when threads hit it at once
+    pub fn allow(&self) -> Option<u64> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let passed = self.passed.fetch_add(1, Ordering::Relaxed);
+        if passed < BURST {
+            return Some(self.dropped.swap(0, Ordering::Relaxed));
+        }
+        let since = self.since.load(Ordering::Relaxed);
+        if now >= since + INTERVAL_SECS
+            && self.since.compare_exchange(since, now, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+            return Some(self.dropped.swap(0, Ordering::Relaxed));
+        }
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+}
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+/// warn!, but a call site that keeps firing is cut down to one message per interval
+#[macro_export]
+macro_rules! warn_ratelimited {
+    ($($arg:tt)+) => {{
+        static LIMIT: $crate::logctl::RateLimit = $crate::logctl::RateLimit::new();
+        match LIMIT.allow() {
+            Some(0) => ::base::warn!($($arg)+),
+            Some(n) => ::base::warn!("{} ({} more like it not shown)", format_args!($($arg)+), n),
+            None => {}
+        }
+    }};
+}
@@ -0,0 +1,198 @@
+// Guest memory to and from files, for the debugger's monitor:
+//   savemem raw FILE [ADDR LEN]    bytes as they are (without a range only if RAM is one piece)
+//   savemem core FILE [ADDR LEN]   ELF core with a PT_LOAD per range, all of RAM by default,
+//                                  for gdb, readelf and the like
+//   loadmem FILE ADDR              file contents into guest memory at ADDR
+// Addresses are the MemoryBackend's, so guest physical in system mode and guest virtual (which is
+// host virtual) in usermode. Whoever calls this says which ranges are RAM and which ones can be
+// touched at all, so a bad address gets an error and not a crash.
+use std::fs::File;
+use std::io::{Read, Write};
+use crate::common::engine::MemoryBackend;
+
+/// read or written this much at a time
+const CHUNK: u64 = 1 << 20;
+const PT_LOAD: u32 = 1;
+const ET_CORE: u16 = 4;
+const PF_RWX: u32 = 7;
+/// e_phnum when there are too many program headers for it, the count is then in the first
+/// section header's sh_info
+const PN_XNUM: u64 = 0xffff;
+
+/// The ELF header fields of the guest
+#[derive(Copy, Clone)]
+pub struct CoreTarget {
+    pub machine: u16,
+    pub is_64: bool,
+    pub little_endian: bool,
+}
+/// 0x for hex, decimal otherwise
+pub(crate) fn parse_num(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(h) => u64::from_str_radix(h, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+/// Copies [start, start + len) into `out`, zeros for anything the backend can't read
+fn copy_out(mem: &mut dyn MemoryBackend, start: u64, len: u64, out: &mut File) -> std::io::Result<()> {
+    let mut at = start;
+    while at < start + len {
+        let n = CHUNK.min(start + len - at);
+        let data = mem.read_bytes(at, n as usize).unwrap_or_else(|_| vec![0; n as usize]);
+        out.write_all(&data)?;
+        at += n;
+    }
+    Ok(())
+}
+struct ElfWriter {
+    buf: Vec<u8>,
+    target: CoreTarget,
+}
+impl ElfWriter {
+    fn put(&mut self, val: u64, size: usize) {
+        let bytes = if self.target.little_endian { val.to_le_bytes() } else { val.to_be_bytes() };
+        if self.target.little_endian {
+            self.buf.extend_from_slice(&bytes[..size]);
+        } else {
+            self.buf.extend_from_slice(&bytes[8 - size..]);
+        }
+    }
+    /// address-sized field
+    fn word(&mut self, val: u64) {
+        self.put(val, if self.target.is_64 { 8 } else { 4 });
+    }
+}
+/// ELF core with one PT_LOAD per range. Returns the bytes of guest memory written
+pub fn write_core(mem: &mut dyn MemoryBackend, ranges: &[(u64, u64)], target: CoreTarget, path: &str) -> Result<u64, String> {
+    let (ehsize, phentsize, shentsize) = if target.is_64 { (64u64, 56u64, 64u64) } else { (52, 32, 40) };
+    let phnum = ranges.len() as u64;
+    if phnum > u32::MAX as u64 {
+        return Err(format!("{} ranges don't fit in a core", phnum));
+    }
+    // past PN_XNUM the real count goes in a section header after the program headers
+    let xnum = phnum >= PN_XNUM;
+    let shoff = ehsize + phnum * phentsize;
+    let headers_end = if xnum { shoff + shentsize } else { shoff };
+    // data starts on a page, like in any other core
+    let data_start = (headers_end + 0xfff) & !0xfff;
+    let data_end = ranges.iter().fold(data_start, |at, r| at + r.1);
+    if !target.is_64 && data_end > u32::MAX as u64 {
+        return Err(format!("{:#x} bytes is too big for a 32-bit core", data_end));
+    }
+    let mut w = ElfWriter { buf: vec![], target };
+    w.buf.extend_from_slice(&[0x7f, b'E', b'L', b'F', if target.is_64 { 2 } else { 1 },
+                              if target.little_endian { 1 } else { 2 }, 1]);
+    w.buf.resize(16, 0);
+    w.put(ET_CORE as u64, 2);
+    w.put(target.machine as u64, 2);
+    w.put(1, 4); // e_version
+    w.word(0); // e_entry
+    w.word(ehsize); // e_phoff
+    w.word(if xnum { shoff } else { 0 }); // e_shoff
+    w.put(0, 4); // e_flags
+    w.put(ehsize, 2);
+    w.put(phentsize, 2);
+    w.put(phnum.min(PN_XNUM), 2);
+    w.put(if xnum { shentsize } else { 0 }, 2); // e_shentsize
+    w.put(xnum as u64, 2); // e_shnum
+    w.put(0, 2); // e_shstrndx
+    let mut offset = data_start;
+    for &(start, len) in ranges {
+        w.put(PT_LOAD as u64, 4);
+        if target.is_64 {
+            w.put(PF_RWX as u64, 4);
+        }
+        w.word(offset);
+        w.word(start); // p_vaddr
+        w.word(start); // p_paddr
+        w.word(len); // p_filesz
+        w.word(len); // p_memsz
+        if !target.is_64 {
+            w.put(PF_RWX as u64, 4);
+        }
+        w.word(0x1000);
+        offset += len;
+    }
+    if xnum {
+        // an SHT_NULL section that's only there for its sh_info
+        w.put(0, 4); // sh_name
+        w.put(0, 4); // sh_type
+        w.word(0); // sh_flags
+        w.word(0); // sh_addr
+        w.word(0); // sh_offset
+        w.word(0); // sh_size
+        w.put(0, 4); // sh_link
+        w.put(phnum, 4); // sh_info
+        w.word(0); // sh_addralign
+        w.word(0); // sh_entsize
+    }
+    w.buf.resize(data_start as usize, 0);
+    let mut f = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+    f.write_all(&w.buf).map_err(|e| format!("{}: {}", path, e))?;
+    for &(start, len) in ranges {
+        copy_out(mem, start, len, &mut f).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    Ok(offset - data_start)
+}
+/// `len` bytes from `start` into a file
+pub fn write_raw(mem: &mut dyn MemoryBackend, start: u64, len: u64, path: &str) -> Result<u64, String> {
+    let mut f = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+    copy_out(mem, start, len, &mut f).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(len)
+}
+/// `ok(start, len, write)`: whether the range can be read (or written) without faulting the host.
+/// `saved` gets the path of every dump written
+pub fn monitor_cmd(cmd: &str, mem: &mut dyn MemoryBackend, ram: &[(u64, u64)], target: CoreTarget,
+                   ok: &dyn Fn(u64, u64, bool) -> bool, saved: &dyn Fn(&str)) -> Option<String> {
+    let words: Vec<&str> = cmd.split_whitespace().collect();
+    let reply = match words.as_slice() {
+        ["savemem", kind, path, rest @ ..] if *kind == "raw" || *kind == "core" => {
+            let ranges = match rest {
+                [] => ram.to_vec(),
+                [a, l] => match (parse_num(a), parse_num(l)) {
+                    (Some(a), Some(l)) if l > 0 && a.checked_add(l).is_some() => vec![(a, l)],
+                    _ => return Some(format!("bad range {} {}", a, l)),
+                },
+                _ => return Some("savemem raw|core FILE [ADDR LEN]".to_string()),
+            };
+            if let Some((a, l)) = ranges.iter().find(|(a, l)| !ok(*a, *l, false)) {
+                return Some(format!("{:#x}-{:#x} isn't all readable", a, a + l));
+            }
+            let res = if *kind == "core" {
+                write_core(mem, &ranges, target, path)
+            } else if ranges.len() == 1 {
+                write_raw(mem, ranges[0].0, ranges[0].1, path)
+            } else {
+                return Some(format!("RAM is {} pieces, give ADDR LEN for a raw dump or use a core", ranges.len()));
+            };
+            match res {
+                Ok(n) => {
+                    saved(path);
+                    format!("wrote {} bytes of guest memory to {}", n, path)
+                }
+                Err(e) => e,
+            }
+        }
+        ["loadmem", path, addr] => {
+            let addr = match parse_num(addr) {
+                Some(a) => a,
+                None => return Some(format!("bad address {}", addr)),
+            };
+            let mut data = vec![];
+            if let Err(e) = File::open(path).and_then(|mut f| f.read_to_end(&mut data)) {
+                return Some(format!("{}: {}", path, e));
+            }
+            if addr.checked_add(data.len() as u64).is_none() || !ok(addr, data.len() as u64, true) {
+                return Some(format!("{:#x}-{:#x} isn't all writable", addr, addr.wrapping_add(data.len() as u64)));
+            }
+            match mem.write_bytes(addr, &data) {
+                Ok(()) => format!("loaded {} bytes at {:#x}", data.len(), addr),
+                Err(e) => format!("failed: {:?}", e),
+            }
+        }
+        ["savemem", ..] => "savemem raw|core FILE [ADDR LEN]".to_string(),
+        ["loadmem", ..] => "loadmem FILE ADDR".to_string(),
+        _ => return None,
+    };
+    Some(reply)
+}
@@ -10,6 +10,17 @@ pub mod vect;
 pub mod arm_fp_defs;
 mod arm_fp_ops;
 pub mod arm_common;
+pub mod poison;
+pub mod shadow_stack;
+pub mod fault_inject;
+pub mod pacing;
+pub mod hart_stats;
+pub mod symbols;
+pub mod spin;
+pub mod engine;
+pub mod memdump;
+pub mod logctl;
+pub mod hotpatch;
 
 #[cfg(target_endian = "little")]
 pub const IS_LITTLE_ENDIAN: bool = true;
@@ -0,0 +1,44 @@
+// Instruction rate throttling: paces guest execution against host time, so the guest runs at
+// roughly a fixed number of instructions per second instead of as fast as the host allows.
+// Guests that spin then don't pin a host core, and timing loops see something closer to a slow
+// real machine. The interpreter reports retired instructions every chunk() of them and sleeps
+// when it's ahead of schedule.
+use std::time::{Duration, Instant};
+
+/// How far behind schedule we can get before catching up is given up on. Time the guest spent
+/// blocked in a syscall shouldn't turn into a burst of running flat out afterwards
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+pub struct Pacer {
+    insns_per_sec: u64,
+    base: Instant,
+    /// instructions retired since base
+    insns: u64,
+}
+impl Pacer {
+    pub fn new(mips: u64) -> Pacer {
+        Pacer {
+            insns_per_sec: mips.max(1) * 1_000_000,
+            base: Instant::now(),
+            insns: 0,
+        }
+    }
+    /// How many instructions to run between calls to account(), about a millisecond's worth
+    pub fn chunk(&self) -> u64 {
+        (self.insns_per_sec / 1000).max(1)
+    }
+    /// `n` more instructions were retired, sleeps if they were done too soon
+    pub fn account(&mut self, n: u64) {
+        self.insns += n;
+        let due = Duration::from_nanos((self.insns as u128 * 1_000_000_000 / self.insns_per_sec as u128) as u64);
+        let elapsed = self.base.elapsed();
+        if due > elapsed {
+            std::thread::sleep(due - elapsed);
+        } else if elapsed - due > MAX_LAG {
+            let now = Instant::now();
+            self.base = now.checked_sub(MAX_LAG).unwrap_or(now);
+            self.insns = 0;
+        }
+    }
+}
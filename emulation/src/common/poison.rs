@@ -0,0 +1,113 @@
+// ASAN-style poisoning of guest memory.
+// Embedders (and tests) can mark guest address ranges they share with the guest as off limits,
+// any load or store the guest does to them is reported with the pc and a backtrace.
+// Only accesses made by guest instructions are checked, memory touched by the emulator on the
+// guest's behalf (syscall buffers, signal frames) is not.
+use std::collections::BTreeMap;
+use base::error;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PoisonPolicy {
+    /// Log and record the report, then let the access go through
+    Report,
+    /// Log the report and exit with status 1, like ASAN does
+    Abort,
+}
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PoisonAccess {
+    Read,
+    Write,
+}
+#[derive(Debug, Clone)]
+pub struct PoisonReport {
+    pub pc: u64,
+    pub addr: u64,
+    pub len: u64,
+    pub access: PoisonAccess,
+    /// start of the poisoned range that was hit and the label it was poisoned with
+    pub range_start: u64,
+    pub label: String,
+    /// return addresses, innermost first
+    pub backtrace: Vec<u64>,
+}
+#[derive(Debug)]
+struct PoisonRange {
+    end: u64, // exclusive
+    label: String,
+}
+/// Set of poisoned guest ranges. Ranges never overlap, poisoning over an existing range replaces it
+#[derive(Debug)]
+pub struct PoisonMap {
+    ranges: BTreeMap<u64, PoisonRange>,
+    pub policy: PoisonPolicy,
+    reports: Vec<PoisonReport>,
+}
+impl PoisonMap {
+    pub fn new(policy: PoisonPolicy) -> PoisonMap {
+        PoisonMap {
+            ranges: BTreeMap::new(),
+            policy,
+            reports: vec![],
+        }
+    }
+    pub fn poison(&mut self, start: u64, len: u64, label: &str) {
+        if len == 0 {
+            return;
+        }
+        self.unpoison(start, len);
+        self.ranges.insert(start, PoisonRange {
+            end: start.saturating_add(len),
+            label: label.to_string(),
+        });
+    }
+    /// Clears [start, start + len). Ranges only partially covered are trimmed or split
+    pub fn unpoison(&mut self, start: u64, len: u64) {
+        let end = start.saturating_add(len);
+        let hit: Vec<u64> = self.ranges.range(..end)
+            .filter(|(_, r)| r.end > start)
+            .map(|(s, _)| *s)
+            .collect();
+        for s in hit {
+            let r = self.ranges.remove(&s).unwrap();
+            if s < start {
+                self.ranges.insert(s, PoisonRange { end: start, label: r.label.clone() });
+            }
+            if r.end > end {
+                self.ranges.insert(end, PoisonRange { end: r.end, label: r.label });
+            }
+        }
+    }
+    pub fn clear(&mut self) {
+        self.ranges.clear();
+    }
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+    /// Returns the start and label of the poisoned range [addr, addr + len) touches, if any
+    pub fn lookup(&self, addr: u64, len: u64) -> Option<(u64, &str)> {
+        let end = addr.saturating_add(len);
+        // since ranges dont overlap, only the last one starting before `end` can be hit
+        let (s, r) = self.ranges.range(..end).next_back()?;
+        if r.end > addr {
+            Some((*s, r.label.as_str()))
+        } else {
+            None
+        }
+    }
+    /// Logs the report and records it, or with the abort policy returns true: the guest has to
+    /// exit now, which is the caller's to do
+    pub fn report(&mut self, rep: PoisonReport) -> bool {
+        let bt: Vec<String> = rep.backtrace.iter().map(|a| format!("{:#x}", a)).collect();
+        error!("poison: {:?} of {} bytes at {:#x} (pc {:#x}) hit range '{}' starting at {:#x}, backtrace: [{}]",
+            rep.access, rep.len, rep.addr, rep.pc, rep.label, rep.range_start, bt.join(", "));
+        if self.policy == PoisonPolicy::Abort {
+            return true;
+        }
+        self.reports.push(rep);
+        false
+    }
+    /// Hands out the reports collected so far
+    pub fn take_reports(&mut self) -> Vec<PoisonReport> {
+        std::mem::take(&mut self.reports)
+    }
+}
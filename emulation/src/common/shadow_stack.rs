@@ -0,0 +1,77 @@
+// Shadow call stack, kept by the interpreter from the guest's calls and returns.
+// Unlike walking frame pointers this doesn't depend on how the guest was compiled, so backtraces
+// stay accurate for code built with -fomit-frame-pointer, and anything that wants to know
+// the current call depth (profiling, function tracing) can ask here.
+use std::collections::VecDeque;
+
+pub const DEFAULT_SHADOW_STACK_DEPTH: usize = 4096;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ShadowFrame {
+    pub call_pc: u64, // pc of the call instruction
+    pub target: u64, // function that was called
+    pub ret_addr: u64,
+    pub sp: u64, // stack pointer at the time of the call
+}
+#[derive(Debug, Clone)]
+pub struct ShadowStack {
+    frames: VecDeque<ShadowFrame>,
+    max_depth: usize,
+    /// frames thrown away because the stack got deeper than max_depth
+    pub dropped: u64,
+    /// returns that didn't match any frame we know about
+    pub mismatches: u64,
+}
+impl ShadowStack {
+    pub fn new(max_depth: usize) -> ShadowStack {
+        ShadowStack {
+            frames: VecDeque::new(),
+            max_depth,
+            dropped: 0,
+            mismatches: 0,
+        }
+    }
+    pub fn push(&mut self, frame: ShadowFrame) {
+        // stack grows down, so frames recorded with a lower sp than the caller has now were
+        // left without a return (longjmp, exceptions, thread switching with swapcontext...)
+        while let Some(top) = self.frames.back() {
+            if top.sp < frame.sp {
+                self.frames.pop_back();
+            } else {
+                break;
+            }
+        }
+        if self.frames.len() >= self.max_depth {
+            self.frames.pop_front();
+            self.dropped += 1;
+        }
+        self.frames.push_back(frame);
+    }
+    /// Returning to `target`. Frames above the one that returns there are popped as well, since
+    /// those were tail calls. If nothing returns there the stack is left alone
+    pub fn ret(&mut self, target: u64) -> Option<ShadowFrame> {
+        let idx = match self.frames.iter().rposition(|f| f.ret_addr == target) {
+            Some(i) => i,
+            None => {
+                self.mismatches += 1;
+                return None;
+            }
+        };
+        let frame = self.frames[idx];
+        self.frames.truncate(idx);
+        Some(frame)
+    }
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+    pub fn frames(&self) -> impl Iterator<Item = &ShadowFrame> {
+        self.frames.iter().rev()
+    }
+    /// Return addresses, innermost first
+    pub fn backtrace(&self, max_frames: usize) -> Vec<u64> {
+        self.frames().take(max_frames).map(|f| f.ret_addr).collect()
+    }
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+}
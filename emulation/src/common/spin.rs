@@ -0,0 +1,111 @@
+// Busy-wait detection. A guest loop that keeps jumping back to the same place with every register
+// the same as last time around isn't getting anywhere: whatever it reads from memory hasn't
+// changed either, or the registers would have. That's what a thread waiting on a flag looks like,
+// and if nothing is ever going to set the flag (a device we don't emulate, a signal that never
+// comes) it burns a host core forever.
+// The cpu calls backward_jump() on every taken jump to a lower address. A few loop heads are
+// tracked at once so a loop that calls a function (with jumps of its own) still gets noticed.
+use std::time::Duration;
+
+pub const DEFAULT_SPIN_THRESHOLD: u64 = 10_000;
+const MIN_BACKOFF: Duration = Duration::from_micros(20);
+const MAX_BACKOFF: Duration = Duration::from_millis(2);
+const HEADS: usize = 4;
+/// loop heads that have been reported, so a thread that spins on and off doesn't flood the log
+const MAX_REPORTED: usize = 64;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SpinPolicy {
+    /// times around the loop with nothing changing before it counts as spinning
+    pub threshold: u64,
+    /// sleep (or give up the rest of the slice) while spinning, not just warn
+    pub backoff: bool,
+}
+impl SpinPolicy {
+    /// "warn" or "backoff"
+    pub fn parse(action: &str, threshold: u64) -> Option<SpinPolicy> {
+        let backoff = match action {
+            "warn" => false,
+            "backoff" => true,
+            _ => return None,
+        };
+        Some(SpinPolicy { threshold: threshold.max(1), backoff })
+    }
+}
+/// What to do about a spinning loop
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SpinHit {
+    pub head: u64,
+    pub iters: u64,
+    /// first time this loop got over the threshold
+    pub report: bool,
+    pub sleep: Option<Duration>,
+}
+#[derive(Copy, Clone, Default)]
+struct Head {
+    addr: u64,
+    digest: u64,
+    iters: u64,
+}
+pub struct SpinDetector {
+    policy: SpinPolicy,
+    heads: [Head; HEADS],
+    next_slot: usize,
+    backoff: Duration,
+    reported: Vec<u64>,
+}
+/// Cheap hash of the architectural state that matters for "did anything change"
+pub fn state_digest(regs: &[u64], fregs: &[u64]) -> u64 {
+    regs.iter().chain(fregs).fold(0xcbf2_9ce4_8422_2325, |h, r| (h ^ r).wrapping_mul(0x0100_0000_01b3).rotate_left(5))
+}
+impl SpinDetector {
+    pub fn new(policy: SpinPolicy) -> SpinDetector {
+        SpinDetector {
+            policy,
+            heads: [Head::default(); HEADS],
+            next_slot: 0,
+            backoff: MIN_BACKOFF,
+            reported: vec![],
+        }
+    }
+    pub fn backward_jump(&mut self, target: u64, digest: u64) -> Option<SpinHit> {
+        let slot = match self.heads.iter().position(|h| h.addr == target) {
+            Some(s) => s,
+            None => {
+                let s = self.next_slot;
+                self.next_slot = (s + 1) % HEADS;
+                self.heads[s] = Head { addr: target, digest, iters: 0 };
+                return None;
+            }
+        };
+        let h = &mut self.heads[slot];
+        if h.digest != digest {
+            // progress
+            h.digest = digest;
+            h.iters = 0;
+            self.backoff = MIN_BACKOFF;
+            return None;
+        }
+        h.iters += 1;
+        if h.iters < self.policy.threshold {
+            return None;
+        }
+        let iters = h.iters;
+        let report = iters == self.policy.threshold && !self.reported.contains(&target)
+            && self.reported.len() < MAX_REPORTED;
+        if report {
+            self.reported.push(target);
+        }
+        let sleep = if self.policy.backoff {
+            let d = self.backoff;
+            self.backoff = (d * 2).min(MAX_BACKOFF);
+            Some(d)
+        } else {
+            None
+        };
+        if !report && sleep.is_none() {
+            return None;
+        }
+        Some(SpinHit { head: target, iters, report, sleep })
+    }
+}
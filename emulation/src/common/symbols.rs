@@ -0,0 +1,74 @@
+// Guest symbol tables, for putting names on guest addresses in warnings and reports. Both the
+// full symbol table and the dynamic one are read (stripped binaries still have the latter); only
+// functions and objects count, and ones without a size cover up to the next symbol.
+use std::path::Path;
+use goblin::elf::Elf;
+use goblin::elf::sym::{STT_FUNC, STT_GNU_IFUNC, STT_NOTYPE, STT_OBJECT};
+
+struct Sym {
+    start: u64,
+    end: u64,
+    name: String,
+}
+#[derive(Default)]
+pub struct SymbolMap {
+    /// sorted by start
+    syms: Vec<Sym>,
+}
+impl SymbolMap {
+    pub fn new() -> SymbolMap {
+        Default::default()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.syms.is_empty()
+    }
+    /// Symbols of the ELF file at `path`, loaded `bias` bytes away from its link addresses
+    pub fn add_elf(&mut self, path: &Path, bias: u64) -> Result<usize, String> {
+        let data = std::fs::read(path).map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+        self.add_elf_bytes(&data, bias).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+    /// Same for an ELF image in memory. Returns how many symbols were added
+    pub fn add_elf_bytes(&mut self, data: &[u8], bias: u64) -> Result<usize, String> {
+        let ef = Elf::parse(data).map_err(|e| e.to_string())?;
+        let before = self.syms.len();
+        let tables = [(&ef.syms, &ef.strtab), (&ef.dynsyms, &ef.dynstrtab)];
+        for (syms, strtab) in tables {
+            for s in syms.iter() {
+                let wanted = matches!(s.st_type(), STT_FUNC | STT_OBJECT | STT_GNU_IFUNC | STT_NOTYPE);
+                if !wanted || s.st_shndx == 0 || s.st_value == 0 {
+                    continue;
+                }
+                let name = match strtab.get_at(s.st_name) {
+                    Some(n) if !n.is_empty() && !n.starts_with("$") => n,
+                    _ => continue,
+                };
+                let start = s.st_value.wrapping_add(bias);
+                self.syms.push(Sym { start, end: start + s.st_size, name: name.to_string() });
+            }
+        }
+        // the same symbol is usually in both tables
+        self.syms.sort_by(|a, b| a.start.cmp(&b.start).then(b.end.cmp(&a.end)));
+        self.syms.dedup_by(|a, b| a.start == b.start && a.name == b.name);
+        for i in 0..self.syms.len() {
+            if self.syms[i].end == self.syms[i].start {
+                let next = self.syms[i + 1..].iter().find(|s| s.start > self.syms[i].start).map(|s| s.start);
+                self.syms[i].end = next.unwrap_or(self.syms[i].start + 1);
+            }
+        }
+        Ok(self.syms.len().saturating_sub(before))
+    }
+    /// Symbol containing `addr`, and how far into it that is
+    pub fn lookup(&self, addr: u64) -> Option<(&str, u64)> {
+        let idx = self.syms.partition_point(|s| s.start <= addr);
+        // the closest start isn't always the one that covers it (nested or overlapping symbols)
+        self.syms[..idx].iter().rev().take(8).find(|s| addr < s.end).map(|s| (s.name.as_str(), addr - s.start))
+    }
+    /// "name+0x10", or just the address
+    pub fn describe(&self, addr: u64) -> String {
+        match self.lookup(addr) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, off)) => format!("{}+{:#x}", name, off),
+            None => format!("{:#x}", addr),
+        }
+    }
+}
@@ -1,4 +1,7 @@
+#[cfg(feature = "gdb")]
+use std::io::{Read, Write};
 use std::net::{TcpListener, TcpStream};
+use base::{info, ControlConnection, ControlTransport};
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum DebugEvent {
@@ -7,6 +10,8 @@ pub enum DebugEvent {
     Break,
     WatchWrite(u64),
     WatchRead(u64),
+    /// a watch expression became true
+    WatchExpr(u32),
 }
 
 pub enum DebugExecMode {
@@ -20,11 +25,307 @@ pub enum DebugRunEvent {
 }
 pub fn wait_for_tcp(port: u16) -> Result<TcpStream, Box<dyn std::error::Error>> {
     let sockaddr = format!("127.0.0.1:{}", port);
-    eprintln!("Waiting for a GDB connection on {:?}...", sockaddr);
+    info!("Waiting for a GDB connection on {:?}...", sockaddr);
 
     let sock = TcpListener::bind(sockaddr)?;
     let (stream, addr) = sock.accept()?;
-    eprintln!("Debugger connected from {}", addr);
+    info!("Debugger connected from {}", addr);
 
     Ok(stream)
 }
+/// A debugger connection over a Unix socket, or a named pipe on Windows
+pub struct ControlConn(pub ControlConnection);
+#[cfg(feature = "gdb")]
+impl gdbstub::conn::Connection for ControlConn {
+    type Error = std::io::Error;
+    fn write(&mut self, byte: u8) -> Result<(), Self::Error> {
+        self.0.write_all(&[byte])
+    }
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+        self.0.write_all(buf)
+    }
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.0.flush()
+    }
+}
+#[cfg(feature = "gdb")]
+impl gdbstub::conn::ConnectionExt for ControlConn {
+    fn read(&mut self) -> Result<u8, Self::Error> {
+        let mut byte = [0u8];
+        self.0.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+    fn peek(&mut self) -> Result<Option<u8>, Self::Error> {
+        self.0.peek()
+    }
+}
+/// wait_for_tcp, but on a ControlTransport (a socket path, or a pipe name on Windows)
+pub fn wait_for_control(name: &str) -> Result<ControlConn, Box<dyn std::error::Error>> {
+    let transport = ControlTransport::bind(name)?;
+    info!("Waiting for a GDB connection on {}...", transport.name());
+    let conn = transport.accept()?;
+    info!("Debugger connected on {}", transport.name());
+    Ok(ControlConn(conn))
+}
+/// What a watch expression needs from the cpu
+pub trait WatchCtx {
+    /// Register by name (arch specific, "pc" included)
+    fn watch_reg(&mut self, name: &str) -> Option<u64>;
+    /// `size` bytes of guest memory, in guest byte order
+    fn watch_mem(&mut self, addr: u64, size: u64) -> Option<u64>;
+}
+#[derive(Debug, Clone, PartialEq)]
+enum WatchNode {
+    Num(u64),
+    Reg(String),
+    Mem(u64, Box<WatchNode>),
+    Not(Box<WatchNode>),
+    Neg(Box<WatchNode>),
+    Bin(&'static str, Box<WatchNode>, Box<WatchNode>),
+}
+// binary operators, loosest first
+const WATCH_PREC: &[&[&str]] = &[
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!=", "<=", ">=", "<", ">"],
+    &["+", "-"],
+    &["*"],
+];
+fn watch_tokens(s: &str) -> Result<Vec<String>, String> {
+    let mut out = Vec::new();
+    let b = s.as_bytes();
+    let mut i = 0;
+    while i < b.len() {
+        let c = b[i] as char;
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            let st = i;
+            while i < b.len() && ((b[i] as char).is_ascii_alphanumeric() || b[i] == b'_') {
+                i += 1;
+            }
+            out.push(s[st..i].to_string());
+        } else {
+            let two = s.get(i..i + 2).unwrap_or("");
+            if ["||", "&&", "==", "!=", "<=", ">="].contains(&two) {
+                out.push(two.to_string());
+                i += 2;
+            } else if "|&^<>+-*![]()".contains(c) {
+                out.push(c.to_string());
+                i += 1;
+            } else {
+                return Err(format!("unexpected '{}'", c));
+            }
+        }
+    }
+    Ok(out)
+}
+struct WatchParser {
+    toks: Vec<String>,
+    pos: usize,
+}
+impl WatchParser {
+    fn peek(&self) -> Option<&str> {
+        self.toks.get(self.pos).map(|t| t.as_str())
+    }
+    fn next(&mut self) -> Option<String> {
+        let t = self.toks.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+    fn expect(&mut self, t: &str) -> Result<(), String> {
+        match self.next() {
+            Some(n) if n == t => Ok(()),
+            other => Err(format!("expected '{}', got {:?}", t, other)),
+        }
+    }
+    fn binary(&mut self, level: usize) -> Result<WatchNode, String> {
+        if level == WATCH_PREC.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(op) = self.peek().and_then(|p| WATCH_PREC[level].iter().find(|o| **o == p).copied()) {
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = WatchNode::Bin(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+    fn unary(&mut self) -> Result<WatchNode, String> {
+        let t = self.next().ok_or("unexpected end of expression")?;
+        match t.as_str() {
+            "!" => Ok(WatchNode::Not(Box::new(self.unary()?))),
+            "-" => Ok(WatchNode::Neg(Box::new(self.unary()?))),
+            "(" => {
+                let e = self.binary(0)?;
+                self.expect(")")?;
+                Ok(e)
+            }
+            "[" => self.mem(8),
+            "u8" | "u16" | "u32" | "u64" if self.peek() == Some("[") => {
+                self.pos += 1;
+                self.mem(t[1..].parse::<u64>().unwrap() / 8)
+            }
+            _ => {
+                let num = match t.strip_prefix("0x") {
+                    Some(h) => u64::from_str_radix(h, 16).ok(),
+                    None if t.as_bytes()[0].is_ascii_digit() => t.parse().ok(),
+                    None => return Ok(WatchNode::Reg(t)),
+                };
+                num.map(WatchNode::Num).ok_or(format!("bad number {}", t))
+            }
+        }
+    }
+    fn mem(&mut self, size: u64) -> Result<WatchNode, String> {
+        let e = self.binary(0)?;
+        self.expect("]")?;
+        Ok(WatchNode::Mem(size, Box::new(e)))
+    }
+}
+/// Something like `a0 == 0xdead && [sp+8] != 0`. Registers by name, `[addr]` reads 8 bytes
+/// (u8[], u16[], u32[] for less), C operators, all unsigned 64 bit. True is anything but 0
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchExpr {
+    pub text: String,
+    root: WatchNode,
+}
+impl WatchExpr {
+    pub fn parse(s: &str) -> Result<WatchExpr, String> {
+        let mut p = WatchParser {
+            toks: watch_tokens(s)?,
+            pos: 0,
+        };
+        let root = p.binary(0)?;
+        if let Some(t) = p.peek() {
+            return Err(format!("unexpected '{}'", t));
+        }
+        Ok(WatchExpr {
+            text: s.trim().to_string(),
+            root,
+        })
+    }
+    /// None if a register doesn't exist or memory can't be read
+    pub fn eval(&self, ctx: &mut dyn WatchCtx) -> Option<u64> {
+        fn ev(n: &WatchNode, ctx: &mut dyn WatchCtx) -> Option<u64> {
+            Some(match n {
+                WatchNode::Num(v) => *v,
+                WatchNode::Reg(r) => ctx.watch_reg(r)?,
+                WatchNode::Mem(size, a) => {
+                    let addr = ev(a, ctx)?;
+                    ctx.watch_mem(addr, *size)?
+                }
+                WatchNode::Not(e) => (ev(e, ctx)? == 0) as u64,
+                WatchNode::Neg(e) => ev(e, ctx)?.wrapping_neg(),
+                WatchNode::Bin(op, l, r) => {
+                    let a = ev(l, ctx)?;
+                    // short circuit, so `p != 0 && [p] == 1` doesn't read through null
+                    match *op {
+                        "&&" if a == 0 => return Some(0),
+                        "||" if a != 0 => return Some(1),
+                        _ => {}
+                    }
+                    let b = ev(r, ctx)?;
+                    match *op {
+                        "&&" | "||" => (b != 0) as u64,
+                        "|" => a | b,
+                        "^" => a ^ b,
+                        "&" => a & b,
+                        "==" => (a == b) as u64,
+                        "!=" => (a != b) as u64,
+                        "<=" => (a <= b) as u64,
+                        ">=" => (a >= b) as u64,
+                        "<" => (a < b) as u64,
+                        ">" => (a > b) as u64,
+                        "+" => a.wrapping_add(b),
+                        "-" => a.wrapping_sub(b),
+                        "*" => a.wrapping_mul(b),
+                        _ => unreachable!(),
+                    }
+                }
+            })
+        }
+        ev(&self.root, ctx)
+    }
+}
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum WatchMode {
+    /// after every instruction (slow)
+    Insn,
+    /// only when control flow jumps somewhere
+    Block,
+}
+/// Watch expressions the debugger registered. Execution stops when one goes from false to true
+pub struct WatchList {
+    pub mode: WatchMode,
+    watches: Vec<(u32, WatchExpr, bool)>,
+    next_id: u32,
+}
+impl Default for WatchList {
+    fn default() -> Self {
+        WatchList {
+            mode: WatchMode::Insn,
+            watches: vec![],
+            next_id: 1,
+        }
+    }
+}
+impl WatchList {
+    pub fn is_empty(&self) -> bool {
+        self.watches.is_empty()
+    }
+    pub fn add(&mut self, expr: WatchExpr, ctx: &mut dyn WatchCtx) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        // one that's already true only fires once it has been false
+        let now = expr.eval(ctx).map_or(false, |v| v != 0);
+        self.watches.push((id, expr, now));
+        id
+    }
+    pub fn remove(&mut self, id: u32) -> bool {
+        let before = self.watches.len();
+        self.watches.retain(|w| w.0 != id);
+        self.watches.len() != before
+    }
+    /// The first watch that just became true
+    pub fn check(&mut self, ctx: &mut dyn WatchCtx) -> Option<(u32, String)> {
+        let mut hit = None;
+        for (id, expr, was) in self.watches.iter_mut() {
+            let now = expr.eval(ctx).map_or(false, |v| v != 0);
+            if now && !*was && hit.is_none() {
+                hit = Some((*id, expr.text.clone()));
+            }
+            *was = now;
+        }
+        hit
+    }
+    /// `monitor` commands: watch EXPR, unwatch ID, watches, watchmode insn|block
+    pub fn monitor_cmd(&mut self, cmd: &str, ctx: &mut dyn WatchCtx) -> Option<String> {
+        let (word, rest) = cmd.trim().split_once(' ').unwrap_or((cmd.trim(), ""));
+        Some(match word {
+            "watch" => match WatchExpr::parse(rest) {
+                Ok(e) => format!("watch {}: {}", self.add(e, ctx), rest.trim()),
+                Err(e) => format!("bad expression: {}", e),
+            },
+            "unwatch" => match rest.trim().parse() {
+                Ok(id) if self.remove(id) => format!("removed watch {}", id),
+                _ => format!("no watch {}", rest.trim()),
+            },
+            "watches" => {
+                let mut s = format!("mode: {:?}\n", self.mode);
+                for (id, e, _) in &self.watches {
+                    s += &format!("{}: {}\n", id, e.text);
+                }
+                s
+            }
+            "watchmode" => match rest.trim() {
+                "insn" => { self.mode = WatchMode::Insn; "watches checked every instruction".to_string() }
+                "block" => { self.mode = WatchMode::Block; "watches checked on jumps".to_string() }
+                _ => "watchmode insn|block".to_string(),
+            },
+            _ => return None,
+        })
+    }
+}
@@ -1,5 +1,6 @@
+use std::ffi::CString;
 use std::fs::File;
-use std::{fmt, mem, process, result};
+use std::{fmt, mem, result};
 use std::borrow::Borrow;
 use std::cmp::{max, min};
 use std::collections::HashMap;
@@ -14,7 +15,7 @@ use base::platform::MemoryMapping;
 use base::{debug, info, MappedRegion, MemoryMappingArena, Protection, warn};
 use goblin::elf::*;
 use goblin::elf::dynamic::{DT_INIT, DT_NEEDED, DT_RPATH, DT_RUNPATH, DT_STRTAB};
-use goblin::elf::program_header::PT_LOAD;
+use goblin::elf::program_header::{PF_W, PT_LOAD};
 use multimap::MultiMap;
 use thiserror::Error as ThisError;
 use thiserror::*;
@@ -27,7 +28,41 @@ use crate::armv8::ume::load::init_arm64_runtime;
 
 use crate::common::memory::*;
 use crate::linux_usermode::defs::SigConstants;
+use crate::linux_usermode::signals::{catch_fatal_defaults, GuestSignals};
+use crate::common::hotpatch::PatchGate;
+use crate::linux_usermode::memusage::MemUsage;
+use crate::linux_usermode::main::GroupExit;
+use crate::linux_usermode::console::Console;
+use crate::common::hart_stats::{HartCounters, StatsRegistry};
+use crate::linux_usermode::dynload::{self, DlPending};
+use crate::linux_usermode::sched::{SchedEvent, SchedState};
+use crate::common::poison::PoisonMap;
+pub use crate::common::fault_inject::FaultInjector;
+use crate::linux_usermode::locks::LocalLocks;
+use crate::linux_usermode::ids::{IdTable, INIT_PID};
+use crate::linux_usermode::fakeroot::{FakeRoot, FakeTable};
+use crate::linux_usermode::cputime::{CpuAccounting, ThreadCpu};
+use crate::linux_usermode::summary::SummaryRecorder;
+use crate::riscv::interpreter::block_store::BlockStore;
+use crate::linux_usermode::isa_report::IsaRecorder;
+pub use crate::riscv::isa_usage::IsaTarget;
+pub use crate::linux_usermode::cputime::DEFAULT_GUEST_MHZ;
+pub use crate::linux_usermode::throttle::{IoClass, IoThrottle, ThrottleConfig};
+pub use crate::linux_usermode::fakeroot::FakeStore;
+pub use crate::linux_usermode::sandbox::SandboxPolicy;
+pub use crate::linux_usermode::memusage::{MemLimit, OomPolicy};
+use crate::linux_usermode::sandbox::apply_sandbox;
+pub use crate::linux_usermode::sched::DEFAULT_SCHED_QUANTUM;
+pub use crate::linux_usermode::vfs::FsMode;
+pub use crate::linux_usermode::identity::GuestIdentity;
+pub use crate::linux_usermode::fdpass::{FdCaps, FdGrant, FdPolicy};
+pub use crate::linux_usermode::conrelay::{ConsoleBackend, ConsoleSpec};
+pub use crate::common::spin::{SpinPolicy, DEFAULT_SPIN_THRESHOLD};
+use crate::common::symbols::SymbolMap;
+use crate::linux_usermode::vfs::{PathIntent, resolve_guest_path};
+use crate::linux_usermode::elfcheck::{check_same_target, elf_target};
 use crate::riscv::ume::load::{init_riscv_runtime};
+use crate::riscv::interpreter::custom::CustomExtensions;
 #[derive(ThisError, Debug)]
 pub enum Error {
     #[error("Failed to open ELF file")]
@@ -44,6 +79,37 @@ pub enum Error {
     NotFound(String),
     #[error("Executable wants an interpreter, but no sysroot path was given")]
     NoInterp,
+    #[error("Failed to apply the host sandbox: {0}")]
+    Sandbox(std::io::Error),
+    #[error("Built-in loader failed: {0}")]
+    DynLoad(anyhow::Error),
+    #[error("Can't pass fds to the guest: {0}")]
+    FdPass(String),
+    #[error("Can't set up the guest console: {0}")]
+    Console(String),
+    #[error("Can't run {0}: {1}")]
+    Unsupported(PathBuf, String),
+    #[error("Can't set up the guest stack: {0}")]
+    Stack(crate::linux_usermode::stack::StackError),
+    #[error("{0}")]
+    WrongMachine(String),
+    #[error("Can't load {0}: {1}")]
+    Load(PathBuf, anyhow::Error),
+}
+impl Error {
+    /// What the emulator exits with when the guest can't be started, the shell's numbers: 127
+    /// when there's nothing to run, 126 when there's something but we can't run it, and 1 when
+    /// setting things up for it failed
+    pub fn exit_status(&self) -> i32 {
+        match self {
+            Error::NotFound(_) | Error::ElfFileError => 127,
+            Error::Io(_, e) if e.kind() == std::io::ErrorKind::NotFound => 127,
+            Error::Io(..) | Error::ParseError(..) | Error::InvalidPath(_) | Error::NoLoadSegments
+            | Error::NoInterp | Error::Unsupported(..) | Error::WrongMachine(_) => 126,
+            Error::Sandbox(_) | Error::DynLoad(_) | Error::FdPass(_) | Error::Console(_) | Error::Stack(_)
+            | Error::Load(..) => 1,
+        }
+    }
 }
 #[derive(Copy, Clone, PartialEq)]
 pub enum MachineType {
@@ -60,6 +126,10 @@ pub struct UserModeInit {
     pub intrp_idx: Option<usize>,
     pub args: Vec<String>,
     pub envp: Vec<String>,
+    /// What /proc/self/exe points at, as the guest sees it
+    pub exe_link: String,
+    /// Left over from the built-in loader, for the cpu to finish
+    pub dl: Option<DlPending>,
 }
 // this does
 impl Default for UserModeInit {
@@ -72,9 +142,15 @@ impl Default for UserModeInit {
             intrp_idx: None,
             args: vec![],
             envp: vec![],
+            exe_link: String::new(),
+            dl: None,
         }
     }
 }
+/// Everything a usermode guest (thread) needs, so several guests can be run from one process.
+/// What they still share is the host's: guest addresses are host addresses, guest fds are host
+/// fds, and the host signal dispositions are process-wide, so guests in one process have to stay
+/// out of each other's memory ranges and shouldn't both install handlers for the same signal
 #[derive(Clone)]
 pub struct UserModeRuntime {
     pub initvars: Arc<Mutex<UserModeInit>>,
@@ -96,8 +172,166 @@ pub struct UserModeRuntime {
     pub tid_val: u64,
     pub flags: i32,
     pub ctid_val: u64,
+    /// Record locks for files the host can't lock, shared by all threads
+    pub local_locks: Arc<LocalLocks>,
+    pub opts: UserModeOptions,
+    /// Set when guest threads are scheduled deterministically, shared by all threads
+    pub sched: Option<Arc<Mutex<SchedState>>>,
+    /// Per thread: why the current slice should end, set by syscall handlers
+    pub sched_event: Option<SchedEvent>,
+    /// Guest pid table, when pids are virtualized. Shared with forked children too
+    pub ids: Option<Arc<IdTable>>,
+    /// Remembered ownership and modes, in fakeroot mode
+    pub fakeroot: Option<Arc<FakeRoot>>,
+    /// Retired instruction counts of all threads, for getrusage() and friends
+    pub cputime: Arc<CpuAccounting>,
+    /// Per thread: where this thread publishes its count
+    pub cpu_slot: Option<Arc<ThreadCpu>>,
+    /// Per thread: icount when the thread (or forked process) started
+    pub icount_base: u64,
+    /// Counters for the run summary, when one was asked for
+    pub summary: Option<Arc<SummaryRecorder>>,
+    /// Decoded RISC-V blocks kept between runs, with --block-cache
+    pub block_store: Option<Arc<BlockStore>>,
+    /// Instruction counts for the ISA usage report, when one was asked for
+    pub isa: Option<Arc<IsaRecorder>>,
+    /// Per thread: guest signal handlers and pending signals
+    pub signals: Arc<GuestSignals>,
+    /// What the guest has mapped, shared by all threads
+    pub memusage: Arc<MemUsage>,
+    /// Every hart's memory and cache counters, when asked for
+    pub stats: Option<Arc<StatsRegistry>>,
+    /// Per thread: this hart's counters in there
+    pub hart_stats: Option<Arc<HartCounters>>,
+    /// Symbols of the loaded objects, read the first time something asks
+    pub symbols: Arc<Mutex<Option<SymbolMap>>>,
+    /// Set once a thread called exit_group(), shared by all threads
+    pub group_exit: Arc<GroupExit>,
+    /// The guest's stdin, shared by all threads
+    pub console: Arc<Console>,
+    /// Pausing the harts to patch guest code, shared by all threads
+    pub patch_gate: Arc<PatchGate>,
 
 }
+/// Settings for a usermode run that come from the command line
+#[derive(Clone, Debug)]
+pub struct UserModeOptions {
+    /// Run all guest threads on one host thread, switching every this many instructions
+    pub det_sched_quantum: Option<u64>,
+    /// Run all guest threads on this many host threads, switching every so many instructions
+    /// (workers, quantum)
+    pub sched_pool: Option<(usize, u64)>,
+    /// Guest ranges the embedder wants guarded, checked on every guest load and store
+    pub poison: Option<Arc<Mutex<PoisonMap>>>,
+    /// Track guest calls/returns so backtraces work without frame pointers
+    pub shadow_stack: bool,
+    /// What guest writes to the sysroot do
+    pub fs_mode: FsMode,
+    /// The sysroot is the guest's whole root directory (container style), not just its /usr, /lib...
+    pub rootfs: bool,
+    /// Become a child subreaper, so orphaned guest processes are left for the guest to reap
+    pub as_init: bool,
+    /// argv[0] for the guest, if it shouldn't be the executable path
+    pub argv0: Option<String>,
+    /// Host command line that runs the emulator with these same options, minus the executable and
+    /// its arguments. Guest execve() of a guest binary needs it
+    pub reexec_prefix: Option<Vec<String>>,
+    /// Give guest processes and threads their own pids instead of the host ones
+    pub virtual_pids: bool,
+    /// Pid table inherited from the emulator that exec'd us
+    pub id_table_fd: Option<i32>,
+    /// uid/gid the guest sees itself running as
+    pub guest_uid: Option<u32>,
+    pub guest_gid: Option<u32>,
+    /// Pretend to be root: chown/mknod work and are remembered in the given place
+    pub fakeroot: Option<FakeStore>,
+    /// Fakeroot table inherited from the emulator that exec'd us
+    pub fakeroot_table_fd: Option<i32>,
+    /// Guest clock rate, for turning instruction counts into CPU time
+    pub guest_mhz: u64,
+    /// Limits and extra latency for guest file and socket I/O, can be changed while running
+    pub io_throttle: Option<Arc<IoThrottle>>,
+    /// Memory faults, bit flips and failing I/O to inject
+    pub faults: Option<Arc<FaultInjector>>,
+    /// Write a JSON summary of the run here at exit ("-" is stdout)
+    pub summary: Option<String>,
+    /// Pace guest execution to this many million instructions per second
+    pub mips: Option<u64>,
+    /// Seccomp filter to put the emulator in once the guest is loaded
+    pub sandbox: Option<SandboxPolicy>,
+    /// Handlers for custom RISC-V opcodes and vendor CSRs
+    pub riscv_custom: Option<Arc<CustomExtensions>>,
+    /// Most memory the guest may commit, and what happens when it tries for more
+    pub mem_limit: Option<MemLimit>,
+    /// Link the guest ourselves instead of running its ld.so, searching these directories for
+    /// libraries (the sysroot's usual ones if empty)
+    pub builtin_loader: Option<Vec<PathBuf>>,
+    /// Count memory accesses, page walks and block cache use per hart
+    pub stats: bool,
+    /// Host name, machine id, MAC address... the guest sees instead of the host's
+    pub identity: Option<Arc<GuestIdentity>>,
+    /// Look out for guest loops that spin without getting anywhere
+    pub spin: Option<SpinPolicy>,
+    /// Host fds the guest gets at fixed numbers, and what it may do with them
+    pub fd_policy: Option<Arc<FdPolicy>>,
+    /// Check guest pointers in syscall arguments against the guest's mappings, EFAULT if they're bad
+    pub check_pointers: bool,
+    /// Where the guest's stdin, stdout and stderr go instead of ours
+    pub console: Option<ConsoleSpec>,
+    /// Keep the executable's decoded RISC-V blocks in this file between runs
+    pub block_cache: Option<String>,
+    /// Write which RISC-V instructions and extensions the guest ran here at exit (JSON, "-" is stdout)
+    pub isa_report: Option<String>,
+    /// Core to check the ISA usage report against
+    pub isa_target: Option<IsaTarget>,
+    /// The guest exiting or dying of a signal only ends its own threads, never the process, for
+    /// embedders running guests in a process of their own. The run returns the status then, 128
+    /// plus the signal for a killed guest like a shell shows it
+    pub keep_process: bool,
+    /// Serve meminfo, stats, throttle and loglevel on this socket path (pipe name on Windows)
+    pub monitor: Option<String>,
+}
+impl Default for UserModeOptions {
+    fn default() -> Self {
+        UserModeOptions {
+            det_sched_quantum: None,
+            sched_pool: None,
+            poison: None,
+            shadow_stack: false,
+            fs_mode: FsMode::Passthrough,
+            rootfs: false,
+            as_init: false,
+            argv0: None,
+            reexec_prefix: None,
+            virtual_pids: false,
+            id_table_fd: None,
+            guest_uid: None,
+            guest_gid: None,
+            fakeroot: None,
+            fakeroot_table_fd: None,
+            guest_mhz: DEFAULT_GUEST_MHZ,
+            io_throttle: None,
+            faults: None,
+            summary: None,
+            mips: None,
+            sandbox: None,
+            riscv_custom: None,
+            mem_limit: None,
+            builtin_loader: None,
+            stats: false,
+            identity: None,
+            spin: None,
+            fd_policy: None,
+            check_pointers: false,
+            console: None,
+            block_cache: None,
+            isa_report: None,
+            isa_target: None,
+            keep_process: false,
+            monitor: None,
+        }
+    }
+}
 #[derive(Default)]
 pub struct MemState {
     pub stack_size: u64,
@@ -137,6 +371,26 @@ impl Default for UserModeRuntime {
             str_path: "".to_string(),
             tid_val: 0,
             flags: 0,
+            opts: Default::default(),
+            sched: None,
+            sched_event: None,
+            local_locks: Arc::new(Default::default()),
+            ids: None,
+            fakeroot: None,
+            cputime: Arc::new(Default::default()),
+            cpu_slot: None,
+            icount_base: 0,
+            summary: None,
+            block_store: None,
+            isa: None,
+            signals: GuestSignals::new(),
+            memusage: Arc::new(MemUsage::new()),
+            stats: None,
+            hart_stats: None,
+            symbols: Arc::new(Mutex::new(None)),
+            group_exit: Arc::new(Default::default()),
+            console: Arc::new(Default::default()),
+            patch_gate: Arc::new(PatchGate::new()),
             ctid_val: 0
         }
     }
@@ -151,34 +405,116 @@ pub struct Segment {
 
 pub type initResult<T> = result::Result<T, Error>;
 
-pub fn init_user_mode_emulation(execpath: String, args: Vec<String>, search_path: String) -> initResult<()> {
+/// Loads and runs a usermode guest, returns its exit status
+pub fn init_user_mode_emulation(execpath: String, args: Vec<String>, search_path: String,
+                                mut opts: UserModeOptions) -> initResult<i32> {
     // todo dont forget to check pagesize validiy (and file exists)
-    let pbuf = PathBuf::from(execpath.clone());
-    let mut fle = File::open(pbuf.clone()).map_err(|_| Error::ElfFileError)?;
-    let mut data = Vec::new();
-    fle.read_to_end(&mut data).map_err(|_| Error::ElfFileError)?;
-    let ef = goblin::elf::Elf::parse(&data).map_err(|e| Error::ParseError(PathBuf::from(pbuf.clone()), e))?;
-    let mut args_str: Vec<String> = vec![execpath.clone()];
+    if let Some(fp) = &opts.fd_policy {
+        // before anything of ours gets an fd number
+        fp.install().map_err(Error::FdPass)?;
+    }
+    if let Some(con) = &opts.console {
+        con.attach().map_err(Error::Console)?;
+    }
+    // a relative path on the command line is the host's
+    let host_exec = if opts.rootfs && execpath.starts_with('/') {
+        let guest = CString::new(execpath.clone()).map_err(|_| Error::ElfFileError)?;
+        let host = resolve_guest_path(&opts.fs_mode, search_path.as_str(), true, None, libc::AT_FDCWD,
+                                      guest.as_ptr(), PathIntent::Read).map_err(|_| Error::ElfFileError)?;
+        host.to_string_lossy().to_string()
+    } else {
+        execpath.clone()
+    };
+    let pbuf = PathBuf::from(host_exec.clone());
+    let (exec_path, exec_file, data) = read_object(&pbuf)?;
+    let ef = goblin::elf::Elf::parse(&data).map_err(|e| Error::ParseError(pbuf.clone(), e))?;
+    let mut args_str: Vec<String> = vec![opts.argv0.clone().unwrap_or(execpath.clone())];
     for i in &args {
         args_str.push(i.clone());
     }
-    let machine_type = ef.header.e_machine;
-    let mut umr = if machine_type == goblin::elf::header::EM_RISCV {
-        init_riscv_runtime(&ef)
-    } else if machine_type == goblin::elf::header::EM_AARCH64 {
-        init_arm64_runtime(&ef)
-    } else {
-        panic!();
+    let target = elf_target(&ef.header).map_err(|e| Error::Unsupported(pbuf.clone(), e))?;
+    let mut umr = match target.machine {
+        MachineType::Riscv => init_riscv_runtime(&ef),
+        MachineType::Arm64 => init_arm64_runtime(&ef),
+        MachineType::None => unreachable!(),
     };
     {
         let mut initm = umr.initvars.lock();
         initm.args = args_str;
+        let canon = std::fs::canonicalize(&pbuf).unwrap_or(pbuf.clone()).to_string_lossy().to_string();
+        initm.exe_link = match canon.strip_prefix(search_path.as_str()) {
+            Some(rest) if opts.rootfs && !search_path.is_empty() => rest.to_string(),
+            _ => canon,
+        };
         initm.envp = std::env::vars()
             .map(|(k, v)| format!("{}={}", k, v))
             .collect();
         umr.str_path = search_path.clone();
         umr.search_path = PathBuf::from(search_path);
     }
+    if let Some(q) = opts.det_sched_quantum {
+        info!("Scheduling guest threads deterministically, quantum is {} instructions", q);
+        umr.sched = Some(Arc::new(Mutex::new(SchedState::new(q, opts.guest_mhz))));
+    } else if let Some((workers, q)) = opts.sched_pool {
+        info!("Running guest threads on {} host threads, quantum is {} instructions", workers, q);
+        umr.sched = Some(Arc::new(Mutex::new(SchedState::pooled(q, opts.guest_mhz, workers))));
+    }
+    if let Some(store) = opts.fakeroot {
+        let uid = *opts.guest_uid.get_or_insert(0);
+        let gid = *opts.guest_gid.get_or_insert(0);
+        let tbl = match opts.fakeroot_table_fd {
+            Some(fd) => FakeTable::open(fd),
+            None => FakeTable::create(),
+        };
+        match tbl {
+            result::Result::Ok(t) => umr.fakeroot = Some(Arc::new(FakeRoot::new(store, uid, gid, t))),
+            Err(e) => warn!("Couldn't set up the fakeroot table (errno {}), guest isn't root", e),
+        }
+    }
+    if opts.virtual_pids {
+        let tbl = match opts.id_table_fd {
+            Some(fd) => IdTable::open(fd),
+            None => IdTable::create().map(|t| {
+                if opts.as_init {
+                    t.insert(unsafe { libc::getpid() }, INIT_PID);
+                }
+                t
+            }),
+        };
+        match tbl {
+            result::Result::Ok(t) => umr.ids = Some(Arc::new(t)),
+            Err(e) => warn!("Couldn't set up the guest pid table (errno {}), guest sees host pids", e),
+        }
+    }
+    if opts.as_init {
+        // orphans get reparented to us instead of the host's init, so the guest init can wait() on them
+        if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) } < 0 {
+            warn!("Couldn't become a child subreaper, orphaned guest processes go to the host's init");
+        }
+    }
+    if opts.stats {
+        umr.stats = Some(Arc::new(StatsRegistry::new()));
+    }
+    if let Some(dest) = &opts.summary {
+        umr.summary = Some(Arc::new(SummaryRecorder::new(dest.clone())));
+    }
+    // the host signal handler needs them before the guest's first sigaction()
+    umr.signals.info.lock().unwrap().cnsts = umr.sigcnst.lock().clone();
+    if opts.summary.is_some() || opts.isa_report.is_some() {
+        // dying of a signal should leave a report too
+        catch_fatal_defaults();
+    }
+    if let Some(dest) = &opts.isa_report {
+        if target.machine == MachineType::Riscv {
+            if let Some(t) = opts.isa_target.as_ref().filter(|t| t.is_64 != target.is_64) {
+                warn!("--isa-target {} doesn't match this {} binary, checking against it anyway", t.spec, target.name());
+            }
+            umr.isa = Some(Arc::new(IsaRecorder::new(dest.clone(), opts.isa_target.clone())));
+        } else {
+            warn!("No ISA usage report for {} guests, only riscv ones", target.name());
+        }
+    }
+    umr.opts = opts;
     // todo call arch specific filler
     let mut p_load_vaddr = 0;
     for zi in &ef.program_headers {
@@ -192,9 +528,8 @@ pub fn init_user_mode_emulation(execpath: String, args: Vec<String>, search_path
     if usebase == 0 {
         usebase = 0x10000; // todo: arch agnostic?
     }
-    let exec_index = umr.load_object( pbuf.clone(),
-                                      Some(usebase),
-                                      false).unwrap();
+    let exec_index = umr.map_object(exec_path, exec_file, &data, &ef, Some(usebase), false)
+        .map_err(|e| Error::Load(pbuf.clone(), e))?;
     {
         let mut meminit = umr.memstate.lock();
         let mut ivi = umr.initvars.lock();
@@ -205,11 +540,19 @@ pub fn init_user_mode_emulation(execpath: String, args: Vec<String>, search_path
     }
     let mmapdown = umr.heap_grow_down;
 
-    let intrpidx: Option<usize> = if ef.interpreter.is_some() {
+    let builtin = umr.opts.builtin_loader.clone()
+        .filter(|_| ef.interpreter.is_some() || !ef.libraries.is_empty());
+    let intrpidx: Option<usize> = if builtin.is_some() {
+        None
+    } else if ef.interpreter.is_some() {
         let v = ef.interpreter.unwrap();
-        let path = umr.object_path(v).unwrap();
+        let path = umr.object_path(v)?;
+        let (path, ifile, idata) = read_object(&path)?;
+        let ief = goblin::elf::Elf::parse(&idata).map_err(|e| Error::ParseError(path.clone(), e))?;
+        check_same_target(target, &pbuf, "program interpreter", &ief, &path).map_err(Error::WrongMachine)?;
         let ibase = umr.initvars.lock().mmap_barrier;
-        let retval = umr.load_object(path, Some(ibase), mmapdown).unwrap();
+        let retval = umr.map_object(path.clone(), ifile, &idata, &ief, Some(ibase), mmapdown)
+            .map_err(|e| Error::Load(path, e))?;
         let mut iv = umr.initvars.lock();
         let psize = iv.objects[retval].mem.size() as u64;
         if mmapdown {
@@ -221,6 +564,14 @@ pub fn init_user_mode_emulation(execpath: String, args: Vec<String>, search_path
     } else {
         None
     };
+    if let Some(mut dirs) = builtin {
+        if dirs.is_empty() {
+            let root = if umr.str_path.is_empty() { PathBuf::from("/") } else { umr.search_path.clone() };
+            dirs = dynload::default_dirs(&root);
+        }
+        info!("Linking {} with the built-in loader", execpath);
+        dynload::link(&mut umr, &ef, exec_index, &dirs).map_err(Error::DynLoad)?;
+    }
     let mut iv = umr.initvars.lock();
 
     if mmapdown {
@@ -248,19 +599,56 @@ pub fn init_user_mode_emulation(execpath: String, args: Vec<String>, search_path
     };
     // goblin::elf::header::EM_RISCV
     mem::drop(iv);
-    match umr.machine_type {
+    if let Some(id) = &mut umr.opts.identity {
+        if let Err(e) = Arc::make_mut(id).make_view() {
+            warn!("Couldn't write the guest identity files, the guest sees the host's: {}", e);
+        }
+    }
+    if let Some(name) = umr.opts.monitor.clone() {
+        if let Err(e) = crate::linux_usermode::monitor::start(&umr, &name) {
+            warn!("Couldn't start the monitor on {}: {}", name, e);
+        }
+    }
+    let identity = umr.opts.identity.clone();
+    if let Some(policy) = umr.opts.sandbox {
+        // everything the emulator itself needs to read is loaded by now
+        if let Err(e) = apply_sandbox(policy) {
+            if let Some(id) = &identity {
+                id.remove_view();
+            }
+            return Err(Error::Sandbox(e));
+        }
+        info!("Host sandbox is on");
+    }
+    let status = match umr.machine_type {
         MachineType::Riscv => {
-            crate::riscv::ume::load::init_riscv_ume(umr, &ef);
+            crate::riscv::ume::load::init_riscv_ume(umr, &ef)
         },
         MachineType::Arm64 => {
-            crate::armv8::ume::load::init_arm64_ume(umr, &ef);
+            crate::armv8::ume::load::init_arm64_ume(umr, &ef)
         }
         _ => {
             panic!("unsupported machine type");
         }
 
+    };
+    if let Some(id) = &identity {
+        id.remove_view();
     }
-    process::exit(0);
+    status
+}
+/// The canonical path of an ELF object, the file (kept open for mapping it) and its contents
+pub fn read_object<P: AsRef<Path>>(path: P) -> initResult<(PathBuf, File, Vec<u8>)> {
+    let path = path
+        .as_ref()
+        .canonicalize()
+        .map_err(|e| Error::Io(path.as_ref().to_path_buf(), e))?;
+    let mut fs_file = File::open(&path).map_err(|e| Error::Io(path.clone(), e))?;
+    let mut input = Vec::new();
+    fs_file
+        .read_to_end(&mut input)
+        .map_err(|e| Error::Io(path.clone(), e))?;
+    result::Result::Ok((path, fs_file, input))
 }
 /// Computes the minimal range that contains two ranges.
 fn convex_hull<T: std::cmp::Ord>(a: Range<T>, b: Range<T>) -> Range<T> {
@@ -269,33 +657,43 @@ fn convex_hull<T: std::cmp::Ord>(a: Range<T>, b: Range<T>) -> Range<T> {
 
 
 impl UserModeRuntime {
-    pub fn object_path(&self, name: &str) -> Result<PathBuf> {
+    /// "function+0x10" for a guest address in the executable or a library we loaded, just the
+    /// address for anything else
+    pub fn describe_pc(&self, pc: u64) -> String {
+        let mut cache = self.symbols.lock();
+        let map = cache.get_or_insert_with(|| {
+            let mut m = SymbolMap::new();
+            for o in &self.initvars.lock().objects {
+                if let Err(e) = m.add_elf(&o.path, o.base as u64) {
+                    debug!("No symbols from {}", e);
+                }
+            }
+            m
+        });
+        map.describe(pc)
+    }
+    pub fn object_path(&self, name: &str) -> initResult<PathBuf> {
         // this function is just for interpreter,
         if name.is_empty() {
-            return Err(anyhow::Error::from(Error::NoInterp));
+            return Err(Error::NoInterp);
         }
-        let val = self.search_path.join(&name[1..]).canonicalize().unwrap();
-        if val.exists() {
-            Ok(val)
-        } else {
-            Err(anyhow::Error::from(Error::NotFound(name.into())))
+        match self.search_path.join(&name[1..]).canonicalize() {
+            result::Result::Ok(val) if val.exists() => result::Result::Ok(val),
+            _ => Err(Error::NotFound(name.into())),
         }
-
     }
     // inspired from https://fasterthanli.me/
     pub fn load_object<P: AsRef<Path>>(&mut self, path: P, use_base: Option<u64>, base_subtract: bool) -> anyhow::Result<usize> {
+        let (path, fs_file, input) = read_object(path)?;
+        let ef = goblin::elf::Elf::parse(&input).map_err(|e| Error::ParseError(path.clone(), e))?;
+        self.map_object(path, fs_file, &input, &ef, use_base, base_subtract)
+    }
+    /// load_object() for a file the caller already read (with read_object()) and parsed, to look
+    /// at it before it's mapped
+    pub fn map_object(&mut self, path: PathBuf, fs_file: File, input: &[u8], ef: &Elf, use_base: Option<u64>,
+                      base_subtract: bool) -> anyhow::Result<usize> {
         let mut iv = self.initvars.lock();
-        let path = path
-            .as_ref()
-            .canonicalize()
-            .map_err(|e| Error::Io(path.as_ref().to_path_buf(), e))?;
-        let mut fs_file = std::fs::File::open(&path).map_err(|e| Error::Io(path.clone(), e))?;
-        let mut input = Vec::new();
-        fs_file
-            .read_to_end(&mut input)
-            .map_err(|e| Error::Io(path.clone(), e))?;
         debug!("Loading {:?}", path);
-        let ef = goblin::elf::Elf::parse(&input).map_err(|e| Error::ParseError(path.clone(), e))?;
         let load_segments = || {
             ef.program_headers
                 .iter()
@@ -384,8 +782,17 @@ impl UserModeRuntime {
                 })
             })
             .collect::<Result<Vec<_>>>()?;
+        // the whole hull is mapped on the host, but the guest only gets to touch the segments
+        for seg in &segments {
+            let start = (base as u64).wrapping_add(seg.vaddr_range.start) & !(self.guest_pagesize - 1);
+            let end = round_up((base as u64).wrapping_add(seg.vaddr_range.end), self.guest_pagesize);
+            self.memusage.map_loader(start, end - start, seg.flags & PF_W != 0);
+        }
         let ep = ((base as u64) + ef.entry.clone()) as u64;
         let realend = ((memareana.as_ptr() as u64) + (memareana.size() as u64));
+        let build_id = ef.iter_note_headers(input).and_then(|mut notes| {
+            notes.find_map(|n| n.ok().filter(|n| n.n_type == note::NT_GNU_BUILD_ID).map(|n| n.desc.to_vec()))
+        });
         let obj = Object {
             // ef,
             entry_point: ep,
@@ -394,6 +801,7 @@ impl UserModeRuntime {
             mem_range,
             real_end: realend,
             segments,
+            build_id,
             mem: memareana,
             fs_file
         };
@@ -423,6 +831,9 @@ pub struct Object {
     /// The memory segments associated with this object.
     pub segments: Vec<Segment>,
 
+    /// Its GNU build-id note, if it has one
+    pub build_id: Option<Vec<u8>>,
+
     pub mem: MemoryMappingArena,
     pub fs_file: File,
 
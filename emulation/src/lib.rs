@@ -6,5 +6,14 @@ pub mod elf;
 #[cfg(feature = "linux-usermode")]
 mod linux_usermode;
 pub(crate) mod debug;
+pub use common::poison;
+pub use riscv::interpreter::guest_call;
+pub use riscv::interpreter::snapshot;
+pub use common::engine;
+pub use common::symbols;
+pub use riscv::interpreter::engine as riscv_engine;
+pub use riscv::interpreter::fuzz_machine;
 
 
+pub use common::fault_inject;
+pub use common::logctl;
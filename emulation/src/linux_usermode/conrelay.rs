@@ -0,0 +1,321 @@
+// The guest's console (fds 0, 1 and 2) on something other than our own stdio: a Unix socket or a
+// TCP port that one client at a time connects to, and optionally a log file that gets a copy of
+// everything the guest prints, whether anyone is connected or not ("stdio,log=FILE" keeps the
+// terminal and only adds the log).
+// The guest's stdio becomes two pipes, and a relay process on the other end moves bytes between
+// them, the connection and the log. It's a process and not a thread so it survives the guest's
+// execve() (which execs us again), and it's forked twice so the guest's wait() never sees it.
+// It goes away once everything holding the guest's stdout is gone. Like a serial line, output
+// with nobody connected only goes to the log, input that the guest doesn't read fast enough is
+// dropped, and a client going away just means the next one can connect. Output a socket client
+// doesn't read fast enough is kept up to MAX_BACKLOG and dropped past that, oldest first, so a
+// slow client never holds up the guest.
+// Since the guest's stdio are pipes even with "stdio", isatty() is false for it and terminal
+// ioctls (TCGETS, TIOCGWINSZ...) fail with ENOTTY: programs behave like their output is piped
+// (full buffering, no colors or line editing), and there's no window size to read.
+use std::fs::{File, OpenOptions};
+use std::net::TcpListener;
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use libc::{c_int, c_void, pollfd, EAGAIN, EINTR, F_GETFL, F_SETFL, MSG_DONTWAIT, MSG_NOSIGNAL,
+           O_CLOEXEC, O_NONBLOCK, POLLIN, POLLOUT, SIGINT, SIGPIPE, SIGQUIT, SIGTSTP, SIG_IGN};
+
+/// Most guest output kept for a socket client that's behind
+pub const MAX_BACKLOG: usize = 256 << 10;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConsoleBackend {
+    /// our own stdin and stdout
+    Stdio,
+    /// listen on a Unix socket at this path
+    Unix(PathBuf),
+    /// listen on this address
+    Tcp(String),
+}
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConsoleSpec {
+    pub backend: ConsoleBackend,
+    /// copy of all guest output
+    pub log: Option<PathBuf>,
+}
+impl ConsoleSpec {
+    /// "unix:PATH", "tcp:[HOST:]PORT" or "stdio", then optionally ",log=FILE"
+    pub fn parse(s: &str) -> Result<ConsoleSpec, String> {
+        let bad = || format!("bad console {}, expected unix:PATH, tcp:[HOST:]PORT or stdio, then optionally ,log=FILE", s);
+        let (dev, rest) = s.split_once(',').unwrap_or((s, ""));
+        let backend = match dev.split_once(':') {
+            Some(("unix", path)) if !path.is_empty() => ConsoleBackend::Unix(PathBuf::from(path)),
+            Some(("tcp", addr)) => {
+                let (host, port) = addr.rsplit_once(':').unwrap_or(("127.0.0.1", addr));
+                port.parse::<u16>().map_err(|_| bad())?;
+                ConsoleBackend::Tcp(format!("{}:{}", host, port))
+            }
+            None if dev == "stdio" => ConsoleBackend::Stdio,
+            _ => return Err(bad()),
+        };
+        let mut log = None;
+        for part in rest.split(',').filter(|p| !p.is_empty()) {
+            match part.split_once('=') {
+                Some(("log", file)) if !file.is_empty() => log = Some(PathBuf::from(file)),
+                _ => return Err(bad()),
+            }
+        }
+        Ok(ConsoleSpec { backend, log })
+    }
+    /// Puts the pipes on fds 0-2 and starts the relay. Has to run while we're still single threaded.
+    pub fn attach(&self) -> Result<(), String> {
+        let log = match &self.log {
+            Some(p) => Some(OpenOptions::new().create(true).append(true).open(p)
+                .map_err(|e| format!("{}: {}", p.display(), e))?),
+            None => None,
+        };
+        let listener = match &self.backend {
+            ConsoleBackend::Stdio => None,
+            ConsoleBackend::Unix(p) => {
+                // a socket left behind by an earlier run
+                let _ = std::fs::remove_file(p);
+                Some(UnixListener::bind(p).map_err(|e| format!("{}: {}", p.display(), e))?.into_raw_fd())
+            }
+            ConsoleBackend::Tcp(a) => Some(TcpListener::bind(a).map_err(|e| format!("{}: {}", a, e))?.into_raw_fd()),
+        };
+        let (in_r, in_w) = pipe()?;
+        let (out_r, out_w) = pipe()?;
+        unsafe {
+            let pid = libc::fork();
+            if pid < 0 {
+                return Err(std::io::Error::last_os_error().to_string());
+            }
+            if pid == 0 {
+                if libc::fork() == 0 {
+                    libc::close(in_r);
+                    libc::close(out_w);
+                    let conn = if listener.is_none() { Some(Conn::stdio()) } else { None };
+                    let mut r = Relay { listener, conn, log, out_r, in_w, backlog: Backlog::default() };
+                    r.run();
+                    if let ConsoleBackend::Unix(p) = &self.backend {
+                        let _ = std::fs::remove_file(p);
+                    }
+                }
+                libc::_exit(0);
+            }
+            libc::waitpid(pid, std::ptr::null_mut(), 0);
+            libc::dup2(in_r, 0);
+            libc::dup2(out_w, 1);
+            libc::dup2(out_w, 2);
+            for fd in [in_r, in_w, out_r, out_w] {
+                libc::close(fd);
+            }
+            if let Some(l) = listener {
+                libc::close(l);
+            }
+        }
+        Ok(())
+    }
+}
+fn pipe() -> Result<(RawFd, RawFd), String> {
+    let mut fds = [0 as c_int; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), O_CLOEXEC) } < 0 {
+        return Err(std::io::Error::last_os_error().to_string());
+    }
+    Ok((fds[0], fds[1]))
+}
+fn write_all(fd: RawFd, mut buf: &[u8]) -> bool {
+    while !buf.is_empty() {
+        let n = unsafe { libc::write(fd, buf.as_ptr() as *const c_void, buf.len()) };
+        if n < 0 {
+            if std::io::Error::last_os_error().raw_os_error() == Some(EINTR) {
+                continue;
+            }
+            return false;
+        }
+        buf = &buf[n as usize..];
+    }
+    true
+}
+fn read(fd: RawFd, buf: &mut [u8]) -> isize {
+    loop {
+        let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut c_void, buf.len()) };
+        if n >= 0 || std::io::Error::last_os_error().raw_os_error() != Some(EINTR) {
+            return n;
+        }
+    }
+}
+/// Guest output a socket client hasn't taken yet
+#[derive(Default)]
+pub(crate) struct Backlog {
+    buf: Vec<u8>,
+}
+impl Backlog {
+    /// Queues `out`, dropping the oldest bytes past MAX_BACKLOG
+    pub(crate) fn push(&mut self, out: &[u8]) {
+        self.buf.extend_from_slice(out);
+        if self.buf.len() > MAX_BACKLOG {
+            let excess = self.buf.len() - MAX_BACKLOG;
+            self.buf.drain(..excess);
+        }
+    }
+    /// Sends as much as socket `fd` takes without blocking, false if the client is gone
+    pub(crate) fn flush(&mut self, fd: RawFd) -> bool {
+        while !self.buf.is_empty() {
+            let n = unsafe {
+                libc::send(fd, self.buf.as_ptr() as *const c_void, self.buf.len(), MSG_DONTWAIT | MSG_NOSIGNAL)
+            };
+            if n < 0 {
+                match std::io::Error::last_os_error().raw_os_error() {
+                    Some(EINTR) => continue,
+                    Some(EAGAIN) => return true,
+                    _ => return false,
+                }
+            }
+            self.buf.drain(..n as usize);
+        }
+        true
+    }
+    pub(crate) fn len(&self) -> usize {
+        self.buf.len()
+    }
+    pub(crate) fn clear(&mut self) {
+        self.buf.clear();
+    }
+}
+#[derive(Copy, Clone)]
+struct Conn {
+    rd: RawFd,
+    wr: RawFd,
+    socket: bool,
+}
+impl Conn {
+    fn stdio() -> Conn {
+        Conn { rd: 0, wr: 1, socket: false }
+    }
+}
+struct Relay {
+    listener: Option<RawFd>,
+    conn: Option<Conn>,
+    log: Option<File>,
+    out_r: RawFd,
+    /// -1 once the guest's stdin is closed
+    in_w: RawFd,
+    /// output for a socket client, which is never waited on
+    backlog: Backlog,
+}
+impl Relay {
+    /// Only our end of the pipes, the listener and the log stay open, anything else (fds passed
+    /// to the guest especially) would be kept alive by us
+    fn close_others(&self) {
+        let mut keep = vec![self.out_r, self.in_w];
+        keep.extend(self.listener);
+        keep.extend(self.log.as_ref().map(|f| f.as_raw_fd()));
+        if self.conn.is_some() {
+            keep.extend([0, 1]);
+        }
+        let fds: Vec<RawFd> = match std::fs::read_dir("/proc/self/fd") {
+            Ok(d) => d.filter_map(|e| e.ok()?.file_name().to_str()?.parse().ok()).collect(),
+            Err(_) => return,
+        };
+        for fd in fds.into_iter().filter(|fd| !keep.contains(fd)) {
+            unsafe { libc::close(fd) };
+        }
+    }
+    fn drop_conn(&mut self) {
+        if let Some(c) = self.conn.take() {
+            if c.socket {
+                unsafe { libc::close(c.rd) };
+            }
+        }
+        self.backlog.clear();
+    }
+    // guest output for the client: our own stdout is waited on like before, a socket isn't
+    fn send(&mut self, out: &[u8]) {
+        let c = match self.conn {
+            Some(c) => c,
+            None => return,
+        };
+        let ok = if c.socket {
+            self.backlog.push(out);
+            self.backlog.flush(c.wr)
+        } else {
+            write_all(c.wr, out)
+        };
+        if !ok {
+            self.drop_conn();
+        }
+    }
+    fn run(&mut self) {
+        unsafe {
+            // ^C at the terminal is for the guest
+            for sig in [SIGINT, SIGQUIT, SIGTSTP, SIGPIPE] {
+                libc::signal(sig, SIG_IGN);
+            }
+            // input the guest doesn't read is dropped, not waited on
+            libc::fcntl(self.in_w, F_SETFL, libc::fcntl(self.in_w, F_GETFL) | O_NONBLOCK);
+        }
+        self.close_others();
+        let mut buf = [0u8; 4096];
+        loop {
+            let mut pfds = vec![pollfd { fd: self.out_r, events: POLLIN, revents: 0 }];
+            match (&self.conn, self.listener) {
+                (Some(c), _) => {
+                    let mut events = if self.in_w >= 0 { POLLIN } else { 0 };
+                    if self.backlog.len() > 0 {
+                        events |= POLLOUT;
+                    }
+                    pfds.push(pollfd { fd: if events != 0 { c.rd } else { -1 }, events, revents: 0 });
+                }
+                (None, Some(l)) => pfds.push(pollfd { fd: l, events: POLLIN, revents: 0 }),
+                (None, None) => {}
+            }
+            if unsafe { libc::poll(pfds.as_mut_ptr(), pfds.len() as _, -1) } < 0 {
+                continue;
+            }
+            if pfds[0].revents != 0 {
+                let n = read(self.out_r, &mut buf);
+                if n <= 0 {
+                    // nobody has the guest's stdout open any more
+                    break;
+                }
+                let out = &buf[..n as usize];
+                if let Some(f) = &self.log {
+                    write_all(f.as_raw_fd(), out);
+                }
+                self.send(out);
+            }
+            if pfds.len() < 2 || pfds[1].revents == 0 {
+                continue;
+            }
+            match self.conn {
+                // only if the client didn't just go away, and that's the listener
+                None if Some(pfds[1].fd) == self.listener => {
+                    let fd = unsafe { libc::accept4(pfds[1].fd, std::ptr::null_mut(), std::ptr::null_mut(), O_CLOEXEC) };
+                    if fd >= 0 {
+                        self.conn = Some(Conn { rd: fd, wr: fd, socket: true });
+                    }
+                }
+                None => {}
+                Some(c) => {
+                    let revents = pfds[1].revents;
+                    if revents & POLLOUT != 0 && !self.backlog.flush(c.wr) {
+                        self.drop_conn();
+                        continue;
+                    }
+                    if revents & !POLLOUT == 0 {
+                        continue;
+                    }
+                    let n = read(c.rd, &mut buf);
+                    if n > 0 {
+                        unsafe { libc::write(self.in_w, buf.as_ptr() as *const c_void, n as usize) };
+                    } else if c.socket {
+                        // the next client can have it
+                        self.drop_conn();
+                    } else {
+                        // our stdin hit EOF, so does the guest's
+                        unsafe { libc::close(self.in_w) };
+                        self.in_w = -1;
+                    }
+                }
+            }
+        }
+        self.drop_conn();
+    }
+}
@@ -0,0 +1,181 @@
+// Guest reads of stdin. A plain read() parks the host thread in the kernel until there's input,
+// and a signal for the guest that arrives meanwhile (a timer, ^C with a handler installed) only
+// gets through once the read returns, never if the handler was installed with SA_RESTART.
+// Instead, stdin readiness is watched by a cros_async executor on a thread of its own, and the
+// guest thread waits for it in short slices, giving up as soon as a signal is pending for the
+// guest. Like in the kernel the read then fails with EINTR, or goes again after the handler if
+// that was installed with SA_RESTART (see ERESTARTSYS). The actual read only happens once there is input, so nothing gets taken from
+// stdin (which may be shared with other processes) before the guest asks for it.
+// With --deterministic all guest threads share one host thread, so there the others still wait.
+// Each guest has a Console of its own (in its runtime), the watcher thread goes away with it.
+use std::fs::File;
+use std::os::unix::io::FromRawFd;
+use std::sync::Arc;
+use std::time::Duration;
+use base::{warn, Event};
+use cros_async::{EventAsync, Executor, ExecutorKind, IoSourceExt};
+use libc::c_int;
+use sync::{Condvar, Mutex};
+use crate::linux_usermode::main::ERESTARTSYS;
+use crate::linux_usermode::signals::{block_all_signals, GuestSignals};
+
+/// How often a waiting guest thread checks for signals
+const WAIT_SLICE: Duration = Duration::from_millis(10);
+
+#[derive(Default)]
+struct WatchState {
+    /// stdin was readable since the last wait was asked for
+    ready: bool,
+    /// the executor gave up, reads just block again
+    dead: bool,
+}
+struct StdinWatch {
+    pid: libc::pid_t,
+    state: Mutex<WatchState>,
+    cv: Condvar,
+    /// tells the executor someone is waiting
+    rearm: Event,
+}
+/// A guest's stdin, shared by its threads
+pub struct Console {
+    watch: Mutex<Option<Arc<StdinWatch>>>,
+    /// (st_dev, st_ino) of stdin when the guest started, to recognize dups of it
+    stdin_id: Option<(u64, u64)>,
+}
+impl Default for Console {
+    fn default() -> Self {
+        Console {
+            watch: Mutex::new(None),
+            stdin_id: file_id(0),
+        }
+    }
+}
+impl Drop for Console {
+    fn drop(&mut self) {
+        // a watcher waiting for the next wait to be asked for leaves, instead of being left over
+        // (a forked child's is the parent's, the child has no executor thread to stop)
+        let pid = unsafe { libc::getpid() };
+        if let Some(w) = self.watch.lock().take().filter(|w| w.pid == pid) {
+            w.state.lock().dead = true;
+            let _ = w.rearm.signal();
+        }
+    }
+}
+
+fn file_id(fd: c_int) -> Option<(u64, u64)> {
+    let mut st: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut st) } < 0 {
+        return None;
+    }
+    Some((st.st_dev as u64, st.st_ino as u64))
+}
+async fn watch_loop(src: Box<dyn IoSourceExt<File> + Send>, rearm: EventAsync, w: Arc<StdinWatch>) {
+    loop {
+        let res = match rearm.next_val().await {
+            Ok(_) if w.state.lock().dead => return,
+            Ok(_) => src.wait_readable().await,
+            Err(e) => Err(e),
+        };
+        let mut st = w.state.lock();
+        st.ready = true;
+        if let Err(e) = res {
+            warn!("Stopped watching stdin: {}", e);
+            st.dead = true;
+        }
+        w.cv.notify_all();
+        if st.dead {
+            return;
+        }
+    }
+}
+fn start_watch(pid: libc::pid_t) -> anyhow::Result<Arc<StdinWatch>> {
+    let watch = Arc::new(StdinWatch {
+        pid,
+        state: Mutex::new(WatchState::default()),
+        cv: Condvar::new(),
+        rearm: Event::new()?,
+    });
+    let rearm = watch.rearm.try_clone()?;
+    // a handle of its own, so the guest closing fd 0 doesn't pull it from under the executor
+    let fd = unsafe { libc::fcntl(0, libc::F_DUPFD_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let stdin = unsafe { File::from_raw_fd(fd) };
+    let ex = Executor::with_executor_kind(ExecutorKind::Fd)?;
+    let src = ex.async_from(stdin)?;
+    let rearm = EventAsync::new(rearm, &ex)?;
+    let w = watch.clone();
+    std::thread::Builder::new().name("stdin".to_string()).spawn(move || {
+        // signals are for the guest threads
+        block_all_signals();
+        if let Err(e) = ex.run_until(watch_loop(src, rearm, w.clone())) {
+            warn!("stdin executor failed: {}", e);
+            let mut st = w.state.lock();
+            st.ready = true;
+            st.dead = true;
+            w.cv.notify_all();
+        }
+    })?;
+    Ok(watch)
+}
+fn would_block(fd: c_int) -> bool {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || flags & libc::O_NONBLOCK != 0 {
+        return false;
+    }
+    let mut pfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    unsafe { libc::poll(&mut pfd, 1, 0) == 0 }
+}
+impl Console {
+    /// Whether `fd` is the guest's stdin, or a dup of it
+    pub fn is_stdin(&self, fd: c_int) -> bool {
+        fd == 0 || (self.stdin_id.is_some() && file_id(fd) == self.stdin_id)
+    }
+    fn watcher(&self) -> Option<Arc<StdinWatch>> {
+        let pid = unsafe { libc::getpid() };
+        let mut w = self.watch.lock();
+        if let Some(cur) = w.as_ref() {
+            // a forked child doesn't have the parent's executor thread
+            if cur.pid == pid {
+                return Some(cur.clone());
+            }
+        }
+        match start_watch(pid) {
+            Ok(n) => {
+                *w = Some(n.clone());
+                Some(n)
+            }
+            Err(e) => {
+                warn!("Couldn't watch stdin asynchronously, reads of it will block: {}", e);
+                None
+            }
+        }
+    }
+    /// Wait until stdin (`fd`) has input, Err(ERESTARTSYS) if a signal for the guest comes in
+    /// first
+    pub fn wait_stdin_readable(&self, fd: c_int, sig: &GuestSignals) -> Result<(), c_int> {
+        if !would_block(fd) {
+            return Ok(());
+        }
+        let w = match self.watcher() {
+            Some(w) => w,
+            None => return Ok(()),
+        };
+        let mut st = w.state.lock();
+        if st.dead {
+            return Ok(());
+        }
+        st.ready = false;
+        let _ = w.rearm.signal();
+        loop {
+            if st.ready {
+                return Ok(());
+            }
+            if sig.is_pending() {
+                return Err(ERESTARTSYS);
+            }
+            st = w.cv.wait_timeout(st, WAIT_SLICE).0;
+        }
+    }
+}
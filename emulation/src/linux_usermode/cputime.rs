@@ -0,0 +1,65 @@
+// Guest CPU time, counted in retired guest instructions instead of host time.
+// Host CPU time mostly measures how fast the emulator is, so getrusage(), times() and the cputime
+// clocks report instructions / guest clock rate instead (all of it as user time). Each thread
+// publishes its count on every syscall it makes, so totals for the whole process can lag behind
+// for threads that are busy computing. Children are separate emulators we can't see into, so
+// their numbers still come from the host.
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use sync::Mutex;
+
+/// Guest clock rate used to turn instructions into time, one instruction per cycle
+pub const DEFAULT_GUEST_MHZ: u64 = 1000;
+
+pub struct ThreadCpu {
+    tid: u64,
+    insns: AtomicU64,
+}
+#[derive(Default)]
+pub struct CpuAccounting {
+    /// instructions of threads that already exited
+    exited: AtomicU64,
+    threads: Mutex<Vec<Arc<ThreadCpu>>>,
+}
+impl CpuAccounting {
+    /// Slot for thread `tid`, made on first use
+    pub fn register(&self, tid: u64) -> Arc<ThreadCpu> {
+        let slot = Arc::new(ThreadCpu {
+            tid,
+            insns: AtomicU64::new(0),
+        });
+        self.threads.lock().push(slot.clone());
+        slot
+    }
+    pub fn thread_exit(&self, slot: &Arc<ThreadCpu>) {
+        self.exited.fetch_add(slot.insns.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.threads.lock().retain(|t| !Arc::ptr_eq(t, slot));
+    }
+    pub fn process_insns(&self) -> u64 {
+        let live: u64 = self.threads.lock().iter().map(|t| t.insns.load(Ordering::Relaxed)).sum();
+        self.exited.load(Ordering::Relaxed) + live
+    }
+    /// The forked child starts counting from zero, with just the thread that forked
+    pub fn forked_child(&self) {
+        self.exited.store(0, Ordering::Relaxed);
+        self.threads.lock().clear();
+    }
+}
+impl ThreadCpu {
+    pub fn tid(&self) -> u64 {
+        self.tid
+    }
+    pub fn set(&self, insns: u64) {
+        self.insns.store(insns, Ordering::Relaxed);
+    }
+    pub fn get(&self) -> u64 {
+        self.insns.load(Ordering::Relaxed)
+    }
+}
+pub fn insns_to_ns(insns: u64, mhz: u64) -> u64 {
+    (insns as u128 * 1000 / mhz.max(1) as u128) as u64
+}
+/// Resolution of the cputime clocks, one instruction (but never below 1ns)
+pub fn insn_resolution_ns(mhz: u64) -> u64 {
+    (1000 / mhz.max(1)).max(1)
+}
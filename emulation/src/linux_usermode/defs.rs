@@ -85,6 +85,9 @@ pub fn plat2generic_stat(stats: stat) -> GenericStat {
     }
 }
 pub const SIG_FIRST_INVALID: i32 = 65; // an invalid value
+// tty ioctls the libc crate doesn't have (asm-generic numbers, same as x86)
+pub const TIOCGSID: u64 = 0x5429;
+pub const TIOCGPTPEER: u64 = 0x5441;
 #[derive(Clone)]
 pub struct SigConstants {
     /*  sf_sigio: i32,
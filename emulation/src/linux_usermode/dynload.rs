@@ -0,0 +1,321 @@
+// Built-in dynamic loader, for running dynamically linked guests without the guest's ld.so.
+// The DT_NEEDED libraries are looked up (DT_RUNPATH/DT_RPATH of the object that wants them, then
+// the given directories) and loaded breadth first next to where the interpreter would have gone.
+// Symbols resolve the usual way: first definition in load order wins, versions are ignored.
+// Relocations are done right away, except the ones that need guest code to run (IFUNC resolvers)
+// and the libraries' initializers, which wait for the cpu (see DlPending).
+// TLS is static only: every module's block goes in one area at the thread pointer. There's no
+// __tls_get_addr, that lives in ld.so, so general dynamic TLS accesses don't work. For the same
+// reason glibc, whose libc.so.6 shares a lot of private state with its ld.so and whose symbols
+// are versioned, still needs the real thing: a guest that needs any of its libraries is turned
+// away before anything is loaded. This is for simpler libraries (musl's libc.so, say).
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, bail};
+use base::{debug, info};
+use goblin::elf::Elf;
+use goblin::elf::program_header::PT_TLS;
+use goblin::elf::reloc::Reloc;
+use goblin::elf::sym::{Sym, STB_LOCAL, STB_WEAK, STT_GNU_IFUNC};
+use crate::common::genfunc::round_up;
+use crate::common::memory::MemEndian;
+use crate::elf::{read_object, MachineType, UserModeRuntime};
+
+/// What's left to do once there's a cpu to run guest code on
+#[derive(Default)]
+pub struct DlPending {
+    /// (where the result goes, resolver, addend) for IFUNC relocations
+    pub ifuncs: Vec<(u64, u64, u64)>,
+    /// library initializers (DT_INIT, then DT_INIT_ARRAY), dependencies first
+    pub init_funcs: Vec<u64>,
+    /// thread pointer of the first thread, when a module has TLS
+    pub tp: Option<u64>,
+}
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum RelKind {
+    None,
+    Abs64,
+    Abs32,
+    Relative,
+    Copy,
+    /// GLOB_DAT and JUMP_SLOT
+    Slot,
+    DtpMod,
+    DtpRel,
+    TpRel,
+    IRelative,
+}
+fn rel_kind(mt: MachineType, t: u32) -> Option<RelKind> {
+    Some(match (mt, t) {
+        (MachineType::Riscv, 0) | (MachineType::Arm64, 0) => RelKind::None,
+        (MachineType::Riscv, 1) => RelKind::Abs32,
+        (MachineType::Riscv, 2) | (MachineType::Arm64, 257) => RelKind::Abs64,
+        (MachineType::Riscv, 3) | (MachineType::Arm64, 1027) => RelKind::Relative,
+        (MachineType::Riscv, 4) | (MachineType::Arm64, 1024) => RelKind::Copy,
+        (MachineType::Riscv, 5) | (MachineType::Arm64, 1025) | (MachineType::Arm64, 1026) => RelKind::Slot,
+        (MachineType::Riscv, 7) | (MachineType::Arm64, 1028) => RelKind::DtpMod,
+        (MachineType::Riscv, 9) | (MachineType::Arm64, 1029) => RelKind::DtpRel,
+        (MachineType::Riscv, 11) | (MachineType::Arm64, 1030) => RelKind::TpRel,
+        (MachineType::Riscv, 58) | (MachineType::Arm64, 1032) => RelKind::IRelative,
+        _ => return None,
+    })
+}
+/// Bytes between the thread pointer and the first TLS block, and the bias of DTPREL values
+fn tls_abi(mt: MachineType) -> (u64, u64) {
+    match mt {
+        MachineType::Arm64 => (16, 0),
+        _ => (0, 0x800),
+    }
+}
+struct Module {
+    name: String,
+    path: PathBuf,
+    base: u64,
+    data: Vec<u8>,
+}
+/// glibc's libraries, which only its own ld.so can link
+const GLIBC_LIBS: &[&str] = &["libc.so.6", "libm.so.6", "libpthread.so.0", "libdl.so.2", "librt.so.1",
+    "libresolv.so.2", "libutil.so.1", "libanl.so.1", "libBrokenLocale.so.1", "libcrypt.so.1"];
+/// Err for a DT_NEEDED library only glibc's ld.so can link
+pub(crate) fn reject_glibc(name: &str, requester: &Path) -> anyhow::Result<()> {
+    let file = name.rsplit('/').next().unwrap_or(name);
+    if GLIBC_LIBS.contains(&file) || file.starts_with("ld-linux") {
+        bail!("{} needs glibc ({}), which the built-in loader can't link: run it with the guest's ld.so \
+               instead of --builtin-loader", requester.display(), file);
+    }
+    Ok(())
+}
+fn expand_origin(dir: &str, origin: &Path) -> PathBuf {
+    let o = origin.to_string_lossy();
+    PathBuf::from(dir.replace("${ORIGIN}", &o).replace("$ORIGIN", &o))
+}
+fn find_library(name: &str, requester: &Path, ef: &Elf, dirs: &[PathBuf], machine: u16) -> Option<PathBuf> {
+    if name.contains('/') {
+        return Some(PathBuf::from(name));
+    }
+    let origin = requester.parent().unwrap_or(Path::new("/"));
+    // DT_RPATH only counts without a DT_RUNPATH
+    let own = if ef.runpaths.is_empty() { &ef.rpaths } else { &ef.runpaths };
+    let own: Vec<PathBuf> = own.iter()
+        .flat_map(|p| p.split(':'))
+        .map(|d| expand_origin(d, origin))
+        .collect();
+    for d in own.iter().chain(dirs.iter()) {
+        let p = d.join(name);
+        // multiarch setups keep libraries for other machines (and word sizes) with the same name around
+        let ok = std::fs::read(&p).ok()
+            .and_then(|data| Elf::parse(&data).ok().map(|e| e.header.e_machine == machine && e.is_64 == ef.is_64))
+            .unwrap_or(false);
+        if ok {
+            return Some(p);
+        }
+    }
+    None
+}
+fn relocs<'a>(ef: &'a Elf) -> impl Iterator<Item = Reloc> + 'a {
+    ef.dynrelas.iter().chain(ef.dynrels.iter()).chain(ef.pltrelocs.iter())
+}
+fn sym_name<'a>(ef: &'a Elf, sym: &Sym) -> &'a str {
+    ef.dynstrtab.get_at(sym.st_name).unwrap_or("")
+}
+/// Load what `exe` (already loaded as object `exe_idx`) needs, relocate everything and leave what
+/// needs the cpu in initvars.dl
+pub fn link(umr: &mut UserModeRuntime, exe: &Elf, exe_idx: usize, dirs: &[PathBuf]) -> anyhow::Result<()> {
+    if !exe.is_64 {
+        bail!("the built-in loader only does 64-bit guests");
+    }
+    let mt = umr.machine_type;
+    let mmapdown = umr.heap_grow_down;
+    let exe_path = umr.initvars.lock().objects[exe_idx].path.clone();
+    for l in &exe.libraries {
+        reject_glibc(l, &exe_path)?;
+    }
+    let mut mods = vec![Module {
+        name: String::new(),
+        path: exe_path.clone(),
+        base: umr.initvars.lock().objects[exe_idx].base as u64,
+        data: std::fs::read(&exe_path)?,
+    }];
+    let mut queue: VecDeque<(String, PathBuf)> = exe.libraries.iter().map(|l| (l.to_string(), exe_path.clone())).collect();
+    while let Some((name, requester)) = queue.pop_front() {
+        if mods.iter().any(|m| m.name == name) {
+            continue;
+        }
+        let req = mods.iter().find(|m| m.path == requester).unwrap();
+        let req_elf = Elf::parse(&req.data)?;
+        let path = find_library(&name, &requester, &req_elf, dirs, exe.header.e_machine)
+            .ok_or_else(|| anyhow!("{} (needed by {}) not found", name, requester.display()))?;
+        // before it's mapped, a library can be built against glibc too
+        let (path, file, data) = read_object(&path)?;
+        let lib = Elf::parse(&data)?;
+        for l in &lib.libraries {
+            reject_glibc(l, &path)?;
+        }
+        let barrier = umr.initvars.lock().mmap_barrier;
+        let idx = umr.map_object(path, file, &data, &lib, Some(barrier), mmapdown)?;
+        let (base, path) = {
+            let mut iv = umr.initvars.lock();
+            let size = iv.objects[idx].mem.size() as u64;
+            if mmapdown {
+                iv.mmap_barrier -= size;
+            } else {
+                iv.mmap_barrier += size;
+            }
+            (iv.objects[idx].base as u64, iv.objects[idx].path.clone())
+        };
+        debug!("built-in loader: {} is {} at {:#x}", name, path.display(), base);
+        queue.extend(lib.libraries.iter().map(|l| (l.to_string(), path.clone())));
+        drop(lib);
+        mods.push(Module { name, path, base, data });
+    }
+    // everything's loaded, each one is parsed once from here on
+    let elfs = mods.iter().map(|m| Elf::parse(&m.data)).collect::<Result<Vec<_>, _>>()?;
+    let mut pending = DlPending::default();
+    let (tp, tls) = setup_tls(umr, &mods, &elfs)?;
+    pending.tp = tp;
+    // first definition in load order wins. Copy relocations look in the libraries only, the
+    // executable's copy is what the other one finds
+    let mut scope: HashMap<String, (usize, Sym)> = HashMap::new();
+    let mut lib_scope: HashMap<String, (usize, Sym)> = HashMap::new();
+    for (i, ef) in elfs.iter().enumerate() {
+        for sym in ef.dynsyms.iter() {
+            if sym.st_shndx == 0 || sym.st_bind() == STB_LOCAL {
+                continue;
+            }
+            let name = sym_name(ef, &sym);
+            scope.entry(name.to_string()).or_insert((i, sym));
+            if i > 0 {
+                lib_scope.entry(name.to_string()).or_insert((i, sym));
+            }
+        }
+    }
+    let endian = if umr.is_little_endian { MemEndian::Little } else { MemEndian::Big };
+    // libraries before the executable, so its copy relocations see relocated data
+    for i in (0..mods.len()).rev() {
+        let ef = &elfs[i];
+        for r in relocs(ef) {
+            let kind = rel_kind(mt, r.r_type).ok_or_else(|| anyhow!("unsupported relocation type {} in {}", r.r_type,
+                                                                     mods[i].path.display()))?;
+            let at = mods[i].base.wrapping_add(r.r_offset);
+            let addend = r.r_addend.unwrap_or(0) as u64;
+            // the definition: (module, symbol), None for r_sym 0 or undefined weak
+            let def = if r.r_sym == 0 {
+                None
+            } else {
+                let sym = ef.dynsyms.get(r.r_sym).ok_or_else(|| anyhow!("bad symbol index {}", r.r_sym))?;
+                let name = sym_name(ef, &sym);
+                let found = if sym.st_bind() == STB_LOCAL {
+                    Some((i, sym))
+                } else if kind == RelKind::Copy {
+                    lib_scope.get(name).copied()
+                } else {
+                    scope.get(name).copied().or(if sym.st_shndx != 0 { Some((i, sym)) } else { None })
+                };
+                if found.is_none() && sym.st_bind() != STB_WEAK {
+                    bail!("undefined symbol {} in {}", name, mods[i].path.display());
+                }
+                found
+            };
+            let sym_addr = def.map(|(j, s)| mods[j].base.wrapping_add(s.st_value)).unwrap_or(0);
+            let is_ifunc = matches!(def, Some((_, s)) if s.st_type() == STT_GNU_IFUNC);
+            let write64 = |umr: &mut UserModeRuntime, v: u64| umr.mem_access.write_phys_64(at, v, endian);
+            let res = match kind {
+                RelKind::None => Ok(()),
+                RelKind::Relative => write64(umr, mods[i].base.wrapping_add(addend)),
+                RelKind::Abs64 | RelKind::Slot if is_ifunc => {
+                    pending.ifuncs.push((at, sym_addr, addend));
+                    Ok(())
+                }
+                RelKind::Abs64 | RelKind::Slot => write64(umr, sym_addr.wrapping_add(addend)),
+                RelKind::Abs32 => umr.mem_access.write_phys_32(at, sym_addr.wrapping_add(addend) as u32, endian),
+                RelKind::IRelative => {
+                    pending.ifuncs.push((at, mods[i].base.wrapping_add(addend), 0));
+                    Ok(())
+                }
+                RelKind::Copy => {
+                    let (j, s) = def.ok_or_else(|| anyhow!("copy relocation without a definition"))?;
+                    let src = mods[j].base.wrapping_add(s.st_value);
+                    let data = umr.mem_access.read_phys_n(src, s.st_size as usize).map_err(|_| anyhow!("bad copy source {:#x}", src))?;
+                    umr.mem_access.write_phys_n(at, data)
+                }
+                RelKind::DtpMod => {
+                    let m = def.map(|(j, _)| j).unwrap_or(i);
+                    write64(umr, tls[m].map(|t| t.1).unwrap_or(0))
+                }
+                RelKind::DtpRel => {
+                    let v = def.map(|(_, s)| s.st_value).unwrap_or(0);
+                    write64(umr, v.wrapping_add(addend).wrapping_sub(tls_abi(mt).1))
+                }
+                RelKind::TpRel => {
+                    let (m, v) = def.map(|(j, s)| (j, s.st_value)).unwrap_or((i, 0));
+                    let off = tls[m].ok_or_else(|| anyhow!("TLS relocation against a module without TLS"))?.0;
+                    write64(umr, off.wrapping_add(v).wrapping_add(addend))
+                }
+            };
+            res.map_err(|_| anyhow!("couldn't write relocation at {:#x}", at))?;
+        }
+    }
+    // the executable's own initializers are run by its startup code
+    for (m, ef) in mods.iter().zip(elfs.iter()).skip(1).rev() {
+        if let Some(d) = &ef.dynamic {
+            if d.info.init != 0 {
+                pending.init_funcs.push(m.base + d.info.init);
+            }
+            for n in 0..d.info.init_arraysz as u64 / 8 {
+                let f = umr.mem_access.read_phys_64(m.base + d.info.init_array + n * 8, endian)
+                    .map_err(|_| anyhow!("bad DT_INIT_ARRAY in {}", m.name))?;
+                if f != 0 && f != u64::MAX {
+                    pending.init_funcs.push(f);
+                }
+            }
+        }
+    }
+    // there's no calling into an arm64 guest from here yet, and a library missing those won't work
+    if mt == MachineType::Arm64 && !(pending.ifuncs.is_empty() && pending.init_funcs.is_empty()) {
+        bail!("can't run IFUNC resolvers or library initializers for arm64 guests yet, leave out --builtin-loader");
+    }
+    info!("built-in loader: {} libraries, {} IFUNCs and {} initializers to run", mods.len() - 1,
+          pending.ifuncs.len(), pending.init_funcs.len());
+    umr.initvars.lock().dl = Some(pending);
+    Ok(())
+}
+// Lay out the static TLS area and fill in its initial contents. Returns the thread pointer and
+// per module, the offset of its block from the thread pointer and its module id
+fn setup_tls(umr: &mut UserModeRuntime, mods: &[Module], elfs: &[Elf]) -> anyhow::Result<(Option<u64>, Vec<Option<(u64, u64)>>)> {
+    let (tcb, _) = tls_abi(umr.machine_type);
+    let mut off = tcb;
+    let mut images = vec![];
+    let mut tls = vec![None; mods.len()];
+    let mut modid = 0;
+    for (i, (m, ef)) in mods.iter().zip(elfs.iter()).enumerate() {
+        if let Some(ph) = ef.program_headers.iter().find(|p| p.p_type == PT_TLS) {
+            off = round_up(off, ph.p_align.max(1));
+            modid += 1;
+            tls[i] = Some((off, modid));
+            images.push((off, m.base + ph.p_vaddr, ph.p_filesz));
+            off += ph.p_memsz;
+        }
+    }
+    if images.is_empty() {
+        return Ok((None, tls));
+    }
+    let size = round_up(off, umr.guest_pagesize);
+    let area = unsafe {
+        libc::mmap(std::ptr::null_mut(), size as usize, libc::PROT_READ | libc::PROT_WRITE,
+                   libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0)
+    };
+    if area == libc::MAP_FAILED {
+        bail!("couldn't map the TLS area");
+    }
+    let tp = area as u64;
+    umr.memusage.map_loader(tp, size, true);
+    for (off, src, len) in images {
+        let data = umr.mem_access.read_phys_n(src, len as usize).map_err(|_| anyhow!("bad TLS image at {:#x}", src))?;
+        umr.mem_access.write_phys_n(tp + off, data).map_err(|_| anyhow!("couldn't fill the TLS area"))?;
+    }
+    Ok((Some(tp), tls))
+}
+/// Everything in the search path that could hold guest libraries, when none was given
+pub fn default_dirs(sysroot: &Path) -> Vec<PathBuf> {
+    ["lib", "lib64", "usr/lib", "usr/lib64", "usr/local/lib"].iter().map(|d| sysroot.join(d)).collect()
+}
@@ -0,0 +1,89 @@
+// What the ELF header says about the machine a binary wants, checked before anything gets loaded,
+// so a binary we can't run is an error saying why and not a panic, or garbage deep in the
+// interpreter. The main binary picks the machine (riscv or aarch64, and the word size), and
+// everything loaded next to it has to be for the same one. A sysroot for another architecture or
+// word size is the usual way to end up with a program interpreter that isn't.
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use goblin::elf::header::{machine_to_str, EI_CLASS, EI_DATA, ELFCLASS32, ELFCLASS64, ELFDATA2LSB, ELFDATA2MSB,
+                          EM_AARCH64, EM_RISCV, ET_CORE, ET_DYN, ET_EXEC, ET_REL};
+use goblin::elf::{Elf, Header};
+use crate::elf::MachineType;
+
+/// The guest machine an ELF file is for
+#[derive(Copy, Clone, PartialEq)]
+pub struct ElfTarget {
+    pub machine: MachineType,
+    pub is_64: bool,
+}
+impl ElfTarget {
+    /// "rv64", "rv32" or "aarch64", like a user would say it
+    pub fn name(&self) -> &'static str {
+        match (self.machine, self.is_64) {
+            (MachineType::Riscv, true) => "rv64",
+            (MachineType::Riscv, false) => "rv32",
+            (MachineType::Arm64, _) => "aarch64",
+            (MachineType::None, _) => "unknown",
+        }
+    }
+}
+fn class_name(class: u8) -> String {
+    match class {
+        ELFCLASS32 => "32-bit".to_string(),
+        ELFCLASS64 => "64-bit".to_string(),
+        c => format!("EI_CLASS {}", c),
+    }
+}
+/// The machine an ELF file with this header runs on, or why we can't run it
+pub fn elf_target(hdr: &Header) -> Result<ElfTarget, String> {
+    let class = hdr.e_ident[EI_CLASS];
+    let arch = match hdr.e_machine {
+        EM_RISCV => "riscv",
+        EM_AARCH64 => "aarch64",
+        m => return Err(format!("{} binary (e_machine {}), only riscv and aarch64 guests are supported",
+                                machine_to_str(m), m)),
+    };
+    match hdr.e_ident[EI_DATA] {
+        ELFDATA2LSB => {}
+        ELFDATA2MSB => return Err(format!("big-endian {} binary, only little-endian guests are supported", arch)),
+        d => return Err(format!("{} binary with an invalid EI_DATA ({})", arch, d)),
+    }
+    let target = match (hdr.e_machine, class) {
+        (EM_RISCV, ELFCLASS32) => ElfTarget { machine: MachineType::Riscv, is_64: false },
+        (EM_RISCV, ELFCLASS64) => ElfTarget { machine: MachineType::Riscv, is_64: true },
+        (EM_AARCH64, ELFCLASS64) => ElfTarget { machine: MachineType::Arm64, is_64: true },
+        (EM_AARCH64, ELFCLASS32) => return Err("ILP32 aarch64 binary, only LP64 is supported".to_string()),
+        _ => return Err(format!("{} binary with an invalid class ({})", arch, class_name(class))),
+    };
+    match hdr.e_type {
+        ET_EXEC | ET_DYN => Ok(target),
+        ET_REL => Err(format!("{} relocatable object, not something that can run (link it first)", target.name())),
+        ET_CORE => Err(format!("{} core dump, not something that can run", target.name())),
+        t => Err(format!("{} ELF file of type {}, not something that can run", target.name(), t)),
+    }
+}
+/// Something loaded for the main binary (`main`), like its program interpreter, has to be for the
+/// same machine
+pub fn check_same_target(main: ElfTarget, main_path: &Path, what: &str, ef: &Elf, path: &Path) -> Result<(), String> {
+    let other = elf_target(&ef.header).map_err(|e| format!("{} {} is a {}", what, path.display(), e))?;
+    if other == main {
+        return Ok(());
+    }
+    let hint = if other.machine == main.machine {
+        format!("the sysroot looks like it's for {}, point --usermode-directory at an {} one", other.name(), main.name())
+    } else {
+        format!("point --usermode-directory at an {} sysroot", main.name())
+    };
+    Err(format!("{} {} is {} but {} is {}; {}", what, path.display(), other.name(),
+                main_path.display(), main.name(), hint))
+}
+/// The header of the ELF file at `host_path`, without reading the rest
+pub fn read_header(host_path: &CStr) -> Option<Header> {
+    let mut buf = [0u8; goblin::elf::header::header64::SIZEOF_EHDR];
+    let mut f = File::open(host_path.to_str().ok()?).ok()?;
+    // an ELF32 header is shorter, and so can the whole file be
+    let n = f.read(&mut buf).ok()?;
+    Elf::parse_header(&buf[..n]).ok()
+}
@@ -0,0 +1,98 @@
+// execve() and the bits of /proc a guest userland expects to find.
+// A guest exec of a binary for our own arch starts a fresh copy of the emulator on it (with the same
+// options), so a whole process tree (an init, shells, daemons) can run under usermode emulation.
+// Binaries for anything else (host tools, most likely) are run natively, and "#!" scripts get their
+// interpreter looked up in the guest's filesystem first.
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io::Read;
+use libc::{c_char, c_int, EFAULT, ENOEXEC};
+use crate::common::memory::{flat_mem, MemEndian};
+use crate::elf::MachineType;
+
+// linux gives up on longer "#!" lines too
+const SHEBANG_MAX: usize = 256;
+
+/// Reads a NULL terminated array of string pointers (argv, envp), EFAULT if it can't be read.
+/// With --check-pointers, ptrcheck.rs has already made sure every string is mapped
+pub fn read_guest_strv(addr: u64, is_64: bool, end: MemEndian) -> Result<Vec<CString>, c_int> {
+    let mut fm = flat_mem::new_usermode();
+    let mut out = vec![];
+    if addr == 0 {
+        return Ok(out);
+    }
+    let width = if is_64 { 8 } else { 4 };
+    let mut cur = addr;
+    loop {
+        let ptr = if is_64 {
+            fm.read_phys_64(cur, end).map_err(|_| EFAULT)?
+        } else {
+            fm.read_phys_32(cur, end).map_err(|_| EFAULT)? as u64
+        };
+        if ptr == 0 {
+            break;
+        }
+        out.push(unsafe { CStr::from_ptr(ptr as *const c_char) }.to_owned());
+        cur = cur.checked_add(width).ok_or(EFAULT)?;
+    }
+    Ok(out)
+}
+/// Interpreter and its optional argument, if the file is a "#!" script
+pub fn read_shebang(host_path: &CStr) -> Option<(String, Option<String>)> {
+    let mut f = File::open(host_path.to_str().ok()?).ok()?;
+    let mut buf = [0u8; SHEBANG_MAX];
+    let n = f.read(&mut buf).ok()?;
+    if n < 2 || &buf[..2] != b"#!" {
+        return None;
+    }
+    let line = &buf[2..n];
+    let line = &line[..line.iter().position(|c| *c == b'\n').unwrap_or(line.len())];
+    let line = String::from_utf8_lossy(line).trim().to_string();
+    if line.is_empty() {
+        return None;
+    }
+    // like linux, everything after the interpreter is one argument
+    match line.split_once(|c: char| c == ' ' || c == '\t') {
+        Some((interp, arg)) => {
+            let arg = arg.trim();
+            Some((interp.to_string(), if arg.is_empty() { None } else { Some(arg.to_string()) }))
+        }
+        None => Some((line, None)),
+    }
+}
+/// e_machine of an ELF file, None if it isn't one
+pub fn elf_machine(host_path: &CStr) -> Option<u16> {
+    let mut f = File::open(host_path.to_str().ok()?).ok()?;
+    let mut hdr = [0u8; 20];
+    f.read_exact(&mut hdr).ok()?;
+    if &hdr[..4] != b"\x7fELF" {
+        return None;
+    }
+    let m = [hdr[18], hdr[19]];
+    // EI_DATA
+    Some(if hdr[5] == 2 { u16::from_be_bytes(m) } else { u16::from_le_bytes(m) })
+}
+pub fn is_our_machine(mt: MachineType, e_machine: u16) -> bool {
+    match mt {
+        MachineType::Riscv => e_machine == goblin::elf::header::EM_RISCV,
+        MachineType::Arm64 => e_machine == goblin::elf::header::EM_AARCH64,
+        MachineType::None => false,
+    }
+}
+/// /proc/self/exe and friends, which would point at the emulator otherwise
+pub fn is_proc_exe_link(path: &str) -> bool {
+    let pid = unsafe { libc::getpid() };
+    path == "/proc/self/exe" || path == "/proc/thread-self/exe" || path == format!("/proc/{}/exe", pid)
+}
+/// Replaces the process, only returns on error
+pub fn host_execve(path: &CStr, argv: &[CString], envp: &[CString]) -> c_int {
+    let mut argv_p: Vec<*const c_char> = argv.iter().map(|a| a.as_ptr()).collect();
+    argv_p.push(std::ptr::null());
+    let mut envp_p: Vec<*const c_char> = envp.iter().map(|a| a.as_ptr()).collect();
+    envp_p.push(std::ptr::null());
+    unsafe {
+        libc::execve(path.as_ptr(), argv_p.as_ptr(), envp_p.as_ptr());
+    }
+    let err = base::Error::last().errno();
+    if err == 0 { ENOEXEC } else { err }
+}
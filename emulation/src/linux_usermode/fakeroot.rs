@@ -0,0 +1,354 @@
+// Fakeroot mode: the guest runs as root as far as it can tell.
+// chown() and mknod() of device nodes always work but only get remembered, chmod() is done for real
+// as far as the host lets us (the owner always keeps access, or we couldn't touch the file after a
+// chmod 000) and remembered too. stat() then shows the remembered values, and files owned by
+// the real user show up as owned by root, like fakeroot(1) does.
+// What is remembered is kept in memory, or in a "user.turbo.fakeroot" xattr on the file itself so
+// it survives later runs, for filesystems that take user xattrs. Files where that fails fall back
+// to memory. The memory is a table in a shared memfd mapping like the pid table (ids.rs), so
+// forked children and the emulator a guest exec starts see what the others faked, for as long as
+// one of them is still running. It has room for FAKE_TABLE_SLOTS files, past that chown() still
+// works but isn't remembered.
+use std::ffi::{CStr, CString};
+use std::mem::{size_of, MaybeUninit};
+use libc::{c_int, mode_t, AT_FDCWD, AT_SYMLINK_NOFOLLOW, MAP_FAILED, MAP_SHARED, PROT_READ, PROT_WRITE, S_IFBLK, S_IFCHR, S_IFMT};
+use crate::linux_usermode::defs::GenericStat;
+use crate::linux_usermode::ids::SharedLock;
+
+const FAKEROOT_XATTR: &[u8] = b"user.turbo.fakeroot\0";
+pub const FAKE_TABLE_SLOTS: usize = 8192;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FakeStore {
+    Memory,
+    Xattr,
+}
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+struct FakeAttrs {
+    uid: Option<u32>,
+    gid: Option<u32>,
+    mode: Option<u32>, // full st_mode, file type bits included
+    rdev: Option<u64>,
+}
+impl FakeAttrs {
+    fn encode(&self) -> String {
+        let f = |v: Option<u64>| v.map(|x| x.to_string()).unwrap_or_default();
+        format!("{}:{}:{}:{}", f(self.uid.map(|x| x as u64)), f(self.gid.map(|x| x as u64)),
+                f(self.mode.map(|x| x as u64)), f(self.rdev))
+    }
+    fn decode(s: &str) -> Option<FakeAttrs> {
+        let mut it = s.split(':');
+        let mut next = || -> Option<Option<u64>> {
+            let p = it.next()?;
+            Some(if p.is_empty() { None } else { Some(p.parse().ok()?) })
+        };
+        Some(FakeAttrs {
+            uid: next()?.map(|x| x as u32),
+            gid: next()?.map(|x| x as u32),
+            mode: next()?.map(|x| x as u32),
+            rdev: next()?,
+        })
+    }
+}
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct FakeSlot {
+    // (0, 0) is a free slot
+    dev: u64,
+    ino: u64,
+    set: u32, // FAKE_* bits of the fields below that mean something
+    uid: u32,
+    gid: u32,
+    mode: u32,
+    rdev: u64,
+}
+const FAKE_UID: u32 = 1;
+const FAKE_GID: u32 = 2;
+const FAKE_MODE: u32 = 4;
+const FAKE_RDEV: u32 = 8;
+impl FakeSlot {
+    fn attrs(&self) -> FakeAttrs {
+        let get = |bit: u32, v: u64| if self.set & bit != 0 { Some(v) } else { None };
+        FakeAttrs {
+            uid: get(FAKE_UID, self.uid as u64).map(|x| x as u32),
+            gid: get(FAKE_GID, self.gid as u64).map(|x| x as u32),
+            mode: get(FAKE_MODE, self.mode as u64).map(|x| x as u32),
+            rdev: get(FAKE_RDEV, self.rdev),
+        }
+    }
+    fn new(key: (u64, u64), a: FakeAttrs) -> FakeSlot {
+        let bit = |v: bool, bit: u32| if v { bit } else { 0 };
+        FakeSlot {
+            dev: key.0,
+            ino: key.1,
+            set: bit(a.uid.is_some(), FAKE_UID) | bit(a.gid.is_some(), FAKE_GID)
+                | bit(a.mode.is_some(), FAKE_MODE) | bit(a.rdev.is_some(), FAKE_RDEV),
+            uid: a.uid.unwrap_or(0),
+            gid: a.gid.unwrap_or(0),
+            mode: a.mode.unwrap_or(0),
+            rdev: a.rdev.unwrap_or(0),
+        }
+    }
+}
+// the lock comes first, padded out so the slots' u64s are aligned
+const SLOTS_AT: usize = 8;
+const TABLE_SIZE: usize = SLOTS_AT + FAKE_TABLE_SLOTS * size_of::<FakeSlot>();
+
+/// What was faked, keyed by (st_dev, st_ino)
+pub struct FakeTable {
+    base: *mut u8,
+    fd: c_int,
+}
+// the slots are only touched with the lock held
+unsafe impl Send for FakeTable {}
+unsafe impl Sync for FakeTable {}
+
+impl FakeTable {
+    /// New empty table
+    pub fn create() -> Result<FakeTable, c_int> {
+        // no MFD_CLOEXEC, execve has to keep it
+        let fd = unsafe { libc::memfd_create(b"turbo-fakeroot\0".as_ptr() as *const libc::c_char, 0) };
+        if fd < 0 {
+            return Err(base::Error::last().errno());
+        }
+        if unsafe { libc::ftruncate(fd, TABLE_SIZE as libc::off_t) } < 0 {
+            let err = base::Error::last().errno();
+            unsafe { libc::close(fd) };
+            return Err(err);
+        }
+        FakeTable::open(fd)
+    }
+    /// Table a previous emulator (before the guest exec'd) left in `fd`
+    pub fn open(fd: c_int) -> Result<FakeTable, c_int> {
+        let base = unsafe {
+            libc::mmap(std::ptr::null_mut(), TABLE_SIZE, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0)
+        };
+        if base == MAP_FAILED {
+            return Err(base::Error::last().errno());
+        }
+        Ok(FakeTable { base: base as *mut u8, fd })
+    }
+    pub fn fd(&self) -> c_int {
+        self.fd
+    }
+    fn locked<R>(&self, f: impl FnOnce(&mut [FakeSlot]) -> R) -> R {
+        let lock = unsafe { &*(self.base as *const SharedLock) };
+        lock.locked(|| {
+            let slots = unsafe {
+                let at = self.base.add(SLOTS_AT) as *mut FakeSlot;
+                std::slice::from_raw_parts_mut(at, FAKE_TABLE_SLOTS)
+            };
+            f(slots)
+        })
+    }
+    fn get(&self, key: (u64, u64)) -> Option<FakeAttrs> {
+        self.locked(|s| s.iter().find(|e| (e.dev, e.ino) == key).map(|e| e.attrs()))
+    }
+    fn insert(&self, key: (u64, u64), attrs: FakeAttrs) {
+        let stored = self.locked(|s| {
+            let slot = s.iter().position(|e| (e.dev, e.ino) == key)
+                .or_else(|| s.iter().position(|e| (e.dev, e.ino) == (0, 0)));
+            slot.map(|i| s[i] = FakeSlot::new(key, attrs)).is_some()
+        });
+        if !stored {
+            crate::warn_ratelimited!("fakeroot table is full, ownership of inode {} isn't remembered", key.1);
+        }
+    }
+    fn remove(&self, key: (u64, u64)) {
+        self.locked(|s| {
+            for e in s.iter_mut().filter(|e| (e.dev, e.ino) == key) {
+                *e = FakeSlot::default();
+            }
+        })
+    }
+}
+impl Drop for FakeTable {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base as *mut libc::c_void, TABLE_SIZE);
+        }
+    }
+}
+/// A file a syscall is about, as a host path getxattr and friends can use
+pub struct FileRef {
+    path: CString,
+    nofollow: bool,
+}
+impl FileRef {
+    pub fn fd(fd: c_int) -> FileRef {
+        FileRef {
+            path: CString::new(format!("/proc/self/fd/{}", fd)).unwrap(),
+            nofollow: false,
+        }
+    }
+    /// dirfd + (already host) path, like the *at() syscalls take. Empty path means dirfd itself
+    pub fn at(dirfd: c_int, path: &CStr, flags: c_int) -> FileRef {
+        let p = path.to_string_lossy();
+        let full = if p.is_empty() {
+            format!("/proc/self/fd/{}", dirfd)
+        } else if p.starts_with('/') || dirfd == AT_FDCWD {
+            p.to_string()
+        } else {
+            format!("/proc/self/fd/{}/{}", dirfd, p)
+        };
+        FileRef {
+            path: CString::new(full).unwrap(),
+            nofollow: flags & AT_SYMLINK_NOFOLLOW != 0,
+        }
+    }
+    fn stat(&self) -> Option<libc::stat> {
+        let mut st = MaybeUninit::<libc::stat>::zeroed();
+        let res = unsafe {
+            if self.nofollow {
+                libc::lstat(self.path.as_ptr(), st.as_mut_ptr())
+            } else {
+                libc::stat(self.path.as_ptr(), st.as_mut_ptr())
+            }
+        };
+        if res < 0 {
+            None
+        } else {
+            Some(unsafe { st.assume_init() })
+        }
+    }
+}
+pub struct FakeRoot {
+    store: FakeStore,
+    /// what the guest is told it is
+    uid: u32,
+    gid: u32,
+    host_uid: u32,
+    host_gid: u32,
+    table: FakeTable,
+}
+impl FakeRoot {
+    pub fn new(store: FakeStore, uid: u32, gid: u32, table: FakeTable) -> FakeRoot {
+        FakeRoot {
+            store,
+            uid,
+            gid,
+            host_uid: unsafe { libc::getuid() },
+            host_gid: unsafe { libc::getgid() },
+            table,
+        }
+    }
+    /// For the emulator a guest exec starts
+    pub fn table_fd(&self) -> c_int {
+        self.table.fd()
+    }
+    fn load(&self, f: &FileRef, key: (u64, u64)) -> FakeAttrs {
+        if let Some(a) = self.table.get(key) {
+            return a;
+        }
+        if self.store != FakeStore::Xattr {
+            return FakeAttrs::default();
+        }
+        let mut buf = [0u8; 128];
+        let n = unsafe {
+            let name = FAKEROOT_XATTR.as_ptr() as *const libc::c_char;
+            let b = buf.as_mut_ptr() as *mut libc::c_void;
+            if f.nofollow {
+                libc::lgetxattr(f.path.as_ptr(), name, b, buf.len())
+            } else {
+                libc::getxattr(f.path.as_ptr(), name, b, buf.len())
+            }
+        };
+        if n <= 0 {
+            return FakeAttrs::default();
+        }
+        FakeAttrs::decode(&String::from_utf8_lossy(&buf[..n as usize])).unwrap_or_default()
+    }
+    fn save(&self, f: &FileRef, key: (u64, u64), attrs: FakeAttrs) {
+        if self.store == FakeStore::Xattr {
+            let val = attrs.encode();
+            let res = unsafe {
+                let name = FAKEROOT_XATTR.as_ptr() as *const libc::c_char;
+                let v = val.as_ptr() as *const libc::c_void;
+                if f.nofollow {
+                    libc::lsetxattr(f.path.as_ptr(), name, v, val.len(), 0)
+                } else {
+                    libc::setxattr(f.path.as_ptr(), name, v, val.len(), 0)
+                }
+            };
+            if res == 0 {
+                // no need to keep it twice
+                self.table.remove(key);
+                return;
+            }
+        }
+        self.table.insert(key, attrs);
+    }
+    fn update(&self, f: &FileRef, change: impl FnOnce(&mut FakeAttrs)) -> Result<(), c_int> {
+        let st = f.stat().ok_or_else(|| base::Error::last().errno())?;
+        let key = (st.st_dev as u64, st.st_ino as u64);
+        let mut attrs = self.load(f, key);
+        change(&mut attrs);
+        self.save(f, key, attrs);
+        Ok(())
+    }
+    /// chown() that always works, -1 leaves that id alone
+    pub fn chown(&self, f: &FileRef, uid: u32, gid: u32) -> Result<(), c_int> {
+        self.update(f, |a| {
+            if uid != u32::MAX {
+                a.uid = Some(uid);
+            }
+            if gid != u32::MAX {
+                a.gid = Some(gid);
+            }
+        })
+    }
+    /// chmod() that the host does as far as it can
+    pub fn chmod(&self, f: &FileRef, mode: mode_t) -> Result<(), c_int> {
+        let st = f.stat().ok_or_else(|| base::Error::last().errno())?;
+        let is_dir = st.st_mode & S_IFMT == libc::S_IFDIR;
+        let keep = if is_dir { libc::S_IRWXU } else { libc::S_IRUSR | libc::S_IWUSR };
+        // symlink modes can't be changed, and files that aren't ours only pretend to
+        if !f.nofollow && st.st_uid == self.host_uid {
+            unsafe { libc::chmod(f.path.as_ptr(), (mode & 0o7777) | keep) };
+        }
+        let ftype = st.st_mode & S_IFMT;
+        self.update(f, |a| {
+            // fake device nodes are regular files on the host
+            let t = a.mode.map(|m| m & S_IFMT).unwrap_or(ftype);
+            a.mode = Some(t | (mode & 0o7777));
+        })
+    }
+    /// mknod() of a device node, made as an empty regular file with the node remembered.
+    /// Other types the host can make itself
+    pub fn mknod(&self, dirfd: c_int, path: &CStr, mode: mode_t, dev: u64) -> Result<(), c_int> {
+        let fd = unsafe { libc::openat(dirfd, path.as_ptr(), libc::O_CREAT | libc::O_EXCL | libc::O_WRONLY | libc::O_CLOEXEC, 0o600) };
+        if fd < 0 {
+            return Err(base::Error::last().errno());
+        }
+        unsafe { libc::close(fd) };
+        self.update(&FileRef::at(dirfd, path, 0), |a| {
+            a.mode = Some(mode);
+            a.rdev = Some(dev);
+        })
+    }
+    pub fn is_fake_node(mode: mode_t) -> bool {
+        matches!(mode & S_IFMT, S_IFCHR | S_IFBLK)
+    }
+    /// What stat() should say about `f`
+    pub fn fix_stat(&self, f: &FileRef, st: &mut GenericStat) {
+        if st.st_uid == self.host_uid as u64 {
+            st.st_uid = self.uid as u64;
+        }
+        if st.st_gid == self.host_gid as u64 {
+            st.st_gid = self.gid as u64;
+        }
+        let a = self.load(f, (st.st_dev, st.st_ino));
+        if let Some(u) = a.uid {
+            st.st_uid = u as u64;
+        }
+        if let Some(g) = a.gid {
+            st.st_gid = g as u64;
+        }
+        if let Some(m) = a.mode {
+            st.st_mode = m as u64;
+        }
+        if let Some(r) = a.rdev {
+            st.st_rdev = r;
+        }
+    }
+}
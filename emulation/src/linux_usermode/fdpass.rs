@@ -0,0 +1,282 @@
+// Host fds handed to the guest at fixed numbers, for the "inherit this socket as fd 3" pattern of
+// daemons and test harnesses. Embedders open whatever they like and list it as an FdGrant; the
+// fds are moved into place before the emulator opens anything of its own, so nothing of ours
+// ends up on those numbers.
+// Guest fds are host fds, so the grant's capabilities are checked at the syscall layer: reads
+// and writes the grant doesn't allow fail with EBADF like on an fd opened the other way, and a
+// "keep" fd can't be closed or replaced by the guest (close() claims it worked). Copies carry the
+// same capabilities, however they're made: dup(), dup3() (libc's dup2() is dup3() on these abis),
+// fcntl(F_DUPFD), reopening /proc/self/fd/N or getting one back through SCM_RIGHTS. Syscalls we
+// don't look at (ioctl and such) aren't restricted.
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::os::unix::io::RawFd;
+use libc::{c_char, c_int, EBADF, F_DUPFD, F_DUPFD_CLOEXEC, F_GETFD, F_SETFD, FD_CLOEXEC, MAP_ANONYMOUS, MAP_SHARED,
+           O_ACCMODE, O_PATH, O_RDONLY, O_TRUNC, O_WRONLY, PROT_WRITE};
+use base::warn;
+use sync::Mutex;
+use crate::linux_usermode::main::{SyscallIn, SyscallOut, SyscallType};
+use crate::linux_usermode::vfs::normalize_guest;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FdCaps {
+    pub read: bool,
+    pub write: bool,
+    /// the guest can't close it or dup3() over it
+    pub keep: bool,
+}
+impl Default for FdCaps {
+    fn default() -> Self {
+        FdCaps { read: true, write: true, keep: false }
+    }
+}
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FdGrant {
+    pub host_fd: RawFd,
+    pub guest_fd: RawFd,
+    pub caps: FdCaps,
+}
+impl FdGrant {
+    /// "GUEST[=HOST][:r|w|rw][,keep]", e.g. "3", "3=7:r" or "4:rw,keep"
+    pub fn parse(s: &str) -> Result<FdGrant, String> {
+        let bad = || format!("bad fd grant {}, expected GUEST[=HOST][:r|w|rw][,keep]", s);
+        let (fds, rest) = s.split_once(':').unwrap_or((s, ""));
+        let (guest, host) = fds.split_once('=').unwrap_or((fds, fds));
+        let guest_fd: RawFd = guest.parse().map_err(|_| bad())?;
+        let host_fd: RawFd = host.parse().map_err(|_| bad())?;
+        if guest_fd < 0 || host_fd < 0 {
+            return Err(bad());
+        }
+        let mut caps = FdCaps::default();
+        for (i, part) in rest.split(',').filter(|p| !p.is_empty()).enumerate() {
+            match part {
+                "keep" => caps.keep = true,
+                "r" | "w" | "rw" if i == 0 => {
+                    caps.read = part.contains('r');
+                    caps.write = part.contains('w');
+                }
+                _ => return Err(bad()),
+            }
+        }
+        Ok(FdGrant { host_fd, guest_fd, caps })
+    }
+    /// How to ask for the same fd after an exec, when it's already in place
+    pub fn in_place_arg(&self) -> String {
+        let rw = match (self.caps.read, self.caps.write) {
+            (true, false) => ":r",
+            (false, true) => ":w",
+            _ => ":rw",
+        };
+        format!("{}{}{}", self.guest_fd, rw, if self.caps.keep { ",keep" } else { "" })
+    }
+}
+pub enum FdVerdict {
+    Allow,
+    Deny(c_int),
+    /// pretend it worked without doing it
+    Skip,
+}
+#[derive(Debug, Default)]
+pub struct FdPolicy {
+    grants: Vec<FdGrant>,
+    /// guest fd -> what it may do, granted fds and their dups
+    table: Mutex<BTreeMap<RawFd, FdCaps>>,
+}
+fn fd_open(fd: RawFd) -> bool {
+    unsafe { libc::fcntl(fd, F_GETFD) >= 0 }
+}
+// kcmp() type for "same open file"
+const KCMP_FILE: c_int = 0;
+/// Whether two fds are the same open file, like a dup or one sent through a socket
+fn same_file(a: RawFd, b: RawFd) -> bool {
+    let pid = unsafe { libc::getpid() };
+    match unsafe { libc::syscall(libc::SYS_kcmp, pid, pid, KCMP_FILE, a, b) } {
+        0 => true,
+        r if r > 0 => false,
+        // kcmp() can be compiled out or forbidden, the inode is the next best thing
+        _ => {
+            let (mut sa, mut sb): (libc::stat, libc::stat) = unsafe { (std::mem::zeroed(), std::mem::zeroed()) };
+            unsafe { libc::fstat(a, &mut sa) == 0 && libc::fstat(b, &mut sb) == 0 }
+                && sa.st_dev == sb.st_dev && sa.st_ino == sb.st_ino
+        }
+    }
+}
+/// The fd an open() reopens through /proc/self/fd/N and friends, with the open's flags
+fn proc_fd_open(sysin: &SyscallIn) -> Option<(RawFd, c_int)> {
+    let (path, flags) = match sysin.syscall {
+        SyscallType::Open => (sysin.args[0], sysin.args[1] as c_int),
+        SyscallType::Openat => (sysin.args[1], sysin.args[2] as c_int),
+        _ => return None,
+    };
+    if path == 0 {
+        return None;
+    }
+    let path = unsafe { CStr::from_ptr(path as *const c_char) }.to_string_lossy().to_string();
+    Some((fd_path_number(&path)?, flags))
+}
+/// N for "/proc/self/fd/N", "/dev/fd/N", "/dev/stdin" and the like
+pub fn fd_path_number(path: &str) -> Option<RawFd> {
+    let path = normalize_guest(path);
+    let n = match path.as_str() {
+        "/dev/stdin" => return Some(0),
+        "/dev/stdout" => return Some(1),
+        "/dev/stderr" => return Some(2),
+        p => {
+            let ours = format!("/proc/{}/fd/", unsafe { libc::getpid() });
+            ["/proc/self/fd/", "/proc/thread-self/fd/", "/dev/fd/", ours.as_str()].iter()
+                .find_map(|pre| p.strip_prefix(pre))?.to_string()
+        }
+    };
+    n.parse().ok().filter(|fd: &RawFd| *fd >= 0)
+}
+fn is_dupfd(sysin: &SyscallIn) -> bool {
+    matches!(sysin.syscall, SyscallType::Fcntl | SyscallType::Fcntl64)
+        && matches!(sysin.args[1] as c_int, F_DUPFD | F_DUPFD_CLOEXEC)
+}
+impl FdPolicy {
+    pub fn new(grants: Vec<FdGrant>) -> FdPolicy {
+        let table = grants.iter().map(|g| (g.guest_fd, g.caps)).collect();
+        FdPolicy { grants, table: Mutex::new(table) }
+    }
+    pub fn grants(&self) -> &[FdGrant] {
+        &self.grants
+    }
+    /// Move every grant to its guest number. Has to run before the emulator opens files of its own
+    pub fn install(&self) -> Result<(), String> {
+        // a guest that closed one and then exec'd gets the same list again
+        for g in self.grants.iter().filter(|g| g.host_fd == g.guest_fd && !fd_open(g.host_fd)) {
+            warn!("fd {} isn't open, not passing it to the guest", g.guest_fd);
+            self.table.lock().remove(&g.guest_fd);
+        }
+        let grants: Vec<&FdGrant> = self.grants.iter().filter(|g| g.host_fd != g.guest_fd || fd_open(g.host_fd)).collect();
+        for g in &grants {
+            if grants.iter().filter(|o| o.guest_fd == g.guest_fd).count() > 1 {
+                return Err(format!("fd {} is granted twice", g.guest_fd));
+            }
+            if !fd_open(g.host_fd) {
+                return Err(format!("host fd {} isn't open", g.host_fd));
+            }
+            // something else of ours sitting there would get closed behind its back
+            let taken = grants.iter().any(|o| o.host_fd == g.guest_fd);
+            if g.host_fd != g.guest_fd && fd_open(g.guest_fd) && !taken {
+                return Err(format!("fd {} is already in use", g.guest_fd));
+            }
+        }
+        // grants can be moved onto each other's host fds, so duplicate them all out of the way first
+        let mut staged = vec![];
+        for g in &grants {
+            let tmp = unsafe { libc::fcntl(g.host_fd, libc::F_DUPFD_CLOEXEC, 0) };
+            if tmp < 0 {
+                return Err(format!("can't dup host fd {}: {}", g.host_fd, std::io::Error::last_os_error()));
+            }
+            staged.push(tmp);
+        }
+        for g in &grants {
+            if g.host_fd != g.guest_fd && !grants.iter().any(|o| o.guest_fd == g.host_fd) {
+                unsafe { libc::close(g.host_fd) };
+            }
+        }
+        for (g, tmp) in grants.iter().zip(staged) {
+            let res = unsafe { libc::dup2(tmp, g.guest_fd) };
+            unsafe { libc::close(tmp) };
+            if res < 0 {
+                return Err(format!("can't move fd to {}: {}", g.guest_fd, std::io::Error::last_os_error()));
+            }
+            // stays open across guest execs, like inherited fds do
+            unsafe {
+                let fl = libc::fcntl(g.guest_fd, F_GETFD);
+                libc::fcntl(g.guest_fd, F_SETFD, fl & !FD_CLOEXEC);
+            }
+        }
+        Ok(())
+    }
+    fn caps(&self, fd: u64) -> Option<FdCaps> {
+        self.table.lock().get(&(fd as RawFd)).copied()
+    }
+    /// `new` is a fresh copy of `old`: the same rights, but it can be closed
+    fn copied(&self, old: RawFd, new: RawFd) {
+        let mut t = self.table.lock();
+        match t.get(&old).copied() {
+            Some(c) => t.insert(new, FdCaps { keep: false, ..c }),
+            None => t.remove(&new),
+        };
+    }
+    /// Fds recvmsg() just put into the guest: one that's a granted file coming back gets the
+    /// grant's rights again
+    pub fn received(&self, fds: &[RawFd]) {
+        let mut t = self.table.lock();
+        for &fd in fds {
+            let from = t.iter().find(|(&g, _)| g != fd && same_file(g, fd)).map(|(_, c)| *c);
+            match from {
+                Some(c) => t.insert(fd, FdCaps { keep: false, ..c }),
+                None => t.remove(&fd),
+            };
+        }
+    }
+    fn need(&self, fd: u64, read: bool, write: bool) -> FdVerdict {
+        match self.caps(fd) {
+            Some(c) if (read && !c.read) || (write && !c.write) => FdVerdict::Deny(EBADF),
+            _ => FdVerdict::Allow,
+        }
+    }
+    /// Before the syscall: whether the guest may do it to the fds involved
+    pub fn check(&self, sysin: &SyscallIn) -> FdVerdict {
+        let a = &sysin.args;
+        match sysin.syscall {
+            SyscallType::Read | SyscallType::Readv | SyscallType::Recvfrom | SyscallType::Recvmsg => {
+                self.need(a[0], true, false)
+            }
+            SyscallType::Write | SyscallType::Writev | SyscallType::Sendto | SyscallType::Sendmsg | SyscallType::Ftruncate => {
+                self.need(a[0], false, true)
+            }
+            SyscallType::Sendfile => match self.need(a[0], false, true) {
+                FdVerdict::Allow => self.need(a[1], true, false),
+                v => v,
+            },
+            SyscallType::Mmap | SyscallType::Mmap2 if a[3] as c_int & MAP_ANONYMOUS == 0 => {
+                let shared_write = a[3] as c_int & MAP_SHARED != 0 && a[2] as c_int & PROT_WRITE != 0;
+                self.need(a[4], true, shared_write)
+            }
+            SyscallType::Close => match self.caps(a[0]) {
+                Some(c) if c.keep => FdVerdict::Skip,
+                _ => FdVerdict::Allow,
+            },
+            SyscallType::Dup3 => match self.caps(a[1]) {
+                Some(c) if c.keep => FdVerdict::Deny(EBADF),
+                _ => FdVerdict::Allow,
+            },
+            // reopening is a way around the access mode the grant has
+            SyscallType::Open | SyscallType::Openat => match proc_fd_open(sysin) {
+                Some((fd, flags)) if flags & O_PATH == 0 => {
+                    let acc = flags & O_ACCMODE;
+                    self.need(fd as u64, acc != O_WRONLY, acc != O_RDONLY || flags & O_TRUNC != 0)
+                }
+                _ => FdVerdict::Allow,
+            },
+            _ => FdVerdict::Allow,
+        }
+    }
+    /// After the syscall: closed numbers are free for anything again, dups inherit
+    pub fn after(&self, sysin: &SyscallIn, sout: &SyscallOut) {
+        if sout.is_error {
+            return;
+        }
+        let a = &sysin.args;
+        match sysin.syscall {
+            SyscallType::Close => {
+                self.table.lock().remove(&(a[0] as RawFd));
+            }
+            SyscallType::Dup3 => self.copied(a[0] as RawFd, a[1] as RawFd),
+            SyscallType::Dup => self.copied(a[0] as RawFd, sout.ret1 as RawFd),
+            _ if is_dupfd(sysin) => self.copied(a[0] as RawFd, sout.ret1 as RawFd),
+            SyscallType::Open | SyscallType::Openat => match proc_fd_open(sysin) {
+                Some((fd, _)) => self.copied(fd, sout.ret1 as RawFd),
+                // the number is free again, whatever it was
+                None => {
+                    self.table.lock().remove(&(sout.ret1 as RawFd));
+                }
+            },
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,171 @@
+// What the guest can find out about the machine it runs on, so a run can pass for some particular
+// device. Every part is optional and stays the host's when not set: the host name (uname() and
+// the files it's also read from), /etc/os-release, the machine id, the MAC address of the network
+// interfaces (SIOCGIFHWADDR and /sys/class/net/*/address, every interface but lo gets the same
+// one) and /proc/cpuinfo.
+// The faked files are written at start to a directory of their own under $TMPDIR (the view), and
+// path lookups of them go there instead (see resolve_guest_path), so open(), stat() and the rest
+// all agree without the sysroot or an overlay's upper layer being touched. The view is named after
+// the process, an emulator started again by a guest execve() replaces it, and it's removed when
+// the process that made it exits.
+use std::ffi::CStr;
+use std::fs;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use libc::{c_char, pid_t, utsname};
+
+const HOSTNAME_FILES: [&str; 2] = ["/etc/hostname", "/proc/sys/kernel/hostname"];
+const OS_RELEASE_FILES: [&str; 2] = ["/etc/os-release", "/usr/lib/os-release"];
+const MACHINE_ID_FILES: [&str; 2] = ["/etc/machine-id", "/var/lib/dbus/machine-id"];
+// struct ifreq: name, then the sockaddr whose sa_data has the address
+const IFNAMSIZ: usize = 16;
+// every interface's /sys/class/net/*/address in the view
+const MAC_FILE: &str = "sys-class-net-address";
+
+#[derive(Clone, Debug, Default)]
+pub struct GuestIdentity {
+    pub hostname: Option<String>,
+    pub mac: Option<[u8; 6]>,
+    /// guest path -> what reading it gives
+    files: Vec<(String, Vec<u8>)>,
+    /// (directory the files are served from, process that made it)
+    view: Option<(PathBuf, pid_t)>,
+}
+impl GuestIdentity {
+    pub fn new() -> GuestIdentity {
+        Default::default()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.hostname.is_none() && self.mac.is_none() && self.files.is_empty()
+    }
+    fn set_file(&mut self, guest: &str, contents: Vec<u8>) {
+        self.files.retain(|(p, _)| p != guest);
+        self.files.push((guest.to_string(), contents));
+    }
+    pub fn set_hostname(&mut self, name: &str) -> Result<(), String> {
+        // nodename is 65 bytes with the NUL
+        if name.is_empty() || name.len() > 64 || name.contains(|c: char| c.is_whitespace() || c == '\0') {
+            return Err(format!("bad host name {:?}", name));
+        }
+        for f in HOSTNAME_FILES {
+            self.set_file(f, format!("{}\n", name).into_bytes());
+        }
+        self.hostname = Some(name.to_string());
+        Ok(())
+    }
+    /// os-release contents from a host file
+    pub fn set_os_release(&mut self, file: &Path) -> Result<(), String> {
+        let data = fs::read(file).map_err(|e| format!("can't read {}: {}", file.display(), e))?;
+        for f in OS_RELEASE_FILES {
+            self.set_file(f, data.clone());
+        }
+        Ok(())
+    }
+    /// 32 hex digits, like systemd writes it
+    pub fn set_machine_id(&mut self, id: &str) -> Result<(), String> {
+        if id.len() != 32 || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("bad machine id {}, expected 32 hex digits", id));
+        }
+        for f in MACHINE_ID_FILES {
+            self.set_file(f, format!("{}\n", id.to_ascii_lowercase()).into_bytes());
+        }
+        Ok(())
+    }
+    /// "aa:bb:cc:dd:ee:ff"
+    pub fn set_mac(&mut self, s: &str) -> Result<(), String> {
+        let bytes: Vec<u8> = s.split(':').filter_map(|b| u8::from_str_radix(b, 16).ok()).collect();
+        if bytes.len() != 6 || s.split(':').count() != 6 {
+            return Err(format!("bad MAC address {}", s));
+        }
+        let mut mac = [0; 6];
+        mac.copy_from_slice(&bytes);
+        self.mac = Some(mac);
+        Ok(())
+    }
+    /// /proc/cpuinfo contents from a host file
+    pub fn set_cpuinfo(&mut self, file: &Path) -> Result<(), String> {
+        let data = fs::read(file).map_err(|e| format!("can't read {}: {}", file.display(), e))?;
+        self.set_file("/proc/cpuinfo", data);
+        Ok(())
+    }
+    fn mac_string(mac: &[u8; 6]) -> String {
+        mac.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":")
+    }
+    /// What reading the guest path gives, if it's one we fake
+    pub fn contents(&self, guest: &str) -> Option<Vec<u8>> {
+        if let Some((_, c)) = self.files.iter().find(|(p, _)| p == guest) {
+            return Some(c.clone());
+        }
+        let mac = self.mac.as_ref()?;
+        Self::is_mac_file(guest).then(|| format!("{}\n", Self::mac_string(mac)).into_bytes())
+    }
+    fn is_mac_file(guest: &str) -> bool {
+        match guest.strip_prefix("/sys/class/net/").and_then(|p| p.strip_suffix("/address")) {
+            Some(ifname) => ifname != "lo" && !ifname.contains('/'),
+            None => false,
+        }
+    }
+    /// Write the faked files to the view, replacing the one an emulator in this process made
+    /// before an execve()
+    pub fn make_view(&mut self) -> std::io::Result<()> {
+        let pid = unsafe { libc::getpid() };
+        let dir = std::env::temp_dir().join(format!("turbo-identity-{}", pid));
+        match fs::remove_dir_all(&dir) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        fs::DirBuilder::new().mode(0o700).create(&dir)?;
+        for (guest, data) in &self.files {
+            let p = dir.join(guest.trim_start_matches('/'));
+            if let Some(parent) = p.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&p, data)?;
+        }
+        if let Some(mac) = &self.mac {
+            fs::write(dir.join(MAC_FILE), format!("{}\n", Self::mac_string(mac)))?;
+        }
+        self.view = Some((dir, pid));
+        Ok(())
+    }
+    /// Where lookups of the (absolute, normalized) guest path go, if it's one we fake
+    pub fn view_path(&self, guest: &str) -> Option<PathBuf> {
+        let (dir, _) = self.view.as_ref()?;
+        if self.files.iter().any(|(p, _)| p == guest) {
+            Some(dir.join(guest.trim_start_matches('/')))
+        } else if self.mac.is_some() && Self::is_mac_file(guest) {
+            Some(dir.join(MAC_FILE))
+        } else {
+            None
+        }
+    }
+    /// Remove the view, if this is the process that made it
+    pub fn remove_view(&self) {
+        if let Some((dir, pid)) = &self.view {
+            if *pid == unsafe { libc::getpid() } {
+                let _ = fs::remove_dir_all(dir);
+            }
+        }
+    }
+    pub fn patch_uname(&self, u: &mut utsname) {
+        if let Some(name) = &self.hostname {
+            u.nodename = [0; 65];
+            for (d, s) in u.nodename.iter_mut().zip(name.bytes()) {
+                *d = s as c_char;
+            }
+        }
+    }
+    /// `ifr` is a struct ifreq SIOCGIFHWADDR filled in
+    pub unsafe fn patch_hwaddr(&self, ifr: *mut u8) {
+        let mac = match &self.mac {
+            Some(m) => m,
+            None => return,
+        };
+        let name = CStr::from_ptr(ifr as *const c_char);
+        if name.to_bytes() == b"lo" {
+            return;
+        }
+        // after sa_family
+        std::ptr::copy_nonoverlapping(mac.as_ptr(), ifr.add(IFNAMSIZ + 2), mac.len());
+    }
+}
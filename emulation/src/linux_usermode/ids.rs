@@ -0,0 +1,182 @@
+// Guest-local process ids.
+// Guest pids/tids are handed out from our own counter instead of showing the host's, so they stay
+// small and the same from run to run, and the first process can be pid 1 when it is an init.
+// The table lives in a shared memfd mapping, so forked children see the same one, and its fd is
+// kept open across execve() so the emulator started by a guest exec can pick it up again.
+// Host pids of processes outside the guest (like the parent of the first one) have no guest pid,
+// getppid() returns 0 for those, same as in a pid namespace.
+use std::mem::size_of;
+use std::sync::atomic::{AtomicI32, AtomicU32, Ordering};
+use libc::{c_int, MAP_FAILED, MAP_SHARED, PROT_READ, PROT_WRITE};
+
+pub const ID_TABLE_SLOTS: usize = 4096;
+/// Guest pid of the first process when it runs as an init
+pub const INIT_PID: i32 = 1;
+const FIRST_PID: i32 = 2;
+
+#[repr(C)]
+struct IdSlot {
+    host: AtomicI32, // 0 is a free slot
+    virt: AtomicI32,
+}
+/// Spinlock for memory shared with other processes, so no futex/mutex. It holds the tid of
+/// whoever has it, and a waiter takes it over when that thread is gone: a process killed part
+/// way through would otherwise leave everyone else spinning forever
+#[repr(transparent)]
+pub(crate) struct SharedLock(pub(crate) AtomicU32);
+impl SharedLock {
+    /// how many times a waiter spins between looking at whether the holder is still there
+    const SPINS_PER_CHECK: u32 = 64;
+    pub(crate) fn locked<R>(&self, f: impl FnOnce() -> R) -> R {
+        let me = unsafe { libc::gettid() } as u32;
+        let mut spins = 0;
+        loop {
+            match self.0.compare_exchange(0, me, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(holder) => {
+                    spins += 1;
+                    if spins % Self::SPINS_PER_CHECK == 0 && !alive(holder) {
+                        // only one waiter gets to take it over
+                        if self.0.compare_exchange(holder, me, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                            crate::warn_ratelimited!("thread {} died holding a shared table lock, taking it over", holder);
+                            break;
+                        }
+                    }
+                    std::thread::yield_now();
+                }
+            }
+        }
+        let r = f();
+        self.0.store(0, Ordering::Release);
+        r
+    }
+}
+fn alive(tid: u32) -> bool {
+    // signal 0 only checks, and it finds threads by their tid too
+    unsafe { libc::kill(tid as libc::pid_t, 0) } == 0 || base::Error::last().errno() != libc::ESRCH
+}
+#[repr(C)]
+struct IdHeader {
+    lock: SharedLock,
+    next: AtomicI32,
+}
+const TABLE_SIZE: usize = size_of::<IdHeader>() + ID_TABLE_SLOTS * size_of::<IdSlot>();
+
+pub struct IdTable {
+    base: *mut u8,
+    fd: c_int,
+}
+// everything in the mapping is atomics
+unsafe impl Send for IdTable {}
+unsafe impl Sync for IdTable {}
+
+impl IdTable {
+    /// New empty table
+    pub fn create() -> Result<IdTable, c_int> {
+        // no MFD_CLOEXEC, execve has to keep it
+        let fd = unsafe { libc::memfd_create(b"turbo-ids\0".as_ptr() as *const libc::c_char, 0) };
+        if fd < 0 {
+            return Err(base::Error::last().errno());
+        }
+        if unsafe { libc::ftruncate(fd, TABLE_SIZE as libc::off_t) } < 0 {
+            let err = base::Error::last().errno();
+            unsafe { libc::close(fd) };
+            return Err(err);
+        }
+        let tbl = IdTable::open(fd)?;
+        tbl.header().next.store(FIRST_PID, Ordering::SeqCst);
+        Ok(tbl)
+    }
+    /// Table a previous emulator (before the guest exec'd) left in `fd`
+    pub fn open(fd: c_int) -> Result<IdTable, c_int> {
+        let base = unsafe {
+            libc::mmap(std::ptr::null_mut(), TABLE_SIZE, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0)
+        };
+        if base == MAP_FAILED {
+            return Err(base::Error::last().errno());
+        }
+        Ok(IdTable { base: base as *mut u8, fd })
+    }
+    pub fn fd(&self) -> c_int {
+        self.fd
+    }
+    fn header(&self) -> &IdHeader {
+        unsafe { &*(self.base as *const IdHeader) }
+    }
+    fn slots(&self) -> &[IdSlot] {
+        unsafe {
+            std::slice::from_raw_parts(self.base.add(size_of::<IdHeader>()) as *const IdSlot, ID_TABLE_SLOTS)
+        }
+    }
+    fn locked<R>(&self, f: impl FnOnce(&[IdSlot]) -> R) -> R {
+        self.header().lock.locked(|| f(self.slots()))
+    }
+    pub fn lookup_virt(&self, host: i32) -> Option<i32> {
+        self.locked(|s| s.iter()
+            .find(|e| e.host.load(Ordering::Relaxed) == host)
+            .map(|e| e.virt.load(Ordering::Relaxed)))
+    }
+    pub fn to_host(&self, virt: i32) -> Option<i32> {
+        self.locked(|s| s.iter()
+            .find(|e| e.host.load(Ordering::Relaxed) != 0 && e.virt.load(Ordering::Relaxed) == virt)
+            .map(|e| e.host.load(Ordering::Relaxed)))
+    }
+    /// Guest id of a host pid/tid, giving it one if it doesn't have one yet
+    pub fn to_virt(&self, host: i32) -> i32 {
+        if host <= 0 {
+            return host;
+        }
+        let next = &self.header().next;
+        self.locked(|s| {
+            if let Some(e) = s.iter().find(|e| e.host.load(Ordering::Relaxed) == host) {
+                return e.virt.load(Ordering::Relaxed);
+            }
+            match s.iter().find(|e| e.host.load(Ordering::Relaxed) == 0) {
+                Some(e) => {
+                    let v = next.fetch_add(1, Ordering::Relaxed);
+                    e.virt.store(v, Ordering::Relaxed);
+                    e.host.store(host, Ordering::Relaxed);
+                    v
+                }
+                None => {
+                    crate::warn_ratelimited!("guest pid table is full, showing host pid {}", host);
+                    host
+                }
+            }
+        })
+    }
+    pub fn insert(&self, host: i32, virt: i32) {
+        self.locked(|s| {
+            let slot = s.iter().find(|e| e.host.load(Ordering::Relaxed) == host)
+                .or_else(|| s.iter().find(|e| e.host.load(Ordering::Relaxed) == 0));
+            if let Some(e) = slot {
+                e.virt.store(virt, Ordering::Relaxed);
+                e.host.store(host, Ordering::Relaxed);
+            }
+        })
+    }
+    /// Process was reaped or thread exited, the host can reuse the id now
+    pub fn remove_host(&self, host: i32) {
+        self.locked(|s| {
+            for e in s.iter().filter(|e| e.host.load(Ordering::Relaxed) == host) {
+                e.host.store(0, Ordering::Relaxed);
+            }
+        })
+    }
+    /// pid argument of kill()/wait4()/setpgid(), where negative values are process groups and
+    /// 0 and -1 mean something special
+    pub fn pid_arg_to_host(&self, pid: i32) -> Result<i32, c_int> {
+        match pid {
+            0 | -1 => Ok(pid),
+            p if p > 0 => self.to_host(p).ok_or(libc::ESRCH),
+            p => self.to_host(-p).map(|h| -h).ok_or(libc::ESRCH),
+        }
+    }
+}
+impl Drop for IdTable {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base as *mut libc::c_void, TABLE_SIZE);
+        }
+    }
+}
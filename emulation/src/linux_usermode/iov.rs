@@ -0,0 +1,158 @@
+// Scatter/gather I/O (readv, writev, sendmsg, recvmsg) straight between guest memory and the host.
+// Guest addresses are host addresses, so the buffers an iovec points at are used as they are and
+// nothing is copied through a bounce buffer. When the guest's struct iovec / struct msghdr is laid
+// out like the host's (64-bit guest, same byte order) even the array and the header are handed to
+// the host as they are, and recvmsg() fills in the guest's msghdr itself.
+// Otherwise (32-bit guests, other byte order) the array and header are converted into host ones
+// first and the fields the kernel writes back are copied out afterwards. The buffers themselves
+// still aren't copied: a guest buffer is never fragmented on the host side, because guest memory
+// is mapped 1:1.
+use std::mem::{offset_of, size_of};
+use std::os::unix::io::RawFd;
+use libc::{c_int, c_void, iovec, msghdr, size_t, socklen_t, EFAULT, EINVAL, EOPNOTSUPP, SCM_RIGHTS, SOL_SOCKET};
+use crate::common::host_guest_endian_mismatch;
+use crate::common::memory::MemEndian;
+use crate::elf::UserModeRuntime;
+use crate::linux_usermode::main::arg_accessible;
+
+/// same as the kernel's UIO_MAXIOV
+pub const IOV_MAX: u64 = 1024;
+// struct msghdr for 32-bit guests: all seven fields are 4 bytes
+const MSGHDR32_SIZE: u64 = 28;
+
+/// Host iovecs for a guest iovec array
+pub enum HostIovecs {
+    /// the guest's own array
+    Guest(*mut iovec, usize),
+    Converted(Vec<iovec>),
+}
+impl HostIovecs {
+    pub fn as_ptr(&mut self) -> *mut iovec {
+        match self {
+            HostIovecs::Guest(p, _) => *p,
+            HostIovecs::Converted(v) => v.as_mut_ptr(),
+        }
+    }
+    pub fn len(&self) -> usize {
+        match self {
+            HostIovecs::Guest(_, n) => *n,
+            HostIovecs::Converted(v) => v.len(),
+        }
+    }
+}
+fn guest_endian(umr: &UserModeRuntime) -> MemEndian {
+    if umr.is_little_endian { MemEndian::Little } else { MemEndian::Big }
+}
+/// Whether guest iovecs and msghdrs can be given to the host untouched
+fn same_layout(umr: &UserModeRuntime) -> bool {
+    umr.is_64 && cfg!(target_pointer_width = "64") && !host_guest_endian_mismatch(guest_endian(umr))
+}
+fn read_word(umr: &mut UserModeRuntime, addr: u64) -> u64 {
+    let endian = guest_endian(umr);
+    if umr.is_64 {
+        umr.mem_access.read_phys_64(addr, endian).unwrap()
+    } else {
+        umr.mem_access.read_phys_32(addr, endian).unwrap() as u64
+    }
+}
+/// The `cnt` guest iovecs at `addr`, EINVAL for more than the kernel takes
+pub fn guest_iovecs(umr: &mut UserModeRuntime, addr: u64, cnt: u64) -> Result<HostIovecs, c_int> {
+    if cnt > IOV_MAX {
+        return Err(EINVAL);
+    }
+    let word = if umr.is_64 { 8 } else { 4 };
+    // readv() and writev() had the array checked by ptrcheck.rs already, a msghdr's hasn't been
+    if !arg_accessible(umr, addr, cnt * 2 * word, false) {
+        return Err(EFAULT);
+    }
+    if same_layout(umr) {
+        return Ok(HostIovecs::Guest(addr as *mut iovec, cnt as usize));
+    }
+    let mut iovs = Vec::with_capacity(cnt as usize);
+    for n in 0..cnt {
+        let at = addr + n * 2 * word;
+        iovs.push(iovec {
+            iov_base: read_word(umr, at) as *mut c_void,
+            iov_len: read_word(umr, at + word) as size_t,
+        });
+    }
+    Ok(HostIovecs::Converted(iovs))
+}
+/// A guest struct msghdr, ready for the host
+pub struct HostMsghdr {
+    guest: u64,
+    /// None when the guest's is used as it is
+    converted: Option<(msghdr, HostIovecs)>,
+}
+impl HostMsghdr {
+    /// The guest's msghdr at `addr`, which recvmsg() (`recv`) writes to as well.
+    /// With --check-pointers the header and its iovec array have to be mapped or it's EFAULT
+    pub fn new(umr: &mut UserModeRuntime, addr: u64, recv: bool) -> Result<HostMsghdr, c_int> {
+        if same_layout(umr) {
+            if !arg_accessible(umr, addr, size_of::<msghdr>() as u64, recv) {
+                return Err(EFAULT);
+            }
+            let iov = read_word(umr, addr + offset_of!(msghdr, msg_iov) as u64);
+            let iovlen = read_word(umr, addr + offset_of!(msghdr, msg_iovlen) as u64);
+            guest_iovecs(umr, iov, iovlen)?;
+            return Ok(HostMsghdr { guest: addr, converted: None });
+        }
+        if umr.is_64 {
+            // a 64-bit guest with the other byte order, nothing runs like that yet
+            return Err(EOPNOTSUPP);
+        }
+        if !arg_accessible(umr, addr, MSGHDR32_SIZE, recv) {
+            return Err(EFAULT);
+        }
+        let f: Vec<u64> = (0..MSGHDR32_SIZE / 4).map(|i| read_word(umr, addr + i * 4)).collect();
+        // control messages are laid out differently too, and nothing converts those yet
+        if f[5] != 0 {
+            return Err(EOPNOTSUPP);
+        }
+        let mut iovs = guest_iovecs(umr, f[2], f[3])?;
+        let mut hdr: msghdr = unsafe { std::mem::zeroed() };
+        hdr.msg_name = f[0] as *mut c_void;
+        hdr.msg_namelen = f[1] as socklen_t;
+        hdr.msg_iov = iovs.as_ptr();
+        hdr.msg_iovlen = iovs.len() as _;
+        hdr.msg_flags = f[6] as c_int;
+        Ok(HostMsghdr { guest: addr, converted: Some((hdr, iovs)) })
+    }
+    pub fn as_ptr(&mut self) -> *mut msghdr {
+        match &mut self.converted {
+            Some((hdr, _)) => hdr,
+            None => self.guest as *mut msghdr,
+        }
+    }
+    /// After recvmsg(): the name length and flags the kernel set go back into the guest's msghdr
+    pub fn copy_back(&self, umr: &mut UserModeRuntime) {
+        if let Some((hdr, _)) = &self.converted {
+            let endian = guest_endian(umr);
+            let mem = &mut umr.mem_access;
+            mem.write_phys_32(self.guest + 4, hdr.msg_namelen, endian).unwrap();
+            mem.write_phys_32(self.guest + 20, 0, endian).unwrap();
+            mem.write_phys_32(self.guest + 24, hdr.msg_flags as u32, endian).unwrap();
+        }
+    }
+    /// After recvmsg(): the fds the kernel installed from SCM_RIGHTS messages
+    pub fn received_fds(&self) -> Vec<RawFd> {
+        // converted headers never carry control messages
+        if self.converted.is_some() {
+            return vec![];
+        }
+        let hdr = self.guest as *const msghdr;
+        let mut fds = vec![];
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == SOL_SOCKET && (*cmsg).cmsg_type == SCM_RIGHTS {
+                    let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                    let n = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize) / size_of::<RawFd>();
+                    fds.extend((0..n).map(|i| data.add(i).read_unaligned()));
+                }
+                cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+            }
+        }
+        fds
+    }
+}
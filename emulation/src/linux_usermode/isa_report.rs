@@ -0,0 +1,246 @@
+// Which RISC-V instructions a run executed and which extensions they need, written as JSON when
+// the guest exits, for checking whether a binary runs on a core that lacks some of them.
+// Each hart counts (pc, instruction word) pairs in a map of its own and hands them over at
+// syscalls and at exit, so nothing is shared while it runs. The words are only decoded (names and
+// extensions) when the report is written: a site is one distinct pc, the count is how many times
+// it ran. Code the run didn't reach can still need more, so the report also has a static half:
+// every instruction in the executable's code sections, found by a linear sweep of the file (data
+// in there shows up as odd instructions, and libraries aren't swept).
+// Like the run summary, only the process the run started in writes it, and a guest execve() of a
+// guest binary hands the destination on to the new emulator.
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use base::warn;
+use rustc_hash::FxHashMap;
+use serde::Serialize;
+use sync::Mutex;
+use goblin::elf::section_header::{SHF_EXECINSTR, SHT_NOBITS};
+use goblin::elf::Elf;
+use crate::elf::UserModeRuntime;
+use crate::riscv::isa_usage::{decoder_name, extension, mnemonic, IsaTarget};
+
+/// sites listed per extension the target lacks
+const MISSING_SITES: usize = 10;
+
+#[derive(Serialize)]
+pub struct IsaReport {
+    pub xlen: u32,
+    /// instructions executed, in total
+    pub instructions: u64,
+    /// distinct instructions (pc and word) executed
+    pub sites: u64,
+    pub extensions: BTreeMap<&'static str, ExtensionUsage>,
+    /// most executed first
+    pub mnemonics: Vec<MnemonicUsage>,
+    /// the --isa-target it was checked against
+    pub target: Option<String>,
+    /// extensions the target lacks that the run used; empty means it'd have run there
+    pub missing: Vec<MissingExtension>,
+    /// what the executable's code holds, whether it ran or not
+    pub static_extensions: BTreeMap<&'static str, StaticUsage>,
+    /// extensions the target lacks that are in the executable's code, the run's or not
+    pub static_missing: Vec<&'static str>,
+}
+#[derive(Serialize, Default)]
+pub struct StaticUsage {
+    /// instructions of this extension in the code
+    pub sites: u64,
+    pub mnemonics: Vec<String>,
+}
+#[derive(Serialize, Default)]
+pub struct ExtensionUsage {
+    pub count: u64,
+    pub sites: u64,
+    pub mnemonics: Vec<String>,
+}
+#[derive(Serialize)]
+pub struct MnemonicUsage {
+    pub name: String,
+    pub extension: &'static str,
+    pub count: u64,
+    pub sites: u64,
+}
+#[derive(Serialize)]
+pub struct MissingExtension {
+    pub extension: &'static str,
+    pub count: u64,
+    /// "function+0x10" of the first few sites
+    pub sites: Vec<String>,
+}
+pub struct IsaRecorder {
+    /// file path, or "-" for stdout
+    dest: String,
+    owner: libc::pid_t,
+    target: Option<IsaTarget>,
+    counts: Mutex<HashMap<(u64, u32), u64>>,
+    written: AtomicBool,
+}
+impl IsaRecorder {
+    pub fn new(dest: String, target: Option<IsaTarget>) -> IsaRecorder {
+        IsaRecorder {
+            dest,
+            owner: unsafe { libc::getpid() },
+            target,
+            counts: Mutex::new(HashMap::new()),
+            written: AtomicBool::new(false),
+        }
+    }
+    /// Adds a hart's counts and empties its map
+    pub fn merge(&self, local: &mut FxHashMap<(u64, u32), u64>) {
+        if local.is_empty() {
+            return;
+        }
+        let mut counts = self.counts.lock();
+        for (k, n) in local.drain() {
+            *counts.entry(k).or_insert(0) += n;
+        }
+    }
+    // (name, extension) of a word
+    fn classify(ume: &UserModeRuntime, word: u32) -> (String, &'static str) {
+        // custom handlers get the first look, like when it ran
+        let custom = word & 3 == 3 && ume.opts.riscv_custom.as_ref().map_or(false, |c| c.decodes(word));
+        match decoder_name(word, ume.is_64) {
+            _ if custom => (format!("custom {:#010x}", word), "custom"),
+            Some(name) => (mnemonic(name), extension(name)),
+            None => (format!("unknown {:#010x}", word), "unknown"),
+        }
+    }
+    /// `text` is what static_text() found in the executable
+    pub(crate) fn build(&self, ume: &UserModeRuntime, text: &[(u64, u32)]) -> IsaReport {
+        let counts = self.counts.lock();
+        let mut names: HashMap<u32, (String, &'static str)> = HashMap::new();
+        let mut mnemonics: BTreeMap<(String, &'static str), (u64, u64)> = BTreeMap::new();
+        let mut extensions: BTreeMap<&'static str, ExtensionUsage> = BTreeMap::new();
+        let mut ext_sites: BTreeMap<&'static str, Vec<(u64, u64)>> = BTreeMap::new();
+        let mut instructions = 0;
+        for (&(pc, word), &n) in counts.iter() {
+            let (name, ext) = names.entry(word).or_insert_with(|| Self::classify(ume, word)).clone();
+            instructions += n;
+            let e = extensions.entry(ext).or_default();
+            e.count += n;
+            e.sites += 1;
+            if !e.mnemonics.contains(&name) {
+                e.mnemonics.push(name.clone());
+            }
+            let m = mnemonics.entry((name, ext)).or_insert((0, 0));
+            m.0 += n;
+            m.1 += 1;
+            ext_sites.entry(ext).or_default().push((pc, n));
+        }
+        for e in extensions.values_mut() {
+            e.mnemonics.sort();
+        }
+        let mut mnemonics: Vec<MnemonicUsage> = mnemonics.into_iter().map(|((name, extension), (count, sites))| {
+            MnemonicUsage { name, extension, count, sites }
+        }).collect();
+        mnemonics.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+        let mut static_extensions: BTreeMap<&'static str, StaticUsage> = BTreeMap::new();
+        for &(_, word) in text {
+            let (name, ext) = names.entry(word).or_insert_with(|| Self::classify(ume, word)).clone();
+            let e = static_extensions.entry(ext).or_default();
+            e.sites += 1;
+            if !e.mnemonics.contains(&name) {
+                e.mnemonics.push(name);
+            }
+        }
+        for e in static_extensions.values_mut() {
+            e.mnemonics.sort();
+        }
+        let static_missing = match &self.target {
+            Some(t) => static_extensions.keys().filter(|ext| !t.has(ext)).copied().collect(),
+            None => vec![],
+        };
+        let missing = match &self.target {
+            Some(t) => extensions.iter().filter(|(ext, _)| !t.has(ext)).map(|(ext, u)| {
+                let mut sites = ext_sites.remove(ext).unwrap_or_default();
+                sites.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+                MissingExtension {
+                    extension: *ext,
+                    count: u.count,
+                    sites: sites.iter().take(MISSING_SITES).map(|(pc, _)| ume.describe_pc(*pc)).collect(),
+                }
+            }).collect(),
+            None => vec![],
+        };
+        IsaReport {
+            xlen: if ume.is_64 { 64 } else { 32 },
+            instructions,
+            sites: counts.len() as u64,
+            extensions,
+            mnemonics,
+            target: self.target.as_ref().map(|t| t.spec.clone()),
+            missing,
+            static_extensions,
+            static_missing,
+        }
+    }
+    /// Write the report, if this is the process that should and it hasn't yet
+    pub fn finish(&self, ume: &UserModeRuntime) {
+        if unsafe { libc::getpid() } != self.owner || self.written.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let text = {
+            let iv = ume.initvars.lock();
+            match iv.obj_idx.map(|i| &iv.objects[i]) {
+                Some(exe) => match std::fs::read(&exe.path) {
+                    Ok(data) => static_text(&data, exe.base as u64),
+                    Err(e) => {
+                        warn!("Couldn't read {} for the ISA usage report: {}", exe.path.display(), e);
+                        vec![]
+                    }
+                },
+                None => vec![],
+            }
+        };
+        let json = match serde_json::to_string_pretty(&self.build(ume, &text)) {
+            Ok(j) => j + "\n",
+            Err(e) => {
+                warn!("Couldn't serialize the ISA usage report: {}", e);
+                return;
+            }
+        };
+        let res = if self.dest == "-" {
+            std::io::stdout().lock().write_all(json.as_bytes())
+        } else {
+            std::fs::write(&self.dest, json)
+        };
+        if let Err(e) = res {
+            warn!("Couldn't write the ISA usage report to {}: {}", self.dest, e);
+        }
+    }
+}
+/// (guest address, word) of every instruction in the code sections of the ELF file `data`, loaded
+/// at `base`. Words of compressed instructions are just their low 16 bits, like the counts
+pub(crate) fn static_text(data: &[u8], base: u64) -> Vec<(u64, u32)> {
+    let elf = match Elf::parse(data) {
+        Ok(e) => e,
+        Err(_) => return vec![],
+    };
+    let mut out = vec![];
+    for sh in &elf.section_headers {
+        if sh.sh_flags & SHF_EXECINSTR as u64 == 0 || sh.sh_type == SHT_NOBITS {
+            continue;
+        }
+        let code = match (sh.sh_offset as usize).checked_add(sh.sh_size as usize)
+            .and_then(|end| data.get(sh.sh_offset as usize..end)) {
+            Some(c) => c,
+            None => continue,
+        };
+        let half = |off: usize| code.get(off..off + 2).map(|b| u16::from_le_bytes([b[0], b[1]]) as u32);
+        let mut off = 0;
+        while let Some(lo) = half(off) {
+            let (word, len) = if lo & 3 != 3 {
+                (lo, 2)
+            } else {
+                match half(off + 2) {
+                    Some(hi) => (lo | hi << 16, 4),
+                    None => break,
+                }
+            };
+            out.push((base + sh.sh_addr + off as u64, word));
+            off += len;
+        }
+    }
+    out
+}
@@ -0,0 +1,282 @@
+// Advisory file locking (fcntl record locks, OFD locks and flock()).
+// Locks are passed to the host whenever it can take them, so they also work against other
+// processes. Files the host refuses to lock (ENOLCK, or filesystems without lock support) get
+// them from our own table instead, which is only visible to this guest.
+// Blocking waits return EINTR when a guest signal comes in, host handlers are installed with
+// SA_RESTART when the guest asked for it, so the host side already behaves like the kernel would.
+use std::mem::MaybeUninit;
+use std::time::Duration;
+use libc::{c_int, EAGAIN, EFAULT, EINTR, EINVAL, ENOLCK, EOPNOTSUPP, F_RDLCK, F_UNLCK, F_WRLCK, SEEK_CUR, SEEK_END, SEEK_SET};
+use sync::{Condvar, Mutex};
+use crate::common::memory::{flat_mem, MemEndian};
+use crate::linux_usermode::signals::GuestSignals;
+
+// guest side command numbers, same for every arch we do (asm-generic)
+pub const GUEST_F_GETLK: c_int = 5;
+pub const GUEST_F_SETLK: c_int = 6;
+pub const GUEST_F_SETLKW: c_int = 7;
+pub const GUEST_F_GETLK64: c_int = 12;
+pub const GUEST_F_SETLK64: c_int = 13;
+pub const GUEST_F_SETLKW64: c_int = 14;
+pub const GUEST_F_OFD_GETLK: c_int = 36;
+pub const GUEST_F_OFD_SETLK: c_int = 37;
+pub const GUEST_F_OFD_SETLKW: c_int = 38;
+
+pub fn is_lock_cmd(cmd: c_int) -> bool {
+    matches!(cmd, GUEST_F_GETLK | GUEST_F_SETLK | GUEST_F_SETLKW | GUEST_F_GETLK64
+        | GUEST_F_SETLK64 | GUEST_F_SETLKW64 | GUEST_F_OFD_GETLK | GUEST_F_OFD_SETLK | GUEST_F_OFD_SETLKW)
+}
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GuestFlock {
+    pub l_type: i16,
+    pub l_whence: i16,
+    pub l_start: i64,
+    pub l_len: i64,
+    pub l_pid: i32,
+}
+/// struct flock on 32 bit guests has 32 bit offsets, struct flock64 and every 64 bit
+/// guest use 64 bit ones (at offset 8 in both cases)
+pub fn flock_is_wide(cmd: c_int, is_64: bool) -> bool {
+    is_64 || !matches!(cmd, GUEST_F_GETLK | GUEST_F_SETLK | GUEST_F_SETLKW)
+}
+/// Bytes of the guest's struct flock the lock calls touch, up to and including l_pid
+pub fn guest_flock_len(wide: bool) -> u64 {
+    if wide { 28 } else { 16 }
+}
+/// The guest's struct flock at `addr`, EFAULT if it can't be read
+pub fn read_guest_flock(addr: u64, wide: bool, end: MemEndian) -> Result<GuestFlock, c_int> {
+    let mut fm = flat_mem::new_usermode();
+    let l_type = fm.read_phys_16(addr, end).map_err(|_| EFAULT)? as i16;
+    let l_whence = fm.read_phys_16(addr + 2, end).map_err(|_| EFAULT)? as i16;
+    if wide {
+        Ok(GuestFlock {
+            l_type,
+            l_whence,
+            l_start: fm.read_phys_64(addr + 8, end).map_err(|_| EFAULT)? as i64,
+            l_len: fm.read_phys_64(addr + 16, end).map_err(|_| EFAULT)? as i64,
+            l_pid: fm.read_phys_32(addr + 24, end).map_err(|_| EFAULT)? as i32,
+        })
+    } else {
+        Ok(GuestFlock {
+            l_type,
+            l_whence,
+            l_start: fm.read_phys_32(addr + 4, end).map_err(|_| EFAULT)? as i32 as i64,
+            l_len: fm.read_phys_32(addr + 8, end).map_err(|_| EFAULT)? as i32 as i64,
+            l_pid: fm.read_phys_32(addr + 12, end).map_err(|_| EFAULT)? as i32,
+        })
+    }
+}
+pub fn write_guest_flock(addr: u64, wide: bool, end: MemEndian, fl: GuestFlock) {
+    let mut fm = flat_mem::new_usermode();
+    let _ = fm.write_phys_16(addr, fl.l_type as u16, end);
+    let _ = fm.write_phys_16(addr + 2, fl.l_whence as u16, end);
+    if wide {
+        let _ = fm.write_phys_64(addr + 8, fl.l_start as u64, end);
+        let _ = fm.write_phys_64(addr + 16, fl.l_len as u64, end);
+        let _ = fm.write_phys_32(addr + 24, fl.l_pid as u32, end);
+    } else {
+        let _ = fm.write_phys_32(addr + 4, fl.l_start as u32, end);
+        let _ = fm.write_phys_32(addr + 8, fl.l_len as u32, end);
+        let _ = fm.write_phys_32(addr + 12, fl.l_pid as u32, end);
+    }
+}
+pub fn guest2host_flock(fl: GuestFlock) -> libc::flock {
+    let mut hfl: libc::flock = unsafe { MaybeUninit::zeroed().assume_init() };
+    hfl.l_type = fl.l_type;
+    hfl.l_whence = fl.l_whence;
+    hfl.l_start = fl.l_start as libc::off_t;
+    hfl.l_len = fl.l_len as libc::off_t;
+    hfl.l_pid = fl.l_pid;
+    hfl
+}
+pub fn host2guest_flock(hfl: libc::flock) -> GuestFlock {
+    GuestFlock {
+        l_type: hfl.l_type,
+        l_whence: hfl.l_whence,
+        l_start: hfl.l_start as i64,
+        l_len: hfl.l_len as i64,
+        l_pid: hfl.l_pid,
+    }
+}
+/// Errors that mean the host can't lock this file at all, rather than "someone else has it"
+pub fn host_cant_lock(errno: c_int) -> bool {
+    errno == ENOLCK || errno == EOPNOTSUPP
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct LockEntry {
+    dev: u64,
+    ino: u64,
+    owner: u64,
+    start: u64,
+    end: u64, // exclusive, u64::MAX for "to end of file and beyond"
+    write: bool,
+    pid: i32,
+}
+#[derive(Default)]
+pub struct LockTable {
+    locks: Vec<LockEntry>,
+}
+impl LockTable {
+    fn conflict(&self, dev: u64, ino: u64, owner: u64, start: u64, end: u64, write: bool) -> Option<LockEntry> {
+        self.locks.iter().find(|l| {
+            l.dev == dev && l.ino == ino && l.owner != owner
+                && l.start < end && start < l.end
+                && (l.write || write)
+        }).copied()
+    }
+    /// Drops every range `owner` holds inside [start, end), splitting locks that stick out
+    fn release(&mut self, dev: u64, ino: u64, owner: u64, start: u64, end: u64) {
+        let mut kept = Vec::with_capacity(self.locks.len());
+        for l in self.locks.drain(..) {
+            if l.dev != dev || l.ino != ino || l.owner != owner || l.end <= start || end <= l.start {
+                kept.push(l);
+                continue;
+            }
+            if l.start < start {
+                kept.push(LockEntry { end: start, ..l });
+            }
+            if l.end > end {
+                kept.push(LockEntry { start: end, ..l });
+            }
+        }
+        self.locks = kept;
+    }
+    /// Drops everything `owner` holds, for close() and exit
+    pub fn release_owner(&mut self, owner: u64) {
+        self.locks.retain(|l| l.owner != owner);
+    }
+}
+pub struct LocalLocks {
+    table: Mutex<LockTable>,
+    cv: Condvar,
+}
+impl Default for LocalLocks {
+    fn default() -> Self {
+        LocalLocks {
+            table: Mutex::new(LockTable::default()),
+            cv: Condvar::new(),
+        }
+    }
+}
+/// Range a (absolute) flock covers, as [start, end)
+pub fn flock_range(fd: c_int, fl: &GuestFlock) -> Result<(u64, u64), c_int> {
+    let base: i64 = match fl.l_whence as c_int {
+        SEEK_SET => 0,
+        SEEK_CUR => unsafe { libc::lseek(fd, 0, SEEK_CUR) as i64 },
+        SEEK_END => {
+            let mut st = MaybeUninit::<libc::stat>::zeroed();
+            if unsafe { libc::fstat(fd, st.as_mut_ptr()) } < 0 {
+                return Err(base::Error::last().errno());
+            }
+            unsafe { st.assume_init().st_size as i64 }
+        }
+        _ => return Err(EINVAL),
+    };
+    let start = base.checked_add(fl.l_start).ok_or(EINVAL)?;
+    let (s, e) = if fl.l_len == 0 {
+        (start, i64::MAX)
+    } else if fl.l_len > 0 {
+        (start, start.saturating_add(fl.l_len))
+    } else {
+        (start + fl.l_len, start)
+    };
+    if s < 0 {
+        return Err(EINVAL);
+    }
+    Ok((s as u64, if e == i64::MAX { u64::MAX } else { e as u64 }))
+}
+fn file_id(fd: c_int) -> Result<(u64, u64), c_int> {
+    let mut st = MaybeUninit::<libc::stat>::zeroed();
+    if unsafe { libc::fstat(fd, st.as_mut_ptr()) } < 0 {
+        return Err(base::Error::last().errno());
+    }
+    let st = unsafe { st.assume_init() };
+    Ok((st.st_dev as u64, st.st_ino as u64))
+}
+impl LocalLocks {
+    /// F_GETLK against our own table. Fills in fl like the kernel does
+    pub fn getlk(&self, fd: c_int, owner: u64, fl: &mut GuestFlock) -> Result<(), c_int> {
+        let (dev, ino) = file_id(fd)?;
+        let (start, end) = flock_range(fd, fl)?;
+        let tbl = self.table.lock();
+        match tbl.conflict(dev, ino, owner, start, end, fl.l_type == F_WRLCK as i16) {
+            Some(l) => {
+                fl.l_type = if l.write { F_WRLCK as i16 } else { F_RDLCK as i16 };
+                fl.l_whence = SEEK_SET as i16;
+                fl.l_start = l.start as i64;
+                fl.l_len = if l.end == u64::MAX { 0 } else { (l.end - l.start) as i64 };
+                fl.l_pid = l.pid;
+            }
+            None => fl.l_type = F_UNLCK as i16,
+        }
+        Ok(())
+    }
+    /// F_SETLK(W) against our own table
+    pub fn setlk(&self, fd: c_int, owner: u64, pid: i32, fl: &GuestFlock, wait: bool, sig: &GuestSignals) -> Result<(), c_int> {
+        let (dev, ino) = file_id(fd)?;
+        let (start, end) = flock_range(fd, fl)?;
+        self.set_range(dev, ino, owner, pid, start, end, fl.l_type as c_int, wait, sig)
+    }
+    /// flock(), which is a lock on the whole file
+    pub fn flock(&self, fd: c_int, owner: u64, op: c_int, sig: &GuestSignals) -> Result<(), c_int> {
+        let (dev, ino) = file_id(fd)?;
+        let wait = op & libc::LOCK_NB == 0;
+        let ltype = match op & !libc::LOCK_NB {
+            libc::LOCK_SH => F_RDLCK,
+            libc::LOCK_EX => F_WRLCK,
+            libc::LOCK_UN => F_UNLCK,
+            _ => return Err(EINVAL),
+        };
+        self.set_range(dev, ino, owner, 0, 0, u64::MAX, ltype, wait, sig)
+    }
+    fn set_range(&self, dev: u64, ino: u64, owner: u64, pid: i32, start: u64, end: u64, ltype: c_int, wait: bool, sig: &GuestSignals) -> Result<(), c_int> {
+        let mut tbl = self.table.lock();
+        if ltype == F_UNLCK {
+            tbl.release(dev, ino, owner, start, end);
+            self.cv.notify_all();
+            return Ok(());
+        }
+        if ltype != F_RDLCK && ltype != F_WRLCK {
+            return Err(EINVAL);
+        }
+        let write = ltype == F_WRLCK;
+        while tbl.conflict(dev, ino, owner, start, end, write).is_some() {
+            if !wait {
+                return Err(EAGAIN);
+            }
+            // wake up now and then to notice guest signals
+            tbl = self.cv.wait_timeout(tbl, Duration::from_millis(10)).0;
+            if sig.is_pending() {
+                return Err(EINTR);
+            }
+        }
+        tbl.release(dev, ino, owner, start, end);
+        tbl.locks.push(LockEntry {
+            dev,
+            ino,
+            owner,
+            start,
+            end,
+            write,
+            pid,
+        });
+        Ok(())
+    }
+    pub fn release_owner(&self, owner: u64) {
+        self.table.lock().release_owner(owner);
+        self.cv.notify_all();
+    }
+    /// close() of any fd for a file drops every record lock the process has on it, even the ones
+    /// taken through other fds (that's POSIX for you)
+    pub fn release_file(&self, fd: c_int, owner: u64) {
+        let mut tbl = self.table.lock();
+        if tbl.locks.is_empty() {
+            return;
+        }
+        if let Ok((dev, ino)) = file_id(fd) {
+            tbl.release(dev, ino, owner, 0, u64::MAX);
+            self.cv.notify_all();
+        }
+    }
+}
@@ -3,9 +3,11 @@ use std::{mem, ptr};
 use std::mem::MaybeUninit;
 use std::ops::Add;
 use std::sync::Arc;
-use base::{debug, errno_result, pagesize, sys};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use base::{debug, errno_result, pagesize, sys, warn};
 use base::platform::MemoryMapping;
-use libc::{c_char, c_int, c_void, clock_gettime, clock_settime, clockid_t, close, EINVAL, ENOMEM, faccessat, fcntl, fd_set, fstatat, getuid, geteuid, iovec, lseek, MAP_ANON, MAP_ANONYMOUS, MAP_FAILED, MAP_FIXED, MAP_PRIVATE, MAP_SHARED, mprotect, off_t, open, openat, PROT_EXEC, PROT_READ, PROT_WRITE, read, readv, sigaction, sigset_t, size_t, ssize_t, SYS_exit_group, SYS_set_tid_address, syscall, time_t, timespec, timeval, uname, TCGETS, utsname, write, writev, TIOCGPGRP, TIOCGWINSZ, winsize, ioctl, SOCK_NONBLOCK, socketpair, ppoll, pollfd, c_short, c_long, socket, clone, SYS_clone, CLONE_VM, pipe2, sysinfo, fstat, posix_fadvise64, off64_t, fchown, uid_t, gid_t, mode_t, fchmod, utimensat, SYS_lookup_dcookie, dup3, O_CLOEXEC, getgid, setgid, setuid, sendfile, bind, sockaddr, socklen_t, sendto, recvfrom, ITIMER_REAL, itimerval, SYS_setitimer, SYS_getitimer, connect, listen, ftruncate, getpid, getppid, pid_t, getpgid, getsid, kill, SYS_getdents64, dirent64, truncate, statx, c_uint, F_SETLK, F_GETFL, F_SETFL, F_GETFD, F_SETFD, rlimit, getrlimit, __rlimit_resource_t, readlink, getrandom, prlimit64, rlimit64, readlinkat, SYS_futex, termios, ETIMEDOUT, sched_getaffinity, cpu_set_t, mkdirat, CLONE_THREAD, CLONE_FS, CLONE_FILES, CLONE_CHILD_SETTID, CLONE_CHILD_CLEARTID, SIGCHLD, CLONE_VFORK, nanosleep, clock_nanosleep, exit, SYS_exit, getpriority, __priority_which_t, id_t, setpriority, AT_SYMLINK_NOFOLLOW, getcwd, chdir, fchdir, unlinkat, SYS_capget, SYS_capset, setpgid, wait4, rusage, EFAULT, clock_getres, prctl, c_ulong};
+use libc::{c_char, c_int, c_void, clock_gettime, clock_settime, clockid_t, close, EINVAL, ENOMEM, faccessat, fcntl, fd_set, fstatat, getuid, geteuid, iovec, lseek, MAP_ANON, MAP_ANONYMOUS, MAP_FAILED, MAP_FIXED, MAP_PRIVATE, MAP_SHARED, mprotect, off_t, open, openat, PROT_EXEC, PROT_READ, PROT_WRITE, read, readv, sigaction, sigset_t, size_t, ssize_t, SYS_set_tid_address, syscall, time_t, timespec, timeval, uname, TCGETS, TCSETS, TCSETSW, TCSETSF, TIOCSWINSZ, TIOCSPGRP, TIOCSCTTY, TIOCNOTTY, TIOCGPTN, TIOCSPTLCK, FIONREAD, TCFLSH, TCXONC, TCSBRK, utsname, write, writev, TIOCGPGRP, TIOCGWINSZ, winsize, ioctl, SOCK_NONBLOCK, socketpair, ppoll, pollfd, c_short, c_long, socket, clone, SYS_clone, CLONE_VM, pipe2, sysinfo, fstat, posix_fadvise64, off64_t, fchown, uid_t, gid_t, mode_t, fchmod, utimensat, SYS_lookup_dcookie, dup3, O_CLOEXEC, getgid, setgid, setuid, sendfile, bind, sockaddr, socklen_t, sendto, recvfrom, ITIMER_REAL, itimerval, SYS_setitimer, SYS_getitimer, connect, listen, ftruncate, getpid, getppid, pid_t, getpgid, getsid, kill, SYS_getdents64, dirent64, truncate, statx, c_uint, F_SETLK, F_GETFL, F_SETFL, F_GETFD, F_SETFD, rlimit, getrlimit, __rlimit_resource_t, readlink, getrandom, prlimit64, rlimit64, readlinkat, SYS_futex, termios, ETIMEDOUT, sched_getaffinity, cpu_set_t, mkdirat, CLONE_THREAD, CLONE_FS, CLONE_FILES, CLONE_CHILD_SETTID, CLONE_CHILD_CLEARTID, SIGCHLD, CLONE_VFORK, nanosleep, clock_nanosleep, exit, getpriority, __priority_which_t, id_t, setpriority, AT_SYMLINK_NOFOLLOW, AT_FDCWD, getcwd, chdir, fchdir, unlinkat, SYS_capget, SYS_capset, setpgid, wait4, rusage, EFAULT, clock_getres, prctl, c_ulong, EAGAIN, ENOSYS, FUTEX_WAIT, FUTEX_WAKE, FUTEX_REQUEUE, FUTEX_CMP_REQUEUE, FUTEX_WAIT_BITSET, FUTEX_WAKE_BITSET, FUTEX_PRIVATE_FLAG, FUTEX_CLOCK_REALTIME, ENOEXEC, SIOCGIFHWADDR, sendmsg, recvmsg};
 use crate::common::genfunc::round_up;
 use crate::elf::{MachineType, UserModeRuntime};
 use libc::mmap;
@@ -13,10 +15,24 @@ use resources::{AddressRange, Alloc};
 use sync::Mutex;
 use crate::common::{host_guest_endian_mismatch, IS_LITTLE_ENDIAN};
 use crate::common::memory::MemEndian;
-use crate::linux_usermode::defs::{GenericStat, plat2generic_stat};
-use crate::linux_usermode::signals::{block_all_signals, GenericSigactionArg, GenericStackt, SigEntry, SigInfo, Sigmask, SINFO, u_sigaction};
+use crate::linux_usermode::defs::{GenericStat, plat2generic_stat, SigConstants, TIOCGPTPEER, TIOCGSID};
+use crate::linux_usermode::sched::SchedEvent;
+use crate::linux_usermode::fdpass::FdVerdict;
+use crate::linux_usermode::ptrcheck::check_syscall_pointers;
+use crate::linux_usermode::iov::{guest_iovecs, HostMsghdr};
+use crate::linux_usermode::locks::{flock_is_wide, guest_flock_len, GUEST_F_GETLK, GUEST_F_GETLK64, GUEST_F_OFD_GETLK, GUEST_F_OFD_SETLK, GUEST_F_OFD_SETLKW, GUEST_F_SETLK, GUEST_F_SETLK64, GUEST_F_SETLKW, GUEST_F_SETLKW64, guest2host_flock, host2guest_flock, host_cant_lock, is_lock_cmd, read_guest_flock, write_guest_flock};
+use crate::linux_usermode::fakeroot::{FakeRoot, FileRef};
+use crate::linux_usermode::cputime::{insn_resolution_ns, insns_to_ns};
+use crate::linux_usermode::throttle::fd_class;
+use crate::linux_usermode::stack::{check_stack, guest_endian, word_size, StackError};
+use crate::linux_usermode::memusage::{MapFlags, MemLimit, OomPolicy, VmaKind};
+use crate::linux_usermode::summary::{RunExit, TrapKind};
+use crate::linux_usermode::exec::{elf_machine, host_execve, is_our_machine, is_proc_exe_link, read_guest_strv, read_shebang};
+use crate::linux_usermode::vfs::{overlay_remove, open_intent, PathIntent, resolve_guest_path};
+use crate::linux_usermode::elfcheck::{elf_target, read_header};
+use crate::linux_usermode::signals::{block_all_signals, die_of_signal, fatal_signal, GenericSigactionArg, GenericStackt, set_mask_block, SigEntry, SigInfo, Sigmask, u_sigaction, u_sigaltstack};
 
-#[derive(Copy, Clone, PartialEq, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub enum SyscallType {
     Access,
     Brk,
@@ -41,8 +57,11 @@ pub enum SyscallType {
     Readv,
     Sigaction,
     Lseek,
+    Llseek,
     ClockGetTime,
     ClockSetTime,
+    ClockGetTime64,
+    ClockSetTime64,
     Getuid,
     Geteuid,
     Ioctl,
@@ -60,6 +79,7 @@ pub enum SyscallType {
     Fchmod,
     Utimensat,
     LookupDcookie,
+    Dup,
     Dup3,
     Getgid,
     Setuid,
@@ -68,6 +88,8 @@ pub enum SyscallType {
     Bind,
     Sendto,
     Recvfrom,
+    Sendmsg,
+    Recvmsg,
     Setitimer,
     Getitimer,
     Connect,
@@ -79,6 +101,8 @@ pub enum SyscallType {
     Getpgid,
     Getsid,
     Kill,
+    Tkill,
+    Tgkill,
     Getdents64,
     ArmSetTls,
     Mmap2,
@@ -90,12 +114,15 @@ pub enum SyscallType {
     Readlinkat,
     Getrandom,
     Futex,
+    Futex64,
     Gettid,
     Getaffinity,
     Sigaltstack,
+    RtSigreturn,
     Mkdirat,
     Nanosleep,
     ClockNanosleep,
+    ClockNanosleep64,
     Madvise,
     Exit,
     Getpriority,
@@ -111,7 +138,14 @@ pub enum SyscallType {
     Setpgid,
     Wait4,
     Getres,
-    Prctl
+    Getres64,
+    Prctl,
+    Flock,
+    Setsid,
+    Execve,
+    Mknodat,
+    Getrusage,
+    Times,
 
 }
 #[derive(Copy, Clone, PartialEq)]
@@ -126,21 +160,46 @@ pub struct SyscallOut {
     pub is_error: bool,
 
 }
-fn fix_path(path: &str, ptr: *const c_char) -> String {
-    let oldpath = unsafe {
-        CStr::from_ptr(ptr).to_string_lossy().clone().to_string()
-    };
-    let mut newstr: String = String::new();
-    if oldpath.starts_with("/etc")
-        || oldpath.starts_with("/usr")
-        || oldpath.starts_with("/var")
-        || oldpath.starts_with("/lib")
-        || oldpath.starts_with("/sbin") {
-        newstr.push_str(path);
+/// Guest path to host path: sysroot directories get the sysroot prepended, and then whatever
+/// the filesystem mode (read-only, overlay) does to it
+fn guest_path(umr: &UserModeRuntime, dirfd: c_int, ptr: u64, intent: PathIntent) -> Result<CString, c_int> {
+    resolve_guest_path(&umr.opts.fs_mode, umr.str_path.as_str(), umr.opts.rootfs, umr.opts.identity.as_deref(), dirfd,
+                       ptr as *const c_char, intent)
+}
+/// Host pid/tid as the guest sees it
+pub fn guest_pid(umr: &UserModeRuntime, host: pid_t) -> pid_t {
+    match &umr.ids {
+        Some(t) => t.to_virt(host),
+        None => host,
+    }
+}
+/// Guest pid argument (negative for process groups) to the host one
+fn host_pid_arg(umr: &UserModeRuntime, pid: pid_t) -> Result<pid_t, c_int> {
+    match &umr.ids {
+        Some(t) => t.pid_arg_to_host(pid),
+        None => Ok(pid),
+    }
+}
+/// With --check-pointers, whether guest memory behind a pointer ptrcheck.rs can't know about
+/// (its meaning depends on another argument) is mapped
+pub(crate) fn arg_accessible(umr: &UserModeRuntime, addr: u64, len: u64, write: bool) -> bool {
+    !umr.opts.check_pointers || umr.memusage.accessible(addr, len, write)
+}
+/// What a wait a guest signal cut short returns. It never reaches the guest: the arch code makes
+/// it EINTR, or restarts the syscall once the signal is taken care of when
+/// GuestSignals::restarts_syscall() says so, like the kernel does with its ERESTARTSYS
+pub(crate) const ERESTARTSYS: c_int = 512;
+impl SyscallOut {
+    pub(crate) fn is_restart(&self) -> bool {
+        self.is_error && self.ret1 == -ERESTARTSYS as i64 as u64
+    }
+}
+pub(crate) fn errno_out(err: c_int) -> SyscallOut {
+    SyscallOut {
+        ret1: -err as i64 as u64,
+        ret2: None,
+        is_error: true,
     }
-    newstr.push_str(oldpath.as_str());
-    newstr
-
 }
 fn generic_error_handle_maxarch_int(sysout: &mut SyscallOut, res: i64, is_64: bool) {
     // if the value return from a syscall is the highest size int, this is the function to handle
@@ -171,7 +230,10 @@ pub fn u_faccess_at(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
     let amode = sysin.args[2];
     let flags = sysin.args[3];
     let mut sout: SyscallOut = Default::default();
-    let newpath = CString::new(fix_path(umr.str_path.as_str(), path)).unwrap();
+    let newpath = match guest_path(umr, fd as c_int, path as u64, PathIntent::Read) {
+        Ok(p) => p,
+        Err(e) => return errno_out(e),
+    };
     let res = unsafe {
         faccessat(fd as c_int, newpath.as_ptr(), amode as c_int, flags as c_int)
     };
@@ -182,11 +244,21 @@ pub fn u_unlinkat(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
     let fd = sysin.args[0];
     let pathname = sysin.args[1];
     let flags = sysin.args[2];
+    if pathname != 0 {
+        if let Some(r) = overlay_remove(&umr.opts.fs_mode, umr.str_path.as_str(), umr.opts.rootfs, fd as c_int,
+                                        pathname as *const c_char, flags as c_int) {
+            return match r {
+                Ok(()) => Default::default(),
+                Err(e) => errno_out(e),
+            };
+        }
+    }
     let mut newpath = CString::new("").unwrap();
     let finalptr: *const c_char = if pathname != 0 {
-        newpath = CString::new(
-            fix_path(umr.str_path.as_str(), pathname as *const c_char)
-        ).unwrap();
+        newpath = match guest_path(umr, fd as c_int, pathname, PathIntent::Read) {
+            Ok(p) => p,
+            Err(e) => return errno_out(e),
+        };
         newpath.as_ptr()
     } else {
         ptr::null_mut()
@@ -203,14 +275,48 @@ pub fn u_wait4(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
     let wstatus = sysin.args[1];
     let options = sysin.args[2];
     let rusage = sysin.args[3];
+    let pid = match host_pid_arg(umr, pid as pid_t) {
+        Ok(p) => p,
+        Err(e) => return errno_out(e),
+    };
+    let mut status: c_int = 0;
+    let mut ru: rusage = unsafe { mem::zeroed() };
     let res = unsafe {
-        wait4(pid as c_int, wstatus as *mut c_int,
-              options as c_int, rusage as *mut rusage)
+        wait4(pid, &mut status,
+              options as c_int, &mut ru)
     };
     let mut sysout: SyscallOut = Default::default();
     generic_error_handle(&mut sysout, res);
+    if res > 0 {
+        sysout.ret1 = guest_pid(umr, res) as u64;
+        if libc::WIFEXITED(status) || libc::WIFSIGNALED(status) {
+            if let Some(t) = &umr.ids {
+                t.remove_host(res);
+            }
+        }
+    }
+    if res > 0 && wstatus != 0 {
+        let endian = if umr.is_little_endian { MemEndian::Little } else { MemEndian::Big };
+        let gstatus = host2guest_wait_status(status, &umr.sigcnst.lock());
+        umr.mem_access.write_phys_32(wstatus, gstatus as u32, endian);
+    }
+    if res > 0 && rusage != 0 {
+        // a child is an emulator of its own, only the host knows its numbers
+        write_guest_rusage(umr, rusage, &ru, None);
+    }
     sysout
 }
+/// The layout of a wait status is the same everywhere, but signal numbers in it are not
+pub(crate) fn host2guest_wait_status(status: c_int, cnsts: &SigConstants) -> c_int {
+    let sig = |s: c_int| cnsts.host_to_guest_sigs[s as usize];
+    if libc::WIFSIGNALED(status) {
+        (status & !0x7f) | sig(libc::WTERMSIG(status))
+    } else if libc::WIFSTOPPED(status) {
+        (status & !0xff00) | (sig(libc::WSTOPSIG(status)) << 8)
+    } else {
+        status
+    }
+}
 pub fn u_clock_getres(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
     let cid = sysin.args[0];
     let tres = sysin.args[1];
@@ -219,17 +325,177 @@ pub fn u_clock_getres(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut
     };
     let mut sysout: SyscallOut = Default::default();
     generic_error_handle(&mut sysout, res);
+    if res == 0 && tres != 0 && cputime_clock_ns(umr, cid as clockid_t).is_some() {
+        let wide = wide_timespec(&sysin, umr);
+        write_guest_timespec(umr, tres, insn_resolution_ns(umr.opts.guest_mhz), wide);
+    }
     sysout
 }
+/// Publishes how many instructions this thread has retired, for the CPU time syscalls
+pub(crate) fn publish_cpu_time(ume: &mut UserModeRuntime, icount: u64) {
+    if !matches!(&ume.cpu_slot, Some(s) if s.tid() == ume.tid_val) {
+        // new thread or forked child
+        ume.cpu_slot = Some(ume.cputime.register(ume.tid_val));
+    }
+    ume.cpu_slot.as_ref().unwrap().set(icount.saturating_sub(ume.icount_base));
+}
+/// Guest CPU time in ns, None when the cpu doesn't count instructions
+fn guest_cpu_ns(ume: &UserModeRuntime, thread_only: bool) -> Option<u64> {
+    let slot = ume.cpu_slot.as_ref()?;
+    let insns = if thread_only { slot.get() } else { ume.cputime.process_insns() };
+    Some(insns_to_ns(insns, ume.opts.guest_mhz))
+}
+fn cputime_clock_ns(ume: &UserModeRuntime, clk: clockid_t) -> Option<u64> {
+    match clk {
+        libc::CLOCK_PROCESS_CPUTIME_ID => guest_cpu_ns(ume, false),
+        libc::CLOCK_THREAD_CPUTIME_ID => guest_cpu_ns(ume, true),
+        _ => None,
+    }
+}
+/// Whether the struct timespec of this call has 64 bit fields: always on 64 bit guests, only for
+/// the *_time64 syscalls on 32 bit ones
+fn wide_timespec(sysin: &SyscallIn, ume: &UserModeRuntime) -> bool {
+    ume.is_64 || matches!(sysin.syscall, SyscallType::ClockGetTime64 | SyscallType::ClockSetTime64
+        | SyscallType::Getres64 | SyscallType::ClockNanosleep64 | SyscallType::Futex64)
+}
+fn write_guest_timespec(ume: &mut UserModeRuntime, addr: u64, ns: u64, wide: bool) {
+    let endian = if ume.is_little_endian { MemEndian::Little } else { MemEndian::Big };
+    if wide {
+        ume.mem_access.write_phys_64(addr, ns / 1_000_000_000, endian);
+        ume.mem_access.write_phys_64(addr + 8, ns % 1_000_000_000, endian);
+    } else {
+        ume.mem_access.write_phys_32(addr, (ns / 1_000_000_000) as u32, endian);
+        ume.mem_access.write_phys_32(addr + 4, (ns % 1_000_000_000) as u32, endian);
+    }
+}
+/// timeval, returns its size
+fn write_guest_timeval(ume: &mut UserModeRuntime, addr: u64, ns: u64) -> u64 {
+    let endian = if ume.is_little_endian { MemEndian::Little } else { MemEndian::Big };
+    let usec = (ns / 1000) % 1_000_000;
+    if ume.is_64 {
+        ume.mem_access.write_phys_64(addr, ns / 1_000_000_000, endian);
+        ume.mem_access.write_phys_64(addr + 8, usec, endian);
+        16
+    } else {
+        ume.mem_access.write_phys_32(addr, (ns / 1_000_000_000) as u32, endian);
+        ume.mem_access.write_phys_32(addr + 4, usec as u32, endian);
+        8
+    }
+}
+/// struct rusage in the guest's layout: two timevals and 14 longs, so 72 bytes on 32 bit guests.
+/// The user time is `cpu_ns` when we have it (and system time 0), the host's otherwise
+fn write_guest_rusage(ume: &mut UserModeRuntime, addr: u64, ru: &rusage, cpu_ns: Option<u64>) {
+    let endian = if ume.is_little_endian { MemEndian::Little } else { MemEndian::Big };
+    let tv_ns = |tv: &timeval| tv.tv_sec as u64 * 1_000_000_000 + tv.tv_usec as u64 * 1000;
+    let (utime, stime) = match cpu_ns {
+        Some(ns) => (ns, 0),
+        None => (tv_ns(&ru.ru_utime), tv_ns(&ru.ru_stime)),
+    };
+    let mut at = addr;
+    at += write_guest_timeval(ume, at, utime);
+    at += write_guest_timeval(ume, at, stime);
+    let longs = [ru.ru_maxrss, ru.ru_ixrss, ru.ru_idrss, ru.ru_isrss, ru.ru_minflt, ru.ru_majflt, ru.ru_nswap,
+        ru.ru_inblock, ru.ru_oublock, ru.ru_msgsnd, ru.ru_msgrcv, ru.ru_nsignals, ru.ru_nvcsw, ru.ru_nivcsw];
+    for v in longs {
+        if ume.is_64 {
+            ume.mem_access.write_phys_64(at, v as u64, endian);
+            at += 8;
+        } else {
+            ume.mem_access.write_phys_32(at, v as u32, endian);
+            at += 4;
+        }
+    }
+}
+pub fn u_getrusage(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
+    let who = sysin.args[0] as c_int;
+    let usage = sysin.args[1];
+    if usage == 0 {
+        return errno_out(EFAULT);
+    }
+    let mut sout: SyscallOut = Default::default();
+    // host fills in everything else (page faults, context switches...)
+    let mut ru: rusage = unsafe { mem::zeroed() };
+    let res = unsafe {
+        libc::getrusage(who as libc::__rusage_who_t, &mut ru)
+    };
+    generic_error_handle(&mut sout, res);
+    if res < 0 {
+        return sout;
+    }
+    let ns = match who {
+        libc::RUSAGE_SELF => guest_cpu_ns(umr, false),
+        libc::RUSAGE_THREAD => guest_cpu_ns(umr, true),
+        _ => None, // children
+    };
+    write_guest_rusage(umr, usage, &ru, ns);
+    sout
+}
+pub fn u_times(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
+    let buf = sysin.args[0];
+    let mut t: libc::tms = unsafe { mem::zeroed() };
+    let ret = unsafe {
+        libc::times(&mut t)
+    };
+    if ret == -1 as libc::clock_t {
+        return errno_out(base::Error::last().errno());
+    }
+    if buf != 0 {
+        let endian = if umr.is_little_endian { MemEndian::Little } else { MemEndian::Big };
+        let hz = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as u64;
+        let (utime, stime) = match guest_cpu_ns(umr, false) {
+            Some(ns) => ((ns as u128 * hz as u128 / 1_000_000_000) as u64, 0),
+            None => (t.tms_utime as u64, t.tms_stime as u64),
+        };
+        let vals = [utime, stime, t.tms_cutime as u64, t.tms_cstime as u64];
+        let width = if umr.is_64 { 8 } else { 4 };
+        for (i, v) in vals.iter().enumerate() {
+            let addr = buf + i as u64 * width;
+            if umr.is_64 {
+                umr.mem_access.write_phys_64(addr, *v, endian);
+            } else {
+                umr.mem_access.write_phys_32(addr, *v as u32, endian);
+            }
+        }
+    }
+    SyscallOut {
+        ret1: ret as u64,
+        ..Default::default()
+    }
+}
+pub fn u_mknodat(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
+    let fd = sysin.args[0];
+    let pathname = sysin.args[1];
+    let mode = sysin.args[2] as mode_t;
+    let dev = sysin.args[3];
+    let newpath = match guest_path(umr, fd as c_int, pathname, PathIntent::Create) {
+        Ok(p) => p,
+        Err(e) => return errno_out(e),
+    };
+    let mut sout: SyscallOut = Default::default();
+    if let Some(fr) = &umr.fakeroot {
+        if FakeRoot::is_fake_node(mode) {
+            return match fr.mknod(fd as c_int, &newpath, mode, dev) {
+                Ok(()) => sout,
+                Err(e) => errno_out(e),
+            };
+        }
+    }
+    let res = unsafe {
+        libc::mknodat(fd as c_int, newpath.as_ptr(), mode, dev as libc::dev_t)
+    };
+    generic_error_handle(&mut sout, res);
+    sout
+}
 pub fn u_mkdirat(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
     let fd = sysin.args[0];
     let pathname = sysin.args[1];
     let mode = sysin.args[2];
     let mut newpath = CString::new("").unwrap();
     let finalptr: *const c_char = if pathname != 0 {
-        newpath = CString::new(
-            fix_path(umr.str_path.as_str(), pathname as *const c_char)
-        ).unwrap();
+        newpath = match guest_path(umr, fd as c_int, pathname, PathIntent::Create) {
+            Ok(p) => p,
+            Err(e) => return errno_out(e),
+        };
         newpath.as_ptr()
     } else {
         ptr::null_mut()
@@ -245,6 +511,12 @@ pub fn u_fchown(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
     let fd = sysin.args[0];
     let owner = sysin.args[1];
     let group = sysin.args[2];
+    if let Some(fr) = &umr.fakeroot {
+        return match fr.chown(&FileRef::fd(fd as c_int), owner as u32, group as u32) {
+            Ok(()) => Default::default(),
+            Err(e) => errno_out(e),
+        };
+    }
     let res = unsafe {
         fchown(fd as c_int, owner as uid_t, group as gid_t)
     };
@@ -260,6 +532,9 @@ pub fn u_futex(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
     let timeout = sysin.args[3];
     let uaddr2 = sysin.args[4];
     let val3 = sysin.args[5];
+    if umr.sched.is_some() {
+        return u_futex_det(sysin, umr);
+    }
     if val == 2 && umr.machine_type == MachineType::Arm64 {
         // glibc sometimes calls this even in single threaded program
         let changeval: *mut u32 = fduaddr as *mut u32;
@@ -275,6 +550,92 @@ pub fn u_futex(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
     generic_error_handle_maxarch_int(&mut sysout, res, true);
     sysout
 }
+// With deterministic scheduling there is only one host thread, so a real FUTEX_WAIT would hang
+// the whole guest. Waiters are parked in the scheduler instead. The pooled scheduler's workers
+// run threads at the same time, so the value is compared with the scheduler locked, same as the
+// kernel does with the futex bucket, or a wakeup could slip in between.
+fn u_futex_det(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
+    let uaddr = sysin.args[0];
+    let realtime = (sysin.args[1] as c_int) & FUTEX_CLOCK_REALTIME != 0;
+    let op = (sysin.args[1] as c_int) & !(FUTEX_PRIVATE_FLAG | FUTEX_CLOCK_REALTIME);
+    let val = sysin.args[2] as u32;
+    let timeout = sysin.args[3]; // val2 for the requeue operations
+    let uaddr2 = sysin.args[4];
+    let val3 = sysin.args[5] as u32;
+    let endian = if umr.is_little_endian { MemEndian::Little } else { MemEndian::Big };
+    let sched = umr.sched.clone().unwrap();
+    let mut sysout: SyscallOut = Default::default();
+    match op {
+        FUTEX_WAIT | FUTEX_WAIT_BITSET => {
+            let bitset = if op == FUTEX_WAIT { u32::MAX } else { val3 };
+            // FUTEX_WAIT's timeout is relative, FUTEX_WAIT_BITSET's a point in time
+            let clock = if realtime { libc::CLOCK_REALTIME } else { libc::CLOCK_MONOTONIC };
+            let wide = wide_timespec(&sysin, umr);
+            let timeout = match futex_timeout(umr, timeout, wide, op == FUTEX_WAIT_BITSET, clock) {
+                Ok(t) => t,
+                Err(e) => return errno_out(e),
+            };
+            let mut st = sched.lock();
+            match umr.mem_access.read_phys_32(uaddr, endian) {
+                Ok(cur) if cur == val => {}
+                Ok(_) => return errno_out(EAGAIN),
+                Err(_) => return errno_out(EFAULT),
+            }
+            st.futex_wait(umr.tid_val, uaddr, bitset, timeout);
+            umr.sched_event = Some(SchedEvent::FutexWait);
+        }
+        FUTEX_WAKE | FUTEX_WAKE_BITSET => {
+            let bitset = if op == FUTEX_WAKE { u32::MAX } else { val3 };
+            sysout.ret1 = sched.lock().futex_wake(uaddr, val as u64, bitset);
+        }
+        FUTEX_REQUEUE | FUTEX_CMP_REQUEUE => {
+            let mut st = sched.lock();
+            if op == FUTEX_CMP_REQUEUE {
+                match umr.mem_access.read_phys_32(uaddr, endian) {
+                    Ok(cur) if cur == val3 => {}
+                    Ok(_) => return errno_out(EAGAIN),
+                    Err(_) => return errno_out(EFAULT),
+                }
+            }
+            sysout.ret1 = st.futex_requeue(uaddr, val as u64, uaddr2, timeout);
+        }
+        _ => {
+            debug!("futex op {:x} is not supported with deterministic scheduling", op);
+            sysout.is_error = true;
+            sysout.ret1 = -ENOSYS as i64 as u64;
+        }
+    }
+    sysout
+}
+/// How long a futex wait with this timespec can sleep, None for no timeout. The pointer is only
+/// known to be one for the wait operations, so ptrcheck.rs leaves it to us
+fn futex_timeout(umr: &mut UserModeRuntime, addr: u64, wide: bool, absolute: bool, clock: clockid_t) -> Result<Option<Duration>, c_int> {
+    if addr == 0 {
+        return Ok(None);
+    }
+    let len = if wide { 16 } else { 8 };
+    if umr.opts.check_pointers && !umr.memusage.accessible(addr, len, false) {
+        return Err(EFAULT);
+    }
+    let endian = if umr.is_little_endian { MemEndian::Little } else { MemEndian::Big };
+    let (sec, nsec) = if wide {
+        (umr.mem_access.read_phys_64(addr, endian), umr.mem_access.read_phys_64(addr + 8, endian))
+    } else {
+        (umr.mem_access.read_phys_32(addr, endian).map(u64::from), umr.mem_access.read_phys_32(addr + 4, endian).map(u64::from))
+    };
+    let (sec, nsec) = match (sec, nsec) {
+        (Ok(s), Ok(n)) => (s, n),
+        _ => return Err(EFAULT),
+    };
+    let t = Duration::from_secs(sec).saturating_add(Duration::from_nanos(nsec));
+    if !absolute {
+        return Ok(Some(t));
+    }
+    let mut now: timespec = unsafe { mem::zeroed() };
+    unsafe { clock_gettime(clock, &mut now) };
+    let now = Duration::new(now.tv_sec as u64, now.tv_nsec as u32);
+    Ok(Some(t.saturating_sub(now)))
+}
 pub fn u_getaffinity(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
     /// todo: diff endian/bitsize
     let pid = sysin.args[0];
@@ -292,6 +653,12 @@ pub fn u_getaffinity(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut
 pub fn u_fchmod(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
     let fd = sysin.args[0];
     let mode = sysin.args[1];
+    if let Some(fr) = &umr.fakeroot {
+        return match fr.chmod(&FileRef::fd(fd as c_int), mode as mode_t) {
+            Ok(()) => Default::default(),
+            Err(e) => errno_out(e),
+        };
+    }
     let res = unsafe {
         fchmod(fd as c_int, mode as mode_t)
     };
@@ -317,7 +684,10 @@ pub fn u_fstat<T: UsermodeCpu>(sysin: SyscallIn, cpu: &mut T) -> SyscallOut {
     let prstat = unsafe {
         pstat.assume_init()
     };
-    let gstat = plat2generic_stat(prstat);
+    let mut gstat = plat2generic_stat(prstat);
+    if let Some(fr) = &cpu.get_ume().fakeroot {
+        fr.fix_stat(&FileRef::fd(fd as c_int), &mut gstat);
+    }
     cpu.write_stat_t(bufptr, gstat);
     sysout
 }
@@ -328,7 +698,10 @@ pub fn u_fstat_at<T: UsermodeCpu>(sysin: SyscallIn, cpu: &mut T) -> SyscallOut {
     let bufptr = sysin.args[2];
     let flags = sysin.args[3];
     let mut sysout: SyscallOut = Default::default();
-    let newpath = CString::new(fix_path(umr.str_path.as_str(), path)).unwrap();
+    let newpath = match guest_path(umr, fd as c_int, path as u64, PathIntent::Read) {
+        Ok(p) => p,
+        Err(e) => return errno_out(e),
+    };
     let mut pstat  = MaybeUninit::<libc::stat>::zeroed();
     let res = unsafe {
         fstatat(fd as c_int, newpath.as_ptr(), pstat.as_mut_ptr(), flags as c_int)
@@ -344,21 +717,26 @@ pub fn u_fstat_at<T: UsermodeCpu>(sysin: SyscallIn, cpu: &mut T) -> SyscallOut {
     let prstat = unsafe {
         pstat.assume_init()
     };
-    let gstat = plat2generic_stat(prstat);
+    let mut gstat = plat2generic_stat(prstat);
+    if let Some(fr) = &cpu.get_ume().fakeroot {
+        fr.fix_stat(&FileRef::at(fd as c_int, &newpath, flags as c_int), &mut gstat);
+    }
     cpu.write_stat_t(bufptr, gstat);
     sysout
 }
 pub fn u_clone<T: UsermodeCpu>(sysin: SyscallIn, cpu: &mut T) -> SyscallOut {
    // clone()
     // we will not support cris/s390x for the forseeable future
-    let exc = CLONE_CHILD_SETTID | CLONE_CHILD_CLEARTID;
+    let exc = CLONE_CHILD_SETTID | CLONE_CHILD_CLEARTID | libc::CLONE_PARENT_SETTID;
     let flags = sysin.args[0] as i32;
     let excflags = flags & !exc;
+    // both hand back guest ids already, they have to store them for the SETTID flags too
     if (excflags == SIGCHLD || excflags == (CLONE_VM | CLONE_VFORK | SIGCHLD)) {
-        return cpu.fork_proc(sysin);
+        cpu.fork_proc(sysin)
        // panic!(); // unimpl: fork()/vfork()
+    } else {
+        cpu.clone_thread(sysin)
     }
-    cpu.clone_thread(sysin)
 
 }
 pub fn u_fadvise64(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
@@ -377,9 +755,10 @@ pub fn u_fadvise64(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
 pub fn u_access(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
     let path = sysin.args[0];
     let mode = sysin.args[1];
-    let newpath = CString::new(
-        fix_path(umr.str_path.as_str(), path as *const c_char)
-    ).unwrap();
+    let newpath = match guest_path(umr, AT_FDCWD, path, PathIntent::Read) {
+        Ok(p) => p,
+        Err(e) => return errno_out(e),
+    };
     let mut sout: SyscallOut = Default::default();
     let res = unsafe {
         libc::access(newpath.as_ptr(), mode as c_int)
@@ -391,9 +770,10 @@ pub fn u_open(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
     let path = sysin.args[0];
     let flags = sysin.args[1];
     let amode = sysin.args[2];
-    let newpath = CString::new(
-        fix_path(umr.str_path.as_str(), path as *const c_char)
-    ).unwrap();
+    let newpath = match guest_path(umr, AT_FDCWD, path, open_intent(flags as c_int)) {
+        Ok(p) => p,
+        Err(e) => return errno_out(e),
+    };
     let mut sout: SyscallOut = Default::default();
 
     let res = unsafe {
@@ -407,9 +787,10 @@ pub fn u_openat(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
     let path = sysin.args[1];
     let flags = sysin.args[2];
     let amode = sysin.args[3];
-    let newpath = CString::new(
-        fix_path(umr.str_path.as_str(), path as *const c_char)
-    ).unwrap();
+    let newpath = match guest_path(umr, dirfd as c_int, path, open_intent(flags as c_int)) {
+        Ok(p) => p,
+        Err(e) => return errno_out(e),
+    };
     debug!("openat: dirfd: {:x}, path: {:}, flags: {:}, mode: {:}", dirfd,
         newpath.clone().to_str().unwrap(), flags, amode);
     let mut sout: SyscallOut = Default::default();
@@ -431,9 +812,10 @@ pub fn u_statx(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
     let statsbux = sysin.args[4];
     let mut newpath = CString::new("").unwrap();
     let finalptr: *const c_char = if path != 0 {
-        newpath = CString::new(
-            fix_path(umr.str_path.as_str(), path as *const c_char)
-        ).unwrap();
+        newpath = match guest_path(umr, dirfd as c_int, path, PathIntent::Read) {
+            Ok(p) => p,
+            Err(e) => return errno_out(e),
+        };
         newpath.as_ptr()
     } else {
         ptr::null_mut()
@@ -447,8 +829,39 @@ pub fn u_statx(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
                     statsbux as *mut statx)
     };
     generic_error_handle(&mut sout, res);
+    if let (Some(fr), false) = (&umr.fakeroot, sout.is_error) {
+        fakeroot_fix_statx(fr, &FileRef::at(dirfd as c_int, &newpath, flags as c_int), statsbux);
+    }
     sout
 }
+/// statx() goes straight into the guest buffer, so patch the ownership fields there
+fn fakeroot_fix_statx(fr: &FakeRoot, f: &FileRef, buf: u64) {
+    let stx = unsafe { &mut *(buf as *mut statx) };
+    let mut gs: GenericStat = GenericStat {
+        st_dev: libc::makedev(stx.stx_dev_major, stx.stx_dev_minor),
+        st_ino: stx.stx_ino,
+        st_mode: stx.stx_mode as u64,
+        st_nlink: stx.stx_nlink as u64,
+        st_uid: stx.stx_uid as u64,
+        st_gid: stx.stx_gid as u64,
+        st_rdev: libc::makedev(stx.stx_rdev_major, stx.stx_rdev_minor),
+        st_size: 0,
+        st_blksize: 0,
+        st_blocks: 0,
+        st_atime: 0,
+        st_atime_nsec: 0,
+        st_mtime: 0,
+        st_mtime_nsec: 0,
+        st_ctime: 0,
+        st_ctime_nsec: 0,
+    };
+    fr.fix_stat(f, &mut gs);
+    stx.stx_mode = gs.st_mode as u16;
+    stx.stx_uid = gs.st_uid as u32;
+    stx.stx_gid = gs.st_gid as u32;
+    stx.stx_rdev_major = libc::major(gs.st_rdev);
+    stx.stx_rdev_minor = libc::minor(gs.st_rdev);
+}
 pub fn u_fchown_at(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
     let dirfd = sysin.args[0];
     let path = sysin.args[1];
@@ -457,9 +870,10 @@ pub fn u_fchown_at(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
     let flags = sysin.args[4];
     let mut newpath = CString::new("").unwrap();
     let finalptr: *const c_char = if path != 0 {
-        newpath = CString::new(
-            fix_path(umr.str_path.as_str(), path as *const c_char)
-        ).unwrap();
+        newpath = match guest_path(umr, dirfd as c_int, path, PathIntent::Modify) {
+            Ok(p) => p,
+            Err(e) => return errno_out(e),
+        };
         newpath.as_ptr()
     } else {
         ptr::null_mut()
@@ -467,6 +881,12 @@ pub fn u_fchown_at(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
     debug!("fchownat(): dirfd: {:x}, path: {:}, owner: {:x}, group: {:x}, flags: {:x}", dirfd,
         newpath.clone().to_str().unwrap(), owner, group, flags);
     let mut sout: SyscallOut = Default::default();
+    if let Some(fr) = &umr.fakeroot {
+        return match fr.chown(&FileRef::at(dirfd as c_int, &newpath, flags as c_int), owner as u32, group as u32) {
+            Ok(()) => sout,
+            Err(e) => errno_out(e),
+        };
+    }
     let res = unsafe {
         libc::fchownat(dirfd as c_int,
                     finalptr, owner as uid_t, group as c_uint,
@@ -485,9 +905,10 @@ pub fn u_fchmod_at(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
     }
     let mut newpath = CString::new("").unwrap();
     let finalptr: *const c_char = if path != 0 {
-        newpath = CString::new(
-            fix_path(umr.str_path.as_str(), path as *const c_char)
-        ).unwrap();
+        newpath = match guest_path(umr, dirfd as c_int, path, PathIntent::Modify) {
+            Ok(p) => p,
+            Err(e) => return errno_out(e),
+        };
         newpath.as_ptr()
     } else {
         ptr::null_mut()
@@ -495,6 +916,12 @@ pub fn u_fchmod_at(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
     debug!("fchmodat(): dirfd: {:x}, path: {:}, mode: {:x}, flags: {:x}", dirfd,
         newpath.clone().to_str().unwrap(), mode, flags);
     let mut sout: SyscallOut = Default::default();
+    if let Some(fr) = &umr.fakeroot {
+        return match fr.chmod(&FileRef::at(dirfd as c_int, &newpath, flags as c_int), mode as mode_t) {
+            Ok(()) => sout,
+            Err(e) => errno_out(e),
+        };
+    }
     let res = unsafe {
         libc::fchmodat(dirfd as c_int,
                        finalptr, mode as mode_t, flags as c_int)
@@ -524,15 +951,40 @@ pub fn u_getrandom(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
     generic_error_handle_maxarch_int(&mut sout, res as i64, umr.is_64);
     sout
 }
+/// readlink of /proc/self/exe gives the guest executable, not us
+fn proc_exe_readlink(umr: &UserModeRuntime, path: u64, buf: u64, bufs: u64) -> Option<SyscallOut> {
+    if path == 0 {
+        return None;
+    }
+    let p = unsafe { CStr::from_ptr(path as *const c_char) }.to_string_lossy().to_string();
+    if !is_proc_exe_link(p.as_str()) {
+        return None;
+    }
+    let link = umr.initvars.lock().exe_link.clone();
+    // no terminating nul, and silently truncated, like the real thing
+    let len = link.len().min(bufs as usize);
+    unsafe {
+        ptr::copy_nonoverlapping(link.as_ptr(), buf as *mut u8, len);
+    }
+    Some(SyscallOut {
+        ret1: len as u64,
+        ret2: None,
+        is_error: false,
+    })
+}
 pub fn u_readlinkat(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
     let dirfd = sysin.args[0];
     let path = sysin.args[1];
-    let buf = sysin.args[1];
-    let bufs = sysin.args[2];
+    let buf = sysin.args[2];
+    let bufs = sysin.args[3];
     let mut sout: SyscallOut = Default::default();
-    let newpath = CString::new(
-        fix_path(umr.str_path.as_str(), path as *const c_char)
-    ).unwrap();
+    if let Some(r) = proc_exe_readlink(umr, path, buf, bufs) {
+        return r;
+    }
+    let newpath = match guest_path(umr, dirfd as c_int, path, PathIntent::Read) {
+        Ok(p) => p,
+        Err(e) => return errno_out(e),
+    };
     let res = unsafe {
         readlinkat(dirfd as c_int, newpath.as_ptr(),
                    buf as *mut c_char, bufs as size_t)
@@ -545,9 +997,13 @@ pub fn u_readlink(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
     let buf = sysin.args[1];
     let bufs = sysin.args[2];
     let mut sout: SyscallOut = Default::default();
-    let newpath = CString::new(
-        fix_path(umr.str_path.as_str(), path as *const c_char)
-    ).unwrap();
+    if let Some(r) = proc_exe_readlink(umr, path, buf, bufs) {
+        return r;
+    }
+    let newpath = match guest_path(umr, AT_FDCWD, path, PathIntent::Read) {
+        Ok(p) => p,
+        Err(e) => return errno_out(e),
+    };
     let res = unsafe {
         readlink(newpath.as_ptr(), buf as *mut c_char, bufs as size_t)
     };
@@ -566,6 +1022,27 @@ pub fn u_lseek(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
     generic_error_handle_maxarch_int(&mut sout, res as i64, true);
     sout
 }
+/// _llseek of 32 bit guests: the offset comes in two halves, the new one is written to `result`
+pub fn u_llseek(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
+    let fd = sysin.args[0];
+    let offset = (sysin.args[1] << 32) | (sysin.args[2] & 0xffff_ffff);
+    let result = sysin.args[3];
+    let whence = sysin.args[4];
+    let endian = if umr.is_little_endian { MemEndian::Little } else { MemEndian::Big };
+    let mut sout: SyscallOut = Default::default();
+    let res = unsafe {
+        lseek(fd as c_int, offset as off_t, whence as c_int)
+    };
+    generic_error_handle_maxarch_int(&mut sout, res as i64, true);
+    if res < 0 {
+        return sout;
+    }
+    if umr.mem_access.write_phys_64(result, res as u64, endian).is_err() {
+        return errno_out(EFAULT);
+    }
+    sout.ret1 = 0;
+    sout
+}
 pub fn u_sysinfo<T: UsermodeCpu>(sysin: SyscallIn, cpu: &mut T) -> SyscallOut {
     let mut sinfo: sysinfo = unsafe { mem::zeroed() };
     let addr = sysin.args[0];
@@ -601,11 +1078,104 @@ pub fn u_pipe2(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
     return sout;
 
 }
+/// tkill() and tgkill(), what abort() and pthread_kill() use. With the default action the host
+/// kills the emulator of the same signal, so whoever ran us sees the guest die of it
+pub fn u_tgkill(sysin: SyscallIn, umr: &mut UserModeRuntime, has_tgid: bool) -> SyscallOut {
+    let (tgid, tid, sig) = if has_tgid {
+        (Some(sysin.args[0] as pid_t), sysin.args[1] as pid_t, sysin.args[2] as c_int)
+    } else {
+        (None, sysin.args[0] as pid_t, sysin.args[1] as c_int)
+    };
+    if let Some(sched) = umr.sched.clone() {
+        // scheduled threads have no host thread of their own, their ids are the scheduler's
+        if tid <= 0 || matches!(tgid, Some(t) if t != guest_pid(umr, unsafe { getpid() })) {
+            return errno_out(libc::ESRCH);
+        }
+        if !(0..libc::SIGRTMAX() + 1).contains(&sig) {
+            return errno_out(EINVAL);
+        }
+        if tid as u64 != umr.tid_val {
+            let target = match sched.lock().thread_signals(tid as u64) {
+                Some(s) => s,
+                None => return errno_out(libc::ESRCH),
+            };
+            let uid = umr.opts.guest_uid.unwrap_or_else(|| unsafe { getuid() });
+            if sig != 0 && target.post_tkill(sig, guest_pid(umr, unsafe { getpid() }), uid) {
+                // it's taken before the thread runs guest code again
+                sched.lock().interrupt(tid as u64);
+                return SyscallOut::default();
+            }
+        }
+        // our own thread, or a default action that is the same whichever thread gets it
+        if let Some(sout) = self_kill(umr, sig) {
+            return sout;
+        }
+        let res = if sig == 0 { 0 } else { unsafe { libc::raise(sig) } };
+        let mut sout: SyscallOut = Default::default();
+        generic_error_handle(&mut sout, res);
+        return sout;
+    }
+    let tid = match host_pid_arg(umr, tid) {
+        Ok(p) => p,
+        Err(e) => return errno_out(e),
+    };
+    if tid == unsafe { libc::gettid() } {
+        if let Some(sout) = self_kill(umr, sig) {
+            return sout;
+        }
+    }
+    let res = match tgid {
+        Some(tgid) => {
+            let tgid = match host_pid_arg(umr, tgid) {
+                Ok(p) => p,
+                Err(e) => return errno_out(e),
+            };
+            unsafe { syscall(libc::SYS_tgkill, tgid, tid, sig) }
+        }
+        None => unsafe { syscall(libc::SYS_tkill, tid, sig) },
+    };
+    let mut sout: SyscallOut = Default::default();
+    generic_error_handle(&mut sout, res as c_int);
+    sout
+}
+// A signal the guest sends itself (or its process group). Some(result) when it ended the guest
+// here instead of raising it on the host, which an embedder's process (keep_process) wouldn't
+// survive
+fn self_kill(umr: &mut UserModeRuntime, sig: c_int) -> Option<SyscallOut> {
+    if !umr.opts.keep_process {
+        // the other fatal ones come back through the run loop (see catch_fatal_defaults()),
+        // SIGKILL doesn't give us the chance
+        if sig == libc::SIGKILL {
+            finish_reports(umr, RunExit::Signal(sig));
+        }
+        return None;
+    }
+    let deadly = sig == libc::SIGKILL || (sig > 0 && fatal_signal(sig) && {
+        let info = umr.signals.info.lock().unwrap();
+        let guest = info.cnsts.host_to_guest_sigs.get(sig as usize).copied().unwrap_or(0);
+        info.action(guest as usize).handler_func == libc::SIG_DFL as u64
+    });
+    if !deadly {
+        return None;
+    }
+    finish_reports(umr, RunExit::Signal(sig));
+    guest_killed(umr, sig);
+    Some(SyscallOut::default())
+}
 pub fn u_kill(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
     let pid = sysin.args[0]; // todo: signal significane
     let sig = sysin.args[1];
+    let pid = match host_pid_arg(umr, pid as pid_t) {
+        Ok(p) => p,
+        Err(e) => return errno_out(e),
+    };
+    if pid == 0 || pid == unsafe { getpid() } {
+        if let Some(sout) = self_kill(umr, sig as c_int) {
+            return sout;
+        }
+    }
     let res = unsafe {
-        kill(pid as pid_t, sig as c_int)
+        kill(pid, sig as c_int)
     };
     let mut sout: SyscallOut = Default::default();
     generic_error_handle(&mut sout, res);
@@ -616,13 +1186,43 @@ pub fn u_mprotect(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
     let addr = sysin.args[0];
     let len = sysin.args[1];
     let prot = sysin.args[2];
+    let writable = prot as c_int & PROT_WRITE != 0;
+    let len = round_up(len, umr.guest_pagesize);
+    let mut mem = umr.memusage.lock();
+    if let Some(policy) = over_mem_limit(umr.opts.mem_limit, mem.committed_after_protect(addr, len, writable)) {
+        drop(mem);
+        return out_of_memory(umr, policy);
+    }
     let res = unsafe {
         mprotect(addr as *mut c_void, len as size_t, prot as c_int)
     };
+    if res == 0 {
+        mem.protect(addr, len, prot as c_int & (PROT_READ | PROT_WRITE | PROT_EXEC) != 0, writable);
+    }
     let mut sout: SyscallOut = Default::default();
     generic_error_handle(&mut sout, res);
     sout
 }
+/// The policy to apply if the guest going to `committed` bytes is over its limit
+fn over_mem_limit(limit: Option<MemLimit>, committed: u64) -> Option<OomPolicy> {
+    let lim = limit?;
+    if committed <= lim.bytes {
+        return None;
+    }
+    debug!("guest would commit {} bytes, limit is {}", committed, lim.bytes);
+    Some(lim.policy)
+}
+/// A request over the limit: it fails with ENOMEM, or with the kill policy the guest exits with
+/// the status a shell shows for a SIGKILLed process. Call it with the mapping list unlocked
+fn out_of_memory(ume: &mut UserModeRuntime, policy: OomPolicy) -> SyscallOut {
+    if policy == OomPolicy::Fail {
+        return errno_out(ENOMEM);
+    }
+    warn!("guest out of memory, limit is {} bytes", ume.opts.mem_limit.map_or(0, |l| l.bytes));
+    finish_reports(ume, RunExit::OutOfMemory);
+    end_guest(ume, 128 + libc::SIGKILL);
+    errno_out(ENOMEM)
+}
 pub fn u_ioctl(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
     let fd = sysin.args[0];
     let endian = if umr.is_little_endian { MemEndian::Little } else { MemEndian::Big };
@@ -666,6 +1266,73 @@ pub fn u_ioctl(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
             umr.mem_access.write_phys_32(addr, pt as u32, endian);
 
         }
+        TCSETS | TCSETSW | TCSETSF => {
+            // same layout problem as TCGETS
+            let addr = sysin.args[2];
+            let ret = unsafe { ioctl(fd as c_int, host_ioctl, addr as *const termios) };
+            generic_error_handle(&mut sout, ret);
+        }
+        TIOCSWINSZ => {
+            let addr = sysin.args[2];
+            if !arg_accessible(umr, addr, 8, false) {
+                return errno_out(EFAULT);
+            }
+            let mut f = [0u16; 4];
+            for (i, v) in f.iter_mut().enumerate() {
+                *v = match umr.mem_access.read_phys_16(addr + 2 * i as u64, endian) {
+                    Ok(v) => v,
+                    Err(_) => return errno_out(EFAULT),
+                };
+            }
+            let ws = winsize { ws_row: f[0], ws_col: f[1], ws_xpixel: f[2], ws_ypixel: f[3] };
+            let ret = unsafe { ioctl(fd as c_int, TIOCSWINSZ, &ws) };
+            generic_error_handle(&mut sout, ret);
+        }
+        TIOCSPGRP | TIOCSPTLCK => {
+            // int passed by pointer
+            if !arg_accessible(umr, sysin.args[2], 4, false) {
+                return errno_out(EFAULT);
+            }
+            let val = match umr.mem_access.read_phys_32(sysin.args[2], endian) {
+                Ok(v) => v as c_int,
+                Err(_) => return errno_out(EFAULT),
+            };
+            let ret = unsafe { ioctl(fd as c_int, host_ioctl, &val) };
+            generic_error_handle(&mut sout, ret);
+        }
+        TIOCSCTTY | TCFLSH | TCXONC | TCSBRK => {
+            // int passed by value
+            let ret = unsafe { ioctl(fd as c_int, host_ioctl, sysin.args[2] as c_int) };
+            generic_error_handle(&mut sout, ret);
+        }
+        TIOCNOTTY => {
+            let ret = unsafe { ioctl(fd as c_int, TIOCNOTTY) };
+            generic_error_handle(&mut sout, ret);
+        }
+        TIOCGPTN | TIOCGSID | FIONREAD => {
+            // int (or unsigned) returned by pointer
+            let mut val: c_int = 0;
+            let ret = unsafe { ioctl(fd as c_int, host_ioctl, &mut val) };
+            generic_error_handle(&mut sout, ret);
+            if ret < 0 {
+                return sout;
+            }
+            umr.mem_access.write_phys_32(sysin.args[2], val as u32, endian);
+        }
+        TIOCGPTPEER => {
+            // opens the other end of a pty master and returns the new fd. open flags match the host
+            let ret = unsafe { ioctl(fd as c_int, TIOCGPTPEER, sysin.args[2] as c_int) };
+            generic_error_handle(&mut sout, ret);
+        }
+        SIOCGIFHWADDR => {
+            // struct ifreq in and out, same layout on every 64 bit target
+            let ifr = sysin.args[2] as *mut u8;
+            let ret = unsafe { ioctl(fd as c_int, SIOCGIFHWADDR, ifr) };
+            generic_error_handle(&mut sout, ret);
+            if let (0, Some(id)) = (ret, &umr.opts.identity) {
+                unsafe { id.patch_hwaddr(ifr) };
+            }
+        }
         _ => panic!()
     }
     sout
@@ -689,6 +1356,7 @@ pub fn u_munmap(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
         sout.is_error = true;
         return sout;
     }
+    umr.memusage.unmap(addr, round_up(len, umr.guest_pagesize));
     sout.ret1 = retval as u64;
     return sout;
 }
@@ -757,6 +1425,22 @@ pub fn u_mmap(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
         // purpose of mem allocator is make sure non-fixed allocations don't overlap with fixed.
         // We really need to make sure any area covered by the mmap fixeed is reserved
     }
+    let flags = MapFlags {
+        kind: if guest_flags & MAP_ANONYMOUS != 0 { VmaKind::Anon } else { VmaKind::File },
+        readable: guest_prot as c_int & (PROT_READ | PROT_WRITE | PROT_EXEC) != 0,
+        writable: guest_prot as c_int & PROT_WRITE != 0,
+        shared: guest_flags & MAP_SHARED != 0,
+    };
+    let maplen = round_up(len, umr.guest_pagesize);
+    let mut mem = umr.memusage.lock();
+    if let Some(policy) = over_mem_limit(umr.opts.mem_limit, mem.committed_after(finalmmapaddr, maplen, &flags)) {
+        if !guest_wants_fixed {
+            ms.mmap_region.as_mut().unwrap().release_containing(finalmmapaddr).unwrap();
+        }
+        drop(mem);
+        drop(ms);
+        return out_of_memory(umr, policy);
+    }
     guest_flags |= MAP_FIXED;
     let retval = unsafe {
         libc::mmap(finalmmapaddr as *mut libc::c_void,
@@ -770,35 +1454,192 @@ pub fn u_mmap(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
         sout.is_error = true;
         return sout;
     }
+    mem.map(retval as u64, maplen, flags);
     sout.ret1 = retval as u64;
     return sout;
 
 }
 pub fn u_gettid(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
+    if ume.sched.is_some() {
+        // the scheduler made the id up, the host thread running us changes from slice to slice
+        return SyscallOut {
+            ret1: ume.tid_val,
+            .. Default::default()
+        };
+    }
     let tid = unsafe { libc::gettid() };
     debug!("tid system call: pid is {:x}", tid);
     SyscallOut {
-        ret1: tid as u64,
+        ret1: guest_pid(ume, tid) as u64,
         .. Default::default()
     }
 }
+/// Write the run summary, the ISA report and the block cache, the process is about to go
+pub fn finish_reports(ume: &UserModeRuntime, exit: RunExit) {
+    if let Some(rec) = &ume.summary {
+        rec.finish(ume, exit);
+    }
+    if let Some(isa) = &ume.isa {
+        isa.finish(ume);
+    }
+    if let Some(store) = &ume.block_store {
+        store.save();
+    }
+}
+/// exit_group() of one guest, shared by its threads. It doesn't end the emulator's process: the
+/// guest's threads unwind back to where they were started, so whoever ran the guest (the command
+/// line, or an embedder with several guests in one process) gets the status back
+pub struct GroupExit {
+    // 1 << 32 | status once it's exiting
+    state: AtomicU64,
+    /// threads that haven't called exit() yet, the one that started the guest included
+    live: AtomicUsize,
+    // the thread that started the guest waits on it for the others to leave
+    done: std::sync::Mutex<()>,
+    done_cv: std::sync::Condvar,
+}
+impl Default for GroupExit {
+    fn default() -> Self {
+        GroupExit {
+            state: AtomicU64::new(0),
+            live: AtomicUsize::new(1),
+            done: Default::default(),
+            done_cv: Default::default(),
+        }
+    }
+}
+impl GroupExit {
+    // like the kernel, the first one to call it decides the status
+    pub(crate) fn start(&self, status: i32) {
+        let _ = self.state.compare_exchange(0, 1 << 32 | status as u8 as u64, Ordering::SeqCst, Ordering::SeqCst);
+        let _g = self.done.lock().unwrap();
+        self.done_cv.notify_all();
+    }
+    pub fn status(&self) -> Option<i32> {
+        match self.state.load(Ordering::SeqCst) {
+            0 => None,
+            s => Some(s as u8 as i32),
+        }
+    }
+    /// clone() started a thread
+    pub fn thread_started(&self) {
+        self.live.fetch_add(1, Ordering::SeqCst);
+    }
+    /// In a fork()ed child, where the thread that forked is the only one
+    pub fn forked_child(&self) {
+        self.live.store(1, Ordering::SeqCst);
+    }
+    // a thread called exit(), true if it was the guest's last one
+    fn thread_left(&self) -> bool {
+        self.live.fetch_sub(1, Ordering::SeqCst) == 1
+    }
+    /// Blocks until the guest exits
+    fn wait(&self) {
+        let mut g = self.done.lock().unwrap();
+        while self.status().is_none() {
+            g = self.done_cv.wait(g).unwrap();
+        }
+    }
+}
+// what a guest thread's stack unwinds with
+struct GroupExitUnwind;
+/// Leave this guest thread, its guest is exiting
+pub fn group_exit_unwind() -> ! {
+    // resume_unwind skips the panic hook, nothing gets printed
+    std::panic::resume_unwind(Box::new(GroupExitUnwind))
+}
+/// Runs (a thread of) a guest, Some(status) if it stopped because the guest called exit_group()
+pub fn catch_group_exit(ge: &GroupExit, f: impl FnOnce()) -> Option<i32> {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+        Ok(()) => None,
+        Err(p) if p.is::<GroupExitUnwind>() => ge.status(),
+        Err(p) => std::panic::resume_unwind(p),
+    }
+}
+/// Ends the guest with `status` like exit_group() would, from wherever its thread is. The reports
+/// are the caller's. Under the scheduler it returns, the thread stops at its next switch point
+pub fn end_guest(ume: &mut UserModeRuntime, status: i32) {
+    ume.group_exit.start(status);
+    if ume.sched.is_none() {
+        ume.patch_gate.leave();
+        group_exit_unwind();
+    }
+    ume.sched_event = Some(SchedEvent::ExitGroup(status));
+}
+/// The guest dies of host signal `sig`, with the reports written already. The process dies of it
+/// too so a shell sees the signal, unless it's to be kept (keep_process): then only the guest ends,
+/// and whoever ran it gets the status a shell shows for it
+pub fn guest_killed(ume: &mut UserModeRuntime, sig: c_int) {
+    if !ume.opts.keep_process {
+        die_of_signal(sig);
+    }
+    end_guest(ume, 128 + sig);
+}
 pub fn u_exit_group(sysin: SyscallIn, ume: &mut UserModeRuntime) -> ! {
-    let status = sysin.args[0];
-    unsafe {
-        syscall(SYS_exit_group, status)
-    };
-    unreachable!();
+    let status = sysin.args[0] as i32 & 0xff;
+    finish_reports(ume, RunExit::Exit(status));
+    // the other threads see it before their next block of guest code
+    ume.group_exit.start(status);
+    group_exit_unwind()
+}
+/// exit_group() under the scheduler, which drops every guest thread it has
+pub fn u_exit_group_det(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
+    let status = sysin.args[0] as i32 & 0xff;
+    finish_reports(ume, RunExit::Exit(status));
+    ume.group_exit.start(status);
+    ume.sched_event = Some(SchedEvent::ExitGroup(status));
+    SyscallOut::default()
 }
 pub fn u_exit(sysin: SyscallIn, ume: &mut UserModeRuntime) -> ! {
-    let status = sysin.args[0];
+    let status = sysin.args[0] as i32 & 0xff;
     let endian = if ume.is_little_endian { MemEndian::Little } else { MemEndian::Big };
     if ume.flags & CLONE_CHILD_CLEARTID != 0 {
         ume.mem_access.write_phys_32(ume.ctid_val,0, endian);
     }
-    unsafe {
-        syscall(SYS_exit, status)
-    };
-    unreachable!();
+    // the last thread leaving ends the guest just like exit_group() would
+    let last = ume.group_exit.thread_left();
+    if last {
+        finish_reports(ume, RunExit::Exit(status));
+    }
+    if let Some(slot) = &ume.cpu_slot {
+        ume.cputime.thread_exit(slot);
+    }
+    if let (Some(r), Some(hc)) = (&ume.stats, &ume.hart_stats) {
+        r.retire(hc);
+    }
+    if let Some(t) = &ume.ids {
+        let tid = unsafe { libc::gettid() };
+        if tid != unsafe { getpid() } {
+            t.remove_host(tid);
+        }
+    }
+    ume.patch_gate.leave();
+    if last {
+        ume.group_exit.start(status);
+    } else if ume.flags & CLONE_THREAD == 0 {
+        // the thread that started the guest returns to whoever did, with the status of the last
+        // thread out, so it stays until then
+        ume.group_exit.wait();
+    }
+    // threads clone() started just end
+    group_exit_unwind()
+}
+pub fn u_exit_det(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
+    // the host thread is shared with the other guest threads, so only leave the scheduler
+    let endian = if ume.is_little_endian { MemEndian::Little } else { MemEndian::Big };
+    if ume.flags & CLONE_CHILD_CLEARTID != 0 {
+        ume.mem_access.write_phys_32(ume.ctid_val,0, endian);
+        ume.sched.as_ref().unwrap().lock().futex_wake(ume.ctid_val, 1, u32::MAX);
+    }
+    ume.sched.as_ref().unwrap().lock().remove_thread(ume.tid_val);
+    if let Some(slot) = &ume.cpu_slot {
+        ume.cputime.thread_exit(slot);
+    }
+    if let (Some(r), Some(hc)) = (&ume.stats, &ume.hart_stats) {
+        r.retire(hc);
+    }
+    ume.sched_event = Some(SchedEvent::Exit);
+    SyscallOut::default()
 }
 pub fn u_uname(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
     // todo: return arch specific string
@@ -808,6 +1649,9 @@ pub fn u_uname(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
     };
     let mut sysout = SyscallOut::default();
     generic_error_handle(&mut sysout, retval);
+    if let (0, Some(id)) = (retval, &ume.opts.identity) {
+        id.patch_uname(unsafe { &mut *(addr as *mut utsname) });
+    }
     sysout
 }
 pub fn u_setpriority(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
@@ -844,10 +1688,14 @@ pub fn u_set_tid_address(sysin: SyscallIn, ume: &mut UserModeRuntime) -> Syscall
     sysout.ret1 = retval as u64;
     sysout
 }
+// F_DUPFD and F_DUPFD_CLOEXEC have the same numbers on every abi
+fn is_dupfd_cmd(cmd: c_int) -> bool {
+    cmd == libc::F_DUPFD || cmd == libc::F_DUPFD_CLOEXEC
+}
 pub fn u_fcntl64(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
     let cmd = sysin.args[1] as c_int;
 
-    if cmd == F_GETFL || cmd == F_SETFL || cmd == F_GETFD || cmd == F_SETFD {
+    if cmd == F_GETFL || cmd == F_SETFL || cmd == F_GETFD || cmd == F_SETFD || is_dupfd_cmd(cmd) || is_lock_cmd(cmd) {
         u_fcntl(sysin, ume)
     } else {
         unimplemented!();
@@ -857,20 +1705,99 @@ pub fn u_fcntl(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
     let fd = sysin.args[0] as c_int;
     let cmd = sysin.args[1] as c_int;
     let arg = sysin.args[2];
-    if cmd == F_GETFL || cmd == F_SETFL || cmd == F_GETFD || cmd == F_SETFD {
+    if cmd == F_GETFL || cmd == F_SETFL || cmd == F_GETFD || cmd == F_SETFD || is_dupfd_cmd(cmd) {
         let retval = unsafe {
             fcntl(fd as c_int, cmd as c_int, arg as c_int)
         };
         let mut sysout = SyscallOut::default();
         generic_error_handle(&mut sysout, retval);
         sysout
+    } else if is_lock_cmd(cmd) {
+        u_fcntl_lock(fd, cmd, arg, ume)
     } else {
         unimplemented!();
     }
 
 }
+// owners in the local lock table. Record locks belong to the process, OFD and flock() locks
+// to the open file, which we can only tell apart by fd
+const OFD_LOCK_OWNER: u64 = 1 << 63;
+const FLOCK_LOCK_OWNER: u64 = 1 << 62;
+fn u_fcntl_lock(fd: c_int, cmd: c_int, arg: u64, ume: &mut UserModeRuntime) -> SyscallOut {
+    let endian = if ume.is_little_endian { MemEndian::Little } else { MemEndian::Big };
+    let wide = flock_is_wide(cmd, ume.is_64);
+    // the struct depends on cmd, so ptrcheck.rs can't look at it. F_GETLK writes it back
+    let is_get = matches!(cmd, GUEST_F_GETLK | GUEST_F_GETLK64 | GUEST_F_OFD_GETLK);
+    if !arg_accessible(ume, arg, guest_flock_len(wide), is_get) {
+        return errno_out(EFAULT);
+    }
+    let mut fl = match read_guest_flock(arg, wide, endian) {
+        Ok(fl) => fl,
+        Err(e) => return errno_out(e),
+    };
+    let (hostcmd, wait) = match cmd {
+        GUEST_F_GETLK | GUEST_F_GETLK64 => (libc::F_GETLK, false),
+        GUEST_F_SETLK | GUEST_F_SETLK64 => (libc::F_SETLK, false),
+        GUEST_F_SETLKW | GUEST_F_SETLKW64 => (libc::F_SETLKW, true),
+        GUEST_F_OFD_GETLK => (libc::F_OFD_GETLK, false),
+        GUEST_F_OFD_SETLK => (libc::F_OFD_SETLK, false),
+        GUEST_F_OFD_SETLKW => (libc::F_OFD_SETLKW, true),
+        _ => unreachable!(),
+    };
+    let mut hfl = guest2host_flock(fl);
+    let res = unsafe {
+        fcntl(fd, hostcmd, &mut hfl as *mut libc::flock)
+    };
+    if res >= 0 {
+        if is_get {
+            write_guest_flock(arg, wide, endian, host2guest_flock(hfl));
+        }
+        return SyscallOut::default();
+    }
+    let err = base::Error::last().errno();
+    if !host_cant_lock(err) {
+        return errno_out(err);
+    }
+    debug!("fcntl: host can't lock fd {}, using the local lock table", fd);
+    let owner = if cmd >= GUEST_F_OFD_GETLK {
+        OFD_LOCK_OWNER | fd as u64
+    } else {
+        unsafe { getpid() as u64 }
+    };
+    let res = if is_get {
+        ume.local_locks.getlk(fd, owner, &mut fl)
+            .map(|_| write_guest_flock(arg, wide, endian, fl))
+    } else {
+        ume.local_locks.setlk(fd, owner, unsafe { getpid() }, &fl, wait, &ume.signals)
+    };
+    match res {
+        Ok(()) => SyscallOut::default(),
+        Err(e) => errno_out(e),
+    }
+}
+pub fn u_flock(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
+    let fd = sysin.args[0] as c_int;
+    let op = sysin.args[1] as c_int;
+    let res = unsafe {
+        libc::flock(fd, op)
+    };
+    if res >= 0 {
+        return SyscallOut::default();
+    }
+    let err = base::Error::last().errno();
+    if !host_cant_lock(err) {
+        return errno_out(err);
+    }
+    match ume.local_locks.flock(fd, FLOCK_LOCK_OWNER | fd as u64, op, &ume.signals) {
+        Ok(()) => SyscallOut::default(),
+        Err(e) => errno_out(e),
+    }
+}
 pub fn u_close(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
     let fd = sysin.args[0];
+    ume.local_locks.release_owner(OFD_LOCK_OWNER | fd);
+    ume.local_locks.release_owner(FLOCK_LOCK_OWNER | fd);
+    ume.local_locks.release_file(fd as c_int, unsafe { getpid() as u64 });
     let retval = unsafe {
         close(fd as c_int)
     };
@@ -882,6 +1809,12 @@ pub fn u_read(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
     let fd = sysin.args[0];
     let addr = sysin.args[1];
     let cnt = sysin.args[2];
+    // waiting for the console shouldn't hold off signals for the guest
+    if ume.console.is_stdin(fd as c_int) {
+        if let Err(e) = ume.console.wait_stdin_readable(fd as c_int, &ume.signals) {
+            return errno_out(e);
+        }
+    }
     let retval = unsafe {
         read(fd as c_int, addr as *mut c_void, cnt as size_t)
     };
@@ -912,8 +1845,9 @@ pub fn u_write(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
 }
 pub fn u_getuid(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
     let mut sout: SyscallOut = Default::default();
-    let retval = unsafe {
-        getuid()
+    let retval = match ume.opts.guest_uid {
+        Some(u) => u,
+        None => unsafe { getuid() },
     };
     sout.ret1 = retval as u64;
     sout
@@ -923,7 +1857,7 @@ pub fn u_getpid(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
     let retval = unsafe {
         getpid()
     };
-    sout.ret1 = retval as u64;
+    sout.ret1 = guest_pid(ume, retval) as u64;
     sout
 }
 pub fn u_getppid(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
@@ -931,44 +1865,148 @@ pub fn u_getppid(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
     let retval = unsafe {
         getppid()
     };
-    sout.ret1 = retval as u64;
+    sout.ret1 = match &ume.ids {
+        // parent outside the guest doesn't have a guest pid
+        Some(t) => t.lookup_virt(retval).unwrap_or(0),
+        None => retval,
+    } as u64;
     sout
 }
 pub fn u_getpgid(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
     let mut sout: SyscallOut = Default::default();
-    let pid = sysin.args[0];
+    let pid = match host_pid_arg(ume, sysin.args[0] as pid_t) {
+        Ok(p) => p,
+        Err(e) => return errno_out(e),
+    };
 
     let retval = unsafe {
-        getpgid(pid as pid_t)
+        getpgid(pid)
     };
     generic_error_handle(&mut sout, retval);
+    if retval > 0 {
+        sout.ret1 = guest_pid(ume, retval) as u64;
+    }
     sout
 }
 pub fn u_setpgid(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
     let mut sout: SyscallOut = Default::default();
-    let pid = sysin.args[0];
-    let pgid = sysin.args[1];
+    let (pid, pgid) = match (host_pid_arg(ume, sysin.args[0] as pid_t), host_pid_arg(ume, sysin.args[1] as pid_t)) {
+        (Ok(p), Ok(g)) => (p, g),
+        (Err(e), _) | (_, Err(e)) => return errno_out(e),
+    };
 
     let retval = unsafe {
-        setpgid(pid as pid_t, pgid as pid_t)
+        setpgid(pid, pgid)
+    };
+    generic_error_handle(&mut sout, retval);
+    sout
+}
+pub fn u_setsid(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
+    let mut sout: SyscallOut = Default::default();
+    let retval = unsafe {
+        libc::setsid()
     };
     generic_error_handle(&mut sout, retval);
+    if retval > 0 {
+        sout.ret1 = guest_pid(ume, retval) as u64;
+    }
     sout
 }
+pub fn u_execve(sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
+    let path = sysin.args[0];
+    let endian = if umr.is_little_endian { MemEndian::Little } else { MemEndian::Big };
+    if path == 0 {
+        return errno_out(EFAULT);
+    }
+    let mut argv = match read_guest_strv(sysin.args[1], umr.is_64, endian) {
+        Ok(v) => v,
+        Err(e) => return errno_out(e),
+    };
+    let envp = match read_guest_strv(sysin.args[2], umr.is_64, endian) {
+        Ok(v) => v,
+        Err(e) => return errno_out(e),
+    };
+    let mut guest = unsafe { CStr::from_ptr(path as *const c_char) }.to_owned();
+    let mut host = match guest_path(umr, AT_FDCWD, path, PathIntent::Read) {
+        Ok(p) => p,
+        Err(e) => return errno_out(e),
+    };
+    if let Some((interp, arg)) = read_shebang(&host) {
+        // interpreter [arg] script script-args...
+        let mut nargv = vec![CString::new(interp.clone()).unwrap()];
+        if let Some(a) = arg {
+            nargv.push(CString::new(a).unwrap());
+        }
+        nargv.push(guest.clone());
+        nargv.extend(argv.into_iter().skip(1));
+        argv = nargv;
+        guest = CString::new(interp).unwrap();
+        host = match guest_path(umr, AT_FDCWD, guest.as_ptr() as u64, PathIntent::Read) {
+            Ok(p) => p,
+            Err(e) => return errno_out(e),
+        };
+    }
+    let ours = elf_machine(&host).map_or(false, |m| is_our_machine(umr.machine_type, m));
+    if !ours {
+        debug!("execve: running {:?} on the host", host);
+        return errno_out(host_execve(&host, &argv, &envp));
+    }
+    // ours but not runnable (big-endian, ILP32, a core file...), fail here like the kernel would
+    // instead of in the emulator we'd exec
+    if let Some(Err(e)) = read_header(&host).map(|h| elf_target(&h)) {
+        crate::warn_ratelimited!("execve: can't run {:?}: {}", guest, e);
+        return errno_out(ENOEXEC);
+    }
+    let prefix = match &umr.opts.reexec_prefix {
+        Some(p) => p,
+        None => {
+            crate::warn_ratelimited!("execve: don't know how to start the emulator again, can't run {:?}", guest);
+            return errno_out(ENOEXEC);
+        }
+    };
+    // with a rootfs the new emulator does the path lookup itself, same as the first one did
+    let target = if umr.opts.rootfs { guest } else { host };
+    let mut hargv: Vec<CString> = prefix.iter().map(|a| CString::new(a.as_str()).unwrap()).collect();
+    if let Some(a0) = argv.first() {
+        hargv.push(CString::new("--argv0").unwrap());
+        hargv.push(a0.clone());
+    }
+    if let Some(t) = &umr.ids {
+        hargv.push(CString::new("--id-table-fd").unwrap());
+        hargv.push(CString::new(t.fd().to_string()).unwrap());
+    }
+    if let Some(fr) = &umr.fakeroot {
+        hargv.push(CString::new("--fakeroot-table-fd").unwrap());
+        hargv.push(CString::new(fr.table_fd().to_string()).unwrap());
+    }
+    hargv.push(CString::new("--").unwrap());
+    hargv.push(target);
+    hargv.extend(argv.into_iter().skip(1));
+    debug!("execve: starting the emulator again, {:?}", hargv);
+    let selfexe = CString::new("/proc/self/exe").unwrap();
+    errno_out(host_execve(&selfexe, &hargv, &envp))
+}
 pub fn u_getsid(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
     let mut sout: SyscallOut = Default::default();
-    let pid = sysin.args[0];
+    let pid = match host_pid_arg(ume, sysin.args[0] as pid_t) {
+        Ok(p) => p,
+        Err(e) => return errno_out(e),
+    };
 
     let retval = unsafe {
-        getsid(pid as pid_t)
+        getsid(pid)
     };
     generic_error_handle(&mut sout, retval);
+    if retval > 0 {
+        sout.ret1 = guest_pid(ume, retval) as u64;
+    }
     sout
 }
 pub fn u_getgid(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
     let mut sout: SyscallOut = Default::default();
-    let retval = unsafe {
-        getgid()
+    let retval = match ume.opts.guest_gid {
+        Some(g) => g,
+        None => unsafe { getgid() },
     };
     sout.ret1 = retval as u64;
     sout
@@ -977,6 +2015,10 @@ pub fn u_getgid(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
 pub fn u_setgid(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
     let mut sout: SyscallOut = Default::default();
     let gid = sysin.args[0];
+    if let Some(g) = ume.opts.guest_gid {
+        // only "changing" to the id we already have works
+        return if gid as u32 == g { sout } else { errno_out(libc::EPERM) };
+    }
     let retval = unsafe {
         setgid(gid as gid_t)
     };
@@ -987,6 +2029,9 @@ pub fn u_setgid(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
 pub fn u_setuid(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
     let mut sout: SyscallOut = Default::default();
     let uid = sysin.args[0];
+    if let Some(u) = ume.opts.guest_uid {
+        return if uid as u32 == u { sout } else { errno_out(libc::EPERM) };
+    }
     let retval = unsafe {
         setuid(uid as gid_t)
     };
@@ -996,8 +2041,9 @@ pub fn u_setuid(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
 }
 pub fn u_geteuid(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
     let mut sout: SyscallOut = Default::default();
-    let retval = unsafe {
-        geteuid()
+    let retval = match ume.opts.guest_uid {
+        Some(u) => u,
+        None => unsafe { geteuid() },
     };
     sout.ret1 = retval as u64;
     sout
@@ -1112,6 +2158,36 @@ pub fn u_recvfrom(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
     generic_error_handle_maxarch_int(&mut sout, retval as i64, ume.is_64);
     sout
 }
+pub fn u_sendmsg(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
+    let mut sout: SyscallOut = Default::default();
+    let mut msg = match HostMsghdr::new(ume, sysin.args[1], false) {
+        Ok(m) => m,
+        Err(e) => return errno_out(e),
+    };
+    let retval = unsafe {
+        sendmsg(sysin.args[0] as c_int, msg.as_ptr(), sysin.args[2] as c_int)
+    };
+    generic_error_handle_maxarch_int(&mut sout, retval as i64, ume.is_64);
+    sout
+}
+pub fn u_recvmsg(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
+    let mut sout: SyscallOut = Default::default();
+    let mut msg = match HostMsghdr::new(ume, sysin.args[1], true) {
+        Ok(m) => m,
+        Err(e) => return errno_out(e),
+    };
+    let retval = unsafe {
+        recvmsg(sysin.args[0] as c_int, msg.as_ptr(), sysin.args[2] as c_int)
+    };
+    generic_error_handle_maxarch_int(&mut sout, retval as i64, ume.is_64);
+    if retval >= 0 {
+        msg.copy_back(ume);
+        if let Some(fp) = &ume.opts.fd_policy {
+            fp.received(&msg.received_fds());
+        }
+    }
+    sout
+}
 pub fn u_socketpair(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
     let mut sout: SyscallOut = Default::default();
     let domain = sysin.args[0];
@@ -1181,12 +2257,16 @@ pub fn u_clock_gettime(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOu
     if ret < 0 {
         return sout;
     }
+    if let Some(ns) = cputime_clock_ns(ume, clk_id as clockid_t) {
+        timespec.tv_sec = (ns / 1_000_000_000) as time_t;
+        timespec.tv_nsec = (ns % 1_000_000_000) as c_long;
+    }
     if tpaddr == 0 {
         sout.is_error = true;
         sout.ret1 = -EFAULT as i64 as u64;
         return sout;
     }
-    if ume.is_64 {
+    if wide_timespec(&sysin, ume) {
         ume.mem_access.write_phys_64(tpaddr, timespec.tv_sec as u64, endian);
         ume.mem_access.write_phys_64(tpaddr + 8, timespec.tv_nsec as u64, endian);
     } else {
@@ -1317,9 +2397,10 @@ pub fn u_utimensat(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
 
     let endian = if ume.is_little_endian { MemEndian::Little } else { MemEndian::Big };
     let newpath = if path != 0 {
-        CString::new(
-            fix_path(ume.str_path.as_str(), path as *const c_char)
-        ).unwrap()
+        match guest_path(ume, dirfd as c_int, path, PathIntent::Modify) {
+            Ok(p) => p,
+            Err(e) => return errno_out(e),
+        }
     } else {
         CString::new("").unwrap()
     };
@@ -1436,7 +2517,10 @@ pub fn u_truncate(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
     let path = sysin.args[0] as *const c_char;
     let length = sysin.args[1];
     let mut sout: SyscallOut = Default::default();
-    let newpath = CString::new(fix_path(ume.str_path.as_str(), path)).unwrap();
+    let newpath = match guest_path(ume, AT_FDCWD, path as u64, PathIntent::Modify) {
+        Ok(p) => p,
+        Err(e) => return errno_out(e),
+    };
     let ret = unsafe {
         truncate(newpath.as_ptr(), length as off_t)
     };
@@ -1448,7 +2532,7 @@ pub fn u_clock_settime(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOu
     let tpaddr = sysin.args[1];
     let mut sout: SyscallOut = Default::default();
     let endian = if ume.is_little_endian { MemEndian::Little } else { MemEndian::Big };
-    let (tv_sec, tv_nsec) = if ume.is_64 {
+    let (tv_sec, tv_nsec) = if wide_timespec(&sysin, ume) {
         let s = ume.mem_access.read_phys_64(tpaddr, endian).unwrap();
         let n = ume.mem_access.read_phys_64(tpaddr + 8,  endian).unwrap();
         (s, n)
@@ -1494,9 +2578,10 @@ pub fn u_chdir(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
     let mut sout: SyscallOut = Default::default();
     let mut newpath = CString::new("").unwrap();
     let finalptr: *const c_char = if path != 0 {
-        newpath = CString::new(
-            fix_path(ume.str_path.as_str(), path as *const c_char)
-        ).unwrap();
+        newpath = match guest_path(ume, AT_FDCWD, path, PathIntent::Read) {
+            Ok(p) => p,
+            Err(e) => return errno_out(e),
+        };
         newpath.as_ptr()
     } else {
         ptr::null_mut()
@@ -1507,6 +2592,14 @@ pub fn u_chdir(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
     generic_error_handle(&mut sout, ret);
     return sout;
 }
+pub fn u_dup(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
+    let mut sout: SyscallOut = Default::default();
+    let ret = unsafe {
+        libc::dup(sysin.args[0] as c_int)
+    };
+    generic_error_handle(&mut sout, ret);
+    return sout;
+}
 pub fn u_dup3(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
     let oldfd = sysin.args[0];
     let newfd = sysin.args[1];
@@ -1521,62 +2614,26 @@ pub fn u_dup3(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
 }
 pub fn u_readv(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
     let fd = sysin.args[0];
-    let initaladdr = sysin.args[1];
-    let cnt = sysin.args[2];
     let mut sout: SyscallOut = Default::default();
-    let endian = if ume.is_little_endian { MemEndian::Little } else { MemEndian::Big };
-    let mut iovecarr: Vec<iovec> = Vec::new();
-    let mut curraddr = initaladdr;
-    for _ in 0..cnt {
-        let (base, len) = if ume.is_64 {
-            let b = ume.mem_access.read_phys_64(curraddr, endian).unwrap();
-            let l = ume.mem_access.read_phys_64(curraddr + 8, endian).unwrap();
-            curraddr += 16;
-            (b, l)
-        } else {
-            let b = ume.mem_access.read_phys_32(curraddr, endian).unwrap() as u64;
-            let l = ume.mem_access.read_phys_32(curraddr + 4, endian).unwrap() as u64;
-            curraddr += 8;
-            (b, l)
-        };
-        iovecarr.push(iovec {
-            iov_base: base as *mut c_void,
-            iov_len: len as size_t
-        });
-    }
+    let mut iovs = match guest_iovecs(ume, sysin.args[1], sysin.args[2]) {
+        Ok(i) => i,
+        Err(e) => return errno_out(e),
+    };
     let ret = unsafe {
-        readv(fd as c_int, iovecarr.as_ptr() as *mut _, iovecarr.len() as c_int)
+        readv(fd as c_int, iovs.as_ptr(), iovs.len() as c_int)
     };
     generic_error_handle_maxarch_int(&mut sout, ret as i64, ume.is_64);
     return sout;
 }
 pub fn u_writev(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
     let fd = sysin.args[0];
-    let initaladdr = sysin.args[1];
-    let cnt = sysin.args[2];
     let mut sout: SyscallOut = Default::default();
-    let endian = if ume.is_little_endian { MemEndian::Little } else { MemEndian::Big };
-    let mut iovecarr: Vec<iovec> = Vec::new();
-    let mut curraddr = initaladdr;
-    for _ in 0..cnt {
-        let (base, len) = if ume.is_64 {
-            let b = ume.mem_access.read_phys_64(curraddr, endian).unwrap();
-            let l = ume.mem_access.read_phys_64(curraddr + 8, endian).unwrap();
-            curraddr += 16;
-            (b, l)
-        } else {
-            let b = ume.mem_access.read_phys_32(curraddr, endian).unwrap() as u64;
-            let l = ume.mem_access.read_phys_32(curraddr + 4, endian).unwrap() as u64;
-            curraddr += 8;
-            (b, l)
-        };
-        iovecarr.push(iovec {
-            iov_base: base as *mut c_void,
-            iov_len: len as size_t
-        });
-    }
+    let mut iovs = match guest_iovecs(ume, sysin.args[1], sysin.args[2]) {
+        Ok(i) => i,
+        Err(e) => return errno_out(e),
+    };
     let ret = unsafe {
-        writev(fd as c_int, iovecarr.as_ptr() as *mut _, iovecarr.len() as c_int)
+        writev(fd as c_int, iovs.as_ptr(), iovs.len() as c_int)
     };
     generic_error_handle_maxarch_int(&mut sout, ret as i64, ume.is_64);
     return sout;
@@ -1585,6 +2642,27 @@ pub fn u_getcwd(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
     let buf = sysin.args[0];
     let size = sysin.args[1];
     let mut sysout: SyscallOut = Default::default();
+    if ume.opts.rootfs {
+        // the host cwd is somewhere inside the rootfs, hide that part
+        let cwd = match std::env::current_dir() {
+            Ok(c) => c.to_string_lossy().to_string(),
+            Err(e) => return errno_out(e.raw_os_error().unwrap_or(libc::EIO)),
+        };
+        let guest = match cwd.strip_prefix(ume.str_path.as_str()) {
+            Some("") => "/".to_string(),
+            Some(rest) if rest.starts_with('/') => rest.to_string(),
+            _ => cwd, // outside the rootfs, not much we can do
+        };
+        if guest.len() + 1 > size as usize {
+            return errno_out(libc::ERANGE);
+        }
+        let c = CString::new(guest).unwrap();
+        unsafe {
+            ptr::copy_nonoverlapping(c.as_ptr(), buf as *mut c_char, c.as_bytes_with_nul().len());
+        }
+        sysout.ret1 = c.as_bytes_with_nul().len() as u64;
+        return sysout;
+    }
     let ret = unsafe {
         getcwd(buf as *mut c_char, size as size_t)
     };
@@ -1614,6 +2692,21 @@ pub fn u_brk(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
         sout.ret1 = new_val;
     } else {
         let size = (new_value_page - ms.brk_max);
+        let flags = MapFlags { kind: VmaKind::Brk, readable: true, writable: true, shared: false };
+        let mut mem = ume.memusage.lock();
+        match over_mem_limit(ume.opts.mem_limit, mem.committed_after(ms.brk_max, size, &flags)) {
+            Some(OomPolicy::Kill) => {
+                drop(mem);
+                drop(ms);
+                return out_of_memory(ume, OomPolicy::Kill);
+            }
+            Some(OomPolicy::Fail) => {
+                // brk() reports failure by not moving
+                sout.ret1 = ms.brk;
+                return sout;
+            }
+            None => {}
+        }
         let maddr = unsafe {
             libc::mmap(ms.brk_max as *mut libc::c_void,
                        size as size_t,
@@ -1629,6 +2722,7 @@ pub fn u_brk(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
             size: size as usize,
         };
         ms.mem_maps.push(memmap);
+        mem.map(ms.brk_max, size, flags);
         ms.brk_max = new_value_page;
         ms.brk = new_val;
         sout.ret1 = ms.brk;
@@ -1636,11 +2730,42 @@ pub fn u_brk(sysin: SyscallIn, ume: &mut UserModeRuntime) -> SyscallOut {
     return sout;
 }
 pub fn dispatch<T: UsermodeCpu>(cpu: &mut T, sysin: SyscallIn) -> SyscallOut {
+    if let Some(n) = cpu.retired_insns() {
+        publish_cpu_time(cpu.get_ume(), n);
+    }
+    if let Some(fi) = &cpu.get_ume().opts.faults {
+        if is_io_request(sysin.syscall) && fi.fail_request() {
+            debug!("fault injection: failing {:?}", sysin.syscall);
+            return errno_out(libc::EIO);
+        }
+    }
+    if let Some(rec) = &cpu.get_ume().summary {
+        rec.count_trap(TrapKind::Syscall(sysin.syscall));
+    }
+    let fd_policy = cpu.get_ume().opts.fd_policy.clone();
+    if let Some(fp) = &fd_policy {
+        match fp.check(&sysin) {
+            FdVerdict::Allow => {}
+            FdVerdict::Deny(e) => return errno_out(e),
+            FdVerdict::Skip => return SyscallOut::default(),
+        }
+    }
+    if cpu.get_ume().opts.check_pointers {
+        if let Err(e) = check_syscall_pointers(cpu.get_ume(), &sysin) {
+            return errno_out(e);
+        }
+    }
 
-    match sysin.syscall {
+    let sout = match sysin.syscall {
         SyscallType::Brk => u_brk(sysin, cpu.get_ume()),
         SyscallType::Writev => u_writev(sysin, cpu.get_ume()),
-        SyscallType::ExitGroup => u_exit_group(sysin, cpu.get_ume()),
+        SyscallType::ExitGroup => {
+            if cpu.get_ume().sched.is_some() {
+                u_exit_group_det(sysin, cpu.get_ume())
+            } else {
+                u_exit_group(sysin, cpu.get_ume())
+            }
+        }
         SyscallType::Uname => u_uname(sysin, cpu.get_ume()),
         SyscallType::Faccessat => u_faccess_at(sysin, cpu.get_ume()),
         SyscallType::Open => u_open(sysin, cpu.get_ume()),
@@ -1655,24 +2780,30 @@ pub fn dispatch<T: UsermodeCpu>(cpu: &mut T, sysin: SyscallIn) -> SyscallOut {
         SyscallType::Fcntl => u_fcntl(sysin, cpu.get_ume()),
         SyscallType::Readv => u_readv(sysin, cpu.get_ume()),
         SyscallType::Lseek => u_lseek(sysin, cpu.get_ume()),
+        SyscallType::Llseek => u_llseek(sysin, cpu.get_ume()),
         SyscallType::Sigprocmask | SyscallType::RtSigprocmask => {
             // Technically, we don't have to actually block all signals
             // Just keep track of which ones the guest program doesn't want
             SyscallOut::default()
         }
-        SyscallType::Sigaction | SyscallType::Sigaltstack  => {
-            // nop for now
-            /*SINFO.with(|z| {
-                let mut k = z.borrow_mut();
-                u_sigaction(cpu, sysin, &mut k)
-            })
-             */
+        SyscallType::Sigaction | SyscallType::Sigaltstack if cpu.get_ume().machine_type == MachineType::Arm64 => {
+            // no arm64 signal frames yet, its guests get no handlers
             SyscallOut::default()
         }
-        SyscallType::ClockSetTime => {
+        SyscallType::Sigaction => {
+            // the host handler takes the lock too, it mustn't come in while we hold it
+            let sseg = block_all_signals();
+            let sigs = cpu.get_ume().signals.clone();
+            let sout = u_sigaction(cpu, sysin, &mut sigs.info.lock().unwrap());
+            set_mask_block(sseg);
+            sout
+        }
+        SyscallType::Sigaltstack => u_sigaltstack(cpu, sysin),
+        SyscallType::RtSigreturn => cpu.rt_frame_restore(),
+        SyscallType::ClockSetTime | SyscallType::ClockSetTime64 => {
             u_clock_settime(sysin, cpu.get_ume())
         }
-        SyscallType::ClockGetTime => {
+        SyscallType::ClockGetTime | SyscallType::ClockGetTime64 => {
             u_clock_gettime(sysin, cpu.get_ume())
         }
         SyscallType::Geteuid => u_geteuid(sysin, cpu.get_ume()),
@@ -1690,6 +2821,7 @@ pub fn dispatch<T: UsermodeCpu>(cpu: &mut T, sysin: SyscallIn) -> SyscallOut {
         SyscallType::Fchmod => u_fchmod(sysin, cpu.get_ume()),
         SyscallType::Utimensat => u_utimensat(sysin, cpu.get_ume()),
         SyscallType::LookupDcookie => u_lookup_dcookie(sysin, cpu.get_ume()),
+        SyscallType::Dup => u_dup(sysin, cpu.get_ume()),
         SyscallType::Dup3 => u_dup3(sysin, cpu.get_ume()),
         SyscallType::Getgid => u_getgid(sysin, cpu.get_ume()),
         SyscallType::Setgid => u_setgid(sysin, cpu.get_ume()),
@@ -1698,6 +2830,8 @@ pub fn dispatch<T: UsermodeCpu>(cpu: &mut T, sysin: SyscallIn) -> SyscallOut {
         SyscallType::Bind => u_bind(sysin, cpu.get_ume()),
         SyscallType::Sendto => u_sendto(sysin, cpu.get_ume()),
         SyscallType::Recvfrom => u_recvfrom(sysin, cpu.get_ume()),
+        SyscallType::Sendmsg => u_sendmsg(sysin, cpu.get_ume()),
+        SyscallType::Recvmsg => u_recvmsg(sysin, cpu.get_ume()),
         SyscallType::Getitimer => u_getitimer(sysin, cpu.get_ume()),
         SyscallType::Setitimer => u_setitimer(sysin, cpu.get_ume()),
         SyscallType::Connect => u_connect(sysin, cpu.get_ume()),
@@ -1708,6 +2842,8 @@ pub fn dispatch<T: UsermodeCpu>(cpu: &mut T, sysin: SyscallIn) -> SyscallOut {
         SyscallType::Getpgid => u_getpgid(sysin, cpu.get_ume()),
         SyscallType::Getsid => u_getsid(sysin, cpu.get_ume()),
         SyscallType::Kill => u_kill(sysin, cpu.get_ume()),
+        SyscallType::Tkill => u_tgkill(sysin, cpu.get_ume(), false),
+        SyscallType::Tgkill => u_tgkill(sysin, cpu.get_ume(), true),
         SyscallType::Getdents64 => u_getdents64(sysin, cpu.get_ume()),
         SyscallType::Mmap2 => u_mmap2(sysin, cpu.get_ume()),
         SyscallType::Truncate => u_truncate(sysin, cpu.get_ume()),
@@ -1715,6 +2851,12 @@ pub fn dispatch<T: UsermodeCpu>(cpu: &mut T, sysin: SyscallIn) -> SyscallOut {
         SyscallType::Statx => u_statx(sysin, cpu.get_ume()),
         SyscallType::Munmap => u_munmap(sysin, cpu.get_ume()),
         SyscallType::Fcntl64 => u_fcntl64(sysin, cpu.get_ume()),
+        SyscallType::Flock => u_flock(sysin, cpu.get_ume()),
+        SyscallType::Setsid => u_setsid(sysin, cpu.get_ume()),
+        SyscallType::Execve => u_execve(sysin, cpu.get_ume()),
+        SyscallType::Mknodat => u_mknodat(sysin, cpu.get_ume()),
+        SyscallType::Getrusage => u_getrusage(sysin, cpu.get_ume()),
+        SyscallType::Times => u_times(sysin, cpu.get_ume()),
         SyscallType::SetRobustList => {
             SyscallOut::default()
         }
@@ -1735,19 +2877,25 @@ pub fn dispatch<T: UsermodeCpu>(cpu: &mut T, sysin: SyscallIn) -> SyscallOut {
         SyscallType::Prlimit64 => u_prlimit64(sysin, cpu.get_ume()),
         SyscallType::Readlinkat => u_readlinkat(sysin, cpu.get_ume()),
         SyscallType::Gettid => u_gettid(sysin, cpu.get_ume()),
-        SyscallType::Futex => {
+        SyscallType::Futex | SyscallType::Futex64 => {
             u_futex(sysin, cpu.get_ume())
         }
         SyscallType::Mkdirat => u_mkdirat(sysin, cpu.get_ume()),
         SyscallType::Nanosleep => u_nanosleep(sysin, cpu.get_ume()),
-        SyscallType::ClockNanosleep => u_clock_nanosleep(sysin, cpu.get_ume()),
+        SyscallType::ClockNanosleep | SyscallType::ClockNanosleep64 => u_clock_nanosleep(sysin, cpu.get_ume()),
         SyscallType::Madvise => {
             SyscallOut::default()
 
         }
         SyscallType::Getpriority => u_getpriority(sysin, cpu.get_ume()),
         SyscallType::Setpriority => u_setpriority(sysin, cpu.get_ume()),
-        SyscallType::Exit => u_exit(sysin, cpu.get_ume()),
+        SyscallType::Exit => {
+            if cpu.get_ume().sched.is_some() {
+                u_exit_det(sysin, cpu.get_ume())
+            } else {
+                u_exit(sysin, cpu.get_ume())
+            }
+        }
         SyscallType::Fchownat => u_fchown_at(sysin, cpu.get_ume()),
         SyscallType::Fchmodat => u_fchmod_at(sysin, cpu.get_ume()),
         SyscallType::Getcwd => u_getcwd(sysin, cpu.get_ume()),
@@ -1758,17 +2906,96 @@ pub fn dispatch<T: UsermodeCpu>(cpu: &mut T, sysin: SyscallIn) -> SyscallOut {
         SyscallType::Capset => u_capset(sysin, cpu.get_ume()),
         SyscallType::Setpgid => u_setpgid(sysin, cpu.get_ume()),
         SyscallType::Wait4 => u_wait4(sysin, cpu.get_ume()),
-        SyscallType::Getres => u_clock_getres(sysin, cpu.get_ume()),
+        SyscallType::Getres | SyscallType::Getres64 => u_clock_getres(sysin, cpu.get_ume()),
         SyscallType::Prctl => u_prctl(sysin, cpu.get_ume()),
         _ => {
             panic!("unimpl syscall");
         },
+    };
+    if let Some(fp) = &fd_policy {
+        fp.after(&sysin, &sout);
+    }
+    if !sout.is_error {
+        throttle_io(cpu.get_ume(), &sysin, sout.ret1);
+        flip_received_bits(cpu.get_ume(), &sysin, sout.ret1);
+    }
+    sout
+}
+fn is_io_request(sc: SyscallType) -> bool {
+    matches!(sc, SyscallType::Read | SyscallType::Write | SyscallType::Readv | SyscallType::Writev
+        | SyscallType::Sendto | SyscallType::Recvfrom | SyscallType::Sendmsg | SyscallType::Recvmsg
+        | SyscallType::Sendfile)
+}
+/// Data the host just put into guest memory for a read, where injected bit flips go
+fn flip_received_bits(ume: &UserModeRuntime, sysin: &SyscallIn, ret: u64) {
+    let fi = match &ume.opts.faults {
+        Some(f) => f,
+        None => return,
+    };
+    match sysin.syscall {
+        SyscallType::Read | SyscallType::Recvfrom if ret > 0 => {
+            let buf = unsafe { std::slice::from_raw_parts_mut(sysin.args[1] as *mut u8, ret as usize) };
+            fi.maybe_flip(buf);
+        }
+        _ => {}
+    }
+}
+/// Slows down file and socket I/O that went through, if the embedder asked for it
+fn throttle_io(ume: &UserModeRuntime, sysin: &SyscallIn, ret: u64) {
+    let thr = match &ume.opts.io_throttle {
+        Some(t) => t,
+        None => return,
+    };
+    let bytes = match sysin.syscall {
+        SyscallType::Read | SyscallType::Write | SyscallType::Readv | SyscallType::Writev
+        | SyscallType::Sendto | SyscallType::Recvfrom | SyscallType::Sendmsg | SyscallType::Recvmsg
+        | SyscallType::Sendfile => ret,
+        _ => return,
+    };
+    let class = match fd_class(sysin.args[0] as c_int) {
+        Some(c) => c,
+        None => return,
+    };
+    let delay = thr.delay_for(class, bytes);
+    if !delay.is_zero() {
+        std::thread::sleep(delay);
     }
 }
 pub trait UsermodeCpu {
-    fn push_stack_natural(&mut self, val: u64);
-    fn pop_stack_natural(&mut self) -> u64;
+    /// Push a guest word (4 or 8 bytes, guest byte order), sp stays word aligned. sp is left
+    /// alone if that's off the stack
+    fn push_stack_natural(&mut self, val: u64) -> Result<(), StackError> {
+        let word = word_size(self);
+        let endian = guest_endian(self);
+        let sp = self.get_stack_reg().wrapping_sub(word) & !(word - 1);
+        check_stack(self.get_ume(), sp, word, true)?;
+        let mem = &mut self.get_ume().mem_access;
+        let res = if word == 8 {
+            mem.write_phys_64(sp, val, endian)
+        } else {
+            mem.write_phys_32(sp, val as u32, endian)
+        };
+        res.map_err(|_| StackError::Fault(sp))?;
+        self.set_stack_reg(sp);
+        Ok(())
+    }
+    fn pop_stack_natural(&mut self) -> Result<u64, StackError> {
+        let word = word_size(self);
+        let endian = guest_endian(self);
+        let sp = self.get_stack_reg();
+        check_stack(self.get_ume(), sp, word, false)?;
+        let mem = &mut self.get_ume().mem_access;
+        let val = if word == 8 {
+            mem.read_phys_64(sp, endian)
+        } else {
+            mem.read_phys_32(sp, endian).map(|v| v as u64)
+        };
+        let val = val.map_err(|_| StackError::Fault(sp))?;
+        self.set_stack_reg(sp + word);
+        Ok(val)
+    }
     fn get_stack_reg(&mut self) -> u64;
+    fn set_stack_reg(&mut self, val: u64);
     fn get_ume(&mut self) -> &mut UserModeRuntime;
     fn write_stat_t(&mut self, addr: u64, stat_t: GenericStat);
     fn write_sysinfo_t(&mut self, addr: u64, si: sysinfo);
@@ -1778,8 +3005,15 @@ pub trait UsermodeCpu {
     fn set_altstack(&mut self, addr: u64, si: &SigInfo);
     fn get_altstack(&mut self, addr: u64) -> GenericStackt;
     fn rt_frame_setup(&mut self, sig: i32, si: &mut SigInfo);
+    /// rt_sigreturn(): back to where the signal came in, from the frame rt_frame_setup() made.
+    /// Returns what goes in the return register, the interrupted code's value
+    fn rt_frame_restore(&mut self) -> SyscallOut;
     //fn set_tls_addr(&mut self, addr: u64) -> GenericStackt;
     fn clone_thread(&mut self, sysin: SyscallIn) -> SyscallOut;
     fn fork_proc(&mut self, sysin: SyscallIn) -> SyscallOut;
+    /// Instructions retired by this thread, if the cpu counts them
+    fn retired_insns(&mut self) -> Option<u64> {
+        None
+    }
 
 }
\ No newline at end of file
@@ -0,0 +1,274 @@
+// What the guest has mapped, and what it costs the host. Every mapping the guest makes through
+// mmap(), mprotect() and brk() is kept in a VMA list, which gives the committed size (what could
+// be dirtied: private writable and shared anonymous memory, the way the kernel's overcommit
+// accounting counts it) and, with mincore(), how much of it is resident on the host. Guest
+// addresses are host addresses, so the host RSS of the guest's pages is attributed exactly.
+// With a limit set, requests that would commit past it either fail with ENOMEM or end the guest
+// with its own exit reason, before the host OOM killer picks the whole emulator. The check and the
+// mapping it allows are done with the list locked (MemUsage::lock()), so two threads can't both
+// squeeze in under the limit.
+// The ELF segments, the initial stack and the other things the loader maps are in the list too (so
+// it can tell whether a guest pointer is good, see ptrcheck.rs) but aren't counted.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::MutexGuard;
+use sync::Mutex;
+use crate::linux_usermode::throttle::parse_size;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OomPolicy {
+    /// mmap()/brk()/mprotect() fail with ENOMEM, like with overcommit disabled
+    Fail,
+    /// end the guest with the status of a SIGKILLed process, as if the OOM killer had picked it
+    Kill,
+}
+impl OomPolicy {
+    pub fn parse(s: &str) -> Option<OomPolicy> {
+        match s {
+            "fail" | "enomem" => Some(OomPolicy::Fail),
+            "kill" => Some(OomPolicy::Kill),
+            _ => None,
+        }
+    }
+}
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MemLimit {
+    /// most committed bytes the guest gets
+    pub bytes: u64,
+    pub policy: OomPolicy,
+}
+impl MemLimit {
+    /// `size` like 512M
+    pub fn parse(size: &str, policy: OomPolicy) -> Result<MemLimit, String> {
+        match parse_size(size) {
+            Some(bytes) => Ok(MemLimit { bytes, policy }),
+            None => Err(format!("bad memory size {}", size)),
+        }
+    }
+}
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum VmaKind {
+    Anon,
+    File,
+    Brk,
+    /// mapped by the loader
+    Loader,
+}
+#[derive(Copy, Clone, Debug)]
+struct Vma {
+    len: u64,
+    kind: VmaKind,
+    readable: bool,
+    writable: bool,
+    shared: bool,
+}
+impl Vma {
+    fn committed(&self) -> bool {
+        if self.kind == VmaKind::Loader {
+            false
+        } else if self.shared {
+            // shared file pages belong to the page cache
+            self.kind == VmaKind::Anon
+        } else {
+            self.writable
+        }
+    }
+}
+#[derive(Default)]
+pub struct MemUsage {
+    /// start -> mapping, never overlapping
+    vmas: Mutex<BTreeMap<u64, Vma>>,
+    committed: AtomicU64,
+    peak: AtomicU64,
+}
+/// Mapping flags as the host sees them
+pub struct MapFlags {
+    pub kind: VmaKind,
+    pub readable: bool,
+    pub writable: bool,
+    pub shared: bool,
+}
+impl MemUsage {
+    pub fn new() -> MemUsage {
+        Default::default()
+    }
+    // Cut [start, start + len) out of the list, returning the pieces that were in it
+    fn carve(vmas: &mut BTreeMap<u64, Vma>, start: u64, len: u64) -> Vec<(u64, Vma)> {
+        let end = start.saturating_add(len);
+        let first = vmas.range(..start).next_back().map(|(s, _)| *s).unwrap_or(start);
+        let hit: Vec<u64> = vmas.range(first..end).map(|(s, _)| *s).collect();
+        let mut out = Vec::new();
+        for s in hit {
+            let v = vmas.remove(&s).unwrap();
+            let e = s + v.len;
+            if e <= start {
+                vmas.insert(s, v);
+                continue;
+            }
+            if s < start {
+                vmas.insert(s, Vma { len: start - s, ..v });
+            }
+            if e > end {
+                vmas.insert(end, Vma { len: e - end, ..v });
+            }
+            let (cs, ce) = (s.max(start), e.min(end));
+            out.push((cs, Vma { len: ce - cs, ..v }));
+        }
+        out
+    }
+    fn committed_in(pieces: &[(u64, Vma)]) -> u64 {
+        pieces.iter().filter(|(_, v)| v.committed()).map(|(_, v)| v.len).sum()
+    }
+    // Bytes of [start, start + len) in mappings that `counts` says are committed, nothing is cut
+    fn committed_overlap(vmas: &BTreeMap<u64, Vma>, start: u64, len: u64, counts: impl Fn(&Vma) -> bool) -> u64 {
+        let end = start.saturating_add(len);
+        let first = vmas.range(..start).next_back().map(|(s, _)| *s).unwrap_or(start);
+        vmas.range(first..end).filter(|(_, v)| counts(v)).map(|(s, v)| {
+            (s + v.len).min(end).saturating_sub((*s).max(start))
+        }).sum()
+    }
+    fn set_committed(&self, val: u64) {
+        self.committed.store(val, Ordering::Relaxed);
+        self.peak.fetch_max(val, Ordering::Relaxed);
+    }
+    /// The list, locked until the guard is dropped
+    pub fn lock(&self) -> MemUsageLock {
+        MemUsageLock { mu: self, vmas: self.vmas.lock() }
+    }
+    /// Record a new mapping, replacing whatever was there (MAP_FIXED does that)
+    pub fn map(&self, start: u64, len: u64, flags: MapFlags) {
+        self.lock().map(start, len, flags)
+    }
+    /// Something the loader mapped for the guest: ELF segments, the stack, trampolines
+    pub fn map_loader(&self, start: u64, len: u64, writable: bool) {
+        self.map(start, len, MapFlags { kind: VmaKind::Loader, readable: true, writable, shared: false });
+    }
+    pub fn unmap(&self, start: u64, len: u64) {
+        self.lock().unmap(start, len)
+    }
+    /// mprotect() of mapped memory, holes in the range are left alone
+    pub fn protect(&self, start: u64, len: u64, readable: bool, writable: bool) {
+        self.lock().protect(start, len, readable, writable)
+    }
+    /// Whether all of [start, start + len) is mapped and can be read (and written, with `write`)
+    pub fn accessible(&self, start: u64, len: u64, write: bool) -> bool {
+        if len == 0 {
+            return true;
+        }
+        let end = match start.checked_add(len) {
+            Some(e) => e,
+            None => return false,
+        };
+        let vmas = self.vmas.lock();
+        let mut at = start;
+        // mappings are split wherever the permissions change, so walk them until `end`
+        let first = vmas.range(..=start).next_back().map(|(s, _)| *s).unwrap_or(start);
+        for (s, v) in vmas.range(first..end) {
+            if s + v.len <= at {
+                continue;
+            }
+            if *s > at || !v.readable || (write && !v.writable) {
+                return false;
+            }
+            at = s + v.len;
+            if at >= end {
+                return true;
+            }
+        }
+        false
+    }
+    pub fn committed(&self) -> u64 {
+        self.committed.load(Ordering::Relaxed)
+    }
+    pub fn peak_committed(&self) -> u64 {
+        self.peak.load(Ordering::Relaxed)
+    }
+    pub fn mapped(&self) -> u64 {
+        self.vmas.lock().values().map(|v| v.len).sum()
+    }
+    /// Host memory actually backing the guest's mappings right now
+    pub fn resident(&self) -> u64 {
+        let psize = base::pagesize() as u64;
+        let mut total = 0;
+        let mut vec = Vec::new();
+        for (s, v) in self.vmas.lock().iter() {
+            let start = s & !(psize - 1);
+            let len = s + v.len - start;
+            vec.resize(((len + psize - 1) / psize) as usize, 0u8);
+            if unsafe { libc::mincore(start as *mut libc::c_void, len as usize, vec.as_mut_ptr()) } == 0 {
+                total += vec.iter().filter(|b| **b & 1 != 0).count() as u64 * psize;
+            }
+        }
+        total
+    }
+    /// Readable mappings, adjacent ones merged, for dumping guest memory. File mappings are left
+    /// out, reading one past the end of its file would take us down with a SIGBUS
+    pub fn dumpable_ranges(&self) -> Vec<(u64, u64)> {
+        let mut out: Vec<(u64, u64)> = vec![];
+        for (s, v) in self.vmas.lock().iter() {
+            if !v.readable || v.kind == VmaKind::File {
+                continue;
+            }
+            match out.last_mut() {
+                Some((ls, ll)) if *ls + *ll == *s => *ll += v.len,
+                _ => out.push((*s, v.len)),
+            }
+        }
+        out
+    }
+    /// For the debugger's "meminfo"
+    pub fn report(&self, limit: Option<MemLimit>) -> String {
+        let mut s = String::new();
+        let kb = |b: u64| b >> 10;
+        let _ = writeln!(s, "committed: {} kB (peak {} kB)", kb(self.committed()), kb(self.peak_committed()));
+        let _ = writeln!(s, "mapped: {} kB, resident: {} kB", kb(self.mapped()), kb(self.resident()));
+        if let Some(l) = limit {
+            let _ = writeln!(s, "limit: {} kB, {:?} when reached", kb(l.bytes), l.policy);
+        }
+        for (start, v) in self.vmas.lock().iter() {
+            let _ = writeln!(s, "{:#x}-{:#x} {}{}{} {:?}", start, start + v.len, if v.readable { "r" } else { "-" },
+                             if v.writable { "w" } else { "-" }, if v.shared { "s" } else { "p" }, v.kind);
+        }
+        s
+    }
+}
+/// The mapping list held locked: what a request would commit is checked against the limit and the
+/// request then recorded, without another thread changing the list in between
+pub struct MemUsageLock<'a> {
+    mu: &'a MemUsage,
+    vmas: MutexGuard<'a, BTreeMap<u64, Vma>>,
+}
+impl MemUsageLock<'_> {
+    /// Bytes committed if [start, start + len) was (re)mapped with `flags`
+    pub fn committed_after(&self, start: u64, len: u64, flags: &MapFlags) -> u64 {
+        let old = MemUsage::committed_overlap(&self.vmas, start, len, Vma::committed);
+        let new = Vma { len, kind: flags.kind, readable: flags.readable, writable: flags.writable, shared: flags.shared };
+        let new = if new.committed() { len } else { 0 };
+        self.mu.committed() - old + new
+    }
+    /// What mprotect() of the range would leave committed
+    pub fn committed_after_protect(&self, start: u64, len: u64, writable: bool) -> u64 {
+        let old = MemUsage::committed_overlap(&self.vmas, start, len, Vma::committed);
+        let new = MemUsage::committed_overlap(&self.vmas, start, len, |v| Vma { writable, ..*v }.committed());
+        self.mu.committed() - old + new
+    }
+    pub fn map(&mut self, start: u64, len: u64, flags: MapFlags) {
+        let old = MemUsage::committed_in(&MemUsage::carve(&mut self.vmas, start, len));
+        let v = Vma { len, kind: flags.kind, readable: flags.readable, writable: flags.writable, shared: flags.shared };
+        self.vmas.insert(start, v);
+        self.mu.set_committed(self.mu.committed() - old + if v.committed() { len } else { 0 });
+    }
+    pub fn unmap(&mut self, start: u64, len: u64) {
+        let old = MemUsage::committed_in(&MemUsage::carve(&mut self.vmas, start, len));
+        self.mu.set_committed(self.mu.committed() - old);
+    }
+    pub fn protect(&mut self, start: u64, len: u64, readable: bool, writable: bool) {
+        let pieces = MemUsage::carve(&mut self.vmas, start, len);
+        let old = MemUsage::committed_in(&pieces);
+        let pieces: Vec<(u64, Vma)> = pieces.into_iter().map(|(s, v)| (s, Vma { readable, writable, ..v })).collect();
+        let new = MemUsage::committed_in(&pieces);
+        self.vmas.extend(pieces);
+        self.mu.set_committed(self.mu.committed() - old + new);
+    }
+}
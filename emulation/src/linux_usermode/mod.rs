@@ -1,3 +1,26 @@
 pub mod main;
 pub mod defs;
-pub mod signals;
\ No newline at end of file
+pub mod signals;
+pub mod sched;
+pub mod vfs;
+pub mod locks;
+pub mod exec;
+pub mod ids;
+pub mod fakeroot;
+pub mod cputime;
+pub mod throttle;
+pub mod summary;
+pub mod sandbox;
+pub mod stack;
+pub mod syscall_table;
+pub mod console;
+pub mod memusage;
+pub mod dynload;
+pub mod identity;
+pub mod fdpass;
+pub mod ptrcheck;
+pub mod iov;
+pub mod conrelay;
+pub mod elfcheck;
+pub mod isa_report;
+pub mod monitor;
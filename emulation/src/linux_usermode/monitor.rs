@@ -0,0 +1,68 @@
+// A monitor for a usermode run that isn't under a debugger: a ControlTransport (a Unix socket, a
+// named pipe on Windows) that one client at a time connects to and sends commands to, a line
+// each, getting the reply back followed by an empty line. The commands are the ones the gdb
+// monitor has that don't need a stopped cpu: meminfo, stats, throttle and loglevel.
+// Only the emulator process that was started with --monitor serves it, guest children from
+// fork() and execve() don't, since there'd be no telling them apart on the one socket.
+use std::io::{self, BufRead, BufReader, Write};
+use std::thread;
+use base::{info, warn, ControlConnection, ControlTransport};
+use crate::elf::UserModeRuntime;
+
+/// Reply to one of the usermode monitor commands, None if it isn't one
+pub fn usermode_cmd(ume: &UserModeRuntime, cmd: &str) -> Option<String> {
+    match cmd.trim() {
+        "meminfo" => return Some(ume.memusage.report(ume.opts.mem_limit)),
+        "stats" => {
+            return Some(match &ume.stats {
+                Some(r) => format!("{}\n{}", r.report().trim_end(), r.trap_sites_report(&|pc| ume.describe_pc(pc))),
+                None => "stats are off, run with --stats".to_string(),
+            });
+        }
+        _ => {}
+    }
+    crate::linux_usermode::throttle::monitor_cmd(cmd, ume.opts.io_throttle.as_deref())
+}
+/// Reply to a line from the monitor socket
+pub fn monitor_cmd(ume: &UserModeRuntime, cmd: &str) -> String {
+    if let Some(reply) = usermode_cmd(ume, cmd) {
+        return reply;
+    }
+    match crate::common::logctl::monitor_cmd(cmd) {
+        Some(reply) => reply,
+        None => "unknown command, try meminfo, stats, throttle [storage|net SPEC|off], loglevel [FILTER]".to_string(),
+    }
+}
+fn serve_conn(ume: &UserModeRuntime, conn: ControlConnection) -> io::Result<()> {
+    let mut out = conn.try_clone()?;
+    for line in BufReader::new(conn).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = monitor_cmd(ume, &line);
+        out.write_all(format!("{}\n\n", reply.trim_end()).as_bytes())?;
+    }
+    Ok(())
+}
+/// Bind the monitor at `name` and answer clients on a thread of its own. Binding happens here so
+/// a bad name is an error before the guest runs (and before any sandbox takes socket() away)
+pub fn start(ume: &UserModeRuntime, name: &str) -> io::Result<()> {
+    let transport = ControlTransport::bind(name)?;
+    info!("Monitor listening on {}", transport.name());
+    let ume = ume.clone();
+    thread::Builder::new().name("monitor".to_string()).spawn(move || loop {
+        match transport.accept() {
+            Ok(conn) => {
+                if let Err(e) = serve_conn(&ume, conn) {
+                    info!("Monitor client went away: {}", e);
+                }
+            }
+            Err(e) => {
+                warn!("Monitor stopped accepting on {}: {}", transport.name(), e);
+                return;
+            }
+        }
+    })?;
+    Ok(())
+}
@@ -0,0 +1,208 @@
+// Guest pointers in syscall arguments, checked before anything touches them. Guest addresses are
+// host addresses, so a bad pointer handed straight to a host syscall just gets EFAULT back, but
+// plenty of ours read or write guest memory themselves first (paths, iovecs, the structs we
+// convert) and a bad one takes the whole emulator down with a SIGSEGV.
+// With the check on, every pointer argument the syscall below uses is looked up in the VMA list
+// (memusage.rs) for the length the syscall would touch, and the syscall fails with EFAULT like it
+// would on a real kernel if any of it isn't mapped or lacks the permission. Lengths are on the
+// short side where the ABIs differ, so a good pointer never fails.
+use libc::EFAULT;
+use crate::common::memory::MemEndian;
+use crate::elf::UserModeRuntime;
+use crate::linux_usermode::main::{SyscallIn, SyscallType};
+
+/// longest string we look at, same as PATH_MAX
+const MAX_STR: u64 = 4096;
+/// argv and envp entries we look at
+const MAX_STRV: u64 = 0x10000;
+
+#[derive(Copy, Clone)]
+enum Len {
+    Fixed(u64),
+    /// args[i]
+    Arg(usize),
+    /// args[i] times this many bytes
+    ArgTimes(usize, u64),
+    /// this many guest words (long, time_t and pointers)
+    Words(u64),
+    /// a struct that's one size for 64-bit guests and another for 32-bit ones
+    PerAbi(u64, u64),
+}
+#[derive(Copy, Clone)]
+enum Ptr {
+    /// memory the syscall reads
+    In(usize, Len),
+    /// memory the syscall writes
+    Out(usize, Len),
+    /// NUL-terminated string
+    Str(usize),
+    /// NULL-terminated array of strings
+    Strv(usize),
+    /// iovec array at args[i] with args[j] entries, whose buffers are written when `bool`
+    Iov(usize, usize, bool),
+    /// NULL is fine
+    Opt(&'static Ptr),
+}
+use self::Len::*;
+use self::Ptr::*;
+
+const TIMESPEC: Len = Words(2);
+/// the *_time64 syscalls' timespec, 64 bit fields on every guest
+const TIMESPEC64: Len = Fixed(16);
+const STAT: Len = PerAbi(128, 64);
+const RUSAGE: Len = PerAbi(144, 72);
+const MSGHDR: Len = PerAbi(56, 28);
+const SIGACTION: Len = PerAbi(24, 16);
+const STACK_T: Len = PerAbi(24, 12);
+
+fn pointer_args(sc: SyscallType) -> &'static [Ptr] {
+    match sc {
+        SyscallType::Read => &[Out(1, Arg(2))],
+        SyscallType::Write => &[In(1, Arg(2))],
+        SyscallType::Readv => &[Iov(1, 2, true)],
+        SyscallType::Writev => &[Iov(1, 2, false)],
+        SyscallType::Open | SyscallType::Access | SyscallType::Chdir | SyscallType::Truncate => &[Str(0)],
+        SyscallType::Openat | SyscallType::Faccessat | SyscallType::Mkdirat | SyscallType::Mknodat
+        | SyscallType::Unlinkat | SyscallType::Fchownat | SyscallType::Fchmodat => &[Str(1)],
+        SyscallType::Fstatat => &[Str(1), Out(2, STAT)],
+        SyscallType::Fstat => &[Out(1, STAT)],
+        SyscallType::Statx => &[Str(1), Out(4, Fixed(256))],
+        SyscallType::Readlink => &[Str(0), Out(1, Arg(2))],
+        SyscallType::Readlinkat => &[Str(1), Out(2, Arg(3))],
+        SyscallType::Utimensat => &[Opt(&Str(1)), Opt(&In(2, Words(4)))],
+        SyscallType::Execve => &[Str(0), Opt(&Strv(1)), Opt(&Strv(2))],
+        SyscallType::Getcwd => &[Out(0, Arg(1))],
+        SyscallType::Getdents64 => &[Out(1, Arg(2))],
+        SyscallType::Getrandom => &[Out(0, Arg(1))],
+        SyscallType::LookupDcookie => &[Out(1, Arg(2))],
+        SyscallType::Uname => &[Out(0, Fixed(390))],
+        SyscallType::Sysinfo => &[Out(0, PerAbi(112, 64))],
+        SyscallType::Getrusage => &[Out(1, RUSAGE)],
+        SyscallType::Times => &[Opt(&Out(0, Words(4)))],
+        SyscallType::Wait4 => &[Opt(&Out(1, Fixed(4))), Opt(&Out(3, RUSAGE))],
+        SyscallType::Getrlimit => &[Out(1, Words(2))],
+        SyscallType::Prlimit64 => &[Opt(&In(2, Fixed(16))), Opt(&Out(3, Fixed(16)))],
+        SyscallType::Getitimer => &[Out(1, Words(4))],
+        SyscallType::Setitimer => &[Opt(&In(1, Words(4))), Opt(&Out(2, Words(4)))],
+        SyscallType::ClockGetTime => &[Out(1, TIMESPEC)],
+        SyscallType::ClockSetTime => &[In(1, TIMESPEC)],
+        SyscallType::Getres => &[Opt(&Out(1, TIMESPEC))],
+        SyscallType::ClockGetTime64 => &[Out(1, TIMESPEC64)],
+        SyscallType::ClockSetTime64 => &[In(1, TIMESPEC64)],
+        SyscallType::Getres64 => &[Opt(&Out(1, TIMESPEC64))],
+        SyscallType::Nanosleep => &[In(0, TIMESPEC), Opt(&Out(1, TIMESPEC))],
+        SyscallType::ClockNanosleep => &[In(2, TIMESPEC), Opt(&Out(3, TIMESPEC))],
+        SyscallType::ClockNanosleep64 => &[In(2, TIMESPEC64), Opt(&Out(3, TIMESPEC64))],
+        SyscallType::Pipe2 => &[Out(0, Fixed(8))],
+        SyscallType::Socketpair => &[Out(3, Fixed(8))],
+        SyscallType::Bind | SyscallType::Connect => &[In(1, Arg(2))],
+        SyscallType::Sendto => &[In(1, Arg(2)), Opt(&In(4, Arg(5)))],
+        SyscallType::Recvfrom => &[Out(1, Arg(2)), Opt(&Out(5, Fixed(4)))],
+        // the iovecs inside aren't looked at
+        SyscallType::Sendmsg => &[In(1, MSGHDR)],
+        SyscallType::Recvmsg => &[Out(1, MSGHDR)],
+        SyscallType::Sendfile => &[Opt(&Out(2, Fixed(8)))],
+        SyscallType::Llseek => &[Out(3, Fixed(8))],
+        SyscallType::Ppoll => &[Out(0, ArgTimes(1, 8)), Opt(&In(2, TIMESPEC)), Opt(&In(3, Arg(4)))],
+        SyscallType::Getaffinity => &[Out(2, Arg(1))],
+        SyscallType::Futex | SyscallType::Futex64 => &[In(0, Fixed(4))],
+        // handler, flags and mask, the restorer some ABIs have comes on top
+        SyscallType::Sigaction => &[Opt(&In(1, SIGACTION)), Opt(&Out(2, SIGACTION))],
+        SyscallType::Sigaltstack => &[Opt(&In(0, STACK_T)), Opt(&Out(1, STACK_T))],
+        _ => &[],
+    }
+}
+fn len_of(len: Len, args: &[u64; 7], is_64: bool) -> u64 {
+    let word = if is_64 { 8 } else { 4 };
+    match len {
+        Fixed(n) => n,
+        Arg(i) => args[i],
+        ArgTimes(i, n) => args[i].saturating_mul(n),
+        Words(n) => n * word,
+        PerAbi(l64, l32) => if is_64 { l64 } else { l32 },
+    }
+}
+/// Readable string at `addr`, looked at a page at a time so we never read past the mapping
+fn check_str(umr: &UserModeRuntime, addr: u64) -> Result<(), u64> {
+    let psize = umr.guest_pagesize;
+    let mut at = addr;
+    while at - addr < MAX_STR {
+        let chunk = (at | (psize - 1)) - at + 1;
+        if !umr.memusage.accessible(at, chunk, false) {
+            return Err(at);
+        }
+        let bytes = unsafe { std::slice::from_raw_parts(at as *const u8, chunk as usize) };
+        if bytes.contains(&0) {
+            return Ok(());
+        }
+        at += chunk;
+    }
+    // too long, the syscall can say so itself
+    Ok(())
+}
+fn read_word(umr: &mut UserModeRuntime, addr: u64) -> u64 {
+    let endian = if umr.is_little_endian { MemEndian::Little } else { MemEndian::Big };
+    if umr.is_64 {
+        umr.mem_access.read_phys_64(addr, endian).unwrap()
+    } else {
+        umr.mem_access.read_phys_32(addr, endian).unwrap() as u64
+    }
+}
+fn check_ptr(umr: &mut UserModeRuntime, args: &[u64; 7], p: &Ptr) -> Result<(), u64> {
+    let word = if umr.is_64 { 8 } else { 4 };
+    match *p {
+        Opt(inner) => match inner {
+            In(i, _) | Out(i, _) | Str(i) | Strv(i) | Iov(i, _, _) if args[*i] == 0 => Ok(()),
+            _ => check_ptr(umr, args, inner),
+        },
+        In(i, len) | Out(i, len) => {
+            let write = matches!(p, Out(..));
+            if umr.memusage.accessible(args[i], len_of(len, args, umr.is_64), write) {
+                Ok(())
+            } else {
+                Err(args[i])
+            }
+        }
+        Str(i) => check_str(umr, args[i]),
+        Strv(i) => {
+            let mut at = args[i];
+            for _ in 0..MAX_STRV {
+                if !umr.memusage.accessible(at, word, false) {
+                    return Err(at);
+                }
+                let s = read_word(umr, at);
+                if s == 0 {
+                    return Ok(());
+                }
+                check_str(umr, s)?;
+                at += word;
+            }
+            Ok(())
+        }
+        Iov(i, cnt, write) => {
+            let base = args[i];
+            let cnt = args[cnt];
+            if !umr.memusage.accessible(base, cnt.saturating_mul(2 * word), false) {
+                return Err(base);
+            }
+            for n in 0..cnt {
+                let iov_base = read_word(umr, base + n * 2 * word);
+                let iov_len = read_word(umr, base + n * 2 * word + word);
+                if !umr.memusage.accessible(iov_base, iov_len, write) {
+                    return Err(iov_base);
+                }
+            }
+            Ok(())
+        }
+    }
+}
+/// EFAULT if a pointer argument of the syscall is bad
+pub fn check_syscall_pointers(umr: &mut UserModeRuntime, sysin: &SyscallIn) -> Result<(), i32> {
+    for p in pointer_args(sysin.syscall) {
+        if let Err(addr) = check_ptr(umr, &sysin.args, p) {
+            crate::warn_ratelimited!("{:?} with bad guest pointer {:#x}, failing it with EFAULT", sysin.syscall, addr);
+            return Err(EFAULT);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,148 @@
+// Host sandbox for the emulator process. Guest syscalls mostly turn into the same host syscalls,
+// so an emulator bug (or a guest that gets the emulator to do something it shouldn't) can do
+// whatever we can. Once everything is loaded, a seccomp filter takes away the host syscalls no
+// guest workload should need: debugging other processes, mounts and namespaces, kernel modules,
+// setting the clock and so on. Those fail with EPERM, like they would for an unprivileged user.
+// The filter stays on across execve(), so the emulators the guest execs (and any foreign binaries
+// it runs natively) are covered too.
+// The filter only knows the syscall numbers of the hosts listed at AUDIT_ARCH_NATIVE, on any
+// other host --sandbox is an error instead of a filter that checks the wrong numbers. Windows
+// hosts have base::restrict_current_process, a job object, instead.
+use libc::{c_int, sock_filter, sock_fprog};
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct SandboxPolicy {
+    /// Also refuse sockets other than AF_UNIX (EACCES), so the guest can't reach the network
+    pub deny_net: bool,
+}
+
+const SECCOMP_SET_MODE_FILTER: c_int = 1;
+const SECCOMP_FILTER_FLAG_TSYNC: c_int = 1;
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+#[cfg(target_arch = "x86_64")]
+const BPF_JMP_JGE_K: u16 = 0x35;
+const BPF_RET_K: u16 = 0x06;
+
+// offsets into struct seccomp_data
+const DATA_NR: u32 = 0;
+const DATA_ARCH: u32 = 4;
+#[cfg(target_endian = "little")]
+const DATA_ARG0: u32 = 16;
+#[cfg(target_endian = "big")]
+const DATA_ARG0: u32 = 20;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH_NATIVE: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH_NATIVE: Option<u32> = Some(0xc000_00b7);
+#[cfg(target_arch = "riscv64")]
+const AUDIT_ARCH_NATIVE: Option<u32> = Some(0xc000_00f3);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "riscv64")))]
+const AUDIT_ARCH_NATIVE: Option<u32> = None;
+
+/// x32 syscalls on x86_64 are numbered from here, everything above is refused
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+const DENIED: &[libc::c_long] = &[
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_kexec_load,
+    libc::SYS_kexec_file_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_reboot,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_setns,
+    libc::SYS_unshare,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_userfaultfd,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_acct,
+    libc::SYS_quotactl,
+    libc::SYS_settimeofday,
+    libc::SYS_clock_settime,
+    libc::SYS_clock_adjtime,
+    libc::SYS_adjtimex,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_name_to_handle_at,
+    libc::SYS_syslog,
+    libc::SYS_vhangup,
+    // ring ops run in kernel workers, outside of this filter (and the socket check below)
+    libc::SYS_io_uring_setup,
+    libc::SYS_io_uring_enter,
+    libc::SYS_io_uring_register,
+];
+
+fn stmt(code: u16, k: u32) -> sock_filter {
+    sock_filter { code, jt: 0, jf: 0, k }
+}
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter { code, jt, jf, k }
+}
+/// The filter for syscalls of the `arch` abi, anything from another abi kills the process
+pub(crate) fn build_filter(policy: SandboxPolicy, arch: u32) -> Vec<sock_filter> {
+    let mut prog = vec![
+        // syscalls of another abi have other numbers, so just don't allow those
+        stmt(BPF_LD_W_ABS, DATA_ARCH),
+        jump(BPF_JMP_JEQ_K, arch, 1, 0),
+        stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+        stmt(BPF_LD_W_ABS, DATA_NR),
+    ];
+    #[cfg(target_arch = "x86_64")]
+    {
+        prog.push(jump(BPF_JMP_JGE_K, X32_SYSCALL_BIT, 0, 1));
+        prog.push(stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+    }
+    for nr in DENIED {
+        prog.push(jump(BPF_JMP_JEQ_K, *nr as u32, 0, 1));
+        prog.push(stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+    }
+    if policy.deny_net {
+        prog.push(jump(BPF_JMP_JEQ_K, libc::SYS_socket as u32, 0, 3));
+        prog.push(stmt(BPF_LD_W_ABS, DATA_ARG0));
+        prog.push(jump(BPF_JMP_JEQ_K, libc::AF_UNIX as u32, 1, 0));
+        prog.push(stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EACCES as u32));
+    }
+    prog.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+    prog
+}
+/// Put the emulator (all of its threads) in the sandbox, for good
+pub fn apply_sandbox(policy: SandboxPolicy) -> std::io::Result<()> {
+    let arch = AUDIT_ARCH_NATIVE.ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::Unsupported, "no seccomp filter for this host architecture")
+    })?;
+    let mut filter = build_filter(policy, arch);
+    let prog = sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    // needed to install a filter without CAP_SYS_ADMIN, also means setuid binaries exec'd
+    // natively don't get their privileges
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let res = unsafe {
+        libc::syscall(libc::SYS_seccomp, SECCOMP_SET_MODE_FILTER, SECCOMP_FILTER_FLAG_TSYNC,
+                      &prog as *const sock_fprog)
+    };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
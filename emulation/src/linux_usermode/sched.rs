@@ -0,0 +1,546 @@
+// Deterministic scheduling for usermode guests.
+// With this enabled, every guest thread runs on the host thread that loaded the program, and
+// control only changes hands at fixed points: after a syscall, when a thread blocks on a futex,
+// or when it has retired its instruction budget. Given the same inputs, two runs interleave their
+// threads the same way, which makes threading bugs reproducible (and is what record/replay needs).
+// Blocking syscalls other than futex still block the host thread, and so every guest thread.
+// Futex timeouts run on a clock of instructions retired by all the threads together (at the guest
+// clock rate), so they expire at the same point in every run too, however busy the others are.
+// The same switch points also drive the pooled scheduler (run_pooled), which runs the guest
+// threads on a fixed number of host threads instead of one each, so a guest with more threads than
+// the host has cores doesn't leave the host OS juggling them. Every worker has its own run queue
+// and takes from another one's back when it runs dry. That isn't reproducible anymore, but a
+// blocking syscall only holds up its own worker. Its futex timeouts are on the wall clock, checked
+// by every worker between slices and by idle ones at least every IDLE_WAIT. When a thread calls
+// exit_group(), the workers still stuck in a syscall are interrupted so the pool can be joined.
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Once};
+use std::time::{Duration, Instant};
+use base::{debug, error, SIGRTMIN};
+use libc::{c_int, pthread_t};
+use sync::{Condvar, Mutex};
+use crate::linux_usermode::signals::GuestSignals;
+
+pub const DEFAULT_SCHED_QUANTUM: u64 = 100_000;
+/// Threads the scheduler makes up get tids from here on. It's the kernel's PID_MAX_LIMIT, so no
+/// host process or thread can have one of them
+pub const DET_TID_BASE: u64 = 1 << 22;
+/// How long an idle worker sleeps before looking for work (and timeouts) again
+const IDLE_WAIT: Duration = Duration::from_millis(5);
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SchedEvent {
+    /// Thread reached a switch point (end of quantum, or a syscall), put it at the back of the queue
+    Yield,
+    /// Thread went to sleep on a futex
+    FutexWait,
+    /// Thread exited
+    Exit,
+    /// The guest called exit_group() with this status, every thread goes
+    ExitGroup(i32),
+    /// We are the child side of a fork(), every other guest thread stays in the parent
+    ForkedChild,
+}
+/// How a scheduler run ended
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SchedExit {
+    /// every thread exited
+    Done,
+    /// a thread called exit_group() with this status
+    ExitGroup(i32),
+    /// every thread is asleep on a futex and no timeout is left to wake one
+    Deadlock,
+}
+struct FutexWaiter {
+    tid: u64,
+    addr: u64,
+    bitset: u32,
+    /// on the scheduler clock
+    deadline: Option<u64>,
+    /// pooled scheduler, on the wall clock instead
+    wall_deadline: Option<Instant>,
+    woken: bool,
+    /// a signal ended the wait, the futex() fails with EINTR
+    interrupted: bool,
+}
+/// How a guest thread fared under the pooled scheduler
+#[derive(Copy, Clone, Debug, Default)]
+pub struct HartShare {
+    pub slices: u64,
+    pub instructions: u64,
+    /// slices that ran on another worker than the slice before
+    pub migrations: u64,
+    /// time spent runnable, waiting for a worker
+    pub wait_us: u64,
+}
+/// State shared by all guest threads of a deterministic (or pooled) process.
+pub struct SchedState {
+    pub quantum: u64,
+    /// host threads running guest threads, 1 is the deterministic scheduler
+    pub workers: usize,
+    /// for turning futex timeouts into instructions
+    pub guest_mhz: u64,
+    /// instructions retired by all threads, what futex timeouts are measured in
+    clock: u64,
+    next_tid: u64,
+    waiters: Vec<FutexWaiter>,
+    /// per guest thread, pooled scheduler only
+    pub shares: BTreeMap<u64, HartShare>,
+    /// signal state of the live guest threads, what tgkill() between them goes by
+    threads: BTreeMap<u64, Arc<GuestSignals>>,
+}
+impl SchedState {
+    pub fn new(quantum: u64, guest_mhz: u64) -> SchedState {
+        SchedState::pooled(quantum, guest_mhz, 1)
+    }
+    pub fn pooled(quantum: u64, guest_mhz: u64, workers: usize) -> SchedState {
+        SchedState {
+            quantum,
+            workers: workers.max(1),
+            guest_mhz,
+            clock: 0,
+            next_tid: DET_TID_BASE,
+            waiters: vec![],
+            shares: BTreeMap::new(),
+            threads: BTreeMap::new(),
+        }
+    }
+    /// Thread ids are handed out in creation order so they are stable across runs
+    pub fn alloc_tid(&mut self) -> u64 {
+        let tid = self.next_tid;
+        self.next_tid += 1;
+        tid
+    }
+    /// What a forked child starts with: the same settings and none of the parent's threads
+    pub fn for_child(&self) -> SchedState {
+        SchedState::pooled(self.quantum, self.guest_mhz, self.workers)
+    }
+    pub fn add_thread(&mut self, tid: u64, signals: Arc<GuestSignals>) {
+        self.threads.insert(tid, signals);
+    }
+    pub fn remove_thread(&mut self, tid: u64) {
+        self.threads.remove(&tid);
+    }
+    pub fn thread_signals(&self, tid: u64) -> Option<Arc<GuestSignals>> {
+        self.threads.get(&tid).cloned()
+    }
+    /// A signal was queued for `tid`, if it's asleep on a futex it wakes up with EINTR
+    pub fn interrupt(&mut self, tid: u64) {
+        for w in self.waiters.iter_mut().filter(|w| w.tid == tid && !w.woken) {
+            w.woken = true;
+            w.interrupted = true;
+        }
+    }
+    // the guest is exiting, waits the emulator does itself (file locks, the console) give up
+    fn cancel_waits(&self) {
+        for s in self.threads.values() {
+            s.cancel();
+        }
+    }
+    pub fn futex_wait(&mut self, tid: u64, addr: u64, bitset: u32, timeout: Option<Duration>) {
+        let (deadline, wall_deadline) = match timeout {
+            Some(t) if self.workers > 1 => (None, Instant::now().checked_add(t)),
+            Some(t) => {
+                let insns = (t.as_nanos() * self.guest_mhz as u128 / 1000).min(u64::MAX as u128) as u64;
+                (Some(self.clock.saturating_add(insns)), None)
+            }
+            None => (None, None),
+        };
+        self.waiters.push(FutexWaiter {
+            tid,
+            addr,
+            bitset,
+            deadline,
+            wall_deadline,
+            woken: false,
+            interrupted: false,
+        });
+    }
+    /// `insns` more instructions were retired
+    pub fn advance(&mut self, insns: u64) {
+        self.clock = self.clock.saturating_add(insns);
+    }
+    /// Wakes up to `count` threads sleeping on `addr`, oldest first. Returns how many were woken
+    pub fn futex_wake(&mut self, addr: u64, count: u64, bitset: u32) -> u64 {
+        let mut woken = 0;
+        for w in self.waiters.iter_mut() {
+            if woken >= count {
+                break;
+            }
+            if !w.woken && w.addr == addr && (w.bitset & bitset) != 0 {
+                w.woken = true;
+                woken += 1;
+            }
+        }
+        woken
+    }
+    /// Wakes up to `count` waiters on `addr`, then moves up to `requeue` of the rest to `addr2`
+    pub fn futex_requeue(&mut self, addr: u64, count: u64, addr2: u64, requeue: u64) -> u64 {
+        let woken = self.futex_wake(addr, count, u32::MAX);
+        let mut moved = 0;
+        for w in self.waiters.iter_mut() {
+            if moved >= requeue {
+                break;
+            }
+            if !w.woken && w.addr == addr {
+                w.addr = addr2;
+                moved += 1;
+            }
+        }
+        woken + moved
+    }
+    /// Ends the wait of `tid` if it was woken, Some(true) if that was a signal
+    fn take_woken(&mut self, tid: u64) -> Option<bool> {
+        let idx = self.waiters.iter().position(|w| w.tid == tid && w.woken)?;
+        Some(self.waiters.remove(idx).interrupted)
+    }
+    /// Ends the wait of `tid` if its wall clock timeout has passed
+    fn take_timed_out(&mut self, tid: u64, now: Instant) -> bool {
+        let due = |w: &FutexWaiter| w.tid == tid && !w.woken && w.wall_deadline.map_or(false, |d| d <= now);
+        if let Some(idx) = self.waiters.iter().position(due) {
+            self.waiters.remove(idx);
+            true
+        } else {
+            false
+        }
+    }
+    fn next_wall_deadline(&self) -> Option<Instant> {
+        self.waiters.iter().filter(|w| !w.woken).filter_map(|w| w.wall_deadline).min()
+    }
+    /// Waiters whose timeout has passed, in the order they went to sleep
+    pub fn expire_due(&mut self) -> Vec<u64> {
+        let clock = self.clock;
+        let mut out = vec![];
+        self.waiters.retain(|w| {
+            let due = !w.woken && w.deadline.map_or(false, |d| d <= clock);
+            if due {
+                out.push(w.tid);
+            }
+            !due
+        });
+        out
+    }
+    /// When nothing can run, time skips ahead to the next timeout
+    fn expire_earliest(&mut self) -> Option<u64> {
+        let next = self.waiters.iter().filter(|w| !w.woken).filter_map(|w| w.deadline).min()?;
+        self.clock = self.clock.max(next);
+        self.expire_due().first().copied()
+    }
+}
+
+pub trait DetThread: Sized {
+    /// Runs the thread until it reaches a switch point. Threads created along the way are
+    /// pushed onto `spawned`.
+    fn run_slice(&mut self, spawned: &mut Vec<Box<Self>>) -> SchedEvent;
+    fn sched_tid(&self) -> u64;
+    /// Instructions retired so far, for the fairness numbers
+    fn sched_icount(&self) -> u64;
+    /// Called when a futex wait of this thread is ended by its timeout rather than a wakeup
+    fn futex_timed_out(&mut self);
+    /// Called when a futex wait of this thread is ended by a signal
+    fn futex_interrupted(&mut self);
+    /// The scheduler state its syscalls use, a forked child gets a new one
+    fn sched_state(&self) -> Arc<Mutex<SchedState>>;
+}
+
+/// Runs the guest's threads until they all exited, one called exit_group() or they're all stuck
+pub fn run_deterministic<T: DetThread>(first: Box<T>, mut state: Arc<Mutex<SchedState>>) -> SchedExit {
+    let mut runq: VecDeque<Box<T>> = VecDeque::new();
+    let mut blocked: Vec<Box<T>> = Vec::new();
+    let mut spawned: Vec<Box<T>> = Vec::new();
+    runq.push_back(first);
+    loop {
+        let mut cur = if let Some(t) = runq.pop_front() {
+            t
+        } else if blocked.is_empty() {
+            return SchedExit::Done;
+        } else {
+            // everyone is asleep, so the only way forward is a timeout
+            let tid = if let Some(t) = state.lock().expire_earliest() {
+                t
+            } else {
+                error!("Deterministic scheduler: all {} guest threads are blocked on futexes", blocked.len());
+                return SchedExit::Deadlock;
+            };
+            let idx = blocked.iter().position(|t| t.sched_tid() == tid).unwrap();
+            let mut t = blocked.remove(idx);
+            t.futex_timed_out();
+            t
+        };
+        let before = cur.sched_icount();
+        let ev = cur.run_slice(&mut spawned);
+        let ran = cur.sched_icount().wrapping_sub(before);
+        match ev {
+            SchedEvent::Yield => runq.push_back(cur),
+            SchedEvent::FutexWait => blocked.push(cur),
+            SchedEvent::Exit => {
+                debug!("Deterministic scheduler: thread {:x} exited", cur.sched_tid());
+            }
+            SchedEvent::ExitGroup(status) => return SchedExit::ExitGroup(status),
+            SchedEvent::ForkedChild => {
+                runq.clear();
+                blocked.clear();
+                spawned.clear();
+                state = cur.sched_state();
+                runq.push_back(cur);
+                continue;
+            }
+        }
+        runq.extend(spawned.drain(..));
+        // wakeups are collected in the order threads went to sleep
+        let mut st = state.lock();
+        st.advance(ran);
+        let mut i = 0;
+        while i < blocked.len() {
+            if let Some(interrupted) = st.take_woken(blocked[i].sched_tid()) {
+                let mut t = blocked.remove(i);
+                if interrupted {
+                    t.futex_interrupted();
+                }
+                runq.push_back(t);
+            } else {
+                i += 1;
+            }
+        }
+        for tid in st.expire_due() {
+            if let Some(idx) = blocked.iter().position(|t| t.sched_tid() == tid) {
+                let mut t = blocked.remove(idx);
+                t.futex_timed_out();
+                runq.push_back(t);
+            }
+        }
+    }
+}
+
+struct Queued<T> {
+    t: Box<T>,
+    /// when it was last put in a queue
+    since: Instant,
+    last_worker: Option<usize>,
+}
+impl<T> Queued<T> {
+    fn new(t: Box<T>) -> Queued<T> {
+        Queued { t, since: Instant::now(), last_worker: None }
+    }
+}
+struct PoolQueues<T> {
+    /// one per worker, threads a worker spawned, woke or ran last go into its own
+    queues: Vec<VecDeque<Queued<T>>>,
+    blocked: Vec<Queued<T>>,
+    /// threads that haven't exited: queued, blocked or running
+    live: usize,
+    /// workers waiting for something to run
+    idle: usize,
+    /// how the run ended, once a thread called exit_group() or they're all stuck
+    exit: Option<SchedExit>,
+    /// per worker, its host thread while it runs a slice
+    running: Vec<Option<pthread_t>>,
+}
+struct Pool<T> {
+    // only taken between slices, so one lock for all of it is fine
+    q: Mutex<PoolQueues<T>>,
+    cv: Condvar,
+    state: Arc<Mutex<SchedState>>,
+    workers: usize,
+}
+/// Runs the guest threads on `state.workers` host threads (this one and the rest spawned), until
+/// they have all exited, one called exit_group() or they're all stuck
+pub fn run_pooled<T: DetThread + Send + 'static>(first: Box<T>, state: Arc<Mutex<SchedState>>) -> SchedExit {
+    let workers = state.lock().workers;
+    let mut queues: Vec<VecDeque<Queued<T>>> = (0..workers).map(|_| VecDeque::new()).collect();
+    queues[0].push_back(Queued::new(first));
+    let pool = Arc::new(Pool {
+        q: Mutex::new(PoolQueues { queues, blocked: vec![], live: 1, idle: 0, exit: None, running: vec![None; workers] }),
+        cv: Condvar::new(),
+        state,
+        workers,
+    });
+    let handles: Vec<_> = (1..workers).map(|w| {
+        let pool = pool.clone();
+        std::thread::Builder::new()
+            .name(format!("sched-worker-{}", w))
+            .spawn(move || {
+                if let Some(t) = pool_worker(pool, w) {
+                    // the child's only host thread, there's no caller to go back to. exit() would
+                    // flush stdio, whose lock another worker may have held when we forked
+                    let state = t.sched_state();
+                    let status = match run_pooled(t, state) {
+                        SchedExit::Done => 0,
+                        SchedExit::ExitGroup(status) => status,
+                        SchedExit::Deadlock => 1,
+                    };
+                    unsafe { libc::_exit(status) }
+                }
+            })
+            .unwrap()
+    }).collect();
+    if let Some(t) = pool_worker(pool.clone(), 0) {
+        // we're a forked child. The other workers and the threads they had stayed in the parent,
+        // there's nothing to join, and dropping the pool could take locks nobody lets go of
+        std::mem::forget(handles);
+        std::mem::forget(pool);
+        let state = t.sched_state();
+        return run_pooled(t, state);
+    }
+    for h in handles {
+        let _ = h.join();
+    }
+    let exit = pool.q.lock().exit.unwrap_or(SchedExit::Done);
+    exit
+}
+// longest other queue, from the back so its owner keeps the order of the front
+fn steal<T>(queues: &mut [VecDeque<Queued<T>>], me: usize) -> Option<Queued<T>> {
+    let victim = (0..queues.len()).filter(|w| *w != me).max_by_key(|w| queues[*w].len())?;
+    queues[victim].pop_back()
+}
+// Blocked threads that were woken or whose timeout passed go to `me`'s queue, true if there were any
+fn wake_blocked<T: DetThread>(pool: &Pool<T>, q: &mut PoolQueues<T>, me: usize) -> bool {
+    let now = Instant::now();
+    let mut st = pool.state.lock();
+    let mut more = false;
+    let mut i = 0;
+    while i < q.blocked.len() {
+        let tid = q.blocked[i].t.sched_tid();
+        let woken = st.take_woken(tid);
+        let timed_out = woken.is_none() && st.take_timed_out(tid, now);
+        if woken.is_some() || timed_out {
+            let mut t = q.blocked.remove(i);
+            if timed_out {
+                t.t.futex_timed_out();
+            }
+            if woken == Some(true) {
+                t.t.futex_interrupted();
+            }
+            t.since = now;
+            q.queues[me].push_back(t);
+            more = true;
+        } else {
+            i += 1;
+        }
+    }
+    more
+}
+extern "C" fn interrupt_handler(_: c_int) {}
+// Workers stuck in a blocking syscall would hold up the join at the end of run_pooled. A signal
+// without SA_RESTART makes the syscall fail with EINTR, which ends their slice. It's sent again
+// until they're all back, one that only got to its syscall after the signal would sleep through it
+fn interrupt_workers<T>(pool: &Pool<T>, me: usize) {
+    static HANDLER: Once = Once::new();
+    HANDLER.call_once(|| unsafe {
+        let mut act: libc::sigaction = std::mem::zeroed();
+        act.sa_sigaction = interrupt_handler as extern "C" fn(c_int) as libc::sighandler_t;
+        libc::sigaction(SIGRTMIN(), &act, std::ptr::null_mut());
+    });
+    pool.state.lock().cancel_waits();
+    loop {
+        {
+            // with the lock held, so none of them can get back, leave and be joined in between
+            let q = pool.q.lock();
+            let busy: Vec<pthread_t> = q.running.iter().enumerate()
+                .filter(|(w, _)| *w != me)
+                .filter_map(|(_, h)| *h)
+                .collect();
+            if busy.is_empty() {
+                return;
+            }
+            for h in busy {
+                unsafe {
+                    libc::pthread_kill(h, SIGRTMIN());
+                }
+            }
+        }
+        std::thread::sleep(IDLE_WAIT);
+    }
+}
+/// Some(thread) if it called fork() and this is the child, which has to run it in a pool of its own
+fn pool_worker<T: DetThread + Send + 'static>(pool: Arc<Pool<T>>, me: usize) -> Option<Box<T>> {
+    let mut spawned: Vec<Box<T>> = Vec::new();
+    let mut q = pool.q.lock();
+    loop {
+        if q.live == 0 {
+            pool.cv.notify_all();
+            return None;
+        }
+        wake_blocked(&pool, &mut q, me);
+        let next = match q.queues[me].pop_front() {
+            Some(t) => Some(t),
+            None => steal(&mut q.queues, me),
+        };
+        let mut cur = match next {
+            Some(t) => t,
+            None => {
+                q.idle += 1;
+                let next_timeout = pool.state.lock().next_wall_deadline();
+                if q.idle == pool.workers && !q.blocked.is_empty() && next_timeout.is_none() {
+                    error!("Pooled scheduler: all {} guest threads are blocked on futexes", q.blocked.len());
+                    q.exit = Some(SchedExit::Deadlock);
+                    q.live = 0;
+                    pool.cv.notify_all();
+                    return None;
+                }
+                // up to the next timeout, so it's seen even when nothing else happens
+                let wait = next_timeout.map_or(IDLE_WAIT, |d| d.saturating_duration_since(Instant::now()).min(IDLE_WAIT));
+                q = pool.cv.wait_timeout(q, wait).0;
+                q.idle -= 1;
+                continue;
+            }
+        };
+        q.running[me] = Some(unsafe { libc::pthread_self() });
+        drop(q);
+        let waited = cur.since.elapsed();
+        let before = cur.t.sched_icount();
+        let ev = cur.t.run_slice(&mut spawned);
+        let tid = cur.t.sched_tid();
+        if ev == SchedEvent::ForkedChild {
+            // we're the child, and the other workers stayed in the parent. One of them may have
+            // held the pool's locks when we forked, so nothing of the parent's pool is touched
+            return Some(cur.t);
+        }
+        q = pool.q.lock();
+        q.running[me] = None;
+        if q.exit.is_some() {
+            // another worker's thread called exit_group() while this one ran
+            return None;
+        }
+        {
+            let mut st = pool.state.lock();
+            let share = st.shares.entry(tid).or_default();
+            share.slices += 1;
+            share.instructions += cur.t.sched_icount().wrapping_sub(before);
+            share.wait_us += waited.as_micros() as u64;
+            if cur.last_worker.map_or(false, |w| w != me) {
+                share.migrations += 1;
+            }
+        }
+        cur.last_worker = Some(me);
+        cur.since = Instant::now();
+        match ev {
+            SchedEvent::Yield => q.queues[me].push_back(cur),
+            // the wakeup (or the timeout) may have come before we got here, wake_blocked sees to it
+            SchedEvent::FutexWait => q.blocked.push(cur),
+            SchedEvent::Exit => {
+                debug!("Pooled scheduler: thread {:x} exited", tid);
+                q.live -= 1;
+            }
+            SchedEvent::ExitGroup(status) => {
+                // the threads still in the queues are dropped with the pool
+                q.exit = Some(SchedExit::ExitGroup(status));
+                q.live = 0;
+                pool.cv.notify_all();
+                drop(q);
+                interrupt_workers(&pool, me);
+                return None;
+            }
+            SchedEvent::ForkedChild => unreachable!(),
+        }
+        let mut more = !spawned.is_empty();
+        q.live += spawned.len();
+        for t in spawned.drain(..) {
+            q.queues[me].push_back(Queued::new(t));
+        }
+        more |= wake_blocked(&pool, &mut q, me);
+        if more || q.queues[me].len() > 1 {
+            pool.cv.notify_all();
+        }
+    }
+}
@@ -3,7 +3,8 @@ use std::mem;
 use std::ops::Range;
 use std::ptr::null_mut;
 use std::sync::{Arc, Mutex};
-use base::block_signal;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use base::{block_signal, debug};
 use base::platform::kill;
 use libc::{c_int, sigaddset, SIGHUP, SIGCHLD, SIGINT, sigset_t, SIGTERM, SIGALRM, SIGPIPE,
            SIGKILL, SIGSEGV, stat, SIGFPE, SIGABRT, SIGQUIT, SIGILL, sigaction, SA_NOCLDSTOP,
@@ -16,7 +17,7 @@ use num::Integer;
 use crate::common::memory::{flat_mem, MemEndian};
 use crate::elf::{MachineType, UserModeRuntime};
 use crate::linux_usermode::defs::{read32_advance_ptr, read64_advance_ptr, SIG_FIRST_INVALID, SigConstants};
-use crate::linux_usermode::main::{generic_error_handle, SyscallIn, SyscallOut, UsermodeCpu};
+use crate::linux_usermode::main::{errno_out, generic_error_handle, SyscallIn, SyscallOut, UsermodeCpu};
 
 #[derive(Copy, Clone)]
 pub struct SigEntry {
@@ -40,7 +41,8 @@ impl Default for SigEntry {
 }
 pub struct SigInfo {
     pub old_masks: Vec<sigset_t>,
-    pub entry: [SigEntry; 64], // by guest
+    /// handlers by guest signal, shared by the threads of a CLONE_SIGHAND group
+    pub actions: Arc<Mutex<[SigEntry; 64]>>,
     pub use_idx: Option<usize>,
     pub use_sig: Option<SiginfoWrapper>,
     pub ss_sp: u64,
@@ -75,7 +77,7 @@ pub fn on_sig_stack(sp: u64, si: &SigInfo) -> bool {
     }
 }
 pub fn target_sigsp(sp: u64, sig_idx: usize, si: &SigInfo) -> u64 {
-    let is_onstack_f_set = si.cnsts.check_host_flag_set(si.entry[sig_idx].flags, SA_ONSTACK);
+    let is_onstack_f_set = si.cnsts.check_host_flag_set(si.action(sig_idx).flags, SA_ONSTACK);
     if is_onstack_f_set && (sas_ss_flags(sp, si) == 0){
         si.ss_sp + si.ss_size
     } else {
@@ -86,7 +88,7 @@ impl SigInfo {
     pub fn new() -> SigInfo {
         SigInfo {
             old_masks: Vec::new(),
-            entry: [Default::default(); 64],
+            actions: Arc::new(Mutex::new([Default::default(); 64])),
             use_idx: None,
             use_sig: None,
             ss_sp: 0,
@@ -100,6 +102,26 @@ impl SigInfo {
         }
 
     }
+    pub fn action(&self, sig: usize) -> SigEntry {
+        self.actions.lock().unwrap()[sig]
+    }
+    /// For a new thread (or process): same handlers, shared or a copy of them, same mask,
+    /// no alternate stack and nothing pending
+    pub fn for_thread(&self, share_actions: bool) -> SigInfo {
+        let actions = if share_actions {
+            self.actions.clone()
+        } else {
+            Arc::new(Mutex::new(*self.actions.lock().unwrap()))
+        };
+        SigInfo {
+            actions,
+            is_32: self.is_32,
+            cnsts: self.cnsts.clone(),
+            current_ss: self.current_ss,
+            mtype: self.mtype,
+            ..SigInfo::new()
+        }
+    }
 }
 use std::cell::{RefCell};
 use std::collections::HashMap;
@@ -107,16 +129,117 @@ use std::cell::UnsafeCell;
 
 use lazy_static::lazy_static;
 use crate::common::{IS_LITTLE_ENDIAN, place_variable_guest_fmt_64};
-/* lazy_static! {
-    static ref SINFO: Mutex<SigInfo> = Mutex::new(SigInfo::new());
-} */
-//pub static SIGNAL_AVAIL: bool = false;
+/// Signal state of one guest thread: handlers (shared with its CLONE_SIGHAND siblings), alternate
+/// stack, what came in. It lives in the thread's UserModeRuntime rather than in globals, so guests
+/// running side by side in one process (or one after another on the same host thread) never see
+/// each other's handlers or signals
+pub struct GuestSignals {
+    pub info: Mutex<SigInfo>,
+    /// a host signal came in that the guest should see
+    pub pending: AtomicBool,
+    /// the guest is exiting, waits that look at is_pending() give up too
+    cancelled: AtomicBool,
+}
+impl GuestSignals {
+    pub fn new() -> Arc<GuestSignals> {
+        Arc::new(GuestSignals {
+            info: Mutex::new(SigInfo::new()),
+            pending: AtomicBool::new(false),
+            cancelled: AtomicBool::new(false),
+        })
+    }
+    pub fn is_pending(&self) -> bool {
+        self.pending.load(Ordering::SeqCst) || self.cancelled.load(Ordering::SeqCst)
+    }
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+    /// tgkill() of this thread by another one sharing its host thread(s), where there's no host
+    /// thread of its own to send it to. Only signals with a guest handler are queued here (false
+    /// otherwise), the others do the same whichever thread gets them. Like with the host handler
+    /// one waits at a time, one that comes in before the last was taken is merged with it
+    pub fn post_tkill(&self, host_sig: c_int, pid: i32, uid: u32) -> bool {
+        let mut info = self.info.lock().unwrap();
+        let guest = info.cnsts.host_to_guest_sigs.get(host_sig as usize).copied().unwrap_or(0);
+        let handler = info.action(guest as usize).handler_func;
+        if guest == 0 || handler == SIG_DFL as u64 || handler == SIG_IGN as u64 {
+            return false;
+        }
+        if !self.pending.load(Ordering::SeqCst) {
+            let mut sinfo: GenericSiginfo = unsafe { mem::zeroed() };
+            sinfo.si_signo = guest;
+            sinfo.si_code = SI_TKILL;
+            sinfo.aux.kill = GenericSIKill { pid, uid: uid as i32 };
+            info.use_sig = Some(SiginfoWrapper { stype: SigType::UserKill, sinfo });
+            info.use_idx = Some(guest as usize);
+            self.pending.store(true, Ordering::SeqCst);
+        }
+        true
+    }
+    /// Whether a syscall the pending signal cut short goes again once the signal is taken care
+    /// of: it has no handler (the fatal ones never get back to it), or one installed with
+    /// SA_RESTART. Not when the guest is exiting
+    pub fn restarts_syscall(&self) -> bool {
+        if self.cancelled.load(Ordering::SeqCst) {
+            return false;
+        }
+        let info = self.info.lock().unwrap();
+        let sig = match info.use_idx {
+            Some(s) => s,
+            None => return false,
+        };
+        let act = info.action(sig);
+        act.handler_func == SIG_DFL as u64 || act.handler_func == SIG_IGN as u64
+            || info.cnsts.check_host_flag_set(act.flags, SA_RESTART)
+    }
+    /// Signal state for a thread clone() makes. CLONE_SIGHAND threads see each other's
+    /// sigaction()s, without it the child gets a copy. A forked process has its own memory, so
+    /// whatever it shared is its own from then on
+    pub fn for_thread(&self, clone_flags: c_int) -> Arc<GuestSignals> {
+        let info = self.info.lock().unwrap().for_thread(clone_flags & libc::CLONE_SIGHAND != 0);
+        Arc::new(GuestSignals {
+            info: Mutex::new(info),
+            pending: AtomicBool::new(false),
+            cancelled: AtomicBool::new(false),
+        })
+    }
+}
 thread_local! {
-    pub static SINFO: RefCell<SigInfo> = RefCell::new(SigInfo::new());
-    pub static SIGNAL_AVAIL: RefCell<bool> = RefCell::new(false);
-  //  static SINFO: Mutex<SigInfo> = Mutex::new(SigInfo::new());
+    // Host signal handlers are process-wide and get no context, the host thread a signal landed
+    // on is the only way to tell which guest thread it's for. So this holds nothing but that link,
+    // and only while a guest runs on the thread (see SignalBinding)
+    static BOUND: RefCell<Option<Arc<GuestSignals>>> = RefCell::new(None);
+}
+fn set_bound(to: Option<Arc<GuestSignals>>) -> Option<Arc<GuestSignals>> {
+    BOUND.with(|b| {
+        // the handler looks at it too
+        let sseg = block_all_signals();
+        let was = b.replace(to);
+        set_mask_block(sseg);
+        was
+    })
+}
+/// Host signals landing on this host thread go to a guest thread for as long as this lives. The
+/// binding from before comes back when it's dropped (or unwound), so nothing of a guest that
+/// stopped running here stays behind for the next one
+pub struct SignalBinding {
+    prev: Option<Option<Arc<GuestSignals>>>,
+}
+impl Drop for SignalBinding {
+    fn drop(&mut self) {
+        if let Some(prev) = self.prev.take() {
+            set_bound(prev);
+        }
+    }
+}
+/// Send host signals that land on this host thread to `sig`. Cheap when it's already bound
+pub fn bind_thread_signals(sig: &Arc<GuestSignals>) -> SignalBinding {
+    let same = BOUND.with(|b| matches!(&*b.borrow(), Some(cur) if Arc::ptr_eq(cur, sig)));
+    if same {
+        return SignalBinding { prev: None };
+    }
+    SignalBinding { prev: Some(set_bound(Some(sig.clone()))) }
 }
-// static mut SINFO: Arc<Option<Mutex<SigInfo>>> = Arc::new(None);
 
 #[derive(Copy, Clone,Default)]
 pub struct Sigmask {
@@ -250,17 +373,66 @@ pub fn set_mask_block(s: sigset_t) {
         }
     }
 }
+// Fatal host signals caught with generic_handler while the guest still has them at the default,
+// so dying of one goes through the run loop (which writes the run summary) instead of the
+// kernel killing us on the spot. Host dispositions are process-wide, so this is too
+static CAUGHT_DEFAULTS: AtomicU64 = AtomicU64::new(0);
+const CATCHABLE_FATAL: [c_int; 12] = [SIGHUP, SIGINT, SIGQUIT, SIGABRT, libc::SIGUSR1, libc::SIGUSR2, SIGPIPE,
+    SIGALRM, SIGTERM, libc::SIGXCPU, libc::SIGXFSZ, libc::SIGVTALRM];
+/// For when something has to be written before the process dies of a signal
+pub fn catch_fatal_defaults() {
+    for sig in CATCHABLE_FATAL {
+        unsafe {
+            let mut old: sigaction = mem::zeroed();
+            // ignored ones (nohup, a shell) stay ignored
+            if sigaction(sig, null_mut(), &mut old) < 0 || old.sa_sigaction != SIG_DFL {
+                continue;
+            }
+            let mut act: sigaction = mem::zeroed();
+            act.sa_flags = SA_SIGINFO | SA_RESTART;
+            act.sa_sigaction = generic_handler as sighandler_t;
+            if sigaction(sig, &act, null_mut()) == 0 {
+                CAUGHT_DEFAULTS.fetch_or(1 << sig, Ordering::SeqCst);
+            }
+        }
+    }
+}
+/// Default action for a signal we only caught for catch_fatal_defaults
+pub fn die_of_signal(sig: c_int) -> ! {
+    unsafe {
+        libc::signal(sig, SIG_DFL);
+        let mut set: sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        sigaddset(&mut set, sig);
+        pthread_sigmask(SIG_UNBLOCK, &set, null_mut());
+        libc::raise(sig);
+        libc::_exit(128 + sig)
+    }
+}
 pub unsafe extern "C" fn generic_handler(sig: c_int, siginfo: *mut siginfo_t, uctx: *mut c_void ) {
     // On host, kernel signal handling is atomic. But here, it's not, so we need to block all signals
     // we can return to mask defined by signal entry as soon are done with signal init.
     // let sseg = block_all_signals(); we block all signals from the get go, neither one of us knows why
     //let mut sunwrapped = unsafe { SINFO };
     // sunwrapped.with_borrow()
-    SINFO.with(|z| {
-        let mut val = z.borrow_mut();
+    BOUND.with(|b| {
+        let b = b.borrow();
+        let caught_default = CAUGHT_DEFAULTS.load(Ordering::SeqCst) & (1 << sig) != 0;
+        let gs = match b.as_ref() {
+            Some(gs) => gs,
+            // not a guest thread, nobody to write anything out
+            None if caught_default => die_of_signal(sig),
+            None => return,
+        };
+        let mut val = gs.info.lock().unwrap();
+        let guestsig = match val.cnsts.host_to_guest_sigs.get(sig as usize) {
+            Some(g) => *g,
+            // a thread that never saw a sigaction() has no handlers
+            None if caught_default => die_of_signal(sig),
+            None => return,
+        };
         val.old_masks.push(block_all_signals()); // we block until we start (or stop) sighandler
         // push so we can handle nested sigs if needed
-        let guestsig = val.cnsts.host_to_guest_sigs[sig as usize];
         // To handle sync symbols (SIGSEIV, SIGBUS) we need to either make use
         // of siglongjmp (undefined on rust) or fiddle with the program counter
         // manually to redirect to arch specific code
@@ -275,11 +447,7 @@ pub unsafe extern "C" fn generic_handler(sig: c_int, siginfo: *mut siginfo_t, uc
         }
         val.use_sig = Some(gensinfo);
         val.use_idx = Some(guestsig as usize);
-        SIGNAL_AVAIL.with(|z| {
-            let mut val = z.borrow_mut();
-            *val = true;
-        });
-
+        gs.pending.store(true, Ordering::SeqCst);
     });
     //sunwrapped.unwrap().entry
     //let mut val = unsafe { sunwrapped.with() };
@@ -335,6 +503,15 @@ pub enum SigType {
 pub const SI_USER: i32 = 0;
 pub const SI_TKILL: i32 = -6;
 
+// si_status is an exit code for CLD_EXITED and a signal number otherwise
+pub(crate) fn sigchld_status(cnsts: &SigConstants, code: i32, status: i32) -> i32 {
+    if code == CLD_EXITED {
+        status
+    } else {
+        let vallower = cnsts.host_to_guest_sigs[(status & 0x7f) as usize];
+        vallower | (status & !0x7f)
+    }
+}
 fn cvt_host_to_guest_siginfo(cnsts: &SigConstants, host_siginfo: siginfo_t, is_32bit_guest: bool) -> SiginfoWrapper {
     let mut gen: GenericSiginfo = unsafe { mem::zeroed() };
     let hostsig = host_siginfo.si_signo;
@@ -354,22 +531,23 @@ fn cvt_host_to_guest_siginfo(cnsts: &SigConstants, host_siginfo: siginfo_t, is_3
                 SIGCHLD => {
                     stype = SigType::Sigchld;
                     if is_32bit_guest {
-                        unimplemented!();
+                        gen.aux.sigchld32.pid = host_siginfo.si_pid();
+                        gen.aux.sigchld32.uid = host_siginfo.si_uid() as i32;
+                        gen.aux.sigchld32.utime = host_siginfo.si_utime() as i32;
+                        gen.aux.sigchld32.stime = host_siginfo.si_stime() as i32;
+                        gen.aux.sigchld32.status = sigchld_status(cnsts, gen.si_code, host_siginfo.si_status());
                     } else {
                         gen.aux.sigchld64.pid = host_siginfo.si_pid();
                         gen.aux.sigchld64.uid = host_siginfo.si_uid() as i32;
                         gen.aux.sigchld64.utime = host_siginfo.si_utime();
                         gen.aux.sigchld64.stime = host_siginfo.si_stime();
-                        if gen.si_code == CLD_EXITED {
-                            gen.aux.sigchld64.status = host_siginfo.si_status();
-                        } else {
-                            let status = host_siginfo.si_status();
-                            let vallower = cnsts.host_to_guest_sigs[(status & 0x7f) as usize];
-                            gen.aux.sigchld64.status = vallower | (status & !0x7f);
-                        }
+                        gen.aux.sigchld64.status = sigchld_status(cnsts, gen.si_code, host_siginfo.si_status());
 
                     }
                 },
+                SIGTTIN | SIGTTOU | SIGTSTP | SIGCONT | SIGWINCH | SIGHUP | SIGINT | SIGQUIT => {
+                    // sent by the tty layer (job control), nothing in the union
+                }
                 _ => {
                     unimplemented!();
                 }
@@ -411,7 +589,7 @@ pub fn inject_signal<T: UsermodeCpu>(cpu: &mut T, sig: i32, si: &mut SigInfo) {
     if sig == 0 {
         panic!();
     }
-    let ent = si.entry[sig as usize];
+    let ent = si.action(sig as usize);
     let realsig = si.cnsts.guest_to_host_sigs[sig as usize];
     if ent.handler_func == SIG_DFL as u64 {
         panic!();
@@ -513,12 +691,18 @@ pub fn u_sigaction<T: UsermodeCpu>(cpu: &mut T, sysin: SyscallIn,
     let oldact = sysin.args[2];
     let mut sout: SyscallOut = Default::default();
     si.cnsts = cpu.get_ume().sigcnst.lock().clone(); // todo: better place to do this
+    let host_sig = match si.cnsts.guest_to_host_sigs.get(signum as usize) {
+        Some(&h) if signum != 0 => h,
+        _ => return errno_out(EINVAL),
+    };
+    if newact != 0 && (host_sig == SIGKILL || host_sig == SIGSTOP) {
+        return errno_out(EINVAL);
+    }
     let sseg = block_all_signals();
     if oldact != 0 {
-        cpu.set_old_sigaction(oldact, si.entry[signum as usize]);
+        cpu.set_old_sigaction(oldact, si.action(signum as usize));
     }
     if newact != 0 {
-        let host_sig = si.cnsts.guest_to_host_sigs[signum as usize];
         if host_sig > SIGRTMAX() {
             sout.ret1 = 0;
             generic_error_handle(&mut sout, -EINVAL);
@@ -527,6 +711,8 @@ pub fn u_sigaction<T: UsermodeCpu>(cpu: &mut T, sysin: SyscallIn,
         }
         let args = cpu.get_sigaction(newact); // same on everywhere?
         if host_sig != SIGSEGV && host_sig != SIGBUS {
+            // it's the guest's now, whatever it set
+            CAUGHT_DEFAULTS.fetch_and(!(1 << host_sig), Ordering::SeqCst);
             let mut hostact: sigaction = unsafe { mem::zeroed() };
             // always use extended handler.
             // Linux puts args in backend anyway regardless of flag
@@ -536,33 +722,31 @@ pub fn u_sigaction<T: UsermodeCpu>(cpu: &mut T, sysin: SyscallIn,
             if si.cnsts.check_host_flag_set(args.flags, SA_RESTART) {
                 hostact.sa_flags |= SA_RESTART;
             }
-            if si.cnsts.check_host_flag_set(args.flags, SA_ONSTACK) {
-                unimplemented!();
-                hostact.sa_flags |= SA_ONSTACK;
-            }
-            if si.cnsts.check_host_flag_set(args.flags, SA_RESETHAND) {
-                unimplemented!();
-                hostact.sa_flags |= SA_RESETHAND; // can we do this here?
-            }
+            // SA_ONSTACK is about where the guest's frame goes (target_sigsp()), and SA_RESETHAND
+            // is done when it's delivered, the host handler stays ours for both
+            // guest children are host children, so these mean the same thing on the host
             if si.cnsts.check_host_flag_set(args.flags, SA_NOCLDSTOP) {
-                unimplemented!();
                 hostact.sa_flags |= SA_NOCLDSTOP;
             }
             if si.cnsts.check_host_flag_set(args.flags, SA_NOCLDWAIT) {
-                unimplemented!();
                 hostact.sa_flags |= SA_NOCLDWAIT;
             }
             if (args.handler == SIG_DFL as u64) && !(fatal_signal(host_sig)){
                 hostact.sa_sigaction = SIG_DFL;
                 // if fatal, we need to use handler
             }
+            if args.handler == SIG_IGN as u64 {
+                // shells ignore SIGTTOU/SIGTTIN/SIGTSTP, these need to be ignored for real
+                // or the host would stop us
+                hostact.sa_sigaction = SIG_IGN;
+            }
             // we don't passthough sa_resethand, sa_restorer,
             // because we take care of that manually
 
             let ret  = unsafe {
                 sigaction(host_sig, &hostact, null_mut())
             };
-            si.entry[signum as usize] = SigEntry {
+            si.actions.lock().unwrap()[signum as usize] = SigEntry {
                 handler_func: args.handler,
                 is_valid: true,
                 maskguest: args.mask,
@@ -572,19 +756,29 @@ pub fn u_sigaction<T: UsermodeCpu>(cpu: &mut T, sysin: SyscallIn,
 
             generic_error_handle(&mut sout, ret);
         } else {
-            panic!();
+            // faults are raised on the host thread in the middle of an instruction, a guest
+            // handler can't run from there. The call works, the fault still kills the guest
+            debug!("guest handler for signal {} is never called", host_sig);
         }
     }
     set_mask_block(sseg);
     sout
 }
-pub fn u_sigaltstack<T: UsermodeCpu>(cpu: &mut T, sysin: SyscallIn, umr: &mut UserModeRuntime) -> SyscallOut {
+/// After a guest handler was set up for `sig`: SA_RESETHAND puts the default back
+pub fn reset_delivered(si: &SigInfo, sig: usize) {
+    let mut actions = si.actions.lock().unwrap();
+    if si.cnsts.check_host_flag_set(actions[sig].flags, SA_RESETHAND) {
+        actions[sig] = SigEntry { is_valid: true, ..Default::default() };
+    }
+}
+pub fn u_sigaltstack<T: UsermodeCpu>(cpu: &mut T, sysin: SyscallIn) -> SyscallOut {
     let ss = sysin.args[0];
     let old_ss = sysin.args[1];
     let mut sout: SyscallOut = Default::default();
     let sseg = block_all_signals();
-    SINFO.with(|z| {
-        let mut val = z.borrow_mut();
+    {
+        let sigs = cpu.get_ume().signals.clone();
+        let mut val = sigs.info.lock().unwrap();
         if old_ss != 0 {
             cpu.set_altstack(old_ss, &val);
         }
@@ -619,7 +813,7 @@ pub fn u_sigaltstack<T: UsermodeCpu>(cpu: &mut T, sysin: SyscallIn, umr: &mut Us
         }
         set_mask_block(sseg);
         sout
-    })
+    }
     // let mut val = unsafe { sunwrapped.unwrap() };
 
 }
@@ -0,0 +1,108 @@
+// Building things on the guest stack: the initial process stack the ELF loaders set up (strings,
+// argv, envp, auxv) and signal frames. It all goes through the UsermodeCpu stack helpers, so word
+// size and byte order come from the guest, and the arch code only has to supply what's its own
+// (its auxv entries, its frame layout).
+use std::ffi::CString;
+use base::debug;
+use crate::common::memory::MemEndian;
+use crate::elf::{Auxv, UserModeRuntime};
+use crate::linux_usermode::main::UsermodeCpu;
+use crate::linux_usermode::signals::{on_sig_stack, target_sigsp, SigInfo};
+
+#[derive(Debug, thiserror::Error)]
+pub enum StackError {
+    #[error("arguments and environment need {0} bytes, more than the guest stack has")]
+    TooBig(u64),
+    #[error("guest stack address 0x{0:x} isn't mapped")]
+    Fault(u64),
+}
+
+/// Bytes in a guest word
+pub fn word_size<C: UsermodeCpu + ?Sized>(cpu: &mut C) -> u64 {
+    if cpu.get_ume().is_64 { 8 } else { 4 }
+}
+pub fn guest_endian<C: UsermodeCpu + ?Sized>(cpu: &mut C) -> MemEndian {
+    if cpu.get_ume().is_little_endian { MemEndian::Little } else { MemEndian::Big }
+}
+/// Error if the guest can't write (or read) `len` bytes at `addr` of its stack
+pub(crate) fn check_stack(ume: &UserModeRuntime, addr: u64, len: u64, write: bool) -> Result<(), StackError> {
+    if ume.memusage.accessible(addr, len, write) {
+        Ok(())
+    } else {
+        Err(StackError::Fault(addr))
+    }
+}
+/// Push raw bytes, returns where they went
+pub fn push_bytes<C: UsermodeCpu + ?Sized>(cpu: &mut C, data: &[u8]) -> Result<u64, StackError> {
+    let sp = cpu.get_stack_reg().wrapping_sub(data.len() as u64);
+    check_stack(cpu.get_ume(), sp, data.len() as u64, true)?;
+    cpu.set_stack_reg(sp);
+    cpu.get_ume().mem_access.write_phys_n(sp, data.to_vec()).map_err(|_| StackError::Fault(sp))?;
+    Ok(sp)
+}
+fn push_str<C: UsermodeCpu + ?Sized>(cpu: &mut C, s: &str) -> Result<u64, StackError> {
+    // strings from our own command line, which can't have a nul in them
+    push_bytes(cpu, &CString::new(s.as_bytes()).unwrap().into_bytes_with_nul())
+}
+/// Lay out the initial process stack the way the kernel does: strings at the top, then argc,
+/// argv, envp and the auxv pairs going up from sp, which ends up 16 byte aligned at argc.
+/// `auxv` should have its AT_NULL already
+pub fn build_initial_stack<C: UsermodeCpu + ?Sized>(cpu: &mut C, args: &[String], envp: &[String], auxv: &[Auxv]) -> Result<(), StackError> {
+    let word = word_size(cpu);
+    let strings: u64 = args.iter().chain(envp.iter()).map(|s| s.len() as u64 + 1).sum();
+    let words = (1 + args.len() + 1 + envp.len() + 1 + auxv.len() * 2) as u64;
+    let need = strings + words * word + 15;
+    let sp = cpu.get_stack_reg();
+    let limit = {
+        let ms = cpu.get_ume().memstate.lock();
+        ms.stack_base - ms.stack_size
+    };
+    if sp < limit || sp - limit < need {
+        return Err(StackError::TooBig(need));
+    }
+    let bottom = (sp - strings - words * word) & !15;
+    let mut env_ptrs = Vec::with_capacity(envp.len());
+    for e in envp {
+        env_ptrs.push(push_str(cpu, e)?);
+    }
+    let mut arg_ptrs = Vec::with_capacity(args.len());
+    for a in args {
+        arg_ptrs.push(push_str(cpu, a)?);
+    }
+    cpu.set_stack_reg(bottom + words * word);
+    for a in auxv.iter().rev() {
+        cpu.push_stack_natural(a.value)?;
+        cpu.push_stack_natural(a.typ as u64)?;
+    }
+    cpu.push_stack_natural(0)?;
+    for p in env_ptrs.iter().rev() {
+        cpu.push_stack_natural(*p)?;
+    }
+    cpu.push_stack_natural(0)?;
+    for p in arg_ptrs.iter().rev() {
+        cpu.push_stack_natural(*p)?;
+    }
+    cpu.push_stack_natural(args.len() as u64)?;
+    debug!("initial stack at 0x{:x}: {} args, {} env vars, {} auxv entries", bottom, args.len(), envp.len(), auxv.len());
+    Ok(())
+}
+/// Where a signal frame of `size` bytes goes: on the alternate stack if the handler for `sig`
+/// asked for it, below sp otherwise. None if it would run off the end of the alternate stack
+pub fn signal_frame_addr(sp: u64, size: u64, sig: i32, si: &SigInfo) -> Option<u64> {
+    if on_sig_stack(sp, si) && !on_sig_stack(sp.wrapping_sub(size), si) {
+        return None;
+    }
+    Some(target_sigsp(sp, sig as usize, si).checked_sub(size)? & !0xf)
+}
+/// Put the (repr(C), already in guest layout) `frame` on the stack for `sig` and point sp at it.
+/// Returns its address, None if it doesn't fit or the stack there isn't mapped (the caller
+/// forces a SIGSEGV then, like the kernel)
+pub fn push_signal_frame<C: UsermodeCpu + ?Sized, T: Copy>(cpu: &mut C, sig: i32, si: &SigInfo, frame: &T) -> Option<u64> {
+    let size = std::mem::size_of::<T>();
+    let addr = signal_frame_addr(cpu.get_stack_reg(), size as u64, sig, si)?;
+    check_stack(cpu.get_ume(), addr, size as u64, true).ok()?;
+    let bytes = unsafe { std::slice::from_raw_parts(frame as *const T as *const u8, size) };
+    cpu.get_ume().mem_access.write_phys_n(addr, bytes.to_vec()).ok()?;
+    cpu.set_stack_reg(addr);
+    Some(addr)
+}
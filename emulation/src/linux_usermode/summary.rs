@@ -0,0 +1,172 @@
+// Machine readable summary of a run, written as JSON when the guest exits, for CI harnesses and
+// scripts that would otherwise have to scrape the log.
+// Only the process the run started in writes it: forked children have their own exit codes that
+// the guest itself collects. A guest execve() of a guest binary passes the destination on to the
+// new emulator, which then writes it instead (with its own instruction count and wall time).
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+use base::warn;
+use serde::Serialize;
+use sync::Mutex;
+use crate::common::hart_stats::{COUNTERS, TOP_TRAP_SITES};
+use crate::elf::UserModeRuntime;
+use crate::linux_usermode::main::SyscallType;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RunExit {
+    /// exit_group() with this status
+    Exit(i32),
+    /// killed by this (host) signal
+    Signal(i32),
+    /// went over the memory limit with the kill policy
+    OutOfMemory,
+    /// every guest thread was blocked on a futex, nothing left to wake them
+    Deadlock,
+}
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TrapKind {
+    Syscall(SyscallType),
+    AccessFault,
+}
+#[derive(Serialize)]
+pub struct RunSummary {
+    pub exit_reason: &'static str,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub instructions: Option<u64>,
+    pub wall_time_secs: f64,
+    /// committed, peak_committed, mapped and resident bytes
+    pub memory: BTreeMap<&'static str, u64>,
+    /// memory access and cache counters summed over all harts, with --stats
+    pub stats: BTreeMap<&'static str, u64>,
+    /// per device counters; in usermode the "devices" are the emulator's own I/O layers
+    pub devices: BTreeMap<&'static str, BTreeMap<&'static str, u64>>,
+    pub traps: BTreeMap<String, u64>,
+    /// the guest instructions that faulted most, with --stats
+    pub trap_sites: Vec<TrapSiteSummary>,
+    pub snapshots: Vec<String>,
+    /// per guest thread id: slices, instructions, migrations and wait_us, with --sched-workers
+    pub sched: BTreeMap<String, BTreeMap<&'static str, u64>>,
+}
+#[derive(Serialize)]
+pub struct TrapSiteSummary {
+    pub pc: u64,
+    /// "function+0x10" when there's a symbol for it
+    pub site: String,
+    pub cause: &'static str,
+    pub count: u64,
+}
+pub struct SummaryRecorder {
+    /// file path, or "-" for stdout
+    dest: String,
+    owner: libc::pid_t,
+    start: Instant,
+    traps: Mutex<HashMap<TrapKind, u64>>,
+    snapshots: Mutex<Vec<String>>,
+    /// several ways out can see the same death (a raise() that comes back as a signal)
+    written: AtomicBool,
+}
+impl SummaryRecorder {
+    pub fn new(dest: String) -> SummaryRecorder {
+        SummaryRecorder {
+            dest,
+            owner: unsafe { libc::getpid() },
+            start: Instant::now(),
+            traps: Mutex::new(HashMap::new()),
+            snapshots: Mutex::new(Vec::new()),
+            written: AtomicBool::new(false),
+        }
+    }
+    pub fn count_trap(&self, kind: TrapKind) {
+        *self.traps.lock().entry(kind).or_insert(0) += 1;
+    }
+    pub fn add_snapshot(&self, path: String) {
+        self.snapshots.lock().push(path);
+    }
+    fn build(&self, ume: &UserModeRuntime, exit: RunExit) -> RunSummary {
+        let mut devices = BTreeMap::new();
+        if let Some(fi) = &ume.opts.faults {
+            let s = &fi.stats;
+            devices.insert("fault_inject", BTreeMap::from([
+                ("mem_faults", s.mem_faults.load(Ordering::Relaxed)),
+                ("bit_flips", s.bit_flips.load(Ordering::Relaxed)),
+                ("failed_requests", s.failed_requests.load(Ordering::Relaxed)),
+            ]));
+        }
+        let traps = self.traps.lock().iter().map(|(k, n)| {
+            let name = match k {
+                TrapKind::Syscall(s) => format!("syscall.{:?}", s),
+                TrapKind::AccessFault => "access_fault".to_string(),
+            };
+            (name, *n)
+        }).collect();
+        let (exit_reason, exit_code, signal) = match exit {
+            RunExit::Exit(c) => ("exit", Some(c), None),
+            RunExit::Signal(s) => ("signal", None, Some(s)),
+            // the OOM killer's signal
+            RunExit::OutOfMemory => ("oom", None, Some(libc::SIGKILL)),
+            RunExit::Deadlock => ("deadlock", Some(1), None),
+        };
+        let mem = &ume.memusage;
+        RunSummary {
+            exit_reason,
+            exit_code,
+            signal,
+            // only counted when the cpu counts instructions
+            instructions: ume.cpu_slot.as_ref().map(|_| ume.cputime.process_insns()),
+            wall_time_secs: self.start.elapsed().as_secs_f64(),
+            memory: BTreeMap::from([
+                ("committed", mem.committed()),
+                ("peak_committed", mem.peak_committed()),
+                ("mapped", mem.mapped()),
+                ("resident", mem.resident()),
+            ]),
+            stats: ume.stats.as_ref().map(|r| {
+                let totals = r.totals();
+                COUNTERS.iter().map(|c| (c.name(), totals[*c as usize])).collect()
+            }).unwrap_or_default(),
+            devices,
+            traps,
+            trap_sites: ume.stats.as_ref().map(|r| {
+                r.top_trap_sites(TOP_TRAP_SITES).into_iter().map(|t| TrapSiteSummary {
+                    pc: t.pc,
+                    site: ume.describe_pc(t.pc),
+                    cause: t.cause.name(),
+                    count: t.count,
+                }).collect()
+            }).unwrap_or_default(),
+            snapshots: self.snapshots.lock().clone(),
+            sched: ume.sched.as_ref().map(|st| {
+                st.lock().shares.iter().map(|(tid, sh)| (tid.to_string(), BTreeMap::from([
+                    ("slices", sh.slices),
+                    ("instructions", sh.instructions),
+                    ("migrations", sh.migrations),
+                    ("wait_us", sh.wait_us),
+                ]))).collect()
+            }).unwrap_or_default(),
+        }
+    }
+    /// Write the summary, if this is the process that should and it hasn't yet
+    pub fn finish(&self, ume: &UserModeRuntime, exit: RunExit) {
+        if unsafe { libc::getpid() } != self.owner || self.written.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let json = match serde_json::to_string_pretty(&self.build(ume, exit)) {
+            Ok(j) => j + "\n",
+            Err(e) => {
+                warn!("Couldn't serialize the run summary: {}", e);
+                return;
+            }
+        };
+        let res = if self.dest == "-" {
+            std::io::stdout().lock().write_all(json.as_bytes())
+        } else {
+            std::fs::write(&self.dest, json)
+        };
+        if let Err(e) = res {
+            warn!("Couldn't write the run summary to {}: {}", self.dest, e);
+        }
+    }
+}
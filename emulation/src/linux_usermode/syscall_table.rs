@@ -0,0 +1,22 @@
+// Guest syscall numbers to SyscallType. The tables are generated by build.rs from
+// syscalls/syscall.tbl (the kernel's numbering, per abi) and syscalls/emulated.txt (which kernel
+// syscall is which SyscallType), so a new frontend only has to list the abis its kernel has.
+use crate::linux_usermode::main::SyscallType;
+
+pub struct SyscallTable {
+    /// indexed by syscall number
+    pub calls: &'static [Option<SyscallType>],
+    /// kernel names, "" where the numbering has a hole
+    pub names: &'static [&'static str],
+}
+impl SyscallTable {
+    pub fn translate(&self, nr: u64) -> Option<SyscallType> {
+        self.calls.get(nr as usize).copied().flatten()
+    }
+    /// What the kernel calls `nr`, None if there's no such syscall
+    pub fn name(&self, nr: u64) -> Option<&'static str> {
+        self.names.get(nr as usize).copied().filter(|n| !n.is_empty())
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/syscall_tables.rs"));
@@ -0,0 +1,235 @@
+// I/O throttling and latency injection for guest file and socket I/O.
+// In usermode the guest's "devices" are host files and sockets, so limits are set per class:
+// storage (regular files and block devices) and network (sockets). Ttys and pipes are left alone.
+// Each class can have an IOPS limit, a bandwidth limit and extra latency per request, with
+// optional jitter and random spikes for bursty behavior. The embedder keeps an Arc of the
+// IoThrottle and can change the settings while the guest runs, and so can the "throttle"
+// monitor command.
+use std::mem::MaybeUninit;
+use std::time::{Duration, Instant};
+use libc::c_int;
+use sync::Mutex;
+
+/// How far behind the limits are allowed to get, so short bursts go at full speed
+const BURST: Duration = Duration::from_millis(100);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum IoClass {
+    Storage,
+    Network,
+}
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ThrottleConfig {
+    pub iops: Option<u64>,
+    pub bytes_per_sec: Option<u64>,
+    /// added to every request
+    pub latency: Duration,
+    /// plus a uniformly random amount up to this
+    pub jitter: Duration,
+    /// and this much more, for spike_pct percent of requests
+    pub spike: Duration,
+    pub spike_pct: u32,
+}
+fn parse_duration(s: &str) -> Option<Duration> {
+    let (num, mul) = if let Some(n) = s.strip_suffix("us") {
+        (n, 1)
+    } else if let Some(n) = s.strip_suffix("ms") {
+        (n, 1000)
+    } else if let Some(n) = s.strip_suffix('s') {
+        (n, 1_000_000)
+    } else {
+        (s, 1000) // plain numbers are ms
+    };
+    Some(Duration::from_micros(num.parse::<u64>().ok()?.checked_mul(mul)?))
+}
+pub(crate) fn parse_size(s: &str) -> Option<u64> {
+    let (num, mul) = match s.chars().last()? {
+        'K' | 'k' => (&s[..s.len() - 1], 1 << 10),
+        'M' | 'm' => (&s[..s.len() - 1], 1 << 20),
+        'G' | 'g' => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    num.parse::<u64>().ok()?.checked_mul(mul)
+}
+impl ThrottleConfig {
+    /// "iops=100,bw=10M,lat=2ms,jitter=5ms,spike=200ms,spike_pct=1"
+    pub fn parse(spec: &str) -> Result<ThrottleConfig, String> {
+        let mut cfg = ThrottleConfig::default();
+        for part in spec.split(',').filter(|p| !p.is_empty()) {
+            let (k, v) = part.split_once('=').ok_or(format!("expected key=value, got {}", part))?;
+            let bad = || format!("bad value for {}: {}", k, v);
+            match k {
+                "iops" => cfg.iops = Some(v.parse().map_err(|_| bad())?),
+                "bw" => cfg.bytes_per_sec = Some(parse_size(v).ok_or_else(bad)?),
+                "lat" => cfg.latency = parse_duration(v).ok_or_else(bad)?,
+                "jitter" => cfg.jitter = parse_duration(v).ok_or_else(bad)?,
+                "spike" => cfg.spike = parse_duration(v).ok_or_else(bad)?,
+                "spike_pct" => cfg.spike_pct = v.parse().map_err(|_| bad())?,
+                _ => return Err(format!("unknown throttle setting {}", k)),
+            }
+        }
+        Ok(cfg)
+    }
+    fn is_off(&self) -> bool {
+        *self == ThrottleConfig::default()
+    }
+    /// Back to the form parse() takes, "off" when nothing is set
+    pub fn spec(&self) -> String {
+        let mut parts = vec![];
+        if let Some(i) = self.iops {
+            parts.push(format!("iops={}", i));
+        }
+        if let Some(b) = self.bytes_per_sec {
+            parts.push(format!("bw={}", b));
+        }
+        for (k, d) in [("lat", self.latency), ("jitter", self.jitter), ("spike", self.spike)] {
+            if !d.is_zero() {
+                parts.push(format!("{}={}us", k, d.as_micros()));
+            }
+        }
+        if self.spike_pct != 0 {
+            parts.push(format!("spike_pct={}", self.spike_pct));
+        }
+        if parts.is_empty() {
+            "off".to_string()
+        } else {
+            parts.join(",")
+        }
+    }
+}
+#[derive(Debug)]
+struct ClassState {
+    cfg: ThrottleConfig,
+    /// when the requests let through so far would be done at the configured rate
+    next_free: Instant,
+}
+impl ClassState {
+    fn new() -> ClassState {
+        ClassState {
+            cfg: ThrottleConfig::default(),
+            next_free: Instant::now(),
+        }
+    }
+}
+#[derive(Debug)]
+struct ThrottleState {
+    storage: ClassState,
+    network: ClassState,
+    rng: u64,
+}
+#[derive(Debug)]
+pub struct IoThrottle {
+    state: Mutex<ThrottleState>,
+}
+impl IoThrottle {
+    pub fn new(seed: u64) -> IoThrottle {
+        IoThrottle {
+            state: Mutex::new(ThrottleState {
+                storage: ClassState::new(),
+                network: ClassState::new(),
+                rng: seed | 1,
+            }),
+        }
+    }
+    pub fn set(&self, class: IoClass, cfg: ThrottleConfig) {
+        let mut st = self.state.lock();
+        let c = st.class(class);
+        c.cfg = cfg;
+        c.next_free = Instant::now();
+    }
+    pub fn get(&self, class: IoClass) -> ThrottleConfig {
+        let mut st = self.state.lock();
+        st.class(class).cfg
+    }
+    /// How long a request of `bytes` that just finished should appear to have taken extra
+    pub fn delay_for(&self, class: IoClass, bytes: u64) -> Duration {
+        self.state.lock().delay_for(class, bytes)
+    }
+}
+impl ThrottleState {
+    fn class(&mut self, class: IoClass) -> &mut ClassState {
+        match class {
+            IoClass::Storage => &mut self.storage,
+            IoClass::Network => &mut self.network,
+        }
+    }
+    fn next_rand(&mut self) -> u64 {
+        // xorshift64, so runs with the same seed get the same delays
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng = x;
+        x
+    }
+    fn delay_for(&mut self, class: IoClass, bytes: u64) -> Duration {
+        let now = Instant::now();
+        let cfg = self.class(class).cfg;
+        if cfg.is_off() {
+            return Duration::ZERO;
+        }
+        let mut cost = Duration::ZERO;
+        if let Some(iops) = cfg.iops.filter(|i| *i > 0) {
+            cost += Duration::from_nanos(1_000_000_000 / iops);
+        }
+        if let Some(bw) = cfg.bytes_per_sec.filter(|b| *b > 0) {
+            cost += Duration::from_nanos((bytes as u128 * 1_000_000_000 / bw as u128) as u64);
+        }
+        let mut extra = cfg.latency;
+        if !cfg.jitter.is_zero() {
+            let r = self.next_rand() % (cfg.jitter.as_nanos() as u64 + 1);
+            extra += Duration::from_nanos(r);
+        }
+        if cfg.spike_pct > 0 && self.next_rand() % 100 < cfg.spike_pct as u64 {
+            extra += cfg.spike;
+        }
+        let st = self.class(class);
+        let earliest = now.checked_sub(BURST).unwrap_or(now);
+        let start = st.next_free.max(earliest);
+        st.next_free = start + cost;
+        st.next_free.saturating_duration_since(now) + extra
+    }
+}
+/// Which class I/O on `fd` belongs to, if any
+pub fn fd_class(fd: c_int) -> Option<IoClass> {
+    let mut st = MaybeUninit::<libc::stat>::zeroed();
+    if unsafe { libc::fstat(fd, st.as_mut_ptr()) } < 0 {
+        return None;
+    }
+    match unsafe { st.assume_init() }.st_mode & libc::S_IFMT {
+        libc::S_IFREG | libc::S_IFBLK => Some(IoClass::Storage),
+        libc::S_IFSOCK => Some(IoClass::Network),
+        _ => None,
+    }
+}
+/// "throttle [storage|net SPEC|off]" from the monitor
+pub fn monitor_cmd(cmd: &str, thr: Option<&IoThrottle>) -> Option<String> {
+    let mut words = cmd.split_whitespace();
+    if words.next() != Some("throttle") {
+        return None;
+    }
+    let thr = match thr {
+        Some(t) => t,
+        None => return Some("throttling is off, start with --throttle-storage or --throttle-net".to_string()),
+    };
+    let class = match words.next() {
+        None => {
+            return Some(format!("storage: {}\nnet: {}", thr.get(IoClass::Storage).spec(), thr.get(IoClass::Network).spec()));
+        }
+        Some("storage") => IoClass::Storage,
+        Some("net") => IoClass::Network,
+        Some(w) => return Some(format!("unknown class {}, use storage or net", w)),
+    };
+    let cfg = match words.next() {
+        None => return Some(thr.get(class).spec()),
+        Some("off") => Ok(ThrottleConfig::default()),
+        Some(spec) => ThrottleConfig::parse(spec),
+    };
+    Some(match cfg {
+        Ok(cfg) => {
+            thr.set(class, cfg);
+            cfg.spec()
+        }
+        Err(e) => e,
+    })
+}
@@ -0,0 +1,299 @@
+// How guest paths end up on the host filesystem.
+// Paths under the sysroot directories (see sysroot_prefixed) can be made read-only, or put under
+// an overlay: the sysroot is the lower layer and is never written, changes go to a per-run
+// scratch directory instead. Files are copied up the first time they are modified and deleted
+// files are hidden with ".wh.<name>" marker files, like aufs does. That way one sysroot can be
+// shared by any number of runs at the same time.
+// With `rootfs` set the sysroot is treated as the guest's whole root directory instead, so every
+// absolute path goes there except the host's /dev, /proc and /sys.
+// Relative paths are made absolute against the guest's view of dirfd or the cwd first, so
+// openat(dirfd, "x") inside the sysroot gets the same treatment as the full path would.
+// Limitations: ".." is taken off lexically without looking at symlinks, and reading a directory
+// that exists in both layers only shows one of them (the upper one).
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use libc::{c_char, c_int, AT_FDCWD, AT_REMOVEDIR, EISDIR, ENOENT, ENOTDIR, ENOTEMPTY, EROFS, O_APPEND, O_CREAT, O_RDWR, O_TRUNC, O_WRONLY};
+use base::debug;
+use crate::linux_usermode::identity::GuestIdentity;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FsMode {
+    /// Guest can write to the sysroot like any other directory
+    Passthrough,
+    /// Any attempt to change the sysroot fails with EROFS
+    ReadOnly,
+    /// Changes to the sysroot go to this directory instead
+    Overlay(PathBuf),
+}
+impl Default for FsMode {
+    fn default() -> Self {
+        FsMode::Passthrough
+    }
+}
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PathIntent {
+    Read,
+    /// change an existing file (write, chmod, truncate...)
+    Modify,
+    /// might create the file
+    Create,
+}
+pub fn open_intent(flags: i32) -> PathIntent {
+    if flags & O_CREAT != 0 {
+        PathIntent::Create
+    } else if flags & (O_WRONLY | O_RDWR | O_TRUNC | O_APPEND) != 0 {
+        PathIntent::Modify
+    } else {
+        PathIntent::Read
+    }
+}
+pub fn sysroot_prefixed(path: &str, rootfs: bool) -> bool {
+    if rootfs {
+        return path.starts_with('/') && !host_only(path);
+    }
+    path.starts_with("/etc")
+        || path.starts_with("/usr")
+        || path.starts_with("/var")
+        || path.starts_with("/lib")
+        || path.starts_with("/sbin")
+}
+/// Stays the host's, even when the guest has its own root
+fn host_only(path: &str) -> bool {
+    ["/dev", "/proc", "/sys"].iter().any(|d| path == *d || path.starts_with(&format!("{}/", d)))
+}
+fn guest_str(ptr: *const c_char) -> String {
+    unsafe {
+        CStr::from_ptr(ptr).to_string_lossy().to_string()
+    }
+}
+/// "." and empty components dropped, ".." taken off lexically. A trailing slash stays, it means
+/// the path has to be a directory
+pub fn normalize_guest(path: &str) -> String {
+    let mut parts: Vec<&str> = vec![];
+    for c in path.split('/') {
+        match c {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            c => parts.push(c),
+        }
+    }
+    let mut norm = format!("/{}", parts.join("/"));
+    if path.ends_with('/') && !parts.is_empty() {
+        norm.push('/');
+    }
+    norm
+}
+/// The guest path of a host path, the overlay's upper directory and the sysroot taken off again
+pub fn guest_view(host: &Path, sysroot: &str, mode: &FsMode) -> String {
+    if let FsMode::Overlay(upperdir) = mode {
+        if let Ok(rest) = host.strip_prefix(upperdir) {
+            return format!("/{}", rest.to_string_lossy());
+        }
+    }
+    if !sysroot.is_empty() {
+        if let Ok(rest) = host.strip_prefix(sysroot) {
+            return format!("/{}", rest.to_string_lossy());
+        }
+    }
+    host.to_string_lossy().to_string()
+}
+/// The directory a relative path given with `dirfd` is relative to, as the guest sees it
+fn guest_dir_of(dirfd: c_int, sysroot: &str, mode: &FsMode) -> Option<String> {
+    let host = if dirfd == AT_FDCWD {
+        std::env::current_dir().ok()?
+    } else {
+        fs::read_link(format!("/proc/self/fd/{}", dirfd)).ok()?
+    };
+    // pipes, sockets and the like aren't directories, the host fails those itself
+    if !host.is_absolute() {
+        return None;
+    }
+    Some(guest_view(&host, sysroot, mode))
+}
+/// The guest path at `ptr`, made absolute and normalized when the mode needs to see where it
+/// really points. Empty paths (AT_EMPTY_PATH) stay empty
+fn absolute_guest(mode: &FsMode, sysroot: &str, rootfs: bool, dirfd: c_int, ptr: *const c_char) -> String {
+    let guest = guest_str(ptr);
+    if *mode == FsMode::Passthrough && !rootfs {
+        return guest;
+    }
+    make_absolute(mode, sysroot, dirfd, guest)
+}
+fn make_absolute(mode: &FsMode, sysroot: &str, dirfd: c_int, guest: String) -> String {
+    if guest.is_empty() {
+        return guest;
+    }
+    if guest.starts_with('/') {
+        return normalize_guest(&guest);
+    }
+    match guest_dir_of(dirfd, sysroot, mode) {
+        Some(dir) => normalize_guest(&format!("{}/{}", dir, guest)),
+        None => guest,
+    }
+}
+fn exists(p: &Path) -> bool {
+    fs::symlink_metadata(p).is_ok()
+}
+fn io_errno(e: std::io::Error) -> c_int {
+    e.raw_os_error().unwrap_or(libc::EIO)
+}
+fn whiteout_of(upper: &Path) -> PathBuf {
+    let name = upper.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    upper.with_file_name(format!(".wh.{}", name))
+}
+struct OverlayPaths {
+    lower: PathBuf,
+    upper: PathBuf,
+    whiteout: PathBuf,
+}
+fn overlay_paths(sysroot: &str, upperdir: &Path, guest: &str) -> OverlayPaths {
+    let lower = PathBuf::from(format!("{}{}", sysroot, guest));
+    let upper = upperdir.join(guest.trim_start_matches('/'));
+    let whiteout = whiteout_of(&upper);
+    OverlayPaths { lower, upper, whiteout }
+}
+fn make_upper_parent(op: &OverlayPaths) -> Result<(), c_int> {
+    if let Some(parent) = op.upper.parent() {
+        fs::create_dir_all(parent).map_err(io_errno)?;
+    }
+    Ok(())
+}
+fn copy_up(op: &OverlayPaths) -> Result<(), c_int> {
+    make_upper_parent(op)?;
+    let md = fs::symlink_metadata(&op.lower).map_err(io_errno)?;
+    debug!("overlay: copying up {:?}", op.lower);
+    if md.file_type().is_symlink() {
+        let target = fs::read_link(&op.lower).map_err(io_errno)?;
+        symlink(target, &op.upper).map_err(io_errno)?;
+    } else if md.is_dir() {
+        fs::create_dir(&op.upper).map_err(io_errno)?;
+        fs::set_permissions(&op.upper, md.permissions()).map_err(io_errno)?;
+    } else {
+        // fs::copy keeps the permission bits
+        fs::copy(&op.lower, &op.upper).map_err(io_errno)?;
+    }
+    Ok(())
+}
+/// Turns the guest path at `ptr` (relative to `dirfd`) into the host path to use, or the errno
+/// to fail with. Files the guest identity fakes go to its view whatever the mode
+pub fn resolve_guest_path(mode: &FsMode, sysroot: &str, rootfs: bool, identity: Option<&GuestIdentity>, dirfd: c_int, ptr: *const c_char, intent: PathIntent) -> Result<CString, c_int> {
+    if let Some(id) = identity {
+        if let Some(view) = id.view_path(&make_absolute(mode, sysroot, dirfd, guest_str(ptr))) {
+            return Ok(CString::new(view.into_os_string().into_vec()).unwrap());
+        }
+    }
+    let guest = absolute_guest(mode, sysroot, rootfs, dirfd, ptr);
+    let in_sysroot = sysroot_prefixed(guest.as_str(), rootfs);
+    let host = match mode {
+        _ if !in_sysroot => guest,
+        FsMode::Passthrough => format!("{}{}", sysroot, guest),
+        FsMode::ReadOnly => {
+            if intent != PathIntent::Read {
+                return Err(EROFS);
+            }
+            format!("{}{}", sysroot, guest)
+        }
+        FsMode::Overlay(upperdir) => {
+            let op = overlay_paths(sysroot, upperdir, guest.as_str());
+            let hidden = exists(&op.whiteout);
+            if hidden {
+                if intent != PathIntent::Create {
+                    return Err(ENOENT);
+                }
+                fs::remove_file(&op.whiteout).map_err(io_errno)?;
+                make_upper_parent(&op)?;
+            } else if !exists(&op.upper) {
+                match intent {
+                    PathIntent::Read => return Ok(CString::new(op.lower.to_string_lossy().as_bytes()).unwrap()),
+                    _ if exists(&op.lower) => copy_up(&op)?,
+                    _ => make_upper_parent(&op)?,
+                }
+            }
+            op.upper.to_string_lossy().to_string()
+        }
+    };
+    Ok(CString::new(host).unwrap())
+}
+/// unlink()/rmdir() of a sysroot path under an overlay. Returns None if the path is not
+/// affected by the overlay and the syscall should just go ahead
+pub fn overlay_remove(mode: &FsMode, sysroot: &str, rootfs: bool, dirfd: c_int, ptr: *const c_char, flags: c_int) -> Option<Result<(), c_int>> {
+    let guest = absolute_guest(mode, sysroot, rootfs, dirfd, ptr);
+    let upperdir = match mode {
+        FsMode::Overlay(u) => u,
+        FsMode::ReadOnly => {
+            return if sysroot_prefixed(guest.as_str(), rootfs) {
+                Some(Err(EROFS))
+            } else {
+                None
+            }
+        }
+        FsMode::Passthrough => return None,
+    };
+    if !sysroot_prefixed(guest.as_str(), rootfs) {
+        return None;
+    }
+    let op = overlay_paths(sysroot, upperdir, guest.as_str());
+    Some(overlay_remove_inner(&op, flags))
+}
+fn overlay_remove_inner(op: &OverlayPaths, flags: c_int) -> Result<(), c_int> {
+    if exists(&op.whiteout) {
+        return Err(ENOENT);
+    }
+    let want_dir = flags & AT_REMOVEDIR != 0;
+    let in_upper = exists(&op.upper);
+    let in_lower = exists(&op.lower);
+    if !in_upper && !in_lower {
+        return Err(ENOENT);
+    }
+    let check = if in_upper { &op.upper } else { &op.lower };
+    let is_dir = fs::symlink_metadata(check).map_err(io_errno)?.is_dir();
+    if want_dir && !is_dir {
+        return Err(ENOTDIR);
+    }
+    if !want_dir && is_dir {
+        return Err(EISDIR);
+    }
+    if want_dir && in_lower && lower_dir_has_visible_entries(op) {
+        return Err(ENOTEMPTY);
+    }
+    if in_upper {
+        let res = if is_dir {
+            // whiteouts left in there from earlier deletes don't count as contents
+            if let Ok(rd) = fs::read_dir(&op.upper) {
+                for ent in rd.flatten() {
+                    if ent.file_name().to_string_lossy().starts_with(".wh.") {
+                        let _ = fs::remove_file(ent.path());
+                    }
+                }
+            }
+            fs::remove_dir(&op.upper)
+        } else {
+            fs::remove_file(&op.upper)
+        };
+        res.map_err(io_errno)?;
+    }
+    if in_lower {
+        make_upper_parent(op)?;
+        fs::File::create(&op.whiteout).map_err(io_errno)?;
+    }
+    Ok(())
+}
+fn lower_dir_has_visible_entries(op: &OverlayPaths) -> bool {
+    let rd = match fs::read_dir(&op.lower) {
+        Ok(r) => r,
+        Err(e) => return e.kind() != ErrorKind::NotFound,
+    };
+    for ent in rd.flatten() {
+        let name = ent.file_name().to_string_lossy().to_string();
+        if !exists(&op.upper.join(format!(".wh.{}", name))) {
+            return true;
+        }
+    }
+    false
+}
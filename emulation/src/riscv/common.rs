@@ -1,3 +1,4 @@
+use crate::common::hart_stats::TrapCause;
 
 #[derive(Debug,Copy, Clone,Eq, PartialEq)]
 pub enum Xlen {
@@ -87,6 +88,17 @@ pub fn get_privilege_mode(encoding: u64) -> Priv {
         _ => panic!("Unknown privilege uncoding")
     }
 }
+/// What --stats counts a trap as, None for the ones that aren't faults
+pub fn fault_trap_cause(e: Exception) -> Option<TrapCause> {
+    match e {
+        Exception::InstructionPageFault | Exception::LoadPageFault | Exception::StorePageFault => Some(TrapCause::PageFault),
+        Exception::InstructionAccessFault | Exception::LoadAccessFault | Exception::StoreAccessFault => Some(TrapCause::AccessFault),
+        Exception::IllegalInstruction => Some(TrapCause::IllegalInstruction),
+        Exception::InstructionAddressMisaligned | Exception::LoadAddressMisaligned
+        | Exception::StoreAddressMisaligned => Some(TrapCause::Misaligned),
+        _ => None,
+    }
+}
 pub fn get_trap_cause(trap: Trap, xlen: Xlen) -> u64 {
     let interrupt_bit = match xlen {
         Xlen::X32 => 0x80000000 as u64,
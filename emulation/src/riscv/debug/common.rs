@@ -1,13 +1,18 @@
 use crate::riscv::interpreter::main::RiscvInt;
+use crate::debug::{DebugEvent, WatchCtx, WatchList, WatchMode};
+use crate::common::memdump::{self, CoreTarget};
+use crate::common::hotpatch;
+use crate::riscv::interpreter::hotpatch::{C_EBREAK, EBREAK, NOP};
+use crate::riscv::common::Xlen;
 cfg_if::cfg_if! {
     if #[cfg(feature = "linux-usermode")] {
-        use crate::riscv::ume::signals::setup_rt_frame;
-        use crate::linux_usermode::signals::{SIGNAL_AVAIL, SINFO};
-        use crate::riscv::common::Exception::EnvironmentCallFromMMode;
+        use crate::riscv::common::Exception::{Breakpoint, EnvironmentCallFromMMode};
     }
 }
 impl RiscvInt {
-    pub fn debug_step(&mut self, bpoints: Vec<u64>) {
+    /// True when it stopped on an ebreak (one patched in, say) instead of running it, which is
+    /// what the debugger gets to see in usermode
+    pub fn debug_step(&mut self, bpoints: Vec<u64>) -> bool {
         loop {
             self.step_one_instr();
             if self.stop_exec || bpoints.contains(&self.pc) { // todo: use pc function
@@ -24,6 +29,13 @@ impl RiscvInt {
                         self.stop_exec = false;
                         self.trap = None;
 
+                    } else if trp.ttype == Breakpoint {
+                        // stays on the ebreak, like after a SIGTRAP on real hardware
+                        self.pc = self.trap_pc;
+                        self.trap = None;
+                        self.want_pc = None;
+                        self.stop_exec = false;
+                        return true;
                     } else {
                         panic!("Protection error  - Suffered RISCV trap in user mode: {:?}", self.trap.unwrap())
                     }
@@ -39,36 +51,179 @@ impl RiscvInt {
                 self.want_pc = None;
                 self.wfi = false;
                 self.stop_exec = false;
-                return;;
+                return false;
             }
 
         }
         #[cfg(feature = "linux-usermode")]
-        {
-            if self.usermode {
-                SIGNAL_AVAIL.with(|z| {
-                    let mut zz = z.borrow_mut();
-                    if *zz == true {
-                        // signal
-                        SINFO.with(|a| {
-                            let mut aa = a.borrow_mut();
-                            let signum = aa.use_idx.unwrap();
-                            setup_rt_frame(self, signum as i32, &mut aa);
-                        });
-                        *zz = false; // we will unblock signals later
-                    }
-                });
-            }
-
-        }
-        if let Some(f) = self.want_pc {
-            // todo: any checks?
-            self.pc = f;
-            self.want_pc = None;
+        if self.usermode {
+            self.deliver_signal();
         }
+        self.apply_want_pc();
         if self.wfi {
             unimplemented!();
         }
         self.stop_exec = false;
+        false
+    }
+}
+const EM_RISCV: u16 = 243;
+impl RiscvInt {
+    /// savemem / loadmem from the monitor. RAM is the guest's readable mappings in usermode
+    pub fn memdump_cmd(&mut self, cmd: &str) -> Option<String> {
+        let target = CoreTarget { machine: EM_RISCV, is_64: self.xlen == Xlen::X64, little_endian: true };
+        #[cfg(feature = "linux-usermode")]
+        if self.usermode {
+            let mu = &self.user_struct.memusage;
+            let summary = &self.user_struct.summary;
+            return memdump::monitor_cmd(cmd, &mut self.memsource.guest_mem, &mu.dumpable_ranges(), target,
+                                        &|a, l, w| mu.accessible(a, l, w),
+                                        &|p| if let Some(rec) = summary { rec.add_snapshot(p.to_string()) });
+        }
+        let mut ram = vec![];
+        let _ = self.memsource.guest_mem.guest_mem.with_regions::<_, ()>(|_, start, size, _, _, _| {
+            ram.push((start.0, size as u64));
+            Ok(())
+        });
+        // GuestMemory says so itself when something's outside RAM
+        memdump::monitor_cmd(cmd, &mut self.memsource.guest_mem, &ram, target, &|_, _, _| true, &|_| {})
+    }
+}
+const RISCV_ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+// x5, t0, fp -> 5, 5, 8
+fn reg_index(name: &str) -> Option<usize> {
+    match name {
+        "fp" => Some(8),
+        _ => match name.strip_prefix('x').and_then(|n| n.parse::<usize>().ok()) {
+            Some(n) if n < 32 => Some(n),
+            _ => RISCV_ABI_NAMES.iter().position(|r| *r == name),
+        },
+    }
+}
+impl RiscvInt {
+    /// Sets pc or an integer register by name (x5, t0, fp). False if there's no such register
+    pub fn set_reg_named(&mut self, name: &str, val: u64) -> bool {
+        let val = if self.xlen == Xlen::X32 { val as u32 as u64 } else { val };
+        if name == "pc" {
+            self.pc = val;
+            return true;
+        }
+        match reg_index(name) {
+            // x0 stays zero, so it's not there to set
+            Some(0) | None => false,
+            Some(idx) => {
+                self.regs[idx] = val;
+                true
+            }
+        }
+    }
+    /// patch / setreg / pause / resume from the monitor
+    pub fn hotpatch_cmd(&mut self, cmd: &str) -> Option<String> {
+        let words: Vec<&str> = cmd.split_whitespace().collect();
+        let reply = match words.as_slice() {
+            ["patch", addr, bytes @ ..] if !bytes.is_empty() => {
+                let addr = match memdump::parse_num(addr) {
+                    Some(a) => a,
+                    None => return Some(format!("bad address {}", addr)),
+                };
+                let data = match bytes {
+                    ["ebreak"] => EBREAK.to_vec(),
+                    ["c.ebreak"] => C_EBREAK.to_vec(),
+                    ["nop"] => NOP.to_vec(),
+                    _ => match hotpatch::parse_bytes(&bytes.join(" ")) {
+                        Some(d) if !d.is_empty() => d,
+                        _ => return Some(format!("bad bytes {}", bytes.join(" "))),
+                    },
+                };
+                match self.patch_code(addr, &data) {
+                    Ok(old) => format!("patched {} bytes at {:#x}, was {}", data.len(), addr,
+                                       old.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")),
+                    Err(e) => format!("not patched: {}", e),
+                }
+            }
+            ["setreg", name, val] => match memdump::parse_num(val) {
+                Some(v) if self.set_reg_named(name, v) => format!("{} = {:#x}", name, v),
+                Some(_) => format!("can't set {}", name),
+                None => format!("bad value {}", val),
+            },
+            ["pause"] => match self.patch_seen.gate().pause(hotpatch::PAUSE_TIMEOUT) {
+                Ok(()) => "other harts paused until resume".to_string(),
+                Err(e) => e,
+            },
+            ["resume"] if self.patch_seen.gate().paused() => {
+                self.patch_seen.gate().resume();
+                if self.patch_seen.gate().paused() { "still paused, pause was given more than once" } else { "resumed" }.to_string()
+            }
+            ["resume"] => "not paused".to_string(),
+            ["patch", ..] => "patch ADDR BYTES|ebreak|c.ebreak|nop".to_string(),
+            ["setreg", ..] => "setreg REG VALUE".to_string(),
+            _ => return None,
+        };
+        Some(reply)
+    }
+}
+impl WatchCtx for RiscvInt {
+    fn watch_reg(&mut self, name: &str) -> Option<u64> {
+        if name == "pc" {
+            return Some(self.pc);
+        }
+        Some(self.regs[reg_index(name)?])
+    }
+    fn watch_mem(&mut self, addr: u64, size: u64) -> Option<u64> {
+        // a bad pointer in the expression mustn't take the emulator down
+        #[cfg(feature = "linux-usermode")]
+        if self.usermode && !self.user_struct.memusage.accessible(addr, size, false) {
+            return None;
+        }
+        let data = self.host_side(|c| c.readx(addr, size, false, false)).ok()?;
+        Some(data.iter().rev().fold(0u64, |acc, b| (acc << 8) | *b as u64))
     }
-}
\ No newline at end of file
+}
+impl RiscvInt {
+    /// Reply to a gdb "monitor" command, the same for the 32-bit and the 64-bit stub
+    pub fn monitor_cmd(&mut self, cmd: &str, watches: &mut WatchList) -> String {
+        #[cfg(feature = "linux-usermode")]
+        if self.usermode {
+            if let Some(reply) = crate::linux_usermode::monitor::usermode_cmd(&self.user_struct, cmd) {
+                return reply;
+            }
+        }
+        if let Some(reply) = self.snapshot_cmd(cmd) {
+            return reply;
+        }
+        if let Some(reply) = self.memdump_cmd(cmd) {
+            return reply;
+        }
+        if let Some(reply) = crate::common::logctl::monitor_cmd(cmd) {
+            return reply;
+        }
+        if let Some(reply) = self.hotpatch_cmd(cmd) {
+            return reply;
+        }
+        match watches.monitor_cmd(cmd, self) {
+            Some(reply) => reply,
+            None => "unknown command, try watch EXPR, unwatch ID, watches, watchmode insn|block, meminfo, stats, throttle [storage|net SPEC|off], savemem raw|core FILE [ADDR LEN], loadmem FILE ADDR, snapshot FILE, loglevel [FILTER], patch ADDR BYTES|ebreak, setreg REG VALUE, pause, resume".to_string(),
+        }
+    }
+    /// Step for the debugger, then see if a watch expression fired
+    pub fn debug_step_watched(&mut self, bpoints: Vec<u64>, watches: &mut WatchList) -> Option<DebugEvent> {
+        let before = self.pc;
+        if self.debug_step(bpoints) {
+            return Some(DebugEvent::Break);
+        }
+        if watches.is_empty() {
+            return None;
+        }
+        // anything but falling through to the next instruction ends a block
+        let jumped = self.pc != before + 2 && self.pc != before + 4;
+        if watches.mode == WatchMode::Block && !jumped {
+            return None;
+        }
+        let (id, text) = watches.check(self)?;
+        base::info!("Watch {} became true: {}", id, text);
+        Some(DebugEvent::WatchExpr(id))
+    }
+}
@@ -1,5 +1,6 @@
 // gdb interface (32-bit)
 
+use base::{error, info};
 use gdbstub::arch::Arch;
 use gdbstub::common::Signal;
 use gdbstub::conn::{Connection, ConnectionExt};
@@ -13,18 +14,22 @@ use gdbstub::target::ext::breakpoints::{Breakpoints, SwBreakpoint, SwBreakpointO
 use crate::riscv::interpreter::main::RiscvInt;
 use gdbstub_arch;
 use gdbstub_arch::riscv::reg::id::RiscvRegId;
-use crate::debug::{DebugEvent, DebugExecMode, DebugRunEvent, wait_for_tcp};
+use gdbstub::target::ext::monitor_cmd::{ConsoleOutput, MonitorCmd, MonitorCmdOps};
+use crate::debug::{DebugEvent, DebugExecMode, DebugRunEvent, wait_for_tcp, WatchList};
 use crate::riscv::common::{get_privilege_encoding, get_privilege_mode, Trap};
 
 pub struct Riscv32DebugWrapper {
     pub icpu: RiscvInt,
     pub breakpoints: Vec<u64>,
     pub exec_mode: DebugExecMode,
+    pub watches: WatchList,
 
 }
 impl Riscv32DebugWrapper {
     fn single_step(&mut self) -> Option<DebugEvent> {
-        self.icpu.debug_step(self.breakpoints.clone());
+        if let Some(event) = self.icpu.debug_step_watched(self.breakpoints.clone(), &mut self.watches) {
+            return Some(event);
+        }
         let pc = self.icpu.get_pc_of_current_instr() as u64;
         if self.breakpoints.contains(&pc) {
             return Some(DebugEvent::Break);
@@ -33,30 +38,31 @@ impl Riscv32DebugWrapper {
 
     }
     pub fn run_debug(&mut self) {
-        let connection: Box<dyn ConnectionExt<Error = std::io::Error>> = {
-            Box::new(wait_for_tcp(9001).unwrap())
-        };
+        self.run_debug_on(Box::new(wait_for_tcp(9001).unwrap()));
+    }
+    /// Same, over a connection that's already there (see debug::wait_for_control)
+    pub fn run_debug_on(&mut self, connection: Box<dyn ConnectionExt<Error = std::io::Error>>) {
         let gdb = GdbStub::new(connection);
-        // todo: propper logging
+        // stdout is the guest's, so this goes to the log
         match gdb.run_blocking::<EmuGdbEventLoop>(self) {
             Ok(disconnect_reason) => match disconnect_reason {
                 DisconnectReason::Disconnect => {
-                    println!("GDB client has disconnected. Running to completion...");
+                    info!("GDB client has disconnected. Running to completion...");
                     while self.single_step() != Some(DebugEvent::Halted) {}
                 }
                 DisconnectReason::TargetExited(code) => {
-                    println!("Target exited with code {}!", code)
+                    info!("Target exited with code {}!", code)
                 }
                 DisconnectReason::TargetTerminated(sig) => {
-                    println!("Target terminated with signal {}!", sig)
+                    info!("Target terminated with signal {}!", sig)
                 }
-                DisconnectReason::Kill => println!("GDB sent a kill command!"),
+                DisconnectReason::Kill => info!("GDB sent a kill command!"),
             },
             Err(GdbStubError::TargetError(e)) => {
-                println!("target encountered a fatal error: {}", e)
+                error!("target encountered a fatal error: {}", e)
             }
             Err(e) => {
-                println!("gdbstub encountered a fatal error: {}", e)
+                error!("gdbstub encountered a fatal error: {}", e)
             }
         }
     }
@@ -143,6 +149,7 @@ impl run_blocking::BlockingEventLoop for EmuGdbEventLoop {
                         kind: WatchKind::Read,
                         addr: addr as u32,
                     },
+                    DebugEvent::WatchExpr(_) => SingleThreadStopReason::Signal(Signal::SIGTRAP),
                 };
                 Ok(run_blocking::Event::TargetStopped(stop_reason))
 
@@ -167,6 +174,10 @@ impl Target for Riscv32DebugWrapper {
     ) -> Option<target::ext::breakpoints::BreakpointsOps<'_, Self>> {
         Some(self)
     }
+    #[inline(always)]
+    fn support_monitor_cmd(&mut self) -> Option<MonitorCmdOps<'_, Self>> {
+        Some(self)
+    }
 
 }
 impl SingleThreadBase for Riscv32DebugWrapper {
@@ -194,8 +205,7 @@ impl SingleThreadBase for Riscv32DebugWrapper {
     }
 
     fn read_addrs(&mut self, start_addr: u32, data: &mut [u8]) -> TargetResult<(), Self> {
-        match self.icpu.readx(start_addr as u64,
-                                   data.len() as u64, false, false) {
+        match self.icpu.host_side(|c| c.readx(start_addr as u64, data.len() as u64, false, false)) {
             Ok(p) => {
                 data.copy_from_slice(&p);
                 Ok(())
@@ -208,7 +218,7 @@ impl SingleThreadBase for Riscv32DebugWrapper {
     }
 
     fn write_addrs(&mut self, start_addr: u32, data: &[u8]) -> TargetResult<(), Self> {
-        match self.icpu.writex(start_addr as u64, data.to_vec(), false) {
+        match self.icpu.host_side(|c| c.writex(start_addr as u64, data.to_vec(), false)) {
             Ok(_) => {
                 Ok(())
 
@@ -339,4 +349,11 @@ impl SingleThreadSingleStep for Riscv32DebugWrapper {
         Ok(())
 
     }
-}
\ No newline at end of file
+}
+impl MonitorCmd for Riscv32DebugWrapper {
+    fn handle_monitor_cmd(&mut self, cmd: &[u8], mut out: ConsoleOutput<'_>) -> Result<(), Self::Error> {
+        let reply = self.icpu.monitor_cmd(&String::from_utf8_lossy(cmd), &mut self.watches);
+        gdbstub::outputln!(out, "{}", reply.trim_end());
+        Ok(())
+    }
+}
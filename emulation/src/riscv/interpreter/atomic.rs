@@ -1,7 +1,6 @@
-use std::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
-use crate::riscv::common::{Xlen, RiscvArgs, Trap};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use crate::riscv::common::RiscvArgs;
 use crate::riscv::interpreter::main::{RiscvInt};
-use crate::riscv::mem::MemAccessCircumstances;
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum AtomicOps {
@@ -15,141 +14,51 @@ pub enum AtomicOps {
     Min,
     MinS
 }
-fn gen_atomic_32(ri: &mut RiscvInt, op: AtomicOps, gg: &RiscvArgs) {
-    if !ri.usermode {
-        unimplemented!();
+// the result of the read-modify-write, given the old value and rs2
+fn amo_result(op: AtomicOps, old: u64, val: u64, signed_min: u64) -> u64 {
+    // flip the sign bit so signed compares can be done unsigned
+    let (so, sv) = (old ^ signed_min, val ^ signed_min);
+    match op {
+        AtomicOps::Swap => val,
+        AtomicOps::Add => old.wrapping_add(val),
+        AtomicOps::And => old & val,
+        AtomicOps::Or => old | val,
+        AtomicOps::Xor => old ^ val,
+        AtomicOps::Max => old.max(val),
+        AtomicOps::MaxS => if sv >= so { val } else { old },
+        AtomicOps::Min => old.min(val),
+        AtomicOps::MinS => if sv >= so { old } else { val },
     }
+}
+// The host does the atomic op for us, so other guest threads (host threads) see it as one. In
+// system mode that's on guest memory's host mapping, after translation
+fn gen_atomic_32(ri: &mut RiscvInt, op: AtomicOps, gg: &RiscvArgs) {
     let addr = ri.regs[gg.rs1 as usize];
     let dat2 = ri.regs[gg.rs2 as usize] as u32;
-    unsafe {
-        loop {
-            let adat1: AtomicPtr<u32> = AtomicPtr::new(addr as *mut u32);
-            let dat1ptr = adat1.load(Ordering::SeqCst);
-            let mut dat1 = *dat1ptr;
-            let mut res = match op {
-                AtomicOps::Swap => {
-                    dat2
-                }
-                AtomicOps::Add => {
-                    dat1.wrapping_add(dat2)
-                }
-                AtomicOps::And => {
-                    dat1 & dat2
-                }
-                AtomicOps::Or => {
-                    dat1 | dat2
-                }
-                AtomicOps::Xor => {
-                    dat1 ^ dat2
-
-                }
-                AtomicOps::MaxS => {
-                    match (dat2 as i32) >= (dat1 as i32) {
-                        true => dat2,
-                        false => dat1
-                    }
-                }
-                AtomicOps::Max => {
-                    match dat2 >= dat1 {
-                        true => dat2,
-                        false => dat1
-                    }
-                }
-                AtomicOps::Min => {
-                    match dat2 >= dat1 {
-                        true => dat1,
-                        false => dat2
-                    }
-                }
-                AtomicOps::MinS => {
-                    match (dat2 as i32) >= (dat1 as i32) {
-                        true => dat1,
-                        false => dat2
-                    }
-                }
-            };
-            let resptr= &mut res;
-            match adat1.compare_exchange(dat1ptr, resptr,
-                                         Ordering::SeqCst, Ordering::SeqCst) {
-                Ok(p) => {
-                    *p = res;
-                    ri.regs[gg.rd as usize] = ri.sign_ext(dat1 as u64);
-                    break;
-                }
-                Err(_) => {
-                    continue
-                }
-            }
-        }
-    }
+    let ptr = match ri.atomic_target(addr, 4, true) {
+        Ok(p) => p,
+        Err(_) => return,
+    };
+    let mem = unsafe { &*(ptr as *const AtomicU32) };
+    let old = mem.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |d| {
+        Some(amo_result(op, d as u64, dat2 as u64, 1 << 31) as u32)
+    }).unwrap();
+    note_store(addr, 4);
+    ri.regs[gg.rd as usize] = ri.sign_ext(old as i32 as i64 as u64);
 }
 fn gen_atomic_64(ri: &mut RiscvInt, op: AtomicOps, gg: &RiscvArgs) {
-    if !ri.usermode {
-        unimplemented!();
-    }
     let addr = ri.regs[gg.rs1 as usize];
     let dat2 = ri.regs[gg.rs2 as usize];
-    unsafe {
-        loop {
-            let mut adat1: AtomicPtr<u64> = AtomicPtr::new(addr as *mut u64);
-            let dat1ptr = adat1.load(Ordering::SeqCst);
-            let mut dat1 = *dat1ptr;
-            let mut res = match op {
-                AtomicOps::Swap => {
-                    dat2
-                }
-                AtomicOps::Add => {
-                    dat1.wrapping_add(dat2)
-                }
-                AtomicOps::And => {
-                    dat1 & dat2
-                }
-                AtomicOps::Or => {
-                    dat1 | dat2
-                }
-                AtomicOps::Xor => {
-                    dat1 ^ dat2
-
-                }
-                AtomicOps::Max => {
-                    match dat2 >= dat1 {
-                        true => dat2,
-                        false => dat1
-                    }
-                }
-                AtomicOps::MaxS => {
-                    match (dat2 as i64) >= (dat1 as i64) {
-                        true => dat2,
-                        false => dat1
-                    }
-                }
-                AtomicOps::Min => {
-                    match dat2 >= dat1 {
-                        true => dat1,
-                        false => dat2
-                    }
-                }
-                AtomicOps::MinS => {
-                    match (dat2 as i64) >= (dat1 as i64) {
-                        true => dat1,
-                        false => dat2
-                    }
-                }
-            };
-            let resptr= &mut res;
-            match adat1.compare_exchange(addr as *mut u64, resptr as *mut u64,
-                                   Ordering::SeqCst, Ordering::SeqCst) {
-                Ok(p) => {
-                    *p = res;
-                    ri.regs[gg.rd as usize] = dat1;
-                    break;
-                }
-                Err(p) => {
-                    continue
-                }
-            }
-        }
-    }
+    let ptr = match ri.atomic_target(addr, 8, true) {
+        Ok(p) => p,
+        Err(_) => return,
+    };
+    let mem = unsafe { &*(ptr as *const AtomicU64) };
+    let old = mem.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |d| {
+        Some(amo_result(op, d, dat2, 1 << 63))
+    }).unwrap();
+    note_store(addr, 8);
+    ri.regs[gg.rd as usize] = old;
 }
 pub fn amoadd_d(ri: &mut RiscvInt, args: &RiscvArgs) {
     gen_atomic_64(ri, AtomicOps::Add, args);
@@ -173,88 +82,100 @@ pub fn amomaxu_d(ri: &mut RiscvInt, args: &RiscvArgs) {
     gen_atomic_64(ri, AtomicOps::Max, args);
 }
 
-pub fn sc_w(ri: &mut RiscvInt, args: &RiscvArgs) {
-    let addr = ri.regs[args.rs1 as usize];
-    let val = ri.regs[args.rs2 as usize] as u32;
+// A reservation is lost when another hart stores to its granule. Harts are host threads we can't
+// watch, so every guest store bumps the epoch of its granule here while any hart holds a
+// reservation, and SC succeeds only if the epoch is still the one LR saw. SC also compares the
+// value: stores the emulator makes for the guest (syscalls) don't bump epochs, and a guest store
+// can land just before its check of RESERVATIONS sees a new LR, in which case only a store of the
+// same value goes unnoticed. Nothing the ISA promises depends on that.
+const GRANULE_SHIFT: u64 = 6;
+const EPOCH_SLOTS: usize = 4096;
+const NO_STORES: AtomicU64 = AtomicU64::new(0);
+static STORE_EPOCHS: [AtomicU64; EPOCH_SLOTS] = [NO_STORES; EPOCH_SLOTS];
+// harts holding a reservation. One that exits holding one only makes stores a bit slower
+static RESERVATIONS: AtomicUsize = AtomicUsize::new(0);
 
-    if ri.is_reservation && (ri.res_len == 4) && (addr == ri.res_val) {
+fn epoch_of(addr: u64) -> &'static AtomicU64 {
+    &STORE_EPOCHS[(addr >> GRANULE_SHIFT) as usize % EPOCH_SLOTS]
+}
+/// A guest store to `len` bytes at `addr`, made after the memory was written
+pub(crate) fn note_store(addr: u64, len: u64) {
+    if RESERVATIONS.load(Ordering::SeqCst) == 0 {
+        return;
+    }
+    let last = addr.wrapping_add(len.max(1) - 1);
+    epoch_of(addr).fetch_add(1, Ordering::SeqCst);
+    if last >> GRANULE_SHIFT != addr >> GRANULE_SHIFT {
+        epoch_of(last).fetch_add(1, Ordering::SeqCst);
+    }
+}
+fn drop_reservation(ri: &mut RiscvInt) {
+    if ri.is_reservation {
         ri.is_reservation = false;
-        match ri.write32(addr, val, true) {
-            Err(_) => {
-                ri.regs[args.rd as usize] = 1;
-                return;
-            },
-            Ok(_) => { }
-        };
-        ri.regs[args.rd as usize] = 0;
-    } else {
-        ri.regs[args.rd as usize] = 1;
+        RESERVATIONS.fetch_sub(1, Ordering::SeqCst);
     }
 }
-
-pub fn sc_d(ri: &mut RiscvInt, args: &RiscvArgs) {
+fn load_reserved(ri: &mut RiscvInt, args: &RiscvArgs, len: u8) {
+    let addr = ri.regs[args.rs1 as usize];
+    let ptr = match ri.atomic_target(addr, len as u64, false) {
+        Ok(p) => p,
+        Err(_) => return,
+    };
+    if !ri.is_reservation {
+        RESERVATIONS.fetch_add(1, Ordering::SeqCst);
+    }
+    ri.is_reservation = true;
+    ri.res_len = len;
+    ri.res_val = addr;
+    // before reading, a store in between has to fail the SC
+    ri.res_epoch = epoch_of(addr).load(Ordering::SeqCst);
+    let data = unsafe {
+        if len == 4 {
+            (*(ptr as *const AtomicU32)).load(Ordering::SeqCst) as i32 as i64 as u64
+        } else {
+            (*(ptr as *const AtomicU64)).load(Ordering::SeqCst)
+        }
+    };
+    ri.res_data = data;
+    ri.regs[args.rd as usize] = data;
+}
+fn store_conditional(ri: &mut RiscvInt, args: &RiscvArgs, len: u8) {
     let addr = ri.regs[args.rs1 as usize];
     let val = ri.regs[args.rs2 as usize];
-
-    if ri.is_reservation && (ri.res_len == 8) && (addr == ri.res_val) {
-        ri.is_reservation = false;
-        match ri.write64(addr, val, true) {
-            Err(_) => {
-                ri.regs[args.rd as usize] = 1;
-                return;
-            },
-            Ok(_) => { }
+    let reserved = ri.is_reservation && ri.res_len == len && addr == ri.res_val;
+    drop_reservation(ri);
+    // misaligned or faulting SCs trap even without a reservation
+    let ptr = match ri.atomic_target(addr, len as u64, true) {
+        Ok(p) => p,
+        Err(_) => return,
+    };
+    let epoch = epoch_of(addr);
+    // taking the next epoch fails other harts' reservations on the granule
+    let ok = reserved && epoch.compare_exchange(ri.res_epoch, ri.res_epoch.wrapping_add(1),
+                                                Ordering::SeqCst, Ordering::SeqCst).is_ok()
+        && unsafe {
+            if len == 4 {
+                (*(ptr as *const AtomicU32)).compare_exchange(ri.res_data as u32, val as u32,
+                                                               Ordering::SeqCst, Ordering::SeqCst).is_ok()
+            } else {
+                (*(ptr as *const AtomicU64)).compare_exchange(ri.res_data, val,
+                                                               Ordering::SeqCst, Ordering::SeqCst).is_ok()
+            }
         };
-        ri.regs[args.rd as usize] = 0;
-    } else {
-        ri.is_reservation = false;
-        ri.regs[args.rd as usize] = 1;
-    }
+    ri.regs[args.rd as usize] = if ok { 0 } else { 1 };
+}
+pub fn sc_w(ri: &mut RiscvInt, args: &RiscvArgs) {
+    store_conditional(ri, args, 4);
+}
+pub fn sc_d(ri: &mut RiscvInt, args: &RiscvArgs) {
+    store_conditional(ri, args, 8);
 }
 pub fn lr_w(ri: &mut RiscvInt, args: &RiscvArgs) {
-    let addr = ri.regs[args.rs1 as usize];
-    if let Ok(data) = ri.read32(addr, false, true) {
-        ri.is_reservation = true;
-        ri.res_len = 4;
-        ri.res_val = addr;
-        ri.regs[args.rd as usize] = data as i32 as i64 as u64;
-    }
+    load_reserved(ri, args, 4);
 }
 pub fn lr_d(ri: &mut RiscvInt, args: &RiscvArgs) {
-    let addr = ri.regs[args.rs1 as usize];
-    if let Ok(data) = ri.read64(addr, false, true) {
-        ri.is_reservation = true;
-        ri.res_len = 8;
-        ri.res_val = addr;
-        ri.regs[args.rd as usize] = data as i64 as u64;
-    }
+    load_reserved(ri, args, 8);
 }
 pub fn amoswap_w(ri: &mut RiscvInt, args: &RiscvArgs) {
-    // todo: actually do correctly with atomptr
-    let addr = ri.regs[args.rs1 as usize];
-    let val = ri.regs[args.rs2 as usize] as u32;
-    /* let getval = match ri.swap32imm(addr, val, Ordering::SeqCst, false, true) {
-        Ok(z) => {
-            z
-        }
-        Err(_) => {
-            return;
-        }
-    };
-    ri.regs[args.rd as usize] = getval as i32 as i64 as u64;
-
-     */
-    let tmp = match ri.read32(addr, false, true) {
-        Ok(z) => z,
-        Err(_) => {
-            return;
-        }
-    };
-    match ri.write32(addr, val,false) {
-        Ok(_) => {},
-        Err(_) => {
-            return;
-        }
-    };
-    ri.regs[args.rd as usize] = tmp as i32 as i64 as u64;
-}
\ No newline at end of file
+    gen_atomic_32(ri, AtomicOps::Swap, args);
+}
@@ -0,0 +1,228 @@
+// Decoded blocks kept on disk between runs of the same guest binary (--block-cache FILE), so a
+// warm run can skip finding the code it ran last time. The file has a key: the binary's build-id
+// (its size and mtime when it has none), the ISA the blocks were decoded for (with or without C,
+// and which custom opcodes have handlers) and a hash of the emulator binary. A file with another
+// key is ignored and replaced at exit, so nothing has to be cleared by hand after a rebuild of
+// either.
+// Only blocks of the main executable are kept, relative to where it was loaded so PIEs get them
+// too. What's stored is where blocks start and end and the raw bits of their instructions, never
+// handlers: the bits are compared with guest memory and decoded again when a block is reused, so
+// a corrupt or edited file can at worst make us decode the guest's own code, and code that was
+// patched or relocated differently is found again from scratch.
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::Hasher;
+use std::io::Read;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use base::warn;
+use serde::{Deserialize, Serialize};
+use sync::Mutex;
+use crate::common::memory::MemEndian;
+use crate::riscv::common::Xlen;
+use crate::riscv::interpreter::custom::CustomExtensions;
+use crate::riscv::interpreter::main::RiscvInt;
+use crate::riscv::mem::RISCV_PAGE_OFFSET;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct StoredInsn {
+    /// raw instruction, the low 16 bits for compressed ones
+    bits: u32,
+    inc_by: u64,
+}
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct StoredBlock {
+    /// last instruction's address, relative to the image like the block's own
+    end: u64,
+    insns: Vec<StoredInsn>,
+}
+#[derive(Serialize, Deserialize, Default)]
+struct CacheFile {
+    key: String,
+    blocks: BTreeMap<u64, StoredBlock>,
+}
+fn hash_file(path: &Path) -> Option<u64> {
+    let mut file = std::fs::File::open(path).ok()?;
+    let mut hasher = DefaultHasher::new();
+    let mut buf = vec![0u8; 1 << 20];
+    loop {
+        match file.read(&mut buf).ok()? {
+            0 => return Some(hasher.finish()),
+            n => hasher.write(&buf[..n]),
+        }
+    }
+}
+lazy_static::lazy_static! {
+    // where blocks end is up to the decoder, which can change with any build. Its contents, not
+    // its size and mtime: reproducible builds and cp -p keep those
+    static ref EMULATOR_HASH: String = std::env::current_exe().ok()
+        .and_then(|p| hash_file(&p))
+        .map_or_else(String::new, |h| format!("{:016x}", h));
+}
+/// What a block cache file is good for: `binary` is the guest's build-id (see Object::build_id),
+/// or None to go by the file at `path` instead
+pub fn cache_key(binary: Option<&[u8]>, path: &Path, xlen: Xlen, ext_c: bool,
+                 custom: Option<&CustomExtensions>) -> String {
+    let guest = match binary {
+        Some(id) => id.iter().map(|b| format!("{:02x}", b)).collect(),
+        None => {
+            let ident = std::fs::metadata(path).ok().map(|m| {
+                let mtime = m.modified().ok().and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok());
+                format!("{}-{}", m.len(), mtime.map_or(0, |d| d.as_nanos()))
+            });
+            format!("file-{}", ident.unwrap_or_default())
+        }
+    };
+    let isa = match (xlen, ext_c) {
+        (Xlen::X32, true) => "rv32gc",
+        (Xlen::X32, false) => "rv32g",
+        (Xlen::X64, true) => "rv64gc",
+        (Xlen::X64, false) => "rv64g",
+    };
+    // a custom instruction ends its block, so which ones have handlers moves block ends around
+    let custom = custom.map_or_else(String::new, |c| format!("+{:?}", c.insn_opcodes()));
+    format!("{}/{}{}/{}-{}", guest, isa, custom, env!("CARGO_PKG_VERSION"), *EMULATOR_HASH)
+}
+/// Blocks of one image, loaded from and saved back to a cache file. Shared by all guest threads
+pub struct BlockStore {
+    path: String,
+    key: String,
+    owner: libc::pid_t,
+    /// where the image starts this run, and the guest addresses it covers
+    base: u64,
+    range: Range<u64>,
+    blocks: Mutex<BTreeMap<u64, StoredBlock>>,
+    reused: AtomicU64,
+    dirty: AtomicBool,
+    written: AtomicBool,
+}
+impl BlockStore {
+    /// The blocks in `path` if its key is `key`, none if it's another one or there's no file yet
+    pub fn open(path: String, key: String, base: u64, range: Range<u64>) -> BlockStore {
+        let blocks = match std::fs::read(&path) {
+            Ok(data) => match serde_json::from_slice::<CacheFile>(&data) {
+                Ok(f) if f.key == key => f.blocks,
+                Ok(_) => {
+                    warn!("Block cache {} is for another binary or emulator build, starting over", path);
+                    BTreeMap::new()
+                }
+                Err(e) => {
+                    warn!("Couldn't read the block cache {}, starting over: {}", path, e);
+                    BTreeMap::new()
+                }
+            },
+            Err(_) => BTreeMap::new(),
+        };
+        BlockStore {
+            path,
+            key,
+            owner: unsafe { libc::getpid() },
+            base,
+            range,
+            blocks: Mutex::new(blocks),
+            reused: AtomicU64::new(0),
+            dirty: AtomicBool::new(false),
+            written: AtomicBool::new(false),
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.blocks.lock().len()
+    }
+    /// Blocks that were taken from the store instead of decoded
+    pub fn reused(&self) -> u64 {
+        self.reused.load(Ordering::Relaxed)
+    }
+    fn offset(&self, addr: u64) -> Option<u64> {
+        self.range.contains(&addr).then(|| addr - self.base)
+    }
+    /// Write the file, if this is the process that should, something was added and it hasn't yet
+    pub fn save(&self) {
+        if unsafe { libc::getpid() } != self.owner || !self.dirty.load(Ordering::SeqCst)
+            || self.written.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let file = CacheFile { key: self.key.clone(), blocks: self.blocks.lock().clone() };
+        let res = serde_json::to_vec(&file).map_err(|e| e.to_string())
+            .and_then(|data| std::fs::write(&self.path, data).map_err(|e| e.to_string()));
+        if let Err(e) = res {
+            warn!("Couldn't write the block cache to {}: {}", self.path, e);
+        }
+    }
+}
+impl RiscvInt {
+    /// Put the stored block at `addr` in the block cache, if there's one and the code is still the
+    /// same. false if it has to be decoded
+    pub(crate) fn load_stored_block(&mut self, addr: u64) -> bool {
+        let store = match &self.block_store {
+            Some(s) => s.clone(),
+            None => return false,
+        };
+        let stored = match store.offset(addr).and_then(|off| store.blocks.lock().get(&off).cloned()) {
+            Some(b) => b,
+            None => return false,
+        };
+        self.stop_translating = false;
+        self.current_block.begin = addr;
+        self.current_block.instrs.clear();
+        let mut iaddr = addr;
+        for (n, i) in stored.insns.iter().enumerate() {
+            // the file is only trusted to say where the block ends: a block has to stay in its
+            // page, and what's decoded is what's in guest memory now
+            if i.inc_by != 4 && !(i.inc_by == 2 && self.ext_c()) {
+                return false;
+            }
+            let same_page = ((iaddr + i.inc_by - 1) ^ addr) & !RISCV_PAGE_OFFSET == 0;
+            if !same_page || self.insn_bits(iaddr, i.inc_by) != Some(i.bits) || (i.inc_by == 2) != (i.bits & 3 != 3) {
+                return false;
+            }
+            self.decode_insn(i.bits, i.inc_by);
+            // a branch or custom instruction that isn't last means the file is off
+            if self.current_block.instrs.len() != n + 1 || (self.stop_translating && n + 1 != stored.insns.len()) {
+                return false;
+            }
+            iaddr += i.inc_by;
+        }
+        if stored.insns.is_empty() || iaddr - stored.insns.last().unwrap().inc_by != stored.end + store.base {
+            return false;
+        }
+        self.current_block.end = stored.end + store.base;
+        self.push_block(self.current_block.clone());
+        store.reused.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+    /// Keep the block build_exec() just made, if it's in the image
+    pub(crate) fn store_current_block(&mut self) {
+        let store = match &self.block_store {
+            Some(s) => s.clone(),
+            None => return,
+        };
+        let (off, end) = match (store.offset(self.current_block.begin), store.offset(self.current_block.end)) {
+            (Some(o), Some(e)) => (o, e),
+            _ => return,
+        };
+        let mut insns = Vec::with_capacity(self.current_block.instrs.len());
+        let mut iaddr = self.current_block.begin;
+        for idx in 0..self.current_block.instrs.len() {
+            let inc_by = self.current_block.instrs[idx].inc_by;
+            let bits = match self.insn_bits(iaddr, inc_by) {
+                Some(b) => b,
+                None => return,
+            };
+            insns.push(StoredInsn { bits, inc_by });
+            iaddr += inc_by;
+        }
+        store.blocks.lock().insert(off, StoredBlock { end, insns });
+        store.dirty.store(true, Ordering::SeqCst);
+    }
+    // stores only exist in user mode, where the image is mapped for as long as it runs
+    fn insn_bits(&mut self, addr: u64, inc_by: u64) -> Option<u32> {
+        let mem = &mut self.memsource.guest_mem;
+        let lo = mem.read_phys_16(addr, MemEndian::Little).ok()? as u32;
+        match inc_by {
+            2 => Some(lo),
+            4 => Some(lo | (mem.read_phys_16(addr + 2, MemEndian::Little).ok()? as u32) << 16),
+            _ => None,
+        }
+    }
+}
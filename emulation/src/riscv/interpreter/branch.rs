@@ -1,4 +1,5 @@
-use crate::riscv::common::{Xlen,RiscvArgs};
+use crate::common::shadow_stack::ShadowFrame;
+use crate::riscv::common::{Xlen, RiscvArgs, RISCV_STACKPOINTER_REG};
 use crate::riscv::interpreter::defs::sign_ext_imm;
 use crate::riscv::interpreter::main::{RiscvInt};
 
@@ -38,9 +39,10 @@ fn gen_branch(ri: &mut RiscvInt, args: &RiscvArgs, btype: BranchOps, is_unsigned
         }
     };
     if cond {
-        ri.want_pc = Some(ri.get_pc_of_current_instr().wrapping_add(sign_ext_imm(args.imm)));
-        ri.changed_pc = true;
-        ri.stop_exec = true;
+        let target = ri.get_pc_of_current_instr().wrapping_add(sign_ext_imm(args.imm));
+        if ri.jump_to(target) {
+            ri.changed_pc = true;
+        }
         // if stacked ops want to stop, the vec would have run out. We just report change here, if it goes on if no branch is somehwere else decision
 
     }
@@ -73,19 +75,60 @@ pub fn auipc(ri: &mut RiscvInt, args: &RiscvArgs) {
     let finalval =  pc.wrapping_add(sign_ext_imm(args.imm));
     ri.regs[args.rd as usize] = ri.sign_ext(finalval);
 }
+// x1 (ra) and x5 (t0) are the link registers, the isa manual's return-address stack hints
+// say what a jal/jalr with them means
+fn is_link_reg(r: u32) -> bool {
+    r == 1 || r == 5
+}
+fn shadow_stack_update(ri: &mut RiscvInt, rd: u32, rs1: Option<u32>, target: u64) {
+    let call_pc = ri.get_pc_of_current_instr();
+    let ret_addr = ri.get_pc_of_next_instr();
+    let sp = ri.regs[RISCV_STACKPOINTER_REG];
+    let ss = match &mut ri.shadow_stack {
+        Some(s) => s,
+        None => return,
+    };
+    let (pop, push) = match rs1 {
+        None => (false, is_link_reg(rd)),
+        Some(rs1) => match (is_link_reg(rd), is_link_reg(rs1)) {
+            (false, false) => (false, false),
+            (false, true) => (true, false),
+            (true, false) => (false, true),
+            (true, true) => (rd != rs1, true), // rd == rs1 is a plain call
+        }
+    };
+    if pop {
+        ss.ret(target);
+    }
+    if push {
+        ss.push(ShadowFrame {
+            call_pc,
+            target,
+            ret_addr,
+            sp,
+        });
+    }
+}
 pub fn jal(ri: &mut RiscvInt, args: &RiscvArgs) {
     let newpc = ri.get_pc_of_next_instr();
     let newpc_sext = ri.sign_ext(newpc);
+    let target = ri.get_pc_of_current_instr().wrapping_add(sign_ext_imm(args.imm));
+    if !ri.jump_to(target) {
+        return;
+    }
+    shadow_stack_update(ri, args.rd, None, target);
     ri.regs[args.rd as usize] = newpc_sext;
-    ri.want_pc = Some(ri.get_pc_of_current_instr().wrapping_add(sign_ext_imm(args.imm)));
-    ri.stop_exec = true;
 
 }
 pub fn jalr(ri: &mut RiscvInt, args: &RiscvArgs) {
     let newpc = ri.get_pc_of_next_instr();
     let newpc_sext = ri.sign_ext(newpc);
-    ri.want_pc = Some(ri.regs[args.rs1 as usize].wrapping_add(sign_ext_imm(args.imm)));
+    // the low bit is always dropped
+    let target = ri.regs[args.rs1 as usize].wrapping_add(sign_ext_imm(args.imm)) & !1;
+    if !ri.jump_to(target) {
+        return;
+    }
+    shadow_stack_update(ri, args.rd, Some(args.rs1), target);
     ri.regs[args.rd as usize] = newpc_sext;
-    ri.stop_exec = true;
 
 }
\ No newline at end of file
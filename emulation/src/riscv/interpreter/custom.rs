@@ -0,0 +1,124 @@
+// Hooks for custom and vendor extensions, so they can be prototyped without forking decoder.rs.
+// Handlers can be registered for the four major opcodes the spec leaves for custom instructions
+// (custom-0 to custom-3) and for ranges of CSR numbers. Custom handlers get the first look at
+// their opcode space, before the generated decoder (custom-2/3 double as RV128 opcodes there).
+// A handler gets the raw instruction and the whole cpu, so it can touch registers, memory,
+// raise traps or jump (set want_pc and stop_exec, like the branch code does).
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use crate::riscv::common::RiscvArgs;
+use crate::riscv::interpreter::core::illegal_instr;
+use crate::riscv::interpreter::main::{RiscvInstr, RiscvInt};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CustomOpcode {
+    Custom0,
+    Custom1,
+    Custom2,
+    Custom3,
+}
+impl CustomOpcode {
+    /// bits 6:0 of the instruction
+    pub fn major(&self) -> u32 {
+        match self {
+            CustomOpcode::Custom0 => 0b0001011,
+            CustomOpcode::Custom1 => 0b0101011,
+            CustomOpcode::Custom2 => 0b1011011,
+            CustomOpcode::Custom3 => 0b1111011,
+        }
+    }
+}
+pub trait CustomInsn: Send + Sync {
+    /// Whether this handler implements `insn`, so several can share an opcode space
+    fn decodes(&self, _insn: u32) -> bool {
+        true
+    }
+    /// Run `insn`. false makes it an illegal instruction
+    fn execute(&self, cpu: &mut RiscvInt, insn: u32) -> bool;
+}
+pub trait CustomCsr: Send + Sync {
+    /// None makes the access an illegal instruction
+    fn read(&self, cpu: &mut RiscvInt, csr: u16) -> Option<u64>;
+    /// false makes the access an illegal instruction
+    fn write(&self, cpu: &mut RiscvInt, csr: u16, val: u64) -> bool;
+}
+/// Everything registered, shared by all harts/threads
+#[derive(Default, Clone)]
+pub struct CustomExtensions {
+    insns: Vec<(CustomOpcode, Arc<dyn CustomInsn>)>,
+    csrs: Vec<(RangeInclusive<u16>, Arc<dyn CustomCsr>)>,
+}
+impl CustomExtensions {
+    pub fn new() -> CustomExtensions {
+        Default::default()
+    }
+    /// Handlers registered first get the first look
+    pub fn add_insn(&mut self, op: CustomOpcode, handler: Arc<dyn CustomInsn>) {
+        self.insns.push((op, handler));
+    }
+    /// CSRs in `range` go to `handler` (still subject to the usual privilege check)
+    pub fn add_csr_range(&mut self, range: RangeInclusive<u16>, handler: Arc<dyn CustomCsr>) {
+        self.csrs.push((range, handler));
+    }
+    /// The opcode of every instruction handler, in the order they get to look
+    pub fn insn_opcodes(&self) -> Vec<CustomOpcode> {
+        self.insns.iter().map(|(op, _)| *op).collect()
+    }
+    fn find_insn(&self, insn: u32) -> Option<usize> {
+        self.insns.iter().position(|(op, h)| insn & 0x7f == op.major() && h.decodes(insn))
+    }
+    /// Whether a registered handler takes `insn`
+    pub(crate) fn decodes(&self, insn: u32) -> bool {
+        self.find_insn(insn).is_some()
+    }
+    pub(crate) fn csr_handler(&self, csr: u16) -> Option<Arc<dyn CustomCsr>> {
+        self.csrs.iter().find(|(r, _)| r.contains(&csr)).map(|(_, h)| h.clone())
+    }
+}
+impl fmt::Debug for CustomExtensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CustomExtensions")
+            .field("insns", &self.insns.iter().map(|(op, _)| *op).collect::<Vec<_>>())
+            .field("csrs", &self.csrs.iter().map(|(r, _)| r.clone()).collect::<Vec<_>>())
+            .finish()
+    }
+}
+// the raw instruction travels in args.imm and the handler index in args.zimm
+fn custom_insn(ri: &mut RiscvInt, args: &RiscvArgs) {
+    let ext = ri.custom.clone().unwrap();
+    let (_, h) = &ext.insns[args.zimm as usize];
+    if !h.execute(ri, args.imm) {
+        illegal_instr(ri, args);
+    }
+}
+impl RiscvInt {
+    /// Decode `insn` with a registered handler, false if none takes it
+    pub(crate) fn decode_custom(&mut self, insn: u32) -> bool {
+        let idx = match &self.custom {
+            Some(c) => c.find_insn(insn),
+            None => None,
+        };
+        let idx = match idx {
+            Some(i) => i,
+            None => return false,
+        };
+        let args = RiscvArgs {
+            imm: insn,
+            zimm: idx as u32,
+            ..Default::default()
+        };
+        if self.cache_enabled {
+            // no telling what the handler does with pc
+            self.stop_translating = true;
+            self.insert_insn_current(RiscvInstr {
+                args,
+                inc_by: 0,
+                func: custom_insn,
+            });
+        } else {
+            custom_insn(self, &args);
+        }
+        true
+    }
+}
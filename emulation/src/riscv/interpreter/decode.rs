@@ -18,6 +18,19 @@ impl crate::riscv::decoder::DecodeTrait for RiscvInt {
         }
         return true;
     }
+    fn ebreak(&mut self, args: RiscvArgs) -> bool {
+        if self.cache_enabled {
+            self.stop_translating = true;
+            self.insert_insn_current(RiscvInstr {
+                args,
+                inc_by: 0,
+                func: interpreter::defs::ebreak
+            });
+        } else {
+            interpreter::defs::ebreak(self, &args);
+        }
+        return true;
+    }
     fn mret(&mut self, args: RiscvArgs) -> bool {
         if self.cache_enabled {
             self.stop_translating = true;
@@ -0,0 +1,110 @@
+// The interpreter behind the common ExecutionEngine seam. RiscvInt itself is the usual engine and
+// caches decoded blocks when cache_enabled is set; OracleEngine is the same interpreter made to
+// decode every instruction as it runs it, slow but with no cache to get wrong, for lockstep()
+// against the other. lockstep_bare_metal() does that for a whole guest (the "lockstep" command):
+// system mode, so each engine gets its own RAM and nothing the guest does reaches the host.
+use std::fs::File;
+use std::path::Path;
+use kernel_loader::load_elf;
+use vm_memory::{GuestAddress, GuestMemory};
+use crate::common::engine::{Divergence, ExecutionEngine, lockstep, MemoryBackend};
+use crate::common::spin::state_digest;
+use crate::riscv::common::{DRAM_BASE, Xlen};
+use crate::riscv::interpreter::main::RiscvInt;
+use crate::riscv::mem::{MemAccessType, RISCV_PAGE_SIZE};
+
+impl RiscvInt {
+    /// The outer loop for `n` instructions, with no pacing
+    pub(crate) fn run_for(&mut self, n: u64) {
+        let stop = self.icount.saturating_add(n);
+        while self.icount < stop {
+            self.icount_limit = stop;
+            self.run_once();
+        }
+        self.icount_limit = u64::MAX;
+    }
+}
+impl ExecutionEngine for RiscvInt {
+    fn translate(&mut self, pc: u64) {
+        if !self.cache_enabled {
+            return;
+        }
+        let macc = self.gen_mem_cirum(MemAccessType::Execute);
+        if let Ok(phys) = self.memsource.virt2phys(pc, macc) {
+            // a fetch fault shows up again when the code is run
+            let _ = self.build_exec(phys);
+            self.stop_exec = false;
+        }
+    }
+    fn run(&mut self, max_insns: u64) {
+        self.run_for(max_insns);
+    }
+    fn invalidate(&mut self, addr: u64, len: u64) {
+        // physical addresses, like the cache. Blocks never cross a page, so dropping the blocks
+        // of every page in the range is enough
+        let mut page = addr & !(RISCV_PAGE_SIZE - 1);
+        while page < addr.saturating_add(len) {
+            self.deal_with_cache(page);
+            page += RISCV_PAGE_SIZE;
+        }
+        self.stop_exec = false;
+    }
+    fn pc(&self) -> u64 {
+        self.pc
+    }
+    fn icount(&self) -> u64 {
+        self.icount
+    }
+    fn state_digest(&self) -> u64 {
+        state_digest(&self.regs, &self.fregs) ^ self.pc
+    }
+    fn memory(&mut self) -> &mut dyn MemoryBackend {
+        if self.usermode {
+            &mut self.memsource.guest_mem
+        } else {
+            &mut self.memsource.guest_mem.guest_mem
+        }
+    }
+}
+/// Reference engine: the interpreter with the block cache kept off
+pub struct OracleEngine(pub RiscvInt);
+impl ExecutionEngine for OracleEngine {
+    fn translate(&mut self, _pc: u64) {}
+    fn run(&mut self, max_insns: u64) {
+        self.0.cache_enabled = false;
+        self.0.run_for(max_insns);
+    }
+    fn invalidate(&mut self, _addr: u64, _len: u64) {}
+    fn pc(&self) -> u64 {
+        self.0.pc
+    }
+    fn icount(&self) -> u64 {
+        self.0.icount
+    }
+    fn state_digest(&self) -> u64 {
+        self.0.state_digest()
+    }
+    fn memory(&mut self) -> &mut dyn MemoryBackend {
+        self.0.memory()
+    }
+}
+/// A bare-metal ELF (a test rom, firmware) in `ram` bytes of RAM of its own at DRAM_BASE, about
+/// to run its first instruction in machine mode
+pub fn load_bare_metal(path: &Path, ram: u64) -> anyhow::Result<RiscvInt> {
+    let data = std::fs::read(path)?;
+    let ef = goblin::elf::Elf::parse(&data)?;
+    let vmmem = GuestMemory::new(&[(GuestAddress(DRAM_BASE), ram)])?;
+    load_elf(&vmmem, GuestAddress(DRAM_BASE), &mut File::open(path)?)?;
+    let mut cpu = RiscvInt::init_systemmode(if ef.is_64 { Xlen::X64 } else { Xlen::X32 }, vmmem);
+    cpu.pc = ef.entry;
+    Ok(cpu)
+}
+/// Runs the guest at `path` on the block-caching interpreter and on the oracle side by side for
+/// `total` instructions, comparing them every `step` (see lockstep)
+pub fn lockstep_bare_metal(path: &Path, ram: u64, total: u64, step: u64,
+                           watch: &[(u64, u64)]) -> anyhow::Result<Result<(), Divergence>> {
+    let mut cached = load_bare_metal(path, ram)?;
+    cached.cache_enabled = true;
+    let mut oracle = OracleEngine(load_bare_metal(path, ram)?);
+    Ok(lockstep(&mut cached, &mut oracle, total, step, watch))
+}
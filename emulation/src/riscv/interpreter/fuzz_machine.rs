@@ -0,0 +1,143 @@
+// Machine layouts picked at random from a seed, with a smoke workload to run on them (the
+// machine half of --fuzz-config). Nothing here should care where RAM starts, how big it is, how
+// many harts share it or whether C is on; a layout that breaks the workload is an emulator bug.
+// There are no devices to shuffle yet, so the layout is RAM, harts and ISA.
+use vm_memory::{GuestAddress, GuestMemory};
+use crate::common::engine::ExecutionEngine;
+use crate::riscv::common::Xlen;
+use crate::riscv::interpreter::consts::{CSR_MHARTID_ADDRESS, CSR_MISA_ADDRESS};
+use crate::riscv::interpreter::main::{MISA_C, RiscvInt};
+
+const PAGE: u64 = 4096;
+// a0 = hart id, a1 = scratch, a2 = words: fill the scratch words with i ^ hartid, add them back up
+// into a3, mhartid into a4, then spin at HALT. RV32I only, so it runs on either xlen
+const SMOKE: [u32; 17] = [
+    0xf1402773, // csrr a4, mhartid
+    0x00058293, // mv t0, a1
+    0x00000313, // li t1, 0
+    0x00a343b3, // 1: xor t2, t1, a0
+    0x0072a023, // sw t2, 0(t0)
+    0x00428293, // addi t0, t0, 4
+    0x00130313, // addi t1, t1, 1
+    0xfec348e3, // blt t1, a2, 1b
+    0x00058293, // mv t0, a1
+    0x00000313, // li t1, 0
+    0x00000693, // li a3, 0
+    0x0002a383, // 2: lw t2, 0(t0)
+    0x007686b3, // add a3, a3, t2
+    0x00428293, // addi t0, t0, 4
+    0x00130313, // addi t1, t1, 1
+    0xfec348e3, // blt t1, a2, 2b
+    0x0000006f, // j .
+];
+const HALT: u64 = 16 * 4;
+/// scratch words per hart
+const SMOKE_WORDS: u64 = 256;
+/// instructions a hart gets at a time, and in all before it counts as stuck
+const SMOKE_QUANTUM: u64 = 97;
+const SMOKE_BUDGET: u64 = 10 * SMOKE_WORDS * 8;
+
+/// splitmix64, everything --fuzz-config picks comes from one of these so a seed always picks the same
+pub struct FuzzRng(u64);
+impl FuzzRng {
+    pub fn new(seed: u64) -> FuzzRng {
+        FuzzRng(seed)
+    }
+    /// A number below `n`
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        (z ^ (z >> 31)) % n
+    }
+}
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuzzMachine {
+    pub xlen: Xlen,
+    pub ram_base: u64,
+    pub ram_size: u64,
+    pub harts: usize,
+    /// compressed instructions on (misa.C)
+    pub ext_c: bool,
+}
+impl std::fmt::Display for FuzzMachine {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let isa = match (self.xlen, self.ext_c) {
+            (Xlen::X32, true) => "rv32gc",
+            (Xlen::X32, false) => "rv32g",
+            (Xlen::X64, true) => "rv64gc",
+            (Xlen::X64, false) => "rv64g",
+        };
+        write!(f, "{}, {} hart(s), {}K of RAM at {:#x}", isa, self.harts, self.ram_size >> 10, self.ram_base)
+    }
+}
+impl FuzzMachine {
+    pub fn from_seed(seed: u64) -> FuzzMachine {
+        let mut rng = FuzzRng::new(seed);
+        let mut next = |n: u64| rng.below(n);
+        let xlen = if next(2) == 0 { Xlen::X32 } else { Xlen::X64 };
+        let harts = 1 + next(8) as usize;
+        // room for the code page and every hart's scratch, up to 64M
+        let min_pages = 1 + (harts as u64 * SMOKE_WORDS * 4 + PAGE - 1) / PAGE;
+        let ram_size = (min_pages + next(16 * 1024 - min_pages)) * PAGE;
+        // rv32 RAM has to end below 4G; rv64 anywhere in the lower 1T
+        let top = match xlen {
+            Xlen::X32 => 1 << 32,
+            Xlen::X64 => 1 << 40,
+        };
+        let ram_base = next((top - ram_size) / PAGE) * PAGE;
+        FuzzMachine { xlen, ram_base, ram_size, harts, ext_c: next(2) == 1 }
+    }
+    /// Runs the smoke workload on every hart, taking turns. Err says which hart got what wrong
+    pub fn smoke(&self) -> Result<(), String> {
+        let mem = GuestMemory::new(&[(GuestAddress(self.ram_base), self.ram_size)])
+            .map_err(|e| format!("couldn't set up the RAM: {}", e))?;
+        for (i, w) in SMOKE.iter().enumerate() {
+            mem.write_obj_at_addr(*w, GuestAddress(self.ram_base + i as u64 * 4))
+                .map_err(|e| format!("couldn't load the workload: {}", e))?;
+        }
+        let ram_end = self.ram_base + self.ram_size;
+        // scratch at the top of RAM, so a wrong idea of its size shows
+        let scratch = |id: u64| ram_end - (id + 1) * SMOKE_WORDS * 4;
+        let mut harts: Vec<RiscvInt> = (0..self.harts as u64).map(|id| {
+            let mut cpu = RiscvInt::init_systemmode(self.xlen, mem.clone());
+            cpu.cache_enabled = true;
+            cpu.csr[CSR_MHARTID_ADDRESS] = id;
+            if !self.ext_c {
+                cpu.csr[CSR_MISA_ADDRESS] &= !MISA_C;
+            }
+            cpu.pc = self.ram_base;
+            cpu.regs[10] = id;
+            cpu.regs[11] = scratch(id);
+            cpu.regs[12] = SMOKE_WORDS;
+            cpu
+        }).collect();
+        let halted = |cpu: &RiscvInt| cpu.pc == self.ram_base + HALT;
+        while harts.iter().any(|h| !halted(h) && h.icount < SMOKE_BUDGET) {
+            for h in harts.iter_mut().filter(|h| !halted(h)) {
+                h.run(SMOKE_QUANTUM);
+            }
+        }
+        for (id, h) in harts.iter().enumerate() {
+            let id = id as u64;
+            if !halted(h) {
+                return Err(format!("hart {} didn't finish, it's at {:#x}", id, h.pc));
+            }
+            let want: u64 = (0..SMOKE_WORDS).map(|i| i ^ id).sum();
+            if h.regs[14] != id {
+                return Err(format!("hart {} read {} from mhartid", id, h.regs[14]));
+            }
+            if h.regs[13] != want {
+                return Err(format!("hart {} added up {}, not {}", id, h.regs[13], want));
+            }
+            // the other harts' scratch is right below, nothing of theirs should have landed here
+            let last = scratch(id) + (SMOKE_WORDS - 1) * 4;
+            match mem.read_obj_from_addr::<u32>(GuestAddress(last)) {
+                Ok(v) if v as u64 == (SMOKE_WORDS - 1) ^ id => {}
+                other => return Err(format!("hart {}'s last word at {:#x} is {:?}", id, last, other.ok())),
+            }
+        }
+        Ok(())
+    }
+}
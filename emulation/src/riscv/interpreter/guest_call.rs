@@ -0,0 +1,167 @@
+// Calling guest functions from the host.
+// Arguments are placed per the RISC-V psABI (hard float, so LP64D/ILP32D): integers in a0-a7,
+// floats in fa0-fa7 and then in whatever integer registers are left, and everything past that on
+// the stack. ra is pointed at an address the guest can never return to by itself, and we run
+// until pc lands there. Register state is put back afterwards, so this can be done between two
+// instructions of a running guest without it noticing (anything the function itself did to
+// memory stays, of course). Traps and syscalls the function runs into are dealt with as usual.
+use crate::riscv::common::{RISCV_STACKPOINTER_REG, Xlen};
+use crate::riscv::interpreter::floating_helpers::{read_float32, read_float64, write_float32, write_float64};
+use crate::riscv::interpreter::main::RiscvInt;
+
+// sign extension of 0xfffffff0, jumps on rv32 cut it back down to that
+pub const GUEST_CALL_RETURN_ADDR: u64 = 0xffff_ffff_ffff_fff0;
+pub const GUEST_CALL_DEFAULT_BUDGET: u64 = 100_000_000;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum GuestCallArg {
+    Int(u64),
+    F32(f32),
+    F64(f64),
+}
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum GuestCallError {
+    /// function didn't return within the instruction budget
+    Timeout,
+    /// couldn't write the stack passed arguments
+    StackWrite(u64),
+}
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GuestCallResult {
+    pub a0: u64,
+    pub a1: u64, // second half of 2*xlen return values
+    fa0_f32: u32,
+    fa0_f64: u64,
+    /// instructions it took
+    pub icount: u64,
+}
+impl GuestCallResult {
+    pub fn as_f32(&self) -> f32 {
+        f32::from_bits(self.fa0_f32)
+    }
+    pub fn as_f64(&self) -> f64 {
+        f64::from_bits(self.fa0_f64)
+    }
+}
+impl RiscvInt {
+    pub fn call_guest_function(&mut self, func: u64, args: &[GuestCallArg]) -> Result<GuestCallResult, GuestCallError> {
+        self.call_guest_function_budget(func, args, GUEST_CALL_DEFAULT_BUDGET)
+    }
+    /// Like call_guest_function, but gives up after `budget` instructions
+    pub fn call_guest_function_budget(&mut self, func: u64, args: &[GuestCallArg], budget: u64) -> Result<GuestCallResult, GuestCallError> {
+        let saved_regs = self.regs;
+        let saved_fregs = self.fregs;
+        let saved_pc = self.pc;
+        let saved_want_pc = self.want_pc.take();
+        let saved_limit = self.icount_limit;
+        #[cfg(feature = "linux-usermode")]
+        let saved_event = self.user_struct.sched_event.take();
+
+        let res = self.guest_call_inner(func, args, budget);
+
+        self.regs = saved_regs;
+        self.fregs = saved_fregs;
+        self.pc = saved_pc;
+        self.want_pc = saved_want_pc;
+        self.icount_limit = saved_limit;
+        self.stop_exec = false;
+        #[cfg(feature = "linux-usermode")]
+        {
+            self.user_struct.sched_event = saved_event;
+        }
+        res
+    }
+    fn guest_call_inner(&mut self, func: u64, args: &[GuestCallArg], budget: u64) -> Result<GuestCallResult, GuestCallError> {
+        let width: u64 = match self.xlen {
+            Xlen::X32 => 4,
+            Xlen::X64 => 8,
+        };
+        let mut next_int = 10; // a0
+        let mut next_float = 10; // fa0
+        let mut on_stack: Vec<u64> = vec![];
+        for a in args {
+            match *a {
+                GuestCallArg::Int(v) => {
+                    let v = self.sign_ext(v);
+                    if next_int <= 17 {
+                        self.regs[next_int] = v;
+                        next_int += 1;
+                    } else {
+                        on_stack.push(v);
+                    }
+                }
+                GuestCallArg::F32(f) => {
+                    if next_float <= 17 {
+                        write_float32(self, f.to_bits(), next_float);
+                        next_float += 1;
+                    } else if next_int <= 17 {
+                        self.regs[next_int] = self.sign_ext(f.to_bits() as u64);
+                        next_int += 1;
+                    } else {
+                        on_stack.push(f.to_bits() as u64);
+                    }
+                }
+                GuestCallArg::F64(f) => {
+                    if next_float <= 17 {
+                        write_float64(self, f.to_bits(), next_float);
+                        next_float += 1;
+                    } else if self.xlen == Xlen::X32 {
+                        // goes in a register pair, or split between a7 and the stack
+                        let lo = self.sign_ext(f.to_bits() & 0xffffffff);
+                        let hi = self.sign_ext(f.to_bits() >> 32);
+                        if next_int <= 16 {
+                            self.regs[next_int] = lo;
+                            self.regs[next_int + 1] = hi;
+                            next_int += 2;
+                        } else if next_int == 17 {
+                            self.regs[17] = lo;
+                            next_int += 1;
+                            on_stack.push(hi);
+                        } else {
+                            on_stack.push(lo);
+                            on_stack.push(hi);
+                        }
+                    } else if next_int <= 17 {
+                        self.regs[next_int] = f.to_bits();
+                        next_int += 1;
+                    } else {
+                        on_stack.push(f.to_bits());
+                    }
+                }
+            }
+        }
+        // stack stays 16 byte aligned at the call, first stack argument is at sp
+        let mut sp = self.regs[RISCV_STACKPOINTER_REG];
+        sp = sp.wrapping_sub(on_stack.len() as u64 * width) & !0xf;
+        for (i, v) in on_stack.iter().enumerate() {
+            let addr = sp + i as u64 * width;
+            let res = self.host_side(|c| match c.xlen {
+                Xlen::X32 => c.write32(addr, *v as u32, false),
+                Xlen::X64 => c.write64(addr, *v, false),
+            });
+            if res.is_err() {
+                return Err(GuestCallError::StackWrite(addr));
+            }
+        }
+        self.regs[RISCV_STACKPOINTER_REG] = sp;
+        self.regs[1] = GUEST_CALL_RETURN_ADDR; // ra
+        self.pc = self.cull_reg(func);
+        let ret_pc = self.cull_reg(GUEST_CALL_RETURN_ADDR);
+        let start = self.icount;
+        self.icount_limit = start.saturating_add(budget);
+        // every jump ends a block, so the return lands us exactly on the trampoline
+        while self.pc != ret_pc {
+            if self.icount >= self.icount_limit {
+                return Err(GuestCallError::Timeout);
+            }
+            self.run_once();
+        }
+        Ok(GuestCallResult {
+            a0: self.regs[10],
+            a1: self.regs[11],
+            fa0_f32: read_float32(self, 10),
+            fa0_f64: read_float64(self, 10),
+            icount: self.icount - start,
+        })
+    }
+}
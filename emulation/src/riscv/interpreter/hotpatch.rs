@@ -0,0 +1,103 @@
+// Patching guest code from the debugger (or anything else holding a RiscvInt): the bytes go in
+// whatever the page protections say, with the other harts paused, and every hart's block cache
+// forgets the pages they touch. common::hotpatch has how the other harts get stopped and told.
+use crate::common::engine::ExecutionEngine;
+use crate::common::hotpatch;
+use crate::riscv::interpreter::main::RiscvInt;
+use crate::riscv::mem::{MemAccessType, RISCV_PAGE_SIZE};
+
+/// for breakpoints the guest runs into by itself
+pub const EBREAK: [u8; 4] = 0x0010_0073u32.to_le_bytes();
+pub const C_EBREAK: [u8; 2] = 0x9002u16.to_le_bytes();
+/// addi x0, x0, 0
+pub const NOP: [u8; 4] = 0x0000_0013u32.to_le_bytes();
+
+impl RiscvInt {
+    /// (physical address, length) of the code at [addr, addr + len), a piece per page
+    fn code_ranges(&mut self, addr: u64, len: u64) -> Result<Vec<(u64, u64)>, String> {
+        let end = addr.checked_add(len).ok_or_else(|| format!("{:#x}+{:#x} wraps around", addr, len))?;
+        let macc = self.gen_mem_cirum(MemAccessType::Execute);
+        let mut out = vec![];
+        let mut at = addr;
+        while at < end {
+            let n = (RISCV_PAGE_SIZE - (at & (RISCV_PAGE_SIZE - 1))).min(end - at);
+            let phys = self.memsource.virt2phys(at, macc)
+                .map_err(|_| format!("{:#x} isn't mapped executable", at))?;
+            out.push((phys, n));
+            at += n;
+        }
+        Ok(out)
+    }
+    // old contents of [phys, phys + new.len()), then new ones in
+    fn swap_phys(&mut self, phys: u64, new: &[u8]) -> Result<Vec<u8>, String> {
+        #[cfg(feature = "linux-usermode")]
+        if self.usermode {
+            // code is usually read-only, and a bad address is EIO here instead of a SIGSEGV
+            let gate = self.patch_seen.gate();
+            let old = gate.read_host(phys, new.len()).map_err(|e| format!("{:#x}: {}", phys, e))?;
+            gate.write_host(phys, new).map_err(|e| format!("{:#x}: {}", phys, e))?;
+            return Ok(old);
+        }
+        let old = self.memsource.guest_mem.read_phys_n(phys, new.len())
+            .map_err(|e| format!("{:#x}: {:?}", phys, e))?;
+        self.memsource.guest_mem.write_phys_n(phys, new.to_vec())
+            .map_err(|e| format!("{:#x}: {:?}", phys, e))?;
+        Ok(old)
+    }
+    /// Writes `data` over the guest code at `addr` (virtual) and returns what was there. The other
+    /// harts are paused meanwhile, and none of them runs a block made from the old bytes after.
+    /// All or nothing: when a page can't be written, the ones before it get their old bytes back
+    pub fn patch_code(&mut self, addr: u64, data: &[u8]) -> Result<Vec<u8>, String> {
+        let ranges = self.code_ranges(addr, data.len() as u64)?;
+        let gate = self.patch_seen.gate().clone();
+        gate.pause(hotpatch::PAUSE_TIMEOUT)?;
+        // (phys, old bytes) of the pieces written so far
+        let mut done: Vec<(u64, Vec<u8>)> = vec![];
+        let mut off = 0;
+        let mut res = Ok(());
+        for &(phys, n) in &ranges {
+            match self.swap_phys(phys, &data[off..off + n as usize]) {
+                Ok(o) => done.push((phys, o)),
+                Err(e) => {
+                    res = Err(e);
+                    break;
+                }
+            }
+            off += n as usize;
+        }
+        if let Err(mut e) = res {
+            // nothing ran in between, so the old bytes back is as if nothing happened
+            for (phys, o) in done.iter().rev() {
+                if let Err(e2) = self.swap_phys(*phys, o) {
+                    e = format!("{}, and putting back the bytes at {:#x} failed too: {}", e, phys, e2);
+                    // whatever is there now, nobody should run blocks made from it
+                    for (phys, o) in &done {
+                        gate.record_patch(*phys, o.len() as u64);
+                        ExecutionEngine::invalidate(self, *phys, o.len() as u64);
+                    }
+                    break;
+                }
+            }
+            gate.resume();
+            return Err(e);
+        }
+        for (phys, n) in ranges {
+            // the other harts find out at their next check-in, this one right away
+            gate.record_patch(phys, n);
+            ExecutionEngine::invalidate(self, phys, n);
+        }
+        gate.resume();
+        Ok(done.into_iter().flat_map(|(_, o)| o).collect())
+    }
+    /// Between blocks: parks while the harts are paused, and forgets blocks made from code that
+    /// was patched since last time
+    #[inline]
+    pub(crate) fn patch_checkpoint(&mut self) {
+        if self.patch_seen.gate().state() != self.patch_seen.state {
+            let gate = self.patch_seen.gate().clone();
+            for (addr, len) in gate.checkin(&mut self.patch_seen) {
+                ExecutionEngine::invalidate(self, addr, len);
+            }
+        }
+    }
+}
@@ -0,0 +1,428 @@
+// Litmus test runner for the memory model: runs the tests herd7 takes (RISCV ... { init } P0 | P1
+// ... exists ...) on usermode harts and checks every final state against the states herd7 says are
+// allowed (the "States" block of its output). A test runs many times, interleaved on one host
+// thread in a random order picked from the seed, and on one host thread per hart, started together,
+// which is where non-atomic AMOs, SCs that ignore other harts and missing fences show up.
+// The assembler only knows what litmus tests use: ALU ops, loads/stores, fences, branches, LR/SC
+// and AMOs, with x0-x31 register names.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::elf::UserModeRuntime;
+use crate::riscv::common::Xlen;
+use crate::riscv::interpreter::main::RiscvInt;
+
+/// Instructions a hart may run before the test is declared stuck
+const STEP_BUDGET: u64 = 100_000;
+/// Bytes between locations, so each gets a cache line of its own
+const LOC_STRIDE: usize = 64;
+
+/// A final state: "1:x5" / "x" -> value
+pub type Outcome = BTreeMap<String, u64>;
+
+#[derive(Debug, Clone)]
+enum InitVal {
+    Num(u64),
+    /// address of a location
+    Loc(String),
+}
+#[derive(Debug, Clone)]
+pub struct LitmusTest {
+    pub name: String,
+    regs: Vec<(usize, usize, InitVal)>,
+    mem: Vec<(String, u64)>,
+    locs: Vec<String>,
+    progs: Vec<Vec<u32>>,
+}
+#[derive(Debug, Default)]
+pub struct LitmusReport {
+    /// every state seen, with how often
+    pub seen: BTreeMap<Outcome, u64>,
+    /// the ones herd7 doesn't allow
+    pub forbidden: Vec<Outcome>,
+}
+fn parse_reg(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    if s == "zero" {
+        return Ok(0);
+    }
+    s.strip_prefix('x').and_then(|n| n.parse().ok()).filter(|n| *n < 32)
+        .ok_or_else(|| format!("bad register {}", s))
+}
+fn parse_num(s: &str) -> Option<i64> {
+    let s = s.trim();
+    if let Some(h) = s.strip_prefix("0x") {
+        i64::from_str_radix(h, 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+// "off(xN)" or "(xN)"
+fn parse_mem(s: &str) -> Result<(i64, usize), String> {
+    let (off, rest) = s.trim().split_once('(').ok_or_else(|| format!("bad address {}", s))?;
+    let off = if off.trim().is_empty() { 0 } else { parse_num(off).ok_or_else(|| format!("bad offset {}", off))? };
+    Ok((off, parse_reg(rest.trim_end_matches(')'))?))
+}
+fn r_type(f7: u32, rs2: usize, rs1: usize, f3: u32, rd: usize, op: u32) -> u32 {
+    f7 << 25 | (rs2 as u32) << 20 | (rs1 as u32) << 15 | f3 << 12 | (rd as u32) << 7 | op
+}
+fn i_type(imm: i64, rs1: usize, f3: u32, rd: usize, op: u32) -> u32 {
+    ((imm as u32) & 0xfff) << 20 | (rs1 as u32) << 15 | f3 << 12 | (rd as u32) << 7 | op
+}
+fn s_type(imm: i64, rs2: usize, rs1: usize, f3: u32) -> u32 {
+    let imm = imm as u32;
+    (imm >> 5 & 0x7f) << 25 | (rs2 as u32) << 20 | (rs1 as u32) << 15 | f3 << 12 | (imm & 0x1f) << 7 | 0x23
+}
+fn b_type(off: i64, rs2: usize, rs1: usize, f3: u32) -> u32 {
+    let o = off as u32;
+    (o >> 12 & 1) << 31 | (o >> 5 & 0x3f) << 25 | (rs2 as u32) << 20 | (rs1 as u32) << 15 | f3 << 12
+        | (o >> 1 & 0xf) << 8 | (o >> 11 & 1) << 7 | 0x63
+}
+fn j_type(off: i64, rd: usize) -> u32 {
+    let o = off as u32;
+    (o >> 20 & 1) << 31 | (o >> 1 & 0x3ff) << 21 | (o >> 11 & 1) << 20 | (o >> 12 & 0xff) << 12 | (rd as u32) << 7 | 0x6f
+}
+fn fence_set(s: &str) -> Result<u32, String> {
+    s.chars().try_fold(0, |acc, c| match c {
+        'i' => Ok(acc | 8),
+        'o' => Ok(acc | 4),
+        'r' => Ok(acc | 2),
+        'w' => Ok(acc | 1),
+        _ => Err(format!("bad fence set {}", s)),
+    })
+}
+fn assemble_one(ins: &str, pc: i64, labels: &HashMap<String, i64>) -> Result<u32, String> {
+    let (mn, ops) = ins.split_once(char::is_whitespace).unwrap_or((ins, ""));
+    let ops: Vec<&str> = ops.split(',').map(|o| o.trim()).filter(|o| !o.is_empty()).collect();
+    let op = |i: usize| ops.get(i).copied().ok_or_else(|| format!("{}: missing operand", ins));
+    let label = |i: usize| -> Result<i64, String> {
+        let l = op(i)?;
+        labels.get(l).map(|t| t - pc).ok_or_else(|| format!("unknown label {}", l))
+    };
+    let alu = ["add", "sub", "xor", "or", "and"];
+    let alui = ["addi", "xori", "ori", "andi"];
+    let loads = ["lb", "lh", "lw", "ld", "lbu", "lhu", "lwu"];
+    let stores = ["sb", "sh", "sw", "sd"];
+    let f3 = |list: &[&str], m: &str| list.iter().position(|x| *x == m);
+    // amo name -> funct5, with the .aq/.rl suffixes split off
+    let mut parts = mn.split('.');
+    let base = parts.next().unwrap();
+    let width = parts.next();
+    let (mut aq, mut rl) = (0, 0);
+    for p in parts {
+        match p {
+            "aq" => aq = 1,
+            "rl" => rl = 1,
+            "aqrl" => { aq = 1; rl = 1; }
+            _ => return Err(format!("bad suffix in {}", mn)),
+        }
+    }
+    let amo_f5 = match base {
+        "lr" => Some(0b00010),
+        "sc" => Some(0b00011),
+        "amoswap" => Some(0b00001),
+        "amoadd" => Some(0b00000),
+        "amoxor" => Some(0b00100),
+        "amoand" => Some(0b01100),
+        "amoor" => Some(0b01000),
+        "amomin" => Some(0b10000),
+        "amomax" => Some(0b10100),
+        "amominu" => Some(0b11000),
+        "amomaxu" => Some(0b11100),
+        _ => None,
+    };
+    if let (Some(f5), Some(w)) = (amo_f5, width) {
+        let f3 = match w {
+            "w" => 2,
+            "d" => 3,
+            _ => return Err(format!("bad width in {}", mn)),
+        };
+        let f7 = f5 << 2 | aq << 1 | rl;
+        let rd = parse_reg(op(0)?)?;
+        return Ok(if base == "lr" {
+            r_type(f7, 0, parse_mem(op(1)?)?.1, f3, rd, 0x2f)
+        } else {
+            r_type(f7, parse_reg(op(1)?)?, parse_mem(op(2)?)?.1, f3, rd, 0x2f)
+        });
+    }
+    Ok(match mn {
+        m if alu.contains(&m) => {
+            let f3 = [0, 0, 4, 6, 7][f3(&alu[..], m).unwrap()];
+            let f7 = if m == "sub" { 0x20 } else { 0 };
+            r_type(f7, parse_reg(op(2)?)?, parse_reg(op(1)?)?, f3, parse_reg(op(0)?)?, 0x33)
+        }
+        m if alui.contains(&m) => {
+            let f3 = [0, 4, 6, 7][f3(&alui[..], m).unwrap()];
+            let imm = parse_num(op(2)?).ok_or_else(|| format!("bad immediate in {}", ins))?;
+            i_type(imm, parse_reg(op(1)?)?, f3, parse_reg(op(0)?)?, 0x13)
+        }
+        "li" => {
+            let imm = parse_num(op(1)?).filter(|i| (-2048..2048).contains(i))
+                .ok_or_else(|| format!("li only does 12 bit immediates: {}", ins))?;
+            i_type(imm, 0, 0, parse_reg(op(0)?)?, 0x13)
+        }
+        "mv" => i_type(0, parse_reg(op(1)?)?, 0, parse_reg(op(0)?)?, 0x13),
+        "nop" => i_type(0, 0, 0, 0, 0x13),
+        m if loads.contains(&m) => {
+            let (off, rs1) = parse_mem(op(1)?)?;
+            i_type(off, rs1, f3(&loads[..], m).unwrap() as u32, parse_reg(op(0)?)?, 0x03)
+        }
+        m if stores.contains(&m) => {
+            let (off, rs1) = parse_mem(op(1)?)?;
+            s_type(off, parse_reg(op(0)?)?, rs1, f3(&stores[..], m).unwrap() as u32)
+        }
+        "fence" => {
+            let (pred, succ) = if ops.is_empty() { (0xf, 0xf) } else { (fence_set(op(0)?)?, fence_set(op(1)?)?) };
+            pred << 24 | succ << 20 | 0x0f
+        }
+        "fence.tso" => 0x8330_000f,
+        "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu" => {
+            let f3 = match mn { "beq" => 0, "bne" => 1, "blt" => 4, "bge" => 5, "bltu" => 6, _ => 7 };
+            b_type(label(2)?, parse_reg(op(1)?)?, parse_reg(op(0)?)?, f3)
+        }
+        "j" => j_type(label(0)?, 0),
+        _ => return Err(format!("can't assemble {}", ins)),
+    })
+}
+/// Assemble one hart's program, labels are "NAME:" on a line of their own (or before an instruction)
+fn assemble(lines: &[String]) -> Result<Vec<u32>, String> {
+    let mut labels = HashMap::new();
+    let mut insns = vec![];
+    for l in lines {
+        let mut l = l.as_str();
+        while let Some((lab, rest)) = l.split_once(':') {
+            labels.insert(lab.trim().to_string(), insns.len() as i64 * 4);
+            l = rest.trim();
+        }
+        if !l.is_empty() {
+            insns.push(l.to_string());
+        }
+    }
+    insns.iter().enumerate().map(|(i, ins)| assemble_one(ins, i as i64 * 4, &labels)).collect()
+}
+impl LitmusTest {
+    pub fn parse(text: &str) -> Result<LitmusTest, String> {
+        let mut lines = text.lines().map(|l| l.trim()).filter(|l| !l.is_empty());
+        let head = lines.next().ok_or("empty test")?;
+        let name = match head.split_whitespace().collect::<Vec<_>>()[..] {
+            ["RISCV", name] => name.to_string(),
+            _ => return Err(format!("not a RISC-V litmus test: {}", head)),
+        };
+        // init block, after an optional quoted description
+        let mut init = String::new();
+        for l in lines.by_ref() {
+            if l.starts_with('"') {
+                continue;
+            }
+            init.push_str(l);
+            if l.contains('}') {
+                break;
+            }
+        }
+        let init = init.trim().trim_start_matches('{').trim_end_matches('}');
+        let mut regs = vec![];
+        let mut mem = vec![];
+        let mut locs: Vec<String> = vec![];
+        for ent in init.split(';').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+            let (k, v) = ent.split_once('=').ok_or_else(|| format!("bad init {}", ent))?;
+            // type names may come first ("int x=1"), only the last word is the name
+            let k = k.split_whitespace().last().unwrap_or("");
+            let v = v.trim();
+            let val = match parse_num(v) {
+                Some(n) => InitVal::Num(n as u64),
+                None => InitVal::Loc(v.to_string()),
+            };
+            if let InitVal::Loc(l) = &val {
+                if !locs.contains(l) {
+                    locs.push(l.clone());
+                }
+            }
+            match k.split_once(':') {
+                Some((hart, reg)) => {
+                    let hart = hart.parse().map_err(|_| format!("bad hart in {}", ent))?;
+                    regs.push((hart, parse_reg(reg)?, val));
+                }
+                None => {
+                    let n = match val {
+                        InitVal::Num(n) => n,
+                        InitVal::Loc(_) => return Err(format!("bad init {}", ent)),
+                    };
+                    if !locs.iter().any(|l| l == k) {
+                        locs.push(k.to_string());
+                    }
+                    mem.push((k.to_string(), n));
+                }
+            }
+        }
+        // P0 | P1 ; then one row of instructions per line, until the condition
+        let header = lines.next().ok_or("no program")?;
+        let nharts = header.trim_end_matches(';').split('|').count();
+        let mut progs: Vec<Vec<String>> = vec![vec![]; nharts];
+        for l in lines {
+            if ["exists", "~exists", "forall", "locations", "("].iter().any(|p| l.starts_with(p)) {
+                break;
+            }
+            for (i, ins) in l.trim_end_matches(';').split('|').enumerate() {
+                if i < nharts && !ins.trim().is_empty() {
+                    progs[i].push(ins.trim().to_string());
+                }
+            }
+        }
+        let progs = progs.iter().map(|p| assemble(p)).collect::<Result<Vec<_>, _>>()?;
+        Ok(LitmusTest { name, regs, mem, locs, progs })
+    }
+    // fresh memory and harts for one run
+    fn setup(&self) -> (Vec<u64>, Vec<RiscvInt>) {
+        let words = (self.locs.len().max(1) * LOC_STRIDE) / 8;
+        let mem = vec![0u64; words];
+        let base = mem.as_ptr() as u64;
+        let addr = |l: &str| base + (self.locs.iter().position(|x| x == l).unwrap() * LOC_STRIDE) as u64;
+        for (l, v) in &self.mem {
+            unsafe { *(addr(l) as *mut u64) = *v };
+        }
+        let harts = self.progs.iter().enumerate().map(|(i, prog)| {
+            let mut cpu = RiscvInt::init_usermode(Xlen::X64, UserModeRuntime::default());
+            cpu.pc = prog.as_ptr() as u64;
+            for (h, r, v) in &self.regs {
+                if *h == i {
+                    cpu.regs[*r] = match v {
+                        InitVal::Num(n) => *n,
+                        InitVal::Loc(l) => addr(l),
+                    };
+                }
+            }
+            cpu
+        }).collect();
+        (mem, harts)
+    }
+    fn outcome(&self, mem: &[u64], harts: &[RiscvInt], keys: &[String]) -> Outcome {
+        keys.iter().map(|k| {
+            let v = match k.split_once(':') {
+                Some((h, r)) => harts[h.parse::<usize>().unwrap()].regs[parse_reg(r).unwrap()],
+                // the harts wrote it behind our back
+                None => unsafe {
+                    std::ptr::read_volatile(&mem[self.locs.iter().position(|x| x == k).unwrap() * LOC_STRIDE / 8])
+                },
+            };
+            (k.clone(), v)
+        }).collect()
+    }
+    fn end_pc(&self, hart: usize) -> u64 {
+        self.progs[hart].as_ptr() as u64 + self.progs[hart].len() as u64 * 4
+    }
+}
+// one instruction, false once the hart ran off the end of its program
+fn step(cpu: &mut RiscvInt, end: u64) -> bool {
+    if cpu.pc == end {
+        return false;
+    }
+    if cpu.icount >= STEP_BUDGET {
+        panic!("hart stuck at {:#x}", cpu.pc);
+    }
+    cpu.step_one_instr();
+    if let Some(t) = cpu.trap {
+        panic!("trap in litmus test: {:?}", t);
+    }
+    cpu.apply_want_pc();
+    cpu.stop_exec = false;
+    true
+}
+/// Allowed final states from herd7's output
+pub fn parse_herd_states(text: &str) -> Result<Vec<Outcome>, String> {
+    let mut lines = text.lines().map(|l| l.trim());
+    let n: usize = lines.by_ref()
+        .find_map(|l| l.strip_prefix("States ").and_then(|n| n.trim().parse().ok()))
+        .ok_or("no States in herd7 output")?;
+    let mut states = vec![];
+    for l in lines.take(n) {
+        let mut st = Outcome::new();
+        for ent in l.split(';').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+            let (k, v) = ent.split_once('=').ok_or_else(|| format!("bad state {}", l))?;
+            // newer herd7 writes memory as [x]
+            let k = k.trim().trim_start_matches('[').trim_end_matches(']');
+            let v = parse_num(v).ok_or_else(|| format!("bad value in {}", l))?;
+            st.insert(k.to_string(), v as u64);
+        }
+        states.push(st);
+    }
+    Ok(states)
+}
+// xorshift, like everywhere else here
+fn next_rand(r: &mut u64) -> u64 {
+    *r ^= *r << 13;
+    *r ^= *r >> 7;
+    *r ^= *r << 17;
+    *r
+}
+fn record(report: &mut LitmusReport, allowed: &[Outcome], o: Outcome) {
+    if !allowed.contains(&o) && !report.forbidden.contains(&o) {
+        report.forbidden.push(o.clone());
+    }
+    *report.seen.entry(o).or_insert(0) += 1;
+}
+/// Run `test` `runs` times with the harts interleaved on this thread, a few instructions at a time
+/// in an order picked from `seed`
+pub fn run_interleaved(test: &LitmusTest, allowed: &[Outcome], runs: u64, seed: u64) -> LitmusReport {
+    let keys: Vec<String> = allowed.first().map(|s| s.keys().cloned().collect()).unwrap_or_default();
+    let mut rng = seed | 1;
+    let mut report = LitmusReport::default();
+    for _ in 0..runs {
+        let (mem, mut harts) = test.setup();
+        let mut live: Vec<usize> = (0..harts.len()).collect();
+        while !live.is_empty() {
+            let pick = (next_rand(&mut rng) % live.len() as u64) as usize;
+            let h = live[pick];
+            let n = 1 + next_rand(&mut rng) % 3;
+            for _ in 0..n {
+                if !step(&mut harts[h], test.end_pc(h)) {
+                    live.swap_remove(pick);
+                    break;
+                }
+            }
+        }
+        let o = test.outcome(&mem, &harts, &keys);
+        record(&mut report, allowed, o);
+    }
+    report
+}
+/// Run `test` `runs` times with every hart on a host thread of its own, released together after
+/// a random head start
+pub fn run_threaded(test: &LitmusTest, allowed: &[Outcome], runs: u64, seed: u64) -> LitmusReport {
+    let keys: Vec<String> = allowed.first().map(|s| s.keys().cloned().collect()).unwrap_or_default();
+    let mut rng = seed | 1;
+    let mut report = LitmusReport::default();
+    let test = Arc::new(test.clone());
+    for _ in 0..runs {
+        let (mem, harts) = test.setup();
+        let ready = Arc::new(AtomicUsize::new(0));
+        let n = harts.len();
+        // the harts are moved over by their registers, interpreters don't go between threads
+        let starts: Vec<([u64; 32], u64)> = harts.iter().map(|c| (c.regs, c.pc)).collect();
+        let handles: Vec<_> = starts.into_iter().enumerate().map(|(i, (regs, pc))| {
+            let ready = ready.clone();
+            let test = test.clone();
+            let delay = next_rand(&mut rng) % 200;
+            std::thread::spawn(move || {
+                let mut cpu = RiscvInt::init_usermode(Xlen::X64, UserModeRuntime::default());
+                cpu.regs = regs;
+                cpu.pc = pc;
+                ready.fetch_add(1, Ordering::SeqCst);
+                while ready.load(Ordering::SeqCst) < n {
+                    std::hint::spin_loop();
+                }
+                for _ in 0..delay {
+                    std::hint::spin_loop();
+                }
+                while step(&mut cpu, test.end_pc(i)) {}
+                cpu.regs
+            })
+        }).collect();
+        let mut done = harts;
+        for (i, h) in handles.into_iter().enumerate() {
+            done[i].regs = h.join().unwrap();
+        }
+        let o = test.outcome(&mem, &done, &keys);
+        record(&mut report, allowed, o);
+    }
+    report
+}
@@ -7,11 +7,19 @@ use sync::Mutex;
 use vm_memory::GuestMemory;
 use rustc_hash::FxHashMap;
 use crate::common::memory::{flat_mem, MemEndian};
-use crate::riscv::common::{Exception, get_privilege_encoding, get_trap_cause, Priv, RISCV_STACKPOINTER_REG, RiscvArgs, Trap, Xlen, xlen2bits, xlen2misa};
+use crate::common::shadow_stack::{DEFAULT_SHADOW_STACK_DEPTH, ShadowStack};
+use crate::common::pacing::Pacer;
+use crate::common::hart_stats::{Counter, HartCounters};
+use crate::common::spin::{state_digest, SpinDetector, SpinHit};
+use crate::common::hotpatch::{PatchGate, PatchSeen};
+use crate::riscv::interpreter::custom::CustomExtensions;
+use crate::riscv::interpreter::block_store::BlockStore;
+use crate::riscv::interpreter::guest_call::GUEST_CALL_RETURN_ADDR;
+use crate::riscv::common::{Exception, fault_trap_cause, get_privilege_encoding, get_trap_cause, Priv, RISCV_STACKPOINTER_REG, RiscvArgs, Trap, Xlen, xlen2bits, xlen2misa};
 use crate::riscv::common::Exception::{EnvironmentCallFromMMode, EnvironmentCallFromUMode};
 use crate::riscv::decoder;
 use crate::riscv::interpreter::consts::*;
-use crate::riscv::mem::{get_read_access_type, MemAccessCircumstances, MemAccessType, RISCV_PAGE_OFFSET, RISCV_PAGE_SHIFT, RISCV_PAGE_SIZE, RiscVMem};
+use crate::riscv::mem::{get_read_access_type, MemAccessCircumstances, MemAccessType, RISCV_PAGE_OFFSET, RISCV_PAGE_SHIFT, RISCV_PAGE_SIZE, PageMode, RiscVMem};
 //use crate::riscv::vector::vect_state;
 use crate::riscv::interpreter::core::illegal_instr;
 use crate::riscv::interpreter::defs::or;
@@ -21,10 +29,13 @@ cfg_if::cfg_if! {
     if #[cfg(feature = "linux-usermode")] {
         use crate::elf::UserModeRuntime;
         use crate::linux_usermode::defs::{GenericStat, read32_advance_ptr, read64_advance_ptr};
-        use crate::linux_usermode::main::{dispatch, SyscallIn, SyscallOut, UsermodeCpu};
+        use crate::linux_usermode::main::{dispatch, errno_out, finish_reports, group_exit_unwind, guest_killed, publish_cpu_time, SyscallIn, SyscallOut, UsermodeCpu};
+        use crate::linux_usermode::summary::{RunExit, TrapKind};
+        use crate::linux_usermode::sched::SchedEvent;
         use crate::linux_usermode::signals::{block_all_signals, GenericSigactionArg, GenericStackt,
-            get_generic_sigaction_64, SigEntry, SigInfo, Sigmask, SIGNAL_AVAIL, SINFO};
-        use crate::riscv::ume::defs::{riscv_translate_syscall, write_riscv_stat, write_riscv_sysinfo};
+            get_generic_sigaction_64, SigEntry, SigInfo, Sigmask, bind_thread_signals, fatal_signal};
+        use std::sync::atomic::Ordering;
+        use crate::riscv::ume::defs::{riscv_syscall_table, riscv_translate_syscall, write_riscv_stat, write_riscv_sysinfo};
         use crate::riscv::ume::signals::setup_rt_frame;
     }
 }
@@ -79,13 +90,38 @@ pub struct RiscvInt {
     pub usermode: bool,
     pub is_reservation: bool,
     pub res_val: u64,
-    pub res_len: u8
+    pub res_len: u8,
+    pub res_data: u64, // what lr read, sc only succeeds if it's still there
+    pub res_epoch: u64, // stores to the reservation's granule before lr, see atomic
+    pub icount: u64, // instructions retired
+    pub icount_limit: u64, // stop executing once icount reaches this, checked after every instruction
+    #[cfg(feature = "linux-usermode")]
+    pub sched_spawned: Vec<Box<RiscvInt>>, // threads created during the current deterministic slice
+    pub shadow_stack: Option<ShadowStack>, // updated by jal/jalr when enabled
+    pub pacer: Option<Arc<Mutex<Pacer>>>, // set when the instruction rate is limited
+    pub pace_mark: u64, // icount when the pacer was last told
+    pub custom: Option<Arc<CustomExtensions>>, // custom opcode and vendor CSR handlers
+    pub stats: Option<Arc<HartCounters>>, // memory and block cache counters, when stats are on
+    pub spin: Option<SpinDetector>, // busy-wait detection, when asked for
+    pub block_store: Option<Arc<BlockStore>>, // decoded blocks kept between runs, see block_store
+    pub patch_seen: PatchSeen, // code patch state at the last check-in, see common::hotpatch
+    pub isa_counts: Option<FxHashMap<(u64, u32), u64>>, // (pc, instruction) counts for the ISA usage report
+    pub host_access: bool, // the emulator itself is touching guest memory, see host_side()
 
 }
 pub enum ExtensionSearchMode {
     AtLeastOne,
     All,
 }
+/// misa.C, the only writable bit of misa
+pub const MISA_C: u64 = 1 << 2;
+// RV32/64IMAFDC with supervisor and user modes
+fn initial_csrs(xlen: Xlen) -> [u64; 4096] {
+    let exts = "IMAFDCSU".bytes().fold(0, |m, e| m | 1 << (e - b'A'));
+    let mut csr = [0; 4096];
+    csr[CSR_MISA_ADDRESS] = (xlen2misa(xlen) << (xlen2bits(xlen) - 2)) | exts;
+    csr
+}
 impl RiscvInt {
     pub fn init_systemmode(xlen: Xlen, vm_mem: GuestMemory) -> RiscvInt {
         RiscvInt {
@@ -95,7 +131,7 @@ impl RiscvInt {
             trap_pc: 0,
             want_pc: None,
             xlen,
-            csr: [0; 4096],
+            csr: initial_csrs(xlen),
             memsource: RiscVMem::new_system(xlen, vm_mem),
             ainstr: Default::default(),
             trap: None,
@@ -112,11 +148,45 @@ impl RiscvInt {
             is_reservation: false,
             res_val: 0,
             is_compressed: false,
-            res_len: 0
+            res_len: 0,
+            res_data: 0,
+            res_epoch: 0,
+            icount: 0,
+            icount_limit: u64::MAX,
+            #[cfg(feature = "linux-usermode")]
+            sched_spawned: vec![],
+            shadow_stack: None,
+            pacer: None,
+            pace_mark: 0,
+            custom: None,
+            stats: None,
+            spin: None,
+            block_store: None,
+            patch_seen: PatchSeen::new(&Arc::new(PatchGate::new())),
+            isa_counts: None,
+            host_access: false,
         }
     }
     #[cfg(feature = "linux-usermode")]
-    pub fn init_usermode(xlen: Xlen, ume: UserModeRuntime) -> RiscvInt {
+    pub fn init_usermode(xlen: Xlen, mut ume: UserModeRuntime) -> RiscvInt {
+        let mut memsource = RiscVMem::new_usermode(xlen);
+        memsource.poison = ume.opts.poison.clone();
+        memsource.faults = ume.opts.faults.clone();
+        // every hart gets its own counters, so it's registered here
+        ume.hart_stats = ume.stats.as_ref().map(|r| r.register());
+        memsource.stats = ume.hart_stats.clone();
+        let stats = ume.hart_stats.clone();
+        let shadow_stack = if ume.opts.shadow_stack {
+            Some(ShadowStack::new(DEFAULT_SHADOW_STACK_DEPTH))
+        } else {
+            None
+        };
+        let pacer = ume.opts.mips.map(|m| Arc::new(Mutex::new(Pacer::new(m))));
+        let custom = ume.opts.riscv_custom.clone();
+        let spin = ume.opts.spin.map(SpinDetector::new);
+        let block_store = ume.block_store.clone();
+        let isa_counts = ume.isa.as_ref().map(|_| FxHashMap::default());
+        let patch_seen = PatchSeen::new(&ume.patch_gate);
         RiscvInt {
             regs: [0; 32],
             fregs: [0; 32],
@@ -124,8 +194,8 @@ impl RiscvInt {
             want_pc: None,
             xlen,
             trap_pc: 0,
-            csr: [0; 4096],
-            memsource: RiscVMem::new_usermode(xlen),
+            csr: initial_csrs(xlen),
+            memsource,
             ainstr: Default::default(),
             trap: None,
             current_block: RiscvBlock::default(),
@@ -140,7 +210,23 @@ impl RiscvInt {
             is_reservation: false,
             res_val: 0,
             is_compressed: false,
-            res_len: 0
+            res_len: 0,
+            res_data: 0,
+            res_epoch: 0,
+            icount: 0,
+            icount_limit: u64::MAX,
+            #[cfg(feature = "linux-usermode")]
+            sched_spawned: vec![],
+            shadow_stack,
+            pacer,
+            pace_mark: 0,
+            custom,
+            stats,
+            spin,
+            block_store,
+            patch_seen,
+            isa_counts,
+            host_access: false,
         }
     }
     pub fn extension_verify(&mut self, exts: &[usize], mode: ExtensionSearchMode) -> bool {
@@ -158,17 +244,28 @@ impl RiscvInt {
         self.regs[RISCV_STACKPOINTER_REG] = val;
     }
 
+    /// Compressed instructions are on (misa.C). Without them jump targets have to be 4 byte aligned
+    pub fn ext_c(&self) -> bool {
+        self.csr[CSR_MISA_ADDRESS] & MISA_C != 0
+    }
     pub fn get_csr_raw(&self, idx: usize) -> u64 {
         self.csr[idx]
     }
     pub fn set_csr_raw(&mut self, idx: usize, val: u64) {
-        unimplemented!()
+        self.csr[idx] = val;
     }
     pub fn change_priv(&mut self, privs: Priv) {
         self.memsource.clear_cache();
         self.prvmode = privs;
     }
+    /// With --stats, a fault at `pc` goes into the per-site counts
+    fn count_trap_site(&self, pc: u64, e: Exception) {
+        if let (Some(s), Some(cause)) = (&self.memsource.stats, fault_trap_cause(e)) {
+            s.count_trap(pc, cause);
+        }
+    }
     pub fn handle_trap(&mut self, trp: Trap, trapped_pc: u64) {
+        self.count_trap_site(trapped_pc, trp.ttype);
         let mut reason = get_trap_cause(trp, self.xlen);
         let mut hsdeleg = 0;
         let intr = if 1 << (xlen2bits(self.xlen) - 1) & reason != 0 {
@@ -285,6 +382,146 @@ impl RiscvInt {
         self.current_block.instrs.push(instr);
     }
 
+    /// Where a jump to `target` actually goes. Misaligned if it's not on an instruction boundary,
+    /// an instruction page fault if it's not a canonical address for the current translation
+    /// mode (in usermode, not in the lower half of the 48 bit space the host gives us)
+    pub fn check_jump_target(&self, target: u64) -> Result<u64, Exception> {
+        let target = self.cull_reg(target);
+        let align = if self.ext_c() { 1 } else { 3 };
+        if target & align != 0 {
+            return Err(Exception::InstructionAddressMisaligned);
+        }
+        if self.xlen == Xlen::X64 {
+            if self.usermode {
+                // the host calling into the guest returns to a made up address up there
+                if target >> 47 != 0 && target != GUEST_CALL_RETURN_ADDR {
+                    return Err(Exception::InstructionPageFault);
+                }
+            } else if self.prvmode != Priv::Machine {
+                let va_bits = match self.memsource.page_mode() {
+                    PageMode::Sv39 => 39,
+                    PageMode::Sv48 => 48,
+                    PageMode::Sv57 => 57,
+                    _ => 64,
+                };
+                // upper bits all copies of the top valid one
+                let upper = (target as i64) >> (va_bits - 1);
+                if va_bits < 64 && upper != 0 && upper != -1 {
+                    return Err(Exception::InstructionPageFault);
+                }
+            }
+        }
+        Ok(target)
+    }
+    /// Jump from the current instruction. A misaligned target traps on the jump itself (with
+    /// nothing else written), anything else wrong with it faults on the fetch over there.
+    /// Returns whether the jump is taken
+    pub fn jump_to(&mut self, target: u64) -> bool {
+        let target = self.cull_reg(target);
+        if let Err(Exception::InstructionAddressMisaligned) = self.check_jump_target(target) {
+            self.set_trap(Trap {
+                ttype: Exception::InstructionAddressMisaligned,
+                val: target,
+            });
+            return false;
+        }
+        self.want_pc = Some(target);
+        self.stop_exec = true;
+        true
+    }
+    /// Move pc to want_pc, if anything wants it moved. Targets that can't be fetched from trap
+    /// like a fetch there would: guest trap handler in system mode, signal in usermode
+    pub(crate) fn apply_want_pc(&mut self) {
+        let f = match self.want_pc.take() {
+            Some(f) => f,
+            None => return,
+        };
+        match self.check_jump_target(f) {
+            Ok(t) => self.pc = t,
+            Err(e) => {
+                let f = self.cull_reg(f);
+                if self.usermode {
+                    #[cfg(feature = "linux-usermode")]
+                    {
+                        self.pc = f;
+                        self.raise_fault_signal(e);
+                    }
+                    #[cfg(not(feature = "linux-usermode"))]
+                    {
+                        unreachable!("usermode functionality not included but CPU has usermode variable set")
+                    }
+                } else {
+                    self.handle_trap(Trap { ttype: e, val: f }, f);
+                }
+            }
+        }
+    }
+    /// The guest did something it gets a signal for. Delivered through the host so that no
+    /// handler means it dies of it too
+    #[cfg(feature = "linux-usermode")]
+    fn raise_fault_signal(&mut self, e: Exception) {
+        let sig = match e {
+            Exception::InstructionAddressMisaligned | Exception::LoadAddressMisaligned
+            | Exception::StoreAddressMisaligned => libc::SIGBUS,
+            Exception::Breakpoint => libc::SIGTRAP,
+            _ => libc::SIGSEGV,
+        };
+        self.count_trap_site(self.pc, e);
+        if let Some(rec) = &self.user_struct.summary {
+            rec.count_trap(TrapKind::AccessFault);
+        }
+        // SIGSEGV and SIGBUS can't have guest handlers (see u_sigaction), a SIGTRAP one can
+        let handled = {
+            let info = self.user_struct.signals.info.lock().unwrap();
+            let guest = info.cnsts.host_to_guest_sigs.get(sig as usize).copied().unwrap_or(0);
+            info.action(guest as usize).handler_func > libc::SIG_IGN as u64
+        };
+        if !handled {
+            self.finish_reports(RunExit::Signal(sig));
+            guest_killed(&mut self.user_struct, sig);
+            return;
+        }
+        unsafe {
+            libc::raise(sig);
+        }
+    }
+    /// A host signal came in for this thread: its guest handler gets called when the guest code
+    /// goes on, or the default action is taken
+    #[cfg(feature = "linux-usermode")]
+    pub(crate) fn deliver_signal(&mut self) {
+        if !self.user_struct.signals.pending.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        let sigs = self.user_struct.signals.clone();
+        let mut aa = sigs.info.lock().unwrap();
+        let signum = match aa.use_idx.take() {
+            Some(s) => s,
+            None => return,
+        };
+        let handler = aa.action(signum).handler_func;
+        if handler == libc::SIG_DFL as u64 || handler == libc::SIG_IGN as u64 {
+            aa.use_sig = None;
+            let host = aa.cnsts.guest_to_host_sigs[signum];
+            drop(aa);
+            // the fatal ones get here at their default (see catch_fatal_defaults), the others
+            // only after an SA_RESETHAND handler ran and they're ignored then
+            if handler == libc::SIG_DFL as u64 && fatal_signal(host) {
+                self.finish_reports(RunExit::Signal(host));
+                guest_killed(&mut self.user_struct, host);
+            }
+            return;
+        }
+        setup_rt_frame(self, signum as i32, &mut aa);
+    }
+    /// The process is going away: the reports get this thread's last counts too
+    #[cfg(feature = "linux-usermode")]
+    pub(crate) fn finish_reports(&mut self, exit: RunExit) {
+        if let (Some(isa), Some(counts)) = (self.user_struct.isa.clone(), &mut self.isa_counts) {
+            isa.merge(counts);
+        }
+        publish_cpu_time(&mut self.user_struct, self.icount);
+        finish_reports(&self.user_struct, exit);
+    }
     pub fn set_trap(&mut self, trp: Trap) {
         // todo piority, i think its if one is already there
         if self.usermode {
@@ -361,10 +598,18 @@ impl RiscvInt {
              */
             unsafe {
                 if self.check_run_block(physpc) {
-                    self.build_exec(physpc).unwrap();
+                    if let Some(s) = &self.stats {
+                        s.bump(Counter::BlockMisses);
+                    }
+                    if !self.load_stored_block(physpc) {
+                        self.build_exec(physpc).unwrap();
+                        self.store_current_block();
+                    }
                     if self.check_run_block(physpc) {
                         panic!();
                     }
+                } else if let Some(s) = &self.stats {
+                    s.bump(Counter::BlockHits);
                 }
                 if self.stop_exec {
                     return Ok(());
@@ -392,7 +637,7 @@ impl RiscvInt {
 
         }
     }
-    fn build_exec(&mut self, addr: u64) -> Result<(), Trap> {
+    pub(crate) fn build_exec(&mut self, addr: u64) -> Result<(), Trap> {
         self.stop_translating = false;
         let mut iaddr = addr;
         self.current_block.begin = addr;
@@ -407,25 +652,18 @@ impl RiscvInt {
         while max_count >= 2 {
             let instr_lower = self.read16(iaddr, true, false)?; // this should be physical
             if (instr_lower & 0x3) != 0x3 {
-                self.is_compressed = true;
                 // compressed
-                if !crate::riscv::decoder16::decode(self, instr_lower as u16) {
-                    self.illegal_instr();
-                }
                 inc_by = 2;
+                self.decode_insn(instr_lower as u32, inc_by);
             } else {
                 if max_count < 4 {
                     break;
                 }
                 let instr_high = self.read16(iaddr + 2, true, false)?; // this should be physical
                 let realinstr = ((instr_high as u32) << 16) | (instr_lower as u32);
-                self.is_compressed = false;
-                if !crate::riscv::decoder::decode(self, realinstr) {
-                    self.illegal_instr(); // this will set stop_exec = true
-                }
                 inc_by = 4;
+                self.decode_insn(realinstr, inc_by);
             }
-            self.current_block.instrs.last_mut().unwrap().inc_by = inc_by;
             iaddr += inc_by;
             max_count -= (inc_by as i64);
             if self.stop_translating {
@@ -436,13 +674,30 @@ impl RiscvInt {
 
         }
         self.current_block.end = iaddr - inc_by; // end would be the last pc the block world cover
+        self.push_block(self.current_block.clone());
+        Ok(())
+    }
+    /// Decode one instruction onto the end of current_block, `insn` being just the low 16 bits
+    /// when it's compressed (`inc_by` 2)
+    pub(crate) fn decode_insn(&mut self, insn: u32, inc_by: u64) {
+        self.is_compressed = inc_by == 2;
+        let known = if self.is_compressed {
+            crate::riscv::decoder16::decode(self, insn as u16)
+        } else {
+            self.decode_custom(insn) || crate::riscv::decoder::decode(self, insn)
+        };
+        if !known {
+            self.illegal_instr(); // this will set stop_exec = true
+        }
+        self.current_block.instrs.last_mut().unwrap().inc_by = inc_by;
+    }
+    pub(crate) fn push_block(&mut self, blk: RiscvBlock) {
         unsafe {
             let z = (self.ainstr.get());
             let newidx = ((*z).idx + 1) % (*z).ainstr.len();
-            (*z).ainstr[newidx] = self.current_block.clone(); // should be z.idx
+            (*z).ainstr[newidx] = blk; // should be z.idx
             (*z).idx = newidx;
         }
-        Ok(())
     }
     unsafe fn check_run_block(&mut self, addr: u64) -> bool {
         // block if there, None if otherwise
@@ -465,9 +720,23 @@ impl RiscvInt {
             } else {
                 false
             };
+            if self.isa_counts.is_some() {
+                // blocks don't keep the words they were decoded from
+                let lo = self.read16(self.pc, true, false).map_or(0, |v| v as u32);
+                let word = if z.inc_by == 2 {
+                    lo
+                } else {
+                    lo | self.read16(self.pc + 2, true, false).map_or(0, |v| (v as u32) << 16)
+                };
+                self.count_isa(word);
+            }
             (z.func)(self, &z.args);
             self.pc += z.inc_by;
             self.regs[0] = 0;
+            self.icount += 1;
+            if self.icount >= self.icount_limit {
+                self.stop_exec = true;
+            }
             if self.stop_exec {
                 // for usual reasons, or maybe this cache has been invalidated 10e4e
                 return;
@@ -478,12 +747,13 @@ impl RiscvInt {
     #[cfg(feature = "linux-usermode")]
     pub fn handle_syscall(&mut self) {
         let syscallnum = self.regs[17]; // a7
-        let systype = if let Some(s) = riscv_translate_syscall(syscallnum as u16) {
+        let systype = if let Some(s) = riscv_translate_syscall(self.xlen, syscallnum) {
             debug!("Going to execute syscall {:?} (number {:}, on thread id {:x})",
                 s, syscallnum, self.user_struct.tid_val);
             s
         } else {
-            debug!("Failed to execute syscall number {:}", syscallnum);
+            debug!("Failed to execute syscall number {:} ({})", syscallnum,
+                riscv_syscall_table(self.xlen).name(syscallnum).unwrap_or("no such syscall"));
             panic!();
         };
         let arg1 = self.regs[10]; // a0
@@ -496,94 +766,175 @@ impl RiscvInt {
             syscall: systype,
             args: [arg1, arg2, arg3, arg4, arg5, arg6, 0]
         };
-        let out = dispatch(self, sysin);
+        if let (Some(isa), Some(counts)) = (&self.user_struct.isa, &mut self.isa_counts) {
+            // the syscall might be the last thing this thread does
+            isa.merge(counts);
+        }
+        let mut out = dispatch(self, sysin);
+        if out.is_restart() {
+            if self.user_struct.signals.restarts_syscall() {
+                // the ecall again after the handler (or right away), a0 is still the argument
+                self.pc = self.trap_pc;
+                out.ret1 = arg1;
+            } else {
+                out = errno_out(libc::EINTR);
+            }
+        }
         self.regs[10] = out.ret1;
         if let Some(xx) = out.ret2 {
             self.regs[11] = xx;
         }
+        if self.user_struct.sched.is_some() && self.user_struct.sched_event.is_none() {
+            // every syscall is a switch point
+            self.user_struct.sched_event = Some(SchedEvent::Yield);
+        }
     }
     pub fn run(&mut self) {
+        #[cfg(feature = "linux-usermode")]
+        let _bound = self.usermode.then(|| bind_thread_signals(&self.user_struct.signals));
+        // never returns, threads leave through exit() or unwind out of exit_group()
+        self.patch_seen.gate().enter();
         loop {
-            if self.cache_enabled {
-                match self.exec_cached_int() {
-                    Ok(()) => { },
-                    Err(z) => {
-                        self.trap = Some(z);
-                    }
+            #[cfg(feature = "linux-usermode")]
+            if self.usermode && self.user_struct.group_exit.status().is_some() {
+                self.patch_seen.gate().leave();
+                group_exit_unwind();
+            }
+            self.icount_limit = self.pace();
+            self.run_once();
+        }
+    }
+    /// Tell the pacer what ran since last time (it sleeps if we're ahead of the rate limit).
+    /// Returns the icount to stop at so it gets told again
+    pub(crate) fn pace(&mut self) -> u64 {
+        match &self.pacer {
+            Some(p) => {
+                let mut p = p.lock();
+                p.account(self.icount - self.pace_mark);
+                self.pace_mark = self.icount;
+                self.icount + p.chunk()
+            }
+            None => u64::MAX,
+        }
+    }
+    // a loop went around too many times without anything changing
+    fn spinning(&mut self, hit: SpinHit) {
+        #[cfg(feature = "linux-usermode")]
+        if self.usermode {
+            if hit.report {
+                crate::warn_ratelimited!("Thread {} looks stuck in a busy loop at {:#x} ({}), {} times around with no change",
+                    self.user_struct.tid_val, hit.head, self.user_struct.describe_pc(hit.head), hit.iters);
+            }
+            if hit.sleep.is_some() && self.user_struct.sched.is_some() {
+                // the thread it waits for may be queued behind us, let it run instead of sleeping
+                if self.user_struct.sched_event.is_none() {
+                    self.user_struct.sched_event = Some(SchedEvent::Yield);
                 }
-                self.cache_enabled = true;
-            } else {
-                match self.exec_one_by_one() {
-                    Ok(()) => { },
-                    Err(z) => {
-                        self.trap = Some(z);
-                    }
+                return;
+            }
+        }
+        if let Some(d) = hit.sleep {
+            std::thread::sleep(d);
+        }
+    }
+    /// One pass of the outer loop: execute until the interpreter stops, then deal with whatever
+    /// stopped it (trap, syscall, pending signal, jump)
+    pub(crate) fn run_once(&mut self) {
+        self.patch_checkpoint();
+        if self.cache_enabled {
+            match self.exec_cached_int() {
+                Ok(()) => { },
+                Err(z) => {
+                    self.trap = Some(z);
                 }
             }
-            if self.trap.is_some() {
-                if self.usermode {
-                    #[cfg(feature = "linux-usermode")]
-                    {
-                        let trp = self.trap.unwrap();
-                        if trp.ttype == EnvironmentCallFromMMode {
-                            self.handle_syscall();
-                            self.stop_exec = false;
-                            self.trap = None;
-
-                        } else {
-                            panic!("Protection error  - Suffered RISCV trap in user mode: {:?}", self.trap.unwrap())
-                        }
-                    }
-                    #[cfg(not(feature = "linux-usermode"))]
-                    {
-                        unreachable!("usermode functionality not included but CPU has usermode variable set")
-                    }
-
-                } else {
-                    self.handle_trap(self.trap.unwrap(), self.trap_pc);
-                    self.trap_pc = 0;
-                    self.trap = None;
-                    self.want_pc = None;
-                    self.wfi = false;
-                    self.stop_exec = false;
-                    continue;
+            self.cache_enabled = true;
+        } else {
+            match self.exec_one_by_one() {
+                Ok(()) => { },
+                Err(z) => {
+                    self.trap = Some(z);
                 }
-
             }
-            #[cfg(feature = "linux-usermode")]
-            {
-                if self.usermode {
-                    SIGNAL_AVAIL.with(|z| {
-                        let mut zz = z.borrow_mut();
-                        if *zz == true {
-                            // signal
-                            SINFO.with(|a| {
-                                let mut aa = a.borrow_mut();
-                                let signum = aa.use_idx.unwrap();
-                                setup_rt_frame(self, signum as i32, &mut aa);
-                            });
-                            *zz = false; // we will unblock signals later
+        }
+        if self.trap.is_some() {
+            if self.usermode {
+                #[cfg(feature = "linux-usermode")]
+                {
+                    let trp = self.trap.unwrap();
+                    if trp.ttype == EnvironmentCallFromMMode {
+                        // a thread blocked in a syscall mustn't hold up a pause
+                        let inside = self.patch_seen.gate().leave();
+                        self.handle_syscall();
+                        if inside {
+                            self.patch_seen.gate().enter();
                         }
-                    });
+                        self.stop_exec = false;
+                        self.trap = None;
+
+                    } else if matches!(trp.ttype, Exception::LoadAccessFault | Exception::StoreAccessFault |
+                        Exception::LoadAddressMisaligned | Exception::StoreAddressMisaligned |
+                        Exception::InstructionAddressMisaligned | Exception::InstructionAccessFault |
+                        Exception::InstructionPageFault | Exception::Breakpoint) {
+                        // bad access (injected ones included), misaligned atomic, jump or ebreak:
+                        // the guest gets a signal for the instruction
+                        self.pc = self.trap_pc;
+                        self.trap = None;
+                        self.want_pc = None;
+                        self.raise_fault_signal(trp.ttype);
+                    } else {
+                        panic!("Protection error  - Suffered RISCV trap in user mode: {:?}", self.trap.unwrap())
+                    }
+                }
+                #[cfg(not(feature = "linux-usermode"))]
+                {
+                    unreachable!("usermode functionality not included but CPU has usermode variable set")
                 }
 
-            }
-            if let Some(f) = self.want_pc {
-                // todo: any checks?
-                self.pc = f;
+            } else {
+                self.handle_trap(self.trap.unwrap(), self.trap_pc);
+                self.trap_pc = 0;
+                self.trap = None;
                 self.want_pc = None;
+                self.wfi = false;
+                self.stop_exec = false;
+                return;
             }
-            if self.wfi {
-                unimplemented!();
-            }
-            self.stop_exec = false;
-        }
 
+        }
+        #[cfg(feature = "linux-usermode")]
+        if self.usermode {
+            self.deliver_signal();
+        }
+        // backward jumps are loops going around
+        let spin = match (self.want_pc, self.spin.as_mut()) {
+            (Some(t), Some(sd)) if t < self.pc => sd.backward_jump(t, state_digest(&self.regs, &self.fregs)),
+            _ => None,
+        };
+        if let Some(hit) = spin {
+            self.spinning(hit);
+        }
+        self.apply_want_pc();
+        if self.wfi {
+            unimplemented!();
+        }
+        self.stop_exec = false;
+    }
+    /// --isa-report: the instruction at pc runs once more, `word` being just the low 16 bits of a
+    /// compressed one
+    #[inline]
+    fn count_isa(&mut self, word: u32) {
+        if let Some(counts) = &mut self.isa_counts {
+            *counts.entry((self.pc, word)).or_insert(0) += 1;
+        }
     }
     // todo: replace errors in exec/step with custom error enum
     #[inline]
     pub(crate) fn step_one_instr(&mut self) {
         let instr = self.read32(self.pc, true, true).unwrap(); // todo: for now
+        if self.isa_counts.is_some() {
+            self.count_isa(if instr & 3 != 3 { instr & 0xffff } else { instr });
+        }
         if (instr & 0x3) != 0x3 {
             self.is_compressed = true;
             // compressed
@@ -594,12 +945,16 @@ impl RiscvInt {
         } else {
             self.is_compressed = false;
 
-            if !crate::riscv::decoder::decode(self, instr) {
+            if !self.decode_custom(instr) && !crate::riscv::decoder::decode(self, instr) {
                 self.illegal_instr(); // this will set stop_exec = true
             }
             self.pc += 4;
         }
         self.regs[0] = 0;
+        self.icount += 1;
+        if self.icount >= self.icount_limit {
+            self.stop_exec = true;
+        }
 
     }
     pub(crate) fn exec_one_by_one(&mut self) -> Result<(), Trap> {
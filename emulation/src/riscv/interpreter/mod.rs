@@ -5,7 +5,7 @@ pub mod arith;
 pub mod branch;
 pub mod loadstore;
 pub mod floating;
-mod atomic;
+pub(crate) mod atomic;
 pub mod crypto;
 pub mod defs;
 mod bitmanip;
@@ -14,7 +14,16 @@ pub mod consts;
 mod floating_helpers;
 #[cfg(test)]
 mod tests;
+#[cfg(all(test, feature = "linux-usermode"))]
+mod litmus;
 pub mod system;
+pub mod guest_call;
+pub mod custom;
+pub mod snapshot;
+pub mod engine;
+pub mod fuzz_machine;
+pub mod block_store;
+pub mod hotpatch;
 
 use arith::*;
 use branch::*;
@@ -0,0 +1,193 @@
+// Snapshots of cpu state plus a hash per guest memory page, and diffs between two of them.
+// Meant for debugging long runs: take one at checkpoint A and one at B (or save them from two
+// runs that should have behaved the same), then see which registers, CSRs and pages changed.
+// Page contents aren't kept, only a hash, so snapshots stay small. Which memory gets hashed is up
+// to the caller: ram_ranges() covers it all in system mode, in usermode the guest address space
+// is the emulator's own, so snapshot_ranges() picks the guest's anonymous mappings, and pages that
+// aren't mapped readable are skipped rather than read. "snapshot FILE" in the gdb monitor saves one.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::ops::Range;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use crate::riscv::interpreter::main::RiscvInt;
+
+pub const SNAPSHOT_PAGE_SIZE: u64 = 4096;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RiscvSnapshot {
+    pub pc: u64,
+    pub icount: u64,
+    pub regs: [u64; 32],
+    pub fregs: [u64; 32],
+    /// only the ones that aren't zero
+    pub csrs: BTreeMap<u16, u64>,
+    /// page address -> hash of its contents
+    pub pages: BTreeMap<u64, u64>,
+}
+// fnv-1a, so hashes are the same in every build and can be compared across runs
+fn page_hash(data: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in data {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h
+}
+impl RiscvInt {
+    /// All of guest ram, in system mode
+    pub fn ram_ranges(&self) -> Vec<Range<u64>> {
+        let mut out = Vec::new();
+        let _ = self.memsource.guest_mem.guest_mem.with_regions::<_, ()>(|_, start, size, _, _, _| {
+            out.push(start.0..start.0 + size as u64);
+            Ok(())
+        });
+        out
+    }
+    /// What a snapshot hashes by default: all of ram, or the guest's mappings in usermode
+    pub fn snapshot_ranges(&self) -> Vec<Range<u64>> {
+        #[cfg(feature = "linux-usermode")]
+        if self.usermode {
+            return self.user_struct.memusage.dumpable_ranges().into_iter().map(|(s, l)| s..s + l).collect();
+        }
+        self.ram_ranges()
+    }
+    // in usermode a guest address is a host one, reading a page that isn't mapped would crash us
+    fn page_readable(&self, _addr: u64) -> bool {
+        #[cfg(feature = "linux-usermode")]
+        if self.usermode {
+            return self.user_struct.memusage.accessible(_addr, SNAPSHOT_PAGE_SIZE, false);
+        }
+        true
+    }
+    pub fn snapshot(&mut self, ranges: &[Range<u64>]) -> RiscvSnapshot {
+        let csrs = self.csr.iter().enumerate()
+            .filter(|(_, v)| **v != 0)
+            .map(|(i, v)| (i as u16, *v))
+            .collect();
+        let mut pages = BTreeMap::new();
+        for r in ranges {
+            let mut addr = r.start & !(SNAPSHOT_PAGE_SIZE - 1);
+            while addr < r.end {
+                if !self.page_readable(addr) {
+                    addr += SNAPSHOT_PAGE_SIZE;
+                    continue;
+                }
+                if let Ok(data) = self.memsource.guest_mem.read_phys_n(addr, SNAPSHOT_PAGE_SIZE as usize) {
+                    pages.insert(addr, page_hash(&data));
+                }
+                addr += SNAPSHOT_PAGE_SIZE;
+            }
+        }
+        RiscvSnapshot {
+            pc: self.pc,
+            icount: self.icount,
+            regs: self.regs,
+            fregs: self.fregs,
+            csrs,
+            pages,
+        }
+    }
+}
+impl RiscvInt {
+    /// The "snapshot FILE" command: saves one, returns what to tell the user
+    pub fn snapshot_cmd(&mut self, cmd: &str) -> Option<String> {
+        let mut words = cmd.split_whitespace();
+        if words.next() != Some("snapshot") {
+            return None;
+        }
+        let path = match (words.next(), words.next()) {
+            (Some(p), None) => p,
+            _ => return Some("usage: snapshot FILE".to_string()),
+        };
+        let ranges = self.snapshot_ranges();
+        let snap = self.snapshot(&ranges);
+        if let Err(e) = snap.save(Path::new(path)) {
+            return Some(format!("couldn't save the snapshot to {}: {}", path, e));
+        }
+        #[cfg(feature = "linux-usermode")]
+        if let Some(rec) = &self.user_struct.summary {
+            rec.add_snapshot(path.to_string());
+        }
+        Some(format!("saved pc {:#x} and {} pages to {}", snap.pc, snap.pages.len(), path))
+    }
+}
+impl RiscvSnapshot {
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::write(path, serde_json::to_vec(self)?)?;
+        Ok(())
+    }
+    pub fn load(path: &Path) -> anyhow::Result<RiscvSnapshot> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+    /// What changed going from `self` to `later`
+    pub fn diff(&self, later: &RiscvSnapshot) -> SnapshotDiff {
+        let changed = |a: &[u64; 32], b: &[u64; 32]| -> Vec<(usize, u64, u64)> {
+            (0..32).filter(|i| a[*i] != b[*i]).map(|i| (i, a[i], b[i])).collect()
+        };
+        let csr_nums: BTreeSet<u16> = self.csrs.keys().chain(later.csrs.keys()).copied().collect();
+        let csrs = csr_nums.into_iter().filter_map(|c| {
+            let (a, b) = (self.csrs.get(&c).copied().unwrap_or(0), later.csrs.get(&c).copied().unwrap_or(0));
+            if a != b { Some((c, a, b)) } else { None }
+        }).collect();
+        let page_addrs: BTreeSet<u64> = self.pages.keys().chain(later.pages.keys()).copied().collect();
+        let pages = page_addrs.into_iter().filter_map(|p| {
+            let (a, b) = (self.pages.get(&p).copied(), later.pages.get(&p).copied());
+            if a != b { Some(PageChange { addr: p, before: a, after: b }) } else { None }
+        }).collect();
+        SnapshotDiff {
+            pc: (self.pc, later.pc),
+            insns: later.icount.wrapping_sub(self.icount),
+            regs: changed(&self.regs, &later.regs),
+            fregs: changed(&self.fregs, &later.fregs),
+            csrs,
+            pages,
+        }
+    }
+}
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct PageChange {
+    pub addr: u64,
+    /// None when the page wasn't in that snapshot
+    pub before: Option<u64>,
+    pub after: Option<u64>,
+}
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct SnapshotDiff {
+    pub pc: (u64, u64),
+    /// instructions retired in between
+    pub insns: u64,
+    /// (register, before, after)
+    pub regs: Vec<(usize, u64, u64)>,
+    pub fregs: Vec<(usize, u64, u64)>,
+    pub csrs: Vec<(u16, u64, u64)>,
+    pub pages: Vec<PageChange>,
+}
+impl SnapshotDiff {
+    pub fn is_empty(&self) -> bool {
+        self.pc.0 == self.pc.1 && self.regs.is_empty() && self.fregs.is_empty()
+            && self.csrs.is_empty() && self.pages.is_empty()
+    }
+}
+impl fmt::Display for SnapshotDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "pc: {:#x} -> {:#x} ({} instructions)", self.pc.0, self.pc.1, self.insns)?;
+        for (r, a, b) in &self.regs {
+            writeln!(f, "x{}: {:#x} -> {:#x}", r, a, b)?;
+        }
+        for (r, a, b) in &self.fregs {
+            writeln!(f, "f{}: {:#x} -> {:#x}", r, a, b)?;
+        }
+        for (c, a, b) in &self.csrs {
+            writeln!(f, "csr {:#05x}: {:#x} -> {:#x}", c, a, b)?;
+        }
+        if !self.pages.is_empty() {
+            writeln!(f, "{} pages changed:", self.pages.len())?;
+        }
+        let h = |v: Option<u64>| v.map(|x| format!("{:016x}", x)).unwrap_or_else(|| "-".repeat(16));
+        for p in &self.pages {
+            writeln!(f, "  {:#x}: {} -> {}", p.addr, h(p.before), h(p.after))?;
+        }
+        Ok(())
+    }
+}
@@ -1,5 +1,5 @@
 use crate::riscv::common::{Exception, get_privilege_encoding, get_privilege_mode, Priv, RiscvArgs, Trap};
-use crate::riscv::interpreter::main::RiscvInt;
+use crate::riscv::interpreter::main::{MISA_C, RiscvInt};
 use crate::riscv::interpreter::consts::*;
 
 fn has_csr_access_privilege(ri: &RiscvInt, address: u16) -> bool {
@@ -7,9 +7,17 @@ fn has_csr_access_privilege(ri: &RiscvInt, address: u16) -> bool {
     privilege as u8 <= get_privilege_encoding(ri.prvmode) as u8
 }
 fn read_csr(ri: &mut RiscvInt, address: u16) -> Result<u64, ()> {
-    match has_csr_access_privilege(ri, address) {
-        true => Ok(read_csr_check(ri, address as usize)),
-        false => {
+    let custom = ri.custom.as_ref().and_then(|c| c.csr_handler(address));
+    let val = match has_csr_access_privilege(ri, address) {
+        true => match custom {
+            Some(h) => h.read(ri, address),
+            None => Some(read_csr_check(ri, address as usize)),
+        },
+        false => None,
+    };
+    match val {
+        Some(v) => Ok(v),
+        None => {
             let val  = ri.get_pc_of_current_instr();
             ri.set_trap(Trap {
                 ttype: Exception::IllegalInstruction,
@@ -29,13 +37,13 @@ fn read_csr_check(ri: &mut RiscvInt, addr: usize) -> u64 {
         CSR_SSTATUS_ADDRESS => ri.csr[CSR_MSTATUS_ADDRESS as usize] & 0x80000003000de162,
         CSR_SIE_ADDRESS => ri.csr[CSR_MIE_ADDRESS as usize] & 0x222,
         CSR_SIP_ADDRESS => ri.csr[CSR_MIP_ADDRESS as usize] & 0x222,
-        CSR_MHARTID_ADDRESS => { 0 } // for now.
+        CSR_MHARTID_ADDRESS => ri.csr[addr], // set when the hart is made, read only
         CSR_MTVEC_ADDRESS | CSR_SATP_ADDRESS |
         CSR_PMPADDR0_ADDRESS | CSR_PMPCFG0_ADDRESS
         | CSR_MEDELEG_ADDRESS | CSR_MIDELEG_ADDRESS
         | CSR_MIE_ADDRESS | CSR_STVEC_ADDRESS
         | CSR_MEPC_ADDRESS | CSR_MSTATUS_ADDRESS
-        | CSR_MCAUSE_ADDRESS | CSR_SEPC_ADDRESS |
+        | CSR_MCAUSE_ADDRESS | CSR_SEPC_ADDRESS | CSR_MISA_ADDRESS |
         _CSR_MSCRATCH_ADDRESS | _CSR_SSCRATCH_ADDRESS
         | CSR_STVAL_ADDRESS | CSR_SCAUSE_ADDRESS | CSR_FCSR_ADDRESS => {
             ri.csr[addr]
@@ -44,6 +52,16 @@ fn read_csr_check(ri: &mut RiscvInt, addr: usize) -> u64 {
     }
 }
 fn write_csr_check(ri: &mut RiscvInt, addr: usize, value: u64) {
+    if let Some(h) = ri.custom.as_ref().and_then(|c| c.csr_handler(addr as u16)) {
+        if !h.write(ri, addr as u16, value) {
+            let val = ri.get_pc_of_current_instr();
+            ri.set_trap(Trap {
+                ttype: Exception::IllegalInstruction,
+                val
+            });
+        }
+        return;
+    }
     match addr {
         CSR_FFLAGS_ADDRESS => {
             ri.csr[CSR_FCSR_ADDRESS as usize] &= !0x1f;
@@ -79,6 +97,14 @@ fn write_csr_check(ri: &mut RiscvInt, addr: usize, value: u64) {
             ri.csr[addr] = value;
             ri.memsource.satp_flush(value);
         }
+        CSR_MISA_ADDRESS => {
+            // only C can be turned off and on. Not off when the next instruction isn't 4 byte
+            // aligned though, it couldn't be fetched
+            let next = ri.get_pc_of_next_instr();
+            if value & MISA_C != 0 || next & 3 == 0 {
+                ri.csr[addr] = (ri.csr[addr] & !MISA_C) | (value & MISA_C);
+            }
+        }
         CSR_MTVEC_ADDRESS | CSR_PMPADDR0_ADDRESS |
         CSR_PMPCFG0_ADDRESS | CSR_MEDELEG_ADDRESS |
          CSR_MIE_ADDRESS | CSR_FCSR_ADDRESS | CSR_SEPC_ADDRESS
@@ -141,7 +167,8 @@ pub fn csrrsi(ri: &mut RiscvInt, args: &RiscvArgs) {
 pub fn sret(ri: &mut RiscvInt, args: &RiscvArgs) {
     ri.stop_exec = true;
     ri.want_pc = match read_csr(ri, CSR_SEPC_ADDRESS as u16) {
-        Ok(z) => Some(z),
+        // without C, bit 1 reads as zero
+        Ok(z) => Some(if ri.ext_c() { z } else { z & !2 }),
         Err(_) => return // trap
     };
     let status = read_csr_check(ri, CSR_SSTATUS_ADDRESS);
@@ -190,12 +217,22 @@ pub fn ecall(ri: &mut RiscvInt, args: &RiscvArgs) {
     })
 
 }
+pub fn ebreak(ri: &mut RiscvInt, args: &RiscvArgs) {
+    let val = ri.get_pc_of_current_instr();
+    ri.set_trap(Trap {
+        ttype: Exception::Breakpoint,
+        val
+    })
+}
 pub fn fence(ri: &mut RiscvInt, args: &RiscvArgs) {
+    // other harts are host threads, so order like the host would. Stronger than most fences ask for
+    std::sync::atomic::fence(std::sync::atomic::Ordering::SeqCst);
 }
 pub fn mret(ri: &mut RiscvInt, args: &RiscvArgs) {
     ri.stop_exec = true;
     ri.want_pc = match read_csr(ri, CSR_MEPC_ADDRESS as u16) {
-        Ok(z) => Some(z),
+        // without C, bit 1 reads as zero
+        Ok(z) => Some(if ri.ext_c() { z } else { z & !2 }),
         Err(_) => return // trap
     };
     let status = read_csr_check(ri, CSR_MSTATUS_ADDRESS);
@@ -2,7 +2,7 @@ use std::fs::File;
 use std::io::Read;
 use std::path::PathBuf;
 use vm_memory::GuestAddress;
-use crate::riscv::common::{DRAM_BASE, Trap, Xlen};
+use crate::riscv::common::{DRAM_BASE, Exception, Priv, Trap, Xlen};
 use crate::riscv::interpreter::main::RiscvInt;
 use kernel_loader::*;
 use crate::common::memory::MemEndian;
@@ -37,10 +37,7 @@ impl RiscvInt {
                 }
 
             }
-            if let Some(f) = self.want_pc {
-                self.pc = f;
-                self.want_pc = None;
-            }
+            self.apply_want_pc();
             if self.wfi {
                 unimplemented!();
             }
@@ -147,6 +144,7 @@ fn init_test(fs: &'static str) -> u32 {
             icpu: rcpu,
             breakpoints: vec![],
             exec_mode: DebugExecMode::Continue,
+            watches: WatchList::default(),
         };
         debugr.run_debug();
         return 0;
@@ -156,6 +154,7 @@ fn init_test(fs: &'static str) -> u32 {
             icpu: rcpu,
             breakpoints: vec![],
             exec_mode: DebugExecMode::Continue,
+            watches: WatchList::default(),
         };
         debugr.run_debug();
         return 0;
@@ -178,6 +177,9 @@ fn init_test(fs: &'static str) -> u32 {
 }
 mod test {
     use crate::riscv::interpreter::tests::init_test;
+    use crate::riscv::interpreter::consts::*;
+    use crate::riscv::interpreter::main::MISA_C;
+    use super::*;
     // integer, 32-bit mode, physical addressing
     #[test]
     fn rv32ui_p_add() {
@@ -1210,5 +1212,2508 @@ mod test {
     fn rv64ua_v_amoswap_w() {
         assert_eq!(1, init_test("rv64ua-v-amoswap_w"));
     }
+    // jalr ra, 0(t0)
+    const JALR_RA_T0: u32 = 0x000280e7;
+    fn jalr_cpu(target: u64, ext_c: bool) -> RiscvInt {
+        let vmmem = vm_memory::GuestMemory::new(&[(GuestAddress(DRAM_BASE), 64 * 1024)]).unwrap();
+        let mut cpu = RiscvInt::init_systemmode(Xlen::X64, vmmem);
+        cpu.memsource.guest_mem.write_phys_32(DRAM_BASE, JALR_RA_T0, MemEndian::Little).unwrap();
+        cpu.pc = DRAM_BASE;
+        cpu.regs[5] = target;
+        if !ext_c {
+            cpu.csr[CSR_MISA_ADDRESS] &= !MISA_C;
+        }
+        cpu.exec_once().unwrap();
+        cpu
+    }
+    #[test]
+    fn jalr_odd_target_drops_low_bit() {
+        let cpu = jalr_cpu(DRAM_BASE + 0x101, true);
+        assert!(cpu.trap.is_none());
+        assert_eq!(cpu.want_pc, Some(DRAM_BASE + 0x100));
+        assert_eq!(cpu.regs[1], DRAM_BASE + 4);
+    }
+    #[test]
+    fn jalr_misaligned_without_c() {
+        for target in [DRAM_BASE + 0x102, DRAM_BASE + 0x103] {
+            let cpu = jalr_cpu(target, false);
+            let trp = cpu.trap.unwrap();
+            assert_eq!(trp.ttype, Exception::InstructionAddressMisaligned);
+            assert_eq!(trp.val, DRAM_BASE + 0x102);
+            assert_eq!(cpu.trap_pc, DRAM_BASE);
+            assert_eq!(cpu.want_pc, None);
+            assert_eq!(cpu.regs[1], 0); // rd isn't written when the jump traps
+        }
+    }
+    #[test]
+    fn jalr_misaligned_traps_to_handler() {
+        let mut cpu = jalr_cpu(DRAM_BASE + 0x103, false);
+        cpu.csr[CSR_MTVEC_ADDRESS] = DRAM_BASE + 0x200;
+        cpu.handle_trap(cpu.trap.unwrap(), cpu.trap_pc);
+        assert_eq!(cpu.pc, DRAM_BASE + 0x200);
+        assert_eq!(cpu.csr[CSR_MEPC_ADDRESS], DRAM_BASE);
+        assert_eq!(cpu.csr[CSR_MTVAL_ADDRESS], DRAM_BASE + 0x102);
+    }
+    // csrrci x0, misa, 4: clear C
+    const CLEAR_MISA_C: u32 = 0x30127073;
+    #[test]
+    fn misa_c_write_turns_compressed_off() {
+        for (xlen, mxl) in [(Xlen::X64, 2u64 << 62), (Xlen::X32, 1 << 30)] {
+            let vmmem = vm_memory::GuestMemory::new(&[(GuestAddress(DRAM_BASE), 64 * 1024)]).unwrap();
+            let mut cpu = RiscvInt::init_systemmode(xlen, vmmem);
+            assert_eq!(cpu.csr[CSR_MISA_ADDRESS] & !0x3ff_ffff, mxl);
+            assert!(cpu.ext_c());
+            cpu.memsource.guest_mem.write_phys_32(DRAM_BASE, CLEAR_MISA_C, MemEndian::Little).unwrap();
+            cpu.pc = DRAM_BASE;
+            let _ = cpu.exec_once();
+            assert!(!cpu.ext_c());
+            assert_eq!(cpu.check_jump_target(DRAM_BASE + 0x102), Err(Exception::InstructionAddressMisaligned));
+            // the other extensions and mxl stay
+            assert_eq!(cpu.csr[CSR_MISA_ADDRESS] & !0x3ff_ffff, mxl);
+            assert_ne!(cpu.csr[CSR_MISA_ADDRESS] & (1 << 8), 0);
+        }
+    }
+    #[test]
+    fn misa_c_stays_on_when_next_insn_misaligned() {
+        let vmmem = vm_memory::GuestMemory::new(&[(GuestAddress(DRAM_BASE), 64 * 1024)]).unwrap();
+        let mut cpu = RiscvInt::init_systemmode(Xlen::X64, vmmem);
+        cpu.memsource.guest_mem.write_phys_16(DRAM_BASE + 2, CLEAR_MISA_C as u16, MemEndian::Little).unwrap();
+        cpu.memsource.guest_mem.write_phys_16(DRAM_BASE + 4, (CLEAR_MISA_C >> 16) as u16, MemEndian::Little).unwrap();
+        cpu.pc = DRAM_BASE + 2;
+        let _ = cpu.exec_once();
+        assert!(cpu.ext_c());
+    }
+    #[test]
+    fn jump_target_canonical_sv39() {
+        let vmmem = vm_memory::GuestMemory::new(&[(GuestAddress(DRAM_BASE), 64 * 1024)]).unwrap();
+        let mut cpu = RiscvInt::init_systemmode(Xlen::X64, vmmem);
+        cpu.memsource.satp_flush(8 << 60);
+        cpu.prvmode = Priv::Supervisor;
+        assert_eq!(cpu.check_jump_target(0x40_0000_0000), Err(Exception::InstructionPageFault));
+        assert_eq!(cpu.check_jump_target(0xffff_ffc0_0000_0000), Ok(0xffff_ffc0_0000_0000));
+        assert_eq!(cpu.check_jump_target(0x3f_ffff_f000), Ok(0x3f_ffff_f000));
+        // no translation in machine mode, anything goes
+        cpu.prvmode = Priv::Machine;
+        assert_eq!(cpu.check_jump_target(0x40_0000_0000), Ok(0x40_0000_0000));
+    }
+    fn guest_call_cpu(xlen: Xlen, code: &[u32]) -> RiscvInt {
+        let vmmem = vm_memory::GuestMemory::new(&[(GuestAddress(DRAM_BASE), 64 * 1024)]).unwrap();
+        let mut cpu = RiscvInt::init_systemmode(xlen, vmmem);
+        for (i, w) in code.iter().enumerate() {
+            cpu.memsource.guest_mem.write_phys_32(DRAM_BASE + i as u64 * 4, *w, MemEndian::Little).unwrap();
+        }
+        cpu.regs[2] = DRAM_BASE + 0x8000; // sp
+        cpu.pc = DRAM_BASE + 0x1000;
+        cpu
+    }
+    #[test]
+    fn guest_call_args_and_restore() {
+        use crate::riscv::interpreter::guest_call::GuestCallArg::*;
+        // add a0, a0, a1; ret
+        const ADD_RET: [u32; 2] = [0x00b50533, 0x00008067];
+        for xlen in [Xlen::X64, Xlen::X32] {
+            let mut cpu = guest_call_cpu(xlen, &ADD_RET);
+            cpu.regs[10] = 7;
+            let res = cpu.call_guest_function(DRAM_BASE, &[Int(40), Int(2)]).unwrap();
+            assert_eq!(res.a0, 42);
+            assert_eq!(res.icount, 2);
+            assert_eq!(cpu.pc, DRAM_BASE + 0x1000);
+            assert_eq!(cpu.regs[10], 7);
+            assert_eq!(cpu.regs[2], DRAM_BASE + 0x8000);
+        }
+        // on rv32 the result is sign extended like any other register
+        let mut cpu = guest_call_cpu(Xlen::X32, &ADD_RET);
+        let res = cpu.call_guest_function(DRAM_BASE, &[Int(0xffff_fff0), Int(1)]).unwrap();
+        assert_eq!(res.a0, 0xffff_ffff_ffff_fff1);
+    }
+    #[test]
+    fn guest_call_stack_args() {
+        use crate::riscv::interpreter::guest_call::GuestCallArg::*;
+        // ld a0, 8(sp); ret
+        let mut cpu = guest_call_cpu(Xlen::X64, &[0x00813503, 0x00008067]);
+        let args: Vec<_> = (0..10).map(Int).collect();
+        let res = cpu.call_guest_function(DRAM_BASE, &args).unwrap();
+        assert_eq!(res.a0, 9);
+        // lw a0, 4(sp); ret
+        let mut cpu = guest_call_cpu(Xlen::X32, &[0x00412503, 0x00008067]);
+        let res = cpu.call_guest_function(DRAM_BASE, &args).unwrap();
+        assert_eq!(res.a0, 9);
+        assert_eq!(cpu.regs[2], DRAM_BASE + 0x8000);
+    }
+    #[test]
+    fn guest_call_timeout() {
+        use crate::riscv::interpreter::guest_call::GuestCallError;
+        // j .
+        let mut cpu = guest_call_cpu(Xlen::X64, &[0x0000006f]);
+        let res = cpu.call_guest_function_budget(DRAM_BASE, &[], 100);
+        assert_eq!(res, Err(GuestCallError::Timeout));
+        assert_eq!(cpu.pc, DRAM_BASE + 0x1000);
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn det_futex_timeout_with_busy_sibling() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::time::Duration;
+        use sync::Mutex;
+        use crate::linux_usermode::sched::*;
+        // the first thread starts one that waits 1ms (1M instructions at 1000MHz), and never blocks
+        struct T {
+            tid: u64,
+            icount: u64,
+            slices: u64,
+            child: Option<Box<T>>,
+            timed_out: Arc<AtomicBool>,
+            st: Arc<Mutex<SchedState>>,
+        }
+        impl DetThread for T {
+            fn run_slice(&mut self, spawned: &mut Vec<Box<T>>) -> SchedEvent {
+                self.slices += 1;
+                spawned.extend(self.child.take());
+                if self.tid >= DET_TID_BASE {
+                    if self.timed_out.load(Ordering::SeqCst) {
+                        return SchedEvent::Exit;
+                    }
+                    self.st.lock().futex_wait(self.tid, 0x1000, u32::MAX, Some(Duration::from_millis(1)));
+                    return SchedEvent::FutexWait;
+                }
+                self.icount += 100_000;
+                assert!(self.slices < 1000, "the wait didn't time out while its sibling was busy");
+                if self.timed_out.load(Ordering::SeqCst) { SchedEvent::Exit } else { SchedEvent::Yield }
+            }
+            fn sched_tid(&self) -> u64 {
+                self.tid
+            }
+            fn sched_icount(&self) -> u64 {
+                self.icount
+            }
+            fn futex_timed_out(&mut self) {
+                self.timed_out.store(true, Ordering::SeqCst);
+            }
+            fn futex_interrupted(&mut self) {}
+            fn sched_state(&self) -> Arc<Mutex<SchedState>> {
+                self.st.clone()
+            }
+        }
+        let st = Arc::new(Mutex::new(SchedState::new(100_000, 1000)));
+        let flag = Arc::new(AtomicBool::new(false));
+        let t = |tid, child| Box::new(T { tid, icount: 0, slices: 0, child, timed_out: flag.clone(), st: st.clone() });
+        let tid = st.lock().alloc_tid();
+        assert!(tid >= DET_TID_BASE);
+        run_deterministic(t(1, Some(t(tid, None))), st.clone());
+        assert!(flag.load(Ordering::SeqCst));
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn vfs_relative_paths() {
+        use std::ffi::CString;
+        use std::os::unix::io::AsRawFd;
+        use crate::linux_usermode::vfs::{normalize_guest, resolve_guest_path, FsMode, PathIntent};
+        assert_eq!(normalize_guest("/usr//lib/./x/../libc.so"), "/usr/lib/libc.so");
+        assert_eq!(normalize_guest("/../etc/"), "/etc/");
+        let dir = std::env::temp_dir().join(format!("vfs-rel-{}", std::process::id()));
+        let sysroot = dir.join("root");
+        let upper = dir.join("upper");
+        std::fs::create_dir_all(sysroot.join("usr")).unwrap();
+        std::fs::create_dir_all(&upper).unwrap();
+        std::fs::write(sysroot.join("usr/f"), "lower").unwrap();
+        let sr = sysroot.to_str().unwrap();
+        // the guest opened /usr, which is the lower layer on the host
+        let usr = std::fs::File::open(sysroot.join("usr")).unwrap();
+        let rel = CString::new("./f").unwrap();
+        let res = resolve_guest_path(&FsMode::ReadOnly, sr, false, None, usr.as_raw_fd(), rel.as_ptr(), PathIntent::Modify);
+        assert_eq!(res, Err(libc::EROFS));
+        let overlay = FsMode::Overlay(upper.clone());
+        let host = resolve_guest_path(&overlay, sr, false, None, usr.as_raw_fd(), rel.as_ptr(), PathIntent::Modify).unwrap();
+        assert_eq!(host.to_str().unwrap(), upper.join("usr/f").to_str().unwrap());
+        assert_eq!(std::fs::read_to_string(upper.join("usr/f")).unwrap(), "lower");
+        // once copied up, a dirfd in the upper layer maps back to the same guest directory
+        let up = std::fs::File::open(upper.join("usr")).unwrap();
+        let dotdot = CString::new("../usr/f").unwrap();
+        let host = resolve_guest_path(&overlay, sr, false, None, up.as_raw_fd(), dotdot.as_ptr(), PathIntent::Read).unwrap();
+        assert_eq!(host.to_str().unwrap(), upper.join("usr/f").to_str().unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn close_drops_record_locks() {
+        use std::os::unix::io::AsRawFd;
+        use crate::linux_usermode::locks::{GuestFlock, LocalLocks};
+        use crate::linux_usermode::signals::GuestSignals;
+        let path = std::env::temp_dir().join(format!("locks-close-{}", std::process::id()));
+        let a = std::fs::File::create(&path).unwrap();
+        let b = std::fs::File::open(&path).unwrap();
+        let locks = LocalLocks::default();
+        let sig = GuestSignals::new();
+        let fl = GuestFlock { l_type: libc::F_WRLCK as i16, l_whence: 0, l_start: 0, l_len: 10, l_pid: 0 };
+        locks.setlk(a.as_raw_fd(), 1, 1, &fl, false, &sig).unwrap();
+        let mut probe = GuestFlock { l_type: libc::F_WRLCK as i16, ..fl };
+        locks.getlk(a.as_raw_fd(), 2, &mut probe).unwrap();
+        assert_eq!(probe.l_pid, 1);
+        // taken through a, gone when the process closes b
+        locks.release_file(b.as_raw_fd(), 1);
+        let mut probe = GuestFlock { l_type: libc::F_WRLCK as i16, ..fl };
+        locks.getlk(a.as_raw_fd(), 2, &mut probe).unwrap();
+        assert_eq!(probe.l_type, libc::F_UNLCK as i16);
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn clone_settid_uses_virtual_tids() {
+        use std::sync::Arc;
+        use crate::elf::UserModeRuntime;
+        use crate::linux_usermode::ids::IdTable;
+        use crate::linux_usermode::main::{SyscallIn, SyscallType, UsermodeCpu};
+        // li a7, 93; ecall (exit)
+        // leaked, the child thread may still be on its way out when the test is done
+        let code: &'static [u32; 2] = Box::leak(Box::new([0x05d00893, 0x00000073]));
+        let tids: &'static [u32; 2] = Box::leak(Box::new([0, 0]));
+        let mut ume = UserModeRuntime::default();
+        ume.ids = Some(Arc::new(IdTable::create().unwrap()));
+        let mut cpu = RiscvInt::init_usermode(Xlen::X64, ume);
+        cpu.pc = code.as_ptr() as u64;
+        let flags = libc::CLONE_VM | libc::CLONE_FS | libc::CLONE_FILES | libc::CLONE_SIGHAND | libc::CLONE_THREAD
+            | libc::CLONE_SYSVSEM | libc::CLONE_PARENT_SETTID | libc::CLONE_CHILD_SETTID;
+        let parent_tid = &tids[0] as *const u32 as u64;
+        let child_tid = &tids[1] as *const u32 as u64;
+        let sysin = SyscallIn { syscall: SyscallType::Clone, args: [flags as u64, 0, parent_tid, 0, child_tid, 0, 0] };
+        let sout = cpu.clone_thread(sysin);
+        assert!(!sout.is_error);
+        // the first guest id handed out, not the host tid
+        assert!(sout.ret1 < 16);
+        let read = |i: usize| unsafe { std::ptr::read_volatile(&tids[i]) };
+        assert_eq!(read(0) as u64, sout.ret1);
+        let start = std::time::Instant::now();
+        while read(1) == 0 && start.elapsed() < std::time::Duration::from_secs(5) {
+            std::thread::yield_now();
+        }
+        assert_eq!(read(1) as u64, sout.ret1);
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn fd_grant_copies_keep_rights() {
+        use std::ffi::CString;
+        use crate::linux_usermode::fdpass::{fd_path_number, FdCaps, FdGrant, FdPolicy, FdVerdict};
+        use crate::linux_usermode::main::{SyscallIn, SyscallOut, SyscallType};
+        assert_eq!(fd_path_number("/proc/self/fd/7"), Some(7));
+        assert_eq!(fd_path_number("/dev//fd/./3"), Some(3));
+        assert_eq!(fd_path_number("/dev/stderr"), Some(2));
+        assert_eq!(fd_path_number("/proc/self/fdinfo/3"), None);
+        let mut p = [0; 2];
+        assert_eq!(unsafe { libc::pipe(p.as_mut_ptr()) }, 0);
+        let caps = FdCaps { read: true, write: false, keep: true };
+        let fp = FdPolicy::new(vec![FdGrant { host_fd: p[0], guest_fd: p[0], caps }]);
+        let call = |syscall, args: &[u64]| {
+            let mut a = [0; 7];
+            a[..args.len()].copy_from_slice(args);
+            SyscallIn { syscall, args: a }
+        };
+        let ret = |fd: i32| SyscallOut { ret1: fd as u64, ..Default::default() };
+        let denied = |sysin: SyscallIn| matches!(fp.check(&sysin), FdVerdict::Deny(libc::EBADF));
+        assert!(denied(call(SyscallType::Write, &[p[0] as u64])));
+        // every way of copying it carries the grant, but the copy can be closed
+        let d = unsafe { libc::dup(p[0]) };
+        fp.after(&call(SyscallType::Dup, &[p[0] as u64]), &ret(d));
+        assert!(denied(call(SyscallType::Write, &[d as u64])));
+        assert!(matches!(fp.check(&call(SyscallType::Close, &[d as u64])), FdVerdict::Allow));
+        let f = unsafe { libc::fcntl(p[0], libc::F_DUPFD_CLOEXEC, 10) };
+        fp.after(&call(SyscallType::Fcntl, &[p[0] as u64, libc::F_DUPFD_CLOEXEC as u64, 10]), &ret(f));
+        assert!(denied(call(SyscallType::Writev, &[f as u64])));
+        let path = CString::new(format!("/proc/self/fd/{}", p[0])).unwrap();
+        let open = |flags: i32| call(SyscallType::Openat, &[libc::AT_FDCWD as u64, path.as_ptr() as u64, flags as u64]);
+        assert!(denied(open(libc::O_WRONLY)));
+        assert!(denied(open(libc::O_RDONLY | libc::O_TRUNC)));
+        assert!(matches!(fp.check(&open(libc::O_RDONLY)), FdVerdict::Allow));
+        // coming back through a socket, and a number reused for something else
+        let r = unsafe { libc::dup(p[0]) };
+        let null = std::fs::File::open("/dev/null").unwrap();
+        let n = std::os::unix::io::AsRawFd::as_raw_fd(&null);
+        fp.received(&[r, n]);
+        assert!(denied(call(SyscallType::Write, &[r as u64])));
+        assert!(!denied(call(SyscallType::Write, &[n as u64])));
+        unsafe { libc::close(d) };
+        fp.after(&call(SyscallType::Close, &[d as u64]), &ret(0));
+        assert!(!denied(call(SyscallType::Write, &[d as u64])));
+        for fd in [p[0], p[1], f, r] {
+            unsafe { libc::close(fd) };
+        }
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn throttle_config_parse() {
+        use std::time::Duration;
+        use crate::linux_usermode::throttle::{monitor_cmd, IoClass, IoThrottle, ThrottleConfig};
+        let cfg = ThrottleConfig::parse("iops=100,bw=10M,lat=2ms,jitter=500us,spike=1s,spike_pct=3").unwrap();
+        assert_eq!(cfg.iops, Some(100));
+        assert_eq!(cfg.bytes_per_sec, Some(10 << 20));
+        assert_eq!(cfg.latency, Duration::from_millis(2));
+        assert_eq!(cfg.jitter, Duration::from_micros(500));
+        assert_eq!(cfg.spike, Duration::from_secs(1));
+        assert_eq!(cfg.spike_pct, 3);
+        assert_eq!(ThrottleConfig::parse(&cfg.spec()), Ok(cfg));
+        // plain numbers are ms
+        assert_eq!(ThrottleConfig::parse("lat=7").unwrap().latency, Duration::from_millis(7));
+        assert_eq!(ThrottleConfig::parse("").unwrap().spec(), "off");
+        assert!(ThrottleConfig::parse("iops").is_err());
+        assert!(ThrottleConfig::parse("bw=lots").is_err());
+        assert!(ThrottleConfig::parse("speed=1").is_err());
+        // too big for a u64 once the suffix is applied
+        assert!(ThrottleConfig::parse("bw=17179869184G").is_err());
+        assert!(ThrottleConfig::parse("lat=18446744073709552s").is_err());
+        // --mem-limit sizes go through the same parser
+        use crate::linux_usermode::memusage::{MemLimit, OomPolicy};
+        assert!(MemLimit::parse("17179869184G", OomPolicy::Fail).is_err());
+        assert_eq!(MemLimit::parse("16G", OomPolicy::Fail).unwrap().bytes, 16 << 30);
+        let thr = IoThrottle::new(1);
+        assert_eq!(monitor_cmd("throttle net iops=5", Some(&thr)).unwrap(), "iops=5");
+        assert_eq!(thr.get(IoClass::Network).iops, Some(5));
+        assert_eq!(monitor_cmd("throttle", Some(&thr)).unwrap(), "storage: off\nnet: iops=5");
+        monitor_cmd("throttle net off", Some(&thr)).unwrap();
+        assert_eq!(thr.get(IoClass::Network), ThrottleConfig::default());
+        assert!(monitor_cmd("throttle", None).unwrap().contains("off"));
+        assert_eq!(monitor_cmd("stats", Some(&thr)), None);
+    }
+    #[test]
+    fn log_filter_expands_subsystems() {
+        use crate::common::logctl::expand_filter;
+        assert_eq!(expand_filter("info, interp=debug"),
+                   "info,emulation::riscv::interpreter=debug,emulation::armv8::interpreter=debug");
+        // module paths and subsystems without a level are left for the logger to make sense of
+        assert_eq!(expand_filter("emulation::debug=trace,mmu,,warn"), "emulation::debug=trace,mmu,warn");
+        assert_eq!(expand_filter(""), "");
+    }
+    #[test]
+    fn rate_limit_counts_dropped() {
+        use crate::common::logctl::RateLimit;
+        let limit = RateLimit::new();
+        for _ in 0..10 {
+            assert_eq!(limit.allow(), Some(0));
+        }
+        // the first one past the burst starts an interval, the rest of it is dropped
+        assert_eq!(limit.allow(), Some(0));
+        for _ in 0..5 {
+            assert_eq!(limit.allow(), None);
+        }
+    }
+    #[test]
+    fn fault_ranges_overlap() {
+        use std::sync::Arc;
+        use crate::common::fault_inject::FaultInjector;
+        let fi = Arc::new(FaultInjector::new(1));
+        fi.add_range(DRAM_BASE, 0x2000, true, false);
+        fi.add_range(DRAM_BASE + 0x800, 0x100, false, true);
+        // the small range starts closer, the big one still covers it
+        assert!(fi.mem_fault(DRAM_BASE + 0x1000, 4, false));
+        assert!(fi.mem_fault(DRAM_BASE + 0x7fe, 4, true));
+        assert!(!fi.mem_fault(DRAM_BASE + 0x1000, 4, true));
+        assert!(!fi.mem_fault(DRAM_BASE + 0x2000, 4, false));
+        let vmmem = vm_memory::GuestMemory::new(&[(GuestAddress(DRAM_BASE), 64 * 1024)]).unwrap();
+        let mut cpu = RiscvInt::init_systemmode(Xlen::X64, vmmem);
+        cpu.set_fault_injector(Some(fi));
+        assert!(cpu.read32(DRAM_BASE + 0x1000, false, false).is_err());
+        // the debugger reading it isn't the guest
+        assert!(cpu.host_side(|c| c.read32(DRAM_BASE + 0x1000, false, false)).is_ok());
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn summary_written_once() {
+        use crate::elf::UserModeRuntime;
+        use crate::linux_usermode::summary::{RunExit, SummaryRecorder};
+        let path = std::env::temp_dir().join(format!("turbo-summary-{}.json", std::process::id()));
+        let rec = SummaryRecorder::new(path.to_str().unwrap().to_string());
+        rec.add_snapshot("/tmp/dump.bin".to_string());
+        let ume = UserModeRuntime::default();
+        rec.finish(&ume, RunExit::Signal(libc::SIGSEGV));
+        let first = std::fs::read_to_string(&path).unwrap();
+        assert!(first.contains("\"signal\": 11"));
+        assert!(first.contains("/tmp/dump.bin"));
+        // a later exit path doesn't overwrite it
+        rec.finish(&ume, RunExit::Exit(0));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), first);
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn snapshot_diff() {
+        use crate::riscv::interpreter::snapshot::{RiscvSnapshot, SNAPSHOT_PAGE_SIZE};
+        let vmmem = vm_memory::GuestMemory::new(&[(GuestAddress(DRAM_BASE), 16 * 1024)]).unwrap();
+        let mut cpu = RiscvInt::init_systemmode(Xlen::X64, vmmem);
+        cpu.pc = DRAM_BASE;
+        let ranges = cpu.ram_ranges();
+        let before = cpu.snapshot(&ranges);
+        assert_eq!(before.pages.len(), 4);
+        assert!(before.diff(&cpu.snapshot(&ranges)).is_empty());
+        cpu.pc = DRAM_BASE + 8;
+        cpu.icount += 2;
+        cpu.regs[5] = 7;
+        cpu.memsource.guest_mem.write_phys_32(DRAM_BASE + SNAPSHOT_PAGE_SIZE + 4, 1, MemEndian::Little).unwrap();
+        let after = cpu.snapshot(&ranges);
+        let diff = before.diff(&after);
+        assert_eq!(diff.pc, (DRAM_BASE, DRAM_BASE + 8));
+        assert_eq!(diff.insns, 2);
+        assert_eq!(diff.regs, vec![(5, 0, 7)]);
+        assert_eq!(diff.pages.len(), 1);
+        assert_eq!(diff.pages[0].addr, DRAM_BASE + SNAPSHOT_PAGE_SIZE);
+        // a page only one side has
+        let mut fewer = after.clone();
+        fewer.pages.remove(&DRAM_BASE);
+        let d = after.diff(&fewer);
+        assert_eq!((d.pages[0].addr, d.pages[0].after), (DRAM_BASE, None));
+        let path = std::env::temp_dir().join(format!("turbo-snap-{}.json", std::process::id()));
+        after.save(&path).unwrap();
+        assert_eq!(RiscvSnapshot::load(&path).unwrap(), after);
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn snapshot_skips_unmapped_pages() {
+        use crate::elf::UserModeRuntime;
+        let mut cpu = RiscvInt::init_usermode(Xlen::X64, UserModeRuntime::default());
+        // nothing's mapped at the bottom of the address space, reading it would crash the test
+        let snap = cpu.snapshot(&[0x1000..0x3000]);
+        assert!(snap.pages.is_empty());
+        assert!(cpu.snapshot_cmd("snapshot").unwrap().starts_with("usage"));
+        assert!(cpu.snapshot_cmd("savemem raw x").is_none());
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn monitor_cmd_dispatch() {
+        use crate::debug::WatchList;
+        use crate::elf::UserModeRuntime;
+        let mut cpu = RiscvInt::init_usermode(Xlen::X64, UserModeRuntime::default());
+        let mut watches = WatchList::default();
+        assert_eq!(cpu.monitor_cmd("watch a0 == 1", &mut watches), "watch 1: a0 == 1");
+        assert!(!watches.is_empty());
+        assert_eq!(cpu.monitor_cmd("stats", &mut watches), "stats are off, run with --stats");
+        assert!(cpu.monitor_cmd("throttle", &mut watches).starts_with("throttling is off"));
+        assert_eq!(cpu.monitor_cmd("snapshot", &mut watches), "usage: snapshot FILE");
+        assert_eq!(cpu.monitor_cmd("setreg a0 5", &mut watches), "a0 = 0x5");
+        assert_eq!(cpu.regs[10], 5);
+        assert!(cpu.monitor_cmd("frobnicate", &mut watches).starts_with("unknown command"));
+    }
+    #[test]
+    fn savemem_core_parses_back() {
+        use goblin::elf::Elf;
+        use goblin::elf::program_header::PT_LOAD;
+        use crate::common::memdump::{write_core, CoreTarget};
+        let vmmem = vm_memory::GuestMemory::new(&[(GuestAddress(DRAM_BASE), 64 * 1024)]).unwrap();
+        let mut cpu = RiscvInt::init_systemmode(Xlen::X64, vmmem);
+        cpu.write32(DRAM_BASE + 0x100, 0xdeadbeef, false).unwrap();
+        let path = std::env::temp_dir().join(format!("turbo-core-{}", std::process::id()));
+        let p = path.to_str().unwrap();
+        let reply = cpu.memdump_cmd(&format!("savemem core {}", p)).unwrap();
+        assert_eq!(reply, format!("wrote 65536 bytes of guest memory to {}", p));
+        let data = std::fs::read(&path).unwrap();
+        let elf = Elf::parse(&data).unwrap();
+        assert_eq!(elf.header.e_type, goblin::elf::header::ET_CORE);
+        assert_eq!(elf.header.e_machine, goblin::elf::header::EM_RISCV);
+        assert_eq!(elf.program_headers.len(), 1);
+        let ph = &elf.program_headers[0];
+        assert_eq!((ph.p_type, ph.p_vaddr, ph.p_filesz), (PT_LOAD, DRAM_BASE, 64 * 1024));
+        let at = ph.p_offset as usize + 0x100;
+        assert_eq!(&data[at..at + 4], &0xdeadbeefu32.to_le_bytes());
+        // more ranges than e_phnum holds: the count moves to the first section header
+        let ranges: Vec<(u64, u64)> = (0..70000).map(|i| (DRAM_BASE + (i % 0x1000), 1)).collect();
+        let target = CoreTarget { machine: 243, is_64: false, little_endian: true };
+        assert_eq!(write_core(&mut cpu.memsource.guest_mem, &ranges, target, p), Ok(70000));
+        let data = std::fs::read(&path).unwrap();
+        let hdr = Elf::parse_header(&data).unwrap();
+        assert_eq!(hdr.e_phnum, 0xffff);
+        assert_eq!(hdr.e_shnum, 1);
+        let ctx = goblin::container::Ctx::new(goblin::container::Container::Little, goblin::container::Endian::Little);
+        let sh = goblin::elf::SectionHeader::parse(&data, hdr.e_shoff as usize, 1, ctx).unwrap();
+        assert_eq!(sh[0].sh_info, 70000);
+        let last = goblin::elf::ProgramHeader::parse(&data, hdr.e_phoff as usize + 69999 * 32, 1, ctx).unwrap();
+        assert_eq!(last[0].p_vaddr, DRAM_BASE + 69999 % 0x1000);
+        std::fs::remove_file(&path).unwrap();
+    }
+    struct FakeWatch {
+        regs: std::collections::HashMap<&'static str, u64>,
+        reads: Vec<u64>,
+    }
+    impl crate::debug::WatchCtx for FakeWatch {
+        fn watch_reg(&mut self, name: &str) -> Option<u64> {
+            self.regs.get(name).copied()
+        }
+        fn watch_mem(&mut self, addr: u64, size: u64) -> Option<u64> {
+            self.reads.push(addr);
+            // only one readable word, at 0x1000
+            match addr {
+                0x1000 => Some(0x1122334455667788 & (u64::MAX >> (64 - size * 8))),
+                _ => None,
+            }
+        }
+    }
+    #[test]
+    fn watch_expr_parse_eval() {
+        use crate::debug::WatchExpr;
+        let mut ctx = FakeWatch { regs: [("a0", 3), ("sp", 0xff8)].into_iter().collect(), reads: vec![] };
+        let mut ev = |s: &str| WatchExpr::parse(s).unwrap().eval(&mut ctx);
+        // * before +, + before ==, == before &&
+        assert_eq!(ev("1 + 2 * 3"), Some(7));
+        assert_eq!(ev("(1 + 2) * 3"), Some(9));
+        assert_eq!(ev("a0 == 3 && 1 | 2 == 3"), Some(1));
+        assert_eq!(ev("!a0"), Some(0));
+        assert_eq!(ev("-1"), Some(u64::MAX));
+        assert_eq!(ev("0x10 - 1"), Some(15));
+        assert_eq!(ev("[sp + 8]"), Some(0x1122334455667788));
+        assert_eq!(ev("u8[sp+8]"), Some(0x88));
+        assert_eq!(ev("u32[0x1000] == 0x55667788"), Some(1));
+        // missing register or unreadable memory
+        assert_eq!(ev("t0"), None);
+        assert_eq!(ev("[0]"), None);
+        for bad in ["", "1 +", "(1", "[sp", "1 2", "a0 @ 1", "0xzz"] {
+            assert!(WatchExpr::parse(bad).is_err(), "{}", bad);
+        }
+        // the right side isn't evaluated once the left decides
+        ctx.reads.clear();
+        let e = WatchExpr::parse("a0 == 0 && [0] == 1").unwrap();
+        assert_eq!(e.eval(&mut ctx), Some(0));
+        assert_eq!(WatchExpr::parse("a0 || [0]").unwrap().eval(&mut ctx), Some(1));
+        assert!(ctx.reads.is_empty());
+    }
+    #[test]
+    fn watch_list_fires_on_rising_edge() {
+        use crate::debug::{WatchExpr, WatchList, WatchMode};
+        let mut ctx = FakeWatch { regs: [("a0", 1)].into_iter().collect(), reads: vec![] };
+        let mut wl = WatchList::default();
+        // already true when added
+        let id = wl.add(WatchExpr::parse("a0 == 1").unwrap(), &mut ctx);
+        assert_eq!(wl.check(&mut ctx), None);
+        ctx.regs.insert("a0", 0);
+        assert_eq!(wl.check(&mut ctx), None);
+        ctx.regs.insert("a0", 1);
+        assert_eq!(wl.check(&mut ctx), Some((id, "a0 == 1".to_string())));
+        assert_eq!(wl.check(&mut ctx), None);
+        assert!(wl.monitor_cmd("watch a0 ==", &mut ctx).unwrap().starts_with("bad expression"));
+        assert_eq!(wl.monitor_cmd("watchmode block", &mut ctx).unwrap(), "watches checked on jumps");
+        assert_eq!(wl.mode, WatchMode::Block);
+        assert_eq!(wl.monitor_cmd(&format!("unwatch {}", id), &mut ctx).unwrap(), format!("removed watch {}", id));
+        assert!(wl.is_empty());
+        assert!(wl.monitor_cmd("meminfo", &mut ctx).is_none());
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn watch_mem_unmapped() {
+        use crate::debug::WatchCtx;
+        use crate::elf::UserModeRuntime;
+        let mut cpu = RiscvInt::init_usermode(Xlen::X64, UserModeRuntime::default());
+        assert_eq!(cpu.watch_mem(0, 8), None);
+        assert_eq!(cpu.watch_mem(0x1000, 4), None);
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn clone_shares_sigactions_with_sighand() {
+        use crate::linux_usermode::signals::{GuestSignals, SigEntry};
+        let parent = GuestSignals::new();
+        parent.info.lock().unwrap().ss_size = 0x1000;
+        let thread = parent.for_thread(libc::CLONE_VM | libc::CLONE_SIGHAND | libc::CLONE_THREAD);
+        let vm_only = parent.for_thread(libc::CLONE_VM);
+        let handler = SigEntry { handler_func: 0x1234, is_valid: true, ..Default::default() };
+        thread.info.lock().unwrap().actions.lock().unwrap()[10] = handler;
+        assert_eq!(parent.info.lock().unwrap().action(10).handler_func, 0x1234);
+        // a copy from before, and the new thread has no alternate stack
+        assert_eq!(vm_only.info.lock().unwrap().action(10).handler_func, 0);
+        assert_eq!(thread.info.lock().unwrap().ss_size, 0);
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn exit_group_unwinds_with_status() {
+        use crate::elf::UserModeRuntime;
+        use crate::linux_usermode::main::{catch_group_exit, u_exit_group, SyscallIn, SyscallType};
+        let mut ume = UserModeRuntime::default();
+        let ge = ume.group_exit.clone();
+        assert_eq!(catch_group_exit(&ge, || {}), None);
+        let exit = |ume: &mut UserModeRuntime, status: u64| {
+            u_exit_group(SyscallIn { syscall: SyscallType::ExitGroup, args: [status, 0, 0, 0, 0, 0, 0] }, ume)
+        };
+        assert_eq!(catch_group_exit(&ge, || exit(&mut ume, 0x103)), Some(3));
+        // the first one decides
+        assert_eq!(catch_group_exit(&ge, || exit(&mut ume, 5)), Some(3));
+        // anything else keeps unwinding
+        let other = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            catch_group_exit(&ge, || std::panic::resume_unwind(Box::new(7u32)))
+        }));
+        assert_eq!(other.unwrap_err().downcast_ref::<u32>(), Some(&7));
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn guest_handler_runs_and_returns() {
+        use std::sync::Arc;
+        use sync::Mutex;
+        use crate::elf::UserModeRuntime;
+        use crate::linux_usermode::main::catch_group_exit;
+        use crate::riscv::ume::signals::riscv64_init_sigconstant;
+        let addi = |rd: u32, rs1: u32, imm: u32| imm << 20 | rs1 << 15 | rd << 7 | 0x13;
+        let ecall = 0x73;
+        // s0 points at the sigaction, s1 at the word the handler sets
+        let code: &'static mut [u32; 17] = Box::leak(Box::new([
+            addi(10, 0, 10), addi(11, 8, 0), addi(12, 0, 0), addi(13, 0, 8),
+            addi(17, 0, 134), ecall, // rt_sigaction(SIGUSR1, s0, NULL, 8)
+            addi(17, 0, 178), ecall, // gettid()
+            addi(11, 0, 10), addi(17, 0, 130), ecall, // tkill(tid, SIGUSR1)
+            3 << 12 | 9 << 15 | 10 << 7 | 0x03, // ld a0, 0(s1)
+            addi(17, 0, 94), ecall, // exit_group(a0)
+            // the handler: sd t0 = 42, 0(s1); ret
+            addi(5, 0, 42), 5 << 20 | 9 << 15 | 3 << 12 | 0x23, 0x00008067,
+        ]));
+        let tramp: &'static [u32; 2] = Box::leak(Box::new([addi(17, 0, 139), ecall]));
+        let handler = &code[14] as *const u32 as u64;
+        let act: &'static [u64; 3] = Box::leak(Box::new([handler, 0, 0]));
+        let flag: &'static mut u64 = Box::leak(Box::new(0));
+        let stack: &'static mut [u64; 8192] = Box::leak(Box::new([0; 8192]));
+        let mut ume = UserModeRuntime::default();
+        ume.sigcnst = Arc::new(Mutex::new(riscv64_init_sigconstant()));
+        ume.sig_tramp = tramp.as_ptr() as u64;
+        let mut cpu = RiscvInt::init_usermode(Xlen::X64, ume);
+        cpu.pc = code.as_ptr() as u64;
+        cpu.regs[8] = act.as_ptr() as u64;
+        cpu.regs[9] = flag as *mut u64 as u64;
+        let sp = (stack.as_ptr() as u64 + 8 * 8192) & !0xf;
+        cpu.regs[2] = sp;
+        let ge = cpu.user_struct.group_exit.clone();
+        // exit_group() got what the handler stored, after rt_sigreturn() put sp back
+        assert_eq!(catch_group_exit(&ge, || cpu.run()), Some(42));
+        assert_eq!(cpu.regs[2], sp);
+        let handler_now = cpu.user_struct.signals.info.lock().unwrap().action(10).handler_func;
+        assert_eq!(handler_now, handler);
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn keep_process_ends_only_the_guest() {
+        use std::sync::Arc;
+        use sync::Mutex;
+        use crate::elf::UserModeRuntime;
+        use crate::linux_usermode::main::catch_group_exit;
+        use crate::riscv::ume::signals::riscv64_init_sigconstant;
+        let addi = |rd: u32, rs1: u32, imm: u32| imm << 20 | rs1 << 15 | rd << 7 | 0x13;
+        let run = |code: Vec<u32>| {
+            let code: &'static [u32] = Box::leak(code.into_boxed_slice());
+            let mut ume = UserModeRuntime::default();
+            ume.sigcnst = Arc::new(Mutex::new(riscv64_init_sigconstant()));
+            ume.opts.keep_process = true;
+            let mut cpu = RiscvInt::init_usermode(Xlen::X64, ume);
+            cpu.pc = code.as_ptr() as u64;
+            let ge = cpu.user_struct.group_exit.clone();
+            catch_group_exit(&ge, || cpu.run())
+        };
+        // exit() of the only thread, which is the one that started the guest
+        assert_eq!(run(vec![addi(10, 0, 7), addi(17, 0, 93), 0x73]), Some(7));
+        // abort(): tkill(gettid(), SIGABRT) at the default action
+        let abort = vec![addi(17, 0, 178), 0x73, addi(11, 0, 6), addi(17, 0, 130), 0x73];
+        assert_eq!(run(abort), Some(128 + libc::SIGABRT));
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn memusage_carve_and_accessible() {
+        use crate::linux_usermode::memusage::{MapFlags, MemUsage, VmaKind};
+        let mu = MemUsage::new();
+        let rw = |kind| MapFlags { kind, readable: true, writable: true, shared: false };
+        mu.map(0x10000, 0x4000, rw(VmaKind::Anon));
+        mu.map_loader(0x20000, 0x1000, true);
+        assert_eq!(mu.committed(), 0x4000);
+        // read-only in the middle splits it
+        mu.protect(0x11000, 0x1000, true, false);
+        assert_eq!(mu.committed(), 0x3000);
+        assert!(mu.accessible(0x10000, 0x4000, false));
+        assert!(!mu.accessible(0x10000, 0x4000, true));
+        assert!(mu.accessible(0x12000, 0x2000, true));
+        mu.unmap(0x12000, 0x1000);
+        assert_eq!(mu.committed(), 0x2000);
+        assert!(!mu.accessible(0x11800, 0x1000, false));
+        assert!(mu.accessible(0x13000, 0x1000, true));
+        assert!(!mu.accessible(0x13000, 0x1001, false));
+        assert!(!mu.accessible(0xf000, 0x2000, false));
+        assert!(mu.accessible(0, 0, true));
+        assert!(!mu.accessible(u64::MAX, 2, false));
+        assert_eq!(mu.dumpable_ranges(), vec![(0x10000, 0x2000), (0x13000, 0x1000), (0x20000, 0x1000)]);
+        // asking doesn't change the list
+        let mut mem = mu.lock();
+        assert_eq!(mem.committed_after(0x10000, 0x4000, &rw(VmaKind::Anon)), 0x4000);
+        let file = MapFlags { kind: VmaKind::File, readable: true, writable: false, shared: true };
+        assert_eq!(mem.committed_after(0x10800, 0x1000, &file), 0x1800);
+        assert_eq!(mem.committed_after_protect(0x10000, 0x4000, true), 0x3000);
+        assert_eq!(mem.committed_after_protect(0x10000, 0x4000, false), 0);
+        mem.protect(0x10000, 0x4000, true, true);
+        drop(mem);
+        assert_eq!(mu.committed(), 0x3000);
+        assert_eq!(mu.peak_committed(), 0x4000);
+        assert_eq!(mu.mapped(), 0x4000);
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn dynload_irelative_resolved() {
+        use crate::elf::UserModeRuntime;
+        use crate::linux_usermode::dynload::DlPending;
+        use crate::riscv::ume::load::finish_dynload;
+        // li a0, 0x123; ret
+        let resolver: Vec<u32> = vec![0x12300513, 0x00008067];
+        let stack = vec![0u64; 512];
+        let mut slot = 0u64;
+        let mut cpu = RiscvInt::init_usermode(Xlen::X64, UserModeRuntime::default());
+        // argc is 0
+        cpu.regs[2] = stack.as_ptr() as u64 + 256 * 8;
+        let at = &mut slot as *mut u64 as u64;
+        cpu.user_struct.initvars.lock().dl = Some(DlPending {
+            ifuncs: vec![(at, resolver.as_ptr() as u64, 0x10)],
+            init_funcs: vec![],
+            tp: Some(0x5000),
+        });
+        finish_dynload(&mut cpu).unwrap();
+        assert_eq!(unsafe { std::ptr::read_volatile(&slot) }, 0x133);
+        assert_eq!(cpu.regs[4], 0x5000);
+        assert_eq!(cpu.regs[2], stack.as_ptr() as u64 + 256 * 8);
+        assert!(cpu.user_struct.initvars.lock().dl.is_none());
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn dynload_rejects_glibc() {
+        use std::path::Path;
+        use crate::linux_usermode::dynload::reject_glibc;
+        let exe = Path::new("/bin/guest");
+        for lib in ["libc.so.6", "libm.so.6", "libpthread.so.0", "ld-linux-riscv64-lp64d.so.1", "/lib/libdl.so.2"] {
+            let err = reject_glibc(lib, exe).unwrap_err().to_string();
+            assert!(err.contains("glibc") && err.contains("/bin/guest"), "{}", err);
+        }
+        for lib in ["libc.so", "libz.so.1", "libstdc++.so.6", "libgcc_s.so.1"] {
+            assert!(reject_glibc(lib, exe).is_ok(), "{}", lib);
+        }
+    }
+    // the block cache against decoding every instruction, on the same test rom. Returns tohost
+    fn lockstep_rom(fs: &str) -> u32 {
+        use crate::common::engine::lockstep;
+        use crate::riscv::interpreter::engine::{load_bare_metal, OracleEngine};
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testrom/riscv").join(fs);
+        let data = std::fs::read(&path).unwrap();
+        let ef = goblin::elf::Elf::parse(&data).unwrap();
+        let to_host = ef.section_headers.iter()
+            .find(|s| ef.shdr_strtab.get_at(s.sh_name) == Some(".tohost")).unwrap().sh_addr;
+        let mut cached = load_bare_metal(&path, 512 * 1024).unwrap();
+        cached.cache_enabled = true;
+        let mut oracle = OracleEngine(load_bare_metal(&path, 512 * 1024).unwrap());
+        lockstep(&mut cached, &mut oracle, 20_000, 16, &[(to_host, 8)]).unwrap();
+        cached.memsource.guest_mem.read_phys_32(to_host, MemEndian::Little).unwrap()
+    }
+    #[test]
+    fn lockstep_cached_vs_oracle() {
+        assert_eq!(lockstep_rom("rv64ui-p-add"), 1);
+        assert_eq!(lockstep_rom("rv64ui-p-sw"), 1);
+    }
+    #[test]
+    fn lockstep_bare_metal_guest() {
+        use crate::common::engine::ExecutionEngine;
+        use crate::riscv::interpreter::engine::{load_bare_metal, lockstep_bare_metal};
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testrom/riscv/rv64ui-v-add");
+        // virtual memory, traps and all, one instruction at a time
+        assert_eq!(lockstep_bare_metal(&path, 512 * 1024, 50_000, 1, &[]).unwrap(), Ok(()));
+        // RAM is checked, the end of it isn't a short read
+        let mut cpu = load_bare_metal(&path, 512 * 1024).unwrap();
+        assert!(cpu.memory().read_bytes(DRAM_BASE + 512 * 1024 - 4, 8).is_err());
+        assert_eq!(cpu.memory().read_bytes(DRAM_BASE, 4).unwrap().len(), 4);
+        assert!(lockstep_bare_metal(&path.with_file_name("no-such-rom"), 512 * 1024, 1, 1, &[]).is_err());
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn arm64_rt_sigreturn_is_enosys() {
+        use crate::armv8::interpreter::main::Arm64Cpu;
+        use crate::elf::UserModeRuntime;
+        use crate::linux_usermode::main::UsermodeCpu;
+        let mut cpu = Arm64Cpu::init_usermode(UserModeRuntime::default());
+        let out = cpu.rt_frame_restore();
+        assert!(out.is_error);
+        assert_eq!(out.ret1 as i64, -libc::ENOSYS as i64);
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn lockstep_arm64() {
+        use crate::armv8::interpreter::main::Arm64Cpu;
+        use crate::common::engine::{lockstep, Mismatch};
+        use crate::elf::UserModeRuntime;
+        // add x0, x0, #1; b .-4
+        let code: Vec<u32> = vec![0x91000400, 0x17ffffff];
+        let cpu = || {
+            let mut cpu = Arm64Cpu::init_usermode(UserModeRuntime::default());
+            cpu.pc = code.as_ptr() as u64;
+            cpu
+        };
+        let (mut a, mut b) = (cpu(), cpu());
+        lockstep(&mut a, &mut b, 1000, 7, &[]).unwrap();
+        assert_eq!((a.icount, a.regs()[0]), (1000, 500));
+        let (mut a, mut b) = (cpu(), cpu());
+        b.set_reg(0, 5, false);
+        let d = lockstep(&mut a, &mut b, 1000, 7, &[]).unwrap_err();
+        assert_eq!((d.icount, d.mismatch), (7, Mismatch::Registers));
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn poison_arm64_loads_and_stores() {
+        use std::sync::Arc;
+        use sync::Mutex;
+        use crate::armv8::interpreter::main::Arm64Cpu;
+        use crate::common::poison::{PoisonAccess, PoisonMap, PoisonPolicy};
+        use crate::elf::UserModeRuntime;
+        let mut data = [0u64; 4];
+        let base = data.as_mut_ptr() as u64;
+        // ldr x1, [x0]; str x1, [x0, #8]; ldr x2, [x0, #16]
+        let code: Vec<u32> = vec![0xf9400001, 0xf9000401, 0xf9400802];
+        let pm = Arc::new(Mutex::new(PoisonMap::new(PoisonPolicy::Report)));
+        pm.lock().poison(base + 8, 8, "guard");
+        let mut cpu = Arm64Cpu::init_usermode(UserModeRuntime::default());
+        cpu.set_poison_map(Some(pm.clone()));
+        cpu.pc = code.as_ptr() as u64;
+        cpu.set_reg(0, base, false);
+        cpu.run_for(3);
+        // the store hits it, neither load does and the code itself isn't checked
+        let reps = pm.lock().take_reports();
+        assert_eq!(reps.len(), 1);
+        assert_eq!((reps[0].access, reps[0].addr, reps[0].label.as_str()), (PoisonAccess::Write, base + 8, "guard"));
+        assert_eq!(reps[0].pc, code.as_ptr() as u64 + 4);
+    }
+    #[test]
+    fn fuzz_machine_smoke() {
+        use crate::riscv::interpreter::fuzz_machine::FuzzMachine;
+        let machines: Vec<FuzzMachine> = (0..32).map(FuzzMachine::from_seed).collect();
+        assert_eq!(FuzzMachine::from_seed(7), machines[7]);
+        assert!(machines.iter().any(|m| m.xlen == Xlen::X32) && machines.iter().any(|m| m.xlen == Xlen::X64));
+        assert!(machines.iter().any(|m| m.harts > 1) && machines.iter().any(|m| !m.ext_c));
+        for m in &machines {
+            assert_eq!(m.ram_base % 4096, 0);
+            if m.xlen == Xlen::X32 {
+                assert!(m.ram_base + m.ram_size <= 1 << 32, "{}", m);
+            }
+            m.smoke().unwrap_or_else(|e| panic!("{}: {}", m, e));
+        }
+    }
+    #[test]
+    fn block_store_reused_between_runs() {
+        use std::sync::Arc;
+        use crate::common::engine::lockstep;
+        use crate::riscv::interpreter::block_store::BlockStore;
+        use crate::riscv::interpreter::engine::OracleEngine;
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testrom/riscv/rv64ui-p-add");
+        let data = std::fs::read(&path).unwrap();
+        let ef = goblin::elf::Elf::parse(&data).unwrap();
+        let to_host = ef.section_headers.iter()
+            .find(|s| ef.shdr_strtab.get_at(s.sh_name) == Some(".tohost")).unwrap().sh_addr;
+        let file = std::env::temp_dir().join(format!("block-store-test-{}", std::process::id()));
+        let file = file.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&file);
+        let open = |key: &str| Arc::new(BlockStore::open(file.clone(), key.to_string(), DRAM_BASE,
+                                                         DRAM_BASE..DRAM_BASE + 512 * 1024));
+        let cpu = || {
+            let vmmem = vm_memory::GuestMemory::new(&[(GuestAddress(DRAM_BASE), 512 * 1024)]).unwrap();
+            load_elf(&vmmem, GuestAddress(DRAM_BASE), &mut File::open(&path).unwrap()).unwrap();
+            let mut cpu = RiscvInt::init_systemmode(Xlen::X64, vmmem);
+            cpu.pc = ef.entry;
+            cpu
+        };
+        // the warm run has to agree with the interpreter as much as the cold one
+        let run = |store: Arc<BlockStore>| {
+            let mut cached = cpu();
+            cached.cache_enabled = true;
+            cached.block_store = Some(store.clone());
+            lockstep(&mut cached, &mut OracleEngine(cpu()), 20_000, 16, &[(to_host, 8)]).unwrap();
+            store.save();
+            cached.memsource.guest_mem.read_phys_32(to_host, MemEndian::Little).unwrap()
+        };
+        let cold = open("k");
+        assert_eq!(run(cold.clone()), 1);
+        assert_eq!(cold.reused(), 0);
+        assert!(cold.len() > 0);
+        let warm = open("k");
+        assert_eq!(warm.len(), cold.len());
+        assert_eq!(run(warm.clone()), 1);
+        assert!(warm.reused() > 0);
+        // another binary or build starts from nothing
+        assert_eq!(open("other").len(), 0);
+        std::fs::remove_file(&file).unwrap();
+    }
+    #[test]
+    fn block_store_rejects_bad_entries() {
+        use std::sync::Arc;
+        use crate::riscv::interpreter::block_store::{cache_key, BlockStore};
+        use crate::riscv::interpreter::custom::{CustomExtensions, CustomInsn, CustomOpcode};
+        const ADDI: u32 = 0x00150513; // addi a0, a0, 1
+        const JAL: u32 = 0x0000006f; // j .
+        let vmmem = vm_memory::GuestMemory::new(&[(GuestAddress(DRAM_BASE), 64 * 1024)]).unwrap();
+        for (i, w) in [ADDI, ADDI, JAL, ADDI].iter().enumerate() {
+            vmmem.write_obj_at_addr(*w, GuestAddress(DRAM_BASE + i as u64 * 4)).unwrap();
+        }
+        let file = std::env::temp_dir().join(format!("block-store-bad-{}", std::process::id()));
+        let file = file.to_str().unwrap().to_string();
+        let insn = |bits: u32, inc_by: u64| format!(r#"{{"bits":{},"inc_by":{}}}"#, bits, inc_by);
+        let block = |end: u64, insns: &[String]| format!(r#"{{"end":{},"insns":[{}]}}"#, end, insns.join(","));
+        // whether a file holding just `entry` at the start of RAM gets it used
+        let reused = |key: &str, entry: String| {
+            std::fs::write(&file, format!(r#"{{"key":"k","blocks":{{"0":{}}}}}"#, entry)).unwrap();
+            let store = BlockStore::open(file.clone(), key.to_string(), DRAM_BASE, DRAM_BASE..DRAM_BASE + 4096);
+            let mut cpu = RiscvInt::init_systemmode(Xlen::X64, vmmem.clone());
+            cpu.cache_enabled = true;
+            cpu.block_store = Some(Arc::new(store));
+            cpu.load_stored_block(DRAM_BASE)
+        };
+        let good = || vec![insn(ADDI, 4), insn(ADDI, 4), insn(JAL, 4)];
+        assert!(reused("k", block(8, &good())));
+        // written for another binary or build
+        assert!(!reused("other", block(8, &good())));
+        // not what's in memory
+        assert!(!reused("k", block(8, &[insn(ADDI, 4), insn(ADDI + (1 << 20), 4), insn(JAL, 4)])));
+        // the jump isn't last, the end is off, a bogus length, nothing at all
+        assert!(!reused("k", block(12, &[good(), vec![insn(ADDI, 4)]].concat())));
+        assert!(!reused("k", block(4, &good())));
+        assert!(!reused("k", block(8, &[insn(ADDI, 4), insn(ADDI, 3), insn(JAL, 4)])));
+        assert!(!reused("k", block(0, &[])));
+        // garbage
+        assert!(!reused("k", "[1, 2]".to_string()));
+        std::fs::remove_file(&file).unwrap();
 
+        // the key changes with what the blocks were decoded for
+        struct Nop;
+        impl CustomInsn for Nop {
+            fn execute(&self, _cpu: &mut RiscvInt, _insn: u32) -> bool {
+                true
+            }
+        }
+        let mut custom = CustomExtensions::new();
+        custom.add_insn(CustomOpcode::Custom0, Arc::new(Nop));
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testrom/riscv/rv64ui-p-add");
+        let keys = [
+            cache_key(None, &path, Xlen::X64, true, None),
+            cache_key(None, &path, Xlen::X64, false, None),
+            cache_key(None, &path, Xlen::X32, true, None),
+            cache_key(None, &path, Xlen::X64, true, Some(&custom)),
+            cache_key(Some(&[1, 2, 3]), &path, Xlen::X64, true, None),
+        ];
+        for (i, k) in keys.iter().enumerate() {
+            assert!(keys[i + 1..].iter().all(|o| o != k), "{}", k);
+        }
+        assert_eq!(keys[0], cache_key(None, &path, Xlen::X64, true, None));
+    }
+    #[test]
+    fn atomics_trap_translate_and_reserve() {
+        use crate::riscv::common::RiscvArgs;
+        use crate::riscv::interpreter::atomic::{amoadd_w, lr_d, sc_d};
+        let vmmem = vm_memory::GuestMemory::new(&[(GuestAddress(DRAM_BASE), 64 * 1024)]).unwrap();
+        let mut cpu = RiscvInt::init_systemmode(Xlen::X64, vmmem);
+        let args = RiscvArgs { rd: 10, rs1: 11, rs2: 12, ..Default::default() };
+        cpu.memsource.guest_mem.write_phys_32(DRAM_BASE, 5, MemEndian::Little).unwrap();
+        cpu.regs[11] = DRAM_BASE;
+        cpu.regs[12] = 3;
+        amoadd_w(&mut cpu, &args);
+        assert_eq!(cpu.regs[10], 5);
+        assert_eq!(cpu.memsource.guest_mem.read_phys_32(DRAM_BASE, MemEndian::Little).unwrap(), 8);
+        let trap_at = |cpu: &mut RiscvInt, addr: u64| {
+            cpu.regs[11] = addr;
+            amoadd_w(cpu, &args);
+            let t = cpu.trap.take().map(|t| (t.ttype, t.val));
+            cpu.stop_exec = false;
+            t
+        };
+        assert_eq!(trap_at(&mut cpu, DRAM_BASE + 2), Some((Exception::StoreAddressMisaligned, DRAM_BASE + 2)));
+        assert_eq!(trap_at(&mut cpu, DRAM_BASE + 64 * 1024), Some((Exception::StoreAccessFault, DRAM_BASE + 64 * 1024)));
+        assert_eq!(cpu.memsource.guest_mem.read_phys_32(DRAM_BASE, MemEndian::Little).unwrap(), 8);
+        // storing the value lr read still takes the reservation
+        cpu.regs[11] = DRAM_BASE + 8;
+        lr_d(&mut cpu, &args);
+        cpu.write64(DRAM_BASE + 8, cpu.regs[10], true).unwrap();
+        cpu.regs[12] = 7;
+        sc_d(&mut cpu, &args);
+        assert_eq!(cpu.regs[10], 1);
+        // other tests' stores can fail an sc too, which is allowed
+        let stored = (0..100).any(|_| {
+            lr_d(&mut cpu, &args);
+            sc_d(&mut cpu, &args);
+            cpu.regs[10] == 0
+        });
+        assert!(stored);
+        assert_eq!(cpu.memsource.guest_mem.read_phys_64(DRAM_BASE + 8, MemEndian::Little).unwrap(), 7);
+    }
+    #[test]
+    fn hot_patch_drops_cached_block() {
+        use crate::common::engine::ExecutionEngine;
+        const ADDI_A0_1: u32 = 0x00150513;
+        const ADDI_A0_2: u32 = 0x00250513;
+        // jal x0, -4
+        const LOOP_BACK: u32 = 0xffdff06f;
+        let vmmem = vm_memory::GuestMemory::new(&[(GuestAddress(DRAM_BASE), 64 * 1024)]).unwrap();
+        let mut cpu = RiscvInt::init_systemmode(Xlen::X64, vmmem);
+        cpu.memsource.guest_mem.write_phys_32(DRAM_BASE, ADDI_A0_1, MemEndian::Little).unwrap();
+        cpu.memsource.guest_mem.write_phys_32(DRAM_BASE + 4, LOOP_BACK, MemEndian::Little).unwrap();
+        cpu.pc = DRAM_BASE;
+        cpu.cache_enabled = true;
+        ExecutionEngine::run(&mut cpu, 10);
+        assert_eq!(cpu.regs[10], 5);
+        let old = cpu.patch_code(DRAM_BASE, &ADDI_A0_2.to_le_bytes()).unwrap();
+        assert_eq!(old, ADDI_A0_1.to_le_bytes());
+        // the block with the old addi is gone from the cache
+        ExecutionEngine::run(&mut cpu, 10);
+        assert_eq!(cpu.regs[10], 15);
+        assert!(cpu.set_reg_named("a0", 0x1234));
+        assert_eq!(cpu.regs[10], 0x1234);
+        assert!(!cpu.set_reg_named("zero", 1));
+    }
+    #[test]
+    fn hot_patch_rolls_back_partial_write() {
+        let vmmem = vm_memory::GuestMemory::new(&[(GuestAddress(DRAM_BASE), 64 * 1024)]).unwrap();
+        let mut cpu = RiscvInt::init_systemmode(Xlen::X64, vmmem);
+        let last = DRAM_BASE + 64 * 1024 - 2;
+        cpu.memsource.guest_mem.write_phys_16(last, 0x1234, MemEndian::Little).unwrap();
+        // the second half is past the end of RAM
+        assert!(cpu.patch_code(last, &[0xaa; 4]).is_err());
+        assert_eq!(cpu.memsource.guest_mem.read_phys_16(last, MemEndian::Little).unwrap(), 0x1234);
+    }
+    #[test]
+    fn hot_patch_log_trimmed() {
+        use crate::common::hotpatch::Gate;
+        let mut g = Gate::new();
+        let a = g.register(0);
+        let b = g.register(0);
+        g.log(1, 0x1000, 4);
+        g.log(2, 0x2000, 4);
+        assert_eq!(g.catch_up(a, 0, 2), vec![(0x1000, 4), (0x2000, 4)]);
+        // b hasn't seen either yet
+        assert_eq!(g.logged(), 2);
+        assert_eq!(g.catch_up(b, 0, 1), vec![(0x1000, 4), (0x2000, 4)]);
+        assert_eq!(g.logged(), 1);
+        // a hart that's gone doesn't hold anything back, and a new one starts out up to date
+        g.unregister(b, 2);
+        assert_eq!(g.logged(), 0);
+        let c = g.register(2);
+        g.log(3, 0x3000, 4);
+        assert_eq!(g.catch_up(c, 2, 3), vec![(0x3000, 4)]);
+        assert_eq!(g.logged(), 1); // a is still at 2
+        g.unregister(a, 3);
+        assert_eq!(g.logged(), 0);
+    }
+    #[test]
+    fn patch_gate_pause_and_checkin() {
+        use std::sync::mpsc::channel;
+        use std::sync::Arc;
+        use std::time::{Duration, Instant};
+        use crate::common::hotpatch::{PatchGate, PatchSeen};
+        let gate = Arc::new(PatchGate::new());
+        // a hart that goes into a syscall wakes the pause right away
+        let g = gate.clone();
+        let (tx, rx) = channel();
+        let t = std::thread::spawn(move || {
+            g.enter();
+            tx.send(()).unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+            g.leave();
+        });
+        rx.recv().unwrap();
+        let start = Instant::now();
+        gate.pause(Duration::from_secs(30)).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        gate.resume();
+        t.join().unwrap();
+        // one that parks at its check-in gets the patch made meanwhile
+        let g = gate.clone();
+        let (tx, rx) = channel();
+        let t = std::thread::spawn(move || {
+            let mut seen = PatchSeen::new(&g);
+            g.enter();
+            tx.send(()).unwrap();
+            while g.state() == seen.state {
+                std::thread::yield_now();
+            }
+            let ranges = g.checkin(&mut seen);
+            g.leave();
+            ranges
+        });
+        rx.recv().unwrap();
+        gate.pause(Duration::from_secs(30)).unwrap();
+        // another guest's gate doesn't wait for this one's harts
+        let other = PatchGate::new();
+        other.pause(Duration::from_millis(1)).unwrap();
+        other.resume();
+        gate.record_patch(0x1000, 4);
+        gate.resume();
+        assert_eq!(t.join().unwrap(), vec![(0x1000, 4)]);
+        // one that never checks in makes the pause give up, unpaused
+        let g = gate.clone();
+        let (tx, rx) = channel();
+        let (done_tx, done_rx) = channel::<()>();
+        let t = std::thread::spawn(move || {
+            g.enter();
+            tx.send(()).unwrap();
+            done_rx.recv().unwrap();
+            g.leave();
+        });
+        rx.recv().unwrap();
+        assert!(gate.pause(Duration::from_millis(20)).is_err());
+        assert!(!gate.paused());
+        done_tx.send(()).unwrap();
+        t.join().unwrap();
+    }
+    // herd7's allowed states live in testrom/litmus/NAME.out, next to the test
+    #[cfg(feature = "linux-usermode")]
+    fn litmus(name: &str) {
+        use crate::riscv::interpreter::litmus::*;
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testrom/litmus");
+        let test = std::fs::read_to_string(dir.join(format!("{}.litmus", name))).unwrap();
+        let test = LitmusTest::parse(&test).unwrap();
+        let allowed = std::fs::read_to_string(dir.join(format!("{}.out", name))).unwrap();
+        let allowed = parse_herd_states(&allowed).unwrap();
+        let reports = [
+            run_interleaved(&test, &allowed, 2000, 0x5eed_1234),
+            run_threaded(&test, &allowed, 300, 0x5eed_1234),
+        ];
+        for r in reports {
+            assert!(r.forbidden.is_empty(), "{}: forbidden states {:?} (seen {:?})", name, r.forbidden, r.seen);
+        }
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn litmus_mp() {
+        litmus("MP");
+        litmus("MP+fence.rw.rws");
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn litmus_sb() {
+        litmus("SB");
+        litmus("SB+fence.rw.rws");
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn litmus_amo() {
+        litmus("ATOM-amoadd");
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn litmus_lrsc() {
+        litmus("ATOM-lrsc");
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn litmus_catches_forbidden() {
+        use crate::riscv::interpreter::litmus::*;
+        // nothing is allowed, whatever comes out has to be reported
+        let test = LitmusTest::parse("RISCV T\n{\n0:x6=x;\n}\n P0 ;\n amoadd.w x5,x6,(x6) ;\nexists (0:x5=0)\n").unwrap();
+        let allowed = parse_herd_states("States 1\n0:x5=7;\n").unwrap();
+        let r = run_interleaved(&test, &allowed, 3, 1);
+        assert_eq!(r.forbidden.len(), 1);
+        assert_eq!(r.seen.values().sum::<u64>(), 3);
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn elf_target_checks() {
+        use crate::linux_usermode::elfcheck::elf_target;
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testrom/riscv/rv64ui-p-add");
+        let data = std::fs::read(path).unwrap();
+        let hdr = goblin::elf::Elf::parse_header(&data).unwrap();
+        assert_eq!(elf_target(&hdr).ok().map(|t| t.name()), Some("rv64"));
+        let mut be = hdr;
+        be.e_ident[goblin::elf::header::EI_DATA] = goblin::elf::header::ELFDATA2MSB;
+        assert!(elf_target(&be).unwrap_err().contains("big-endian"));
+        let mut x86 = hdr;
+        x86.e_machine = goblin::elf::header::EM_X86_64;
+        assert!(elf_target(&x86).is_err());
+        let mut obj = hdr;
+        obj.e_type = goblin::elf::header::ET_REL;
+        assert!(elf_target(&obj).unwrap_err().contains("relocatable"));
+    }
+    #[test]
+    fn isa_usage_names() {
+        use crate::riscv::isa_usage::*;
+        let ext = |w: u32, is_64: bool| decoder_name(w, is_64).map(|n| (mnemonic(n), extension(n)));
+        assert_eq!(ext(0x00150513, true), Some(("addi".to_string(), "I"))); // addi a0, a0, 1
+        assert_eq!(ext(0x40c5f533, true), Some(("andn".to_string(), "Zbb")));
+        assert_eq!(ext(0x4015f553, true), Some(("fcvt.s.d".to_string(), "D")));
+        assert_eq!(ext(0x0505, true), Some(("c.addi".to_string(), "C")));
+        // same bits, different instruction per xlen
+        assert_eq!(decoder_name(0x6188, true), Some("c_ld"));
+        assert_eq!(decoder_name(0x6188, false), Some("c_flw"));
+        assert_eq!(decoder_name(0x0005b503, false), None); // ld
+        let t = IsaTarget::parse("rv64gc").unwrap();
+        assert!(t.has("I") && t.has("D") && t.has("C") && t.has("Zicsr"));
+        assert!(!t.has("V") && !t.has("Zbb"));
+        assert!(IsaTarget::parse("rv64imac_zba_zbb").unwrap().has("Zbb"));
+        assert!(IsaTarget::parse("x86_64").is_err());
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn isa_report_merges_and_finds_missing() {
+        use std::sync::Arc;
+        use rustc_hash::FxHashMap;
+        use crate::elf::UserModeRuntime;
+        use crate::linux_usermode::isa_report::IsaRecorder;
+        use crate::riscv::interpreter::custom::{CustomExtensions, CustomInsn, CustomOpcode};
+        use crate::riscv::isa_usage::IsaTarget;
+        struct Nop;
+        impl CustomInsn for Nop {
+            fn execute(&self, _cpu: &mut RiscvInt, _insn: u32) -> bool {
+                true
+            }
+        }
+        let mut ume = UserModeRuntime::default();
+        ume.is_64 = true;
+        let mut custom = CustomExtensions::new();
+        custom.add_insn(CustomOpcode::Custom0, Arc::new(Nop));
+        ume.opts.riscv_custom = Some(Arc::new(custom));
+        let rec = IsaRecorder::new("-".to_string(), Some(IsaTarget::parse("rv64imac").unwrap()));
+        let mut hart = FxHashMap::default();
+        hart.insert((0x1000, 0x00150513), 3); // addi a0, a0, 1
+        hart.insert((0x1004, 0x40c5f533), 2); // andn, Zbb
+        hart.insert((0x1008, 0x0000000b), 1); // custom-0, taken by the handler
+        rec.merge(&mut hart);
+        assert!(hart.is_empty());
+        // another hart ran the same addi
+        let mut other = FxHashMap::default();
+        other.insert((0x1000, 0x00150513), 4);
+        rec.merge(&mut other);
+        // the executable also has a fcvt.s.d that never ran
+        let r = rec.build(&ume, &[(0x1000, 0x00150513), (0x2000, 0x4015f553)]);
+        assert_eq!((r.instructions, r.sites), (10, 3));
+        assert_eq!((r.mnemonics[0].name.as_str(), r.mnemonics[0].count, r.mnemonics[0].sites), ("addi", 7, 1));
+        assert_eq!(r.extensions["custom"].count, 1);
+        assert_eq!(r.extensions["Zbb"].mnemonics, vec!["andn".to_string()]);
+        let missing: Vec<_> = r.missing.iter().map(|m| (m.extension, m.count, m.sites.len())).collect();
+        assert_eq!(missing, vec![("Zbb", 2, 1), ("custom", 1, 1)]);
+        assert_eq!(r.static_extensions["I"].sites, 1);
+        assert_eq!(r.static_missing, vec!["D"]);
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn isa_report_static_text() {
+        use crate::linux_usermode::isa_report::static_text;
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testrom/riscv/rv64ui-p-add");
+        let data = std::fs::read(&path).unwrap();
+        let ef = goblin::elf::Elf::parse(&data).unwrap();
+        let text = static_text(&data, 0x1000);
+        let entry = text.iter().find(|(a, _)| *a == ef.entry + 0x1000).expect("no instruction at the entry");
+        let off = ef.program_headers.iter().find(|p| (p.p_vaddr..p.p_vaddr + p.p_filesz).contains(&ef.entry))
+            .map(|p| (ef.entry - p.p_vaddr + p.p_offset) as usize).unwrap();
+        assert_eq!(entry.1, u32::from_le_bytes(data[off..off + 4].try_into().unwrap()));
+        // in order, no overlaps
+        assert!(text.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(static_text(b"not an elf", 0).is_empty());
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn identity_view_paths() {
+        use std::ffi::CString;
+        use std::os::unix::io::AsRawFd;
+        use crate::linux_usermode::identity::GuestIdentity;
+        use crate::linux_usermode::vfs::{resolve_guest_path, FsMode, PathIntent};
+        let mut id = GuestIdentity::new();
+        assert!(id.set_hostname("has space").is_err());
+        assert!(id.set_machine_id("1234").is_err());
+        assert!(id.set_mac("aa:bb:cc:dd:ee").is_err());
+        id.set_hostname("board").unwrap();
+        id.set_machine_id("0123456789ABCDEF0123456789abcdef").unwrap();
+        id.set_mac("02:00:00:aa:bb:cc").unwrap();
+        assert_eq!(id.contents("/etc/machine-id").unwrap(), b"0123456789abcdef0123456789abcdef\n");
+        assert_eq!(id.contents("/sys/class/net/eth0/address").unwrap(), b"02:00:00:aa:bb:cc\n");
+        assert_eq!(id.contents("/sys/class/net/lo/address"), None);
+        // nothing's served before there's a view
+        assert_eq!(id.view_path("/etc/hostname"), None);
+        id.make_view().unwrap();
+        let dir = std::env::temp_dir().join(format!("vfs-id-{}", std::process::id()));
+        let sysroot = dir.join("root");
+        let upper = dir.join("upper");
+        std::fs::create_dir_all(sysroot.join("etc")).unwrap();
+        std::fs::create_dir_all(&upper).unwrap();
+        std::fs::write(sysroot.join("etc/hostname"), "sysroot\n").unwrap();
+        let sr = sysroot.to_str().unwrap();
+        let etc = std::fs::File::open(sysroot.join("etc")).unwrap();
+        let read = |mode: &FsMode, dirfd: i32, path: &str, id: Option<&GuestIdentity>| {
+            let p = CString::new(path).unwrap();
+            let host = resolve_guest_path(mode, sr, false, id, dirfd, p.as_ptr(), PathIntent::Read).unwrap();
+            std::fs::read_to_string(host.to_str().unwrap()).unwrap()
+        };
+        let overlay = FsMode::Overlay(upper.clone());
+        for mode in [FsMode::Passthrough, FsMode::ReadOnly, overlay.clone()] {
+            assert_eq!(read(&mode, libc::AT_FDCWD, "/etc/hostname", Some(&id)), "board\n");
+            assert_eq!(read(&mode, libc::AT_FDCWD, "/etc/hostname", None), "sysroot\n");
+        }
+        // relative to a guest directory, and with a detour
+        assert_eq!(read(&overlay, etc.as_raw_fd(), "hostname", Some(&id)), "board\n");
+        assert_eq!(read(&overlay, etc.as_raw_fd(), "../etc/./hostname", Some(&id)), "board\n");
+        assert_eq!(read(&FsMode::Passthrough, libc::AT_FDCWD, "/sys/class/../class/net/eth9/address", Some(&id)), "02:00:00:aa:bb:cc\n");
+        // writes go to the view too, the overlay never sees the file
+        let p = CString::new("/etc/hostname").unwrap();
+        let host = resolve_guest_path(&overlay, sr, false, Some(&id), libc::AT_FDCWD, p.as_ptr(), PathIntent::Modify).unwrap();
+        assert_eq!(Some(std::path::PathBuf::from(host.to_str().unwrap())), id.view_path("/etc/hostname"));
+        assert!(!upper.join("etc").exists());
+        let view = id.view_path("/etc/hostname").unwrap();
+        id.remove_view();
+        assert!(!view.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn pooled_futex_timeout_with_busy_workers() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::time::Duration;
+        use sync::Mutex;
+        use crate::linux_usermode::sched::*;
+        // two busy threads keep both workers going, the third waits 5ms and has to be woken anyway
+        struct T {
+            tid: u64,
+            slices: u64,
+            children: Vec<Box<T>>,
+            timed_out: Arc<AtomicBool>,
+            st: Arc<Mutex<SchedState>>,
+        }
+        impl DetThread for T {
+            fn run_slice(&mut self, spawned: &mut Vec<Box<T>>) -> SchedEvent {
+                self.slices += 1;
+                spawned.append(&mut self.children);
+                if self.timed_out.load(Ordering::SeqCst) {
+                    return SchedEvent::Exit;
+                }
+                if self.tid == 3 {
+                    self.st.lock().futex_wait(self.tid, 0x1000, u32::MAX, Some(Duration::from_millis(5)));
+                    return SchedEvent::FutexWait;
+                }
+                std::thread::sleep(Duration::from_millis(1));
+                assert!(self.slices < 2000, "the wait didn't time out while the workers were busy");
+                SchedEvent::Yield
+            }
+            fn sched_tid(&self) -> u64 {
+                self.tid
+            }
+            fn sched_icount(&self) -> u64 {
+                self.slices
+            }
+            fn futex_timed_out(&mut self) {
+                assert_eq!(self.tid, 3);
+                self.timed_out.store(true, Ordering::SeqCst);
+            }
+            fn futex_interrupted(&mut self) {}
+            fn sched_state(&self) -> Arc<Mutex<SchedState>> {
+                self.st.clone()
+            }
+        }
+        let st = Arc::new(Mutex::new(SchedState::pooled(100_000, 1000, 2)));
+        let flag = Arc::new(AtomicBool::new(false));
+        let t = |tid, children| Box::new(T { tid, slices: 0, children, timed_out: flag.clone(), st: st.clone() });
+        assert_eq!(run_pooled(t(1, vec![t(2, vec![]), t(3, vec![])]), st.clone()), SchedExit::Done);
+        assert!(flag.load(Ordering::SeqCst));
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn det_forked_child_leaves_parent_state() {
+        use std::sync::Arc;
+        use std::time::Duration;
+        use sync::Mutex;
+        use crate::linux_usermode::sched::*;
+        struct T {
+            slices: u64,
+            st: Arc<Mutex<SchedState>>,
+            child: Option<Arc<Mutex<SchedState>>>,
+        }
+        impl DetThread for T {
+            fn run_slice(&mut self, _spawned: &mut Vec<Box<T>>) -> SchedEvent {
+                self.slices += 1;
+                match self.slices {
+                    // what fork_proc does, the child gets a state of its own
+                    1 => {
+                        self.st = self.child.take().unwrap();
+                        SchedEvent::ForkedChild
+                    }
+                    2 => SchedEvent::Yield,
+                    _ => SchedEvent::ExitGroup(4),
+                }
+            }
+            fn sched_tid(&self) -> u64 {
+                1
+            }
+            fn sched_icount(&self) -> u64 {
+                self.slices
+            }
+            fn futex_timed_out(&mut self) {}
+            fn futex_interrupted(&mut self) {}
+            fn sched_state(&self) -> Arc<Mutex<SchedState>> {
+                self.st.clone()
+            }
+        }
+        let parent = Arc::new(Mutex::new(SchedState::new(100_000, 1000)));
+        let child = Some(Arc::new(Mutex::new(parent.lock().for_child())));
+        let (tx, rx) = std::sync::mpsc::channel();
+        let st = parent.clone();
+        let held = parent.lock();
+        // with the parent's lock held, as if another thread had it when we forked
+        let thread = std::thread::spawn(move || {
+            let t = Box::new(T { slices: 0, st: st.clone(), child });
+            tx.send(run_deterministic(t, st)).unwrap();
+        });
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(SchedExit::ExitGroup(4)));
+        drop(held);
+        thread.join().unwrap();
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn det_tgkill_queues_for_target_thread() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use sync::Mutex;
+        use crate::elf::UserModeRuntime;
+        use crate::linux_usermode::main::{u_tgkill, SyscallIn, SyscallType};
+        use crate::linux_usermode::sched::*;
+        use crate::linux_usermode::signals::{GuestSignals, SigEntry};
+        use crate::riscv::ume::signals::riscv64_init_sigconstant;
+        // thread 2 sleeps on a futex, thread 1 sends it SIGUSR1, which has a handler
+        struct T {
+            tid: u64,
+            slices: u64,
+            child: Option<Box<T>>,
+            ume: Option<UserModeRuntime>,
+            interrupted: Arc<AtomicBool>,
+            st: Arc<Mutex<SchedState>>,
+        }
+        impl DetThread for T {
+            fn run_slice(&mut self, spawned: &mut Vec<Box<T>>) -> SchedEvent {
+                self.slices += 1;
+                spawned.extend(self.child.take());
+                match (self.tid, self.slices) {
+                    (2, 1) => {
+                        self.st.lock().futex_wait(2, 0x1000, u32::MAX, None);
+                        SchedEvent::FutexWait
+                    }
+                    (1, 3) => {
+                        let ume = self.ume.as_mut().unwrap();
+                        let pid = unsafe { libc::getpid() } as u64;
+                        let args = [pid, 2, libc::SIGUSR1 as u64, 0, 0, 0, 0];
+                        let out = u_tgkill(SyscallIn { syscall: SyscallType::Tgkill, args }, ume, true);
+                        assert_eq!(out.ret1, 0);
+                        let args = [pid, 9, libc::SIGUSR1 as u64, 0, 0, 0, 0];
+                        let out = u_tgkill(SyscallIn { syscall: SyscallType::Tgkill, args }, ume, true);
+                        assert_eq!(out.ret1 as i64, -libc::ESRCH as i64);
+                        SchedEvent::Exit
+                    }
+                    (1, _) => SchedEvent::Yield,
+                    _ => SchedEvent::Exit,
+                }
+            }
+            fn sched_tid(&self) -> u64 {
+                self.tid
+            }
+            fn sched_icount(&self) -> u64 {
+                self.slices
+            }
+            fn futex_timed_out(&mut self) {}
+            fn futex_interrupted(&mut self) {
+                assert_eq!(self.tid, 2);
+                self.interrupted.store(true, Ordering::SeqCst);
+            }
+            fn sched_state(&self) -> Arc<Mutex<SchedState>> {
+                self.st.clone()
+            }
+        }
+        let st = Arc::new(Mutex::new(SchedState::new(100_000, 1000)));
+        let target = GuestSignals::new();
+        {
+            let mut info = target.info.lock().unwrap();
+            info.cnsts = riscv64_init_sigconstant();
+            info.actions.lock().unwrap()[10] = SigEntry { handler_func: 0x1234, is_valid: true, ..Default::default() };
+        }
+        st.lock().add_thread(2, target.clone());
+        let mut ume = UserModeRuntime::default();
+        ume.tid_val = 1;
+        ume.sched = Some(st.clone());
+        let flag = Arc::new(AtomicBool::new(false));
+        let t = |tid, child, ume| Box::new(T { tid, slices: 0, child, ume, interrupted: flag.clone(), st: st.clone() });
+        assert_eq!(run_deterministic(t(1, Some(t(2, None, None)), Some(ume)), st.clone()), SchedExit::Done);
+        assert!(flag.load(Ordering::SeqCst));
+        assert!(target.pending.load(Ordering::SeqCst));
+        assert_eq!(target.info.lock().unwrap().use_idx, Some(10));
+        // no handler, so it's the same on any thread and isn't queued
+        assert!(!target.post_tkill(libc::SIGUSR2, 1, 0));
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn pooled_exit_group_interrupts_blocked_worker() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::time::Duration;
+        use sync::Mutex;
+        use crate::linux_usermode::sched::*;
+        // thread 2 blocks in a read() nobody answers, thread 1 calls exit_group() meanwhile
+        struct T {
+            tid: u64,
+            children: Vec<Box<T>>,
+            fd: i32,
+            reading: Arc<AtomicBool>,
+            st: Arc<Mutex<SchedState>>,
+        }
+        impl DetThread for T {
+            fn run_slice(&mut self, spawned: &mut Vec<Box<T>>) -> SchedEvent {
+                spawned.append(&mut self.children);
+                if self.tid == 2 {
+                    self.reading.store(true, Ordering::SeqCst);
+                    let mut b = [0u8; 1];
+                    let n = unsafe { libc::read(self.fd, b.as_mut_ptr() as *mut libc::c_void, 1) };
+                    assert_eq!(n, -1);
+                    return SchedEvent::Yield;
+                }
+                if !self.reading.load(Ordering::SeqCst) {
+                    std::thread::sleep(Duration::from_millis(1));
+                    return SchedEvent::Yield;
+                }
+                std::thread::sleep(Duration::from_millis(20));
+                SchedEvent::ExitGroup(3)
+            }
+            fn sched_tid(&self) -> u64 {
+                self.tid
+            }
+            fn sched_icount(&self) -> u64 {
+                0
+            }
+            fn futex_timed_out(&mut self) {}
+            fn futex_interrupted(&mut self) {}
+            fn sched_state(&self) -> Arc<Mutex<SchedState>> {
+                self.st.clone()
+            }
+        }
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let st = Arc::new(Mutex::new(SchedState::pooled(100_000, 1000, 2)));
+        let reading = Arc::new(AtomicBool::new(false));
+        let t = |tid, children| Box::new(T { tid, children, fd: fds[0], reading: reading.clone(), st: st.clone() });
+        let first = t(1, vec![t(2, vec![])]);
+        let (tx, rx) = std::sync::mpsc::channel();
+        let st2 = st.clone();
+        std::thread::spawn(move || tx.send(run_pooled(first, st2)).unwrap());
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(SchedExit::ExitGroup(3)));
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn sched_deadlock_ends_the_run() {
+        use std::sync::Arc;
+        use sync::Mutex;
+        use crate::linux_usermode::sched::*;
+        // a thread asleep with nobody to wake it, the run ends instead of the process
+        struct T {
+            st: Arc<Mutex<SchedState>>,
+        }
+        impl DetThread for T {
+            fn run_slice(&mut self, _spawned: &mut Vec<Box<T>>) -> SchedEvent {
+                self.st.lock().futex_wait(1, 0x1000, u32::MAX, None);
+                SchedEvent::FutexWait
+            }
+            fn sched_tid(&self) -> u64 {
+                1
+            }
+            fn sched_icount(&self) -> u64 {
+                0
+            }
+            fn futex_timed_out(&mut self) {}
+            fn futex_interrupted(&mut self) {}
+            fn sched_state(&self) -> Arc<Mutex<SchedState>> {
+                self.st.clone()
+            }
+        }
+        let st = Arc::new(Mutex::new(SchedState::new(100_000, 1000)));
+        assert_eq!(run_deterministic(Box::new(T { st: st.clone() }), st), SchedExit::Deadlock);
+        let st = Arc::new(Mutex::new(SchedState::pooled(100_000, 1000, 2)));
+        assert_eq!(run_pooled(Box::new(T { st: st.clone() }), st), SchedExit::Deadlock);
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn det_futex_bad_timeout_is_efault() {
+        use std::sync::Arc;
+        use sync::Mutex;
+        use crate::elf::UserModeRuntime;
+        use crate::linux_usermode::main::{u_futex, SyscallIn, SyscallType};
+        use crate::linux_usermode::sched::SchedState;
+        let mut ume = UserModeRuntime::default();
+        ume.is_little_endian = true;
+        ume.is_64 = true;
+        ume.sched = Some(Arc::new(Mutex::new(SchedState::new(100_000, 1000))));
+        ume.opts.check_pointers = true;
+        let word = 7u32;
+        let addr = &word as *const u32 as u64;
+        let wait = |ume: &mut UserModeRuntime, val: u64, timeout: u64| {
+            let args = [addr, libc::FUTEX_WAIT as u64, val, timeout, 0, 0, 0];
+            u_futex(SyscallIn { syscall: SyscallType::Futex, args }, ume)
+        };
+        // nothing is mapped as far as the VMA list knows
+        let out = wait(&mut ume, 7, 0x10);
+        assert!(out.is_error);
+        assert_eq!(out.ret1 as i64, -libc::EFAULT as i64);
+        assert!(ume.sched_event.is_none());
+        let out = wait(&mut ume, 8, 0);
+        assert_eq!(out.ret1 as i64, -libc::EAGAIN as i64);
+        let out = wait(&mut ume, 7, 0);
+        assert!(!out.is_error);
+        assert!(ume.sched_event.is_some());
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn ioctl_bad_pointers_are_efault() {
+        use crate::elf::UserModeRuntime;
+        use crate::linux_usermode::main::{u_ioctl, SyscallIn, SyscallType};
+        let mut ume = UserModeRuntime::default();
+        ume.is_little_endian = true;
+        ume.opts.check_pointers = true;
+        // nothing is mapped as far as the VMA list knows, so the ioctl never reaches the host
+        for req in [libc::TIOCSWINSZ, libc::TIOCSPGRP, libc::TIOCSPTLCK] {
+            let args = [0, req as u64, 0x10, 0, 0, 0, 0];
+            let out = u_ioctl(SyscallIn { syscall: SyscallType::Ioctl, args }, &mut ume);
+            assert!(out.is_error);
+            assert_eq!(out.ret1 as i64, -libc::EFAULT as i64);
+        }
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn msghdr32_round_trip() {
+        use crate::elf::UserModeRuntime;
+        use crate::linux_usermode::main::{u_recvmsg, u_sendmsg, SyscallIn, SyscallType};
+        // a 32-bit guest's pointers have to be host addresses below 4 GiB
+        let page = unsafe {
+            libc::mmap(0x2000_0000 as *mut libc::c_void, 4096, libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED_NOREPLACE, -1, 0)
+        };
+        assert_ne!(page, libc::MAP_FAILED);
+        let base = page as u64;
+        let put = |at: u64, words: &[u32]| {
+            for (i, w) in words.iter().enumerate() {
+                unsafe { ((base + at) as *mut u32).add(i).write(*w) };
+            }
+        };
+        let get = |at: u64| unsafe { ((base + at) as *const u32).read() };
+        let b = base as u32;
+        // sending msghdr at 0, its iovec at 0x100 and data at 0x200
+        put(0, &[0, 0, b + 0x100, 1, 0, 0, 0]);
+        put(0x100, &[b + 0x200, 5]);
+        unsafe { std::ptr::copy_nonoverlapping(b"hello".as_ptr(), (base + 0x200) as *mut u8, 5) };
+        // receiving msghdr at 0x40 with room for a name at 0x400, iovec at 0x140 and buffer at 0x300
+        put(0x40, &[b + 0x400, 16, b + 0x140, 1, 0, 0, 0xdead]);
+        put(0x140, &[b + 0x300, 16]);
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_DGRAM, 0, fds.as_mut_ptr()) }, 0);
+        let mut ume = UserModeRuntime::default();
+        ume.is_little_endian = cfg!(target_endian = "little");
+        ume.is_64 = false;
+        let args = [fds[0] as u64, base, 0, 0, 0, 0, 0];
+        let out = u_sendmsg(SyscallIn { syscall: SyscallType::Sendmsg, args }, &mut ume);
+        assert_eq!(out.ret1, 5);
+        let args = [fds[1] as u64, base + 0x40, 0, 0, 0, 0, 0];
+        let out = u_recvmsg(SyscallIn { syscall: SyscallType::Recvmsg, args }, &mut ume);
+        assert_eq!(out.ret1, 5);
+        assert_eq!(unsafe { std::slice::from_raw_parts((base + 0x300) as *const u8, 5) }, b"hello");
+        // the kernel's name length and flags are back in the guest's header, the rest is untouched
+        assert!(get(0x44) < 16);
+        assert_eq!(get(0x40 + 24), 0);
+        assert_eq!(get(0x40 + 8), b + 0x140);
+        // a header that isn't mapped never reaches the host with --check-pointers
+        ume.opts.check_pointers = true;
+        let args = [fds[1] as u64, 0x10, 0, 0, 0, 0, 0];
+        let out = u_recvmsg(SyscallIn { syscall: SyscallType::Recvmsg, args }, &mut ume);
+        assert_eq!(out.ret1 as i64, -libc::EFAULT as i64);
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+            libc::munmap(page, 4096);
+        }
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn shared_lock_taken_over_from_dead_holder() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+        use crate::linux_usermode::ids::SharedLock;
+        // a thread that has exited is as gone as a killed process
+        let dead = std::thread::spawn(|| unsafe { libc::gettid() }).join().unwrap() as u32;
+        let lock = SharedLock(AtomicU32::new(dead));
+        assert_eq!(lock.locked(|| 7), 7);
+        assert_eq!(lock.0.load(Ordering::SeqCst), 0);
+        assert_eq!(lock.locked(|| 8), 8);
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn fakeroot_chown_stat_round_trip() {
+        use std::ffi::CString;
+        use crate::linux_usermode::defs::plat2generic_stat;
+        use crate::linux_usermode::fakeroot::{FakeRoot, FakeStore, FakeTable, FileRef};
+        let path = std::env::temp_dir().join(format!("turbo-fakeroot-{}", std::process::id()));
+        std::fs::write(&path, b"").unwrap();
+        let cpath = CString::new(path.to_str().unwrap()).unwrap();
+        let stat = || {
+            let mut st: libc::stat = unsafe { std::mem::zeroed() };
+            assert_eq!(unsafe { libc::stat(cpath.as_ptr(), &mut st) }, 0);
+            plat2generic_stat(st)
+        };
+        let fr = FakeRoot::new(FakeStore::Memory, 0, 0, FakeTable::create().unwrap());
+        let f = FileRef::at(libc::AT_FDCWD, &cpath, 0);
+        // the host owner shows up as root
+        let mut st = stat();
+        fr.fix_stat(&f, &mut st);
+        assert_eq!((st.st_uid, st.st_gid), (0, 0));
+        fr.chown(&f, 123, u32::MAX).unwrap();
+        fr.chmod(&f, 0o4711).unwrap();
+        let mut st = stat();
+        fr.fix_stat(&f, &mut st);
+        assert_eq!((st.st_uid, st.st_gid), (123, 0));
+        assert_eq!(st.st_mode as u32, libc::S_IFREG | 0o4711);
+        // a forked child's chown is seen by the parent
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            let ok = fr.chown(&f, u32::MAX, 456).is_ok();
+            unsafe { libc::_exit(if ok { 0 } else { 1 }) };
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert_eq!(status, 0);
+        // and so is everything by the emulator a guest exec starts, which gets the table's fd
+        let after_exec = FakeRoot::new(FakeStore::Memory, 0, 0, FakeTable::open(fr.table_fd()).unwrap());
+        let mut st = stat();
+        after_exec.fix_stat(&f, &mut st);
+        assert_eq!((st.st_uid, st.st_gid), (123, 456));
+        std::fs::remove_file(&path).unwrap();
+    }
+    // what the hand-written tables had before they were generated from syscalls/
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn syscall_tables_keep_old_numbers() {
+        use crate::linux_usermode::main::SyscallType::*;
+        use crate::linux_usermode::syscall_table::{ARM64_SYSCALLS, RISCV32_SYSCALLS, RISCV64_SYSCALLS};
+        let riscv = [
+            (17, Getcwd), (18, LookupDcookie), (24, Dup3), (25, Fcntl), (29, Ioctl), (32, Flock),
+            (33, Mknodat), (34, Mkdirat), (35, Unlinkat), (46, Ftruncate), (48, Faccessat),
+            (49, Chdir), (50, Fchdir), (52, Fchmod), (53, Fchmodat), (54, Fchownat), (55, Fchown),
+            (56, Openat), (57, Close), (59, Pipe2), (61, Getdents64), (62, Lseek), (63, Read),
+            (64, Write), (65, Readv), (66, Writev), (71, Sendfile), (73, Ppoll), (78, Readlinkat),
+            (79, Fstatat), (80, Fstat), (88, Utimensat), (90, Capget), (91, Capset), (93, Exit),
+            (94, ExitGroup), (96, SetTidAddr), (98, Futex), (99, SetRobustList), (102, Getitimer),
+            (103, Setitimer), (112, ClockSetTime), (113, ClockGetTime), (114, Getres),
+            (115, ClockNanosleep), (123, Getaffinity), (129, Kill), (134, Sigaction),
+            (135, Sigprocmask), (140, Setpriority), (141, Getpriority), (144, Setgid),
+            (146, Setuid), (153, Times), (154, Setpgid), (155, Getpgid), (156, Getsid),
+            (157, Setsid), (160, Uname), (165, Getrusage), (167, Prctl), (172, Getpid),
+            (173, Getppid), (174, Getuid), (175, Geteuid), (176, Getgid), (178, Gettid),
+            (179, Sysinfo), (198, Socket), (199, Socketpair), (200, Bind), (201, Listen),
+            (203, Connect), (206, Sendto), (207, Recvfrom), (214, Brk), (215, Munmap), (220, Clone),
+            (221, Execve), (222, Mmap), (223, Fadvise64), (226, Mprotect), (233, Madvise),
+            (260, Wait4), (261, Prlimit64), (278, Getrandom), (291, Statx)
+        ];
+        for (nr, sc) in riscv {
+            assert_eq!(RISCV64_SYSCALLS.translate(nr), Some(sc), "riscv64 {}", nr);
+            // these numbers are the 32 bit variants there
+            let rv32 = match sc {
+                Fcntl => Fcntl64,
+                Lseek => Llseek,
+                Mmap => Mmap2,
+                sc => sc,
+            };
+            assert_eq!(RISCV32_SYSCALLS.translate(nr), Some(rv32), "riscv32 {}", nr);
+        }
+        let arm64 = [
+            (25, Fcntl), (29, Ioctl), (32, Flock), (33, Mknodat), (46, Ftruncate), (48, Faccessat),
+            (52, Fchmod), (55, Fchown), (56, Openat), (57, Close), (59, Pipe2), (63, Read),
+            (64, Write), (66, Writev), (73, Ppoll), (78, Readlinkat), (79, Fstatat), (80, Fstat),
+            (88, Utimensat), (94, ExitGroup), (96, SetTidAddr), (98, Futex), (99, SetRobustList),
+            (113, ClockGetTime), (123, Getaffinity), (132, Sigaltstack), (134, Sigaction),
+            (135, Sigprocmask), (153, Times), (154, Setpgid), (155, Getpgid), (156, Getsid),
+            (157, Setsid), (160, Uname), (163, Getrlimit), (165, Getrusage), (172, Getpid),
+            (175, Geteuid), (178, Gettid), (179, Sysinfo), (214, Brk), (215, Munmap), (221, Execve),
+            (222, Mmap), (223, Fadvise64), (226, Mprotect), (261, Prlimit64), (278, Getrandom),
+            (293, Rseq)
+        ];
+        for (nr, sc) in arm64 {
+            assert_eq!(ARM64_SYSCALLS.translate(nr), Some(sc), "arm64 {}", nr);
+        }
+        // and no others, see syscalls/arm64.txt
+        let mapped = (0..ARM64_SYSCALLS.calls.len() as u64).filter(|n| ARM64_SYSCALLS.translate(*n).is_some());
+        assert_eq!(mapped.count(), arm64.len());
+        assert_eq!(RISCV32_SYSCALLS.translate(422), Some(Futex64));
+        assert_eq!(RISCV32_SYSCALLS.name(403), Some("clock_gettime64"));
+    }
+    // runs the filter like the kernel would, on (arch, nr, arg0)
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn sandbox_filter_verdicts() {
+        use crate::linux_usermode::sandbox::{build_filter, SandboxPolicy};
+        fn run(prog: &[libc::sock_filter], arch: u32, nr: u32, arg0: u32) -> u32 {
+            let (mut acc, mut pc) = (0u32, 0usize);
+            loop {
+                let ins = prog[pc];
+                pc += 1;
+                match ins.code {
+                    0x20 => acc = match ins.k { 0 => nr, 4 => arch, 16 | 20 => arg0, k => panic!("load of {}", k) },
+                    0x15 => pc += if acc == ins.k { ins.jt } else { ins.jf } as usize,
+                    0x35 => pc += if acc >= ins.k { ins.jt } else { ins.jf } as usize,
+                    0x06 => return ins.k,
+                    c => panic!("unexpected opcode {:#x}", c),
+                }
+            }
+        }
+        const ARCH: u32 = 0xc000_003e;
+        const ALLOW: u32 = 0x7fff_0000;
+        const KILL: u32 = 0x8000_0000;
+        let eperm = 0x0005_0000 | libc::EPERM as u32;
+        let eacces = 0x0005_0000 | libc::EACCES as u32;
+        let open = build_filter(SandboxPolicy::default(), ARCH);
+        assert_eq!(run(&open, ARCH, libc::SYS_read as u32, 0), ALLOW);
+        assert_eq!(run(&open, ARCH, libc::SYS_ptrace as u32, 0), eperm);
+        assert_eq!(run(&open, ARCH, libc::SYS_mount as u32, 0), eperm);
+        assert_eq!(run(&open, ARCH, libc::SYS_io_uring_setup as u32, 0), eperm);
+        assert_eq!(run(&open, ARCH, libc::SYS_socket as u32, libc::AF_INET as u32), ALLOW);
+        // another abi's numbers mean something else
+        assert_eq!(run(&open, 0x4000_0003, libc::SYS_read as u32, 0), KILL);
+        let no_net = build_filter(SandboxPolicy { deny_net: true }, ARCH);
+        assert_eq!(run(&no_net, ARCH, libc::SYS_socket as u32, libc::AF_INET as u32), eacces);
+        assert_eq!(run(&no_net, ARCH, libc::SYS_socket as u32, libc::AF_INET6 as u32), eacces);
+        assert_eq!(run(&no_net, ARCH, libc::SYS_socket as u32, libc::AF_UNIX as u32), ALLOW);
+        assert_eq!(run(&no_net, ARCH, libc::SYS_ptrace as u32, 0), eperm);
+        assert_eq!(run(&no_net, ARCH, libc::SYS_write as u32, libc::AF_INET as u32), ALLOW);
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn initial_stack_layout() {
+        use crate::elf::{AuxType, Auxv, UserModeRuntime};
+        use crate::linux_usermode::main::UsermodeCpu;
+        use crate::linux_usermode::stack::{build_initial_stack, StackError};
+        let stack: &'static mut [u64] = Box::leak(vec![0u64; 1024].into_boxed_slice());
+        let lo = stack.as_ptr() as u64;
+        let hi = lo + 8 * 1024;
+        let mut ume = UserModeRuntime::default();
+        ume.is_64 = true;
+        ume.is_little_endian = true;
+        {
+            let mut ms = ume.memstate.lock();
+            ms.stack_base = hi;
+            ms.stack_size = hi - lo;
+        }
+        ume.memusage.map_loader(lo, hi - lo, true);
+        let mut cpu = RiscvInt::init_usermode(Xlen::X64, ume);
+        cpu.set_stack_reg(hi - 24);
+        let args = vec!["prog".to_string(), "-v".to_string()];
+        let envp = vec!["HOME=/".to_string()];
+        let auxv = [Auxv { typ: AuxType::PageSz, value: 4096 }, Auxv { typ: AuxType::Null, value: 0 }];
+        build_initial_stack(&mut cpu, &args, &envp, &auxv).unwrap();
+        let sp = cpu.get_stack_reg();
+        assert_eq!(sp % 16, 0);
+        let word = |i: u64| unsafe { *((sp + 8 * i) as *const u64) };
+        let cstr = |p: u64| unsafe { std::ffi::CStr::from_ptr(p as *const libc::c_char) }.to_str().unwrap().to_string();
+        assert_eq!(word(0), 2);
+        assert_eq!(cstr(word(1)), "prog");
+        assert_eq!(cstr(word(2)), "-v");
+        assert_eq!(word(3), 0);
+        assert_eq!(cstr(word(4)), "HOME=/");
+        assert_eq!(word(5), 0);
+        assert_eq!((word(6), word(7)), (AuxType::PageSz as u64, 4096));
+        assert_eq!((word(8), word(9)), (AuxType::Null as u64, 0));
+        // the strings sit above the vectors, inside the stack
+        assert!(word(1) > sp + 80 && word(1) < hi);
+        // more than the stack holds is an error, not a panic
+        cpu.set_stack_reg(hi - 24);
+        let huge = vec!["x".repeat(8192)];
+        assert!(matches!(build_initial_stack(&mut cpu, &huge, &envp, &auxv), Err(StackError::TooBig(_))));
+        // and so is a stack that isn't mapped
+        cpu.user_struct.memusage.unmap(lo, hi - lo);
+        cpu.set_stack_reg(hi - 24);
+        assert!(matches!(cpu.push_stack_natural(1), Err(StackError::Fault(_))));
+        assert_eq!(cpu.get_stack_reg(), hi - 24);
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn signal_frame_placement() {
+        use crate::linux_usermode::signals::SigInfo;
+        use crate::linux_usermode::stack::signal_frame_addr;
+        const GUEST_SA_ONSTACK: u64 = 0x0800_0000;
+        let mut si = SigInfo::new();
+        si.cnsts.host_to_guest_flags.insert(libc::SA_ONSTACK, GUEST_SA_ONSTACK as i32);
+        si.actions.lock().unwrap()[10].flags = GUEST_SA_ONSTACK;
+        // no alternate stack yet: below sp, 16 byte aligned
+        assert_eq!(signal_frame_addr(0x7fff_1234, 0x100, 10, &si), Some(0x7fff_1130));
+        si.ss_sp = 0x10000;
+        si.ss_size = 0x2000;
+        // SA_ONSTACK moves it to the top of the alternate stack, other signals stay put
+        assert_eq!(signal_frame_addr(0x7fff_1234, 0x100, 10, &si), Some(0x11f00));
+        assert_eq!(signal_frame_addr(0x7fff_1234, 0x100, 11, &si), Some(0x7fff_1130));
+        // already on it (a nested signal): below sp there
+        assert_eq!(signal_frame_addr(0x11000, 0x100, 10, &si), Some(0x10f00));
+        // running off its end, or off the bottom of the address space
+        assert_eq!(signal_frame_addr(0x10010, 0x100, 10, &si), None);
+        assert_eq!(signal_frame_addr(0x80, 0x100, 11, &si), None);
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn stdin_read_restarts_with_sa_restart() {
+        use std::sync::Arc;
+        use crate::elf::UserModeRuntime;
+        use crate::linux_usermode::console::Console;
+        use crate::linux_usermode::main::ERESTARTSYS;
+        const GUEST_SA_RESTART: u64 = 0x1000_0000;
+        // stdin has to be a pipe nobody writes to, so in a child of our own
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            let mut fds = [0; 2];
+            unsafe {
+                libc::pipe(fds.as_mut_ptr());
+                libc::dup2(fds[0], 0);
+            }
+            let mut ume = UserModeRuntime::default();
+            ume.is_64 = true;
+            ume.console = Arc::new(Console::default());
+            let sigs = ume.signals.clone();
+            {
+                let mut info = sigs.info.lock().unwrap();
+                info.cnsts.host_to_guest_flags.insert(libc::SA_RESTART, GUEST_SA_RESTART as i32);
+                let mut acts = info.actions.lock().unwrap();
+                acts[10].handler_func = 0x1000;
+                acts[14].handler_func = 0x1000;
+                acts[14].flags = GUEST_SA_RESTART;
+            }
+            let pending = |sig: usize| {
+                sigs.info.lock().unwrap().use_idx = Some(sig);
+                sigs.pending.store(true, std::sync::atomic::Ordering::SeqCst);
+            };
+            let mut fails = 0;
+            pending(10);
+            if ume.console.wait_stdin_readable(0, &sigs) != Err(ERESTARTSYS) {
+                fails |= 1;
+            }
+            if sigs.restarts_syscall() {
+                fails |= 2;
+            }
+            let mut cpu = RiscvInt::init_usermode(Xlen::X64, ume);
+            let mut buf = [0u8; 4];
+            let read = |cpu: &mut RiscvInt| {
+                cpu.regs[10] = 0;
+                cpu.regs[11] = buf.as_mut_ptr() as u64;
+                cpu.regs[12] = 4;
+                cpu.regs[17] = 63;
+                cpu.trap_pc = 0x1000;
+                cpu.pc = 0x1004;
+                cpu.handle_syscall();
+            };
+            // no SA_RESTART: EINTR, and on to the next instruction
+            read(&mut cpu);
+            if cpu.regs[10] != -libc::EINTR as i64 as u64 || cpu.pc != 0x1004 {
+                fails |= 4;
+            }
+            // SA_RESTART: back to the ecall, with a0 as it was
+            pending(14);
+            read(&mut cpu);
+            if cpu.regs[10] != 0 || cpu.pc != 0x1000 {
+                fails |= 8;
+            }
+            // an ignored signal restarts it too
+            cpu.user_struct.signals.info.lock().unwrap().actions.lock().unwrap()[10].handler_func = libc::SIG_IGN as u64;
+            pending(10);
+            if !cpu.user_struct.signals.restarts_syscall() {
+                fails |= 16;
+            }
+            // and with input there, nothing to wait for
+            cpu.user_struct.signals.pending.store(false, std::sync::atomic::Ordering::SeqCst);
+            unsafe { libc::write(fds[1], b"x".as_ptr() as *const libc::c_void, 1) };
+            read(&mut cpu);
+            if cpu.regs[10] != 1 || buf[0] != b'x' {
+                fails |= 32;
+            }
+            unsafe { libc::_exit(fails) };
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn console_spec_parse() {
+        use std::path::PathBuf;
+        use crate::linux_usermode::conrelay::{ConsoleBackend, ConsoleSpec};
+        let spec = |s: &str| ConsoleSpec::parse(s);
+        assert_eq!(spec("stdio"), Ok(ConsoleSpec { backend: ConsoleBackend::Stdio, log: None }));
+        assert_eq!(spec("unix:/tmp/con.sock,log=/tmp/con.log"), Ok(ConsoleSpec {
+            backend: ConsoleBackend::Unix(PathBuf::from("/tmp/con.sock")),
+            log: Some(PathBuf::from("/tmp/con.log")),
+        }));
+        // a bare port is on localhost
+        assert_eq!(spec("tcp:4444").unwrap().backend, ConsoleBackend::Tcp("127.0.0.1:4444".to_string()));
+        assert_eq!(spec("tcp:0.0.0.0:4444").unwrap().backend, ConsoleBackend::Tcp("0.0.0.0:4444".to_string()));
+        assert_eq!(spec("tcp:[::1]:4444").unwrap().backend, ConsoleBackend::Tcp("[::1]:4444".to_string()));
+        for bad in ["", "unix:", "tcp:", "tcp:99999", "tcp:host:port", "serial:0", "stdio,log=", "stdio,mux=1", "stdio:x"] {
+            assert!(spec(bad).is_err(), "{}", bad);
+        }
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn console_backlog_drops_oldest() {
+        use crate::linux_usermode::conrelay::{Backlog, MAX_BACKLOG};
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) }, 0);
+        let mut b = Backlog::default();
+        // a client that reads nothing never blocks us, it just misses the oldest output
+        for i in 0..(4 * MAX_BACKLOG / 4096) {
+            b.push(&[i as u8; 4096]);
+            assert!(b.flush(fds[0]));
+        }
+        assert!(b.len() <= MAX_BACKLOG);
+        assert!(b.len() > 0);
+        // once it reads, what's left gets through, newest last
+        let mut got = vec![];
+        let mut buf = [0u8; 65536];
+        while b.len() > 0 {
+            let n = unsafe { libc::read(fds[1], buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            assert!(n > 0);
+            got.extend_from_slice(&buf[..n as usize]);
+            assert!(b.flush(fds[0]));
+        }
+        let last = (4 * MAX_BACKLOG / 4096 - 1) as u8;
+        assert_eq!(*got.last().unwrap(), last);
+        // and a client that's gone is noticed
+        unsafe { libc::close(fds[1]) };
+        b.push(b"bye");
+        assert!(!b.flush(fds[0]));
+        unsafe { libc::close(fds[0]) };
+    }
+    #[test]
+    fn stats_registry_totals() {
+        use std::sync::Arc;
+        use crate::common::hart_stats::{Counter, StatsRegistry, COUNTERS};
+        let reg = StatsRegistry::new();
+        let a = reg.register();
+        let b = reg.register();
+        a.add(Counter::Loads, 3);
+        a.add(Counter::LoadBytes, 24);
+        b.bump(Counter::Stores);
+        b.add(Counter::Loads, 2);
+        let t = reg.totals();
+        assert_eq!(t[Counter::Loads as usize], 5);
+        assert_eq!(t[Counter::LoadBytes as usize], 24);
+        assert_eq!(t[Counter::Stores as usize], 1);
+        // a hart that exits keeps its share of the totals
+        reg.retire(&b);
+        let c = reg.register();
+        c.bump(Counter::Loads);
+        let t = reg.totals();
+        assert_eq!(t[Counter::Loads as usize], 6);
+        assert_eq!(t[Counter::Stores as usize], 1);
+        // the report lists just the live harts, a and c
+        assert_eq!(reg.report().lines().count(), COUNTERS.len());
+        assert!(reg.report().starts_with("loads: 6 (live harts: 3 1)"));
+        // a forked child only keeps the hart that forked, from zero
+        reg.forked_child(&a);
+        assert!(reg.totals().iter().all(|&n| n == 0));
+        a.bump(Counter::Stores);
+        c.bump(Counter::Stores);
+        let t = reg.totals();
+        assert_eq!(t[Counter::Stores as usize], 1);
+        assert_eq!(t[Counter::Loads as usize], 0);
+        assert_eq!(Arc::strong_count(&c), 1);
+    }
+    #[test]
+    fn spin_detector_threshold_and_reset() {
+        use crate::common::spin::{state_digest, SpinDetector, SpinPolicy};
+        let d1 = state_digest(&[1, 2, 3], &[]);
+        let d2 = state_digest(&[1, 2, 4], &[]);
+        assert_ne!(d1, d2);
+        let mut sd = SpinDetector::new(SpinPolicy::parse("warn", 3).unwrap());
+        // the first jump only starts tracking the head
+        assert_eq!(sd.backward_jump(0x1000, d1), None);
+        assert_eq!(sd.backward_jump(0x1000, d1), None);
+        assert_eq!(sd.backward_jump(0x1000, d1), None);
+        let hit = sd.backward_jump(0x1000, d1).unwrap();
+        assert_eq!((hit.head, hit.iters, hit.report, hit.sleep), (0x1000, 3, true, None));
+        // warning only: reported once, then quiet
+        assert_eq!(sd.backward_jump(0x1000, d1), None);
+        // a register changing is progress and starts the count over
+        assert_eq!(sd.backward_jump(0x1000, d2), None);
+        assert_eq!(sd.backward_jump(0x1000, d2), None);
+        assert_eq!(sd.backward_jump(0x1000, d2), None);
+        // and the same loop isn't reported again
+        assert_eq!(sd.backward_jump(0x1000, d2), None);
+
+        let mut sd = SpinDetector::new(SpinPolicy::parse("backoff", 2).unwrap());
+        sd.backward_jump(0x2000, d1);
+        assert_eq!(sd.backward_jump(0x2000, d1), None);
+        let a = sd.backward_jump(0x2000, d1).unwrap();
+        assert!(a.report);
+        let b = sd.backward_jump(0x2000, d1).unwrap();
+        assert!(!b.report);
+        // backing off doubles, up to a cap, and progress brings it back down
+        assert!(b.sleep.unwrap() > a.sleep.unwrap());
+        let mut last = b.sleep.unwrap();
+        for _ in 0..20 {
+            last = sd.backward_jump(0x2000, d1).unwrap().sleep.unwrap();
+        }
+        assert_eq!(sd.backward_jump(0x2000, d1).unwrap().sleep.unwrap(), last);
+        sd.backward_jump(0x2000, d2);
+        sd.backward_jump(0x2000, d2);
+        assert_eq!(sd.backward_jump(0x2000, d2).unwrap().sleep, a.sleep);
+        // a loop that calls a function still gets noticed, its jumps don't evict the head
+        let mut sd = SpinDetector::new(SpinPolicy::parse("warn", 2).unwrap());
+        let mut hit = None;
+        for _ in 0..4 {
+            hit = hit.or(sd.backward_jump(0x3000, d1));
+            sd.backward_jump(0x5000, d1);
+        }
+        assert_eq!(hit.unwrap().head, 0x3000);
+        assert_eq!(SpinPolicy::parse("sleep", 1), None);
+        assert_eq!(SpinPolicy::parse("warn", 0).unwrap().threshold, 1);
+    }
+    #[test]
+    fn top_trap_sites_merge_and_order() {
+        use crate::common::hart_stats::{StatsRegistry, TrapCause, TrapSite};
+        let reg = StatsRegistry::new();
+        assert!(reg.top_trap_sites(10).is_empty());
+        assert_eq!(reg.trap_sites_report(&|pc| format!("{:#x}", pc)), "no faults\n");
+        let a = reg.register();
+        let b = reg.register();
+        for _ in 0..3 {
+            a.count_trap(0x100, TrapCause::PageFault);
+        }
+        a.count_trap(0x200, TrapCause::Misaligned);
+        // the same site on another hart adds up with the first
+        b.count_trap(0x100, TrapCause::PageFault);
+        b.count_trap(0x200, TrapCause::Misaligned);
+        // same pc, different cause, is a site of its own
+        b.count_trap(0x100, TrapCause::AccessFault);
+        // and ties go to the lower pc
+        b.count_trap(0x80, TrapCause::IllegalInstruction);
+        b.count_trap(0x80, TrapCause::IllegalInstruction);
+        let site = |pc, cause, count| TrapSite { pc, cause, count };
+        assert_eq!(reg.top_trap_sites(10), vec![
+            site(0x100, TrapCause::PageFault, 4),
+            site(0x80, TrapCause::IllegalInstruction, 2),
+            site(0x200, TrapCause::Misaligned, 2),
+            site(0x100, TrapCause::AccessFault, 1),
+        ]);
+        assert_eq!(reg.top_trap_sites(1), vec![site(0x100, TrapCause::PageFault, 4)]);
+        // a hart that's gone still has its faults counted
+        reg.retire(&b);
+        a.count_trap(0x200, TrapCause::Misaligned);
+        let top = reg.top_trap_sites(2);
+        assert_eq!(top, vec![site(0x100, TrapCause::PageFault, 4), site(0x200, TrapCause::Misaligned, 3)]);
+        let report = reg.trap_sites_report(&|pc| format!("{:#x}", pc));
+        assert_eq!(report.lines().nth(1).unwrap().split_whitespace().collect::<Vec<_>>(), ["4", "page_fault", "0x100"]);
+        reg.forked_child(&a);
+        assert!(reg.top_trap_sites(10).is_empty());
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn loader_error_statuses() {
+        use std::path::PathBuf;
+        use crate::elf::{init_user_mode_emulation, Error, UserModeOptions};
+        let run = |path: &str| {
+            init_user_mode_emulation(path.to_string(), vec![], String::new(), UserModeOptions::default())
+                .err().map(|e| e.exit_status())
+        };
+        // nothing there is the shell's 127, something we can't run is 126
+        assert_eq!(run("/nonexistent/turbo-emulator-test"), Some(127));
+        let dir = std::env::temp_dir().join(format!("loader-status-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let junk = dir.join("junk");
+        std::fs::write(&junk, b"#!/bin/sh\nexit 0\n").unwrap();
+        assert_eq!(run(junk.to_str().unwrap()), Some(126));
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(Error::WrongMachine("rv32 interpreter".to_string()).exit_status(), 126);
+        assert_eq!(Error::NotFound("/lib/ld-musl-riscv64.so.1".to_string()).exit_status(), 127);
+        assert_eq!(Error::Console("bad".to_string()).exit_status(), 1);
+        assert_eq!(Error::Io(PathBuf::from("/x"), std::io::Error::from_raw_os_error(libc::EACCES)).exit_status(), 126);
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn fcntl_lock_bad_pointer() {
+        use std::os::unix::io::AsRawFd;
+        use crate::elf::UserModeRuntime;
+        use crate::linux_usermode::locks::{guest_flock_len, GUEST_F_GETLK, GUEST_F_SETLK};
+        use crate::linux_usermode::main::{u_fcntl, SyscallIn, SyscallType};
+        let path = std::env::temp_dir().join(format!("flock-efault-{}", std::process::id()));
+        let file = std::fs::File::create(&path).unwrap();
+        let fl: &'static mut [u64; 4] = Box::leak(Box::new([0; 4]));
+        let addr = fl.as_ptr() as u64;
+        let mut ume = UserModeRuntime::default();
+        ume.is_64 = true;
+        ume.is_little_endian = true;
+        ume.opts.check_pointers = true;
+        let fcntl = |ume: &mut UserModeRuntime, cmd: i32| {
+            u_fcntl(SyscallIn { syscall: SyscallType::Fcntl, args: [file.as_raw_fd() as u64, cmd as u64, addr, 0, 0, 0, 0] }, ume)
+        };
+        // not mapped for the guest, so EFAULT and not a crash
+        let out = fcntl(&mut ume, GUEST_F_SETLK);
+        assert!(out.is_error);
+        assert_eq!(out.ret1, -libc::EFAULT as i64 as u64);
+        // F_GETLK writes the struct back, read-only isn't enough
+        ume.memusage.map_loader(addr, guest_flock_len(true), false);
+        let out = fcntl(&mut ume, GUEST_F_GETLK);
+        assert_eq!(out.ret1, -libc::EFAULT as i64 as u64);
+        ume.memusage.unmap(addr, guest_flock_len(true));
+        ume.memusage.map_loader(addr, guest_flock_len(true), true);
+        // l_type F_WRLCK, the whole file
+        fl[0] = libc::F_WRLCK as u64;
+        let out = fcntl(&mut ume, GUEST_F_GETLK);
+        assert!(!out.is_error);
+        assert_eq!(fl[0] & 0xffff, libc::F_UNLCK as u64);
+        drop(file);
+        let _ = std::fs::remove_file(&path);
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn guest_strv_read() {
+        use crate::common::memory::MemEndian;
+        use crate::linux_usermode::exec::read_guest_strv;
+        let a = b"sh\0";
+        let b = b"-c\0";
+        let v64 = [a.as_ptr() as u64, b.as_ptr() as u64, 0];
+        let got = read_guest_strv(v64.as_ptr() as u64, true, MemEndian::Little).unwrap();
+        assert_eq!(got.iter().map(|s| s.to_str().unwrap()).collect::<Vec<_>>(), ["sh", "-c"]);
+        assert_eq!(read_guest_strv(0, true, MemEndian::Little), Ok(vec![]));
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn poison_abort_exits_the_guest() {
+        use std::sync::Arc;
+        use sync::Mutex;
+        use crate::common::poison::{PoisonMap, PoisonPolicy};
+        use crate::elf::UserModeRuntime;
+        use crate::linux_usermode::main::catch_group_exit;
+        use crate::riscv::ume::signals::riscv64_init_sigconstant;
+        let data: &'static mut [u64; 2] = Box::leak(Box::new([0; 2]));
+        let base = data.as_mut_ptr() as u64;
+        // ld a1, 8(a0), and an illegal instruction after it that mustn't be reached
+        let code: &'static [u32] = Box::leak(vec![8 << 20 | 10 << 15 | 3 << 12 | 11 << 7 | 0x03, 0].into_boxed_slice());
+        let pm = Arc::new(Mutex::new(PoisonMap::new(PoisonPolicy::Abort)));
+        pm.lock().poison(base + 8, 8, "freed");
+        let mut ume = UserModeRuntime::default();
+        ume.sigcnst = Arc::new(Mutex::new(riscv64_init_sigconstant()));
+        let mut cpu = RiscvInt::init_usermode(Xlen::X64, ume);
+        cpu.set_poison_map(Some(pm));
+        cpu.pc = code.as_ptr() as u64;
+        cpu.regs[10] = base;
+        let ge = cpu.user_struct.group_exit.clone();
+        // the guest exits with 1 the way exit_group() does, the test process is still here
+        assert_eq!(catch_group_exit(&ge, || cpu.run()), Some(1));
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn shadow_stack_calls_and_returns() {
+        use std::sync::Arc;
+        use sync::Mutex;
+        use crate::common::shadow_stack::{ShadowFrame, ShadowStack};
+        use crate::elf::UserModeRuntime;
+        use crate::linux_usermode::main::catch_group_exit;
+        use crate::riscv::ume::signals::riscv64_init_sigconstant;
+        let frame = |ret_addr, sp| ShadowFrame { call_pc: ret_addr - 4, target: 0, ret_addr, sp };
+        let mut ss = ShadowStack::new(3);
+        ss.push(frame(0x104, 0x8000));
+        ss.push(frame(0x204, 0x7f00));
+        ss.push(frame(0x304, 0x7e00));
+        // returning past a frame drops it too, that was a tail call
+        assert_eq!(ss.ret(0x204).map(|f| f.ret_addr), Some(0x204));
+        assert_eq!(ss.backtrace(8), [0x104]);
+        assert_eq!(ss.ret(0x999), None);
+        assert_eq!((ss.depth(), ss.mismatches), (1, 1));
+        // deeper than the limit loses the outermost frames
+        for i in 0..4 {
+            ss.push(frame(0x400 + 0x10 * i, 0x7000 - 0x100 * i));
+        }
+        assert_eq!((ss.depth(), ss.dropped), (3, 2));
+        assert_eq!(ss.backtrace(2), [0x430, 0x420]);
+        // a call with a higher sp than the frames on top means those were longjmp()ed over
+        ss.push(frame(0x504, 0x7f80));
+        assert_eq!(ss.backtrace(8), [0x504]);
+
+        let jal = |rd: u32, off: i32| {
+            let o = off as u32;
+            (o >> 20 & 1) << 31 | (o >> 1 & 0x3ff) << 21 | (o >> 11 & 1) << 20 | (o >> 12 & 0xff) << 12 | rd << 7 | 0x6f
+        };
+        let addi = |rd: u32, rs1: u32, imm: u32| imm << 20 | rs1 << 15 | rd << 7 | 0x13;
+        let (nop, ret, ecall) = (0x13, 0x00008067, 0x73);
+        let code: &'static [u32] = Box::leak(vec![
+            jal(1, 16), // 0: call f
+            jal(1, 20), // 4: call g
+            nop, nop,
+            ret, // 16: f
+            nop,
+            jal(1, 8), // 24: g calls h
+            nop,
+            addi(10, 0, 0), addi(17, 0, 94), ecall, // 32: h does exit_group(0)
+        ].into_boxed_slice());
+        let base = code.as_ptr() as u64;
+        let mut ume = UserModeRuntime::default();
+        ume.sigcnst = Arc::new(Mutex::new(riscv64_init_sigconstant()));
+        ume.opts.shadow_stack = true;
+        let mut cpu = RiscvInt::init_usermode(Xlen::X64, ume);
+        cpu.pc = base;
+        let ge = cpu.user_struct.group_exit.clone();
+        assert_eq!(catch_group_exit(&ge, || cpu.run()), Some(0));
+        // f is gone, h was called from g which was called from the start
+        let ss = cpu.shadow_stack.as_ref().unwrap();
+        assert_eq!(ss.backtrace(8), [base + 28, base + 8]);
+        assert_eq!(ss.mismatches, 0);
+        assert_eq!(ss.frames().next().unwrap().target, base + 32);
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn wait_status_to_guest() {
+        use std::sync::Arc;
+        use sync::Mutex;
+        use crate::elf::UserModeRuntime;
+        use crate::linux_usermode::main::{host2guest_wait_status, u_wait4, SyscallIn, SyscallType};
+        use crate::linux_usermode::signals::sigchld_status;
+        use crate::riscv::ume::signals::riscv64_init_sigconstant;
+        // signal numbers get translated, the rest of the layout is the same everywhere
+        let mut cnsts = riscv64_init_sigconstant();
+        cnsts.host_to_guest_sigs[libc::SIGTERM as usize] = 20;
+        cnsts.host_to_guest_sigs[libc::SIGSTOP as usize] = 17;
+        assert_eq!(host2guest_wait_status(3 << 8, &cnsts), 3 << 8);
+        assert_eq!(host2guest_wait_status(libc::SIGTERM, &cnsts), 20);
+        assert_eq!(host2guest_wait_status(libc::SIGTERM | 0x80, &cnsts), 20 | 0x80); // core dumped
+        assert_eq!(host2guest_wait_status(libc::SIGSTOP << 8 | 0x7f, &cnsts), 17 << 8 | 0x7f);
+        assert_eq!(sigchld_status(&cnsts, libc::CLD_EXITED, libc::SIGTERM), libc::SIGTERM);
+        assert_eq!(sigchld_status(&cnsts, libc::CLD_KILLED, libc::SIGTERM), 20);
+        assert_eq!(sigchld_status(&cnsts, libc::CLD_STOPPED, libc::SIGSTOP), 17);
+
+        // and wait4() writes them where the guest asked
+        let mut ume = UserModeRuntime::default();
+        ume.is_little_endian = true;
+        ume.sigcnst = Arc::new(Mutex::new(cnsts));
+        let status: &'static mut i32 = Box::leak(Box::new(0));
+        let status_addr = status as *mut i32 as u64;
+        let wait = |ume: &mut UserModeRuntime, pid: i32, options: i32| {
+            let out = u_wait4(SyscallIn { syscall: SyscallType::Wait4, args: [pid as u64, status_addr, options as u64, 0, 0, 0, 0] }, ume);
+            assert_eq!(out.ret1, pid as u64);
+            unsafe { *(status_addr as *const i32) }
+        };
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            unsafe { libc::_exit(3) };
+        }
+        assert_eq!(wait(&mut ume, pid, 0), 3 << 8);
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            unsafe {
+                libc::raise(libc::SIGSTOP);
+                libc::raise(libc::SIGTERM);
+                libc::_exit(0);
+            }
+        }
+        assert_eq!(wait(&mut ume, pid, libc::WUNTRACED), 17 << 8 | 0x7f);
+        unsafe { libc::kill(pid, libc::SIGCONT) };
+        assert_eq!(wait(&mut ume, pid, 0), 20);
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn guest_cpu_time_in_rusage() {
+        use crate::armv8::interpreter::main::Arm64Cpu;
+        use crate::elf::UserModeRuntime;
+        use crate::linux_usermode::main::{publish_cpu_time, u_getrusage, SyscallIn, SyscallType, UsermodeCpu};
+        let getrusage = |ume: &mut UserModeRuntime, who: i32, addr: u64| {
+            u_getrusage(SyscallIn { syscall: SyscallType::Getrusage, args: [who as u64, addr, 0, 0, 0, 0, 0] }, ume)
+        };
+        // 2ms of guest time at 1000MHz, all of it user time
+        let mut ume = UserModeRuntime::default();
+        ume.is_little_endian = true;
+        ume.opts.guest_mhz = 1000;
+        ume.is_64 = true;
+        publish_cpu_time(&mut ume, 2_000_000);
+        let ru64: &'static mut [u64; 18] = Box::leak(Box::new([u64::MAX; 18]));
+        assert!(!getrusage(&mut ume, libc::RUSAGE_SELF, ru64.as_ptr() as u64).is_error);
+        assert_eq!(ru64[..4], [0, 2000, 0, 0]);
+        assert!(ru64[4] > 0 && ru64[4] != u64::MAX); // ru_maxrss is the host's
+        // a 32 bit guest's struct is all 32 bit longs, 72 bytes and not a byte more
+        ume.is_64 = false;
+        let ru32: &'static mut [u32; 20] = Box::leak(Box::new([u32::MAX; 20]));
+        assert!(!getrusage(&mut ume, libc::RUSAGE_THREAD, ru32.as_ptr() as u64).is_error);
+        assert_eq!(ru32[..4], [0, 2000, 0, 0]);
+        assert!(ru32[4] > 0 && ru32[4] != u32::MAX);
+        assert_eq!(ru32[18..], [u32::MAX, u32::MAX]);
+        assert_eq!(getrusage(&mut ume, libc::RUSAGE_SELF, 0).ret1, -libc::EFAULT as i64 as u64);
+
+        // arm64 counts its instructions too
+        let code: &'static [u32] = Box::leak(vec![0xd503201f; 3].into_boxed_slice()); // nop
+        let mut cpu = Arm64Cpu::init_usermode(UserModeRuntime::default());
+        cpu.pc = code.as_ptr() as u64;
+        cpu.run_for(3);
+        assert_eq!(cpu.retired_insns(), Some(3));
+    }
+    #[test]
+    fn pacer_sleeps_and_forgets_lag() {
+        use std::time::{Duration, Instant};
+        use crate::common::pacing::Pacer;
+        assert_eq!(Pacer::new(0).chunk(), 1000);
+        let mut p = Pacer::new(1);
+        assert_eq!(p.chunk(), 1000);
+        // 50ms worth of instructions done at once sleeps off the rest of the 50ms
+        let t = Instant::now();
+        p.account(50_000);
+        assert!(t.elapsed() >= Duration::from_millis(45));
+        // a long stop (a blocking syscall) isn't made up for by running flat out afterwards
+        std::thread::sleep(Duration::from_millis(400));
+        let t = Instant::now();
+        p.account(1000);
+        assert!(t.elapsed() < Duration::from_millis(20));
+        // lag was cut to 100ms, so 150ms of instructions still has to wait about 50ms
+        let t = Instant::now();
+        p.account(150_000);
+        let slept = t.elapsed();
+        assert!(slept >= Duration::from_millis(30) && slept < Duration::from_millis(140), "{:?}", slept);
+    }
+    #[cfg(feature = "linux-usermode")]
+    #[test]
+    fn custom_insn_and_csr_handlers() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use sync::Mutex;
+        use crate::elf::UserModeRuntime;
+        use crate::linux_usermode::main::catch_group_exit;
+        use crate::riscv::interpreter::custom::{CustomCsr, CustomExtensions, CustomInsn, CustomOpcode};
+        use crate::riscv::ume::signals::riscv64_init_sigconstant;
+        // custom-0 with funct3 0: rd = rs1 + 2 * rs2
+        struct Mac;
+        impl CustomInsn for Mac {
+            fn decodes(&self, insn: u32) -> bool {
+                insn >> 12 & 7 == 0
+            }
+            fn execute(&self, cpu: &mut RiscvInt, insn: u32) -> bool {
+                let (rd, rs1, rs2) = ((insn >> 7 & 31) as usize, (insn >> 15 & 31) as usize, (insn >> 20 & 31) as usize);
+                cpu.regs[rd] = cpu.regs[rs1].wrapping_add(cpu.regs[rs2].wrapping_mul(2));
+                true
+            }
+        }
+        // a user read/write CSR that reads back one more than was written
+        struct Scratch(AtomicU64);
+        impl CustomCsr for Scratch {
+            fn read(&self, _cpu: &mut RiscvInt, _csr: u16) -> Option<u64> {
+                Some(self.0.load(Ordering::Relaxed) + 1)
+            }
+            fn write(&self, _cpu: &mut RiscvInt, _csr: u16, val: u64) -> bool {
+                self.0.store(val, Ordering::Relaxed);
+                true
+            }
+        }
+        let scratch = Arc::new(Scratch(AtomicU64::new(0)));
+        let mut ext = CustomExtensions::new();
+        ext.add_insn(CustomOpcode::Custom0, Arc::new(Mac));
+        ext.add_insn(CustomOpcode::Custom3, Arc::new(Mac));
+        ext.add_csr_range(0x800..=0x80f, scratch.clone());
+        assert_eq!(ext.insn_opcodes(), [CustomOpcode::Custom0, CustomOpcode::Custom3]);
+        let mac = 11 << 20 | 10 << 15 | 12 << 7 | CustomOpcode::Custom0.major();
+        assert!(ext.decodes(mac));
+        // funct3 1 isn't Mac's, and custom-1 has nothing registered
+        assert!(!ext.decodes(mac | 1 << 12));
+        assert!(!ext.decodes(mac & !0x7f | CustomOpcode::Custom1.major()));
+        assert!(ext.csr_handler(0x80f).is_some() && ext.csr_handler(0x810).is_none());
+
+        let addi = |rd: u32, rs1: u32, imm: u32| imm << 20 | rs1 << 15 | rd << 7 | 0x13;
+        let code: &'static [u32] = Box::leak(vec![
+            mac, // a2 = a0 + 2 * a1
+            0x805 << 20 | 12 << 15 | 1 << 12 | 13 << 7 | 0x73, // csrrw a3, 0x805, a2
+            0x805 << 20 | 2 << 12 | 14 << 7 | 0x73, // csrr a4, 0x805
+            addi(17, 0, 94), 0x73, // exit_group(a0)
+        ].into_boxed_slice());
+        let mut ume = UserModeRuntime::default();
+        ume.sigcnst = Arc::new(Mutex::new(riscv64_init_sigconstant()));
+        ume.opts.riscv_custom = Some(Arc::new(ext));
+        let mut cpu = RiscvInt::init_usermode(Xlen::X64, ume);
+        cpu.pc = code.as_ptr() as u64;
+        cpu.regs[10] = 5;
+        cpu.regs[11] = 7;
+        let ge = cpu.user_struct.group_exit.clone();
+        assert_eq!(catch_group_exit(&ge, || cpu.run()), Some(5));
+        assert_eq!(cpu.regs[12], 19);
+        assert_eq!(cpu.regs[13], 1); // what was there before the write
+        assert_eq!(cpu.regs[14], 20);
+        assert_eq!(scratch.0.load(Ordering::Relaxed), 19);
+    }
 }
@@ -0,0 +1,257 @@
+// Which instruction and extension a raw instruction word is, found by running it through the
+// decoders with a DecodeTrait that only takes down the name. For ISA usage reports (what a binary
+// needs from the core it's going to run on), so names are the decoder's with dots for
+// underscores (which lumps some compressed ones together, c.li and c.addi16sp are c.addi), and
+// the extension is the one a core has to have.
+use crate::riscv::common::RiscvArgs;
+use crate::riscv::{decoder, decoder16};
+
+struct Namer {
+    is_64: bool,
+    name: Option<&'static str>,
+}
+impl Namer {
+    // false passes the word on to the next pattern, like the interpreter does for the other xlen
+    fn take(&mut self, name: &'static str) -> bool {
+        if RV128_ONLY.contains(&name) || (self.is_64 && RV32_ONLY.contains(&name))
+            || (!self.is_64 && RV64_ONLY.contains(&name)) {
+            return false;
+        }
+        self.name = Some(name);
+        true
+    }
+}
+macro_rules! namer {
+    ($tr:path; $($n:ident)*) => {
+        impl $tr for Namer {
+            $(fn $n(&mut self, _args: RiscvArgs) -> bool { self.take(stringify!($n)) })*
+        }
+    };
+}
+namer!(decoder::DecodeTrait;
+    ecall ebreak uret sret mret wfi sfence_vma sfence_vm lui auipc jal jalr beq bne blt bge bltu
+    bgeu lb lh lw lbu lhu sb sh sw addi slti sltiu xori ori andi slli srli srai add sub sll slt sltu
+    xor srl sra or and pause fence fence_i csrrw csrrs csrrc csrrwi csrrsi csrrci lwu ld sd addiw
+    slliw srliw sraiw addw subw sllw srlw sraw ldu lq sq addid sllid srlid sraid addd subd slld srld
+    srad mul mulh mulhsu mulhu div divu rem remu mulw divw divuw remw remuw muld divd divud remd
+    remud lr_w sc_w amoswap_w amoadd_w amoxor_w amoand_w amoor_w amomin_w amomax_w amominu_w
+    amomaxu_w lr_d sc_d amoswap_d amoadd_d amoxor_d amoand_d amoor_d amomin_d amomax_d amominu_d
+    amomaxu_d flw fsw fmadd_s fmsub_s fnmsub_s fnmadd_s fadd_s fsub_s fmul_s fdiv_s fsqrt_s fsgnj_s
+    fsgnjn_s fsgnjx_s fmin_s fmax_s fcvt_w_s fcvt_wu_s fmv_x_w feq_s flt_s fle_s fclass_s fcvt_s_w
+    fcvt_s_wu fmv_w_x fcvt_l_s fcvt_lu_s fcvt_s_l fcvt_s_lu fld fsd fmadd_d fmsub_d fnmsub_d
+    fnmadd_d fadd_d fsub_d fmul_d fdiv_d fsqrt_d fsgnj_d fsgnjn_d fsgnjx_d fmin_d fmax_d fcvt_s_d
+    fcvt_d_s feq_d flt_d fle_d fclass_d fcvt_w_d fcvt_wu_d fcvt_d_w fcvt_d_wu fcvt_l_d fcvt_lu_d
+    fmv_x_d fcvt_d_l fcvt_d_lu fmv_d_x hlv_b hlv_bu hlv_h hlv_hu hlvx_hu hlv_w hlvx_wu hsv_b hsv_h
+    hsv_w hfence_gvma hfence_vvma hlv_wu hlv_d hsv_d vle8_v vle16_v vle32_v vle64_v vse8_v vse16_v
+    vse32_v vse64_v vlm_v vsm_v vlse8_v vlse16_v vlse32_v vlse64_v vsse8_v vsse16_v vsse32_v
+    vsse64_v vlxei8_v vlxei16_v vlxei32_v vlxei64_v vsxei8_v vsxei16_v vsxei32_v vsxei64_v vle8ff_v
+    vle16ff_v vle32ff_v vle64ff_v vl1re8_v vl1re16_v vl1re32_v vl1re64_v vl2re8_v vl2re16_v
+    vl2re32_v vl2re64_v vl4re8_v vl4re16_v vl4re32_v vl4re64_v vl8re8_v vl8re16_v vl8re32_v
+    vl8re64_v vs1r_v vs2r_v vs4r_v vs8r_v vadd_vv vadd_vx vadd_vi vsub_vv vsub_vx vrsub_vx vrsub_vi
+    vwaddu_vv vwaddu_vx vwadd_vv vwadd_vx vwsubu_vv vwsubu_vx vwsub_vv vwsub_vx vwaddu_wv vwaddu_wx
+    vwadd_wv vwadd_wx vwsubu_wv vwsubu_wx vwsub_wv vwsub_wx vadc_vvm vadc_vxm vadc_vim vmadc_vvm
+    vmadc_vxm vmadc_vim vsbc_vvm vsbc_vxm vmsbc_vvm vmsbc_vxm vand_vv vand_vx vand_vi vor_vv vor_vx
+    vor_vi vxor_vv vxor_vx vxor_vi vsll_vv vsll_vx vsll_vi vsrl_vv vsrl_vx vsrl_vi vsra_vv vsra_vx
+    vsra_vi vnsrl_wv vnsrl_wx vnsrl_wi vnsra_wv vnsra_wx vnsra_wi vmseq_vv vmseq_vx vmseq_vi
+    vmsne_vv vmsne_vx vmsne_vi vmsltu_vv vmsltu_vx vmslt_vv vmslt_vx vmsleu_vv vmsleu_vx vmsleu_vi
+    vmsle_vv vmsle_vx vmsle_vi vmsgtu_vx vmsgtu_vi vmsgt_vx vmsgt_vi vminu_vv vminu_vx vmin_vv
+    vmin_vx vmaxu_vv vmaxu_vx vmax_vv vmax_vx vmul_vv vmul_vx vmulh_vv vmulh_vx vmulhu_vv vmulhu_vx
+    vmulhsu_vv vmulhsu_vx vdivu_vv vdivu_vx vdiv_vv vdiv_vx vremu_vv vremu_vx vrem_vv vrem_vx
+    vwmulu_vv vwmulu_vx vwmulsu_vv vwmulsu_vx vwmul_vv vwmul_vx vmacc_vv vmacc_vx vnmsac_vv
+    vnmsac_vx vmadd_vv vmadd_vx vnmsub_vv vnmsub_vx vwmaccu_vv vwmaccu_vx vwmacc_vv vwmacc_vx
+    vwmaccsu_vv vwmaccsu_vx vwmaccus_vx vmv_v_v vmv_v_x vmv_v_i vmerge_vvm vmerge_vxm vmerge_vim
+    vsaddu_vv vsaddu_vx vsaddu_vi vsadd_vv vsadd_vx vsadd_vi vssubu_vv vssubu_vx vssub_vv vssub_vx
+    vaadd_vv vaadd_vx vaaddu_vv vaaddu_vx vasub_vv vasub_vx vasubu_vv vasubu_vx vsmul_vv vsmul_vx
+    vssrl_vv vssrl_vx vssrl_vi vssra_vv vssra_vx vssra_vi vnclipu_wv vnclipu_wx vnclipu_wi vnclip_wv
+    vnclip_wx vnclip_wi vfadd_vv vfadd_vf vfsub_vv vfsub_vf vfrsub_vf vfwadd_vv vfwadd_vf vfwadd_wv
+    vfwadd_wf vfwsub_vv vfwsub_vf vfwsub_wv vfwsub_wf vfmul_vv vfmul_vf vfdiv_vv vfdiv_vf vfrdiv_vf
+    vfwmul_vv vfwmul_vf vfmacc_vv vfnmacc_vv vfnmacc_vf vfmacc_vf vfmsac_vv vfmsac_vf vfnmsac_vv
+    vfnmsac_vf vfmadd_vv vfmadd_vf vfnmadd_vv vfnmadd_vf vfmsub_vv vfmsub_vf vfnmsub_vv vfnmsub_vf
+    vfwmacc_vv vfwmacc_vf vfwnmacc_vv vfwnmacc_vf vfwmsac_vv vfwmsac_vf vfwnmsac_vv vfwnmsac_vf
+    vfsqrt_v vfrsqrt7_v vfrec7_v vfmin_vv vfmin_vf vfmax_vv vfmax_vf vfsgnj_vv vfsgnj_vf vfsgnjn_vv
+    vfsgnjn_vf vfsgnjx_vv vfsgnjx_vf vfslide1up_vf vfslide1down_vf vmfeq_vv vmfeq_vf vmfne_vv
+    vmfne_vf vmflt_vv vmflt_vf vmfle_vv vmfle_vf vmfgt_vf vmfge_vf vfclass_v vfmerge_vfm vfmv_v_f
+    vfcvt_xu_f_v vfcvt_x_f_v vfcvt_f_xu_v vfcvt_f_x_v vfcvt_rtz_xu_f_v vfcvt_rtz_x_f_v vfwcvt_xu_f_v
+    vfwcvt_x_f_v vfwcvt_f_xu_v vfwcvt_f_x_v vfwcvt_f_f_v vfwcvt_rtz_xu_f_v vfwcvt_rtz_x_f_v
+    vfncvt_xu_f_w vfncvt_x_f_w vfncvt_f_xu_w vfncvt_f_x_w vfncvt_f_f_w vfncvt_rod_f_f_w
+    vfncvt_rtz_xu_f_w vfncvt_rtz_x_f_w vredsum_vs vredand_vs vredor_vs vredxor_vs vredminu_vs
+    vredmin_vs vredmaxu_vs vredmax_vs vwredsumu_vs vwredsum_vs vfredusum_vs vfredosum_vs vfredmin_vs
+    vfredmax_vs vfwredusum_vs vfwredosum_vs vmand_mm vmnand_mm vmandn_mm vmxor_mm vmor_mm vmnor_mm
+    vmorn_mm vmxnor_mm vcpop_m vfirst_m vmsbf_m vmsif_m vmsof_m viota_m vid_v vmv_x_s vmv_s_x
+    vfmv_f_s vfmv_s_f vslideup_vx vslideup_vi vslide1up_vx vslidedown_vx vslidedown_vi
+    vslide1down_vx vrgather_vv vrgatherei16_vv vrgather_vx vrgather_vi vcompress_vm vmv1r_v vmv2r_v
+    vmv4r_v vmv8r_v vzext_vf2 vzext_vf4 vzext_vf8 vsext_vf2 vsext_vf4 vsext_vf8 vsetvli vsetivli
+    vsetvl sh1add sh2add sh3add add_uw sh1add_uw sh2add_uw sh3add_uw slli_uw andn rol ror rori
+    rev8_32 zext_h_32 pack xnor clz cpop ctz max maxu min minu orc_b orn sext_b sext_h brev8 packh
+    unzip zip rev8_64 rolw roriw rorw zext_h_64 packw clzw ctzw cpopw clmul clmulh clmulr xperm4
+    xperm8 bclr bclri bext bexti binv binvi bset bseti flh fsh fmadd_h fmsub_h fnmsub_h fnmadd_h
+    fadd_h fsub_h fmul_h fdiv_h fsqrt_h fsgnj_h fsgnjn_h fsgnjx_h fmin_h fmax_h fcvt_h_s fcvt_s_h
+    fcvt_h_d fcvt_d_h fcvt_w_h fcvt_wu_h fmv_x_h feq_h flt_h fle_h fclass_h fcvt_h_w fcvt_h_wu
+    fmv_h_x fcvt_l_h fcvt_lu_h fcvt_h_l fcvt_h_lu sinval_vma sfence_w_inval sfence_inval_ir
+    hinval_vvma hinval_gvma aes32dsmi aes32dsi aes64dsm aes64ds aes64im aes32esmi aes32esi aes64es
+    aes64esm aes64ks2 aes64ks1i sha256sig0 sha256sig1 sha256sum0 sha256sum1 sha512sum0r sha512sum1r
+    sha512sig0l sha512sig0h sha512sig1l sha512sig1h sha512sig0 sha512sig1 sha512sum0 sha512sum1
+    sm3p0 sm3p1 sm4ed sm4ks
+);
+namer!(decoder16::DecodeTrait;
+    c_illegal c_addi c_lq c_fld c_lw c_sq c_fsd c_sw c_ld c_flw c_sd c_fsw c_lui c_srli c_srai
+    c_andi c_sub c_xor c_or c_and c_jal c_beq c_bne c64_illegal c_addiw c_subw c_addw c_slli c_jalr
+    c_ebreak c_add
+);
+const RV128_ONLY: &[&str] = &[
+    "ldu", "lq", "sq", "addid", "sllid", "srlid", "sraid", "addd", "subd", "slld", "srld", "srad", "muld",
+    "divd", "divud", "remd", "remud", "c_lq", "c_sq",
+];
+const RV64_ONLY: &[&str] = &[
+    "lwu", "ld", "sd", "addiw", "slliw", "srliw", "sraiw", "addw", "subw", "sllw", "srlw", "sraw", "mulw",
+    "divw", "divuw", "remw", "remuw", "lr_d", "sc_d", "amoswap_d", "amoadd_d", "amoxor_d", "amoand_d",
+    "amoor_d", "amomin_d", "amomax_d", "amominu_d", "amomaxu_d", "fcvt_l_s", "fcvt_lu_s", "fcvt_s_l",
+    "fcvt_s_lu", "fcvt_l_d", "fcvt_lu_d", "fmv_x_d", "fcvt_d_l", "fcvt_d_lu", "fmv_d_x", "fcvt_l_h",
+    "fcvt_lu_h", "fcvt_h_l", "fcvt_h_lu", "hlv_wu", "hlv_d", "hsv_d", "add_uw", "sh1add_uw", "sh2add_uw",
+    "sh3add_uw", "slli_uw", "rev8_64", "zext_h_64", "rolw", "roriw", "rorw", "packw", "clzw", "ctzw",
+    "cpopw", "aes64dsm", "aes64ds", "aes64im", "aes64es", "aes64esm", "aes64ks2", "aes64ks1i",
+    "sha512sig0", "sha512sig1", "sha512sum0", "sha512sum1", "c_ld", "c_sd", "c_addiw", "c_subw", "c_addw",
+    "c64_illegal",
+];
+const RV32_ONLY: &[&str] = &[
+    "rev8_32", "zext_h_32", "zip", "unzip", "aes32dsmi", "aes32dsi", "aes32esmi", "aes32esi", "sha512sum0r",
+    "sha512sum1r", "sha512sig0l", "sha512sig0h", "sha512sig1l", "sha512sig1h", "c_jal", "c_flw", "c_fsw",
+];
+
+/// The decoder's name for `insn` (compressed if the low bits say so), None if it's not a valid
+/// instruction for this xlen
+pub fn decoder_name(insn: u32, is_64: bool) -> Option<&'static str> {
+    let mut n = Namer { is_64, name: None };
+    let ok = if insn & 3 != 3 {
+        decoder16::decode(&mut n, insn as u16)
+    } else {
+        decoder::decode(&mut n, insn)
+    };
+    match n.name {
+        Some("c_illegal") | Some("c64_illegal") => None,
+        name if ok => name,
+        _ => None,
+    }
+}
+/// "fcvt_w_s" -> "fcvt.w.s". The _32/_64 variants are the same instruction to whoever reads it
+pub fn mnemonic(name: &str) -> String {
+    let base = name.strip_suffix("_32").or_else(|| name.strip_suffix("_64")).unwrap_or(name);
+    base.replace('_', ".")
+}
+const ZBA: &[&str] = &["sh1add", "sh2add", "sh3add", "add_uw", "sh1add_uw", "sh2add_uw", "sh3add_uw", "slli_uw"];
+const ZBB: &[&str] = &[
+    "andn", "orn", "xnor", "clz", "clzw", "ctz", "ctzw", "cpop", "cpopw", "max", "maxu", "min", "minu",
+    "sext_b", "sext_h", "zext_h_32", "zext_h_64", "rol", "rolw", "ror", "rori", "roriw", "rorw",
+    "rev8_32", "rev8_64", "orc_b",
+];
+const ZBC: &[&str] = &["clmul", "clmulh", "clmulr"];
+const ZBS: &[&str] = &["bclr", "bclri", "bext", "bexti", "binv", "binvi", "bset", "bseti"];
+const ZBKB: &[&str] = &["pack", "packh", "packw", "brev8", "zip", "unzip"];
+const M: &[&str] = &[
+    "mul", "mulh", "mulhsu", "mulhu", "div", "divu", "rem", "remu", "mulw", "divw", "divuw", "remw", "remuw",
+];
+const PRIV: &[&str] = &["mret", "sret", "uret", "wfi", "sfence_vma", "sfence_vm"];
+/// The extension a core needs for the instruction the decoder calls `name`. Base integer
+/// instructions are "I", RV64-only ones included
+pub fn extension(name: &str) -> &'static str {
+    let fp = |n: &str| {
+        // the widest format in the name decides: fcvt.s.d needs D, fcvt.s.h needs Zfh
+        let parts: Vec<&str> = n.split('_').skip(1).collect();
+        match n {
+            "flh" | "fsh" => "Zfh",
+            "fld" | "fsd" => "D",
+            "flw" | "fsw" => "F",
+            _ if parts.contains(&"h") => "Zfh",
+            _ if parts.contains(&"d") => "D",
+            _ => "F",
+        }
+    };
+    match name {
+        // c.fld and the like too, a binary that has those runs other D instructions as well
+        n if n.starts_with("c_") => "C",
+        n if n.starts_with('v') => "V",
+        "pause" => "Zihintpause",
+        "fence_i" => "Zifencei",
+        "fence" | "ecall" | "ebreak" => "I",
+        n if n.starts_with("csrr") => "Zicsr",
+        n if PRIV.contains(&n) => "privileged",
+        "sinval_vma" | "sfence_w_inval" | "sfence_inval_ir" => "Svinval",
+        n if n.starts_with("hlv") || n.starts_with("hsv") || n.starts_with("hfence") || n.starts_with("hinval") => "H",
+        n if M.contains(&n) => "M",
+        n if n.starts_with("lr_") || n.starts_with("sc_") || n.starts_with("amo") => "A",
+        n if ZBA.contains(&n) => "Zba",
+        n if ZBB.contains(&n) => "Zbb",
+        // clmul and clmulh are in Zbkc too, a core with either runs them
+        n if ZBC.contains(&n) => "Zbc",
+        n if ZBS.contains(&n) => "Zbs",
+        n if ZBKB.contains(&n) => "Zbkb",
+        "xperm4" | "xperm8" => "Zbkx",
+        n if n.starts_with("aes") && n.contains("ds") || n == "aes64im" => "Zknd",
+        n if n.starts_with("aes") => "Zkne",
+        n if n.starts_with("sha") => "Zknh",
+        n if n.starts_with("sm3") => "Zksh",
+        n if n.starts_with("sm4") => "Zksed",
+        n if n.starts_with('f') => fp(n),
+        _ => "I",
+    }
+}
+/// What a core has, from an ISA string like "rv64gc" or "rv64imac_zba_zbb"
+#[derive(Clone, Debug, PartialEq)]
+pub struct IsaTarget {
+    pub spec: String,
+    pub is_64: bool,
+    /// lower case
+    exts: Vec<String>,
+}
+impl IsaTarget {
+    pub fn parse(spec: &str) -> Result<IsaTarget, String> {
+        let s = spec.trim().to_ascii_lowercase();
+        let (is_64, rest) = if let Some(r) = s.strip_prefix("rv64") {
+            (true, r)
+        } else if let Some(r) = s.strip_prefix("rv32") {
+            (false, r)
+        } else {
+            return Err(format!("{} doesn't start with rv32 or rv64", spec));
+        };
+        let mut parts = rest.split('_');
+        let mut exts: Vec<String> = vec![];
+        for c in parts.next().unwrap_or("").chars() {
+            let add: &[&str] = match c {
+                'g' => &["i", "m", "a", "f", "d", "zicsr", "zifencei"],
+                'b' => &["zba", "zbb", "zbs"],
+                'q' => &["q", "d", "f"],
+                'd' => &["d", "f"],
+                'i' | 'e' | 'm' | 'a' | 'f' | 'c' | 'v' | 'h' => &[],
+                _ => return Err(format!("{}: no extension called {}", spec, c)),
+            };
+            exts.push(c.to_string());
+            exts.extend(add.iter().map(|e| e.to_string()));
+        }
+        for p in parts.filter(|p| !p.is_empty()) {
+            let add: &[&str] = match p {
+                "zk" | "zkn" => &["zbkb", "zbkc", "zbkx", "zkne", "zknd", "zknh"],
+                "zks" => &["zbkb", "zbkc", "zbkx", "zksed", "zksh"],
+                _ => &[],
+            };
+            exts.push(p.to_string());
+            exts.extend(add.iter().map(|e| e.to_string()));
+        }
+        if exts.iter().any(|e| e == "e") {
+            exts.push("i".to_string());
+        }
+        Ok(IsaTarget { spec: spec.to_string(), is_64, exts })
+    }
+    /// Whether a core like this runs instructions of `ext` (as extension() names them)
+    pub fn has(&self, ext: &str) -> bool {
+        let ext = ext.to_ascii_lowercase();
+        match ext.as_str() {
+            // not something a user program runs, so not something to hold against a core
+            "privileged" => true,
+            // older ISA strings like rv64imafd don't name it, but F can't do without it
+            "zicsr" => self.exts.iter().any(|e| e == "zicsr" || e == "f"),
+            "zihintpause" => true, // a hint, it's a fence on cores without it
+            "zbc" => self.exts.iter().any(|e| e == "zbc" || e == "zbkc"),
+            _ => self.exts.iter().any(|e| *e == ext),
+        }
+    }
+}
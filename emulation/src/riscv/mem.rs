@@ -1,13 +1,23 @@
 use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::sync::Arc;
+use sync::Mutex;
 use vm_memory::{GuestAddress, GuestMemory};
 use crate::common::memory::{flat_mem, MemEndian, MemError};
+use crate::common::poison::{PoisonAccess, PoisonMap, PoisonReport};
+use crate::common::fault_inject::FaultInjector;
+use crate::common::hart_stats::{Counter, HartCounters};
 use crate::riscv::common::{Exception, Priv, RiscvMemError, Trap, Xlen};
 use crate::riscv::common::Priv::{Machine, Supervisor, UserApp};
-use base::{debug, info, warn};
+use base::{debug, info};
 use crate::riscv::common::RiscvMemError::{GenError, PageError};
 use crate::riscv::interpreter::consts::CSR_MSTATUS_ADDRESS;
 use crate::riscv::interpreter::main::RiscvInt;
+use crate::riscv::interpreter::atomic::note_store;
+#[cfg(feature = "linux-usermode")]
+use crate::linux_usermode::main::end_guest;
+#[cfg(feature = "linux-usermode")]
+use crate::linux_usermode::summary::RunExit;
 
 pub const RISCV_PAGE_SIZE: u64 = 4096; // smallest possible, just to be safe. In riscv, it is the only possible page size
 pub const RISCV_PAGE_OFFSET: u64 = RISCV_PAGE_SIZE - 1;
@@ -63,6 +73,9 @@ pub struct RiscVMem {
     tlb: HashMap<u64, u64>,
     pub read_watchpoints: Vec<u64>,
     pub write_watchpoints: Vec<u64>,
+    pub poison: Option<Arc<Mutex<PoisonMap>>>, // shared by every hart/thread of the guest
+    pub faults: Option<Arc<FaultInjector>>, // same
+    pub stats: Option<Arc<HartCounters>>, // this hart's counters, when stats are on
 
 }
 // reads will be return in native form, writes are expected in native form
@@ -79,6 +92,9 @@ impl RiscVMem {
             tlb: Default::default(),
             read_watchpoints: Vec::new(),
             write_watchpoints: Vec::new(),
+            poison: None,
+            faults: None,
+            stats: None,
         }
     }
 
@@ -93,11 +109,17 @@ impl RiscVMem {
             usermode: false,
             tlb: Default::default(),
             read_watchpoints: Vec::new(),
-            write_watchpoints: Vec::new()
+            write_watchpoints: Vec::new(),
+            poison: None,
+            faults: None,
+            stats: None,
         }
     }
     pub fn clear_cache(&mut self) {
         // sfence.vma
+        if let Some(s) = &self.stats {
+            s.bump(Counter::TlbFlushes);
+        }
         self.tlb.clear();
     }
     fn trunc(&self, addr: u64) -> u64 {
@@ -106,6 +128,9 @@ impl RiscVMem {
             Xlen::X64 => addr
         }
     }
+    pub fn page_mode(&self) -> PageMode {
+        self.pmode
+    }
     pub fn satp_flush(&mut self, value: u64) {
         // write to satp
         self.pmode = match self.reglen {
@@ -192,6 +217,15 @@ impl RiscVMem {
         let val = self.guest_mem.swap_atomic_imm_32(realaddr, imm, MemEndian::Little, ord);
         return Ok(val);
     }
+    /// Host address of `addr`, for an atomic access (which stays in one page)
+    pub fn atomic_ptr(&mut self, addr: u64, access: MemAccessCircumstances) -> Result<*mut u8, RiscvMemError> {
+        if self.usermode {
+            return Ok(addr as *mut u8);
+        }
+        let realaddr = self.virt2phys(addr, access)
+            .map_err(|_| RiscvMemError::PageError(addr))?;
+        self.guest_mem.guest_mem.get_host_address(GuestAddress(realaddr)).map_err(|_| GenError(realaddr))
+    }
     pub fn read16(&mut self, addr: u64, access: MemAccessCircumstances) -> Result<u16, RiscvMemError> {
         let vect = self.read_n_bytes(addr, 2, access)?;
         let mut retval: [u8; 2] = [0; 2];
@@ -241,6 +275,9 @@ impl RiscVMem {
     }
     // todo: we can return a pagewalk_error enum with speicific reasons
     fn page_walk(&mut self, addr: u64, acctype: MemAccessCircumstances) -> Result<u64, ()> {
+        if let Some(s) = &self.stats {
+            s.bump(Counter::PageWalks);
+        }
         let (mut ptesize, mut level) = match self.pmode {
             PageMode::None => panic!("how are we here?"),
             PageMode::Sv32 => (4, 2),
@@ -281,7 +318,7 @@ impl RiscVMem {
             };
             ptestr = self.pte_parse(pte);
             if ptestr.n == 1 || ptestr.pbmt != 0 {
-                warn!("riscv: page_walk() encountered unsupported extension");
+                crate::warn_ratelimited!("riscv: page_walk() encountered unsupported extension");
                 return Err(()); // that extension not supported
             }
             if ptestr.v == 0 || (ptestr.r == 0 && ptestr.w == 1) {
@@ -496,6 +533,9 @@ impl RiscvInt {
                     // we could also page fault,
                     // what matters is outer loop return guaranteed + cache invalid is done
                     self.stop_exec = true;
+                    if let Some(s) = &self.stats {
+                        s.bump(Counter::BlockInvalidations);
+                    }
                     i.begin = 0;
                     i.end = 0;
                     i.instrs.clear();
@@ -503,18 +543,191 @@ impl RiscvInt {
             }
         }
     }
+    #[inline(always)]
+    fn count_access(&self, what: Counter, bytes_counter: Counter, bytes: u64) {
+        if self.host_access {
+            return;
+        }
+        if let Some(s) = &self.stats {
+            s.bump(what);
+            s.add(bytes_counter, bytes);
+        }
+    }
+    /// Runs `f` with its memory accesses counting as the emulator's own (syscall results, signal
+    /// frames, the debugger), which stats don't count, poisoning doesn't report and injected
+    /// faults don't hit
+    pub fn host_side<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        let was = std::mem::replace(&mut self.host_access, true);
+        let r = f(self);
+        self.host_access = was;
+        r
+    }
+    /// Poisoned ranges are shared, so set the same map on every hart of a guest
+    pub fn set_poison_map(&mut self, pm: Option<Arc<Mutex<PoisonMap>>>) {
+        self.memsource.poison = pm;
+    }
+    #[inline(always)]
+    fn poison_check(&mut self, addr: u64, len: u64, access: PoisonAccess) {
+        if self.host_access {
+            return;
+        }
+        if let Some(pm) = &self.memsource.poison {
+            let pm = pm.clone();
+            self.poison_check_slow(pm, addr, len, access);
+        }
+    }
+    fn poison_check_slow(&mut self, pm: Arc<Mutex<PoisonMap>>, addr: u64, len: u64, access: PoisonAccess) {
+        let addr = self.get_effective_address(addr);
+        let hit = pm.lock().lookup(addr, len).map(|(s, l)| (s, l.to_string()));
+        if let Some((range_start, label)) = hit {
+            let rep = PoisonReport {
+                pc: self.pc,
+                addr,
+                len,
+                access,
+                range_start,
+                label,
+                backtrace: self.guest_backtrace(16),
+            };
+            if pm.lock().report(rep) {
+                self.poison_abort();
+            }
+        }
+    }
+    // the abort policy: the guest exits with 1 like under ASAN. A usermode guest goes the way
+    // exit_group() does, so whoever ran it still gets to write its summary and stats
+    fn poison_abort(&mut self) {
+        #[cfg(feature = "linux-usermode")]
+        if self.usermode {
+            self.finish_reports(RunExit::Exit(1));
+            end_guest(&mut self.user_struct, 1);
+            self.stop_exec = true;
+            return;
+        }
+        std::process::exit(1);
+    }
+    /// Injected memory faults, shared like the poison map
+    pub fn set_fault_injector(&mut self, fi: Option<Arc<FaultInjector>>) {
+        self.memsource.faults = fi;
+    }
+    #[inline(always)]
+    fn fault_check(&mut self, addr: u64, len: u64, acc: MemAccessType, set_trap: bool) -> Result<(), Trap> {
+        if self.host_access {
+            return Ok(());
+        }
+        if let Some(fi) = &self.memsource.faults {
+            let fi = fi.clone();
+            if fi.mem_fault(self.get_effective_address(addr), len, acc == MemAccessType::Write) {
+                let trp = self.mem_trap_access(acc, addr);
+                if set_trap {
+                    self.set_trap(trp);
+                }
+                return Err(trp);
+            }
+        }
+        Ok(())
+    }
+    /// Uses the shadow stack when there is one. Otherwise this is a best effort walk of the frame
+    /// pointer (s0) chain, so it only goes as deep as the guest was built with frame pointers.
+    /// Return address is at fp - xlen, previous fp below it
+    pub fn guest_backtrace(&mut self, max_frames: usize) -> Vec<u64> {
+        if let Some(ss) = &self.shadow_stack {
+            return ss.backtrace(max_frames);
+        }
+        let width: u64 = match self.xlen {
+            Xlen::X32 => 4,
+            Xlen::X64 => 8,
+        };
+        let mut ret = vec![];
+        let mut fp = self.regs[8];
+        let macc = self.gen_mem_cirum(MemAccessType::Read);
+        while ret.len() < max_frames {
+            if fp == 0 || fp % width != 0 || fp < 2 * width {
+                break;
+            }
+            let raw = match self.memsource.read_n_bytes(self.get_effective_address(fp - 2 * width), 2 * width as usize, macc) {
+                Ok(r) => r,
+                Err(_) => break,
+            };
+            let (prev_fp, ra) = match self.xlen {
+                Xlen::X32 => (u32::from_le_bytes(raw[0..4].try_into().unwrap()) as u64,
+                              u32::from_le_bytes(raw[4..8].try_into().unwrap()) as u64),
+                Xlen::X64 => (u64::from_le_bytes(raw[0..8].try_into().unwrap()),
+                              u64::from_le_bytes(raw[8..16].try_into().unwrap())),
+            };
+            if ra == 0 {
+                break;
+            }
+            ret.push(ra);
+            // stack grows down, so a sane chain only goes up
+            if prev_fp <= fp {
+                break;
+            }
+            fp = prev_fp;
+        }
+        ret
+    }
+    /// Where an LR, SC or AMO of `len` bytes at `addr` is on the host, after the checks any other
+    /// load or store (`write`, AMOs are stores) gets: alignment, poisoning, injected faults and
+    /// --check-pointers. The trap is set when there's none
+    pub(crate) fn atomic_target(&mut self, addr: u64, len: u64, write: bool) -> Result<*mut u8, Trap> {
+        let acc = if write { MemAccessType::Write } else { MemAccessType::Read };
+        if addr % len != 0 {
+            let trp = Trap {
+                ttype: if write { Exception::StoreAddressMisaligned } else { Exception::LoadAddressMisaligned },
+                val: addr,
+            };
+            self.set_trap(trp);
+            return Err(trp);
+        }
+        self.poison_check(addr, len, if write { PoisonAccess::Write } else { PoisonAccess::Read });
+        self.fault_check(addr, len, acc, true)?;
+        // the host would just crash on it, like it does for plain loads and stores when the check
+        // is off
+        #[cfg(feature = "linux-usermode")]
+        if self.usermode && self.user_struct.opts.check_pointers && !self.host_access
+            && !self.user_struct.memusage.accessible(addr, len, write) {
+            let trp = self.mem_trap_access(acc, addr);
+            self.set_trap(trp);
+            return Err(trp);
+        }
+        self.count_access(Counter::Loads, Counter::LoadBytes, len);
+        if write {
+            self.count_access(Counter::Stores, Counter::StoreBytes, len);
+        }
+        if write && self.cache_enabled {
+            self.deal_with_cache(addr);
+        }
+        let macc = self.gen_mem_cirum(acc);
+        let res = self.memsource.atomic_ptr(self.get_effective_address(addr), macc);
+        self.mem_fn_handler(res, true, acc)
+    }
     pub fn readx(&mut self, addr: u64, size: u64, is_exec: bool, set_trap: bool) -> Result<Vec<u8>, Trap> {
+        if !is_exec {
+            self.poison_check(addr, size, PoisonAccess::Read);
+            self.fault_check(addr, size, MemAccessType::Read, set_trap)?;
+            self.count_access(Counter::Loads, Counter::LoadBytes, size);
+        }
         let macc = self.gen_mem_cirum(get_read_access_type(is_exec));
         let x = self.memsource.read_n_bytes(self.get_effective_address(addr), size as usize, macc);
         self.mem_fn_handler(x, set_trap, macc.access_type)
     }
     pub fn writex(&mut self, addr: u64, vals: Vec<u8>, set_trap: bool) -> Result<(), Trap> {
+        self.poison_check(addr, vals.len() as u64, PoisonAccess::Write);
+        self.fault_check(addr, vals.len() as u64, MemAccessType::Write, set_trap)?;
+        self.count_access(Counter::Stores, Counter::StoreBytes, vals.len() as u64);
+        note_store(addr, vals.len() as u64);
 
         let macc = self.gen_mem_cirum(MemAccessType::Write);
         let x = self.memsource.write_n_bytes(self.get_effective_address(addr),  macc, vals);
         self.mem_fn_handler(x,  set_trap, macc.access_type)
     }
     pub fn read64(&mut self, addr: u64, is_exec: bool, set_trap: bool) -> Result<u64, Trap> {
+        if !is_exec {
+            self.poison_check(addr, 8, PoisonAccess::Read);
+            self.fault_check(addr, 8, MemAccessType::Read, set_trap)?;
+            self.count_access(Counter::Loads, Counter::LoadBytes, 8);
+        }
         // todo- check mmio, etc
         #[cfg(feature = "linux-usermode")]
         if self.usermode {
@@ -527,6 +740,11 @@ impl RiscvInt {
     }
 
     pub fn read32(&mut self, addr: u64, is_exec: bool, set_trap: bool) -> Result<u32, Trap> {
+        if !is_exec {
+            self.poison_check(addr, 4, PoisonAccess::Read);
+            self.fault_check(addr, 4, MemAccessType::Read, set_trap)?;
+            self.count_access(Counter::Loads, Counter::LoadBytes, 4);
+        }
         #[cfg(feature = "linux-usermode")]
         if self.usermode {
             return Ok(self.memsource.guest_mem.read_phys_32(addr, MemEndian::Little).unwrap());
@@ -537,6 +755,11 @@ impl RiscvInt {
     }
 
     pub fn read16(&mut self, addr: u64, is_exec: bool, set_trap: bool) -> Result<u16, Trap> {
+        if !is_exec {
+            self.poison_check(addr, 2, PoisonAccess::Read);
+            self.fault_check(addr, 2, MemAccessType::Read, set_trap)?;
+            self.count_access(Counter::Loads, Counter::LoadBytes, 2);
+        }
         #[cfg(feature = "linux-usermode")]
         if self.usermode {
             return Ok(self.memsource.guest_mem.read_phys_16(addr, MemEndian::Little).unwrap());
@@ -547,6 +770,11 @@ impl RiscvInt {
     }
 
     pub fn read8(&mut self, addr: u64, is_exec: bool, set_trap: bool) -> Result<u8, Trap> {
+        if !is_exec {
+            self.poison_check(addr, 1, PoisonAccess::Read);
+            self.fault_check(addr, 1, MemAccessType::Read, set_trap)?;
+            self.count_access(Counter::Loads, Counter::LoadBytes, 1);
+        }
         #[cfg(feature = "linux-usermode")]
         if self.usermode {
             return Ok(self.memsource.guest_mem.read_phys_8(addr).unwrap());
@@ -566,6 +794,10 @@ impl RiscvInt {
     }
 
     pub fn write64(&mut self, addr: u64, val: u64, set_trap: bool) -> Result<(), Trap> {
+        self.poison_check(addr, 8, PoisonAccess::Write);
+        self.fault_check(addr, 8, MemAccessType::Write, set_trap)?;
+        self.count_access(Counter::Stores, Counter::StoreBytes, 8);
+        note_store(addr, 8);
         if self.cache_enabled {
             self.deal_with_cache(addr);
         }
@@ -579,6 +811,10 @@ impl RiscvInt {
 
     }
     pub fn write32(&mut self, addr: u64, val: u32, set_trap: bool) -> Result<(), Trap> {
+        self.poison_check(addr, 4, PoisonAccess::Write);
+        self.fault_check(addr, 4, MemAccessType::Write, set_trap)?;
+        self.count_access(Counter::Stores, Counter::StoreBytes, 4);
+        note_store(addr, 4);
         if self.cache_enabled {
             self.deal_with_cache(addr);
         }
@@ -592,6 +828,10 @@ impl RiscvInt {
         self.mem_fn_handler(res, set_trap, macc.access_type)
     }
     pub fn write16(&mut self, addr: u64, val: u16, set_trap: bool) -> Result<(), Trap> {
+        self.poison_check(addr, 2, PoisonAccess::Write);
+        self.fault_check(addr, 2, MemAccessType::Write, set_trap)?;
+        self.count_access(Counter::Stores, Counter::StoreBytes, 2);
+        note_store(addr, 2);
         if self.cache_enabled {
             self.deal_with_cache(addr);
         }
@@ -606,6 +846,10 @@ impl RiscvInt {
 
     }
     pub fn write8(&mut self, addr: u64, val: u8, set_trap: bool) -> Result<(), Trap> {
+        self.poison_check(addr, 1, PoisonAccess::Write);
+        self.fault_check(addr, 1, MemAccessType::Write, set_trap)?;
+        self.count_access(Counter::Stores, Counter::StoreBytes, 1);
+        note_store(addr, 1);
         if self.cache_enabled {
             self.deal_with_cache(addr);
         }
@@ -5,4 +5,5 @@ pub mod mem;
 mod decoder16;
 #[cfg(feature = "linux-usermode")]
 pub mod ume;
-mod debug;
\ No newline at end of file
+mod debug;
+pub mod isa_usage;
\ No newline at end of file
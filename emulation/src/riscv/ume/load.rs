@@ -1,16 +1,26 @@
 use std::collections::HashMap;
-use std::ffi::CString;
 use std::sync::Arc;
 use base::platform::MemoryMapping;
-use base::{debug, gettid, info, MappedRegion, pagesize, Protection};
+use base::{gettid, info, MappedRegion, pagesize, Protection};
+use anyhow::anyhow;
 use goblin::elf::Elf;
 use sync::Mutex;
 use crate::common::genfunc::{round_down, round_up};
 use crate::common::memory::{flat_mem, MemEndian};
-use crate::elf::{AuxType, Auxv, MachineType, MemState, UserModeInit, UserModeRuntime};
+use crate::elf::{initResult, AuxType, Auxv, Error, MachineType, MemState, Object, UserModeInit, UserModeRuntime};
 use crate::linux_usermode::defs::SigConstants;
+use crate::linux_usermode::signals::GuestSignals;
+use crate::common::hotpatch::PatchGate;
+use crate::linux_usermode::memusage::MemUsage;
+use crate::linux_usermode::stack::{build_initial_stack, StackError};
+use crate::linux_usermode::sched::{run_deterministic, run_pooled, SchedExit};
+use crate::linux_usermode::main::{catch_group_exit, finish_reports};
+use crate::linux_usermode::summary::RunExit;
 use crate::riscv::common::{RISCV_PAGE_SIZE, RISCV_STACKPOINTER_REG, Xlen};
 use crate::riscv::common::Xlen::{X64, X32};
+use crate::riscv::interpreter::block_store::{cache_key, BlockStore};
+use crate::riscv::interpreter::custom::CustomExtensions;
+use crate::riscv::interpreter::guest_call::GuestCallArg;
 use crate::riscv::interpreter::main::RiscvInt;
 use crate::riscv::ume::signals::riscv64_init_sigconstant;
 
@@ -46,6 +56,8 @@ pub fn init_riscv_runtime(ef: &Elf) -> UserModeRuntime {
         next_thread_stack_base: stackbase - max_stack_size,
         anon_idx: 0
     };
+    let memusage = Arc::new(MemUsage::new());
+    memusage.map_loader(sigaddr, pagesize() as u64, false);
     let ival = UserModeInit {
         real_entry_point: 0,
         mmap_barrier: mmap_end,
@@ -54,6 +66,8 @@ pub fn init_riscv_runtime(ef: &Elf) -> UserModeRuntime {
         intrp_idx: None,
         args: vec![],
         envp: vec![],
+        exe_link: String::new(),
+        dl: None,
     };
     UserModeRuntime {
         initvars: Arc::new(Mutex::new(ival)),
@@ -73,41 +87,28 @@ pub fn init_riscv_runtime(ef: &Elf) -> UserModeRuntime {
         str_path: "".to_string(),
         tid_val: gettid() as u64,
         flags: 0,
+        opts: Default::default(),
+        sched: None,
+        sched_event: None,
+        local_locks: Arc::new(Default::default()),
+        ids: None,
+        fakeroot: None,
+        cputime: Arc::new(Default::default()),
+        cpu_slot: None,
+        icount_base: 0,
+        summary: None,
+        isa: None,
+        signals: GuestSignals::new(),
+        memusage,
+        stats: None,
+        hart_stats: None,
+        symbols: Arc::new(Mutex::new(None)),
+        group_exit: Arc::new(Default::default()),
+        console: Arc::new(Default::default()),
+        patch_gate: Arc::new(PatchGate::new()),
         ctid_val: 0
     }
 }
-fn push_stack_val(ri: &mut RiscvInt, val: u64) {
-    let ms = ri.user_struct.memstate.lock();
-    if (ms.stack_base - ms.stack_size) > ri.regs[RISCV_STACKPOINTER_REG] {
-        panic!("ran out stack")
-    }
-    if ri.xlen == X64 {
-        ri.regs[RISCV_STACKPOINTER_REG] -= 8;
-        ri.memsource.guest_mem.write_phys_64(ri.regs[RISCV_STACKPOINTER_REG],val, MemEndian::Little);
-
-    } else if  ri.xlen == Xlen::X32 {
-        ri.regs[RISCV_STACKPOINTER_REG] -= 4;
-        ri.memsource.guest_mem.write_phys_32(ri.regs[RISCV_STACKPOINTER_REG],val as u32, MemEndian::Little);
-
-    } else {
-        panic!();
-    }
-}
-fn push_stack(ri: &mut RiscvInt, val: &[u8]) {
-    let ms = ri.user_struct.memstate.lock();
-    ri.regs[RISCV_STACKPOINTER_REG] -= val.len() as u64;
-    if (ms.stack_base - ms.stack_size) > ri.regs[RISCV_STACKPOINTER_REG] {
-        panic!("ran out stack")
-    }
-    let mut stack_ptr_up = ri.regs[RISCV_STACKPOINTER_REG] as *mut u8;
-    for i in val {
-        unsafe {
-            *stack_ptr_up = *i;
-            stack_ptr_up = stack_ptr_up.add(1);
-        }
-
-    }
-}
 fn map_stack(ri: &mut RiscvInt) {
     let mut ms = ri.user_struct.memstate.lock();
     let mapreg = MemoryMapping::new_protection_fixed(
@@ -116,10 +117,11 @@ fn map_stack(ri: &mut RiscvInt) {
         , Protection::read_write_execute(),
         false).unwrap();
     ri.regs[RISCV_STACKPOINTER_REG] = ms.stack_base;
+    ri.user_struct.memusage.map_loader(ms.stack_base - ms.stack_size, ms.stack_size, true);
     ms.mem_maps.push(mapreg);
 
 }
-pub fn init_stack(ri: &mut RiscvInt, ef: &Elf) {
+pub fn init_stack(ri: &mut RiscvInt, ef: &Elf) -> Result<(), StackError> {
     ri.regs[RISCV_STACKPOINTER_REG] -= 16;
    // let ms = &mut ume.memstate;
     let random_ptr = ri.get_stack_reg();
@@ -144,47 +146,51 @@ pub fn init_stack(ri: &mut RiscvInt, ef: &Elf) {
     auxv.push(Auxv { typ: AuxType::Flags, value: 0 as u64 });
     auxv.push(Auxv { typ: AuxType::Random, value: random_ptr });
     auxv.push(Auxv { typ: AuxType::Null, value: 0 as u64 });
-    let subval = if ri.xlen == Xlen::X64 { 8 } else { 4 };
-    let envpclone = iv.envp.clone();
-    let argclone = iv.args.clone();
+    let envp = iv.envp.clone();
+    let args = iv.args.clone();
     drop(iv);
-    let mut envPtrs: Vec<u64> = Vec::new();
-    for i in &envpclone {
-        let pval = CString::new(i.clone().as_bytes()).unwrap().into_bytes_with_nul();
-        debug!("going to write env val value {} to addr 0x{:x}", i, ri.get_stack_reg() - (pval.len() as u64));
-        push_stack(ri, &pval);
-        envPtrs.push(ri.get_stack_reg())
-    }
-    envPtrs.push(0);
-    let mut argPtrs: Vec<u64> = Vec::new();
-    for i in &argclone {
-        let pval = CString::new(i.clone().as_bytes()).unwrap().into_bytes_with_nul();
-        debug!("going to write arg val value {} to addr 0x{:x}", i, ri.get_stack_reg() - (pval.len() as u64));
-        push_stack(ri,  &pval);
-        argPtrs.push(ri.get_stack_reg())
-    }
-    argPtrs.push(0);
-    ri.regs[RISCV_STACKPOINTER_REG] &= !15;
-    for i in auxv.into_iter().rev() {
-        debug!("going to write aux value {} to addr 0x{:x}", i.value, ri.get_stack_reg() - subval);
-        push_stack_val(ri, i.value as u64);
-        debug!("going to write aux key {:?} to addr 0x{:x}", i.typ, ri.get_stack_reg() - subval);
-        push_stack_val(ri,  i.typ as u64);
+    build_initial_stack(ri, &args, &envp, &auxv)
+}
+// What the built-in loader left for us: thread pointer, IFUNC resolvers and library initializers.
+// Like with ld.so, a resolver or initializer that doesn't come back is the end of the run
+pub(crate) fn finish_dynload(ri: &mut RiscvInt) -> anyhow::Result<()> {
+    let dl = match ri.user_struct.initvars.lock().dl.take() {
+        Some(dl) => dl,
+        None => return Ok(()),
+    };
+    if let Some(tp) = dl.tp {
+        ri.regs[4] = tp;
     }
-    for i in envPtrs.into_iter().rev() {
-        debug!("going to write envp ptr 0x{:x} to addr 0x{:x}", i, ri.get_stack_reg() - subval);
-        push_stack_val(ri,  i as u64);
+    for (at, resolver, addend) in dl.ifuncs {
+        let r = ri.call_guest_function(resolver, &[])
+            .map_err(|e| anyhow!("IFUNC resolver at {:#x} failed: {:?}", resolver, e))?;
+        ri.user_struct.mem_access.write_phys_64(at, r.a0.wrapping_add(addend), MemEndian::Little)
+            .map_err(|_| anyhow!("couldn't write the IFUNC result at {:#x}", at))?;
     }
-    for i in argPtrs.into_iter().rev() {
-        // the last valuee should be higher on stack
-        debug!("going to write arg ptr 0x{:x} to addr 0x{:x}", i, ri.get_stack_reg() - subval);
-        push_stack_val(ri,  i as u64);
+    // initializers get (argc, argv, envp), like ld.so passes them
+    let sp = ri.regs[RISCV_STACKPOINTER_REG];
+    let argc = ri.user_struct.mem_access.read_phys_64(sp, MemEndian::Little)
+        .map_err(|_| anyhow!("couldn't read argc at {:#x}", sp))?;
+    let argv = sp + 8;
+    let envp = argv + (argc + 1) * 8;
+    for f in dl.init_funcs {
+        let args = [GuestCallArg::Int(argc), GuestCallArg::Int(argv), GuestCallArg::Int(envp)];
+        ri.call_guest_function(f, &args).map_err(|e| anyhow!("initializer at {:#x} failed: {:?}", f, e))?;
     }
-    let argc = ri.user_struct.initvars.lock().args.len() as u64;
-    push_stack_val(ri, argc);
-
+    Ok(())
 }
-pub fn init_riscv_ume(ume: UserModeRuntime, ef: &Elf) {
+/// The executable's decoded blocks from an earlier run, if the file is for this one
+fn open_block_store(exe: &Object, path: String, xlen: Xlen, custom: Option<&CustomExtensions>) -> BlockStore {
+    let start = (exe.base + exe.mem_range.start) as u64;
+    let end = (exe.base + exe.mem_range.end) as u64;
+    // usermode harts start with misa.C set and can't write misa
+    let key = cache_key(exe.build_id.as_deref(), &exe.path, xlen, true, custom);
+    let store = BlockStore::open(path, key, exe.base as u64, start..end);
+    info!("{} decoded blocks in the block cache", store.len());
+    store
+}
+/// Runs the guest, returns its exit status
+pub fn init_riscv_ume(mut ume: UserModeRuntime, ef: &Elf) -> initResult<i32> {
     let iv = ume.initvars.lock();
 
     let mut maxaddr = iv.objects[iv.obj_idx.unwrap()].mem_range.end;
@@ -199,14 +205,46 @@ pub fn init_riscv_ume(ume: UserModeRuntime, ef: &Elf) {
     } else {
         false
     };
+    let xlen = if is64bit {Xlen::X64} else {Xlen::X32};
+    if let Some(path) = ume.opts.block_cache.clone() {
+        let store = open_block_store(&iv.objects[iv.obj_idx.unwrap()], path, xlen, ume.opts.riscv_custom.as_deref());
+        ume.block_store = Some(Arc::new(store));
+    }
     drop(iv);
-    let mut riscvcpu = RiscvInt::init_usermode(if is64bit {Xlen::X64} else {Xlen::X32}, ume);
+    let mut riscvcpu = RiscvInt::init_usermode(xlen, ume);
     map_stack(&mut riscvcpu);
-    init_stack(&mut riscvcpu, ef);
+    init_stack(&mut riscvcpu, ef).map_err(Error::Stack)?;
+    let group_exit = riscvcpu.user_struct.group_exit.clone();
+    // an initializer can exit_group() too
+    let mut linked = Ok(());
+    if let Some(status) = catch_group_exit(&group_exit, || linked = finish_dynload(&mut riscvcpu)) {
+        return Ok(status);
+    }
+    linked.map_err(Error::DynLoad)?;
     riscvcpu.pc = riscvcpu.user_struct.initvars.lock().real_entry_point;
-    riscvcpu.cache_enabled = false;
-    riscvcpu.run();
-    // anything below run() should not happen.
-    unreachable!("riscv processor error")
-
+    // decoded blocks are only cached for --block-cache so far
+    riscvcpu.cache_enabled = riscvcpu.block_store.is_some();
+    if let Some(st) = riscvcpu.user_struct.sched.clone() {
+        let ume = riscvcpu.user_struct.clone();
+        st.lock().add_thread(ume.tid_val, ume.signals.clone());
+        let res = if st.lock().workers > 1 {
+            run_pooled(Box::new(riscvcpu), st)
+        } else {
+            run_deterministic(Box::new(riscvcpu), st)
+        };
+        return Ok(match res {
+            SchedExit::ExitGroup(status) => status,
+            // every guest thread left through exit() instead of exit_group()
+            SchedExit::Done => {
+                finish_reports(&ume, RunExit::Exit(0));
+                0
+            }
+            SchedExit::Deadlock => {
+                finish_reports(&ume, RunExit::Deadlock);
+                1
+            }
+        });
+    }
+    // threads only come back from run() by exit_group()
+    Ok(catch_group_exit(&group_exit, || riscvcpu.run()).expect("riscv processor error"))
 }
@@ -2,35 +2,34 @@ use std::os::unix::thread::JoinHandleExt;
 use std::sync::Arc;
 use base::{get_blocked_signals, gettid};
 use base::platform::eventfd::EventFd;
-use libc::{CLONE_CHILD_CLEARTID, CLONE_CHILD_SETTID, CLONE_PARENT_SETTID, CLONE_SETTLS, fork, getpid, sysinfo, vfork};
+use libc::{CLONE_CHILD_CLEARTID, CLONE_CHILD_SETTID, CLONE_PARENT_SETTID, CLONE_SETTLS, EINTR, ETIMEDOUT, fork, getpid, sysinfo, vfork};
 use sync::Mutex;
 use crate::common::memory::MemEndian;
 use crate::elf::UserModeRuntime;
-use crate::linux_usermode::defs::GenericStat;
-use crate::linux_usermode::main::{SyscallIn, SyscallOut, UsermodeCpu};
-use crate::linux_usermode::signals::{block_all_signals, GenericSigactionArg, GenericStackt, get_generic_sigaction_64, set_mask_block, SigEntry, SigInfo, Sigmask};
+use crate::linux_usermode::defs::{GenericStat, read32_advance_ptr, read64_advance_ptr, write32_advance_ptr, write64_advance_ptr};
+use crate::linux_usermode::main::{catch_group_exit, guest_pid, SyscallIn, SyscallOut, UsermodeCpu};
+use crate::linux_usermode::sched::{DetThread, SchedEvent, SchedState};
+use crate::linux_usermode::signals::{block_all_signals, fill_generic_stackt, GenericSigactionArg, GenericStackt, get_generic_sigaction_64, set_mask_block, SigEntry, SigInfo, Sigmask, bind_thread_signals};
 use crate::riscv::common::{RISCV_STACKPOINTER_REG, Xlen};
 use crate::riscv::interpreter::main::RiscvInt;
 use crate::riscv::ume::defs::write_riscv_stat;
 use crate::riscv::ume::defs::{riscv_translate_syscall, write_riscv_sysinfo};
-use crate::riscv::ume::signals::setup_rt_frame;
+use crate::riscv::ume::signals::{restore_rt_frame, setup_rt_frame};
 pub mod load;
 pub mod defs;
 pub mod signals;
 
 impl UsermodeCpu for RiscvInt {
-    fn push_stack_natural(&mut self, val: u64) {
-        todo!()
+    fn get_stack_reg(&mut self) -> u64 {
+        self.regs[RISCV_STACKPOINTER_REG]
     }
-
-    fn pop_stack_natural(&mut self) -> u64 {
-        todo!()
+    fn set_stack_reg(&mut self, val: u64) {
+        self.regs[RISCV_STACKPOINTER_REG] = val;
     }
 
-    fn get_stack_reg(&mut self) -> u64 {
-        todo!()
+    fn retired_insns(&mut self) -> Option<u64> {
+        Some(self.icount)
     }
-
     fn get_ume(&mut self) -> &mut UserModeRuntime {
         &mut self.user_struct
     }
@@ -48,34 +47,62 @@ impl UsermodeCpu for RiscvInt {
     }
 
     fn set_old_sigaction(&mut self, addr: u64, se: SigEntry) {
-        todo!()
+        // what get_sigaction() reads
+        let mut a = addr;
+        write64_advance_ptr(&mut a, se.handler_func, MemEndian::Little);
+        write64_advance_ptr(&mut a, se.flags, MemEndian::Little);
+        if let Some(r) = se.sa_restorer {
+            write64_advance_ptr(&mut a, r, MemEndian::Little);
+        }
+        let mask = se.maskguest.normalize_to_u32();
+        write32_advance_ptr(&mut a, mask[0], MemEndian::Little);
+        write32_advance_ptr(&mut a, mask[1], MemEndian::Little);
     }
     fn write_sysinfo_t(&mut self, addr: u64, si: sysinfo) {
         write_riscv_sysinfo(addr, MemEndian::Little, si);
     }
     fn set_altstack(&mut self, addr: u64, si: &SigInfo) {
-        todo!()
+        let st = fill_generic_stackt(self.get_stack_reg(), si);
+        let mut a = addr;
+        write64_advance_ptr(&mut a, st.ss_sp, MemEndian::Little);
+        write32_advance_ptr(&mut a, st.ss_flags as u32, MemEndian::Little);
+        write32_advance_ptr(&mut a, 0, MemEndian::Little); // padding
+        write64_advance_ptr(&mut a, st.ss_size, MemEndian::Little);
     }
 
     fn get_altstack(&mut self, addr: u64) -> GenericStackt {
-        todo!()
+        let mut a = addr;
+        let ss_sp = read64_advance_ptr(&mut a, MemEndian::Little);
+        let ss_flags = read32_advance_ptr(&mut a, MemEndian::Little) as i32;
+        a += 4;
+        let ss_size = read64_advance_ptr(&mut a, MemEndian::Little);
+        GenericStackt { ss_sp, ss_flags, ss_size }
     }
 
     fn rt_frame_setup(&mut self, sig: i32, si: &mut SigInfo) {
-        todo!()
+        setup_rt_frame(self, sig, si)
+    }
+
+    fn rt_frame_restore(&mut self) -> SyscallOut {
+        restore_rt_frame(self)
     }
     fn clone_thread(&mut self, sysin: SyscallIn) -> SyscallOut {
         let flags = sysin.args[0] as i32;
         let stack_addr = sysin.args[1];
         let parent_tid_addr = sysin.args[2];
+        if let Some(st) = self.user_struct.sched.clone() {
+            return self.clone_thread_det(sysin, st);
+        }
         let ss_old = block_all_signals();
         let ss_old2 = ss_old.clone();
-        let umec = self.user_struct.clone();
+        let mut umec = self.user_struct.clone();
+        umec.signals = self.user_struct.signals.for_thread(flags);
 
         let xlen = self.xlen;
         let regs = self.regs.clone();
         let fregs = self.fregs.clone();
         let pc = self.pc;
+        let cache_enabled = self.cache_enabled;
         let new_tls = sysin.args[3];
         //let mut ar: Arc<Mutex<u64>> = Arc::new(Mutex::new(0));
         //let mut ar2 = ar.clone();
@@ -83,11 +110,14 @@ impl UsermodeCpu for RiscvInt {
 
         let evt = EventFd::new().unwrap();
         let evt_clone = evt.try_clone().unwrap();
+        let owner = unsafe { getpid() };
+        self.user_struct.group_exit.thread_started();
         let k = std::thread::Builder::new()
             .spawn(move || {
                 let mut rv = RiscvInt::init_usermode(xlen, umec);
                 rv.user_struct.tid_val = gettid() as u64;
                 rv.user_struct.flags = flags;
+                rv.user_struct.icount_base = 0;
                 evt_clone.write(rv.user_struct.tid_val).unwrap();
                 //let mut s = ar2.lock();
                 //*s = rv.user_struct.tid_val;
@@ -98,12 +128,14 @@ impl UsermodeCpu for RiscvInt {
                     rv.fregs[i] = fregs[i];
                 }
                 rv.pc = pc;
+                rv.cache_enabled = cache_enabled;
                 // 4 is thread pointer
                 if flags & CLONE_SETTLS != 0 {
                     rv.regs[4] = new_tls;
                 }
                 if flags & CLONE_CHILD_SETTID != 0 {
-                    panic!();
+                    let tid = guest_pid(&rv.user_struct, rv.user_struct.tid_val as libc::pid_t);
+                    rv.host_side(|c| c.write32(child_tid_addr, tid as u32, false)).unwrap();
                 }
                 if flags & CLONE_CHILD_CLEARTID != 0 {
                     rv.user_struct.ctid_val = child_tid_addr;
@@ -112,7 +144,15 @@ impl UsermodeCpu for RiscvInt {
                 rv.regs[RISCV_STACKPOINTER_REG] = stack_addr;
                 rv.regs[10] = 0;
                 set_mask_block(ss_old2);
-                rv.run();
+                let keep = rv.user_struct.opts.keep_process;
+                let group_exit = rv.user_struct.group_exit.clone();
+                if let Some(status) = catch_group_exit(&group_exit, || rv.run()) {
+                    // the thread that started the guest may be stuck in a syscall, it can't
+                    // be the one to end the process. A fork()ed child's process is all ours
+                    if !keep || unsafe { getpid() } != owner {
+                        std::process::exit(status);
+                    }
+                }
 
             }).unwrap();
         //let p = k.as_pthread_t() as *mut u64; // todo fix
@@ -120,10 +160,12 @@ impl UsermodeCpu for RiscvInt {
         if p == 0 {
             panic!();
         }
+        // the guest sees the same id in the return value and in both SETTID stores
+        let tid = guest_pid(&self.user_struct, p as libc::pid_t);
         let mut sout: SyscallOut = Default::default();
-        sout.ret1 = p as u64;
+        sout.ret1 = tid as u64;
         if flags & CLONE_PARENT_SETTID != 0 {
-            self.write32(parent_tid_addr, p as u32, false).unwrap();
+            self.host_side(|c| c.write32(parent_tid_addr, tid as u32, false)).unwrap();
         }
         set_mask_block(ss_old);
         return sout;
@@ -135,19 +177,32 @@ impl UsermodeCpu for RiscvInt {
         let parent_tid_addr = sysin.args[2];
         let child_tid_addr = sysin.args[4];
 
+        // made here, another guest thread may hold the parent's scheduler lock when we fork and
+        // nobody would ever let go of it in the child
+        let child_sched = self.user_struct.sched.as_ref().map(|s| Arc::new(Mutex::new(s.lock().for_child())));
         let pid = unsafe {
             fork()
         };
         if pid == 0 {
-            let pid = unsafe {
-                getpid()
-            } as u32;
+            let pid = guest_pid(&self.user_struct, unsafe { getpid() }) as u32;
             self.user_struct.tid_val = gettid() as u64;
+            self.user_struct.cputime.forked_child();
+            self.user_struct.group_exit.forked_child();
+            self.patch_seen.gate().forked_child(&self.patch_seen);
+            if let (Some(r), Some(hc)) = (&self.user_struct.stats, &self.user_struct.hart_stats) {
+                r.forked_child(hc);
+            }
+            self.user_struct.icount_base = self.icount;
+            if let Some(s) = child_sched {
+                s.lock().add_thread(self.user_struct.tid_val, self.user_struct.signals.clone());
+                self.user_struct.sched = Some(s);
+                self.user_struct.sched_event = Some(SchedEvent::ForkedChild);
+            }
             if stack_addr != 0 {
                 self.regs[RISCV_STACKPOINTER_REG] = stack_addr;
             }
             if flags & CLONE_CHILD_SETTID != 0 {
-                self.write32(child_tid_addr, pid, false).unwrap();
+                self.host_side(|c| c.write32(child_tid_addr, pid, false)).unwrap();
             }
             if flags & CLONE_CHILD_CLEARTID != 0 {
                 self.user_struct.ctid_val = child_tid_addr;
@@ -159,6 +214,10 @@ impl UsermodeCpu for RiscvInt {
             return sout;
             // child
         }
+        let pid = guest_pid(&self.user_struct, pid);
+        if pid > 0 && flags & CLONE_PARENT_SETTID != 0 {
+            self.host_side(|c| c.write32(parent_tid_addr, pid as u32, false)).unwrap();
+        }
         let mut sout: SyscallOut = Default::default();
         sout.ret1 = pid as u64;
         return sout;
@@ -166,4 +225,90 @@ impl UsermodeCpu for RiscvInt {
 
 
     }
-}
\ No newline at end of file
+}
+impl RiscvInt {
+    // Deterministic version of clone(): the new thread is just another interpreter that the
+    // scheduler picks up once the current slice ends
+    fn clone_thread_det(&mut self, sysin: SyscallIn, st: Arc<Mutex<SchedState>>) -> SyscallOut {
+        let flags = sysin.args[0] as i32;
+        let stack_addr = sysin.args[1];
+        let parent_tid_addr = sysin.args[2];
+        let new_tls = sysin.args[3];
+        let child_tid_addr = sysin.args[4];
+        let tid = st.lock().alloc_tid();
+        let mut rv = Box::new(RiscvInt::init_usermode(self.xlen, self.user_struct.clone()));
+        rv.user_struct.tid_val = tid;
+        rv.user_struct.flags = flags;
+        rv.user_struct.sched_event = None;
+        rv.user_struct.signals = self.user_struct.signals.for_thread(flags);
+        st.lock().add_thread(tid, rv.user_struct.signals.clone());
+        rv.regs = self.regs;
+        rv.fregs = self.fregs;
+        rv.pc = self.pc;
+        rv.cache_enabled = self.cache_enabled;
+        rv.icount = self.icount;
+        rv.user_struct.icount_base = self.icount;
+        // scheduled threads share their host thread(s), so they share the rate limit too
+        rv.pacer = self.pacer.clone();
+        rv.pace_mark = self.icount;
+        if flags & CLONE_SETTLS != 0 {
+            rv.regs[4] = new_tls;
+        }
+        if flags & CLONE_CHILD_SETTID != 0 {
+            rv.host_side(|c| c.write32(child_tid_addr, tid as u32, false)).unwrap();
+        }
+        if flags & CLONE_CHILD_CLEARTID != 0 {
+            rv.user_struct.ctid_val = child_tid_addr;
+        }
+        rv.regs[RISCV_STACKPOINTER_REG] = stack_addr;
+        rv.regs[10] = 0;
+        if flags & CLONE_PARENT_SETTID != 0 {
+            self.host_side(|c| c.write32(parent_tid_addr, tid as u32, false)).unwrap();
+        }
+        self.sched_spawned.push(rv);
+        let mut sout: SyscallOut = Default::default();
+        sout.ret1 = tid;
+        sout
+    }
+}
+impl DetThread for RiscvInt {
+    fn run_slice(&mut self, spawned: &mut Vec<Box<RiscvInt>>) -> SchedEvent {
+        let quantum = self.user_struct.sched.as_ref().unwrap().lock().quantum;
+        let _bound = bind_thread_signals(&self.user_struct.signals);
+        let slice_end = self.icount + quantum;
+        self.patch_seen.gate().enter();
+        let ev = loop {
+            self.icount_limit = self.pace().min(slice_end);
+            self.run_once();
+            spawned.append(&mut self.sched_spawned);
+            if let Some(ev) = self.user_struct.sched_event.take() {
+                break ev;
+            }
+            if self.icount >= slice_end {
+                break SchedEvent::Yield;
+            }
+        };
+        self.patch_seen.gate().leave();
+        ev
+    }
+
+    fn sched_tid(&self) -> u64 {
+        self.user_struct.tid_val
+    }
+
+    fn sched_icount(&self) -> u64 {
+        self.icount
+    }
+
+    fn futex_timed_out(&mut self) {
+        self.regs[10] = -ETIMEDOUT as i64 as u64;
+    }
+
+    fn futex_interrupted(&mut self) {
+        self.regs[10] = -EINTR as i64 as u64;
+    }
+
+    fn sched_state(&self) -> Arc<Mutex<SchedState>> {
+        self.user_struct.sched.clone().unwrap()
+    }
+}
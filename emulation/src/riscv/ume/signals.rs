@@ -3,13 +3,17 @@ use std::mem;
 use libc::{SA_NODEFER, SA_RESTART, SIGABRT, SIGALRM, SIGBUS, SIGCHLD, SIGCONT, SIGFPE, SIGHUP, SIGILL, SIGINT, SIGIO, SIGKILL, SIGPIPE, SIGPROF, SIGPWR, SIGQUIT, SIGSEGV, SIGSTKFLT, SIGSTOP, SIGSYS, SIGTRAP, SIGTSTP, SIGTTIN, SIGTTOU, SIGURG, SIGUSR1, SIGUSR2, SIGVTALRM, SIGWINCH, SIGXCPU, SIGXFSZ};
 use crate::common::memory::{flat_mem, MemEndian};
 use crate::linux_usermode::defs::{SigConstants, snyth_sigconst};
-use crate::linux_usermode::signals::{fill_generic_stackt, GenericSigactionArg, GenericSiginfo, on_sig_stack, SigInfo, target_sigsp};
+use crate::linux_usermode::main::{guest_killed, SyscallOut};
+use crate::linux_usermode::signals::{fill_generic_stackt, GenericSigactionArg, GenericSiginfo, reset_delivered, SigInfo};
+use crate::linux_usermode::stack::push_signal_frame;
+use crate::linux_usermode::summary::RunExit;
 use crate::riscv::interpreter::consts::CSR_FCSR_ADDRESS;
+use crate::riscv::common::RISCV_STACKPOINTER_REG;
 use crate::riscv::interpreter::main::RiscvInt;
 
 #[repr(C)]
 #[derive(Copy, Clone)]
-pub union RiscvDFloatCtx {
+pub struct RiscvDFloatCtx {
     pub fpr: [u64; 32],
     pub fcsr: u32,
 }
@@ -42,16 +46,6 @@ pub struct Riscv64RtSigframe {
     info: GenericSiginfo,
     uctx: Riscv64Uctx
 }
-fn get_sigframe_64(ri: &mut RiscvInt, si: &SigInfo, framesize: u64, sig: i32) -> u64 {
-    // guest sig
-    let sp = ri.get_stack_reg();
-    if on_sig_stack(sp, si) && !on_sig_stack(sp - framesize, si) {
-        return u64::max_value();
-    }
-    let mut nsp = target_sigsp(sp, sig as usize, si) - framesize;
-    nsp &= !0xf;
-    nsp
-}
 pub fn riscv64_setup_sigctx(ri: &mut RiscvInt) -> Riscv64SigCtx {
     let mut rsc: Riscv64SigCtx = Default::default();
     rsc.pc = if let Some(ss) = ri.want_pc {
@@ -75,7 +69,7 @@ pub fn riscv64_setup_sigctx(ri: &mut RiscvInt) -> Riscv64SigCtx {
 pub fn riscv64_setup_uctx(ri: &mut RiscvInt, si: &SigInfo, idx: usize) -> Riscv64Uctx {
     let mut uctx: Riscv64Uctx = Default::default();
     uctx.stackt = riscv64_setup_stackt(ri, si);
-    let arr = si.entry[idx].maskguest.normalize_to_u64();
+    let arr = si.action(idx).maskguest.normalize_to_u64();
     uctx.sigset =arr;
     uctx.sctx = riscv64_setup_sigctx(ri);
     uctx
@@ -111,25 +105,55 @@ pub struct Riscv64Uctx {
 
 }
 pub fn setup_rt_frame(ri: &mut RiscvInt, sig: i32, si: &mut SigInfo) {
-    let fsize = mem::size_of::<Riscv64RtSigframe>();
     let isize = mem::size_of::<GenericSiginfo>() as u64;
-
-    let addr = get_sigframe_64(ri, si, fsize as u64, sig);
-    let mut rt: *mut Riscv64RtSigframe = addr as *mut Riscv64RtSigframe;
-    unsafe {
-        (*rt).uctx = riscv64_setup_uctx(ri, si, sig as usize);
-        (*rt).info = si.use_sig.as_mut().unwrap().sinfo;
-    }
+    let frame = Riscv64RtSigframe {
+        info: si.use_sig.take().unwrap().sinfo,
+        uctx: riscv64_setup_uctx(ri, si, sig as usize),
+    };
+    let addr = match push_signal_frame(ri, sig, si, &frame) {
+        Some(a) => a,
+        // the kernel forces a SIGSEGV then, and that one can't have a guest handler
+        None => {
+            ri.finish_reports(RunExit::Signal(SIGSEGV));
+            guest_killed(&mut ri.user_struct, SIGSEGV);
+            return;
+        }
+    };
     ri.stop_exec = true;
-    ri.want_pc = Some(si.entry[sig as usize].handler_func);
-    // todo: write stryct
-    ri.regs[2] = addr as u64; // sp
+    ri.want_pc = Some(si.action(sig as usize).handler_func);
     ri.regs[10] = sig as u64; // a0
     ri.regs[11] = addr as u64; // a1 for siginfo, which is at begiinning
     ri.regs[12] = (addr + isize) as u64;
     ri.regs[1] = ri.user_struct.sig_tramp; // ra
-    panic!();
-
+    reset_delivered(si, sig as usize);
+}
+/// rt_sigreturn() from a handler setup_rt_frame() called: the frame is where sp points, like the
+/// handler got it. Returns a0 as it was
+pub fn restore_rt_frame(ri: &mut RiscvInt) -> SyscallOut {
+    let addr = ri.regs[RISCV_STACKPOINTER_REG];
+    let size = mem::size_of::<Riscv64RtSigframe>() as u64;
+    let mu = &ri.user_struct.memusage;
+    let readable = !ri.user_struct.opts.check_pointers || mu.accessible(addr, size, false);
+    let bytes = match ri.user_struct.mem_access.read_phys_n(addr, size as usize) {
+        Ok(b) if readable => b,
+        // the kernel kills it with a SIGSEGV
+        _ => {
+            ri.finish_reports(RunExit::Signal(SIGSEGV));
+            guest_killed(&mut ri.user_struct, SIGSEGV);
+            return SyscallOut::default();
+        }
+    };
+    let frame: Riscv64RtSigframe = unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const Riscv64RtSigframe) };
+    let sctx = frame.uctx.sctx;
+    for i in 1..32 {
+        ri.regs[i] = sctx.gpr[i - 1];
+    }
+    let fp = unsafe { sctx.fpr.dfloat };
+    ri.fregs.copy_from_slice(&fp.fpr);
+    ri.set_csr_raw(CSR_FCSR_ADDRESS, fp.fcsr as u64);
+    ri.want_pc = Some(sctx.pc);
+    ri.stop_exec = true;
+    SyscallOut { ret1: ri.regs[10], ..Default::default() }
 }
 pub fn riscv64_init_sigconstant() -> SigConstants {
     // 2048 min
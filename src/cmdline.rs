@@ -6,7 +6,8 @@ pub struct GeneralCmdlineArgs {
     /// use extended exit status
     pub extended_status: bool,
     #[argh(option, default = r#"String::from("info")"#)]
-    /// specify log level, eg "off", "error", "debug,disk=off", etc
+    /// specify log level, eg "off", "error", "debug,disk=off", etc. interp, mmu, syscalls and devices work as
+    /// module names, eg "info,syscalls=debug"
     pub log_level: String,
     #[argh(option, arg_name = "TAG")]
     /// when logging to syslog, use the provided tag
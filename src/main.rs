@@ -1,11 +1,20 @@
 pub mod sys;
 pub mod config;
 pub mod cmdline;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "linux-usermode")]
+use std::sync::Arc;
 use anyhow::Result;
 use base::syslog::{Log, LogConfig, Metadata};
 use argh::FromArgs;
 #[cfg(feature = "linux-usermode")]
-use emulation::elf::init_user_mode_emulation;
+use emulation::elf::{init_user_mode_emulation, ConsoleSpec, FakeStore, FaultInjector, FdGrant, FdPolicy, FsMode, GuestIdentity, IoClass, IoThrottle, IsaTarget, MemLimit, OomPolicy, SandboxPolicy, SpinPolicy, ThrottleConfig, UserModeOptions, DEFAULT_SCHED_QUANTUM, DEFAULT_SPIN_THRESHOLD};
+#[cfg(feature = "linux-usermode")]
+use emulation::fuzz_machine::{FuzzMachine, FuzzRng};
+use emulation::engine::Mismatch;
+use emulation::riscv_engine::lockstep_bare_metal;
+use emulation::symbols::SymbolMap;
+use emulation::snapshot::RiscvSnapshot;
 use log::{info, Record};
 use crate::config::*;
 use crate::sys::platform::cmdline::Commands;
@@ -24,12 +33,222 @@ pub enum CommandStatus {
 fn linux_sys_cmd(c: crate::sys::platform::cmdline::Commands, usermode: Option<String>) -> Result<CommandStatus> {
     match c {
         #[cfg(feature = "linux-usermode")]
-        Commands::RunUser(userm) => {
-            init_user_mode_emulation(userm.exec_path, userm.args,
-                                     usermode.unwrap_or(String::from(""))).unwrap();
-            // probably will not return after this
+        Commands::RunUser(mut userm) => {
+            if let Some(seed) = userm.fuzz_config.take() {
+                // the machine layout first, on a bare-metal machine of its own
+                let machine = FuzzMachine::from_seed(seed);
+                eprintln!("--fuzz-config {}: smoke test on {}", seed, machine);
+                // the options were fine, a layout that fails is ours to fix
+                if let Err(e) = machine.smoke() {
+                    return Err(anyhow::anyhow!("--fuzz-config {}: smoke test on {} failed: {}", seed, machine, e));
+                }
+                // the picks become ordinary flags, so an exec'd guest keeps them too
+                let picked = fuzz_usermode_config(&mut userm, seed);
+                eprintln!("--fuzz-config {}: {}", seed, picked.join(" "));
+            }
+            let mut opts = UserModeOptions::default();
+            opts.reexec_prefix = Some(usermode_reexec_prefix(&userm));
+            if userm.deterministic && userm.sched_workers.is_some() {
+                eprintln!("--deterministic and --sched-workers don't go together");
+                return Ok(CommandStatus::InvalidArgs);
+            }
+            if userm.deterministic {
+                opts.det_sched_quantum = Some(userm.sched_quantum.unwrap_or(DEFAULT_SCHED_QUANTUM));
+            }
+            match userm.sched_workers {
+                Some(0) => {
+                    eprintln!("--sched-workers needs at least one worker");
+                    return Ok(CommandStatus::InvalidArgs);
+                }
+                Some(n) => opts.sched_pool = Some((n, userm.sched_quantum.unwrap_or(DEFAULT_SCHED_QUANTUM))),
+                None => {}
+            }
+            opts.shadow_stack = userm.shadow_stack;
+            if let Some(dir) = userm.overlay {
+                opts.fs_mode = FsMode::Overlay(PathBuf::from(dir));
+            } else if userm.read_only_sysroot {
+                opts.fs_mode = FsMode::ReadOnly;
+            }
+            opts.rootfs = userm.rootfs;
+            opts.as_init = userm.as_init;
+            opts.argv0 = userm.argv0.clone();
+            opts.virtual_pids = userm.virtual_pids || userm.id_table_fd.is_some();
+            opts.id_table_fd = userm.id_table_fd;
+            opts.guest_uid = userm.uid;
+            opts.guest_gid = userm.gid;
+            if let Some(mhz) = userm.guest_mhz {
+                opts.guest_mhz = mhz;
+            }
+            opts.mips = userm.mips;
+            if userm.sandbox || userm.sandbox_no_net {
+                opts.sandbox = Some(SandboxPolicy { deny_net: userm.sandbox_no_net });
+            }
+            if userm.throttle_storage.is_some() || userm.throttle_net.is_some() {
+                let thr = IoThrottle::new(userm.throttle_seed.unwrap_or(0));
+                for (class, spec) in [(IoClass::Storage, &userm.throttle_storage), (IoClass::Network, &userm.throttle_net)] {
+                    if let Some(spec) = spec {
+                        match ThrottleConfig::parse(spec) {
+                            Ok(cfg) => thr.set(class, cfg),
+                            Err(e) => {
+                                eprintln!("{}", e);
+                                return Ok(CommandStatus::InvalidArgs);
+                            }
+                        }
+                    }
+                }
+                opts.io_throttle = Some(Arc::new(thr));
+            }
+            if !userm.fault_range.is_empty() || userm.fault_io_ppm.is_some() || userm.fault_bitflip_ppm.is_some() {
+                let fi = FaultInjector::new(userm.fault_seed.unwrap_or(0));
+                for r in &userm.fault_range {
+                    match parse_fault_range(r) {
+                        Some((start, len, rd, wr)) => fi.add_range(start, len, rd, wr),
+                        None => {
+                            eprintln!("bad --fault-range {}, expected START:LEN[:r|w|rw]", r);
+                            return Ok(CommandStatus::InvalidArgs);
+                        }
+                    }
+                }
+                fi.set_fail_ppm(userm.fault_io_ppm.unwrap_or(0));
+                fi.set_bitflip_ppm(userm.fault_bitflip_ppm.unwrap_or(0));
+                opts.faults = Some(Arc::new(fi));
+            }
+            if let Some(size) = &userm.mem_limit {
+                let policy = match userm.oom.as_deref().map(OomPolicy::parse) {
+                    None => OomPolicy::Fail,
+                    Some(Some(p)) => p,
+                    Some(None) => {
+                        eprintln!("bad --oom, expected fail or kill");
+                        return Ok(CommandStatus::InvalidArgs);
+                    }
+                };
+                match MemLimit::parse(size, policy) {
+                    Ok(l) => opts.mem_limit = Some(l),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        return Ok(CommandStatus::InvalidArgs);
+                    }
+                }
+            }
+            opts.stats = userm.stats;
+            opts.check_pointers = userm.check_pointers;
+            if let Some(spec) = &userm.console {
+                match ConsoleSpec::parse(spec) {
+                    Ok(c) => opts.console = Some(c),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        return Ok(CommandStatus::InvalidArgs);
+                    }
+                }
+            }
+            if userm.builtin_loader || userm.lib_path.is_some() {
+                opts.builtin_loader = Some(userm.lib_path.as_deref().unwrap_or("")
+                    .split(':').filter(|d| !d.is_empty()).map(PathBuf::from).collect());
+            }
+            if !userm.pass_fd.is_empty() {
+                let mut grants = vec![];
+                for spec in &userm.pass_fd {
+                    match FdGrant::parse(spec) {
+                        Ok(g) => grants.push(g),
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            return Ok(CommandStatus::InvalidArgs);
+                        }
+                    }
+                }
+                opts.fd_policy = Some(Arc::new(FdPolicy::new(grants)));
+            }
+            if let Some(action) = &userm.spin_detect {
+                match SpinPolicy::parse(action, userm.spin_threshold.unwrap_or(DEFAULT_SPIN_THRESHOLD)) {
+                    Some(p) => opts.spin = Some(p),
+                    None => {
+                        eprintln!("bad --spin-detect, expected warn or backoff");
+                        return Ok(CommandStatus::InvalidArgs);
+                    }
+                }
+            }
+            match guest_identity(&userm) {
+                Ok(id) => opts.identity = id.map(Arc::new),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return Ok(CommandStatus::InvalidArgs);
+                }
+            }
+            // the guest may chdir before it exits
+            opts.summary = userm.summary.as_deref().map(summary_dest);
+            opts.block_cache = userm.block_cache.as_deref().map(summary_dest);
+            opts.isa_report = userm.isa_report.as_deref().map(summary_dest);
+            if let Some(spec) = &userm.isa_target {
+                match IsaTarget::parse(spec) {
+                    Ok(t) => opts.isa_target = Some(t),
+                    Err(e) => {
+                        eprintln!("bad --isa-target: {}", e);
+                        return Ok(CommandStatus::InvalidArgs);
+                    }
+                }
+                if userm.isa_report.is_none() {
+                    eprintln!("--isa-target only means something with --isa-report");
+                    return Ok(CommandStatus::InvalidArgs);
+                }
+            }
+            if userm.fakeroot_xattrs {
+                opts.fakeroot = Some(FakeStore::Xattr);
+            } else if userm.fakeroot {
+                opts.fakeroot = Some(FakeStore::Memory);
+            }
+            opts.fakeroot_table_fd = userm.fakeroot_table_fd;
+            opts.monitor = userm.monitor.clone();
+            match init_user_mode_emulation(userm.exec_path, userm.args,
+                                           usermode.unwrap_or(String::from("")), opts) {
+                // the guest's other threads may still be in the middle of something, they go with us
+                Ok(status) => std::process::exit(status),
+                // the guest never ran, say why with the shell's 126/127 or 1
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(e.exit_status());
+                }
+            }
 
         }
+        Commands::SnapDiff(sd) => {
+            let before = RiscvSnapshot::load(Path::new(&sd.before))?;
+            let after = RiscvSnapshot::load(Path::new(&sd.after))?;
+            let diff = before.diff(&after);
+            if diff.is_empty() {
+                println!("No differences.");
+            } else {
+                print!("{}", diff);
+            }
+        }
+        Commands::Lockstep(ls) => {
+            let mut watch = vec![];
+            for w in &ls.watch {
+                match w.split_once(':').and_then(|(start, len)| Some((parse_num(start)?, parse_num(len)?))) {
+                    Some(r) => watch.push(r),
+                    None => {
+                        eprintln!("bad --watch {}, expected START:LEN", w);
+                        return Ok(CommandStatus::InvalidArgs);
+                    }
+                }
+            }
+            let insns = ls.insns.unwrap_or(1_000_000);
+            let ram = ls.ram_mib.unwrap_or(64) << 20;
+            match lockstep_bare_metal(Path::new(&ls.exec_path), ram, insns, ls.step.unwrap_or(1), &watch)? {
+                Ok(()) => println!("No divergence in {} instructions.", insns),
+                Err(d) => {
+                    let mut syms = SymbolMap::new();
+                    if let Err(e) = syms.add_elf(Path::new(ls.symbols.as_ref().unwrap_or(&ls.exec_path)), 0) {
+                        eprintln!("No symbols from {}", e);
+                    }
+                    let mismatch = match d.mismatch {
+                        Mismatch::Pc(a, b) => format!("pc {} vs {}", syms.describe(a), syms.describe(b)),
+                        m => format!("{:?}", m),
+                    };
+                    println!("Diverged within the {} instructions before {}, at {}: {}", d.step, d.icount, syms.describe(d.pc), mismatch);
+                    return Ok(CommandStatus::InvalidArgs);
+                }
+            }
+        }
         Commands::Nothing(_) => {
             println!("This does nothing.");
         }
@@ -37,8 +256,286 @@ fn linux_sys_cmd(c: crate::sys::platform::cmdline::Commands, usermode: Option<St
     }
     Ok(CommandStatus::Success)
 }
+/// decimal or 0x hex
+fn parse_num(v: &str) -> Option<u64> {
+    match v.strip_prefix("0x") {
+        Some(h) => u64::from_str_radix(h, 16).ok(),
+        None => v.parse().ok(),
+    }
+}
+/// "START:LEN[:r|w|rw]"
+#[cfg(feature = "linux-usermode")]
+fn parse_fault_range(s: &str) -> Option<(u64, u64, bool, bool)> {
+    let mut it = s.split(':');
+    let start = parse_num(it.next()?)?;
+    let len = parse_num(it.next()?)?;
+    let (rd, wr) = match it.next() {
+        None | Some("rw") => (true, true),
+        Some("r") => (true, false),
+        Some("w") => (false, true),
+        _ => return None,
+    };
+    Some((start, len, rd, wr))
+}
+/// --hostname, --os-release and friends, None if none was given
+#[cfg(feature = "linux-usermode")]
+fn guest_identity(userm: &crate::sys::platform::cmdline::RunUserCommand) -> Result<Option<GuestIdentity>, String> {
+    let mut id = GuestIdentity::new();
+    if let Some(h) = &userm.hostname {
+        id.set_hostname(h)?;
+    }
+    if let Some(f) = &userm.os_release {
+        id.set_os_release(Path::new(f))?;
+    }
+    if let Some(m) = &userm.machine_id {
+        id.set_machine_id(m)?;
+    }
+    if let Some(m) = &userm.mac_address {
+        id.set_mac(m)?;
+    }
+    if let Some(f) = &userm.cpuinfo {
+        id.set_cpuinfo(Path::new(f))?;
+    }
+    Ok(if id.is_empty() { None } else { Some(id) })
+}
+#[cfg(feature = "linux-usermode")]
+fn summary_dest(p: &str) -> String {
+    if p == "-" {
+        return p.to_string();
+    }
+    std::env::current_dir().map(|d| d.join(p)).unwrap_or(PathBuf::from(p)).to_string_lossy().to_string()
+}
+/// Random but legal settings for whatever wasn't given on the command line, returned as the flags
+/// that would ask for them, so a run that breaks can be repeated without the seed
+#[cfg(feature = "linux-usermode")]
+fn fuzz_usermode_config(userm: &mut crate::sys::platform::cmdline::RunUserCommand, seed: u64) -> Vec<String> {
+    let mut rng = FuzzRng::new(seed);
+    let mut next = |n: u64| rng.below(n);
+    let mut picked = vec![];
+    if !userm.deterministic && userm.sched_workers.is_none() {
+        match next(3) {
+            0 => {}
+            1 => {
+                userm.deterministic = true;
+                picked.push("--deterministic".to_string());
+            }
+            _ => {
+                let n = 1 + next(8) as usize;
+                userm.sched_workers = Some(n);
+                picked.push(format!("--sched-workers {}", n));
+            }
+        }
+    }
+    let scheduled = userm.deterministic || userm.sched_workers.is_some();
+    if scheduled && userm.sched_quantum.is_none() {
+        // anywhere from a handful of instructions to the default's order of magnitude
+        let bits = 4 + next(14);
+        let q = 1 + next(1 << bits);
+        userm.sched_quantum = Some(q);
+        picked.push(format!("--sched-quantum {}", q));
+    }
+    if userm.guest_mhz.is_none() {
+        let mhz = 1 + next(5000);
+        userm.guest_mhz = Some(mhz);
+        picked.push(format!("--guest-mhz {}", mhz));
+    }
+    for (on, flag) in [(&mut userm.shadow_stack, "--shadow-stack"), (&mut userm.stats, "--stats"),
+                       (&mut userm.check_pointers, "--check-pointers")] {
+        if !*on && next(2) == 1 {
+            *on = true;
+            picked.push(flag.to_string());
+        }
+    }
+    picked
+}
+/// Emulator command line for guest execve(): everything up to "runuser" as we got it, then the
+/// runuser options that should carry over. --as-init doesn't, only the first process is the init,
+/// and the pid table gets passed on by the exec itself
+#[cfg(feature = "linux-usermode")]
+fn usermode_reexec_prefix(userm: &crate::sys::platform::cmdline::RunUserCommand) -> Vec<String> {
+    let host_args = prepare_argh_args(std::env::args());
+    let mut prefix: Vec<String> = match host_args.iter().position(|a| a == "runuser") {
+        Some(i) => host_args[..=i].to_vec(),
+        None => host_args,
+    };
+    if userm.deterministic {
+        prefix.push("--deterministic".to_string());
+    }
+    if let Some(q) = userm.sched_quantum {
+        prefix.push("--sched-quantum".to_string());
+        prefix.push(q.to_string());
+    }
+    if let Some(n) = userm.sched_workers {
+        prefix.push("--sched-workers".to_string());
+        prefix.push(n.to_string());
+    }
+    if userm.shadow_stack {
+        prefix.push("--shadow-stack".to_string());
+    }
+    if userm.read_only_sysroot {
+        prefix.push("--read-only-sysroot".to_string());
+    }
+    if let Some(dir) = &userm.overlay {
+        prefix.push("--overlay".to_string());
+        prefix.push(dir.clone());
+    }
+    if userm.rootfs {
+        prefix.push("--rootfs".to_string());
+    }
+    if userm.fakeroot_xattrs {
+        prefix.push("--fakeroot-xattrs".to_string());
+    } else if userm.fakeroot {
+        prefix.push("--fakeroot".to_string());
+    }
+    if let Some(mhz) = userm.guest_mhz {
+        prefix.push("--guest-mhz".to_string());
+        prefix.push(mhz.to_string());
+    }
+    if userm.sandbox {
+        prefix.push("--sandbox".to_string());
+    }
+    if userm.sandbox_no_net {
+        prefix.push("--sandbox-no-net".to_string());
+    }
+    if let Some(mips) = userm.mips {
+        prefix.push("--mips".to_string());
+        prefix.push(mips.to_string());
+    }
+    for (flag, val) in [("--throttle-storage", &userm.throttle_storage), ("--throttle-net", &userm.throttle_net)] {
+        if let Some(v) = val {
+            prefix.push(flag.to_string());
+            prefix.push(v.clone());
+        }
+    }
+    for r in &userm.fault_range {
+        prefix.push("--fault-range".to_string());
+        prefix.push(r.clone());
+    }
+    for (flag, val) in [("--fault-io-ppm", userm.fault_io_ppm), ("--fault-bitflip-ppm", userm.fault_bitflip_ppm)] {
+        if let Some(v) = val {
+            prefix.push(flag.to_string());
+            prefix.push(v.to_string());
+        }
+    }
+    if let Some(seed) = userm.fault_seed {
+        prefix.push("--fault-seed".to_string());
+        prefix.push(seed.to_string());
+    }
+    for (flag, val) in [("--mem-limit", &userm.mem_limit), ("--oom", &userm.oom)] {
+        if let Some(v) = val {
+            prefix.push(flag.to_string());
+            prefix.push(v.clone());
+        }
+    }
+    if userm.stats {
+        prefix.push("--stats".to_string());
+    }
+    if userm.builtin_loader {
+        prefix.push("--builtin-loader".to_string());
+    }
+    if let Some(dirs) = &userm.lib_path {
+        prefix.push("--lib-path".to_string());
+        prefix.push(dirs.clone());
+    }
+    // the fds are in place by then, the new emulator just has to keep the rules
+    for spec in &userm.pass_fd {
+        if let Ok(g) = FdGrant::parse(spec) {
+            prefix.push("--pass-fd".to_string());
+            prefix.push(g.in_place_arg());
+        }
+    }
+    if userm.check_pointers {
+        prefix.push("--check-pointers".to_string());
+    }
+    // no --console, fds 0-2 already lead to the relay
+    if let Some(a) = &userm.spin_detect {
+        prefix.push("--spin-detect".to_string());
+        prefix.push(a.clone());
+    }
+    if let Some(n) = userm.spin_threshold {
+        prefix.push("--spin-threshold".to_string());
+        prefix.push(n.to_string());
+    }
+    for (flag, val) in [("--hostname", &userm.hostname), ("--machine-id", &userm.machine_id), ("--mac-address", &userm.mac_address)] {
+        if let Some(v) = val {
+            prefix.push(flag.to_string());
+            prefix.push(v.clone());
+        }
+    }
+    // files are read again by the new emulator, which may be in another directory
+    for (flag, val) in [("--os-release", &userm.os_release), ("--cpuinfo", &userm.cpuinfo)] {
+        if let Some(v) = val {
+            prefix.push(flag.to_string());
+            prefix.push(summary_dest(v));
+        }
+    }
+    if let Some(dest) = &userm.summary {
+        prefix.push("--summary".to_string());
+        prefix.push(summary_dest(dest));
+    }
+    if let Some(dest) = &userm.isa_report {
+        prefix.push("--isa-report".to_string());
+        prefix.push(summary_dest(dest));
+    }
+    if let Some(t) = &userm.isa_target {
+        prefix.push("--isa-target".to_string());
+        prefix.push(t.clone());
+    }
+    if let Some(seed) = userm.throttle_seed {
+        prefix.push("--throttle-seed".to_string());
+        prefix.push(seed.to_string());
+    }
+    if let Some(u) = userm.uid {
+        prefix.push("--uid".to_string());
+        prefix.push(u.to_string());
+    }
+    if let Some(g) = userm.gid {
+        prefix.push("--gid".to_string());
+        prefix.push(g.to_string());
+    }
+    prefix
+}
+/// Subcommand names, anything else in their place is taken to be a guest executable
+const SUBCOMMANDS: &[&str] = &["runuser", "snapdiff", "lockstep", "nothing"];
+/// General options that are followed by a value
+const VALUE_FLAGS: &[&str] = &["--log-level", "--syslog-tag", "--usermode-directory"];
+/// Cargo target runner mode: cargo runs `RUNNER BINARY ARGS...` (RUNNER may bring its own general
+/// options), which becomes `runuser BINARY -- ARGS...` so none of the test harness's options are
+/// taken for ours. The guest's exit status is already ours (exit_group and fatal signals go
+/// straight through), the log is just kept down so it doesn't end up in the test output
+fn runner_args(args: Vec<String>) -> Vec<String> {
+    let mut i = 1;
+    while i < args.len() {
+        let a = args[i].as_str();
+        if VALUE_FLAGS.contains(&a) {
+            i += 2;
+        } else if is_flag(a) {
+            i += 1;
+        } else {
+            break;
+        }
+    }
+    if i >= args.len() || SUBCOMMANDS.contains(&args[i].as_str()) {
+        return args;
+    }
+    // cargo passes paths relative to the workspace
+    let exe = match std::fs::canonicalize(&args[i]) {
+        Ok(p) if p.is_file() => p.to_string_lossy().into_owned(),
+        _ => return args,
+    };
+    let mut out = args[..i].to_vec();
+    if !out.iter().any(|a| a == "--log-level" || a.starts_with("--log-level=")) {
+        out.push("--log-level".to_string());
+        out.push("warn".to_string());
+    }
+    out.push("runuser".to_string());
+    out.push(exe);
+    out.push("--".to_string());
+    out.extend_from_slice(&args[i + 1..]);
+    out
+}
 fn gen_main() -> Result<CommandStatus> {
-    let args = prepare_argh_args(std::env::args());
+    let args = prepare_argh_args(runner_args(std::env::args().collect()));
     let args = args.iter().map(|s| s.as_str()).collect::<Vec<_>>();
     let args: GeneralCmdlineArgs = match crate::cmdline::GeneralCmdlineArgs::from_args(&args[..1], &args[1..]) {
         Ok(args) => args,
@@ -49,8 +546,10 @@ fn gen_main() -> Result<CommandStatus> {
     };
     let extended_status = args.extended_status;
     info!("CLI arguments parsed.");
+    // subsystem names (see emulation::logctl) to module paths
+    let log_filter = emulation::logctl::expand_filter(&args.log_level);
     let mut log_config = LogConfig {
-        filter: &args.log_level,
+        filter: &log_filter,
         proc_name: args.syslog_tag.unwrap_or("turbo_emulator".to_string()),
         syslog: !args.no_syslog,
         ..Default::default()
@@ -94,8 +593,18 @@ fn is_flag(arg: &str) -> bool {
 }
 fn prepare_argh_args<I: IntoIterator<Item = String>>(args_iter: I) -> Vec<String> {
     let mut args: Vec<String> = Vec::default();
+    let mut rest = false;
     for arg in args_iter {
+        if rest {
+            // after "--" it all belongs to the guest
+            args.push(arg);
+            continue;
+        }
         match arg.as_str() {
+            "--" => {
+                rest = true;
+                args.push(arg);
+            }
             "-h" => args.push("--help".to_string()),
             arg if is_flag(arg) => {
                 // Split `--arg=val` into `--arg val`, since argh doesn't support the former.
@@ -111,4 +620,39 @@ fn prepare_argh_args<I: IntoIterator<Item = String>>(args_iter: I) -> Vec<String
     }
 
     args
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+    #[test]
+    fn runner_args_wraps_executable() {
+        let exe = std::env::current_exe().unwrap().canonicalize().unwrap();
+        let exe = exe.to_str().unwrap();
+        assert_eq!(runner_args(strings(&["turbo", exe, "--nocapture", "-q"])),
+                   strings(&["turbo", "--log-level", "warn", "runuser", exe, "--", "--nocapture", "-q"]));
+        assert_eq!(runner_args(strings(&["turbo", "--log-level", "debug", exe, "a"])),
+                   strings(&["turbo", "--log-level", "debug", "runuser", exe, "--", "a"]));
+        // relative to the working directory, like cargo gives it
+        let manifest = std::fs::canonicalize("Cargo.toml").unwrap();
+        assert_eq!(runner_args(strings(&["turbo", "--log-level=info", "Cargo.toml"])),
+                   strings(&["turbo", "--log-level=info", "runuser", manifest.to_str().unwrap(), "--"]));
+    }
+    #[test]
+    fn runner_args_leaves_commands_alone() {
+        for args in [&["turbo"][..], &["turbo", "runuser", "prog"], &["turbo", "--syslog-tag", "runuser", "nothing"],
+                     &["turbo", "--log-level", "warn"], &["turbo", "/no/such/file", "x"], &["turbo", "/"],
+                     &["turbo", "lockstep", "--insns", "5", "rom"]] {
+            assert_eq!(runner_args(strings(args)), strings(args));
+        }
+    }
+    #[test]
+    fn runner_args_knows_every_subcommand() {
+        use argh::SubCommands;
+        for c in Commands::COMMANDS {
+            assert!(SUBCOMMANDS.contains(&c.name), "{} is missing from SUBCOMMANDS", c.name);
+        }
+    }
+}
@@ -13,11 +13,243 @@ pub struct RunUserCommand {
     /// the absolute path of an executable file to load and run
     pub exec_path: String,
 
+    #[argh(switch)]
+    /// run guest threads one at a time on a single host thread, in a reproducible order
+    pub deterministic: bool,
+
+    #[argh(option, arg_name = "INSNS")]
+    /// with --deterministic or --sched-workers, switch guest threads after this many instructions
+    pub sched_quantum: Option<u64>,
+
+    #[argh(option, arg_name = "N")]
+    /// run guest threads on a pool of N host threads, taking turns (per thread numbers go in the --summary)
+    pub sched_workers: Option<usize>,
+
+    #[argh(switch)]
+    /// keep a shadow call stack of the guest, for backtraces that don't need frame pointers
+    pub shadow_stack: bool,
+
+    #[argh(switch)]
+    /// don't let the guest change anything in the sysroot
+    pub read_only_sysroot: bool,
+
+    #[argh(option, arg_name = "DIR")]
+    /// write guest changes to the sysroot into DIR instead, leaving the sysroot untouched
+    pub overlay: Option<String>,
+
+    #[argh(switch)]
+    /// use the usermode directory as the guest's root directory, for running a container rootfs
+    pub rootfs: bool,
+
+    #[argh(switch)]
+    /// the guest is an init: orphaned guest processes are reparented to it
+    pub as_init: bool,
+
+    #[argh(option, arg_name = "NAME")]
+    /// argv[0] to give the guest, instead of the executable path
+    pub argv0: Option<String>,
+
+    #[argh(switch)]
+    /// give guest processes their own pids, starting from 1 with --as-init
+    pub virtual_pids: bool,
+
+    #[argh(option, arg_name = "FD")]
+    /// pid table of the emulator that exec'd this one (set by guest execve)
+    pub id_table_fd: Option<i32>,
+
+    #[argh(option)]
+    /// uid the guest sees itself running as
+    pub uid: Option<u32>,
+
+    #[argh(option)]
+    /// gid the guest sees itself running as
+    pub gid: Option<u32>,
+
+    #[argh(switch)]
+    /// pretend the guest is root: chown, chmod and mknod work and are remembered for this run
+    pub fakeroot: bool,
+
+    #[argh(switch)]
+    /// like --fakeroot, but keep what was faked in xattrs on the files so it lasts
+    pub fakeroot_xattrs: bool,
+
+    #[argh(option, arg_name = "FD")]
+    /// fakeroot table of the emulator that exec'd this one (set by guest execve)
+    pub fakeroot_table_fd: Option<i32>,
+
+    #[argh(option, arg_name = "MHZ")]
+    /// guest clock rate used to turn instruction counts into CPU time (getrusage, times)
+    pub guest_mhz: Option<u64>,
+
+    #[argh(option, arg_name = "SPEC")]
+    /// limit guest file I/O, e.g. iops=100,bw=10M,lat=2ms,jitter=5ms,spike=200ms,spike_pct=1
+    pub throttle_storage: Option<String>,
+
+    #[argh(option, arg_name = "SPEC")]
+    /// limit guest socket I/O, same settings as --throttle-storage
+    pub throttle_net: Option<String>,
+
+    #[argh(option, arg_name = "SEED")]
+    /// seed for the random parts of --throttle-storage/--throttle-net
+    pub throttle_seed: Option<u64>,
+
+    #[argh(option, arg_name = "START:LEN[:r|w|rw]")]
+    /// make guest accesses to this range fault (SIGSEGV), can be given more than once
+    pub fault_range: Vec<String>,
+
+    #[argh(option, arg_name = "PPM")]
+    /// fail this many guest I/O requests per million with EIO
+    pub fault_io_ppm: Option<u32>,
+
+    #[argh(option, arg_name = "PPM")]
+    /// flip a bit in this many guest read buffers per million
+    pub fault_bitflip_ppm: Option<u32>,
+
+    #[argh(option, arg_name = "SEED")]
+    /// seed for the random parts of fault injection
+    pub fault_seed: Option<u64>,
+
+    #[argh(option, arg_name = "N")]
+    /// run the guest at about N million instructions per second (per thread) instead of flat out
+    pub mips: Option<u64>,
+
+    #[argh(switch)]
+    /// once the guest is loaded, block host syscalls it has no business causing (ptrace, mount, modules...)
+    pub sandbox: bool,
+
+    #[argh(switch)]
+    /// --sandbox, and also refuse guest sockets other than unix ones
+    pub sandbox_no_net: bool,
+
+    #[argh(option, arg_name = "SIZE")]
+    /// most memory the guest may commit (mmap, brk), e.g. 512M
+    pub mem_limit: Option<String>,
+
+    #[argh(option, arg_name = "fail|kill")]
+    /// what happens at --mem-limit: the request fails with ENOMEM (default) or the guest exits with 137
+    pub oom: Option<String>,
+
+    #[argh(switch)]
+    /// count memory accesses, page walks and block cache hits per hart (in the summary and the gdb "stats" command)
+    pub stats: bool,
+
+    #[argh(switch)]
+    /// link the guest with the emulator's own loader instead of its ld.so (simple libraries only)
+    pub builtin_loader: bool,
+
+    #[argh(option, arg_name = "DIRS")]
+    /// colon separated directories to look for guest libraries in, instead of the sysroot's (implies --builtin-loader)
+    pub lib_path: Option<String>,
+
+    #[argh(option, arg_name = "GUEST[=HOST][:r|w|rw][,keep]")]
+    /// give the guest host fd HOST (default the same number) as fd GUEST, optionally read or write only, or not closable by the guest; can be repeated
+    pub pass_fd: Vec<String>,
+
+    #[argh(option, arg_name = "warn|backoff")]
+    /// notice guest loops going around without anything changing (RISC-V): warn with the PC and symbol, or also back off
+    pub spin_detect: Option<String>,
+
+    #[argh(option, arg_name = "N")]
+    /// with --spin-detect, times around a loop before it counts as spinning (default 10000)
+    pub spin_threshold: Option<u64>,
+
+    #[argh(switch)]
+    /// check the pointers the guest passes to syscalls against its mappings and fail with EFAULT like the kernel, instead of crashing on bad ones
+    pub check_pointers: bool,
+
+    #[argh(option, arg_name = "unix:PATH|tcp:[HOST:]PORT|stdio[,log=FILE]")]
+    /// put the guest's stdin/stdout/stderr on a Unix socket or TCP port clients can connect to (one at a time, reconnecting is fine), optionally copying all output to a log file; the guest then sees pipes, not a terminal
+    pub console: Option<String>,
+
+    #[argh(option, arg_name = "FILE")]
+    /// keep the executable's decoded RISC-V code in FILE so later runs of the same binary start faster (rebuilt when the binary or the emulator changes)
+    pub block_cache: Option<String>,
+
+    #[argh(option, arg_name = "NAME")]
+    /// host name the guest sees (uname, /etc/hostname)
+    pub hostname: Option<String>,
+
+    #[argh(option, arg_name = "FILE")]
+    /// host file the guest reads as its /etc/os-release
+    pub os_release: Option<String>,
+
+    #[argh(option, arg_name = "ID")]
+    /// machine id the guest sees (/etc/machine-id), 32 hex digits
+    pub machine_id: Option<String>,
+
+    #[argh(option, arg_name = "MAC")]
+    /// MAC address every network interface but lo reports, like 02:00:00:12:34:56
+    pub mac_address: Option<String>,
+
+    #[argh(option, arg_name = "FILE")]
+    /// host file the guest reads as its /proc/cpuinfo
+    pub cpuinfo: Option<String>,
+
+    #[argh(option, arg_name = "SEED")]
+    /// for shaking out emulator assumptions: first run a smoke workload on a bare-metal RISC-V machine with RAM base and size, hart count and extensions picked from SEED, then pick the scheduling mode, guest clock rate and optional checks for the guest the same way (flags given explicitly are kept) and print the picks
+    pub fuzz_config: Option<u64>,
+
+    #[argh(option, arg_name = "PATH")]
+    /// write a JSON summary of the run (exit status, instruction count, stats) here at exit, - for stdout
+    pub summary: Option<String>,
+
+    #[argh(option, arg_name = "PATH")]
+    /// count the RISC-V instructions the guest runs and write which ones ran and which extensions they need here at exit (JSON), - for stdout
+    pub isa_report: Option<String>,
+
+    #[argh(option, arg_name = "ISA")]
+    /// ISA string of the core the binary should run on, like rv64gc or rv64imac_zba_zbb; the --isa-report then lists what it lacks
+    pub isa_target: Option<String>,
+
+    #[argh(option, arg_name = "PATH")]
+    /// answer meminfo, stats, throttle and loglevel commands, one per line, on a Unix socket here while the guest runs
+    pub monitor: Option<String>,
+
     #[argh(positional, greedy)]
     /// arguments for the executable file
     pub args: Vec<String>,
 }
 #[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "snapdiff")]
+/// Show what changed between two saved RISC-V snapshots
+pub struct SnapDiffCommand {
+    #[argh(positional)]
+    /// the earlier snapshot
+    pub before: String,
+
+    #[argh(positional)]
+    /// the later snapshot
+    pub after: String,
+}
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "lockstep")]
+/// Run a bare-metal RISC-V ELF on the block-caching interpreter and on one that decodes every instruction, side by side, and show where they first disagree
+pub struct LockstepCommand {
+    #[argh(positional)]
+    /// the ELF to run, loaded at 0x80000000 and started in machine mode
+    pub exec_path: String,
+
+    #[argh(option, arg_name = "N")]
+    /// instructions to run (default 1000000)
+    pub insns: Option<u64>,
+
+    #[argh(option, arg_name = "N")]
+    /// compare after every N instructions (default 1)
+    pub step: Option<u64>,
+
+    #[argh(option, arg_name = "MIB")]
+    /// RAM each side gets, in MiB (default 64)
+    pub ram_mib: Option<u64>,
+
+    #[argh(option, arg_name = "START:LEN")]
+    /// also compare this range of guest memory after every step, can be given more than once
+    pub watch: Vec<String>,
+
+    #[argh(option, arg_name = "ELF")]
+    /// take guest symbol names from this ELF (a vmlinux, say) rather than the one being run
+    pub symbols: Option<String>,
+}
+#[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand, name = "nothing")]
 /// Nothing command (placeholder just so arg handler will be happy)
 pub struct NothingCommand {
@@ -30,5 +262,7 @@ pub struct NothingCommand {
 pub enum Commands {
     #[cfg(feature = "linux-usermode")]
     RunUser(RunUserCommand),
+    SnapDiff(SnapDiffCommand),
+    Lockstep(LockstepCommand),
     Nothing(NothingCommand),
 }
@@ -0,0 +1,106 @@
+//! Shared memory over a `ControlConnection`, so a backend in another process can map the same
+//! memory (guest RAM especially) instead of having every access copied over the connection.
+//! Descriptors go with SCM_RIGHTS on Unix and DuplicateHandle on Windows; messages carrying them
+//! are framed the same way on both, a `MESSAGE_HEADER_LEN` byte header giving the data length and
+//! the number of descriptors. The length and count come from the other process, so the receiving
+//! end checks them against `MAX_MESSAGE_LEN` and `MAX_MESSAGE_DESCRIPTORS` before trusting them.
+
+use std::io;
+
+use crate::descriptor::AsRawDescriptor;
+use crate::ControlConnection;
+use crate::SharedMemory;
+
+pub(crate) const MESSAGE_HEADER_LEN: usize = 8;
+/// Most data one message with descriptors carries
+pub const MAX_MESSAGE_LEN: usize = 16 << 20;
+/// Most descriptors one message carries, what Unix fits in one SCM_RIGHTS message
+pub const MAX_MESSAGE_DESCRIPTORS: usize = 253;
+
+pub(crate) fn message_header(
+    len: usize,
+    descriptors: usize,
+) -> io::Result<[u8; MESSAGE_HEADER_LEN]> {
+    if len > MAX_MESSAGE_LEN || descriptors > MAX_MESSAGE_DESCRIPTORS {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "message too big"));
+    }
+    let len = len as u32;
+    let descriptors = descriptors as u32;
+    let mut header = [0u8; MESSAGE_HEADER_LEN];
+    header[..4].copy_from_slice(&len.to_le_bytes());
+    header[4..].copy_from_slice(&descriptors.to_le_bytes());
+    Ok(header)
+}
+
+/// (data length, descriptor count), an error if either is over the limit
+pub(crate) fn parse_message_header(header: &[u8; MESSAGE_HEADER_LEN]) -> io::Result<(usize, usize)> {
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let descriptors = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+    if len > MAX_MESSAGE_LEN || descriptors > MAX_MESSAGE_DESCRIPTORS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message of {} bytes and {} descriptors is over the limit", len, descriptors),
+        ));
+    }
+    Ok((len, descriptors))
+}
+
+impl ControlConnection {
+    /// Sends `data` along with `regions`, which the other end gets back from
+    /// `recv_shared_memory` as `SharedMemory` it can map. Anything that can back a mapping works,
+    /// `SharedMemory` (or a file on Unix).
+    pub fn send_shared_memory(
+        &mut self,
+        data: &[u8],
+        regions: &[&dyn SharedRegionSource],
+    ) -> io::Result<()> {
+        // sizes first, Windows can't tell how big a mapping handle is
+        let mut msg = Vec::with_capacity(8 * regions.len() + data.len());
+        for r in regions {
+            msg.extend_from_slice(&r.region_size().to_le_bytes());
+        }
+        msg.extend_from_slice(data);
+        let descriptors: Vec<_> = regions.iter().map(|r| r.as_raw_descriptor()).collect();
+        self.send_with_descriptors(&msg, &descriptors)
+    }
+
+    /// Receives a message sent with `send_shared_memory`.
+    pub fn recv_shared_memory(&mut self) -> io::Result<(Vec<u8>, Vec<SharedMemory>)> {
+        let (mut msg, descriptors) = self.recv_with_descriptors()?;
+        if msg.len() < 8 * descriptors.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "shared memory message too short",
+            ));
+        }
+        let data = msg.split_off(8 * descriptors.len());
+        let regions = descriptors
+            .into_iter()
+            .zip(msg.chunks_exact(8))
+            .map(|(desc, size)| {
+                let size = u64::from_le_bytes(size.try_into().unwrap());
+                SharedMemory::from_safe_descriptor(desc, Some(size)).map_err(io::Error::from)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok((data, regions))
+    }
+}
+
+/// Something `send_shared_memory` can send: a descriptor that can be mapped, and its size.
+pub trait SharedRegionSource: AsRawDescriptor {
+    fn region_size(&self) -> u64;
+}
+
+impl SharedRegionSource for SharedMemory {
+    fn region_size(&self) -> u64 {
+        self.size()
+    }
+}
+
+// Windows needs a file mapping handle, not the file
+#[cfg(unix)]
+impl SharedRegionSource for std::fs::File {
+    fn region_size(&self) -> u64 {
+        self.metadata().map(|m| m.len()).unwrap_or(0)
+    }
+}
@@ -6,6 +6,7 @@
 
 mod alloc;
 mod clock;
+mod control_transport;
 pub mod custom_serde;
 pub mod descriptor;
 pub mod descriptor_reflection;
@@ -27,6 +28,9 @@ pub use alloc::LayoutAllocation;
 
 pub use clock::Clock;
 pub use clock::FakeClock;
+pub use control_transport::SharedRegionSource;
+pub use control_transport::MAX_MESSAGE_DESCRIPTORS;
+pub use control_transport::MAX_MESSAGE_LEN;
 pub use errno::errno_result;
 pub use errno::Error;
 pub use errno::Result;
@@ -156,6 +160,8 @@ pub use platform::set_cpu_affinity;
 pub use platform::with_as_descriptor;
 pub use platform::with_raw_descriptor;
 pub use platform::BlockingMode;
+pub use platform::ControlConnection;
+pub use platform::ControlTransport;
 pub use platform::EventContext;
 pub use platform::FileSerdeWrapper;
 pub use platform::FramingMode;
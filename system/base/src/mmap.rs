@@ -196,6 +196,8 @@ impl MemoryMapping {
     /// The write operation will be volatile, i.e. it will not be reordered by
     /// the compiler and is suitable for I/O, but must be aligned. When writing
     /// to regular memory, prefer [`MemoryMapping::write_obj`].
+    /// Registers at unaligned offsets or with a fixed byte order can go through
+    /// [`VolatileMemory::get_slice`] and [`VolatileSlice::write_obj_le`] or `write_obj_be` instead.
     ///
     /// # Examples
     /// * Write a u32 at offset 16.
@@ -226,6 +228,8 @@ impl MemoryMapping {
     /// The read operation will be volatile, i.e. it will not be reordered by
     /// the compiler and is suitable for I/O, but must be aligned. When reading
     /// from regular memory, prefer [`MemoryMapping::read_obj`].
+    /// Registers at unaligned offsets or with a fixed byte order can go through
+    /// [`VolatileMemory::get_slice`] and [`VolatileSlice::read_obj_le`] or `read_obj_be` instead.
     ///
     /// # Examples
     /// * Read a u32 written to offset 16.
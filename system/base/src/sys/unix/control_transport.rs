@@ -0,0 +1,263 @@
+use std::io;
+use std::io::IoSlice;
+use std::io::IoSliceMut;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+
+use super::RawDescriptor;
+use super::ScmSocket;
+use super::SCM_SOCKET_MAX_FD_COUNT;
+use crate::control_transport::message_header;
+use crate::control_transport::parse_message_header;
+use crate::control_transport::MESSAGE_HEADER_LEN;
+use crate::descriptor::AsRawDescriptor;
+use crate::descriptor::FromRawDescriptor;
+use crate::descriptor::SafeDescriptor;
+
+/// Listening end of a control channel (monitor, debugger, backend processes). On Unix it's a Unix
+/// domain socket at the path given as the name, on Windows a named pipe of that name.
+pub struct ControlTransport {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ControlTransport {
+    /// Listens on `name`, replacing a socket left behind by an earlier run.
+    pub fn bind(name: &str) -> io::Result<ControlTransport> {
+        let path = PathBuf::from(name);
+        if let Ok(meta) = std::fs::symlink_metadata(&path) {
+            if meta.file_type().is_socket() {
+                std::fs::remove_file(&path)?;
+            }
+        }
+        Ok(ControlTransport {
+            listener: UnixListener::bind(&path)?,
+            path,
+        })
+    }
+
+    /// Waits for the next client.
+    pub fn accept(&self) -> io::Result<ControlConnection> {
+        let (stream, _) = self.listener.accept()?;
+        Ok(ControlConnection { stream })
+    }
+
+    /// With `nonblocking`, `accept` fails with `WouldBlock` instead of waiting for a client.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.listener.set_nonblocking(nonblocking)
+    }
+
+    pub fn name(&self) -> String {
+        self.path.display().to_string()
+    }
+}
+
+impl Drop for ControlTransport {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl AsRawDescriptor for ControlTransport {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.listener.as_raw_fd()
+    }
+}
+
+/// One client of a `ControlTransport`, a plain byte stream.
+pub struct ControlConnection {
+    stream: UnixStream,
+}
+
+impl ControlConnection {
+    /// Connects to the `ControlTransport` listening on `name`.
+    pub fn connect(name: &str) -> io::Result<ControlConnection> {
+        Ok(ControlConnection {
+            stream: UnixStream::connect(name)?,
+        })
+    }
+
+    /// The next byte without consuming it, `None` if nothing has arrived yet. Never blocks.
+    pub fn peek(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = 0u8;
+        let ret = handle_eintr_errno!(unsafe {
+            // Safe because we give the kernel one byte to write to and check the return value.
+            libc::recv(
+                self.stream.as_raw_fd(),
+                &mut byte as *mut u8 as *mut libc::c_void,
+                1,
+                libc::MSG_PEEK | libc::MSG_DONTWAIT,
+            )
+        });
+        match ret {
+            1 => Ok(Some(byte)),
+            0 => Err(io::ErrorKind::UnexpectedEof.into()),
+            _ => {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    Ok(None)
+                } else {
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    /// With `nonblocking`, reads and writes fail with `WouldBlock` instead of waiting.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.stream.set_nonblocking(nonblocking)
+    }
+
+    pub fn try_clone(&self) -> io::Result<ControlConnection> {
+        Ok(ControlConnection {
+            stream: self.stream.try_clone()?,
+        })
+    }
+
+    /// Sends `data` as one message with copies of `descriptors` attached (SCM_RIGHTS). The
+    /// other end gets both back from `recv_with_descriptors`.
+    pub fn send_with_descriptors(
+        &mut self,
+        data: &[u8],
+        descriptors: &[RawDescriptor],
+    ) -> io::Result<()> {
+        if descriptors.len() > SCM_SOCKET_MAX_FD_COUNT {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "too many descriptors for one message",
+            ));
+        }
+        let header = message_header(data.len(), descriptors.len())?;
+        let sent = self
+            .stream
+            .send_bufs_with_fds(&[IoSlice::new(&header), IoSlice::new(data)], descriptors)?;
+        // the descriptors went with the first byte, the rest is a plain stream
+        let mut msg = header.to_vec();
+        msg.extend_from_slice(data);
+        self.stream.write_all(&msg[sent..])
+    }
+
+    /// Receives a message sent with `send_with_descriptors`.
+    pub fn recv_with_descriptors(&mut self) -> io::Result<(Vec<u8>, Vec<SafeDescriptor>)> {
+        let mut header = [0u8; MESSAGE_HEADER_LEN];
+        let mut fds = [0 as RawDescriptor; SCM_SOCKET_MAX_FD_COUNT];
+        let (got, fd_count) = self
+            .stream
+            .recv_with_fds(IoSliceMut::new(&mut header), &mut fds)?;
+        // Safe because recvmsg just gave us these and nothing else owns them.
+        let descriptors: Vec<SafeDescriptor> = fds[..fd_count]
+            .iter()
+            .map(|fd| unsafe { SafeDescriptor::from_raw_descriptor(*fd) })
+            .collect();
+        if got == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.stream.read_exact(&mut header[got..])?;
+        let (len, count) = parse_message_header(&header)?;
+        if count != descriptors.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected {} descriptors, got {}", count, descriptors.len()),
+            ));
+        }
+        let mut data = vec![0; len];
+        self.stream.read_exact(&mut data)?;
+        Ok((data, descriptors))
+    }
+}
+
+impl Read for ControlConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for ControlConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl AsRawDescriptor for ControlConnection {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.stream.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_transport_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let name = dir.path().join("ctl").display().to_string();
+        let transport = ControlTransport::bind(&name).unwrap();
+        let mut client = ControlConnection::connect(&name).unwrap();
+        let mut server = transport.accept().unwrap();
+
+        assert_eq!(server.peek().unwrap(), None);
+        client.write_all(b"hi").unwrap();
+        let mut buf = [0u8; 2];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hi");
+
+        drop(client);
+        assert!(server.peek().is_err());
+    }
+
+    #[test]
+    fn control_transport_shared_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let name = dir.path().join("ctl").display().to_string();
+        let transport = ControlTransport::bind(&name).unwrap();
+        let mut client = ControlConnection::connect(&name).unwrap();
+        let mut server = transport.accept().unwrap();
+
+        let shm = crate::SharedMemory::new("ctl_test", 4096).unwrap();
+        let mapping = crate::MemoryMappingBuilder::new(4096)
+            .from_shared_memory(&shm)
+            .build()
+            .unwrap();
+        mapping.write_obj(0x1234u32, 16).unwrap();
+        client.send_shared_memory(b"ram", &[&shm]).unwrap();
+
+        let (data, regions) = server.recv_shared_memory().unwrap();
+        assert_eq!(data, b"ram");
+        assert_eq!(regions.len(), 1);
+        assert_eq!(regions[0].size(), 4096);
+        let theirs = crate::MemoryMappingBuilder::new(4096)
+            .from_shared_memory(&regions[0])
+            .build()
+            .unwrap();
+        assert_eq!(theirs.read_obj::<u32>(16).unwrap(), 0x1234);
+    }
+
+    #[test]
+    fn control_transport_rejects_huge_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let name = dir.path().join("ctl").display().to_string();
+        let transport = ControlTransport::bind(&name).unwrap();
+        let mut client = ControlConnection::connect(&name).unwrap();
+        let mut server = transport.accept().unwrap();
+
+        // a peer claiming 4 GiB of data doesn't get us to allocate it
+        let mut header = [0u8; MESSAGE_HEADER_LEN];
+        header[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        client.write_all(&header).unwrap();
+        let err = server.recv_with_descriptors().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let big = vec![0u8; crate::MAX_MESSAGE_LEN + 1];
+        assert!(client.send_with_descriptors(&big, &[]).is_err());
+    }
+}
@@ -9,7 +9,7 @@ use std::{
     cmp::min,
     io,
     mem::size_of,
-    ptr::{copy_nonoverlapping, null_mut, read_unaligned, write_unaligned},
+    ptr::{copy_nonoverlapping, null_mut},
 };
 use crate::MappedRegion;
 
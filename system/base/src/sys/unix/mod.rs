@@ -21,6 +21,7 @@ pub mod ioctl;
 pub mod syslog;
 mod acpi_event;
 mod capabilities;
+mod control_transport;
 mod descriptor;
 mod event;
 mod file;
@@ -67,6 +68,7 @@ use std::time::Duration;
 
 pub use acpi_event::*;
 pub use capabilities::drop_capabilities;
+pub use control_transport::*;
 pub use descriptor::*;
 pub use event::EventExt;
 pub(crate) use event::PlatformEvent;
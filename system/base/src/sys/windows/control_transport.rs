@@ -0,0 +1,257 @@
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::sync::Mutex;
+
+use win_util::duplicate_handle_with_target_pid;
+use winapi::shared::minwindef::BOOL;
+use winapi::shared::minwindef::ULONG;
+use winapi::um::winbase::GetNamedPipeClientProcessId;
+use winapi::um::winbase::GetNamedPipeServerProcessId;
+use winapi::um::winnt::HANDLE;
+
+use super::named_pipes;
+use super::named_pipes::BlockingMode;
+use super::named_pipes::FramingMode;
+use super::named_pipes::OverlappedWrapper;
+use super::named_pipes::PipeConnection;
+use super::RawDescriptor;
+use crate::control_transport::message_header;
+use crate::control_transport::parse_message_header;
+use crate::control_transport::MESSAGE_HEADER_LEN;
+use crate::descriptor::AsRawDescriptor;
+use crate::descriptor::FromRawDescriptor;
+use crate::descriptor::SafeDescriptor;
+
+/// Listening end of a control channel (monitor, debugger, backend processes). On Windows it's a
+/// named pipe, `\\.\pipe\NAME` unless the name is a full pipe path already. One client at a time:
+/// the previous connection has to be dropped before `accept` can hand out the next one.
+pub struct ControlTransport {
+    name: String,
+    // the pipe instance the next client connects to
+    next: Mutex<Option<PipeConnection>>,
+}
+
+fn pipe_path(name: &str) -> String {
+    if name.starts_with(r"\\") {
+        name.to_string()
+    } else {
+        format!(r"\\.\pipe\{}", name)
+    }
+}
+
+fn server_pipe(name: &str) -> io::Result<PipeConnection> {
+    named_pipes::create_server_pipe(
+        name,
+        &FramingMode::Byte,
+        &BlockingMode::Wait,
+        /* timeout= */ 0,
+        named_pipes::DEFAULT_BUFFER_SIZE,
+        /* overlapped= */ true,
+    )
+}
+
+impl ControlTransport {
+    /// Creates the pipe, failing if something else already serves `name`.
+    pub fn bind(name: &str) -> io::Result<ControlTransport> {
+        let name = pipe_path(name);
+        let first = server_pipe(&name)?;
+        Ok(ControlTransport {
+            name,
+            next: Mutex::new(Some(first)),
+        })
+    }
+
+    /// Waits for the next client.
+    pub fn accept(&self) -> io::Result<ControlConnection> {
+        let mut pipe = match self.next.lock().unwrap().take() {
+            Some(p) => p,
+            None => server_pipe(&self.name)?,
+        };
+        pipe.wait_for_client_connection_overlapped_blocking()?;
+        let peer_pid = peer_pid(&pipe, GetNamedPipeClientProcessId)?;
+        Ok(ControlConnection {
+            pipe,
+            peeked: None,
+            peer_pid,
+        })
+    }
+
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+fn peer_pid(
+    pipe: &PipeConnection,
+    query: unsafe extern "system" fn(HANDLE, *mut ULONG) -> BOOL,
+) -> io::Result<u32> {
+    let mut pid: ULONG = 0;
+    // Safe because the pipe handle is valid and we check the return value.
+    if unsafe { query(pipe.as_raw_descriptor() as HANDLE, &mut pid) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(pid)
+}
+
+/// One client of a `ControlTransport`, a plain byte stream. The pipe is opened overlapped, so a
+/// `try_clone` can write while a read on the original is still waiting for input.
+pub struct ControlConnection {
+    pipe: PipeConnection,
+    // named pipes can only peek into a buffer we'd have to keep anyway
+    peeked: Option<u8>,
+    // handles we send are duplicated into this process
+    peer_pid: u32,
+}
+
+impl ControlConnection {
+    /// Connects to the `ControlTransport` serving `name`.
+    pub fn connect(name: &str) -> io::Result<ControlConnection> {
+        let pipe = named_pipes::create_client_pipe(
+            &pipe_path(name),
+            &FramingMode::Byte,
+            &BlockingMode::Wait,
+            /* overlapped= */ true,
+        )?;
+        let peer_pid = peer_pid(&pipe, GetNamedPipeServerProcessId)?;
+        Ok(ControlConnection {
+            pipe,
+            peeked: None,
+            peer_pid,
+        })
+    }
+
+    /// The next byte without consuming it, `None` if nothing has arrived yet. Never blocks.
+    pub fn peek(&mut self) -> io::Result<Option<u8>> {
+        if self.peeked.is_none() && self.pipe.get_available_byte_count()? > 0 {
+            let mut byte = [0u8];
+            if self.read_pipe(&mut byte)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.peeked = Some(byte[0]);
+        }
+        Ok(self.peeked)
+    }
+
+    /// Another handle on the same connection, with its own cancellation (`CancelIoEx` only hits
+    /// I/O started through the handle it's given). A byte `peek`ed through one stays with that one.
+    pub fn try_clone(&self) -> io::Result<ControlConnection> {
+        Ok(ControlConnection {
+            pipe: self.pipe.try_clone()?,
+            peeked: None,
+            peer_pid: self.peer_pid,
+        })
+    }
+
+    // the pipe is overlapped, so these start the operation and wait for it right away
+    fn read_pipe(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut overlapped = OverlappedWrapper::new(/* include_event= */ true)?;
+        // Safe because any bytes make valid u8s, and `buf` outlives the read since we wait for it.
+        unsafe { self.pipe.read_overlapped(buf, &mut overlapped)? };
+        Ok(self.pipe.get_overlapped_result(&mut overlapped)? as usize)
+    }
+
+    fn write_pipe(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut overlapped = OverlappedWrapper::new(/* include_event= */ true)?;
+        self.pipe.write_overlapped(buf, &mut overlapped)?;
+        Ok(self.pipe.get_overlapped_result(&mut overlapped)? as usize)
+    }
+
+    /// Sends `data` as one message along with `descriptors`, which are duplicated straight into
+    /// the other process (DuplicateHandle) and sent as handle values. The other end gets both back
+    /// from `recv_with_descriptors`.
+    pub fn send_with_descriptors(
+        &mut self,
+        data: &[u8],
+        descriptors: &[RawDescriptor],
+    ) -> io::Result<()> {
+        let mut msg = message_header(data.len(), descriptors.len())?.to_vec();
+        for desc in descriptors {
+            // if the write below fails these stay open over there, nothing we can do about it
+            let theirs = duplicate_handle_with_target_pid(*desc, self.peer_pid)?;
+            msg.extend_from_slice(&(theirs as u64).to_le_bytes());
+        }
+        msg.extend_from_slice(data);
+        self.write_all(&msg)
+    }
+
+    /// Receives a message sent with `send_with_descriptors`.
+    pub fn recv_with_descriptors(&mut self) -> io::Result<(Vec<u8>, Vec<SafeDescriptor>)> {
+        let mut header = [0u8; MESSAGE_HEADER_LEN];
+        self.read_exact(&mut header)?;
+        let (len, count) = parse_message_header(&header)?;
+        let mut descriptors = Vec::with_capacity(count);
+        for _ in 0..count {
+            let mut handle = [0u8; 8];
+            self.read_exact(&mut handle)?;
+            // Safe because the sender duplicated the handle into this process for us to own.
+            descriptors.push(unsafe {
+                SafeDescriptor::from_raw_descriptor(u64::from_le_bytes(handle) as RawDescriptor)
+            });
+        }
+        let mut data = vec![0; len];
+        self.read_exact(&mut data)?;
+        Ok((data, descriptors))
+    }
+}
+
+impl Read for ControlConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.peeked.take() {
+            Some(b) if !buf.is_empty() => {
+                buf[0] = b;
+                Ok(1)
+            }
+            peeked => {
+                self.peeked = peeked;
+                self.read_pipe(buf)
+            }
+        }
+    }
+}
+
+impl Write for ControlConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_pipe(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.pipe.flush()
+    }
+}
+
+impl AsRawDescriptor for ControlConnection {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.pipe.as_raw_descriptor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn write_while_read_waits() {
+        let name = format!("turbo-control-test-{}", std::process::id());
+        let transport = ControlTransport::bind(&name).unwrap();
+        let client = thread::spawn(move || ControlConnection::connect(&name).unwrap());
+        let mut server = transport.accept().unwrap();
+        let mut client = client.join().unwrap();
+
+        let mut writer = server.try_clone().unwrap();
+        // nothing's coming until the client has seen our write, which this read mustn't hold up
+        let reader = thread::spawn(move || {
+            let mut buf = [0u8; 4];
+            server.read_exact(&mut buf).unwrap();
+            buf
+        });
+        writer.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        client.write_all(b"pong").unwrap();
+        assert_eq!(&reader.join().unwrap(), b"pong");
+    }
+}
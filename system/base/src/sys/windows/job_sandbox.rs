@@ -0,0 +1,90 @@
+use std::io;
+use std::mem;
+use std::ptr;
+
+use winapi::shared::minwindef::DWORD;
+use winapi::shared::minwindef::FALSE;
+use winapi::um::jobapi2::AssignProcessToJobObject;
+use winapi::um::jobapi2::CreateJobObjectW;
+use winapi::um::jobapi2::SetInformationJobObject;
+use winapi::um::processthreadsapi::GetCurrentProcess;
+use winapi::um::winnt::JobObjectBasicUIRestrictions;
+use winapi::um::winnt::JobObjectExtendedLimitInformation;
+use winapi::um::winnt::JOBOBJECT_BASIC_UI_RESTRICTIONS;
+use winapi::um::winnt::JOBOBJECT_EXTENDED_LIMIT_INFORMATION;
+use winapi::um::winnt::JOB_OBJECT_LIMIT_ACTIVE_PROCESS;
+use winapi::um::winnt::JOB_OBJECT_LIMIT_DIE_ON_UNHANDLED_EXCEPTION;
+use winapi::um::winnt::JOB_OBJECT_UILIMIT_DESKTOP;
+use winapi::um::winnt::JOB_OBJECT_UILIMIT_DISPLAYSETTINGS;
+use winapi::um::winnt::JOB_OBJECT_UILIMIT_EXITWINDOWS;
+use winapi::um::winnt::JOB_OBJECT_UILIMIT_GLOBALATOMS;
+use winapi::um::winnt::JOB_OBJECT_UILIMIT_HANDLES;
+use winapi::um::winnt::JOB_OBJECT_UILIMIT_READCLIPBOARD;
+use winapi::um::winnt::JOB_OBJECT_UILIMIT_SYSTEMPARAMETERS;
+use winapi::um::winnt::JOB_OBJECT_UILIMIT_WRITECLIPBOARD;
+
+use crate::descriptor::AsRawDescriptor;
+use crate::descriptor::FromRawDescriptor;
+use crate::descriptor::SafeDescriptor;
+
+/// The Windows counterpart of the seccomp sandbox: put this process in a job object that can't
+/// start other processes, touch the desktop, the clipboard or system settings, or sit in a crash
+/// dialog. It's for good, the job handle is leaked so it stays around as long as we do.
+pub fn restrict_current_process() -> io::Result<()> {
+    // Safe because the name and security attributes are optional and the handle is checked.
+    let job = unsafe { CreateJobObjectW(ptr::null_mut(), ptr::null()) };
+    if job.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because job is a handle we own from here on.
+    let job = unsafe { SafeDescriptor::from_raw_descriptor(job) };
+    let job_handle = job.as_raw_descriptor();
+
+    // Safe because the struct is plain data and all zeros means no limits.
+    let mut limits: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = unsafe { mem::zeroed() };
+    limits.BasicLimitInformation.LimitFlags =
+        JOB_OBJECT_LIMIT_ACTIVE_PROCESS | JOB_OBJECT_LIMIT_DIE_ON_UNHANDLED_EXCEPTION;
+    limits.BasicLimitInformation.ActiveProcessLimit = 1;
+    // Safe because limits lives through the call and the size is its own.
+    if unsafe {
+        SetInformationJobObject(
+            job_handle,
+            JobObjectExtendedLimitInformation,
+            &mut limits as *mut _ as *mut _,
+            mem::size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as DWORD,
+        )
+    } == FALSE
+    {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut ui = JOBOBJECT_BASIC_UI_RESTRICTIONS {
+        UIRestrictionsClass: JOB_OBJECT_UILIMIT_DESKTOP
+            | JOB_OBJECT_UILIMIT_DISPLAYSETTINGS
+            | JOB_OBJECT_UILIMIT_EXITWINDOWS
+            | JOB_OBJECT_UILIMIT_GLOBALATOMS
+            | JOB_OBJECT_UILIMIT_HANDLES
+            | JOB_OBJECT_UILIMIT_READCLIPBOARD
+            | JOB_OBJECT_UILIMIT_SYSTEMPARAMETERS
+            | JOB_OBJECT_UILIMIT_WRITECLIPBOARD,
+    };
+    // Safe because ui lives through the call and the size is its own.
+    if unsafe {
+        SetInformationJobObject(
+            job_handle,
+            JobObjectBasicUIRestrictions,
+            &mut ui as *mut _ as *mut _,
+            mem::size_of::<JOBOBJECT_BASIC_UI_RESTRICTIONS>() as DWORD,
+        )
+    } == FALSE
+    {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safe because both handles are valid, the process one is a pseudo handle.
+    if unsafe { AssignProcessToJobObject(job_handle, GetCurrentProcess()) } == FALSE {
+        return Err(io::Error::last_os_error());
+    }
+    mem::forget(job);
+    Ok(())
+}
@@ -7,7 +7,7 @@ use std::{
     cmp::min,
     io::{self, Read, Write},
     mem::size_of,
-    ptr::{copy_nonoverlapping, read_unaligned, write_unaligned},
+    ptr::copy_nonoverlapping,
 };
 
 use crate::descriptor::{FromRawDescriptor, SafeDescriptor};
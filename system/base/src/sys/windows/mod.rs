@@ -14,12 +14,14 @@ pub mod ioctl;
 #[macro_use]
 pub mod syslog;
 mod console;
+mod control_transport;
 mod descriptor;
 mod event;
 mod events;
 pub mod file_traits;
 mod get_filesystem_type;
 mod gmtime;
+mod job_sandbox;
 mod mmap;
 mod mmap_platform;
 pub mod named_pipes;
@@ -45,12 +47,14 @@ pub use crate::descriptor_reflection::{
 };
 pub use crate::errno::{Error, Result, *};
 pub use console::*;
+pub use control_transport::*;
 pub use descriptor::*;
 pub use event::*;
 pub use events::*;
 pub use get_filesystem_type::*;
 pub use gmtime::*;
 pub use ioctl::*;
+pub use job_sandbox::*;
 pub use mmap::*;
 pub use priority::*;
 pub use sched::*;
@@ -720,6 +720,32 @@ impl PipeConnection {
         Ok(())
     }
 
+    /// Same as `wait_for_client_connection`, for a server pipe opened in overlapped mode (where
+    /// `ConnectNamedPipe` can't be given a null `OVERLAPPED`).
+    pub fn wait_for_client_connection_overlapped_blocking(&mut self) -> Result<()> {
+        let mut overlapped_wrapper = OverlappedWrapper::new(/* include_event= */ true)?;
+        // Safe because the handle is valid, the OVERLAPPED struct stays put until the operation
+        // completes (we wait for it below) and we check the return code.
+        unsafe {
+            let success_flag = ConnectNamedPipe(
+                self.as_raw_descriptor(),
+                &mut *overlapped_wrapper.overlapped,
+            );
+            if success_flag == 0 {
+                match GetLastError() {
+                    ERROR_PIPE_CONNECTED => return Ok(()),
+                    ERROR_IO_PENDING => {}
+                    _ => return Err(io::Error::last_os_error()),
+                }
+            } else {
+                return Ok(());
+            }
+        }
+        overlapped_wrapper.in_use = true;
+        self.get_overlapped_result(&mut overlapped_wrapper)?;
+        Ok(())
+    }
+
     /// Used for overlapped read and write operations.
     ///
     /// This will block until the ReadFile or WriteFile operation that also took in
@@ -49,7 +49,7 @@
 //!
 //! [log-crate-url]: https://docs.rs/log/
 
-use std::{fmt::Display, io, io::Write};
+use std::{fmt::Display, io, io::Write, sync::RwLock};
 
 use chrono::Local;
 use once_cell::sync::OnceCell;
@@ -185,9 +185,22 @@ pub(crate) trait Syslog {
     ) -> Result<(Option<Box<dyn Log + Send>>, Option<RawDescriptor>), Error>;
 }
 
-pub(crate) struct State {
-    // Record filter
+struct FilterState {
+    spec: String,
     filter: env_logger::filter::Filter,
+}
+fn build_filter(spec: &str) -> FilterState {
+    let mut builder = env_logger::filter::Builder::new();
+    builder.parse(spec);
+    FilterState {
+        spec: spec.to_string(),
+        filter: builder.build(),
+    }
+}
+
+pub(crate) struct State {
+    // Record filter, replaceable at runtime
+    filter: RwLock<FilterState>,
     // All the loggers we have
     loggers: Vec<Box<dyn Log + Send>>,
     // Raw Descriptors to preserve
@@ -244,9 +257,7 @@ impl State {
     {
         let mut loggers: Vec<Box<dyn Log + Send>> = vec![];
         let mut descriptors = vec![];
-        let mut builder = env_logger::filter::Builder::new();
-        builder.parse(cfg.filter);
-        let filter = builder.build();
+        let filter = RwLock::new(build_filter(cfg.filter));
 
         let create_formatted_builder = || {
             let mut builder = env_logger::Builder::new();
@@ -305,6 +316,15 @@ impl State {
             descriptors,
         })
     }
+
+    fn set_filter(&self, spec: &str) {
+        *self.filter.write().unwrap_or_else(|e| e.into_inner()) = build_filter(spec);
+    }
+
+    /// The most verbose level anything in the filter lets through
+    fn max_level(&self) -> log::LevelFilter {
+        self.filter.read().unwrap_or_else(|e| e.into_inner()).filter.filter()
+    }
 }
 
 static STATE: OnceCell<State> = OnceCell::new();
@@ -365,9 +385,23 @@ where
     apply_logging_state(state?);
     Ok(())
 }
+/// Replaces the filter given to `init_with`, same syntax. For changing log levels while running.
+pub fn set_filter(filter: &str) -> Result<(), Error> {
+    let state = STATE.get().ok_or(Error::NeverInitialized)?;
+    state.set_filter(filter);
+    log::set_max_level(state.max_level());
+    Ok(())
+}
+/// The filter in use, None before `init_with`
+pub fn filter() -> Option<String> {
+    STATE
+        .get()
+        .map(|s| s.filter.read().unwrap_or_else(|e| e.into_inner()).spec.clone())
+}
 fn apply_logging_state(state: &'static State) {
     let _ = log::set_logger(state);
-    log::set_max_level(log::LevelFilter::Trace);
+    // the log macros drop anything above this before they get to the filter and its lock
+    log::set_max_level(state.max_level());
 }
 
 
@@ -386,11 +420,12 @@ pub fn push_descriptors(fds: &mut Vec<RawDescriptor>) {
 
 impl Log for State {
     fn enabled(&self, metadata: &log::Metadata) -> bool {
-        self.filter.enabled(metadata)
+        self.filter.read().unwrap_or_else(|e| e.into_inner()).filter.enabled(metadata)
     }
 
     fn log(&self, record: &log::Record) {
-        if self.filter.matches(record) {
+        let matches = self.filter.read().unwrap_or_else(|e| e.into_inner()).filter.matches(record);
+        if matches {
             for logger in self.loggers.iter() {
                 logger.log(record)
             }
@@ -753,4 +788,30 @@ mod tests {
                 .metadata(),
         ));
     }
+
+    #[test]
+    fn set_filter_should_replace_the_old_one() {
+        let state = State::new(LogConfig {
+            filter: "info",
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(state.max_level(), log::LevelFilter::Info);
+        state.set_filter("info,test=debug");
+        assert_eq!(state.max_level(), log::LevelFilter::Debug);
+
+        assert!(state.enabled(
+            log::RecordBuilder::new()
+                .level(Level::Debug)
+                .target("test")
+                .build()
+                .metadata(),
+        ));
+        assert!(!state.enabled(
+            log::RecordBuilder::new()
+                .level(Level::Debug)
+                .build()
+                .metadata(),
+        ));
+    }
 }
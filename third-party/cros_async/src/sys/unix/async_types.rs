@@ -5,12 +5,15 @@
 use std::io;
 use std::ops::Deref;
 
+use base::ControlConnection;
+use base::ControlTransport;
 use base::Tube;
 use base::TubeResult;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
 use crate::Executor;
+use crate::IntoAsync;
 use crate::IoSourceExt;
 
 pub struct AsyncTube {
@@ -47,3 +50,61 @@ impl From<AsyncTube> for Tube {
         at.inner.into_source()
     }
 }
+
+impl IntoAsync for ControlTransport {}
+impl IntoAsync for ControlConnection {}
+
+pub struct AsyncControlTransport {
+    inner: Box<dyn IoSourceExt<ControlTransport>>,
+}
+
+impl AsyncControlTransport {
+    pub fn new(ex: &Executor, transport: ControlTransport) -> io::Result<AsyncControlTransport> {
+        transport.set_nonblocking(true)?;
+        Ok(AsyncControlTransport {
+            inner: ex.async_from(transport)?,
+        })
+    }
+
+    /// Waits for the next client. The connection is a blocking one, hand it to
+    /// `AsyncControlConnection::new` to use it here.
+    pub async fn accept(&self) -> io::Result<ControlConnection> {
+        loop {
+            match self.inner.as_source().accept() {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.inner.wait_readable().await?
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+pub struct AsyncControlConnection {
+    inner: Box<dyn IoSourceExt<ControlConnection>>,
+}
+
+impl AsyncControlConnection {
+    pub fn new(ex: &Executor, conn: ControlConnection) -> io::Result<AsyncControlConnection> {
+        conn.set_nonblocking(true)?;
+        Ok(AsyncControlConnection {
+            inner: ex.async_from(conn)?,
+        })
+    }
+
+    /// Reads into `buf`, returning it with the number of bytes read. 0 means the client is gone.
+    pub async fn read(&self, buf: Vec<u8>) -> io::Result<(usize, Vec<u8>)> {
+        Ok(self.inner.read_to_vec(None, buf).await?)
+    }
+
+    /// Writes some of `buf`, returning it with the number of bytes written.
+    pub async fn write(&self, buf: Vec<u8>) -> io::Result<(usize, Vec<u8>)> {
+        Ok(self.inner.write_from_vec(None, buf).await?)
+    }
+}
+
+impl From<AsyncControlConnection> for ControlConnection {
+    fn from(ac: AsyncControlConnection) -> ControlConnection {
+        ac.inner.into_source()
+    }
+}
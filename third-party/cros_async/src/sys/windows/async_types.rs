@@ -3,10 +3,14 @@
 // found in the LICENSE file.
 
 use std::io;
+use std::io::Read;
+use std::io::Write;
 use std::sync::Arc;
 use std::sync::Mutex;
 
 use base::AsRawDescriptor;
+use base::ControlConnection;
+use base::ControlTransport;
 use base::Descriptor;
 use base::Tube;
 use base::TubeError;
@@ -67,3 +71,91 @@ impl From<AsyncTube> for Tube {
         Arc::try_unwrap(at.inner).unwrap().into_inner().unwrap()
     }
 }
+
+fn cancelled() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "operation cancelled")
+}
+
+/// Blocking futures like `AsyncTube`, the pipes aren't opened overlapped.
+pub struct AsyncControlTransport {
+    inner: Arc<ControlTransport>,
+}
+
+impl AsyncControlTransport {
+    pub fn new(_ex: &Executor, transport: ControlTransport) -> io::Result<AsyncControlTransport> {
+        Ok(AsyncControlTransport {
+            inner: Arc::new(transport),
+        })
+    }
+
+    /// Waits for the next client. Hand the connection to `AsyncControlConnection::new` to use it
+    /// here. Cancelling leaves the worker waiting until a client does show up.
+    pub async fn accept(&self) -> io::Result<ControlConnection> {
+        let transport = Arc::clone(&self.inner);
+        unblock(move || transport.accept(), move || Err(cancelled())).await
+    }
+}
+
+/// Reads and writes go through separate handles on the (overlapped) pipe, so a read waiting for
+/// input doesn't hold up writes, and cancelling one doesn't cancel the other.
+pub struct AsyncControlConnection {
+    reader: Arc<Mutex<ControlConnection>>,
+    writer: Arc<Mutex<ControlConnection>>,
+    // taken up front, the mutexes are held for as long as an operation runs
+    read_handle: Descriptor,
+    write_handle: Descriptor,
+}
+
+impl AsyncControlConnection {
+    pub fn new(_ex: &Executor, conn: ControlConnection) -> io::Result<AsyncControlConnection> {
+        let writer = conn.try_clone()?;
+        Ok(AsyncControlConnection {
+            read_handle: Descriptor(conn.as_raw_descriptor()),
+            write_handle: Descriptor(writer.as_raw_descriptor()),
+            reader: Arc::new(Mutex::new(conn)),
+            writer: Arc::new(Mutex::new(writer)),
+        })
+    }
+
+    /// Reads into `buf`, returning it with the number of bytes read. 0 means the client is gone.
+    pub async fn read(&self, mut buf: Vec<u8>) -> io::Result<(usize, Vec<u8>)> {
+        let conn = Arc::clone(&self.reader);
+        let handles = HandleWrapper::new(vec![self.read_handle]);
+        unblock(
+            move || {
+                let n = conn.lock().unwrap().read(&mut buf)?;
+                Ok((n, buf))
+            },
+            move || Err(handles.lock().cancel_sync_io(cancelled())),
+        )
+        .await
+    }
+
+    /// Writes some of `buf`, returning it with the number of bytes written.
+    pub async fn write(&self, buf: Vec<u8>) -> io::Result<(usize, Vec<u8>)> {
+        let conn = Arc::clone(&self.writer);
+        let handles = HandleWrapper::new(vec![self.write_handle]);
+        unblock(
+            move || {
+                let n = conn.lock().unwrap().write(&buf)?;
+                Ok((n, buf))
+            },
+            move || Err(handles.lock().cancel_sync_io(cancelled())),
+        )
+        .await
+    }
+}
+
+impl From<AsyncControlConnection> for ControlConnection {
+    fn from(ac: AsyncControlConnection) -> ControlConnection {
+        // same as AsyncTube, waits for the workers to let go. The write half is only a second
+        // handle, dropping it leaves the connection open.
+        std::mem::drop(ac.writer.lock().unwrap());
+        std::mem::drop(ac.reader.lock().unwrap());
+        Arc::try_unwrap(ac.reader)
+            .ok()
+            .unwrap()
+            .into_inner()
+            .unwrap()
+    }
+}
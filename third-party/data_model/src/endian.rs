@@ -120,6 +120,38 @@ endian_type!(i64, SBe64, to_be, from_be);
 endian_type!(usize, BeSize, to_be, from_be);
 endian_type!(isize, SBeSize, to_be, from_be);
 
+/// Plain integers that can be converted from and to a given byte order, for reading fields out of
+/// memory whose layout is fixed regardless of the host (device registers, on-disk formats).
+pub trait ByteOrdered: DataInit {
+    fn from_le(v: Self) -> Self;
+    fn from_be(v: Self) -> Self;
+    fn to_le(self) -> Self;
+    fn to_be(self) -> Self;
+}
+
+macro_rules! byte_ordered {
+    ($($T:ident),*) => {
+        $(
+            impl ByteOrdered for $T {
+                fn from_le(v: Self) -> Self {
+                    $T::from_le(v)
+                }
+                fn from_be(v: Self) -> Self {
+                    $T::from_be(v)
+                }
+                fn to_le(self) -> Self {
+                    $T::to_le(self)
+                }
+                fn to_be(self) -> Self {
+                    $T::to_be(self)
+                }
+            }
+        )*
+    };
+}
+
+byte_ordered!(u8, i8, u16, i16, u32, i32, u64, i64, usize, isize);
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -21,7 +21,7 @@
 
 use std::cmp::min;
 use std::marker::PhantomData;
-use std::mem::size_of;
+use std::mem::{align_of, size_of, MaybeUninit};
 use std::ptr::{copy, read_volatile, write_bytes, write_volatile};
 use std::result;
 use std::slice;
@@ -30,7 +30,7 @@ use std::usize;
 use remain::sorted;
 use thiserror::Error;
 
-use crate::{sys::IoBufMut, DataInit};
+use crate::{sys::IoBufMut, ByteOrdered, DataInit};
 
 #[sorted]
 #[derive(Error, Eq, PartialEq, Debug)]
@@ -308,6 +308,91 @@ impl<'a> VolatileSlice<'a> {
             }
         }
     }
+
+    /// Reads a `T` at `offset` bytes into this slice, which doesn't have to be aligned for `T`.
+    ///
+    /// An aligned `T` is read with a single volatile access, so registers that care about access
+    /// width see the one they expect. Otherwise it's read a byte at a time, from smallest to
+    /// largest address.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use data_model::VolatileSlice;
+    /// let mut mem = [0u8, 0x78, 0x56, 0x34, 0x12];
+    /// let vslice = VolatileSlice::new(&mut mem[..]);
+    /// let v: [u8; 2] = vslice.read_obj(3).unwrap();
+    /// assert_eq!(v, [0x34, 0x12]);
+    /// assert!(vslice.read_obj::<u32>(2).is_err());
+    /// ```
+    pub fn read_obj<T: DataInit>(&self, offset: usize) -> Result<T> {
+        let slice = self.sub_slice(offset, size_of::<T>())?;
+        let src = slice.as_mut_ptr() as *const u8;
+        if src as usize % align_of::<T>() == 0 {
+            // Safe because the slice is big enough for a T and T is aligned here.
+            return Ok(unsafe { read_volatile(src as *const T) });
+        }
+        let mut v = MaybeUninit::<T>::zeroed();
+        let dst = v.as_mut_ptr() as *mut u8;
+        // Safe because both sides are size_of::<T>() bytes, and DataInit says any bytes make a
+        // valid T.
+        unsafe {
+            for i in 0..size_of::<T>() {
+                *dst.add(i) = read_volatile(src.add(i));
+            }
+            Ok(v.assume_init())
+        }
+    }
+
+    /// Writes `val` at `offset` bytes into this slice, which doesn't have to be aligned for `T`.
+    /// Same access rules as [`VolatileSlice::read_obj`].
+    pub fn write_obj<T: DataInit>(&self, val: T, offset: usize) -> Result<()> {
+        let slice = self.sub_slice(offset, size_of::<T>())?;
+        let dst = slice.as_mut_ptr();
+        // Safe because the slice is big enough for a T, and it's either aligned or written a
+        // byte at a time.
+        unsafe {
+            if dst as usize % align_of::<T>() == 0 {
+                write_volatile(dst as *mut T, val);
+            } else {
+                let src = &val as *const T as *const u8;
+                for i in 0..size_of::<T>() {
+                    write_volatile(dst.add(i), *src.add(i));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a little endian `T` at `offset`, at any alignment.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use data_model::VolatileSlice;
+    /// let mut mem = [0u8, 0x78, 0x56, 0x34, 0x12];
+    /// let vslice = VolatileSlice::new(&mut mem[..]);
+    /// assert_eq!(vslice.read_obj_le::<u32>(1).unwrap(), 0x12345678);
+    /// assert_eq!(vslice.read_obj_be::<u16>(1).unwrap(), 0x7856);
+    /// ```
+    pub fn read_obj_le<T: ByteOrdered>(&self, offset: usize) -> Result<T> {
+        self.read_obj(offset).map(T::from_le)
+    }
+
+    /// Reads a big endian `T` at `offset`, at any alignment.
+    pub fn read_obj_be<T: ByteOrdered>(&self, offset: usize) -> Result<T> {
+        self.read_obj(offset).map(T::from_be)
+    }
+
+    /// Writes `val` as little endian at `offset`, at any alignment.
+    pub fn write_obj_le<T: ByteOrdered>(&self, val: T, offset: usize) -> Result<()> {
+        self.write_obj(val.to_le(), offset)
+    }
+
+    /// Writes `val` as big endian at `offset`, at any alignment.
+    pub fn write_obj_be<T: ByteOrdered>(&self, val: T, offset: usize) -> Result<()> {
+        self.write_obj(val.to_be(), offset)
+    }
 }
 
 impl<'a> VolatileMemory for VolatileSlice<'a> {
@@ -551,6 +636,29 @@ mod tests {
         assert_eq!(res, Error::OutOfBounds { addr: 101 });
     }
 
+    #[test]
+    fn obj_unaligned() {
+        let mut a = [0u8; 11];
+        let s = VolatileSlice::new(&mut a[..]);
+        for off in 0..4 {
+            s.write_obj_le(0x0102_0304_0506_0708u64, off).unwrap();
+            assert_eq!(s.read_obj_le::<u64>(off).unwrap(), 0x0102_0304_0506_0708);
+            assert_eq!(s.read_obj_be::<u64>(off).unwrap(), 0x0807_0605_0403_0201);
+            assert_eq!(s.read_obj::<u8>(off).unwrap(), 0x08);
+        }
+        s.write_obj_be(0xabcdu16, 9).unwrap();
+        assert_eq!(a[9..], [0xab, 0xcd]);
+    }
+
+    #[test]
+    fn obj_oob_error() {
+        let a = VecMem::new(8);
+        let s = a.get_slice(0, 8).unwrap();
+        let res = s.read_obj_le::<u32>(5).unwrap_err();
+        assert_eq!(res, Error::OutOfBounds { addr: 9 });
+        assert!(s.write_obj_be(0u16, 7).is_err());
+    }
+
     #[test]
     fn ref_oob_too_large() {
         let a = VecMem::new(3);
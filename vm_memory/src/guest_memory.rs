@@ -19,6 +19,7 @@ use zerocopy::AsBytes;
 use base::pagesize;
 use base::AsRawDescriptor;
 use base::AsRawDescriptors;
+use base::ControlConnection;
 use base::Error as SysError;
 use base::MappedRegion;
 use base::MemoryMapping;
@@ -26,6 +27,7 @@ use base::MemoryMappingBuilder;
 use base::MmapError;
 use base::RawDescriptor;
 use base::SharedMemory;
+use base::SharedRegionSource;
 use cros_async::mem;
 use cros_async::BackingMemory;
 use data_model::volatile_memory::*;
@@ -61,6 +63,8 @@ pub enum Error {
     MemoryRegionOverlap,
     #[error("memory region size {0} is too large")]
     MemoryRegionTooLarge(u128),
+    #[error("failed to transfer memory regions: {0}")]
+    RegionTransfer(#[source] std::io::Error),
     #[error("incomplete read of {completed} instead of {expected} bytes")]
     ShortRead { expected: usize, completed: usize },
     #[error("incomplete write of {completed} instead of {expected} bytes")]
@@ -281,6 +285,57 @@ impl GuestMemory {
         })
     }
 
+    /// Sends every region's backing memory over `conn`, so a device backend in another process
+    /// can map guest RAM itself (with `GuestMemory::recv_regions`) instead of asking for copies.
+    pub fn send_regions(&self, conn: &mut ControlConnection) -> Result<()> {
+        let mut layout = Vec::with_capacity(24 * self.regions.len());
+        let mut sources: Vec<&dyn SharedRegionSource> = Vec::with_capacity(self.regions.len());
+        for region in self.regions.iter() {
+            layout.extend_from_slice(&region.guest_base.0.to_le_bytes());
+            layout.extend_from_slice(&(region.mapping.size() as u64).to_le_bytes());
+            layout.extend_from_slice(&region.obj_offset.to_le_bytes());
+            sources.push(match &region.shared_obj {
+                BackingObject::Shm(shm) => shm.as_ref(),
+                #[cfg(unix)]
+                BackingObject::File(f) => f.as_ref(),
+                #[cfg(windows)]
+                BackingObject::File(_) => {
+                    return Err(Error::RegionTransfer(std::io::Error::new(
+                        std::io::ErrorKind::Unsupported,
+                        "file backed regions can't be sent on Windows",
+                    )))
+                }
+            });
+        }
+        conn.send_shared_memory(&layout, &sources)
+            .map_err(Error::RegionTransfer)
+    }
+
+    /// The guest memory another process sent with `send_regions`, mapped here.
+    pub fn recv_regions(conn: &mut ControlConnection) -> Result<GuestMemory> {
+        let (layout, shms) = conn.recv_shared_memory().map_err(Error::RegionTransfer)?;
+        if layout.len() != 24 * shms.len() {
+            return Err(Error::RegionTransfer(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "region layout doesn't match the regions sent",
+            )));
+        }
+        let word = |b: &[u8]| u64::from_le_bytes(b.try_into().unwrap());
+        let regions = layout
+            .chunks_exact(24)
+            .zip(shms)
+            .map(|(l, shm)| {
+                MemoryRegion::new_from_shm(
+                    word(&l[8..16]),
+                    GuestAddress(word(&l[..8])),
+                    word(&l[16..]),
+                    Arc::new(shm),
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        GuestMemory::from_regions(regions)
+    }
+
     /// Returns the end address of memory.
     ///
     /// # Examples